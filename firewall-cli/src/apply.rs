@@ -0,0 +1,77 @@
+//! `firewall-cli apply`: перевод запущенного файрволла на текущую конфигурацию без меню и
+//! без перезапуска, для скриптов.
+//!
+//! То же, что делает SIGHUP, но из отдельного процесса: запущенный экземпляр находится по
+//! закреплённым картам, его правила читаются, как в `dump-rules`, и в карты пишется только
+//! разница, тем же порядком, что у `apply-policy`. Повторный запуск с той же
+//! конфигурацией ничего не меняет.
+
+use crate::{
+    audit,
+    config::Config,
+    dump,
+    policy::{self, Policy, ROLLBACK_FILE},
+};
+
+/// Строки `+ ключ: значения` и `- ключ: значения` перехода с `old` на `new`.
+fn changes(old: &Policy, new: &Policy) -> Vec<String> {
+    let old = dump::rule_entries(old);
+    let new = dump::rule_entries(new);
+    let mut lines = Vec::new();
+    for ((key, old), (_, new)) in old.iter().zip(&new) {
+        let added = dump::missing(new, old);
+        let removed = dump::missing(old, new);
+        if !added.is_empty() {
+            lines.push(format!("+ {key}: {}", added.join(", ")));
+        }
+        if !removed.is_empty() {
+            lines.push(format!("- {key}: {}", removed.join(", ")));
+        }
+    }
+    lines
+}
+
+/// Выполняет `firewall-cli apply`: 0 — правила совпадают с конфигурацией (или уже
+/// совпадали), 1 — файрволл не запущен или правила не заменены.
+pub fn run(config: &Config) -> i32 {
+    let new = match Policy::from_config(config) {
+        Ok(policy) => policy,
+        Err(e) => {
+            println!("Ошибка: {e:#}");
+            return 1;
+        }
+    };
+    let old = match policy::live() {
+        Ok(policy) => policy,
+        Err(e) => {
+            println!("Не удалось прочитать карты: {e:#}");
+            return 1;
+        }
+    };
+
+    if old == new {
+        println!("Правила запущенного файрволла уже совпадают с конфигурацией.");
+    } else {
+        if let Err(e) = policy::switch_to(&new, config.grace_period.unwrap_or(0)) {
+            println!("Ошибка: {e:#}");
+            return 1;
+        }
+        if let Err(e) = audit::record("apply") {
+            println!("Не удалось записать apply в {}: {e}", audit::AUDIT_LOG);
+        }
+        println!("Правила обновлены; прежние сохранены в {ROLLBACK_FILE}.");
+        for line in changes(&old, &new) {
+            println!("  {line}");
+        }
+    }
+
+    match dump::stale_limits(config) {
+        Ok(keys) if !keys.is_empty() => println!(
+            "Не применены, меняются только перезапуском: {}.",
+            keys.join(", ")
+        ),
+        Ok(_) => {}
+        Err(e) => println!("Не удалось сверить пределы и таймауты: {e:#}"),
+    }
+    0
+}
@@ -0,0 +1,74 @@
+//! `firewall-cli bench`: замер скорости принятия решений на этой машине.
+//!
+//! Кадры из pcap снова и снова проходят [`replay::frame_verdict`] — тот же разбор и те же
+//! правила, что в `firewall-cli test`, — пока не истечёт заданное время. Ни логов, ни
+//! счётчиков здесь нет, так что результат — потолок для самих правил; сравнивая его для
+//! разных конфигураций, можно оценить цену отдельных настроек. Правила конфигурации
+//! проверяются перебором списков, а программа XDP ищет их в хеш-картах, поэтому с длинными
+//! списками замер занижает скорость программы. Её потолок на интерфейсе меряют загрузчиком
+//! с `fast-path`.
+
+use std::{
+    fs, hint,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use firewall_common::classify::Verdict;
+
+use crate::{config::Config, replay};
+
+/// Итоги замера.
+struct Report {
+    decisions: u64,
+    drops: u64,
+    elapsed: Duration,
+}
+
+fn measure(config: &Config, pcap: &Path, duration: Duration) -> anyhow::Result<(usize, Report)> {
+    let data = fs::read(pcap).with_context(|| format!("не удалось прочитать {}", pcap.display()))?;
+    let frames = replay::read_pcap(&data).with_context(|| format!("{}", pcap.display()))?;
+    if frames.is_empty() {
+        anyhow::bail!("{}: в записи нет пакетов", pcap.display());
+    }
+    let countries = replay::country_table(config)?;
+    let mut report = Report {
+        decisions: 0,
+        drops: 0,
+        elapsed: Duration::ZERO,
+    };
+    let start = Instant::now();
+    // Время проверяется после каждого прохода по записи, а не после каждого пакета.
+    while report.elapsed < duration {
+        for frame in &frames {
+            let verdict = replay::frame_verdict(config, &countries, hint::black_box(frame));
+            if matches!(hint::black_box(verdict), Some(Verdict::Drop(_))) {
+                report.drops += 1;
+            }
+        }
+        report.decisions += frames.len() as u64;
+        report.elapsed = start.elapsed();
+    }
+    Ok((frames.len(), report))
+}
+
+/// Выполняет `firewall-cli bench`.
+pub fn run(config: &Config, pcap: &Path, duration: Duration) -> i32 {
+    match measure(config, pcap, duration) {
+        Ok((frames, report)) => {
+            let seconds = report.elapsed.as_secs_f64();
+            let rate = report.decisions as f64 / seconds;
+            println!(
+                "{} решений за {seconds:.2} с по {frames} пакетам записи, отброшено бы {}.",
+                report.decisions, report.drops
+            );
+            println!("{:.2} млн решений/с, {:.0} нс на пакет.", rate / 1e6, 1e9 / rate);
+            0
+        }
+        Err(e) => {
+            println!("Ошибка: {e:#}");
+            1
+        }
+    }
+}
@@ -0,0 +1,170 @@
+//! `firewall-cli block`: блокировка адреса в запущенном файрволле, при желании после
+//! испытания.
+//!
+//! С `--stage` адрес сначала попадает в закреплённую карту `STAGED_IPS`: программа XDP
+//! только считает пакеты с него, дошедшие до правил. По истечении срока правило
+//! применяется, если средняя частота совпадений не выше `--max-rate`, а иначе
+//! откладывается: такое правило отрезало бы заметную долю трафика.
+
+use std::{
+    net::Ipv4Addr,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use aya::{
+    maps::{HashMap, Map, MapData, PerCpuHashMap, PerCpuValues},
+    util::nr_cpus,
+};
+use firewall_common::{BLOCKED_IPS_MAP, PIN_PATH, STAGED_IPS_MAP};
+
+use crate::{audit, config};
+
+/// Разбирает длительность вида `90s`, `10m`, `1h` (без суффикса — секунды).
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(split) => text.split_at(split),
+        None => (text, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("'{text}' не является длительностью (например, 30s, 10m, 1h)"))?;
+    let secs = match unit {
+        "s" => number,
+        "m" => number * 60,
+        "h" => number * 3_600,
+        _ => return Err(format!("'{text}': единица длительности — s, m или h")),
+    };
+    if secs == 0 {
+        return Err("длительность испытания должна быть больше нуля".to_string());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// Решение по итогам испытания.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// Частота не выше порога: правило применяется.
+    Promote,
+    /// Частота выше порога: правило отложено.
+    Hold,
+}
+
+/// Средняя частота совпадений за испытание и решение по ней.
+pub fn evaluate(matches: u64, elapsed: Duration, max_rate: f64) -> (f64, Outcome) {
+    let rate = matches as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    let outcome = if rate <= max_rate { Outcome::Promote } else { Outcome::Hold };
+    (rate, outcome)
+}
+
+fn open(name: &str) -> anyhow::Result<MapData> {
+    let path = Path::new(PIN_PATH).join(name);
+    if !path.exists() {
+        anyhow::bail!("файрволл не запущен: нет {}", path.display());
+    }
+    MapData::from_pin(&path).with_context(|| format!("не удалось открыть {path:?}"))
+}
+
+/// Считает пакеты с `addr` в течение `stage` и возвращает их число и фактический срок.
+fn observe(addr: u32, stage: Duration) -> anyhow::Result<(u64, Duration)> {
+    let mut staged: PerCpuHashMap<_, u32, u64> =
+        PerCpuHashMap::try_from(Map::PerCpuHashMap(open(STAGED_IPS_MAP)?))?;
+    let cpus = nr_cpus().map_err(|(path, e)| anyhow::anyhow!("{path}: {e}"))?;
+    staged.insert(addr, PerCpuValues::try_from(vec![0u64; cpus])?, 0)?;
+    let started = Instant::now();
+    thread::sleep(stage);
+    let elapsed = started.elapsed();
+    let matches = staged.get(&addr, 0).map(|values| values.iter().sum());
+    // Испытание заканчивается в любом случае, даже если счётчик не прочитался.
+    staged.remove(&addr)?;
+    Ok((matches?, elapsed))
+}
+
+/// Блокирует адрес в запущенном файрволле и добавляет его в `blocked-ips` конфигурации,
+/// чтобы блокировка пережила перезапуск.
+fn enforce(ip: Ipv4Addr, symlinks: config::SymlinkPolicy) -> anyhow::Result<()> {
+    let mut blocked: HashMap<_, u32, u64> =
+        HashMap::try_from(Map::HashMap(open(BLOCKED_IPS_MAP)?))?;
+    // Уже заблокированный адрес оставляется как есть, вместе со счётчиком срабатываний.
+    if blocked.get(&u32::from(ip), 0).is_err() {
+        blocked.insert(u32::from(ip), 0, 0)?;
+    }
+
+    let path = config::main_path();
+    let content =
+        config::read(path).with_context(|| format!("не удалось прочитать {}", path.display()))?;
+    let current = config::Config::load(path).map(|c| c.blocked_ips).unwrap_or_default();
+    if !current.iter().any(|net| net.contains(ip)) {
+        let updated = config::append_to_list(path, &content, "blocked-ips", &ip.to_string());
+        config::write(path, &updated, symlinks)
+            .with_context(|| format!("не удалось записать {}", path.display()))?;
+    }
+    Ok(())
+}
+
+fn record(action: &str) {
+    if let Err(e) = audit::record(action) {
+        println!("Не удалось записать «{action}» в {}: {e}", audit::AUDIT_LOG);
+    }
+}
+
+/// Выполняет `firewall-cli block`.
+pub fn run(
+    ip: Ipv4Addr,
+    stage: Option<Duration>,
+    max_rate: f64,
+    symlinks: config::SymlinkPolicy,
+) -> i32 {
+    if let Some(stage) = stage {
+        println!(
+            "Испытание {ip}: {} с только подсчёт, порог {max_rate} пак/с...",
+            stage.as_secs()
+        );
+        let (matches, elapsed) = match observe(u32::from(ip), stage) {
+            Ok(observed) => observed,
+            Err(e) => {
+                println!("Испытание не удалось: {e:#}");
+                return 1;
+            }
+        };
+        let (rate, outcome) = evaluate(matches, elapsed, max_rate);
+        println!("Совпадений: {matches} за {:.0} с, {rate:.1} пак/с.", elapsed.as_secs_f64());
+        if outcome == Outcome::Hold {
+            println!(
+                "Правило отложено: частота выше порога {max_rate} пак/с, блокировка {ip} \
+                 затронула бы заметную долю трафика."
+            );
+            record(&format!("block {ip} held ({rate:.1} pps > {max_rate})"));
+            return 1;
+        }
+    }
+    if let Err(e) = enforce(ip, symlinks) {
+        println!("Не удалось заблокировать {ip}: {e:#}");
+        return 1;
+    }
+    record(&format!("block {ip}"));
+    println!("{ip} заблокирован и добавлен в blocked-ips.");
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn staged_rule_is_promoted_below_threshold_and_held_above() {
+        let stage = parse_duration("10m").unwrap();
+        assert_eq!(stage, Duration::from_secs(600));
+
+        // 50 совпадений в секунду — ровно на пороге, правило ещё применяется.
+        assert_eq!(evaluate(30_000, stage, 50.0), (50.0, Outcome::Promote));
+        assert_eq!(evaluate(600, stage, 50.0), (1.0, Outcome::Promote));
+        assert_eq!(evaluate(0, stage, 50.0), (0.0, Outcome::Promote));
+        assert_eq!(evaluate(60_000, stage, 50.0), (100.0, Outcome::Hold));
+
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("10d").is_err());
+    }
+}
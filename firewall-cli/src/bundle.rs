@@ -0,0 +1,123 @@
+//! Пакет правил: вся конфигурация одним файлом JSON для переноса между файрволлами.
+//!
+//! `firewall-cli export <файл>` записывает пакет из действующей конфигурации,
+//! `firewall-cli import <файл>` проверяет его и заменяет им основной файл конфигурации.
+//! Интерфейсы на разных машинах обычно свои, поэтому в пакет они попадают только с
+//! `--with-iface`, а пакет без них оставляет при импорте локальные.
+//!
+//! ```text
+//! {
+//!   "bundle": 1,
+//!   "config-version": 3,
+//!   "rules": { "allowed-ports": ["80", "443/tcp"], "policy": "deny", "dry-run": true }
+//! }
+//! ```
+
+use serde_json::{json, Value};
+
+use crate::{
+    config::{self, Config},
+    conflicts,
+};
+
+/// Текущая версия формата пакета.
+pub const VERSION: u64 = 1;
+
+/// Пакет из конфигурации `config`; `with_iface` — вместе с интерфейсами.
+pub fn encode(config: &Config, with_iface: bool) -> String {
+    let mut rules = config::render_json(config);
+    if !with_iface {
+        rules.remove("iface");
+    }
+    let bundle = json!({
+        "bundle": VERSION,
+        "config-version": config::CONFIG_VERSION,
+        "rules": rules,
+    });
+    let mut text = serde_json::to_string_pretty(&bundle).unwrap_or_default();
+    text.push('\n');
+    text
+}
+
+/// Проверенный пакет правил.
+pub struct Bundle {
+    pub config: Config,
+    /// Есть ли в пакете `iface`; если нет, при импорте остаются локальные интерфейсы.
+    pub has_iface: bool,
+}
+
+/// Значение ключа в том виде, в каком его разбирает [`Config::parse_values`].
+fn value_text(key: &str, value: &Value) -> Result<String, String> {
+    let item = |value: &Value| match value {
+        Value::String(text) => Ok(text.clone()),
+        Value::Number(number) => Ok(number.to_string()),
+        _ => Err(format!("{key}: ожидается строка или число, а не {value}")),
+    };
+    match value {
+        Value::Bool(on) => Ok(if *on { "yes" } else { "no" }.to_string()),
+        Value::Array(items) => {
+            let items = items.iter().map(item).collect::<Result<Vec<_>, _>>()?;
+            if let Some(bad) = items.iter().find(|item| item.contains(',')) {
+                return Err(format!("{key}: в элементе списка '{bad}' не может быть запятой"));
+            }
+            Ok(items.join(", "))
+        }
+        value => item(value),
+    }
+}
+
+/// Разбирает и полностью проверяет пакет: формат, версии, ключи и значения, как в
+/// `firewall-cli check`, включая противоречия между правилами. Возвращает все ошибки сразу.
+pub fn decode(text: &str) -> Result<Bundle, Vec<String>> {
+    let value: Value = serde_json::from_str(text).map_err(|e| vec![format!("не JSON: {e}")])?;
+    let Value::Object(mut bundle) = value else {
+        return Err(vec!["пакет должен быть объектом JSON".to_string()]);
+    };
+    match bundle.remove("bundle").as_ref().and_then(Value::as_u64) {
+        Some(VERSION) => {}
+        Some(version) => {
+            let expected = format!("ожидается {VERSION}");
+            return Err(vec![format!("версия пакета {version} не поддерживается ({expected})")]);
+        }
+        None => return Err(vec!["это не пакет правил: нет поля bundle".to_string()]),
+    }
+    let mut errors = Vec::new();
+    match bundle.remove("config-version").as_ref().and_then(Value::as_u64) {
+        Some(version) if version == u64::from(config::CONFIG_VERSION) => {}
+        Some(version) => errors.push(format!(
+            "config-version {version}: пакет записан для другой версии конфигурации (здесь {})",
+            config::CONFIG_VERSION
+        )),
+        None => errors.push("нет поля config-version".to_string()),
+    }
+    let rules = match bundle.remove("rules") {
+        Some(Value::Object(rules)) => rules,
+        Some(_) => return Err(vec!["rules: ожидается объект".to_string()]),
+        None => return Err(vec!["нет поля rules".to_string()]),
+    };
+    errors.extend(bundle.keys().map(|key| format!("{key}: неизвестное поле пакета")));
+
+    let mut pairs = Vec::new();
+    for (key, value) in &rules {
+        if key == "config-version" || !config::KNOWN_KEYS.contains(&key.as_str()) {
+            errors.push(format!("{key}: неизвестный ключ конфигурации"));
+            continue;
+        }
+        match value_text(key, value) {
+            Ok(value) => pairs.push((key.clone(), value)),
+            Err(e) => errors.push(e),
+        }
+    }
+    let config = match Config::parse_values(&pairs) {
+        Ok(config) => config,
+        Err(parse_errors) => {
+            errors.extend(parse_errors.iter().map(ToString::to_string));
+            return Err(errors);
+        }
+    };
+    errors.extend(conflicts::errors(&config).iter().map(ToString::to_string));
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(Bundle { config, has_iface: rules.contains_key("iface") })
+}
@@ -0,0 +1,148 @@
+//! `firewall-cli check` (он же `validate`): проверка конфигурации без запуска файрволла.
+//!
+//! Проверяются те же значения, что при запуске, но eBPF не загружается и прав root не
+//! нужно, так что команду можно вызывать из хука pre-commit. Интерфейсы, которых нет в
+//! этой системе, — только предупреждение: конфигурацию часто проверяют не на той машине,
+//! где работает файрволл.
+
+use std::path::Path;
+
+use pnet::datalink;
+
+use crate::{
+    config::{self, Config, ConfigError},
+    conflicts,
+};
+
+/// Строка JSON в кавычках.
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Отчёт одним объектом JSON: `valid`, список `problems` и сводка `summary`.
+///
+/// Ошибки (`"severity": "error"`) не дают запустить файрволл; предупреждения
+/// (`"warning"`) — ключи, которые файрволл не знает и пропускает, подозрительные сочетания
+/// правил и интерфейсы, которых нет в системе.
+pub fn format_json(errors: &[ConfigError], warnings: &[ConfigError]) -> String {
+    let problem = |error: &ConfigError, severity: &str| {
+        let file = error
+            .file
+            .as_ref()
+            .map_or("null".to_string(), |f| json_string(&f.display().to_string()));
+        format!(
+            "{{\"file\":{file},\"field\":{},\"line\":{},\"message\":{},\
+             \"severity\":\"{severity}\"}}",
+            json_string(&error.key),
+            error.line,
+            json_string(&error.message),
+        )
+    };
+    let problems: Vec<String> = errors
+        .iter()
+        .map(|e| problem(e, "error"))
+        .chain(warnings.iter().map(|w| problem(w, "warning")))
+        .collect();
+    format!(
+        "{{\"valid\":{},\"problems\":[{}],\"summary\":{{\"errors\":{},\"warnings\":{}}}}}",
+        errors.is_empty(),
+        problems.join(","),
+        errors.len(),
+        warnings.len(),
+    )
+}
+
+/// Предупреждения об интерфейсах из `iface`, которых сейчас нет в системе.
+fn missing_ifaces(path: &Path, config: &Config) -> Vec<ConfigError> {
+    let present: Vec<String> = datalink::interfaces().into_iter().map(|i| i.name).collect();
+    config
+        .ifaces
+        .iter()
+        .filter(|iface| !present.contains(iface))
+        .map(|iface| ConfigError {
+            file: Some(path.to_path_buf()),
+            line: 0,
+            key: "iface".to_string(),
+            message: format!("интерфейса '{iface}' нет в этой системе"),
+        })
+        .collect()
+}
+
+/// Выполняет `firewall-cli check`; код выхода 1, если конфигурация с ошибками.
+pub fn run(rules_dir: Option<&Path>, json: bool) -> i32 {
+    let path = config::main_path();
+    let mut warnings = config::load_warnings(path, rules_dir);
+    let errors = match config::load_effective(path, rules_dir) {
+        Ok(config) => {
+            warnings.extend(conflicts::warnings(&config));
+            warnings.extend(missing_ifaces(path, &config));
+            Vec::new()
+        }
+        Err(errors) => errors,
+    };
+    if json {
+        println!("{}", format_json(&errors, &warnings));
+        return i32::from(!errors.is_empty());
+    }
+    if errors.is_empty() {
+        println!("Конфигурация корректна.");
+    } else {
+        println!("Конфигурация содержит ошибки ({}):", errors.len());
+        for error in &errors {
+            println!("  {error}");
+        }
+    }
+    for warning in &warnings {
+        println!("Предупреждение: {warning}");
+    }
+    i32::from(!errors.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    #[test]
+    fn json_report_lists_each_problem() {
+        let content = "\"allowed-ports\"\nhttp\n\"blocked-ips\"\n300.1.1.1\n";
+        let errors = Config::parse(content).unwrap_err();
+        assert_eq!(errors.len(), 2);
+        let warning = ConfigError::new(0, "iface", "интерфейса 'eth9' нет в этой системе");
+
+        let report: Value = serde_json::from_str(&format_json(&errors, &[warning])).unwrap();
+        assert_eq!(report["valid"], false);
+        assert_eq!(report["summary"], json!({"errors": 2, "warnings": 1}));
+        let problems = report["problems"].as_array().unwrap();
+        let fields: Vec<_> = problems
+            .iter()
+            .map(|p| (p["field"].as_str(), p["line"].as_u64(), p["severity"].as_str()))
+            .map(|(field, line, severity)| (field.unwrap(), line.unwrap(), severity.unwrap()))
+            .collect();
+        assert_eq!(
+            fields,
+            [("allowed-ports", 1, "error"), ("blocked-ips", 3, "error"), ("iface", 0, "warning")]
+        );
+        for (problem, error) in problems.iter().zip(&errors) {
+            assert_eq!(problem["message"], error.message.as_str());
+            assert_eq!(problem["file"], Value::Null);
+        }
+
+        let clean: Value = serde_json::from_str(&format_json(&[], &[])).unwrap();
+        let summary = json!({"errors": 0, "warnings": 0});
+        assert_eq!(clean, json!({"valid": true, "problems": [], "summary": summary}));
+    }
+}
@@ -0,0 +1,2569 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs, io,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+
+use firewall_common::{direction, event_fields, protocol_rule, time_window, RateState};
+
+use crate::{countries, menu};
+
+mod toml;
+
+/// Основной файл конфигурации в синтаксисе TOML; если его нет, читается [`LEGACY_PATH`].
+pub const TOML_PATH: &str = "config.toml";
+
+/// Основной файл конфигурации в исходном формате: ключ в кавычках, на следующей строке
+/// значение.
+pub const LEGACY_PATH: &str = "config.cfg";
+
+static MAIN_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Задаёт основной файл конфигурации вместо поиска по [`main_path`] (`--config`).
+pub fn set_main_path(path: PathBuf) {
+    let _ = MAIN_PATH.set(path);
+}
+
+/// Основной файл конфигурации: заданный через [`set_main_path`], иначе `config.toml`,
+/// если он есть, иначе `config.cfg`.
+pub fn main_path() -> &'static Path {
+    if let Some(path) = MAIN_PATH.get() {
+        path
+    } else if Path::new(TOML_PATH).exists() {
+        Path::new(TOML_PATH)
+    } else {
+        Path::new(LEGACY_PATH)
+    }
+}
+
+/// Записан ли файл в синтаксисе TOML; формат определяется по расширению `.toml`.
+pub fn is_toml(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "toml")
+}
+
+/// Начальный `config.toml` для нового каталога, с комментариями к ключам.
+pub fn toml_template() -> String {
+    format!(
+        "# Конфигурация файрволла. Ключи те же, что в config.cfg, но через подчёркивание:\n\
+         # allowed_ports вместо allowed-ports. Списки — массивы TOML, флаги — true и false.\n\
+         config_version = {CONFIG_VERSION}\n\
+         \n\
+         # Сетевой интерфейс, к которому подключается программа XDP, или несколько:\n\
+         # [\"wan0\", \"wan1\"].\n\
+         iface = \"eth0\"\n\
+         \n\
+         # Разрешённые порты назначения: 80, \"53/udp\", \"8000-8100\". HTTPS и QUIC (HTTP/3)\n\
+         # разрешаются отдельно: \"443/tcp\" и \"443/udp\".\n\
+         allowed_ports = [80, \"443/tcp\", \"443/udp\", 53]\n\
+         \n\
+         # Заблокированные адреса и сети, IPv4 и IPv6: \"203.0.113.7\", \"198.51.100.0/24\",\n\
+         # \"2001:db8::/32\".\n\
+         blocked_ips = []\n\
+         \n\
+         # Заблокированные страны, двухбуквенные коды: \"CN\".\n\
+         blocked_countries = []\n"
+    )
+}
+
+/// Типизированная конфигурация файрволла.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    /// Интерфейсы, к которым привязывается программа (`iface`, через запятую).
+    pub ifaces: Vec<String>,
+    /// Как привязывать программу XDP к интерфейсу (`attach-mode`).
+    pub attach_mode: AttachMode,
+    /// К каким пакетам применяются правила (`direction`); отбрасывать загрузчик умеет только
+    /// входящие, программа TC на egress лишь записывает соединения.
+    pub direction: Direction,
+    /// Если интерфейс пропал во время работы, ждать его и запустить файрволл снова, а не
+    /// возвращаться в меню (`reattach-iface`).
+    pub reattach_iface: bool,
+    /// Проверять во время работы, что программа XDP всё ещё привязана к интерфейсам, и
+    /// привязывать её снова, если её сняли (`watchdog`).
+    pub watchdog: bool,
+    /// Раз в сколько секунд проверять привязку (`watchdog-interval`), по умолчанию
+    /// [`DEFAULT_WATCHDOG_INTERVAL`].
+    pub watchdog_interval: Option<u32>,
+    pub allowed_ports: Vec<AllowedPort>,
+    /// Порты источника, пакеты TCP и UDP с которых отбрасываются, что бы ни разрешали
+    /// `allowed-ports` (`blocked-src-ports`).
+    pub blocked_src_ports: Vec<AllowedPort>,
+    /// С каким портом пакета сравниваются разрешённые порты (`port-match`).
+    pub port_match: PortMatch,
+    /// Что делать с пакетом, который не разрешило ни одно правило (`policy`).
+    pub policy: DefaultPolicy,
+    /// Адреса и сети IPv4 из `blocked-ips`.
+    pub blocked_ips: Vec<Ipv4Network>,
+    /// Адреса и сети IPv6 из `blocked-ips`: к пакетам IPv6 из всех списков адресов
+    /// применяется только этот.
+    pub blocked_ips6: Vec<Ipv6Network>,
+    /// Правила «адрес/маска» с произвольной маской (`blocked-masks`).
+    pub blocked_masks: Vec<MaskedIp>,
+    /// MAC-адреса источника, кадры с которых отбрасываются до разбора IP (`blocked-macs`).
+    pub blocked_macs: Vec<MacAddress>,
+    pub blocked_countries: Vec<String>,
+    /// Когда по местному времени действуют `blocked-countries`, окно [`time_window`]
+    /// (`blocked-countries-window`); без окна — всегда.
+    pub blocked_countries_window: Option<u32>,
+    /// База «сеть → страна» в формате CSV для `blocked-countries` (`country-db`).
+    pub country_db: Option<PathBuf>,
+    /// База MaxMind City в формате CSV для `blocked-regions` (`region-db`).
+    pub region_db: Option<PathBuf>,
+    /// Заблокированные регионы, `geoname_id` из базы (`blocked-regions`).
+    pub blocked_regions: Vec<u32>,
+    /// Составные правила «адрес:порт назначения» (`blocked-endpoints`).
+    pub blocked_endpoints: Vec<Endpoint>,
+    /// С каким адресом сравниваются составные правила (`endpoint-match`).
+    pub endpoint_match: EndpointMatch,
+    /// Адреса, которые пропускаются, даже если их страна заблокирована.
+    pub allowed_ips: Vec<Ipv4Addr>,
+    /// Доверенные префиксы, трафик из которых пропускается без проверки правил.
+    pub fast_accept_prefixes: Vec<Ipv4Network>,
+    /// Адреса назначения, к трафику для которых применяются правила (`protected-ips`);
+    /// пустой список — правила для всего трафика.
+    pub protected_ips: Vec<Ipv4Network>,
+    /// Проверять внутренний заголовок пакетов IP-in-IP (`unwrap-ipip`).
+    pub unwrap_ipip: bool,
+    /// Пропускать эхо-запросы ICMP и ICMPv6, то есть ping (`allow-icmp-echo`).
+    pub allow_icmp_echo: bool,
+    /// Пропускать пакеты TCP с флагами сканирования NULL, FIN и XMAS
+    /// (`allow-invalid-tcp-flags`).
+    pub allow_invalid_tcp_flags: bool,
+    /// Решать протоколы кроме TCP, UDP и ICMP политикой `policy`, а не пропускать их
+    /// (`strict-protocols`).
+    pub strict_protocols: bool,
+    /// Правила для отдельных протоколов IP (`protocols`): запрещённые отбрасываются целиком,
+    /// разрешённые проходят, даже если правила их не разбирают. С ними протоколы не из списка
+    /// решаются политикой `policy`, как при `strict-protocols`.
+    pub protocols: Vec<ProtocolRule>,
+    /// Порт назначения, закрытый, пока источник не простучит `knock-sequence` (`knock-port`).
+    pub knock_port: Option<u16>,
+    /// Порты, в которые надо постучать по порядку, чтобы открыть `knock-port`
+    /// (`knock-sequence`).
+    pub knock_sequence: Vec<u16>,
+    /// Сколько секунд `knock-port` остаётся открытым после последнего пакета к нему
+    /// (`knock-timeout`), по умолчанию [`DEFAULT_KNOCK_TIMEOUT`].
+    pub knock_timeout: Option<u32>,
+    /// Проверять правилами и широковещательные и групповые кадры, а не пропускать их
+    /// (`filter-multicast`).
+    pub filter_multicast: bool,
+    /// Отбрасывать все фрагменты IPv4 (`drop-fragments`).
+    pub drop_fragments: bool,
+    /// Пропускать DNS (порт 53 по TCP и UDP в любую сторону) без `allowed-ports`
+    /// (`allow-dns`); `None` — не задано, то есть да, см. [`Config::allows_dns`].
+    pub allow_dns: Option<bool>,
+    /// Срок записи таблицы соединений в секундах (`conntrack-timeout`): ответы на соединения
+    /// TCP, открытые самим хостом, проходят без `allowed-ports`. `None` — таблица не ведётся.
+    pub conntrack_timeout: Option<u32>,
+    /// Сколько секунд после перезагрузки правил (SIGHUP, `apply`) доживают принятые
+    /// соединения TCP к портам, снятым с `allowed-ports` (`grace-period`); нужна
+    /// `conntrack-timeout`. `None` — соединения обрываются сразу.
+    pub grace_period: Option<u32>,
+    /// Пропускать ARP только от отправителей из этой сети (`arp-subnet`); без неё ARP
+    /// пропускается всегда.
+    pub arp_subnet: Option<Ipv4Network>,
+    /// Только сообщать о пакетах, которые отбросили бы правила, пропуская весь трафик
+    /// (`dry-run`).
+    pub dry_run: bool,
+    /// Режим замера (`fast-path`): программа только решает судьбу пакетов, без логов,
+    /// событий и счётчиков.
+    pub fast_path: bool,
+    /// Что делать с пакетом, который решено отбросить (`block-action`).
+    pub block_action: BlockAction,
+    /// Предел пакетов в секунду с одного источника (`rate-limit`).
+    pub rate_limit: Option<u32>,
+    /// Сколько пакетов источник может отправить подряд сверх предела (`rate-burst`).
+    pub rate_burst: Option<u32>,
+    /// Предел пакетов SYN без ACK в секунду с одного источника (`syn-rate-limit`).
+    pub syn_rate_limit: Option<u32>,
+    /// Сколько попыток соединения подряд источник может сделать сверх предела
+    /// (`syn-rate-burst`).
+    pub syn_rate_burst: Option<u32>,
+    /// Как часто загрузчик удаляет из таблиц соединений, стука и вёдер записи замолчавших
+    /// источников, в секундах (`sweep-interval`, 0 — не удалять); `None` — как решит
+    /// загрузчик.
+    pub sweep_interval: Option<u32>,
+    /// Сколько секунд без пакетов запись ждёт удаления (`sweep-ttl`); срок самой таблицы
+    /// загрузчик не сокращает.
+    pub sweep_ttl: Option<u32>,
+    /// Файл, в который дописываются события об отброшенных пакетах (`log-file`).
+    pub log_file: Option<PathBuf>,
+    /// Размер `log-file` в байтах, после которого он переименовывается в `.1` и
+    /// начинается заново (`log-max-size`).
+    pub log_max_size: Option<u64>,
+    /// Что программа XDP пишет в журнал ядра (`log-level`).
+    pub log_level: LogLevel,
+    /// Как программа XDP сообщает о пакетах (`log-backend`); с `events` `log-level` не
+    /// действует.
+    pub log_backend: LogBackend,
+    /// Как `firewall-cli events` и системный журнал выводят события (`log-format`).
+    pub log_format: LogFormat,
+    /// Отправлять события об отброшенных пакетах в системный журнал (`syslog`).
+    pub syslog: bool,
+    /// Источник (facility) записей в системном журнале (`syslog-facility`).
+    pub syslog_facility: SyslogFacility,
+    /// Размеры окна TCP, по которым отбрасываются пакеты сканеров (`block-tcp-window`).
+    pub blocked_tcp_windows: Vec<u16>,
+    /// Необязательные поля событий об отброшенных пакетах (`ttl`, `tcp-flags`, `length`, `vlan`).
+    pub event_fields: Vec<String>,
+    /// Действия, которые идут первыми в главном меню (`menu-order`).
+    pub menu_order: Vec<menu::Action>,
+    /// Действия, скрытые из главного меню (`menu-hidden`).
+    pub menu_hidden: Vec<menu::Action>,
+    /// Через что запускать загрузчик, когда у `firewall-cli` нет прав root (`escalator`).
+    pub escalator: Escalator,
+}
+
+/// Порт или диапазон портов из `allowed-ports` (см. `port-match`) или `blocked-src-ports`:
+/// `80` (TCP и UDP), `443/tcp`, `53/udp` или `8000-8100/tcp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllowedPort {
+    pub port: u16,
+    /// Последний порт диапазона; у одиночного порта совпадает с `port`.
+    pub last: u16,
+    /// `None` — оба протокола.
+    pub proto: Option<PortProto>,
+}
+
+impl AllowedPort {
+    pub fn ports(&self) -> std::ops::RangeInclusive<u16> {
+        self.port..=self.last
+    }
+
+    /// `80` или `8000-8100`, без протокола.
+    pub fn range(&self) -> String {
+        if self.port == self.last {
+            self.port.to_string()
+        } else {
+            format!("{}-{}", self.port, self.last)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PortProto {
+    Tcp,
+    Udp,
+}
+
+impl PortProto {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Tcp => "tcp",
+            Self::Udp => "udp",
+        }
+    }
+}
+
+impl fmt::Display for AllowedPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.proto {
+            Some(proto) => write!(f, "{}/{}", self.range(), proto.as_str()),
+            None => write!(f, "{}", self.range()),
+        }
+    }
+}
+
+/// Составное правило `адрес[/длина]:порт`; порт всегда порт назначения.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Endpoint {
+    pub network: Ipv4Network,
+    pub port: u16,
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.network.prefix() == 32 {
+            write!(f, "{}:{}", self.network.ip(), self.port)
+        } else {
+            write!(f, "{}:{}", self.network, self.port)
+        }
+    }
+}
+
+/// Правило `адрес/маска`, где маска — любая битовая маска в виде адреса, например
+/// `0.0.0.100/0.0.0.255`. Адрес хранится с уже наложенной маской.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaskedIp {
+    pub addr: Ipv4Addr,
+    pub mask: Ipv4Addr,
+}
+
+impl fmt::Display for MaskedIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.mask)
+    }
+}
+
+/// MAC-адрес `xx:xx:xx:xx:xx:xx`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+/// Правило `имя=allow|deny` из `protocols` для протокола IP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolRule {
+    /// Номер протокола IP.
+    pub proto: u8,
+    /// Разрешён протокол или запрещён.
+    pub allow: bool,
+}
+
+impl ProtocolRule {
+    /// Имя протокола из [`protocol_rule::NAMES`] или его номер.
+    pub fn name(&self) -> String {
+        protocol_rule::name(self.proto).map_or_else(|| self.proto.to_string(), str::to_string)
+    }
+
+    pub fn action(&self) -> &'static str {
+        if self.allow {
+            "allow"
+        } else {
+            "deny"
+        }
+    }
+}
+
+impl fmt::Display for ProtocolRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name(), self.action())
+    }
+}
+
+/// Какой порт пакета сравнивается с `allowed-ports`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PortMatch {
+    /// Порт назначения: входящие подключения к службам хоста.
+    #[default]
+    Dst,
+    /// Порт источника: ответы серверов, к которым подключается сам хост.
+    Src,
+}
+
+impl PortMatch {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Dst => "dst",
+            Self::Src => "src",
+        }
+    }
+}
+
+/// Политика по умолчанию для пакетов, которые не разрешило ни одно правило.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DefaultPolicy {
+    /// Отбросить: проходят только разрешённые порты и ICMP.
+    #[default]
+    Deny,
+    /// Пропустить: отбрасываются только пакеты, попавшие под блокировки.
+    Allow,
+}
+
+impl DefaultPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Deny => "deny",
+            Self::Allow => "allow",
+        }
+    }
+}
+
+/// Режим привязки программы XDP к интерфейсу.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AttachMode {
+    /// Сначала в драйвере, а если драйвер не поддерживает XDP — generic.
+    #[default]
+    Auto,
+    /// Generic (SKB): работает с любым интерфейсом, но после выделения sk_buff.
+    Skb,
+    /// В драйвере сетевой карты, до выделения sk_buff.
+    Driver,
+    /// Выгрузка программы в сетевую карту, если карта это умеет.
+    Hw,
+}
+
+impl AttachMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Skb => "skb",
+            Self::Driver => "driver",
+            Self::Hw => "hw",
+        }
+    }
+}
+
+/// Направление трафика, к которому применяются правила.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Direction {
+    /// Входящий, его видит программа XDP.
+    #[default]
+    Ingress,
+    /// Исходящий.
+    Egress,
+    /// Оба направления.
+    Both,
+}
+
+impl Direction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Ingress => "ingress",
+            Self::Egress => "egress",
+            Self::Both => "both",
+        }
+    }
+
+    /// Значение настройки `settings::DIRECTIONS`.
+    pub fn setting(self) -> u32 {
+        match self {
+            Self::Ingress => direction::INGRESS,
+            Self::Egress => direction::EGRESS,
+            Self::Both => direction::BOTH,
+        }
+    }
+}
+
+/// Что программа XDP делает с отбрасываемым пакетом.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlockAction {
+    /// Молча отбросить.
+    #[default]
+    Drop,
+    /// Отбросить с событием трассировки `xdp:xdp_exception`.
+    Abort,
+    /// Вернуть кадр отправителю через тот же интерфейс.
+    Tx,
+}
+
+impl BlockAction {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Drop => "drop",
+            Self::Abort => "abort",
+            Self::Tx => "tx",
+        }
+    }
+}
+
+/// Какой адрес пакета сравнивается с составными правилами.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EndpointMatch {
+    /// Адрес источника и порт назначения.
+    #[default]
+    Src,
+    /// Адрес и порт назначения.
+    Dst,
+}
+
+impl EndpointMatch {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Src => "src",
+            Self::Dst => "dst",
+        }
+    }
+}
+
+/// Подробность сообщений программы XDP в журнале ядра.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogLevel {
+    /// Ничего не писать.
+    None,
+    /// Только отброшенные пакеты.
+    #[default]
+    Drops,
+    /// Каждый разобранный пакет; под нагрузкой заметно замедляет фильтрацию.
+    Verbose,
+}
+
+impl LogLevel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Drops => "drops",
+            Self::Verbose => "verbose",
+        }
+    }
+}
+
+/// Канал, по которому программа XDP сообщает о пакетах.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogBackend {
+    /// Только события об отброшенных пакетах в кольцевом буфере: дешевле всего и ничего
+    /// больше не нужно читать.
+    #[default]
+    Events,
+    /// Ещё текстовые сообщения aya-log по `log-level`: их форматирование стоит времени на
+    /// каждом пакете, зато видны подробности разбора.
+    AyaLog,
+}
+
+impl LogBackend {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Events => "events",
+            Self::AyaLog => "aya-log",
+        }
+    }
+}
+
+/// Вид событий в `firewall-cli events` и в системном журнале.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Строка для чтения человеком.
+    #[default]
+    Text,
+    /// Объект JSON на строку, как в `log-file`.
+    Json,
+}
+
+impl LogFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Json => "json",
+        }
+    }
+}
+
+/// Источник записей в системном журнале, см. `syslog(3)`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyslogFacility {
+    User,
+    #[default]
+    Daemon,
+    Auth,
+    /// `local0`–`local7`: источники для местных нужд, номер от 0 до 7.
+    Local(u8),
+}
+
+impl SyslogFacility {
+    pub fn as_str(self) -> &'static str {
+        const LOCAL: [&str; 8] =
+            ["local0", "local1", "local2", "local3", "local4", "local5", "local6", "local7"];
+        match self {
+            Self::User => "user",
+            Self::Daemon => "daemon",
+            Self::Auth => "auth",
+            Self::Local(n) => LOCAL[usize::from(n & 7)],
+        }
+    }
+
+    /// Код источника в приоритете записи.
+    pub fn code(self) -> u8 {
+        match self {
+            Self::User => 1,
+            Self::Daemon => 3,
+            Self::Auth => 4,
+            Self::Local(n) => 16 + (n & 7),
+        }
+    }
+}
+
+/// Программа, через которую загрузчик получает права root, см. [`crate::privileges`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Escalator {
+    #[default]
+    Sudo,
+    Doas,
+    Pkexec,
+}
+
+impl Escalator {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Sudo => "sudo",
+            Self::Doas => "doas",
+            Self::Pkexec => "pkexec",
+        }
+    }
+}
+
+/// Ошибка разбора или проверки конфигурации с указанием места.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// Файл, в котором найдена ошибка (если известен).
+    pub file: Option<PathBuf>,
+    /// Номер строки, начиная с 1; 0 — ошибка относится к файлу целиком.
+    pub line: usize,
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(line: usize, key: &str, message: impl Into<String>) -> Self {
+        Self {
+            file: None,
+            line,
+            key: key.to_string(),
+            message: message.into(),
+        }
+    }
+
+    pub fn in_file(mut self, file: &Path) -> Self {
+        self.file = Some(file.to_path_buf());
+        self
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}:", file.display())?;
+        }
+        if self.line > 0 {
+            write!(f, "{}: ", self.line)?;
+        } else if self.file.is_some() {
+            write!(f, " ")?;
+        }
+        if !self.key.is_empty() {
+            write!(f, "{}: ", self.key)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Ключи, которые понимает файрволл. Остальные ключи не ошибка: файл может быть общим с
+/// другими инструментами, поэтому такие ключи сохраняются при перезаписи как есть.
+pub const KNOWN_KEYS: &[&str] = &[
+    "config-version",
+    "iface",
+    "attach-mode",
+    "direction",
+    "reattach-iface",
+    "watchdog",
+    "watchdog-interval",
+    "allowed-ports",
+    "blocked-src-ports",
+    "port-match",
+    "policy",
+    "blocked-ips",
+    "blocked-masks",
+    "blocked-macs",
+    "blocked-countries",
+    "blocked-countries-window",
+    "country-db",
+    "region-db",
+    "blocked-regions",
+    "blocked-endpoints",
+    "endpoint-match",
+    "allowed-ips",
+    "fast-accept-prefixes",
+    "protected-ips",
+    "unwrap-ipip",
+    "allow-icmp-echo",
+    "allow-invalid-tcp-flags",
+    "strict-protocols",
+    "protocols",
+    "knock-port",
+    "knock-sequence",
+    "knock-timeout",
+    "filter-multicast",
+    "drop-fragments",
+    "allow-dns",
+    "conntrack-timeout",
+    "grace-period",
+    "arp-subnet",
+    "dry-run",
+    "fast-path",
+    "block-action",
+    "rate-limit",
+    "rate-burst",
+    "syn-rate-limit",
+    "syn-rate-burst",
+    "sweep-interval",
+    "sweep-ttl",
+    "block-tcp-window",
+    "event-fields",
+    "log-file",
+    "log-max-size",
+    "log-level",
+    "log-backend",
+    "log-format",
+    "syslog",
+    "syslog-facility",
+    "menu-order",
+    "menu-hidden",
+    "escalator",
+];
+
+/// Предупреждения о ключах не из [`KNOWN_KEYS`], по одному на ключ; формат текста
+/// определяется по пути файла.
+pub fn unknown_keys(path: &Path, content: &str) -> Vec<ConfigError> {
+    let message = "неизвестный ключ: файрволл его не использует, при записи он сохраняется";
+    let keys: Vec<(String, usize)> = if is_toml(path) {
+        let items = toml::items(content).unwrap_or_default();
+        items.into_iter().map(|item| (item.key, item.line)).collect()
+    } else {
+        entries(content).into_iter().map(|entry| (entry.key.to_string(), entry.line)).collect()
+    };
+    keys.into_iter()
+        .filter(|(key, _)| !KNOWN_KEYS.contains(&key.as_str()))
+        .map(|(key, line)| {
+            let name = if is_toml(path) { toml::key_name(&key) } else { key.clone() };
+            ConfigError::new(line, &name, message)
+        })
+        .collect()
+}
+
+/// Предупреждения о неизвестных ключах в основном файле и в каталоге правил.
+pub fn load_warnings(path: &Path, rules_dir: Option<&Path>) -> Vec<ConfigError> {
+    let mut paths = vec![path.to_path_buf()];
+    if let Some(dir) = rules_dir {
+        if let Ok(configs) = load_rules_dir(dir) {
+            paths.extend(configs.into_iter().map(|(path, _)| path));
+        }
+    }
+    paths
+        .iter()
+        .filter_map(|path| Some((path, fs::read_to_string(path).ok()?)))
+        .flat_map(|(path, content)| {
+            unknown_keys(path, &content).into_iter().map(|warning| warning.in_file(path))
+        })
+        .collect()
+}
+
+/// Ключ, значение которого меняет запись файла; `None` — ключа нет.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyChange {
+    pub key: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// Ключи, значения которых различаются в `old` и `new`, в порядке нового текста; формат
+/// определяется по пути файла. Значения TOML — в виде значений `config.cfg`.
+pub fn changed_keys(path: &Path, old: &str, new: &str) -> Vec<KeyChange> {
+    let values = |content: &str| -> Vec<(String, String)> {
+        if is_toml(path) {
+            let items = toml::items(content).unwrap_or_default();
+            items.into_iter().map(|item| (item.key, item.value)).collect()
+        } else {
+            let entries = entries(content).into_iter();
+            entries.map(|entry| (entry.key.to_string(), entry.value.trim().to_string())).collect()
+        }
+    };
+    let (old, new) = (values(old), values(new));
+    let find = |list: &[(String, String)], key: &str| {
+        list.iter().find(|(k, _)| k == key).map(|(_, value)| value.clone())
+    };
+    let keys = new.iter().chain(&old).map(|(key, _)| key);
+    let mut changes: Vec<KeyChange> = Vec::new();
+    for key in keys {
+        let change = KeyChange {
+            key: key.clone(),
+            old: find(&old, key),
+            new: find(&new, key),
+        };
+        if change.old != change.new && !changes.iter().any(|c| c.key == *key) {
+            changes.push(change);
+        }
+    }
+    changes
+}
+
+/// Пара «ключ — значение» из файла вместе с номером строки ключа.
+struct Entry<'a> {
+    key: &'a str,
+    value: &'a str,
+    line: usize,
+    /// Индекс строки значения; `None`, если за ключом сразу идёт другой ключ или конец файла.
+    value_line: Option<usize>,
+}
+
+/// Строка ключа: имя в кавычках.
+fn is_key(line: &str) -> bool {
+    let line = line.trim();
+    line.len() >= 2 && line.starts_with('"') && line.ends_with('"')
+}
+
+/// Пустая строка или комментарий `# ...`: при сопоставлении ключей и значений пропускается.
+fn is_filler(line: &str) -> bool {
+    let line = line.trim();
+    line.is_empty() || line.starts_with('#')
+}
+
+/// Разбивает содержимое на пары: строка с ключом в кавычках, затем строка значения.
+/// Пустые строки и комментарии между ключом и значением пропускаются, пробелы вокруг ключа и
+/// значения отбрасываются.
+///
+/// Раздел без значения (за ключом сразу следующий ключ или конец файла) считается пустым и
+/// не забирает следующий ключ себе. Строки вне пар пропускаются.
+fn entries(content: &str) -> Vec<Entry<'_>> {
+    let lines: Vec<&str> = content.lines().collect();
+    let mut entries = Vec::new();
+
+    let mut i = 0;
+    while i < lines.len() {
+        if !is_key(lines[i]) {
+            i += 1;
+            continue;
+        }
+        let key = lines[i].trim().trim_matches('"').trim();
+        let next = (i + 1..lines.len()).find(|&j| !is_filler(lines[j]));
+        let value_line = next.filter(|&j| !is_key(lines[j]));
+        entries.push(Entry {
+            key,
+            value: value_line.map_or("", |v| lines[v].trim()),
+            line: i + 1,
+            value_line,
+        });
+        i = value_line.map_or(i + 1, |v| v + 1);
+    }
+
+    entries
+}
+
+/// Есть ли в тексте конфигурации раздел `key`, пусть даже пустой.
+pub fn has_key(content: &str, key: &str) -> bool {
+    entries(content).iter().any(|entry| entry.key == key)
+}
+
+/// Заменяет значение раздела `key` или дописывает раздел в конец, не трогая остальные строки.
+pub fn set_value(content: &str, key: &str, value: &str) -> String {
+    let mut lines: Vec<String> = content.lines().map(str::to_string).collect();
+    match entries(content).iter().find(|entry| entry.key == key) {
+        Some(Entry { value_line: Some(v), .. }) => lines[*v] = value.to_string(),
+        Some(entry) => lines.insert(entry.line, value.to_string()),
+        None => lines.extend([format!("\"{key}\""), value.to_string()]),
+    }
+    lines.join("\n")
+}
+
+/// Значение раздела `key` в том виде, как оно записано в файле, без подстановки переменных.
+pub fn raw_value<'a>(content: &'a str, key: &str) -> Option<&'a str> {
+    entries(content).into_iter().find(|entry| entry.key == key).map(|entry| entry.value)
+}
+
+/// Заменяет значение скалярного ключа `key` в тексте файла `path`, в формате этого файла.
+pub fn set_scalar(path: &Path, content: &str, key: &str, value: &str) -> String {
+    if is_toml(path) {
+        toml::set_value(content, key, &toml::string(value))
+    } else {
+        set_value(content, key, value)
+    }
+}
+
+/// Заменяет список `key` в тексте файла `path` на `items`, в формате этого файла.
+pub fn set_list(path: &Path, content: &str, key: &str, items: &[String]) -> String {
+    if is_toml(path) {
+        toml::set_value(content, key, &toml::array(items.iter().map(String::as_str)))
+    } else {
+        set_value(content, key, &items.join(", "))
+    }
+}
+
+/// Ключи, значения которых — адреса или сети IPv4; в `blocked-ips` могут быть и IPv6.
+const NETWORK_KEYS: &[&str] =
+    &["blocked-ips", "allowed-ips", "fast-accept-prefixes", "protected-ips"];
+
+/// Список `key`, готовый к записи, и предупреждения о том, что в нём исправлено.
+#[derive(Debug)]
+pub struct Normalized {
+    pub items: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// Убирает из списка `key` повторы и упорядочивает его: сети — по адресу и длине префикса,
+/// порты — по номеру, страны — по алфавиту. У сетей обнуляются биты узла (10.0.0.5/8 —
+/// 10.0.0.0/8), о каждой такой сети — предупреждение. Порядок `iface` и списков, для
+/// которых он ничего не значит, не меняется; повторы убираются и из них. Элемент, который
+/// не разбирается, остаётся как есть: о нём сообщит проверка конфигурации.
+pub fn normalize_list(key: &str, items: &[String]) -> Normalized {
+    let mut warnings = Vec::new();
+    let mut items: Vec<String> = if NETWORK_KEYS.contains(&key) {
+        let mut networks = Vec::new();
+        let mut networks6 = Vec::new();
+        let mut other = Vec::new();
+        let mut fixed = |network: &dyn fmt::Display, canonical: &dyn fmt::Display| {
+            warnings.push(format!("{key}: в {network} ненулевые биты узла, записано {canonical}"));
+        };
+        for item in items {
+            if let Ok(network) = parse_network(item) {
+                let canonical = Ipv4Network::new(network.network(), network.prefix())
+                    .expect("длина префикса прежняя");
+                if canonical != network {
+                    fixed(&network, &canonical);
+                }
+                networks.push(canonical);
+            } else if let Some(network) =
+                parse_network6(item).ok().filter(|_| key == "blocked-ips")
+            {
+                let canonical = Ipv6Network::new(network.network(), network.prefix())
+                    .expect("длина префикса прежняя");
+                if canonical != network {
+                    fixed(&network, &canonical);
+                }
+                networks6.push(canonical);
+            } else {
+                other.push(item.clone());
+            }
+        }
+        networks.sort_by_key(|network| (network.network(), network.prefix()));
+        networks6.sort_by_key(|network| (network.network(), network.prefix()));
+        let networks = networks.iter().map(ToString::to_string);
+        networks.chain(networks6.iter().map(ToString::to_string)).chain(other).collect()
+    } else if matches!(key, "allowed-ports" | "blocked-src-ports") {
+        let mut ports = Vec::new();
+        let mut other = Vec::new();
+        for item in items {
+            match parse_allowed_port(item) {
+                Ok(port) => ports.push(port),
+                Err(_) => other.push(item.clone()),
+            }
+        }
+        ports.sort_by_key(|port| (port.port, port.last, port.proto));
+        ports.iter().map(ToString::to_string).chain(other).collect()
+    } else if matches!(key, "blocked-countries" | "blocked-regions") {
+        let mut sorted = items.to_vec();
+        sorted.sort();
+        sorted
+    } else {
+        items.to_vec()
+    };
+    let mut seen = HashSet::new();
+    items.retain(|item| seen.insert(item.clone()));
+    Normalized { items, warnings }
+}
+
+/// Дописывает `item` к списку `key` в тексте файла `path`, в формате этого файла.
+///
+/// В `config.cfg` элемент дописывается к тексту значения, чтобы ссылки на переменные
+/// остались ссылками.
+pub fn append_to_list(path: &Path, content: &str, key: &str, item: &str) -> String {
+    if is_toml(path) {
+        let items = toml::items(content).unwrap_or_default();
+        let current = items.iter().find(|i| i.key == key).map_or("", |i| i.value.as_str());
+        return toml::set_value(content, key, &toml::array(list(current).chain([item])));
+    }
+    let value = match raw_value(content, key) {
+        Some("") | None => item.to_string(),
+        Some(value) => format!("{value}, {item}"),
+    };
+    set_value(content, key, &value)
+}
+
+fn list(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|s| !s.is_empty())
+}
+
+/// Переменные из строк `@имя = значение` вне пар «ключ — значение».
+///
+/// Значение переменной подставляется как есть, поэтому переменная может хранить и список:
+/// `@web = 80, 443`. Ссылки на другие переменные в значении не раскрываются.
+fn variables<'a>(
+    content: &'a str,
+    parsed: &[Entry<'a>],
+) -> (HashMap<&'a str, &'a str>, Vec<ConfigError>) {
+    let mut vars = HashMap::new();
+    let mut errors = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let Some(definition) = line.trim().strip_prefix('@') else {
+            continue;
+        };
+        if parsed.iter().any(|entry| entry.value_line == Some(index)) {
+            continue;
+        }
+        let (name, value) = definition.split_once('=').unwrap_or((definition, ""));
+        let name = name.trim();
+        let error = |message: String| ConfigError::new(index + 1, "", message);
+        if name.is_empty()
+            || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            errors.push(error(format!(
+                "'{}' не является определением переменной (ожидается @имя = значение)",
+                line.trim()
+            )));
+        } else if vars.insert(name, value.trim()).is_some() {
+            errors.push(error(format!("переменная '@{name}' определена повторно")));
+        }
+    }
+    (vars, errors)
+}
+
+/// Подставляет значения переменных вместо элементов `@имя`.
+fn resolve(value: &str, vars: &HashMap<&str, &str>) -> Result<String, String> {
+    if !value.contains('@') {
+        return Ok(value.to_string());
+    }
+    let mut tokens = Vec::new();
+    for token in list(value) {
+        match token.strip_prefix('@') {
+            Some(name) => tokens.push(
+                *vars
+                    .get(name)
+                    .ok_or_else(|| format!("переменная '@{name}' не определена"))?,
+            ),
+            None => tokens.push(token),
+        }
+    }
+    Ok(tokens.join(", "))
+}
+
+fn parse_port(token: &str) -> Result<u16, String> {
+    token
+        .parse::<u16>()
+        .map_err(|_| format!("'{token}' не является портом 0-65535"))
+}
+
+pub fn parse_allowed_port(token: &str) -> Result<AllowedPort, String> {
+    let (port, proto) = match token.split_once('/') {
+        Some((port, proto)) => match proto.to_ascii_lowercase().as_str() {
+            "tcp" => (port, Some(PortProto::Tcp)),
+            "udp" => (port, Some(PortProto::Udp)),
+            _ => return Err(format!("'{token}': протокол должен быть tcp или udp")),
+        },
+        None => (token, None),
+    };
+    let (first, last) = match port.split_once('-') {
+        Some((first, last)) => {
+            let error = |_| format!("'{token}' не является диапазоном портов 0-65535");
+            (parse_port(first.trim()).map_err(error)?, parse_port(last.trim()).map_err(error)?)
+        }
+        None => (parse_port(port)?, parse_port(port)?),
+    };
+    if first > last {
+        return Err(format!("'{token}': диапазон портов кончается раньше, чем начинается"));
+    }
+    Ok(AllowedPort {
+        port: first,
+        last,
+        proto,
+    })
+}
+
+fn parse_window(token: &str) -> Result<u16, String> {
+    token
+        .parse::<u16>()
+        .map_err(|_| format!("'{token}' не является размером окна TCP 0-65535"))
+}
+
+fn parse_rate(token: &str) -> Result<u32, String> {
+    token
+        .parse::<u32>()
+        .ok()
+        .filter(|rate| *rate > 0)
+        .ok_or_else(|| format!("'{token}' не является числом пакетов в секунду больше нуля"))
+}
+
+/// Срок `knock-timeout`, если он не задан.
+pub const DEFAULT_KNOCK_TIMEOUT: u32 = 30;
+
+/// Интервал проверки `watchdog`, если `watchdog-interval` не задан.
+pub const DEFAULT_WATCHDOG_INTERVAL: u32 = 5;
+
+fn parse_timeout(token: &str) -> Result<u32, String> {
+    token
+        .parse::<u32>()
+        .ok()
+        .filter(|secs| *secs > 0)
+        .ok_or_else(|| format!("'{token}' не является числом секунд больше нуля"))
+}
+
+/// Интервал в секундах, 0 выключает то, что он задаёт.
+fn parse_interval(token: &str) -> Result<u32, String> {
+    token.parse::<u32>().map_err(|_| format!("'{token}' не является числом секунд"))
+}
+
+fn parse_burst(token: &str) -> Result<u32, String> {
+    token
+        .parse::<u32>()
+        .ok()
+        .filter(|burst| (1..=RateState::MAX_BURST).contains(burst))
+        .ok_or_else(|| {
+            format!("'{token}': размер всплеска — от 1 до {} пакетов", RateState::MAX_BURST)
+        })
+}
+
+/// Окно времени `HH:MM-HH:MM`; конец раньше начала означает переход через полночь.
+fn parse_time_window(token: &str) -> Result<u32, String> {
+    let (start, end) = token
+        .split_once('-')
+        .ok_or_else(|| format!("'{token}' не является окном времени ЧЧ:ММ-ЧЧ:ММ"))?;
+    for time in [start, end] {
+        if time_window::parse_time(time.trim()).is_none() {
+            return Err(format!("'{}' не является временем ЧЧ:ММ от 00:00 до 23:59", time.trim()));
+        }
+    }
+    time_window::parse(token)
+        .ok_or_else(|| format!("'{token}': начало и конец окна совпадают, окно пустое"))
+}
+
+/// Окно в записи `HH:MM-HH:MM`, как в конфигурации.
+pub fn format_window(window: u32) -> String {
+    let (start, end) = time_window::bounds(window).unwrap_or_default();
+    let time = |minute: u16| format!("{:02}:{:02}", minute / 60, minute % 60);
+    format!("{}-{}", time(start), time(end))
+}
+
+/// Размер в байтах, можно с суффиксом `K`, `M` или `G` (степени 1024).
+fn parse_size(token: &str) -> Result<u64, String> {
+    let upper = token.to_ascii_uppercase();
+    let (digits, unit) = match upper.as_bytes().last() {
+        Some(b'K') => (&upper[..upper.len() - 1], 1 << 10),
+        Some(b'M') => (&upper[..upper.len() - 1], 1 << 20),
+        Some(b'G') => (&upper[..upper.len() - 1], 1 << 30),
+        _ => (upper.as_str(), 1),
+    };
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .filter(|size| *size > 0)
+        .and_then(|size| size.checked_mul(unit))
+        .ok_or_else(|| format!("'{token}' не является размером в байтах (например, 10M)"))
+}
+
+fn parse_region(token: &str) -> Result<u32, String> {
+    token
+        .parse::<u32>()
+        .map_err(|_| format!("'{token}' не является идентификатором региона (geoname_id)"))
+}
+
+/// Элемент `blocked-ips`: адрес или сеть IPv4 либо, если в нём есть двоеточие, IPv6.
+pub fn parse_blocked_ip(token: &str) -> Result<IpNetwork, String> {
+    if token.contains(':') {
+        parse_network6(token).map(IpNetwork::V6)
+    } else {
+        parse_network(token).map(IpNetwork::V4)
+    }
+}
+
+fn parse_network6(token: &str) -> Result<Ipv6Network, String> {
+    if let Some((_, len)) = token.split_once('/') {
+        if len.parse::<u8>().map_or(true, |len| len > 128) {
+            return Err(format!("'{token}': длина префикса IPv6 должна быть от 0 до 128"));
+        }
+    }
+    token
+        .parse::<Ipv6Network>()
+        .map_err(|_| format!("'{token}' не является IPv6-адресом или CIDR"))
+}
+
+pub fn parse_network(token: &str) -> Result<Ipv4Network, String> {
+    if let Some((_, len)) = token.split_once('/') {
+        if len.parse::<u8>().map_or(true, |len| len > 32) {
+            return Err(format!("'{token}': длина префикса должна быть от 0 до 32"));
+        }
+    }
+    token
+        .parse::<Ipv4Network>()
+        .map_err(|_| format!("'{token}' не является IPv4-адресом или CIDR"))
+}
+
+fn parse_endpoint(token: &str) -> Result<Endpoint, String> {
+    let error = || format!("'{token}' не является правилом адрес[/длина]:порт");
+    let (network, port) = token.rsplit_once(':').ok_or_else(error)?;
+    Ok(Endpoint {
+        network: network.parse().map_err(|_| error())?,
+        port: port.parse().map_err(|_| error())?,
+    })
+}
+
+/// Наибольшее число правил `blocked-masks`, столько проверяет программа XDP.
+pub const MAX_BLOCKED_MASKS: usize = firewall_common::MAX_BLOCKED_MASKS as usize;
+
+fn parse_masked(token: &str) -> Result<MaskedIp, String> {
+    let error = || format!("'{token}' не является правилом адрес/маска (маска вида 0.0.0.255)");
+    let (addr, mask) = token.split_once('/').ok_or_else(error)?;
+    let addr: Ipv4Addr = addr.parse().map_err(|_| error())?;
+    let mask: Ipv4Addr = mask.parse().map_err(|_| error())?;
+    Ok(MaskedIp {
+        addr: Ipv4Addr::from(u32::from(addr) & u32::from(mask)),
+        mask,
+    })
+}
+
+fn parse_mac(token: &str) -> Result<MacAddress, String> {
+    firewall_common::parse_mac(token)
+        .map(MacAddress)
+        .ok_or_else(|| format!("'{token}' не является MAC-адресом вида xx:xx:xx:xx:xx:xx"))
+}
+
+fn parse_attach_mode(token: &str) -> Result<AttachMode, String> {
+    match token.to_ascii_lowercase().as_str() {
+        "auto" => Ok(AttachMode::Auto),
+        "skb" | "generic" => Ok(AttachMode::Skb),
+        "driver" | "native" => Ok(AttachMode::Driver),
+        "hw" | "offload" => Ok(AttachMode::Hw),
+        _ => Err(format!("'{token}': ожидается auto, skb, driver или hw")),
+    }
+}
+
+fn parse_direction(token: &str) -> Result<Direction, String> {
+    match token.to_ascii_lowercase().as_str() {
+        "ingress" => Ok(Direction::Ingress),
+        "egress" => Ok(Direction::Egress),
+        "both" => Ok(Direction::Both),
+        _ => Err(format!("'{token}': ожидается ingress, egress или both")),
+    }
+}
+
+fn parse_protocol_rule(token: &str) -> Result<ProtocolRule, String> {
+    let (name, action) = token
+        .split_once('=')
+        .ok_or_else(|| format!("'{token}': ожидается имя=allow или имя=deny, например gre=deny"))?;
+    let name = name.trim().to_ascii_lowercase();
+    let proto = protocol_rule::from_name(&name).ok_or_else(|| {
+        let names: Vec<_> = protocol_rule::NAMES.iter().map(|(n, _)| *n).collect();
+        format!("неизвестный протокол '{name}': ожидается номер или одно из {}", names.join(", "))
+    })?;
+    let allow = match action.trim().to_ascii_lowercase().as_str() {
+        "allow" => true,
+        "deny" => false,
+        _ => return Err(format!("'{token}': действие должно быть allow или deny")),
+    };
+    Ok(ProtocolRule { proto, allow })
+}
+
+/// Добавляет правило для протокола; второе правило с другим действием — ошибка.
+fn push_protocol(list: &mut Vec<ProtocolRule>, rule: ProtocolRule) -> Result<(), String> {
+    match list.iter().find(|r| r.proto == rule.proto) {
+        Some(other) if other.allow != rule.allow => {
+            Err(format!("протокол {} указан и как allow, и как deny", rule.name()))
+        }
+        Some(_) => Ok(()),
+        None => {
+            list.push(rule);
+            Ok(())
+        }
+    }
+}
+
+fn parse_block_action(token: &str) -> Result<BlockAction, String> {
+    match token.to_ascii_lowercase().as_str() {
+        "drop" => Ok(BlockAction::Drop),
+        "abort" => Ok(BlockAction::Abort),
+        "tx" => Ok(BlockAction::Tx),
+        _ => Err(format!("'{token}': ожидается drop, abort или tx")),
+    }
+}
+
+fn parse_endpoint_match(token: &str) -> Result<EndpointMatch, String> {
+    match token.to_ascii_lowercase().as_str() {
+        "src" => Ok(EndpointMatch::Src),
+        "dst" => Ok(EndpointMatch::Dst),
+        _ => Err(format!("'{token}': ожидается src или dst")),
+    }
+}
+
+fn parse_log_level(token: &str) -> Result<LogLevel, String> {
+    match token.to_ascii_lowercase().as_str() {
+        "none" => Ok(LogLevel::None),
+        "drops" => Ok(LogLevel::Drops),
+        "verbose" => Ok(LogLevel::Verbose),
+        _ => Err(format!("'{token}': ожидается none, drops или verbose")),
+    }
+}
+
+fn parse_log_backend(token: &str) -> Result<LogBackend, String> {
+    match token.to_ascii_lowercase().as_str() {
+        "events" => Ok(LogBackend::Events),
+        "aya-log" => Ok(LogBackend::AyaLog),
+        _ => Err(format!("'{token}': ожидается events или aya-log")),
+    }
+}
+
+fn parse_log_format(token: &str) -> Result<LogFormat, String> {
+    match token.to_ascii_lowercase().as_str() {
+        "text" => Ok(LogFormat::Text),
+        "json" => Ok(LogFormat::Json),
+        _ => Err(format!("'{token}': ожидается text или json")),
+    }
+}
+
+fn parse_escalator(token: &str) -> Result<Escalator, String> {
+    match token.to_ascii_lowercase().as_str() {
+        "sudo" => Ok(Escalator::Sudo),
+        "doas" => Ok(Escalator::Doas),
+        "pkexec" => Ok(Escalator::Pkexec),
+        _ => Err(format!("'{token}': ожидается sudo, doas или pkexec")),
+    }
+}
+
+fn parse_syslog_facility(token: &str) -> Result<SyslogFacility, String> {
+    let name = token.to_ascii_lowercase();
+    match name.as_str() {
+        "user" => return Ok(SyslogFacility::User),
+        "daemon" => return Ok(SyslogFacility::Daemon),
+        "auth" => return Ok(SyslogFacility::Auth),
+        _ => {}
+    }
+    name.strip_prefix("local")
+        .and_then(|n| n.parse::<u8>().ok())
+        .filter(|n| *n <= 7)
+        .map(SyslogFacility::Local)
+        .ok_or_else(|| format!("'{token}': ожидается user, daemon, auth или local0-local7"))
+}
+
+fn parse_policy(token: &str) -> Result<DefaultPolicy, String> {
+    match token.to_ascii_lowercase().as_str() {
+        "deny" => Ok(DefaultPolicy::Deny),
+        "allow" => Ok(DefaultPolicy::Allow),
+        _ => Err(format!("'{token}': ожидается deny или allow")),
+    }
+}
+
+fn parse_port_match(token: &str) -> Result<PortMatch, String> {
+    match token.to_ascii_lowercase().as_str() {
+        "src" => Ok(PortMatch::Src),
+        "dst" => Ok(PortMatch::Dst),
+        _ => Err(format!("'{token}': ожидается src или dst")),
+    }
+}
+
+fn parse_event_field(token: &str) -> Result<String, String> {
+    let name = token.to_ascii_lowercase();
+    if event_fields::from_name(&name).is_some() {
+        Ok(name)
+    } else {
+        let names: Vec<_> = event_fields::NAMES.iter().map(|(n, _)| *n).collect();
+        Err(format!("неизвестное поле события '{token}', допустимы: {}", names.join(", ")))
+    }
+}
+
+fn parse_bool(token: &str) -> Result<bool, String> {
+    match token.to_ascii_lowercase().as_str() {
+        "yes" | "true" | "on" | "1" => Ok(true),
+        "no" | "false" | "off" | "0" | "" => Ok(false),
+        _ => Err(format!("'{token}': ожидается yes или no")),
+    }
+}
+
+fn parse_ip(token: &str) -> Result<Ipv4Addr, String> {
+    if token.parse::<Ipv6Addr>().is_ok() {
+        return Err(format!(
+            "'{token}' — адрес IPv6, а allowed-ips только снимает блокировку стран и регионов, \
+             которая к IPv6 не относится"
+        ));
+    }
+    token
+        .parse::<Ipv4Addr>()
+        .map_err(|_| format!("'{token}' не является IPv4-адресом"))
+}
+
+/// Код ISO 3166-1 alpha-2 из встроенной таблицы или название страны на английском
+/// (`Russia` -> `RU`, `UK` -> `GB`).
+pub fn parse_country(token: &str) -> Result<String, String> {
+    let code = token.to_ascii_uppercase();
+    if countries::is_code(&code) {
+        Ok(code)
+    } else {
+        countries::resolve(token).map(str::to_string)
+    }
+}
+
+/// Добавляет элемент в список, если его там ещё нет.
+fn push_unique<T: PartialEq>(list: &mut Vec<T>, item: T) {
+    if !list.contains(&item) {
+        list.push(item);
+    }
+}
+
+impl Config {
+    /// Разбирает конфигурацию, собирая все ошибки, а не только первую.
+    ///
+    /// Ссылки на переменные (`allowed-ips: @mgmt`) раскрываются до проверки значений.
+    pub fn parse(content: &str) -> Result<Config, Vec<ConfigError>> {
+        let parsed = entries(content);
+        let (vars, errors) = variables(content, &parsed);
+        let pairs = parsed.iter().map(|entry| (entry.key, entry.value, entry.line));
+        Config::from_pairs(pairs, &vars, errors)
+    }
+
+    /// Разбирает `config.toml` ([`toml`]); переменных в этом формате нет.
+    pub fn parse_toml(content: &str) -> Result<Config, Vec<ConfigError>> {
+        let items = toml::items(content).map_err(|e| vec![e])?;
+        let pairs = items.iter().map(|item| (item.key.as_str(), item.value.as_str(), item.line));
+        Config::from_pairs(pairs, &HashMap::new(), Vec::new()).map_err(|errors| {
+            errors.into_iter().map(|e| ConfigError { key: toml::key_name(&e.key), ..e }).collect()
+        })
+    }
+
+    /// Разбирает пары «ключ — значение» пакета правил ([`crate::bundle`]); переменных и
+    /// номеров строк в нём нет.
+    pub fn parse_values(pairs: &[(String, String)]) -> Result<Config, Vec<ConfigError>> {
+        let pairs = pairs.iter().map(|(key, value)| (key.as_str(), value.as_str(), 0));
+        Config::from_pairs(pairs, &HashMap::new(), Vec::new())
+    }
+
+    /// Разбирает `config.cfg`, пропуская неверные значения: конфигурация собирается из
+    /// верных, а об остальных говорят возвращённые ошибки. Для `firewall-cli migrate`.
+    pub fn parse_lenient(content: &str) -> (Config, Vec<ConfigError>) {
+        let parsed = entries(content);
+        let (vars, errors) = variables(content, &parsed);
+        let pairs = parsed.iter().map(|entry| (entry.key, entry.value, entry.line));
+        Config::collect(pairs, &vars, errors)
+    }
+
+    /// Проверяет пары «ключ — значение, строка» независимо от формата файла.
+    fn from_pairs<'a>(
+        pairs: impl Iterator<Item = (&'a str, &'a str, usize)>,
+        vars: &HashMap<&str, &str>,
+        errors: Vec<ConfigError>,
+    ) -> Result<Config, Vec<ConfigError>> {
+        let (config, errors) = Config::collect(pairs, vars, errors);
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Собирает конфигурацию из верных пар, а ошибки в остальных добавляет к `errors`.
+    fn collect<'a>(
+        pairs: impl Iterator<Item = (&'a str, &'a str, usize)>,
+        vars: &HashMap<&str, &str>,
+        mut errors: Vec<ConfigError>,
+    ) -> (Config, Vec<ConfigError>) {
+        let mut config = Config::default();
+        for (key, value, line) in pairs {
+            let value = match resolve(value, vars) {
+                Ok(value) => value,
+                Err(message) => {
+                    errors.push(ConfigError::new(line, key, message));
+                    continue;
+                }
+            };
+            let value = value.as_str();
+            let mut check = |result: Result<(), String>| {
+                if let Err(message) = result {
+                    errors.push(ConfigError::new(line, key, message));
+                }
+            };
+            match key {
+                "iface" => {
+                    for name in list(value) {
+                        push_unique(&mut config.ifaces, name.to_string());
+                    }
+                }
+                "attach-mode" if !value.is_empty() => {
+                    check(parse_attach_mode(value).map(|m| config.attach_mode = m));
+                }
+                "direction" if !value.is_empty() => {
+                    check(parse_direction(value).map(|d| config.direction = d));
+                }
+                "reattach-iface" => check(parse_bool(value).map(|on| config.reattach_iface = on)),
+                "watchdog" => check(parse_bool(value).map(|on| config.watchdog = on)),
+                "watchdog-interval" if !value.is_empty() => {
+                    check(parse_timeout(value).map(|secs| config.watchdog_interval = Some(secs)))
+                }
+                "allowed-ports" => {
+                    for token in list(value) {
+                        check(
+                            parse_allowed_port(token)
+                                .map(|p| push_unique(&mut config.allowed_ports, p)),
+                        );
+                    }
+                }
+                "blocked-src-ports" => {
+                    for token in list(value) {
+                        check(
+                            parse_allowed_port(token)
+                                .map(|p| push_unique(&mut config.blocked_src_ports, p)),
+                        );
+                    }
+                }
+                "port-match" if !value.is_empty() => {
+                    check(parse_port_match(value).map(|m| config.port_match = m));
+                }
+                "policy" if !value.is_empty() => {
+                    check(parse_policy(value).map(|p| config.policy = p));
+                }
+                "blocked-ips" => {
+                    for token in list(value) {
+                        check(parse_blocked_ip(token).map(|network| match network {
+                            IpNetwork::V4(n) => push_unique(&mut config.blocked_ips, n),
+                            IpNetwork::V6(n) => push_unique(&mut config.blocked_ips6, n),
+                        }));
+                    }
+                }
+                "blocked-masks" => {
+                    for token in list(value) {
+                        check(
+                            parse_masked(token).map(|m| push_unique(&mut config.blocked_masks, m)),
+                        );
+                    }
+                }
+                "blocked-macs" => {
+                    for token in list(value) {
+                        check(
+                            parse_mac(token).map(|mac| push_unique(&mut config.blocked_macs, mac)),
+                        );
+                    }
+                }
+                "blocked-countries" => {
+                    for token in list(value) {
+                        check(
+                            parse_country(token)
+                                .map(|c| push_unique(&mut config.blocked_countries, c)),
+                        );
+                    }
+                }
+                "blocked-countries-window" if !value.is_empty() => {
+                    check(
+                        parse_time_window(value)
+                            .map(|window| config.blocked_countries_window = Some(window)),
+                    );
+                }
+                "country-db" if !value.is_empty() => {
+                    config.country_db = Some(PathBuf::from(value))
+                }
+                "region-db" if !value.is_empty() => config.region_db = Some(PathBuf::from(value)),
+                "blocked-regions" => {
+                    for token in list(value) {
+                        check(
+                            parse_region(token)
+                                .map(|r| push_unique(&mut config.blocked_regions, r)),
+                        );
+                    }
+                }
+                "blocked-endpoints" => {
+                    for token in list(value) {
+                        check(
+                            parse_endpoint(token)
+                                .map(|e| push_unique(&mut config.blocked_endpoints, e)),
+                        );
+                    }
+                }
+                "endpoint-match" if !value.is_empty() => {
+                    check(parse_endpoint_match(value).map(|m| config.endpoint_match = m));
+                }
+                "allowed-ips" => {
+                    for token in list(value) {
+                        check(parse_ip(token).map(|ip| push_unique(&mut config.allowed_ips, ip)));
+                    }
+                }
+                "fast-accept-prefixes" => {
+                    for token in list(value) {
+                        check(
+                            parse_network(token)
+                                .map(|n| push_unique(&mut config.fast_accept_prefixes, n)),
+                        );
+                    }
+                }
+                "protected-ips" => {
+                    for token in list(value) {
+                        check(
+                            parse_network(token)
+                                .map(|n| push_unique(&mut config.protected_ips, n)),
+                        );
+                    }
+                }
+                "unwrap-ipip" => check(parse_bool(value).map(|on| config.unwrap_ipip = on)),
+                "allow-icmp-echo" => {
+                    check(parse_bool(value).map(|on| config.allow_icmp_echo = on))
+                }
+                "allow-invalid-tcp-flags" => {
+                    check(parse_bool(value).map(|on| config.allow_invalid_tcp_flags = on))
+                }
+                "strict-protocols" => {
+                    check(parse_bool(value).map(|on| config.strict_protocols = on))
+                }
+                "protocols" => {
+                    for token in list(value) {
+                        check(
+                            parse_protocol_rule(token)
+                                .and_then(|r| push_protocol(&mut config.protocols, r)),
+                        );
+                    }
+                }
+                "knock-port" if !value.is_empty() => {
+                    check(parse_port(value).map(|port| config.knock_port = Some(port)))
+                }
+                // Порядок важен, а порт может повторяться не подряд: повторы не убираются.
+                "knock-sequence" => {
+                    for token in list(value) {
+                        check(parse_port(token).map(|port| config.knock_sequence.push(port)));
+                    }
+                }
+                "knock-timeout" if !value.is_empty() => {
+                    check(parse_timeout(value).map(|secs| config.knock_timeout = Some(secs)))
+                }
+                "filter-multicast" => {
+                    check(parse_bool(value).map(|on| config.filter_multicast = on))
+                }
+                "drop-fragments" => check(parse_bool(value).map(|on| config.drop_fragments = on)),
+                "allow-dns" if !value.is_empty() => {
+                    check(parse_bool(value).map(|on| config.allow_dns = Some(on)))
+                }
+                "conntrack-timeout" if !value.is_empty() => {
+                    check(parse_timeout(value).map(|secs| config.conntrack_timeout = Some(secs)))
+                }
+                "grace-period" if !value.is_empty() => {
+                    check(parse_timeout(value).map(|secs| config.grace_period = Some(secs)))
+                }
+                "arp-subnet" if !value.is_empty() => {
+                    check(parse_network(value).map(|net| config.arp_subnet = Some(net)))
+                }
+                "dry-run" => check(parse_bool(value).map(|on| config.dry_run = on)),
+                "fast-path" => check(parse_bool(value).map(|on| config.fast_path = on)),
+                "block-action" if !value.is_empty() => {
+                    check(parse_block_action(value).map(|a| config.block_action = a));
+                }
+                "rate-limit" if !value.is_empty() => {
+                    check(parse_rate(value).map(|rate| config.rate_limit = Some(rate)))
+                }
+                "rate-burst" if !value.is_empty() => {
+                    check(parse_burst(value).map(|burst| config.rate_burst = Some(burst)))
+                }
+                "syn-rate-limit" if !value.is_empty() => {
+                    check(parse_rate(value).map(|rate| config.syn_rate_limit = Some(rate)))
+                }
+                "syn-rate-burst" if !value.is_empty() => {
+                    check(parse_burst(value).map(|burst| config.syn_rate_burst = Some(burst)))
+                }
+                "sweep-interval" if !value.is_empty() => {
+                    check(parse_interval(value).map(|secs| config.sweep_interval = Some(secs)))
+                }
+                "sweep-ttl" if !value.is_empty() => {
+                    check(parse_timeout(value).map(|secs| config.sweep_ttl = Some(secs)))
+                }
+                "log-file" if !value.is_empty() => config.log_file = Some(PathBuf::from(value)),
+                "log-max-size" if !value.is_empty() => {
+                    check(parse_size(value).map(|size| config.log_max_size = Some(size)))
+                }
+                "log-level" if !value.is_empty() => {
+                    check(parse_log_level(value).map(|level| config.log_level = level))
+                }
+                "log-backend" if !value.is_empty() => {
+                    check(parse_log_backend(value).map(|backend| config.log_backend = backend))
+                }
+                "log-format" if !value.is_empty() => {
+                    check(parse_log_format(value).map(|format| config.log_format = format))
+                }
+                "syslog" => check(parse_bool(value).map(|on| config.syslog = on)),
+                "syslog-facility" if !value.is_empty() => {
+                    check(parse_syslog_facility(value).map(|f| config.syslog_facility = f))
+                }
+                "block-tcp-window" => {
+                    for token in list(value) {
+                        check(
+                            parse_window(token)
+                                .map(|w| push_unique(&mut config.blocked_tcp_windows, w)),
+                        );
+                    }
+                }
+                "event-fields" => {
+                    for token in list(value) {
+                        check(
+                            parse_event_field(token)
+                                .map(|f| push_unique(&mut config.event_fields, f)),
+                        );
+                    }
+                }
+                "menu-order" => {
+                    for token in list(value) {
+                        check(
+                            menu::parse_action(token)
+                                .map(|a| push_unique(&mut config.menu_order, a)),
+                        );
+                    }
+                }
+                "menu-hidden" => {
+                    for token in list(value) {
+                        check(
+                            menu::parse_action(token)
+                                .map(|a| push_unique(&mut config.menu_hidden, a)),
+                        );
+                    }
+                }
+                "escalator" if !value.is_empty() => {
+                    check(parse_escalator(value).map(|e| config.escalator = e))
+                }
+                _ => {}
+            }
+        }
+        (config, errors)
+    }
+
+    /// Читает и разбирает файл; ошибки помечаются его путём. Файла нет — конфигурация по
+    /// умолчанию, а файл, который есть, но не читается, — ошибка, а не пустой набор правил.
+    pub fn load(path: &Path) -> Result<Config, Vec<ConfigError>> {
+        let content = read(path).map_err(|e| {
+            vec![ConfigError::new(0, "", format!("не удалось прочитать: {e}")).in_file(path)]
+        })?;
+        let parsed = if is_toml(path) {
+            Config::parse_toml(&content)
+        } else {
+            Config::parse(&content)
+        };
+        parsed.map_err(|errors| errors.into_iter().map(|e| e.in_file(path)).collect())
+    }
+
+    /// Пропускается ли DNS без `allowed-ports`: да, если `allow-dns` не выключен явно.
+    pub fn allows_dns(&self) -> bool {
+        self.allow_dns != Some(false)
+    }
+
+    /// Решаются ли протоколы не из `protocols` политикой: да при `strict-protocols` и при
+    /// любом правиле в `protocols`.
+    pub fn strict_protocols_in_effect(&self) -> bool {
+        self.strict_protocols || !self.protocols.is_empty()
+    }
+}
+
+/// Значение раздела в [`render`] и [`render_toml`].
+enum Section {
+    Text(String),
+    List(Vec<String>),
+    Flag(bool),
+}
+
+impl Section {
+    fn is_empty(&self) -> bool {
+        match self {
+            Self::Text(text) => text.is_empty(),
+            Self::List(items) => items.is_empty(),
+            Self::Flag(on) => !on,
+        }
+    }
+}
+
+/// Разделы действующей конфигурации по порядку; пустые значения опускаются при выводе.
+fn sections(config: &Config) -> Vec<(&'static str, Section)> {
+    fn list<T: ToString>(items: &[T]) -> Section {
+        Section::List(items.iter().map(ToString::to_string).collect())
+    }
+    let text = Section::Text;
+    let flag = Section::Flag;
+    let optional = |value: Option<String>| Section::Text(value.unwrap_or_default());
+    let menu = |actions: &[menu::Action]| {
+        Section::List(actions.iter().map(|a| a.name().to_string()).collect())
+    };
+    vec![
+        ("config-version", text(CONFIG_VERSION.to_string())),
+        ("iface", list(&config.ifaces)),
+        ("attach-mode", text(config.attach_mode.as_str().to_string())),
+        ("direction", text(config.direction.as_str().to_string())),
+        ("reattach-iface", flag(config.reattach_iface)),
+        ("watchdog", flag(config.watchdog)),
+        ("watchdog-interval", optional(config.watchdog_interval.map(|secs| secs.to_string()))),
+        ("allowed-ports", list(&config.allowed_ports)),
+        ("blocked-src-ports", list(&config.blocked_src_ports)),
+        ("port-match", text(config.port_match.as_str().to_string())),
+        ("policy", text(config.policy.as_str().to_string())),
+        (
+            "blocked-ips",
+            Section::List(
+                config
+                    .blocked_ips
+                    .iter()
+                    .map(ToString::to_string)
+                    .chain(config.blocked_ips6.iter().map(ToString::to_string))
+                    .collect(),
+            ),
+        ),
+        ("blocked-masks", list(&config.blocked_masks)),
+        ("blocked-macs", list(&config.blocked_macs)),
+        ("blocked-countries", list(&config.blocked_countries)),
+        ("blocked-countries-window", optional(config.blocked_countries_window.map(format_window))),
+        ("country-db", optional(config.country_db.as_ref().map(|p| p.display().to_string()))),
+        ("region-db", optional(config.region_db.as_ref().map(|p| p.display().to_string()))),
+        ("blocked-regions", list(&config.blocked_regions)),
+        ("blocked-endpoints", list(&config.blocked_endpoints)),
+        ("endpoint-match", text(config.endpoint_match.as_str().to_string())),
+        ("allowed-ips", list(&config.allowed_ips)),
+        ("fast-accept-prefixes", list(&config.fast_accept_prefixes)),
+        ("protected-ips", list(&config.protected_ips)),
+        ("unwrap-ipip", flag(config.unwrap_ipip)),
+        ("allow-icmp-echo", flag(config.allow_icmp_echo)),
+        ("allow-invalid-tcp-flags", flag(config.allow_invalid_tcp_flags)),
+        ("strict-protocols", flag(config.strict_protocols)),
+        ("protocols", list(&config.protocols)),
+        ("knock-port", optional(config.knock_port.map(|port| port.to_string()))),
+        ("knock-sequence", list(&config.knock_sequence)),
+        ("knock-timeout", optional(config.knock_timeout.map(|secs| secs.to_string()))),
+        ("filter-multicast", flag(config.filter_multicast)),
+        ("drop-fragments", flag(config.drop_fragments)),
+        ("allow-dns", optional(config.allow_dns.map(|on| if on { "yes" } else { "no" }.into()))),
+        ("conntrack-timeout", optional(config.conntrack_timeout.map(|secs| secs.to_string()))),
+        ("grace-period", optional(config.grace_period.map(|secs| secs.to_string()))),
+        ("arp-subnet", optional(config.arp_subnet.map(|net| net.to_string()))),
+        ("dry-run", flag(config.dry_run)),
+        ("fast-path", flag(config.fast_path)),
+        ("block-action", text(config.block_action.as_str().to_string())),
+        ("rate-limit", optional(config.rate_limit.map(|rate| rate.to_string()))),
+        ("rate-burst", optional(config.rate_burst.map(|burst| burst.to_string()))),
+        ("syn-rate-limit", optional(config.syn_rate_limit.map(|rate| rate.to_string()))),
+        ("syn-rate-burst", optional(config.syn_rate_burst.map(|burst| burst.to_string()))),
+        ("sweep-interval", optional(config.sweep_interval.map(|secs| secs.to_string()))),
+        ("sweep-ttl", optional(config.sweep_ttl.map(|secs| secs.to_string()))),
+        ("log-file", optional(config.log_file.as_ref().map(|p| p.display().to_string()))),
+        ("log-max-size", optional(config.log_max_size.map(|size| size.to_string()))),
+        ("log-level", text(config.log_level.as_str().to_string())),
+        ("log-backend", text(config.log_backend.as_str().to_string())),
+        ("log-format", text(config.log_format.as_str().to_string())),
+        ("syslog", flag(config.syslog)),
+        ("syslog-facility", text(config.syslog_facility.as_str().to_string())),
+        ("block-tcp-window", list(&config.blocked_tcp_windows)),
+        ("event-fields", list(&config.event_fields)),
+        ("menu-order", menu(&config.menu_order)),
+        ("menu-hidden", menu(&config.menu_hidden)),
+        ("escalator", text(config.escalator.as_str().to_string())),
+    ]
+}
+
+/// Текст `config.cfg` с теми же правилами, что в `config`: пустые списки и выключенные
+/// флаги опускаются, переменные уже раскрыты.
+pub fn render(config: &Config) -> String {
+    sections(config)
+        .into_iter()
+        .filter(|(_, section)| !section.is_empty())
+        .map(|(key, section)| {
+            let value = match section {
+                Section::Text(text) => text,
+                Section::List(items) => items.join(", "),
+                Section::Flag(_) => "yes".to_string(),
+            };
+            format!("\"{key}\"\n{value}\n")
+        })
+        .collect()
+}
+
+/// Та же конфигурация, что в [`render`], в синтаксисе `config.toml`: списки — массивами,
+/// флаги — `true`, числа без кавычек.
+pub fn render_toml(config: &Config) -> String {
+    sections(config)
+        .into_iter()
+        .filter(|(_, section)| !section.is_empty())
+        .map(|(key, section)| {
+            let value = match section {
+                // Один интерфейс пишется строкой, как в шаблоне.
+                Section::List(items) if key == "iface" && items.len() == 1 => {
+                    toml::scalar(&items[0])
+                }
+                Section::List(items) => {
+                    let items: Vec<String> = items.iter().map(|item| toml::scalar(item)).collect();
+                    format!("[{}]", items.join(", "))
+                }
+                Section::Text(text) => toml::scalar(&text),
+                Section::Flag(_) => "true".to_string(),
+            };
+            format!("{} = {value}\n", toml::key_name(key))
+        })
+        .collect()
+}
+
+/// Та же конфигурация, что в [`render`], объектом JSON для пакета правил: списки —
+/// массивами строк, флаги — `true`. `config-version` в пакет пишется отдельно.
+pub fn render_json(config: &Config) -> serde_json::Map<String, serde_json::Value> {
+    sections(config)
+        .into_iter()
+        .filter(|(key, section)| *key != "config-version" && !section.is_empty())
+        .map(|(key, section)| {
+            let value = match section {
+                Section::Text(text) => serde_json::Value::from(text),
+                Section::List(items) => serde_json::Value::from(items),
+                Section::Flag(on) => serde_json::Value::from(on),
+            };
+            (key.to_string(), value)
+        })
+        .collect()
+}
+
+/// Что делать при записи, если файл конфигурации — символическая ссылка (`--config-symlink`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum SymlinkPolicy {
+    /// Записать в файл, на который указывает ссылка; сама ссылка остаётся на месте.
+    #[default]
+    Follow,
+    /// Отказаться от записи: файлом управляет кто-то другой.
+    Refuse,
+}
+
+/// Записывает `content` в `path` целиком или не записывает вовсе.
+///
+/// Новое содержимое пишется во временный файл рядом и переименовывается поверх старого,
+/// поэтому прерванная запись не оставит половину файла. Переименование поверх ссылки
+/// заменило бы саму ссылку, поэтому ссылка сначала разрешается (или запись отклоняется,
+/// смотря по `symlinks`), и временный файл создаётся рядом с настоящим файлом.
+pub fn write(path: &Path, content: &str, symlinks: SymlinkPolicy) -> io::Result<()> {
+    let is_link = fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_symlink());
+    let target = match (is_link, symlinks) {
+        (false, _) => path.to_path_buf(),
+        (true, SymlinkPolicy::Follow) => fs::canonicalize(path)?,
+        (true, SymlinkPolicy::Refuse) => {
+            let target = fs::read_link(path)?;
+            return Err(io::Error::other(format!(
+                "{} — ссылка на {}, запись отключена (--config-symlink refuse)",
+                path.display(),
+                target.display()
+            )));
+        }
+    };
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    let temp = target.with_file_name(format!(".{name}.tmp"));
+    fs::write(&temp, content)?;
+    if let Ok(meta) = fs::metadata(&target) {
+        fs::set_permissions(&temp, meta.permissions())?;
+    }
+    fs::rename(&temp, &target).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+/// Создаёт `path` с содержимым `content`, если такого файла ещё нет.
+///
+/// Как и в [`write`], файл появляется сразу целиком: содержимое пишется во временный файл,
+/// который затем получает имя `path` жёсткой ссылкой. В отличие от переименования, ссылка
+/// не заменяет существующий файл, даже пустой или созданный одновременно другим запуском:
+/// тогда возвращается [`io::ErrorKind::AlreadyExists`] и файл остаётся как был.
+pub fn create(path: &Path, content: &str) -> io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(".{name}.{}.tmp", std::process::id()));
+    fs::write(&temp, content)?;
+    let linked = fs::hard_link(&temp, path);
+    let _ = fs::remove_file(&temp);
+    linked
+}
+
+/// Резервная копия файла конфигурации: `config.cfg.bak` рядом с `config.cfg`.
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".bak");
+    PathBuf::from(name)
+}
+
+/// Содержимое файла конфигурации; если файла нет, пустая строка. Любая другая ошибка
+/// чтения возвращается: пустое содержимое вместо недоступного файла означало бы запуск
+/// без правил или запись поверх них.
+pub fn read(path: &Path) -> io::Result<String> {
+    match fs::read_to_string(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        result => result,
+    }
+}
+
+/// Сохраняет текущее содержимое `path` в [`backup_path`] перед правкой из меню. Хранится
+/// только последняя копия; если самого файла ещё нет, копировать нечего.
+pub fn backup(path: &Path) -> io::Result<()> {
+    match fs::read_to_string(path) {
+        Ok(content) => fs::write(backup_path(path), content),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Версия формата файла конфигурации; файл без ключа `config-version` имеет версию 1.
+pub const CONFIG_VERSION: u32 = 3;
+
+/// Обновляет файл до [`CONFIG_VERSION`] и возвращает описания внесённых изменений; пустой
+/// список — файл уже в текущей версии. Обновляется только `config.cfg`: `config.toml`
+/// появился уже в третьей версии.
+///
+/// Во второй версии порт источника 53 (DNS) больше не разрешён программой XDP безусловно, а
+/// задаётся в `allowed-ports`. Чтобы поведение не изменилось, он добавляется в старые файлы
+/// один раз, после этого его можно удалить.
+///
+/// В третьей версии `allowed-ports` сравниваются с портом назначения. Старые файлы писались
+/// в расчёте на порт источника, поэтому в них добавляется `port-match: src`.
+pub fn migrate(path: &Path, symlinks: SymlinkPolicy) -> io::Result<Vec<&'static str>> {
+    let content = fs::read_to_string(path)?;
+    let parsed = entries(&content);
+    let version = match parsed.iter().find(|entry| entry.key == "config-version") {
+        Some(entry) => entry.value.parse().unwrap_or(CONFIG_VERSION),
+        None => 1,
+    };
+    if version >= CONFIG_VERSION {
+        return Ok(Vec::new());
+    }
+
+    let mut notes = Vec::new();
+    let mut updated = content.clone();
+    if version < 2 {
+        let ports = parsed.iter().find(|entry| entry.key == "allowed-ports").map(|e| e.value);
+        updated = match ports {
+            Some(ports) if list(ports).any(|port| port == "53") => updated,
+            Some("") | None => set_value(&updated, "allowed-ports", "53"),
+            Some(ports) => set_value(&updated, "allowed-ports", &format!("{ports}, 53")),
+        };
+        notes.push("порт 53 (DNS) теперь явно указан в allowed-ports, его можно удалить");
+    }
+    if version < 3 && !has_key(&updated, "port-match") {
+        updated = set_value(&updated, "port-match", "src");
+        notes.push(
+            "добавлен port-match: src, чтобы allowed-ports по-прежнему сравнивались с портом \
+             источника; новое значение по умолчанию — dst",
+        );
+    }
+    if has_key(&updated, "config-version") {
+        updated = set_value(&updated, "config-version", &CONFIG_VERSION.to_string());
+    } else {
+        updated = format!("\"config-version\"\n{CONFIG_VERSION}\n{updated}");
+    }
+    if content.ends_with('\n') && !updated.ends_with('\n') {
+        updated.push('\n');
+    }
+    write(path, &updated, symlinks)?;
+    Ok(notes)
+}
+
+/// Объединяет конфигурации в порядке следования.
+///
+/// Списки объединяются без повторов. Интерфейсы не объединяются и не переопределяются
+/// молча: если два источника задают разные списки, это конфликт с указанием обоих файлов.
+pub fn merge(sources: Vec<(PathBuf, Config)>) -> Result<Config, Vec<ConfigError>> {
+    let mut merged = Config::default();
+    let mut iface_source: Option<PathBuf> = None;
+    let mut errors = Vec::new();
+
+    for (path, config) in sources {
+        if !config.ifaces.is_empty() {
+            match &iface_source {
+                Some(first) if merged.ifaces != config.ifaces => {
+                    errors.push(
+                        ConfigError::new(
+                            0,
+                            "iface",
+                            format!(
+                                "конфликт: '{}' здесь и '{}' в {}",
+                                config.ifaces.join(", "),
+                                merged.ifaces.join(", "),
+                                first.display()
+                            ),
+                        )
+                        .in_file(&path),
+                    );
+                }
+                Some(_) => {}
+                None => {
+                    merged.ifaces = config.ifaces;
+                    iface_source = Some(path.clone());
+                }
+            }
+        }
+        for port in config.allowed_ports {
+            push_unique(&mut merged.allowed_ports, port);
+        }
+        for port in config.blocked_src_ports {
+            push_unique(&mut merged.blocked_src_ports, port);
+        }
+        for network in config.blocked_ips {
+            push_unique(&mut merged.blocked_ips, network);
+        }
+        for network in config.blocked_ips6 {
+            push_unique(&mut merged.blocked_ips6, network);
+        }
+        for rule in config.blocked_masks {
+            push_unique(&mut merged.blocked_masks, rule);
+        }
+        for mac in config.blocked_macs {
+            push_unique(&mut merged.blocked_macs, mac);
+        }
+        for country in config.blocked_countries {
+            push_unique(&mut merged.blocked_countries, country);
+        }
+        if config.country_db.is_some() {
+            merged.country_db = config.country_db;
+        }
+        if config.region_db.is_some() {
+            merged.region_db = config.region_db;
+        }
+        for region in config.blocked_regions {
+            push_unique(&mut merged.blocked_regions, region);
+        }
+        for endpoint in config.blocked_endpoints {
+            push_unique(&mut merged.blocked_endpoints, endpoint);
+        }
+        if config.attach_mode != AttachMode::default() {
+            merged.attach_mode = config.attach_mode;
+        }
+        if config.direction != Direction::default() {
+            merged.direction = config.direction;
+        }
+        merged.reattach_iface |= config.reattach_iface;
+        merged.watchdog |= config.watchdog;
+        if config.watchdog_interval.is_some() {
+            merged.watchdog_interval = config.watchdog_interval;
+        }
+        if config.port_match != PortMatch::default() {
+            merged.port_match = config.port_match;
+        }
+        if config.policy != DefaultPolicy::default() {
+            merged.policy = config.policy;
+        }
+        if config.endpoint_match != EndpointMatch::default() {
+            merged.endpoint_match = config.endpoint_match;
+        }
+        for ip in config.allowed_ips {
+            push_unique(&mut merged.allowed_ips, ip);
+        }
+        for network in config.fast_accept_prefixes {
+            push_unique(&mut merged.fast_accept_prefixes, network);
+        }
+        for network in config.protected_ips {
+            push_unique(&mut merged.protected_ips, network);
+        }
+        merged.unwrap_ipip |= config.unwrap_ipip;
+        merged.allow_icmp_echo |= config.allow_icmp_echo;
+        merged.allow_invalid_tcp_flags |= config.allow_invalid_tcp_flags;
+        merged.strict_protocols |= config.strict_protocols;
+        // Правило из каталога правил заменяет правило основного файла для того же протокола.
+        for rule in config.protocols {
+            merged.protocols.retain(|r| r.proto != rule.proto);
+            merged.protocols.push(rule);
+        }
+        if config.knock_port.is_some() {
+            merged.knock_port = config.knock_port;
+        }
+        // Последовательность не сливается: из каталога правил она заменяет основную целиком.
+        if !config.knock_sequence.is_empty() {
+            merged.knock_sequence = config.knock_sequence;
+        }
+        if config.knock_timeout.is_some() {
+            merged.knock_timeout = config.knock_timeout;
+        }
+        merged.filter_multicast |= config.filter_multicast;
+        merged.drop_fragments |= config.drop_fragments;
+        merged.dry_run |= config.dry_run;
+        merged.fast_path |= config.fast_path;
+        if config.block_action != BlockAction::default() {
+            merged.block_action = config.block_action;
+        }
+        if config.blocked_countries_window.is_some() {
+            merged.blocked_countries_window = config.blocked_countries_window;
+        }
+        if config.arp_subnet.is_some() {
+            merged.arp_subnet = config.arp_subnet;
+        }
+        if config.allow_dns.is_some() {
+            merged.allow_dns = config.allow_dns;
+        }
+        if config.conntrack_timeout.is_some() {
+            merged.conntrack_timeout = config.conntrack_timeout;
+        }
+        if config.grace_period.is_some() {
+            merged.grace_period = config.grace_period;
+        }
+        if config.rate_limit.is_some() {
+            merged.rate_limit = config.rate_limit;
+        }
+        if config.rate_burst.is_some() {
+            merged.rate_burst = config.rate_burst;
+        }
+        if config.syn_rate_limit.is_some() {
+            merged.syn_rate_limit = config.syn_rate_limit;
+        }
+        if config.syn_rate_burst.is_some() {
+            merged.syn_rate_burst = config.syn_rate_burst;
+        }
+        if config.sweep_interval.is_some() {
+            merged.sweep_interval = config.sweep_interval;
+        }
+        if config.sweep_ttl.is_some() {
+            merged.sweep_ttl = config.sweep_ttl;
+        }
+        if config.log_file.is_some() {
+            merged.log_file = config.log_file;
+        }
+        if config.log_max_size.is_some() {
+            merged.log_max_size = config.log_max_size;
+        }
+        if config.log_level != LogLevel::default() {
+            merged.log_level = config.log_level;
+        }
+        if config.log_backend != LogBackend::default() {
+            merged.log_backend = config.log_backend;
+        }
+        if config.log_format != LogFormat::default() {
+            merged.log_format = config.log_format;
+        }
+        merged.syslog |= config.syslog;
+        if config.syslog_facility != SyslogFacility::default() {
+            merged.syslog_facility = config.syslog_facility;
+        }
+        if config.escalator != Escalator::default() {
+            merged.escalator = config.escalator;
+        }
+        for window in config.blocked_tcp_windows {
+            push_unique(&mut merged.blocked_tcp_windows, window);
+        }
+        for field in config.event_fields {
+            push_unique(&mut merged.event_fields, field);
+        }
+        if !config.menu_order.is_empty() {
+            merged.menu_order = config.menu_order;
+        }
+        for action in config.menu_hidden {
+            push_unique(&mut merged.menu_hidden, action);
+        }
+    }
+    // Программа XDP перебирает эти правила для каждого пакета, их число ограничено.
+    if merged.blocked_masks.len() > MAX_BLOCKED_MASKS {
+        errors.push(ConfigError::new(
+            0,
+            "blocked-masks",
+            format!(
+                "{} правил, программа проверяет не больше {MAX_BLOCKED_MASKS}",
+                merged.blocked_masks.len()
+            ),
+        ));
+    }
+
+    if errors.is_empty() {
+        Ok(merged)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Читает все файлы каталога правил в лексическом порядке имён.
+///
+/// Скрытые файлы и подкаталоги пропускаются. Каждый файл проверяется отдельно, ошибки всех
+/// файлов возвращаются вместе.
+pub fn load_rules_dir(dir: &Path) -> Result<Vec<(PathBuf, Config)>, Vec<ConfigError>> {
+    let read_error = |e: std::io::Error| {
+        vec![ConfigError::new(0, "", format!("не удалось прочитать каталог: {e}")).in_file(dir)]
+    };
+
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).map_err(read_error)? {
+        let entry = entry.map_err(read_error)?;
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if !hidden && entry.path().is_file() {
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+
+    let mut configs = Vec::new();
+    let mut errors = Vec::new();
+    for path in paths {
+        match Config::load(&path) {
+            Ok(config) => configs.push((path, config)),
+            Err(file_errors) => errors.extend(file_errors),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(configs)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Основной файл конфигурации, дополненный правилами из `rules_dir`, если он задан.
+pub fn load_effective(path: &Path, rules_dir: Option<&Path>) -> Result<Config, Vec<ConfigError>> {
+    let mut sources = vec![(path.to_path_buf(), Config::load(path)?)];
+    if let Some(dir) = rules_dir {
+        sources.extend(load_rules_dir(dir)?);
+    }
+    let config = merge(sources)?;
+    let conflicts = crate::conflicts::errors(&config);
+    if conflicts.is_empty() {
+        Ok(config)
+    } else {
+        Err(conflicts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use firewall_common::{classify::Verdict, DropReason};
+
+    use super::*;
+
+    #[test]
+    fn malformed_configs_keep_keys_aligned() {
+        // Ключ без значения в конце файла.
+        let config = Config::parse("\"iface\"\neth0\n\"allowed-ports\"").unwrap();
+        assert_eq!(config.ifaces, ["eth0"]);
+        assert!(config.allowed_ports.is_empty());
+
+        // Ключ без значения перед другим ключом не забирает его себе.
+        let config = Config::parse("\"blocked-ips\"\n\"blocked-countries\"\nCN\n").unwrap();
+        assert!(config.blocked_ips.is_empty());
+        assert_eq!(config.blocked_countries, ["CN"]);
+
+        // Строка вне пар пропускается, а ошибка указывает на строку своего ключа.
+        let content = "\"iface\"\neth0\nмусор\n\"allowed-ports\"\n\n\"blocked-ips\"\n10.0.0.300\n";
+        let errors = Config::parse(content).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].key.as_str(), errors[0].line), ("blocked-ips", 6));
+        assert!(errors[0].message.contains("'10.0.0.300'"));
+    }
+
+    #[test]
+    fn invalid_ports_and_addresses_are_rejected_by_value() {
+        for token in ["99999", "abc", "-1", "80/sctp", "443-80", ""] {
+            let error = parse_allowed_port(token).unwrap_err();
+            assert!(error.contains(&format!("'{token}'")), "{token}: {error}");
+        }
+        for token in ["not.an.ip", "10.0.0.0/33", "300.1.1.1", "10.0.0.0/x", "::1/129"] {
+            let error = parse_blocked_ip(token).unwrap_err();
+            assert!(error.contains(&format!("'{token}'")), "{token}: {error}");
+        }
+
+        let content = "\"allowed-ports\"\n99999, abc\n\"blocked-ips\"\nnot.an.ip\n";
+        let errors = Config::parse(content).unwrap_err();
+        let found: Vec<_> = errors.iter().map(|e| (e.key.as_str(), e.line)).collect();
+        assert_eq!(found, [("allowed-ports", 1), ("allowed-ports", 1), ("blocked-ips", 3)]);
+    }
+
+    /// Конфигурация, которую `ensure_config_exists` писала до `config.toml`, байт в байт.
+    const LEGACY_DEFAULT: &str =
+        "\"iface\"\neth0\n\"allowed-ports\"\n80, 443\n\"blocked-ips\"\n\n\"blocked-countries\"\n";
+
+    #[test]
+    fn default_template_variants_parse_alike() {
+        let expected = Config::parse(LEGACY_DEFAULT).unwrap();
+        let trimmed = LEGACY_DEFAULT.trim_end_matches('\n');
+        let collapsed = LEGACY_DEFAULT.replace("\"blocked-ips\"\n\n", "\"blocked-ips\"\n");
+        for variant in [trimmed, collapsed.as_str()] {
+            let keys: Vec<_> = entries(variant).iter().map(|e| (e.key, e.value)).collect();
+            assert_eq!(keys[2..], [("blocked-ips", ""), ("blocked-countries", "")]);
+            assert_eq!(Config::parse(variant).unwrap(), expected);
+        }
+
+        // Шаблон, который пишет `ensure_config_exists` сейчас, в формате config.cfg.
+        let rendered = render(&Config::parse_toml(&toml_template()).unwrap());
+        let config = Config::parse(&rendered).unwrap();
+        assert_eq!(config, Config::parse_toml(&toml_template()).unwrap());
+    }
+
+    /// Каталог правил с файлами `files` во временном каталоге.
+    fn rules_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("firewall-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (file, content) in files {
+            fs::write(dir.join(file), content).unwrap();
+        }
+        dir
+    }
+
+    fn ports(config: &Config) -> Vec<u16> {
+        config.allowed_ports.iter().map(|p| p.port).collect()
+    }
+
+    #[test]
+    fn comments_and_whitespace_do_not_shift_pairs() {
+        let content = "# интерфейс\n  \" iface \"  \n\n# внешний\n   eth0   \n\
+            # порты\n\"allowed-ports\"\n    # только веб\n80, 443\n\n\
+            \"blocked-ips\"\n\t# пусто\n\n\"blocked-countries\"\n# CN\n";
+        let config = Config::parse(content).unwrap();
+        assert_eq!(config, Config::parse(LEGACY_DEFAULT).unwrap());
+
+        // Закомментированное значение не становится значением раздела.
+        let found: Vec<_> = entries(content).iter().map(|e| (e.key, e.value, e.line)).collect();
+        assert_eq!(
+            found,
+            [
+                ("iface", "eth0", 2),
+                ("allowed-ports", "80, 443", 7),
+                ("blocked-ips", "", 11),
+                ("blocked-countries", "", 14),
+            ]
+        );
+
+        // Запись заменяет строку значения, а не комментарий перед ней.
+        let updated = set_value(content, "allowed-ports", "22");
+        assert!(updated.contains("    # только веб\n22\n"));
+        assert_eq!(ports(&Config::parse(&updated).unwrap()), [22]);
+    }
+
+    #[test]
+    fn rules_dir_files_merge_in_lexical_order() {
+        let dir = rules_dir(
+            "rules-merge",
+            &[
+                ("20-ssh.cfg", "\"allowed-ports\"\n22, 80\n"),
+                ("10-web.cfg", "\"iface\"\neth0\n\"allowed-ports\"\n80, 443\n"),
+                (".hidden.cfg", "\"iface\"\neth9\n"),
+            ],
+        );
+        let sources = load_rules_dir(&dir).unwrap();
+        let names: Vec<_> = sources.iter().map(|(path, _)| path.file_name().unwrap()).collect();
+        assert_eq!(names, ["10-web.cfg", "20-ssh.cfg"]);
+
+        let merged = merge(sources).unwrap();
+        assert_eq!(merged.ifaces, ["eth0"]);
+        assert_eq!(ports(&merged), [80, 443, 22]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn conflict_across_rule_files_names_both_files() {
+        let dir = rules_dir(
+            "rules-conflict",
+            &[("10-a.cfg", "\"iface\"\neth0\n"), ("20-b.cfg", "\"iface\"\neth1\n")],
+        );
+        let errors = merge(load_rules_dir(&dir).unwrap()).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].key, "iface");
+        assert_eq!(errors[0].file, Some(dir.join("20-b.cfg")));
+        assert!(errors[0].message.contains(&dir.join("10-a.cfg").display().to_string()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Решение для пакета на `port` (UDP или SYN TCP) при `allowed-ports` и без `allow-dns`.
+    fn port_verdict(allowed_ports: &str, port: u16, udp: bool) -> Option<Verdict> {
+        let pairs = [
+            ("allowed-ports".to_string(), allowed_ports.to_string()),
+            ("allow-dns".to_string(), "no".to_string()),
+        ];
+        let config = Config::parse_values(&pairs).unwrap();
+        let src = Ipv4Addr::new(198, 51, 100, 7);
+        let frame = crate::selftest::frame(src, Ipv4Addr::new(192, 0, 2, 1), port, udp);
+        crate::replay::frame_verdict(&config, &Default::default(), &frame)
+    }
+
+    fn dns_verdict(allowed_ports: &str) -> Option<Verdict> {
+        port_verdict(allowed_ports, 53, true)
+    }
+
+    #[test]
+    fn protocol_qualified_ports_allow_only_their_protocol() {
+        let tcp = |port| AllowedPort { port, last: port, proto: Some(PortProto::Tcp) };
+        let udp = |port| AllowedPort { port, last: port, proto: Some(PortProto::Udp) };
+        assert_eq!(parse_allowed_port("443/tcp"), Ok(tcp(443)));
+        assert_eq!(parse_allowed_port("53/UDP"), Ok(udp(53)));
+        assert_eq!(parse_allowed_port("80"), Ok(AllowedPort { port: 80, last: 80, proto: None }));
+        assert!(parse_allowed_port("80/sctp").is_err());
+
+        let ports = "443/tcp, 53/udp, 80";
+        let denied = Some(Verdict::Drop(DropReason::PortNotAllowed));
+        assert_eq!(port_verdict(ports, 443, false), Some(Verdict::Pass));
+        assert_eq!(port_verdict(ports, 443, true), denied);
+        assert_eq!(port_verdict(ports, 53, true), Some(Verdict::Pass));
+        assert_eq!(port_verdict(ports, 53, false), denied);
+        assert_eq!(port_verdict(ports, 80, false), Some(Verdict::Pass));
+        assert_eq!(port_verdict(ports, 80, true), Some(Verdict::Pass));
+    }
+
+    #[test]
+    fn removing_53_from_allowed_ports_blocks_dns() {
+        assert_eq!(dns_verdict("22, 53"), Some(Verdict::Pass));
+        assert_eq!(dns_verdict("22, 53/udp"), Some(Verdict::Pass));
+        assert_eq!(dns_verdict("22"), Some(Verdict::Drop(DropReason::PortNotAllowed)));
+        assert_eq!(dns_verdict("22, 53/tcp"), Some(Verdict::Drop(DropReason::PortNotAllowed)));
+    }
+
+    #[test]
+    fn default_config_has_all_four_sections() {
+        let found: Vec<_> = entries(LEGACY_DEFAULT).iter().map(|e| (e.key, e.value)).collect();
+        assert_eq!(
+            found,
+            [
+                ("iface", "eth0"),
+                ("allowed-ports", "80, 443"),
+                ("blocked-ips", ""),
+                ("blocked-countries", ""),
+            ]
+        );
+
+        let config = Config::parse(LEGACY_DEFAULT).unwrap();
+        assert_eq!(config.ifaces, ["eth0"]);
+        assert_eq!(ports(&config), [80, 443]);
+        assert!(config.blocked_ips.is_empty());
+        assert!(config.blocked_countries.is_empty());
+
+        // Пустой последний раздел получает значение, а не ещё один ключ.
+        let updated = set_value(LEGACY_DEFAULT, "blocked-countries", "CN");
+        assert_eq!(Config::parse(&updated).unwrap().blocked_countries, ["CN"]);
+    }
+
+    #[test]
+    fn endpoint_rules_parse_host_and_cidr_forms() {
+        let config = Config::parse_values(&[(
+            "blocked-endpoints".to_string(),
+            "1.2.3.4:443, 10.0.0.0/8:22".to_string(),
+        )])
+        .unwrap();
+        let host = Endpoint { network: "1.2.3.4/32".parse().unwrap(), port: 443 };
+        let net = Endpoint { network: "10.0.0.0/8".parse().unwrap(), port: 22 };
+        assert_eq!(config.blocked_endpoints, [host, net]);
+        assert_eq!(config.endpoint_match, EndpointMatch::Src);
+        assert_eq!(host.to_string(), "1.2.3.4:443");
+        assert_eq!(net.to_string(), "10.0.0.0/8:22");
+
+        let pairs = [("endpoint-match".to_string(), "dst".to_string())];
+        assert_eq!(Config::parse_values(&pairs).unwrap().endpoint_match, EndpointMatch::Dst);
+    }
+
+    #[test]
+    fn write_through_symlink_keeps_link_and_updates_target() {
+        let dir = rules_dir("symlink", &[("managed.cfg", "\"iface\"\neth0\n")]);
+        let target = dir.join("managed.cfg");
+        let link = dir.join("config.cfg");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        write(&link, "\"iface\"\neth1\n", SymlinkPolicy::Follow).unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_link(&link).unwrap(), target);
+        assert_eq!(fs::read_to_string(&target).unwrap(), "\"iface\"\neth1\n");
+        assert!(!dir.join(".managed.cfg.tmp").exists());
+
+        let error = write(&link, "\"iface\"\neth2\n", SymlinkPolicy::Refuse).unwrap_err();
+        assert!(error.to_string().contains("--config-symlink refuse"));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "\"iface\"\neth1\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rewrite_preserves_unknown_key() {
+        let content = "\"iface\"\neth0\n\"owner\"\nops-team\n\"allowed-ports\"\n22\n";
+        let warnings = unknown_keys(Path::new("config.cfg"), content);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!((warnings[0].key.as_str(), warnings[0].line), ("owner", 3));
+
+        let updated = set_scalar(Path::new("config.cfg"), content, "iface", "eth1");
+        assert_eq!(raw_value(&updated, "owner"), Some("ops-team"));
+        assert_eq!(raw_value(&updated, "iface"), Some("eth1"));
+
+        let toml = "iface = \"eth0\"\nowner = \"ops-team\"\n";
+        let updated = set_scalar(Path::new("config.toml"), toml, "iface", "eth1");
+        assert_eq!(updated, "iface = \"eth1\"\nowner = \"ops-team\"\n");
+
+        // Миграция переписывает файл целиком, но тоже не трогает чужой ключ.
+        let dir = rules_dir("unknown-keys", &[("config.cfg", content)]);
+        let path = dir.join("config.cfg");
+        assert!(!migrate(&path, SymlinkPolicy::Follow).unwrap().is_empty());
+        let migrated = fs::read_to_string(&path).unwrap();
+        assert_eq!(raw_value(&migrated, "owner"), Some("ops-team"));
+        assert_eq!(raw_value(&migrated, "config-version"), Some(&*CONFIG_VERSION.to_string()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn variables_are_resolved_before_validation() {
+        let content = "@mgmt_net = 10.0.0.0/24\n@web = 80, 443\n\
+                       \"fast-accept-prefixes\"\n@mgmt_net\n\
+                       \"protected-ips\"\n@mgmt_net, 192.0.2.0/24\n\
+                       \"allowed-ports\"\n22, @web\n";
+        let config = Config::parse(content).unwrap();
+        let mgmt: Ipv4Network = "10.0.0.0/24".parse().unwrap();
+        assert_eq!(config.fast_accept_prefixes, [mgmt]);
+        assert_eq!(config.protected_ips, [mgmt, "192.0.2.0/24".parse().unwrap()]);
+        assert_eq!(ports(&config), [22, 80, 443]);
+
+        let errors = Config::parse("\"allowed-ips\"\n@office\n").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].key.as_str(), errors[0].line), ("allowed-ips", 1));
+        assert!(errors[0].message.contains("'@office' не определена"));
+    }
+
+    #[test]
+    fn rule_file_errors_are_reported_per_file() {
+        let dir = rules_dir(
+            "rules-errors",
+            &[("10-a.cfg", "\"allowed-ports\"\nhttp\n"), ("20-b.cfg", "\"iface\"\neth0\n")],
+        );
+        let errors = load_rules_dir(&dir).unwrap_err();
+        assert!(!errors.is_empty());
+        assert!(errors.iter().all(|e| e.file == Some(dir.join("10-a.cfg"))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
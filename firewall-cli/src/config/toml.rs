@@ -0,0 +1,206 @@
+//! `config.toml`: та же конфигурация в синтаксисе TOML.
+//!
+//! Файл разбирает крейт `toml` через serde. Значения — строки, целые числа, `true`/`false`
+//! и массивы из них; таблицы (`[раздел]`) не поддерживаются. Подчёркивания в ключах
+//! равнозначны дефисам `config.cfg`: `allowed_ports` — это `allowed-ports`.
+//!
+//! Значения переводятся в строку того же вида, что значение раздела `config.cfg` (массив —
+//! элементы через запятую, `true` — `yes`), и проверяются тем же [`Config::parse`] кодом.
+//!
+//! [`Config::parse`]: super::Config::parse
+
+use std::{collections::BTreeMap, fmt, ops::Range};
+
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use toml::Spanned;
+
+use super::ConfigError;
+
+/// Пара `ключ = значение` из файла.
+#[derive(Debug)]
+pub struct Item {
+    /// Имя ключа в виде `config.cfg`, с дефисами.
+    pub key: String,
+    /// Значение в виде значения раздела `config.cfg`.
+    pub value: String,
+    /// Номер строки ключа, начиная с 1.
+    pub line: usize,
+    /// Байты значения в тексте, для замены в [`set_value`].
+    span: Range<usize>,
+}
+
+/// Значение ключа верхнего уровня в виде значения раздела `config.cfg`.
+struct Flat(String);
+
+struct FlatVisitor {
+    nested: bool,
+}
+
+impl<'de> Visitor<'de> for FlatVisitor {
+    type Value = Flat;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("строка в кавычках, целое число, true, false или массив из них")
+    }
+
+    fn visit_bool<E: de::Error>(self, value: bool) -> Result<Flat, E> {
+        Ok(Flat(if value { "yes" } else { "no" }.to_string()))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<Flat, E> {
+        Ok(Flat(value.to_string()))
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<Flat, E> {
+        Ok(Flat(value.to_string()))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Flat, E> {
+        Ok(Flat(value.to_string()))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Flat, A::Error> {
+        if self.nested {
+            return Err(de::Error::custom("вложенные массивы не поддерживаются"));
+        }
+        let mut items = Vec::new();
+        while let Some(Nested(item)) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(Flat(items.join(", ")))
+    }
+
+    fn visit_map<A: de::MapAccess<'de>>(self, _map: A) -> Result<Flat, A::Error> {
+        Err(de::Error::custom(
+            "таблицы TOML не поддерживаются, все ключи пишутся на верхнем уровне",
+        ))
+    }
+}
+
+impl<'de> Deserialize<'de> for Flat {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Flat, D::Error> {
+        deserializer.deserialize_any(FlatVisitor { nested: false })
+    }
+}
+
+/// Элемент массива: то же, что [`Flat`], но без массивов.
+struct Nested(String);
+
+impl<'de> Deserialize<'de> for Nested {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Nested, D::Error> {
+        let Flat(text) = deserializer.deserialize_any(FlatVisitor { nested: true })?;
+        Ok(Nested(text))
+    }
+}
+
+/// Номер строки байта `offset` текста, начиная с 1.
+fn line_at(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+/// Разбирает текст `config.toml` на пары в порядке файла; ошибка синтаксиса останавливает
+/// разбор.
+pub fn items(text: &str) -> Result<Vec<Item>, ConfigError> {
+    let table: BTreeMap<String, Spanned<Flat>> = toml::from_str(text).map_err(|e| {
+        let line = e.span().map_or(0, |span| line_at(text, span.start));
+        ConfigError::new(line, "", e.message().trim_end().replace('\n', ": "))
+    })?;
+    let mut items: Vec<Item> = Vec::with_capacity(table.len());
+    for (name, value) in table {
+        let key = name.replace('_', "-");
+        let span = value.span();
+        let line = line_at(text, span.start);
+        if items.iter().any(|item| item.key == key) {
+            return Err(ConfigError::new(line, &name, "ключ задан повторно"));
+        }
+        let Flat(value) = value.into_inner();
+        items.push(Item { key, value, line, span });
+    }
+    items.sort_by_key(|item| item.span.start);
+    Ok(items)
+}
+
+/// Имя ключа в виде TOML, с подчёркиваниями, для сообщений об ошибках.
+pub fn key_name(key: &str) -> String {
+    key.replace('-', "_")
+}
+
+/// Строка TOML в двойных кавычках.
+pub fn string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Значение раздела `config.cfg` в виде TOML: целое число без кавычек, остальное — строкой.
+pub fn scalar(text: &str) -> String {
+    if !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit()) {
+        text.to_string()
+    } else {
+        string(text)
+    }
+}
+
+/// Массив строк TOML.
+pub fn array<'a>(items: impl IntoIterator<Item = &'a str>) -> String {
+    let items: Vec<String> = items.into_iter().map(string).collect();
+    format!("[{}]", items.join(", "))
+}
+
+/// Заменяет значение ключа `key` (в виде `config.cfg`) на `literal` — значение, уже
+/// записанное в синтаксисе TOML, — или дописывает пару в конец. Остальной текст, включая
+/// комментарии, не меняется. Текст с ошибкой синтаксиса возвращается как есть.
+pub fn set_value(content: &str, key: &str, literal: &str) -> String {
+    let Ok(items) = items(content) else {
+        return content.to_string();
+    };
+    match items.iter().find(|item| item.key == key) {
+        Some(item) => format!(
+            "{}{literal}{}",
+            &content[..item.span.start],
+            &content[item.span.end..]
+        ),
+        None => {
+            let separator = if content.is_empty() || content.ends_with('\n') { "" } else { "\n" };
+            format!("{content}{separator}{} = {literal}\n", key_name(key))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn items_flatten_values_in_file_order() {
+        let text = "# интерфейс\niface = \"eth0\"\nallowed_ports = [\n  80,\n  \"443/tcp\",\n]\n\
+                    dry_run = true\n";
+        let items = items(text).unwrap();
+        let pairs: Vec<(&str, &str, usize)> =
+            items.iter().map(|item| (item.key.as_str(), item.value.as_str(), item.line)).collect();
+        assert_eq!(
+            pairs,
+            [("iface", "eth0", 2), ("allowed-ports", "80, 443/tcp", 3), ("dry-run", "yes", 7)]
+        );
+    }
+
+    #[test]
+    fn tables_and_bad_syntax_are_errors_with_a_line() {
+        let error = items("iface = \"eth0\"\n[limits]\nrate = 5\n").unwrap_err();
+        assert!(error.message.contains("таблицы"), "{}", error.message);
+        assert_eq!(items("iface = \"eth0\"\nallowed_ports = [[80]]\n").unwrap_err().line, 2);
+        assert_eq!(items("iface = \"eth0\"\niface = \"eth1\"\n").unwrap_err().line, 2);
+        assert_eq!(items("a = 1\nb = \n").unwrap_err().line, 2);
+    }
+
+    #[test]
+    fn set_value_keeps_the_rest_of_the_text() {
+        let text = "iface = \"eth0\" # uplink\nallowed_ports = [80]\n";
+        assert_eq!(
+            set_value(text, "allowed-ports", &array(["80", "443"])),
+            "iface = \"eth0\" # uplink\nallowed_ports = [\"80\", \"443\"]\n"
+        );
+        assert_eq!(
+            set_value(text, "dry-run", "true"),
+            format!("{text}dry_run = true\n")
+        );
+    }
+}
@@ -0,0 +1,204 @@
+//! Противоречия между правилами конфигурации.
+//!
+//! Каждое значение по отдельности может быть верным, а вместе они перечёркивают друг друга.
+//! Ошибки — записи, одна из которых никогда не сработает: с ними конфигурация не
+//! загружается. Предупреждения — сочетания, которые обычно означают ошибку, но могут быть
+//! и намеренными; их показывают `check` и запуск файрволла из firewall-cli.
+
+use firewall_common::MAX_KNOCK_PORTS;
+
+use crate::config::{
+    AllowedPort, Config, ConfigError, DefaultPolicy, LogBackend, LogLevel, PortMatch,
+};
+
+/// Есть ли у двух записей портов общие порты для общего протокола.
+fn ports_overlap(a: &AllowedPort, b: &AllowedPort) -> bool {
+    let protos = a.proto.is_none() || b.proto.is_none() || a.proto == b.proto;
+    protos && a.port <= b.last && b.port <= a.last
+}
+
+/// Пары разрешённых портов и заблокированных портов источника с общими портами.
+fn port_pairs(config: &Config) -> impl Iterator<Item = (&AllowedPort, &AllowedPort)> {
+    config.allowed_ports.iter().flat_map(move |allowed| {
+        config
+            .blocked_src_ports
+            .iter()
+            .filter(move |blocked| ports_overlap(allowed, blocked))
+            .map(move |blocked| (allowed, blocked))
+    })
+}
+
+/// Противоречия, с которыми конфигурация не загружается.
+pub fn errors(config: &Config) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    for ip in &config.allowed_ips {
+        if let Some(net) = config.blocked_ips.iter().find(|net| net.contains(*ip)) {
+            errors.push(ConfigError::new(
+                0,
+                "allowed-ips",
+                format!(
+                    "{ip} заблокирован в blocked-ips ({net}): исключения снимают только \
+                     блокировку стран и регионов, адрес всё равно будет отброшен"
+                ),
+            ));
+        }
+    }
+    for blocked in &config.blocked_ips {
+        let trusted = config.fast_accept_prefixes.iter().find(|fast| blocked.is_subnet_of(**fast));
+        if let Some(fast) = trusted {
+            errors.push(ConfigError::new(
+                0,
+                "blocked-ips",
+                format!(
+                    "{blocked} целиком входит в fast-accept-prefixes ({fast}): доверенные \
+                     префиксы пропускаются до всех правил, блокировка не сработает"
+                ),
+            ));
+        }
+    }
+    if config.port_match == PortMatch::Src {
+        for (allowed, blocked) in port_pairs(config) {
+            errors.push(ConfigError::new(
+                0,
+                "allowed-ports",
+                format!(
+                    "{allowed} пересекается с blocked-src-ports {blocked}: с port-match src \
+                     оба сравниваются с портом источника, и блокировка проверяется раньше"
+                ),
+            ));
+        }
+    }
+    errors.extend(knock_errors(config));
+    errors
+}
+
+/// Чем опасна конфигурация, в которой при policy deny не разрешён ни один порт: запуск с
+/// ней отрезает и сеанс SSH, через который его запустили. `None` — порты есть или policy
+/// allow.
+pub fn no_open_ports(config: &Config) -> Option<String> {
+    if !config.allowed_ports.is_empty() || config.policy != DefaultPolicy::Deny {
+        return None;
+    }
+    let except = if config.allows_dns() { ", кроме DNS," } else { "" };
+    Some(format!(
+        "список пуст, а policy deny: весь входящий трафик TCP и UDP{except} будет отброшен"
+    ))
+}
+
+/// Стук, который никогда не откроет порт или который программа не примет.
+fn knock_errors(config: &Config) -> Vec<ConfigError> {
+    let error = |key: &str, message: String| ConfigError::new(0, key, message);
+    let sequence = &config.knock_sequence;
+    let Some(port) = config.knock_port else {
+        if sequence.is_empty() {
+            return Vec::new();
+        }
+        return vec![error("knock-sequence", "стучать некуда: knock-port не задан".to_string())];
+    };
+    let mut errors = Vec::new();
+    if sequence.is_empty() {
+        errors.push(error(
+            "knock-port",
+            format!("knock-sequence пуста: порт {port} не откроется никогда"),
+        ));
+    }
+    if sequence.len() > MAX_KNOCK_PORTS as usize {
+        errors.push(error(
+            "knock-sequence",
+            format!("{} портов, программа помнит не больше {MAX_KNOCK_PORTS}", sequence.len()),
+        ));
+    }
+    if sequence.contains(&port) {
+        errors.push(error(
+            "knock-sequence",
+            format!("содержит сам knock-port {port}: пакет к нему не засчитывается как стук"),
+        ));
+    }
+    // Повтор только что засчитанного порта считается повторной отправкой, а не шагом.
+    if let Some(pair) = sequence.windows(2).find(|pair| pair[0] == pair[1]) {
+        errors.push(error(
+            "knock-sequence",
+            format!("порт {} дважды подряд: второй стук не засчитывается", pair[0]),
+        ));
+    }
+    if port == 0 || sequence.contains(&0) {
+        errors.push(error("knock-port", "порт 0 не бывает в пакетах TCP и UDP".to_string()));
+    }
+    errors
+}
+
+/// Сочетания, о которых стоит предупредить, но которые не мешают запуску.
+pub fn warnings(config: &Config) -> Vec<ConfigError> {
+    let mut warnings = Vec::new();
+    if config.port_match == PortMatch::Dst {
+        for (allowed, blocked) in port_pairs(config) {
+            warnings.push(ConfigError::new(
+                0,
+                "allowed-ports",
+                format!(
+                    "{allowed} разрешён как порт назначения, а blocked-src-ports {blocked} \
+                     блокирует его как порт источника: ответы с этого порта отбрасываются"
+                ),
+            ));
+        }
+    }
+    if let Some(message) = no_open_ports(config) {
+        warnings.push(ConfigError::new(0, "allowed-ports", message));
+    }
+    if config.log_level == LogLevel::Verbose && config.log_backend == LogBackend::Events {
+        warnings.push(ConfigError::new(
+            0,
+            "log-level",
+            "verbose не действует с log-backend events: подробности разбора пишет только aya-log"
+                .to_string(),
+        ));
+    }
+    if let Some(knock) = config.knock_port {
+        let open = config.allowed_ports.iter().find(|p| p.ports().contains(&knock));
+        if let Some(allowed) = open {
+            warnings.push(ConfigError::new(
+                0,
+                "allowed-ports",
+                format!(
+                    "{allowed} включает knock-port {knock}: порт за стуком всё равно закрыт, \
+                     пока источник не простучит knock-sequence"
+                ),
+            ));
+        }
+    }
+    if config.watchdog_interval.is_some() && !config.watchdog {
+        warnings.push(ConfigError::new(
+            0,
+            "watchdog-interval",
+            "задан, но watchdog выключен: привязка программы не проверяется".to_string(),
+        ));
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_allowed_ports_under_deny_is_refused() {
+        let parse = |content: &str| Config::parse(content).unwrap();
+
+        let config = parse("\"allowed-ports\"\n\n\"policy\"\ndeny\n");
+        let message = no_open_ports(&config).unwrap();
+        assert!(message.contains("кроме DNS"), "{message}");
+        assert!(warnings(&config).iter().any(|w| w.key == "allowed-ports"));
+
+        let config = parse("\"allowed-ports\"\n\n\"policy\"\ndeny\n\"allow-dns\"\nno\n");
+        let message = no_open_ports(&config).unwrap();
+        assert!(!message.contains("DNS"), "{message}");
+
+        // Порты из --ports попадают в allowed-ports до проверки.
+        let mut config = parse("\"allowed-ports\"\n\n\"policy\"\ndeny\n");
+        config.allowed_ports = parse("\"allowed-ports\"\n22\n").allowed_ports;
+        assert_eq!(no_open_ports(&config), None);
+
+        assert_eq!(no_open_ports(&parse("\"allowed-ports\"\n\n\"policy\"\nallow\n")), None);
+        assert_eq!(no_open_ports(&parse("\"allowed-ports\"\n22\n\"policy\"\ndeny\n")), None);
+    }
+}
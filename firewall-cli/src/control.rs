@@ -0,0 +1,210 @@
+//! Управление запущенным файрволлом через закреплённую карту `SETTINGS`.
+
+use std::path::Path;
+
+use anyhow::Context as _;
+use aya::maps::{Array, Map, MapData};
+use clap::ValueEnum;
+use firewall_common::{mode, settings, PIN_PATH, SETTINGS_MAP};
+
+use crate::audit;
+
+/// Открывает карту настроек; `Ok(None)` — файрволл не запущен.
+pub fn open_settings() -> anyhow::Result<Option<Array<MapData, u32>>> {
+    let path = Path::new(PIN_PATH).join(SETTINGS_MAP);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = MapData::from_pin(&path).with_context(|| format!("не удалось открыть {path:?}"))?;
+    Ok(Some(Array::try_from(Map::Array(data))?))
+}
+
+/// Текущий режим программы, одно из значений [`mode`]; `Ok(None)` — файрволл не запущен.
+pub fn current_mode() -> anyhow::Result<Option<u32>> {
+    match open_settings()? {
+        Some(map) => Ok(Some(map.get(&settings::MODE, 0)?)),
+        None => Ok(None),
+    }
+}
+
+/// Название режима для `status`.
+pub fn mode_name(value: u32) -> &'static str {
+    match value {
+        mode::ENFORCE => "фильтрация",
+        mode::COUNT_ONLY => "только подсчёт",
+        mode::PAUSED => "пауза (firewall-cli resume возобновит фильтрацию)",
+        mode::DRY_RUN => "пробный (dry-run): пакеты не отбрасываются",
+        _ => "неизвестный",
+    }
+}
+
+/// Включён ли аварийный режим; `Ok(None)` — файрволл не запущен.
+pub fn panic_active() -> anyhow::Result<Option<bool>> {
+    match open_settings()? {
+        Some(map) => Ok(Some(map.get(&settings::PANIC, 0)? != 0)),
+        None => Ok(None),
+    }
+}
+
+/// Предупреждение, которое видно, пока включён аварийный режим.
+pub const PANIC_BANNER: &str = "!!! АВАРИЙНЫЙ РЕЖИМ: отбрасывается весь трафик, кроме \
+                                fast-accept-prefixes и ARP (firewall-cli panic off) !!!";
+
+/// Карта настроек запущенного файрволла; `None` — не запущен или не открылась, об этом
+/// уже сказано.
+fn running_settings() -> Option<Array<MapData, u32>> {
+    match open_settings() {
+        Ok(Some(map)) => Some(map),
+        Ok(None) => {
+            println!("Файрволл не запущен.");
+            None
+        }
+        Err(e) => {
+            println!("Не удалось открыть настройки: {e:#}");
+            None
+        }
+    }
+}
+
+fn record(action: &str) {
+    if let Err(e) = audit::record(action) {
+        println!("Не удалось записать {action} в {}: {e}", audit::AUDIT_LOG);
+    }
+}
+
+/// Режим после команды `action`, которая переводит программу из `from` в `to`; ошибка —
+/// программа сейчас в режиме `current`, и команда к нему неприменима.
+fn transition(current: u32, from: u32, to: u32, action: &str) -> Result<u32, String> {
+    if current == from {
+        Ok(to)
+    } else {
+        Err(format!("Сейчас режим «{}», команда {action} неприменима.", mode_name(current)))
+    }
+}
+
+/// Переключает режим из `from` в `to` и записывает это в журнал аудита.
+fn switch(from: u32, to: u32, action: &str) -> i32 {
+    let Some(mut map) = running_settings() else {
+        return 1;
+    };
+    let current = match map.get(&settings::MODE, 0) {
+        Ok(current) => current,
+        Err(e) => {
+            println!("Не удалось прочитать режим: {e}");
+            return 1;
+        }
+    };
+    let to = match transition(current, from, to, action) {
+        Ok(to) => to,
+        Err(message) => {
+            println!("{message}");
+            return 1;
+        }
+    };
+    if let Err(e) = map.set(settings::MODE, to, 0) {
+        println!("Не удалось изменить режим: {e}");
+        return 1;
+    }
+    record(action);
+    println!("Режим: {}.", mode_name(to));
+    0
+}
+
+/// `firewall-cli pause`: пропускать весь трафик, не выгружая программу и карты.
+pub fn pause() -> i32 {
+    switch(mode::ENFORCE, mode::PAUSED, "pause")
+}
+
+/// `firewall-cli resume`: вернуть фильтрацию после `pause`.
+pub fn resume() -> i32 {
+    switch(mode::PAUSED, mode::ENFORCE, "resume")
+}
+
+/// Состояние для `firewall-cli panic`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Switch {
+    On,
+    Off,
+}
+
+/// `firewall-cli panic`: включает или выключает аварийный режим.
+///
+/// Без доверенных префиксов аварийный режим отрезал бы и того, кто его включает, поэтому
+/// тогда нужен `force`.
+pub fn panic(state: Switch, force: bool) -> i32 {
+    let Some(mut map) = running_settings() else {
+        return 1;
+    };
+    let read = |index| map.get(&index, 0);
+    let (active, trusted) = match (read(settings::PANIC), read(settings::FAST_ACCEPT)) {
+        (Ok(active), Ok(trusted)) => (active != 0, trusted != 0),
+        (Err(e), _) | (_, Err(e)) => {
+            println!("Не удалось прочитать настройки: {e}");
+            return 1;
+        }
+    };
+    let on = state == Switch::On;
+    if active == on {
+        println!("Аварийный режим уже {}.", if on { "включён" } else { "выключен" });
+        return 0;
+    }
+    if on && !trusted && !force {
+        println!(
+            "fast-accept-prefixes пуст: аварийный режим отбросит весь трафик, включая это \
+             подключение. Если это и нужно, повторите с --force."
+        );
+        return 1;
+    }
+    if let Err(e) = map.set(settings::PANIC, u32::from(on), 0) {
+        println!("Не удалось изменить режим: {e}");
+        return 1;
+    }
+    if on {
+        record("panic on");
+        println!("{PANIC_BANNER}");
+    } else {
+        record("panic off");
+        println!("Аварийный режим выключен, правила снова действуют.");
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use firewall_common::{classify::Verdict, DropReason};
+
+    use super::*;
+    use crate::{config::Config, replay, selftest};
+
+    #[test]
+    fn pause_passes_blocked_packet_and_resume_enforces() {
+        let pairs = [
+            ("blocked-ips".to_string(), "198.51.100.7".to_string()),
+            ("allowed-ports".to_string(), "22".to_string()),
+        ];
+        let config = Config::parse_values(&pairs).unwrap();
+        let src = Ipv4Addr::new(198, 51, 100, 7);
+        let frame = selftest::frame(src, Ipv4Addr::new(192, 0, 2, 1), 22, false);
+        let verdict = |current| {
+            if mode::passes_all(current) {
+                Some(Verdict::Pass)
+            } else {
+                replay::frame_verdict(&config, &Default::default(), &frame)
+            }
+        };
+        let blocked = Some(Verdict::Drop(DropReason::BlockedIp));
+        assert_eq!(verdict(mode::ENFORCE), blocked);
+
+        let paused = transition(mode::ENFORCE, mode::ENFORCE, mode::PAUSED, "pause").unwrap();
+        assert_eq!(paused, mode::PAUSED);
+        assert_eq!(verdict(paused), Some(Verdict::Pass));
+        assert!(transition(paused, mode::ENFORCE, mode::PAUSED, "pause").is_err());
+
+        let resumed = transition(paused, mode::PAUSED, mode::ENFORCE, "resume").unwrap();
+        assert_eq!(verdict(resumed), blocked);
+        let error = transition(resumed, mode::PAUSED, mode::ENFORCE, "resume").unwrap_err();
+        assert!(error.contains("фильтрация"), "{error}");
+    }
+}
@@ -0,0 +1,409 @@
+//! Встроенная таблица стран для `blocked-countries`.
+
+/// Сколько похожих названий предлагать для неизвестной страны.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Находит код alpha-2 по названию страны без учёта регистра.
+///
+/// Для неизвестного названия ошибка содержит до `MAX_SUGGESTIONS` похожих, для
+/// неоднозначного — все подходящие страны.
+pub fn resolve(input: &str) -> Result<&'static str, String> {
+    let wanted = input.trim().to_lowercase();
+    let matches: Vec<&(&str, &[&str])> = COUNTRIES
+        .iter()
+        .filter(|(_, names)| names.iter().any(|n| n.to_lowercase() == wanted))
+        .collect();
+
+    match matches.as_slice() {
+        [(code, _)] => Ok(code),
+        [] => {
+            let suggestions = suggest(&wanted);
+            if suggestions.is_empty() {
+                Err(format!("неизвестная страна '{input}'"))
+            } else {
+                Err(format!("неизвестная страна '{input}', возможно: {}", suggestions.join(", ")))
+            }
+        }
+        many => {
+            let options: Vec<String> = many
+                .iter()
+                .map(|(code, names)| format!("{code} ({})", names[0]))
+                .collect();
+            Err(format!(
+                "название '{input}' неоднозначно, укажите код: {}",
+                options.join(", ")
+            ))
+        }
+    }
+}
+
+/// Есть ли код во встроенной таблице; регистр важен, коды пишутся заглавными.
+pub fn is_code(code: &str) -> bool {
+    COUNTRIES.iter().any(|(known, _)| *known == code)
+}
+
+/// Основное название страны по коду.
+pub fn name(code: &str) -> Option<&'static str> {
+    COUNTRIES.iter().find(|(known, _)| *known == code).map(|(_, names)| names[0])
+}
+
+/// Ближайшие по расстоянию редактирования названия в виде «Название (КОД)».
+///
+/// Допускается примерно одна ошибка на три буквы, чтобы короткий ввод не совпадал со всем подряд.
+fn suggest(wanted: &str) -> Vec<String> {
+    let max_distance = (wanted.chars().count() / 3).max(1);
+    let mut scored: Vec<(usize, &str, &str)> = COUNTRIES
+        .iter()
+        .filter_map(|(code, names)| {
+            let best = names
+                .iter()
+                .map(|n| distance(wanted, &n.to_lowercase()))
+                .min()?;
+            (best <= max_distance).then_some((best, names[0], *code))
+        })
+        .collect();
+    scored.sort();
+    scored
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, name, code)| format!("{name} ({code})"))
+        .collect()
+}
+
+/// Расстояние Левенштейна по символам.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Коды ISO 3166-1 alpha-2 с названиями стран на английском.
+///
+/// Первое название — основное, остальные принимаются как синонимы. Одно и то же название
+/// может стоять у нескольких стран (например, «Korea»), тогда оно считается неоднозначным.
+pub const COUNTRIES: &[(&str, &[&str])] = &[
+    ("AD", &["Andorra", "Principality of Andorra"]),
+    ("AE", &["United Arab Emirates", "UAE"]),
+    ("AF", &["Afghanistan", "Islamic Republic of Afghanistan"]),
+    ("AG", &["Antigua and Barbuda"]),
+    ("AI", &["Anguilla"]),
+    ("AL", &["Albania", "Republic of Albania"]),
+    ("AM", &["Armenia", "Republic of Armenia"]),
+    ("AO", &["Angola", "Republic of Angola"]),
+    ("AQ", &["Antarctica"]),
+    ("AR", &["Argentina", "Argentine Republic"]),
+    ("AS", &["American Samoa"]),
+    ("AT", &["Austria", "Republic of Austria"]),
+    ("AU", &["Australia"]),
+    ("AW", &["Aruba"]),
+    ("AX", &["Åland Islands", "Aland Islands"]),
+    ("AZ", &["Azerbaijan", "Republic of Azerbaijan"]),
+    ("BA", &["Bosnia and Herzegovina", "Republic of Bosnia and Herzegovina"]),
+    ("BB", &["Barbados"]),
+    ("BD", &["Bangladesh", "People's Republic of Bangladesh"]),
+    ("BE", &["Belgium", "Kingdom of Belgium"]),
+    ("BF", &["Burkina Faso"]),
+    ("BG", &["Bulgaria", "Republic of Bulgaria"]),
+    ("BH", &["Bahrain", "Kingdom of Bahrain"]),
+    ("BI", &["Burundi", "Republic of Burundi"]),
+    ("BJ", &["Benin", "Republic of Benin"]),
+    ("BL", &["Saint Barthélemy", "Saint Barthelemy"]),
+    ("BM", &["Bermuda"]),
+    ("BN", &["Brunei Darussalam", "Brunei"]),
+    ("BO", &["Bolivia", "Bolivia, Plurinational State of", "Plurinational State of Bolivia"]),
+    ("BQ", &["Bonaire, Sint Eustatius and Saba"]),
+    ("BR", &["Brazil", "Federative Republic of Brazil"]),
+    ("BS", &["Bahamas", "Commonwealth of the Bahamas"]),
+    ("BT", &["Bhutan", "Kingdom of Bhutan"]),
+    ("BV", &["Bouvet Island"]),
+    ("BW", &["Botswana", "Republic of Botswana"]),
+    ("BY", &["Belarus", "Republic of Belarus"]),
+    ("BZ", &["Belize"]),
+    ("CA", &["Canada"]),
+    ("CC", &["Cocos (Keeling) Islands"]),
+    (
+        "CD",
+        &[
+            "Democratic Republic of the Congo",
+            "Congo, The Democratic Republic of the",
+            "DR Congo",
+            "Congo",
+        ],
+    ),
+    ("CF", &["Central African Republic"]),
+    ("CG", &["Congo", "Republic of the Congo"]),
+    ("CH", &["Switzerland", "Swiss Confederation"]),
+    ("CI", &["Côte d'Ivoire", "Republic of Côte d'Ivoire", "Cote d'Ivoire", "Ivory Coast"]),
+    ("CK", &["Cook Islands"]),
+    ("CL", &["Chile", "Republic of Chile"]),
+    ("CM", &["Cameroon", "Republic of Cameroon"]),
+    ("CN", &["China", "People's Republic of China"]),
+    ("CO", &["Colombia", "Republic of Colombia"]),
+    ("CR", &["Costa Rica", "Republic of Costa Rica"]),
+    ("CU", &["Cuba", "Republic of Cuba"]),
+    ("CV", &["Cabo Verde", "Republic of Cabo Verde", "Cape Verde"]),
+    ("CW", &["Curaçao", "Curacao"]),
+    ("CX", &["Christmas Island"]),
+    ("CY", &["Cyprus", "Republic of Cyprus"]),
+    ("CZ", &["Czechia", "Czech Republic"]),
+    ("DE", &["Germany", "Federal Republic of Germany"]),
+    ("DJ", &["Djibouti", "Republic of Djibouti"]),
+    ("DK", &["Denmark", "Kingdom of Denmark"]),
+    ("DM", &["Dominica", "Commonwealth of Dominica"]),
+    ("DO", &["Dominican Republic"]),
+    ("DZ", &["Algeria", "People's Democratic Republic of Algeria"]),
+    ("EC", &["Ecuador", "Republic of Ecuador"]),
+    ("EE", &["Estonia", "Republic of Estonia"]),
+    ("EG", &["Egypt", "Arab Republic of Egypt"]),
+    ("EH", &["Western Sahara"]),
+    ("ER", &["Eritrea", "the State of Eritrea"]),
+    ("ES", &["Spain", "Kingdom of Spain"]),
+    ("ET", &["Ethiopia", "Federal Democratic Republic of Ethiopia"]),
+    ("FI", &["Finland", "Republic of Finland"]),
+    ("FJ", &["Fiji", "Republic of Fiji"]),
+    ("FK", &["Falkland Islands (Malvinas)", "Falkland Islands"]),
+    ("FM", &["Micronesia, Federated States of", "Federated States of Micronesia", "Micronesia"]),
+    ("FO", &["Faroe Islands"]),
+    ("FR", &["France", "French Republic"]),
+    ("GA", &["Gabon", "Gabonese Republic"]),
+    (
+        "GB",
+        &[
+            "United Kingdom",
+            "United Kingdom of Great Britain and Northern Ireland",
+            "UK",
+            "Great Britain",
+            "Britain",
+        ],
+    ),
+    ("GD", &["Grenada"]),
+    ("GE", &["Georgia"]),
+    ("GF", &["French Guiana"]),
+    ("GG", &["Guernsey"]),
+    ("GH", &["Ghana", "Republic of Ghana"]),
+    ("GI", &["Gibraltar"]),
+    ("GL", &["Greenland"]),
+    ("GM", &["Gambia", "Republic of the Gambia"]),
+    ("GN", &["Guinea", "Republic of Guinea"]),
+    ("GP", &["Guadeloupe"]),
+    ("GQ", &["Equatorial Guinea", "Republic of Equatorial Guinea"]),
+    ("GR", &["Greece", "Hellenic Republic"]),
+    ("GS", &["South Georgia and the South Sandwich Islands"]),
+    ("GT", &["Guatemala", "Republic of Guatemala"]),
+    ("GU", &["Guam"]),
+    ("GW", &["Guinea-Bissau", "Republic of Guinea-Bissau"]),
+    ("GY", &["Guyana", "Republic of Guyana"]),
+    ("HK", &["Hong Kong", "Hong Kong Special Administrative Region of China"]),
+    ("HM", &["Heard Island and McDonald Islands"]),
+    ("HN", &["Honduras", "Republic of Honduras"]),
+    ("HR", &["Croatia", "Republic of Croatia"]),
+    ("HT", &["Haiti", "Republic of Haiti"]),
+    ("HU", &["Hungary"]),
+    ("ID", &["Indonesia", "Republic of Indonesia"]),
+    ("IE", &["Ireland"]),
+    ("IL", &["Israel", "State of Israel"]),
+    ("IM", &["Isle of Man"]),
+    ("IN", &["India", "Republic of India"]),
+    ("IO", &["British Indian Ocean Territory"]),
+    ("IQ", &["Iraq", "Republic of Iraq"]),
+    ("IR", &["Iran", "Iran, Islamic Republic of", "Islamic Republic of Iran"]),
+    ("IS", &["Iceland", "Republic of Iceland"]),
+    ("IT", &["Italy", "Italian Republic"]),
+    ("JE", &["Jersey"]),
+    ("JM", &["Jamaica"]),
+    ("JO", &["Jordan", "Hashemite Kingdom of Jordan"]),
+    ("JP", &["Japan"]),
+    ("KE", &["Kenya", "Republic of Kenya"]),
+    ("KG", &["Kyrgyzstan", "Kyrgyz Republic"]),
+    ("KH", &["Cambodia", "Kingdom of Cambodia"]),
+    ("KI", &["Kiribati", "Republic of Kiribati"]),
+    ("KM", &["Comoros", "Union of the Comoros"]),
+    ("KN", &["Saint Kitts and Nevis"]),
+    (
+        "KP",
+        &[
+            "North Korea",
+            "Korea, Democratic People's Republic of",
+            "Democratic People's Republic of Korea",
+            "Korea",
+        ],
+    ),
+    ("KR", &["South Korea", "Korea, Republic of", "Korea"]),
+    ("KW", &["Kuwait", "State of Kuwait"]),
+    ("KY", &["Cayman Islands"]),
+    ("KZ", &["Kazakhstan", "Republic of Kazakhstan"]),
+    ("LA", &["Laos", "Lao People's Democratic Republic"]),
+    ("LB", &["Lebanon", "Lebanese Republic"]),
+    ("LC", &["Saint Lucia"]),
+    ("LI", &["Liechtenstein", "Principality of Liechtenstein"]),
+    ("LK", &["Sri Lanka", "Democratic Socialist Republic of Sri Lanka"]),
+    ("LR", &["Liberia", "Republic of Liberia"]),
+    ("LS", &["Lesotho", "Kingdom of Lesotho"]),
+    ("LT", &["Lithuania", "Republic of Lithuania"]),
+    ("LU", &["Luxembourg", "Grand Duchy of Luxembourg"]),
+    ("LV", &["Latvia", "Republic of Latvia"]),
+    ("LY", &["Libya"]),
+    ("MA", &["Morocco", "Kingdom of Morocco"]),
+    ("MC", &["Monaco", "Principality of Monaco"]),
+    ("MD", &["Moldova", "Moldova, Republic of", "Republic of Moldova"]),
+    ("ME", &["Montenegro"]),
+    ("MF", &["Saint Martin (French part)"]),
+    ("MG", &["Madagascar", "Republic of Madagascar"]),
+    ("MH", &["Marshall Islands", "Republic of the Marshall Islands"]),
+    ("MK", &["North Macedonia", "Republic of North Macedonia", "Macedonia"]),
+    ("ML", &["Mali", "Republic of Mali"]),
+    ("MM", &["Myanmar", "Republic of Myanmar", "Burma"]),
+    ("MN", &["Mongolia"]),
+    ("MO", &["Macao", "Macao Special Administrative Region of China"]),
+    ("MP", &["Northern Mariana Islands", "Commonwealth of the Northern Mariana Islands"]),
+    ("MQ", &["Martinique"]),
+    ("MR", &["Mauritania", "Islamic Republic of Mauritania"]),
+    ("MS", &["Montserrat"]),
+    ("MT", &["Malta", "Republic of Malta"]),
+    ("MU", &["Mauritius", "Republic of Mauritius"]),
+    ("MV", &["Maldives", "Republic of Maldives"]),
+    ("MW", &["Malawi", "Republic of Malawi"]),
+    ("MX", &["Mexico", "United Mexican States"]),
+    ("MY", &["Malaysia"]),
+    ("MZ", &["Mozambique", "Republic of Mozambique"]),
+    ("NA", &["Namibia", "Republic of Namibia"]),
+    ("NC", &["New Caledonia"]),
+    ("NE", &["Niger", "Republic of the Niger"]),
+    ("NF", &["Norfolk Island"]),
+    ("NG", &["Nigeria", "Federal Republic of Nigeria"]),
+    ("NI", &["Nicaragua", "Republic of Nicaragua"]),
+    ("NL", &["Netherlands", "Kingdom of the Netherlands", "Holland"]),
+    ("NO", &["Norway", "Kingdom of Norway"]),
+    ("NP", &["Nepal", "Federal Democratic Republic of Nepal"]),
+    ("NR", &["Nauru", "Republic of Nauru"]),
+    ("NU", &["Niue"]),
+    ("NZ", &["New Zealand"]),
+    ("OM", &["Oman", "Sultanate of Oman"]),
+    ("PA", &["Panama", "Republic of Panama"]),
+    ("PE", &["Peru", "Republic of Peru"]),
+    ("PF", &["French Polynesia"]),
+    ("PG", &["Papua New Guinea", "Independent State of Papua New Guinea"]),
+    ("PH", &["Philippines", "Republic of the Philippines"]),
+    ("PK", &["Pakistan", "Islamic Republic of Pakistan"]),
+    ("PL", &["Poland", "Republic of Poland"]),
+    ("PM", &["Saint Pierre and Miquelon"]),
+    ("PN", &["Pitcairn"]),
+    ("PR", &["Puerto Rico"]),
+    ("PS", &["Palestine, State of", "the State of Palestine", "Palestine"]),
+    ("PT", &["Portugal", "Portuguese Republic"]),
+    ("PW", &["Palau", "Republic of Palau"]),
+    ("PY", &["Paraguay", "Republic of Paraguay"]),
+    ("QA", &["Qatar", "State of Qatar"]),
+    ("RE", &["Réunion", "Reunion"]),
+    ("RO", &["Romania"]),
+    ("RS", &["Serbia", "Republic of Serbia"]),
+    ("RU", &["Russia", "Russian Federation"]),
+    ("RW", &["Rwanda", "Rwandese Republic"]),
+    ("SA", &["Saudi Arabia", "Kingdom of Saudi Arabia"]),
+    ("SB", &["Solomon Islands"]),
+    ("SC", &["Seychelles", "Republic of Seychelles"]),
+    ("SD", &["Sudan", "Republic of the Sudan"]),
+    ("SE", &["Sweden", "Kingdom of Sweden"]),
+    ("SG", &["Singapore", "Republic of Singapore"]),
+    ("SH", &["Saint Helena, Ascension and Tristan da Cunha"]),
+    ("SI", &["Slovenia", "Republic of Slovenia"]),
+    ("SJ", &["Svalbard and Jan Mayen"]),
+    ("SK", &["Slovakia", "Slovak Republic"]),
+    ("SL", &["Sierra Leone", "Republic of Sierra Leone"]),
+    ("SM", &["San Marino", "Republic of San Marino"]),
+    ("SN", &["Senegal", "Republic of Senegal"]),
+    ("SO", &["Somalia", "Federal Republic of Somalia"]),
+    ("SR", &["Suriname", "Republic of Suriname"]),
+    ("SS", &["South Sudan", "Republic of South Sudan"]),
+    ("ST", &["Sao Tome and Principe", "Democratic Republic of Sao Tome and Principe"]),
+    ("SV", &["El Salvador", "Republic of El Salvador"]),
+    ("SX", &["Sint Maarten (Dutch part)"]),
+    ("SY", &["Syria", "Syrian Arab Republic"]),
+    ("SZ", &["Eswatini", "Kingdom of Eswatini", "Swaziland"]),
+    ("TC", &["Turks and Caicos Islands"]),
+    ("TD", &["Chad", "Republic of Chad"]),
+    ("TF", &["French Southern Territories"]),
+    ("TG", &["Togo", "Togolese Republic"]),
+    ("TH", &["Thailand", "Kingdom of Thailand"]),
+    ("TJ", &["Tajikistan", "Republic of Tajikistan"]),
+    ("TK", &["Tokelau"]),
+    ("TL", &["Timor-Leste", "Democratic Republic of Timor-Leste", "East Timor"]),
+    ("TM", &["Turkmenistan"]),
+    ("TN", &["Tunisia", "Republic of Tunisia"]),
+    ("TO", &["Tonga", "Kingdom of Tonga"]),
+    ("TR", &["Türkiye", "Republic of Türkiye", "Turkey"]),
+    ("TT", &["Trinidad and Tobago", "Republic of Trinidad and Tobago"]),
+    ("TV", &["Tuvalu"]),
+    ("TW", &["Taiwan", "Taiwan, Province of China"]),
+    ("TZ", &["Tanzania", "Tanzania, United Republic of", "United Republic of Tanzania"]),
+    ("UA", &["Ukraine"]),
+    ("UG", &["Uganda", "Republic of Uganda"]),
+    ("UM", &["United States Minor Outlying Islands"]),
+    ("US", &["United States", "United States of America", "USA", "America"]),
+    ("UY", &["Uruguay", "Eastern Republic of Uruguay"]),
+    ("UZ", &["Uzbekistan", "Republic of Uzbekistan"]),
+    ("VA", &["Holy See (Vatican City State)", "Vatican", "Vatican City"]),
+    ("VC", &["Saint Vincent and the Grenadines"]),
+    ("VE", &["Venezuela", "Venezuela, Bolivarian Republic of", "Bolivarian Republic of Venezuela"]),
+    ("VG", &["Virgin Islands, British", "British Virgin Islands"]),
+    ("VI", &["Virgin Islands, U.S.", "Virgin Islands of the United States", "US Virgin Islands"]),
+    ("VN", &["Vietnam", "Viet Nam", "Socialist Republic of Viet Nam"]),
+    ("VU", &["Vanuatu", "Republic of Vanuatu"]),
+    ("WF", &["Wallis and Futuna"]),
+    ("WS", &["Samoa", "Independent State of Samoa"]),
+    ("YE", &["Yemen", "Republic of Yemen"]),
+    ("YT", &["Mayotte"]),
+    ("ZA", &["South Africa", "Republic of South Africa"]),
+    ("ZM", &["Zambia", "Republic of Zambia"]),
+    ("ZW", &["Zimbabwe", "Republic of Zimbabwe"]),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    fn blocked_countries(value: &str) -> Result<Vec<String>, String> {
+        let pairs = [("blocked-countries".to_string(), value.to_string())];
+        Config::parse_values(&pairs)
+            .map(|config| config.blocked_countries)
+            .map_err(|errors| errors[0].message.clone())
+    }
+
+    #[test]
+    fn names_resolve_case_insensitively() {
+        assert_eq!(resolve("Russia"), Ok("RU"));
+        assert_eq!(resolve("  russian federation "), Ok("RU"));
+        assert_eq!(blocked_countries("Russia, china").unwrap(), ["RU", "CN"]);
+    }
+
+    #[test]
+    fn misspelling_is_rejected_with_suggestion() {
+        let error = resolve("Rusia").unwrap_err();
+        assert!(error.contains("Russia (RU)"), "{error}");
+        assert!(blocked_countries("Rusia").unwrap_err().contains("Russia (RU)"));
+        assert_eq!(resolve("Qqqqqqqq"), Err("неизвестная страна 'Qqqqqqqq'".to_string()));
+    }
+
+    #[test]
+    fn ambiguous_name_lists_every_country() {
+        let error = resolve("Korea").unwrap_err();
+        assert!(error.contains("KP") && error.contains("KR"), "{error}");
+    }
+
+    #[test]
+    fn code_still_works() {
+        assert!(is_code("RU"));
+        assert_eq!(name("RU"), Some("Russia"));
+        assert_eq!(blocked_countries("RU, DE").unwrap(), ["RU", "DE"]);
+    }
+}
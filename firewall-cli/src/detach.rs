@@ -0,0 +1,225 @@
+//! `firewall-cli detach`: снятие программы XDP, оставшейся на интерфейсе.
+//!
+//! Загрузчик сам отвязывает программу при выходе, но если его убили (`kill -9`,
+//! падение), привязанная через netlink программа продолжает фильтровать трафик. Команда
+//! спрашивает у ядра по rtnetlink, какие программы привязаны к интерфейсу, и снимает их
+//! в каждом режиме (generic, драйвер, сетевая карта).
+
+use std::{
+    ffi::CString,
+    io,
+    os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd},
+};
+
+/// Вложенный атрибут `IFLA_XDP` сообщения о ссылке и его поля (`linux/if_link.h`).
+const IFLA_XDP: u16 = libc::IFLA_XDP;
+const IFLA_XDP_FD: u16 = 1;
+const IFLA_XDP_FLAGS: u16 = 3;
+const IFLA_XDP_DRV_PROG_ID: u16 = 5;
+const IFLA_XDP_SKB_PROG_ID: u16 = 6;
+const IFLA_XDP_HW_PROG_ID: u16 = 7;
+const NLA_F_NESTED: u16 = 1 << 15;
+const NLA_TYPE_MASK: u16 = !(NLA_F_NESTED | 1 << 14);
+
+/// Режимы привязки: флаг `XDP_FLAGS_*`, атрибут с номером программы и название.
+const MODES: [(u32, u16, &str); 3] = [
+    (1 << 1, IFLA_XDP_SKB_PROG_ID, "generic"),
+    (1 << 2, IFLA_XDP_DRV_PROG_ID, "драйвер"),
+    (1 << 3, IFLA_XDP_HW_PROG_ID, "сетевая карта"),
+];
+
+/// Заголовки `nlmsghdr` и `ifinfomsg`, с которых начинается сообщение о ссылке.
+const HEADER_LEN: usize = 16;
+const IFINFO_LEN: usize = 16;
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Дописывает атрибут netlink с выравниванием до 4 байт.
+fn push_attr(buf: &mut Vec<u8>, kind: u16, payload: &[u8]) {
+    let len = 4 + payload.len();
+    buf.extend_from_slice(&(len as u16).to_ne_bytes());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(payload);
+    buf.resize(align(buf.len()), 0);
+}
+
+/// Атрибуты из `data`: тип без флагов и содержимое.
+fn attrs(mut data: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut out = Vec::new();
+    while data.len() >= 4 {
+        let len = usize::from(u16::from_ne_bytes([data[0], data[1]]));
+        let kind = u16::from_ne_bytes([data[2], data[3]]) & NLA_TYPE_MASK;
+        if len < 4 || len > data.len() {
+            break;
+        }
+        out.push((kind, &data[4..len]));
+        data = &data[align(len).min(data.len())..];
+    }
+    out
+}
+
+struct Route {
+    socket: OwnedFd,
+    seq: u32,
+}
+
+impl Route {
+    fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            socket: unsafe { OwnedFd::from_raw_fd(fd) },
+            seq: 0,
+        })
+    }
+
+    /// Отправляет сообщение о ссылке `ifindex` и возвращает атрибуты ответа `RTM_NEWLINK`
+    /// (пустые, если ядро ответило только подтверждением).
+    fn request(
+        &mut self,
+        kind: u16,
+        flags: u16,
+        ifindex: u32,
+        attrs: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        self.seq += 1;
+        let len = HEADER_LEN + IFINFO_LEN + attrs.len();
+        let mut msg = Vec::with_capacity(len);
+        msg.extend_from_slice(&(len as u32).to_ne_bytes());
+        msg.extend_from_slice(&kind.to_ne_bytes());
+        msg.extend_from_slice(&(flags | libc::NLM_F_REQUEST as u16).to_ne_bytes());
+        msg.extend_from_slice(&self.seq.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        // ifinfomsg: семейство, выравнивание, тип устройства, индекс, флаги и их маска.
+        msg.extend_from_slice(&[libc::AF_UNSPEC as u8, 0, 0, 0]);
+        msg.extend_from_slice(&(ifindex as i32).to_ne_bytes());
+        msg.extend_from_slice(&[0; 8]);
+        msg.extend_from_slice(attrs);
+
+        let sent = unsafe { libc::send(self.socket.as_raw_fd(), msg.as_ptr().cast(), len, 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; 32 * 1024];
+        loop {
+            let received = unsafe {
+                libc::recv(self.socket.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0)
+            };
+            if received < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut data = &buf[..received as usize];
+            while data.len() >= HEADER_LEN {
+                let msg_len = u32::from_ne_bytes(data[..4].try_into().unwrap()) as usize;
+                let msg_kind = u16::from_ne_bytes([data[4], data[5]]);
+                let msg_seq = u32::from_ne_bytes(data[8..12].try_into().unwrap());
+                if msg_len < HEADER_LEN || msg_len > data.len() {
+                    break;
+                }
+                let body = &data[HEADER_LEN..msg_len];
+                data = &data[align(msg_len).min(data.len())..];
+                if msg_seq != self.seq {
+                    continue;
+                }
+                if msg_kind == libc::NLMSG_ERROR as u16 && body.len() >= 4 {
+                    let errno = i32::from_ne_bytes(body[..4].try_into().unwrap());
+                    if errno == 0 {
+                        return Ok(Vec::new());
+                    }
+                    return Err(io::Error::from_raw_os_error(-errno));
+                }
+                if msg_kind == libc::RTM_NEWLINK && body.len() >= IFINFO_LEN {
+                    return Ok(body[IFINFO_LEN..].to_vec());
+                }
+            }
+        }
+    }
+
+    /// Программы XDP на интерфейсе: флаг режима, название режима и номер программы.
+    fn attached(&mut self, ifindex: u32) -> io::Result<Vec<(u32, &'static str, u32)>> {
+        let reply = self.request(libc::RTM_GETLINK, 0, ifindex, &[])?;
+        let Some((_, xdp)) = attrs(&reply).into_iter().find(|(kind, _)| *kind == IFLA_XDP) else {
+            return Ok(Vec::new());
+        };
+        let xdp = attrs(xdp);
+        Ok(MODES
+            .iter()
+            .filter_map(|&(flag, attr, name)| {
+                let (_, id) = xdp.iter().find(|(kind, _)| *kind == attr)?;
+                let id = u32::from_ne_bytes((*id).try_into().ok()?);
+                (id != 0).then_some((flag, name, id))
+            })
+            .collect())
+    }
+
+    /// Снимает программу, привязанную в режиме `flag`.
+    fn detach(&mut self, ifindex: u32, flag: u32) -> io::Result<()> {
+        let mut xdp = Vec::new();
+        push_attr(&mut xdp, IFLA_XDP_FD, &(-1i32).to_ne_bytes());
+        push_attr(&mut xdp, IFLA_XDP_FLAGS, &flag.to_ne_bytes());
+        let mut attrs = Vec::new();
+        push_attr(&mut attrs, IFLA_XDP | NLA_F_NESTED, &xdp);
+        self.request(libc::RTM_SETLINK, libc::NLM_F_ACK as u16, ifindex, &attrs)?;
+        Ok(())
+    }
+}
+
+fn ifindex(iface: &str) -> Option<u32> {
+    let name = CString::new(iface).ok()?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    (index != 0).then_some(index)
+}
+
+/// Выполняет `firewall-cli detach`; код выхода 1, если программа осталась на интерфейсе.
+///
+/// Интерфейс, которого уже нет, не ошибка: с ним исчезла и программа.
+pub fn run(iface: &str) -> i32 {
+    let Some(index) = ifindex(iface) else {
+        println!("Интерфейса {iface} нет: привязанной программы XDP на нём тоже нет.");
+        return 0;
+    };
+    let result = Route::open().and_then(|mut route| {
+        let attached = route.attached(index)?;
+        let mut held = 0;
+        for &(flag, mode, id) in &attached {
+            match route.detach(index, flag) {
+                Ok(()) => println!("Программа XDP {id} ({mode}) снята с {iface}."),
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => {
+                    println!(
+                        "Программа XDP {id} ({mode}) привязана через bpf_link: она снимется, \
+                         когда завершится удерживающий её процесс."
+                    );
+                    held += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((attached.len(), held))
+    });
+    match result {
+        Ok((0, _)) => {
+            println!("К {iface} не привязана программа XDP.");
+            0
+        }
+        Ok((_, held)) => i32::from(held > 0),
+        Err(e) if e.raw_os_error() == Some(libc::ENODEV) => {
+            println!("Интерфейс {iface} удалён: привязанной программы XDP на нём больше нет.");
+            0
+        }
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+            println!("Не удалось снять программу XDP с {iface}: нужны права root (CAP_NET_ADMIN).");
+            1
+        }
+        Err(e) => {
+            println!("Не удалось снять программу XDP с {iface}: {e}");
+            1
+        }
+    }
+}
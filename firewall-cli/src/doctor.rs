@@ -0,0 +1,227 @@
+use aya::maps::MapType;
+use firewall_common::probe::{self, Support};
+
+/// Типы карт, которые использует программа XDP, с именами для отчёта.
+const REQUIRED_MAPS: &[(&str, MapType)] = &[
+    ("PerCpuArray (STATS)", MapType::PerCpuArray),
+    ("PerCpuHashMap (PORT_STATS, COUNTRY_STATS, COUNTRY_DROPS)", MapType::PerCpuHash),
+    ("HashMap (BLOCKED_IPS, TCP_WINDOWS)", MapType::Hash),
+    ("LruHashMap (FLOWS)", MapType::LruHash),
+    ("LpmTrie (FAST_ACCEPT)", MapType::LpmTrie),
+    ("Array (SETTINGS)", MapType::Array),
+    ("RingBuf (EVENTS)", MapType::RingBuf),
+];
+
+/// Результаты проб ядра, из которых собирается отчёт.
+#[derive(Debug, Clone)]
+pub struct ProbeResults {
+    pub xdp: Support,
+    pub maps: Vec<(&'static str, Support)>,
+    /// Пакетное обновление карт; без него загрузчик пишет записи по одной.
+    pub map_batch: Support,
+    pub btf: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+/// Одна строка отчёта о готовности.
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: String,
+    pub severity: Severity,
+    pub advice: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// Готово ли ядро к запуску: предупреждения допустимы, ошибки — нет.
+    pub fn ready(&self) -> bool {
+        self.checks.iter().all(|c| c.severity != Severity::Error)
+    }
+}
+
+/// Опрашивает текущее ядро.
+pub fn probe() -> ProbeResults {
+    ProbeResults {
+        xdp: probe::probe_xdp(),
+        maps: REQUIRED_MAPS
+            .iter()
+            .map(|&(name, map_type)| (name, probe::probe_map(map_type)))
+            .collect(),
+        map_batch: probe::probe_map_batch(),
+        btf: probe::probe_btf(),
+    }
+}
+
+fn support_check(name: String, support: Support, unsupported_advice: &str) -> Check {
+    let (severity, advice) = match support {
+        Support::Supported => (Severity::Ok, None),
+        Support::PermissionDenied => (
+            Severity::Error,
+            Some("недостаточно прав: запустите doctor от root (sudo)".to_string()),
+        ),
+        Support::Unsupported(errno) => (
+            Severity::Error,
+            Some(format!("ядро отказало (errno {errno}): {unsupported_advice}")),
+        ),
+    };
+    Check {
+        name,
+        severity,
+        advice,
+    }
+}
+
+/// Собирает отчёт с советами по результатам проб.
+pub fn build_report(results: &ProbeResults) -> Report {
+    let mut checks = vec![support_check(
+        "Программы XDP".to_string(),
+        results.xdp,
+        "нужно ядро 4.8+, собранное с CONFIG_BPF_SYSCALL",
+    )];
+
+    for &(name, support) in &results.maps {
+        checks.push(support_check(
+            format!("Карта {name}"),
+            support,
+            "обновите ядро до версии, поддерживающей этот тип карты",
+        ));
+    }
+
+    checks.push(match results.map_batch {
+        Support::Unsupported(errno) => Check {
+            name: "Пакетное обновление карт".to_string(),
+            severity: Severity::Warning,
+            advice: Some(format!(
+                "ядро отказало (errno {errno}): большие списки будут загружаться по одной записи, \
+                 это медленнее; нужно ядро 5.6+"
+            )),
+        },
+        support => support_check(
+            "Пакетное обновление карт".to_string(),
+            support,
+            "нужно ядро 5.6+",
+        ),
+    });
+
+    checks.push(if results.btf {
+        Check {
+            name: "BTF ядра".to_string(),
+            severity: Severity::Ok,
+            advice: None,
+        }
+    } else {
+        Check {
+            name: "BTF ядра".to_string(),
+            severity: Severity::Warning,
+            advice: Some(format!(
+                "{} не найден: файрволл запустится без логов eBPF. Установите пакет с BTF \
+                 ядра или пересоберите ядро с CONFIG_DEBUG_INFO_BTF=y",
+                probe::VMLINUX_BTF
+            )),
+        }
+    });
+
+    Report { checks }
+}
+
+pub fn format_report(report: &Report) -> String {
+    let mut out = String::from("Проверка готовности ядра\n\n");
+    for check in &report.checks {
+        let mark = match check.severity {
+            Severity::Ok => "[ OK ]",
+            Severity::Warning => "[WARN]",
+            Severity::Error => "[FAIL]",
+        };
+        out.push_str(&format!("{mark} {}\n", check.name));
+        if let Some(advice) = &check.advice {
+            out.push_str(&format!("       {advice}\n"));
+        }
+    }
+    out.push('\n');
+    out.push_str(if report.ready() {
+        "Ядро готово к запуску файрволла.\n"
+    } else {
+        "Файрволл не сможет запуститься на этом ядре, см. советы выше.\n"
+    });
+    out
+}
+
+/// Выполняет `firewall-cli doctor` и возвращает код выхода.
+pub fn run() -> i32 {
+    let report = build_report(&probe());
+    print!("{}", format_report(&report));
+    if report.ready() {
+        0
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn results() -> ProbeResults {
+        ProbeResults {
+            xdp: Support::Supported,
+            maps: REQUIRED_MAPS.iter().map(|&(name, _)| (name, Support::Supported)).collect(),
+            map_batch: Support::Supported,
+            btf: true,
+        }
+    }
+
+    fn severities(report: &Report) -> Vec<(&str, Severity)> {
+        report.checks.iter().map(|check| (check.name.as_str(), check.severity)).collect()
+    }
+
+    #[test]
+    fn supported_kernel_is_ready() {
+        let report = build_report(&results());
+        assert_eq!(report.checks.len(), REQUIRED_MAPS.len() + 3);
+        assert!(report.checks.iter().all(|check| check.severity == Severity::Ok));
+        assert!(report.ready());
+        assert!(format_report(&report).ends_with("Ядро готово к запуску файрволла.\n"));
+    }
+
+    #[test]
+    fn missing_batch_and_btf_only_warn() {
+        let report = build_report(&ProbeResults {
+            map_batch: Support::Unsupported(22),
+            btf: false,
+            ..results()
+        });
+        let checks = severities(&report);
+        assert!(checks.contains(&("Пакетное обновление карт", Severity::Warning)));
+        assert!(checks.contains(&("BTF ядра", Severity::Warning)));
+        assert!(report.ready());
+        assert!(format_report(&report).contains("[WARN] BTF ядра\n"));
+    }
+
+    #[test]
+    fn refused_program_or_map_is_an_error_with_advice() {
+        let mut probed = results();
+        probed.xdp = Support::PermissionDenied;
+        probed.maps[0].1 = Support::Unsupported(95);
+        let report = build_report(&probed);
+        assert!(!report.ready());
+        let xdp = &report.checks[0];
+        assert_eq!(xdp.severity, Severity::Error);
+        assert!(xdp.advice.as_deref().is_some_and(|advice| advice.contains("root")));
+        let map = &report.checks[1];
+        assert_eq!(map.name, format!("Карта {}", REQUIRED_MAPS[0].0));
+        assert!(map.advice.as_deref().is_some_and(|advice| advice.contains("errno 95")));
+        let text = format_report(&report);
+        assert!(text.contains("[FAIL] Программы XDP\n"));
+        assert!(text.ends_with("Файрволл не сможет запуститься на этом ядре, см. советы выше.\n"));
+    }
+}
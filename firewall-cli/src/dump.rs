@@ -0,0 +1,344 @@
+//! `firewall-cli dump-rules`: правила, которые программа применяет сейчас, и их расхождения
+//! с конфигурацией.
+//!
+//! Правила читаются из закреплённых карт так же, как `apply-policy` читает их перед заменой
+//! ([`policy::live`]), а ожидаемые собираются из конфигурации, как их загрузил бы загрузчик
+//! ([`Policy::from_config`]). Обе стороны выводятся одними и теми же строками в синтаксисе
+//! config.cfg, поэтому сравниваются построчно, а вывод можно сверить с файлом глазами.
+//!
+//! Записи `blocked-ips` в картах считают отброшенные по ним пакеты, поэтому здесь же видно,
+//! какие из них ничего не ловят и их можно убрать.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use firewall_common::{
+    block_action, direction, port_protos, protocol_rule, settings, unpack_country, RateState,
+};
+use ipnetwork::{Ipv4Network, Ipv6Network};
+
+use crate::{
+    config::{
+        self, AllowedPort, Config, Endpoint, MacAddress, MaskedIp, PortProto, ProtocolRule,
+    },
+    control,
+    policy::{self, Policy},
+};
+
+/// Пределы и таймауты, которые загрузчик пишет в `SETTINGS` только при запуске: их нет в
+/// [`Policy`], потому что `apply-policy` их не меняет.
+#[derive(Debug)]
+struct Limits {
+    block_action: u32,
+    rate_limit: u32,
+    rate_burst: u32,
+    syn_rate_limit: u32,
+    syn_rate_burst: u32,
+    conntrack_timeout: u32,
+    knock_port: u32,
+    knock_timeout: u32,
+    directions: u32,
+}
+
+/// Ёмкость ведра, как её выбирает загрузчик, если `rate-burst` не задан.
+fn burst_size(rate: u32, burst: Option<u32>) -> u32 {
+    burst.unwrap_or(rate.min(RateState::MAX_BURST))
+}
+
+impl Limits {
+    fn from_config(config: &Config) -> Limits {
+        let rate = config.rate_limit.unwrap_or(0);
+        let syn_rate = config.syn_rate_limit.unwrap_or(0);
+        Limits {
+            block_action: match config.block_action {
+                config::BlockAction::Drop => block_action::DROP,
+                config::BlockAction::Abort => block_action::ABORT,
+                config::BlockAction::Tx => block_action::TX,
+            },
+            rate_limit: rate,
+            rate_burst: config.rate_limit.map_or(0, |rate| burst_size(rate, config.rate_burst)),
+            syn_rate_limit: syn_rate,
+            syn_rate_burst: config
+                .syn_rate_limit
+                .map_or(0, |rate| burst_size(rate, config.syn_rate_burst)),
+            conntrack_timeout: config.conntrack_timeout.unwrap_or(0),
+            knock_port: config.knock_port.map_or(0, u32::from),
+            knock_timeout: config
+                .knock_port
+                .map_or(0, |_| config.knock_timeout.unwrap_or(config::DEFAULT_KNOCK_TIMEOUT)),
+            directions: config.direction.setting(),
+        }
+    }
+
+    fn live() -> anyhow::Result<Limits> {
+        let map = control::open_settings()?.ok_or_else(|| anyhow::anyhow!("файрволл не запущен"))?;
+        let get = |index| map.get(&index, 0);
+        Ok(Limits {
+            block_action: get(settings::BLOCK_ACTION)?,
+            rate_limit: get(settings::RATE_LIMIT)?,
+            rate_burst: get(settings::RATE_BURST)?,
+            syn_rate_limit: get(settings::SYN_RATE_LIMIT)?,
+            syn_rate_burst: get(settings::SYN_RATE_BURST)?,
+            conntrack_timeout: get(settings::CONNTRACK_TIMEOUT)?,
+            knock_port: get(settings::KNOCK_PORT)?,
+            knock_timeout: get(settings::KNOCK_TIMEOUT)?,
+            directions: get(settings::DIRECTIONS)?,
+        })
+    }
+}
+
+fn yes_no(value: u32) -> Vec<String> {
+    vec![if value != 0 { "yes" } else { "no" }.to_string()]
+}
+
+/// Число или ничего, если настройка выключена нулём.
+fn nonzero(value: u32) -> Vec<String> {
+    (value != 0).then(|| value.to_string()).into_iter().collect()
+}
+
+/// Сеть из префикса LPM-ключа с адресом в сетевом порядке байт.
+fn network(prefix: u8, addr: u32) -> Ipv4Network {
+    Ipv4Network::new(Ipv4Addr::from(u32::from_be(addr)), prefix).expect("длина префикса из карты")
+}
+
+/// Порты с масками `port_protos`, собранные обратно в диапазоны.
+fn port_ranges(ports: &[(u16, u8)]) -> Vec<String> {
+    let mut ranges: Vec<AllowedPort> = Vec::new();
+    for &(port, protos) in ports {
+        let proto = match protos {
+            port_protos::TCP => Some(PortProto::Tcp),
+            port_protos::UDP => Some(PortProto::Udp),
+            _ => None,
+        };
+        match ranges.last_mut() {
+            Some(last) if last.proto == proto && u32::from(last.last) + 1 == u32::from(port) => {
+                last.last = port;
+            }
+            _ => ranges.push(AllowedPort { port, last: port, proto }),
+        }
+    }
+    ranges.iter().map(AllowedPort::to_string).collect()
+}
+
+/// Сеть IPv6 из префикса LPM-ключа.
+fn network6(prefix: u8, addr: [u8; 16]) -> Ipv6Network {
+    Ipv6Network::new(Ipv6Addr::from(addr), prefix).expect("длина префикса из карты")
+}
+
+fn strings<T: ToString>(items: impl IntoIterator<Item = T>) -> Vec<String> {
+    items.into_iter().map(|item| item.to_string()).collect()
+}
+
+/// Правила по ключам конфигурации, значения в её синтаксисе.
+pub fn rule_entries(policy: &Policy) -> Vec<(&'static str, Vec<String>)> {
+    let blocked_ips6 =
+        policy.blocked_ips6.iter().map(|&(prefix, addr)| network6(prefix, addr).to_string());
+    let blocked_ips = policy
+        .blocked_ips
+        .iter()
+        .map(|&addr| Ipv4Addr::from(addr).to_string())
+        .chain(policy.blocked_nets.iter().map(|&(prefix, addr)| network(prefix, addr).to_string()))
+        .chain(blocked_ips6);
+    let endpoints = policy.blocked_endpoints.iter().map(|&(prefix, key)| {
+        let [p0, p1, a0, a1, a2, a3] = key;
+        let addr = Ipv4Addr::from([a0, a1, a2, a3]);
+        let network = Ipv4Network::new(addr, prefix - 16).expect("длина префикса из карты");
+        Endpoint { network, port: u16::from_be_bytes([p0, p1]) }
+    });
+    let masks = policy.blocked_masks.iter().map(|rule| MaskedIp {
+        addr: Ipv4Addr::from(rule.addr),
+        mask: Ipv4Addr::from(rule.mask),
+    });
+    let protocols = policy.protocols.iter().map(|&(proto, rule)| ProtocolRule {
+        proto,
+        allow: rule == protocol_rule::ALLOW,
+    });
+    let endpoint_match = match policy.endpoint_match {
+        1 => vec!["src".to_string()],
+        2 => vec!["dst".to_string()],
+        _ => Vec::new(),
+    };
+    let arp_subnet = (policy.arp_prefix != 0).then(|| {
+        let net = Ipv4Network::new(Ipv4Addr::from(policy.arp_subnet), policy.arp_prefix as u8);
+        net.expect("длина префикса из карты").to_string()
+    });
+    let window = (policy.country_window != 0).then(|| config::format_window(policy.country_window));
+    vec![
+        ("policy", vec![if policy.default_policy != 0 { "allow" } else { "deny" }.to_string()]),
+        ("allowed-ports", port_ranges(&policy.allowed_ports)),
+        ("port-match", vec![if policy.port_match != 0 { "src" } else { "dst" }.to_string()]),
+        ("blocked-ips", blocked_ips.collect()),
+        ("blocked-masks", strings(masks)),
+        ("blocked-macs", strings(policy.blocked_macs.iter().copied().map(MacAddress))),
+        (
+            "blocked-countries",
+            strings(policy.blocked_countries.iter().map(|&key| {
+                String::from_utf8_lossy(&unpack_country(key)).into_owned()
+            })),
+        ),
+        ("blocked-countries-window", window.into_iter().collect()),
+        ("allowed-ips", strings(policy.allowed_ips.iter().copied().map(Ipv4Addr::from))),
+        ("block-tcp-window", strings(&policy.tcp_windows)),
+        ("blocked-endpoints", strings(endpoints)),
+        ("endpoint-match", endpoint_match),
+        ("blocked-src-ports", port_ranges(&policy.blocked_src_ports)),
+        (
+            "fast-accept-prefixes",
+            strings(policy.fast_accept.iter().map(|&(prefix, addr)| network(prefix, addr))),
+        ),
+        (
+            "protected-ips",
+            strings(policy.protected_ips.iter().map(|&(prefix, addr)| network(prefix, addr))),
+        ),
+        ("allow-icmp-echo", yes_no(policy.icmp_echo)),
+        ("allow-invalid-tcp-flags", yes_no(u32::from(policy.tcp_flag_filter == 0))),
+        ("strict-protocols", yes_no(policy.strict_protocols)),
+        ("protocols", strings(protocols)),
+        ("filter-multicast", yes_no(policy.filter_multicast)),
+        ("drop-fragments", yes_no(policy.drop_fragments)),
+        ("allow-dns", yes_no(policy.allow_dns)),
+        ("arp-subnet", arp_subnet.into_iter().collect()),
+    ]
+}
+
+/// Имя `direction` для настройки `settings::DIRECTIONS`; 0 — только входящие.
+fn direction_name(directions: u32) -> &'static str {
+    direction::NAMES
+        .iter()
+        .find(|&&(_, bits)| bits == directions)
+        .map_or("ingress", |&(name, _)| name)
+}
+
+/// Пределы и таймауты по ключам конфигурации.
+fn limit_entries(limits: &Limits) -> Vec<(&'static str, Vec<String>)> {
+    let action = match limits.block_action {
+        block_action::ABORT => "abort",
+        block_action::TX => "tx",
+        _ => "drop",
+    };
+    vec![
+        ("block-action", vec![action.to_string()]),
+        ("rate-limit", nonzero(limits.rate_limit)),
+        ("rate-burst", nonzero(limits.rate_burst)),
+        ("syn-rate-limit", nonzero(limits.syn_rate_limit)),
+        ("syn-rate-burst", nonzero(limits.syn_rate_burst)),
+        ("conntrack-timeout", nonzero(limits.conntrack_timeout)),
+        ("knock-port", nonzero(limits.knock_port)),
+        ("knock-timeout", nonzero(limits.knock_timeout)),
+        ("direction", vec![direction_name(limits.directions).to_string()]),
+    ]
+}
+
+/// Ключи пределов и таймаутов, которые в запущенном файрволле не такие, как в `config`:
+/// их загрузчик пишет только при запуске.
+pub fn stale_limits(config: &Config) -> anyhow::Result<Vec<&'static str>> {
+    let live = limit_entries(&Limits::live()?);
+    let expected = limit_entries(&Limits::from_config(config));
+    Ok(live.into_iter().zip(expected).filter(|(a, b)| a.1 != b.1).map(|(a, _)| a.0).collect())
+}
+
+fn entries(policy: &Policy, limits: &Limits) -> Vec<(&'static str, Vec<String>)> {
+    let mut entries = rule_entries(policy);
+    entries.extend(limit_entries(limits));
+    entries
+}
+
+/// Записи `blocked-ips` в синтаксисе конфигурации со счётчиками срабатываний, сначала
+/// сработавшие чаще.
+fn blocklist_hits(hits: &policy::Hits) -> Vec<(String, u64)> {
+    let mut entries: Vec<(String, u64)> = hits
+        .blocked_ips
+        .iter()
+        .map(|&(addr, count)| (Ipv4Addr::from(addr).to_string(), count))
+        .chain(hits.blocked_nets.iter().map(|&((prefix, addr), count)| {
+            (network(prefix, addr).to_string(), count)
+        }))
+        .chain(hits.blocked_ips6.iter().map(|&((prefix, addr), count)| {
+            (network6(prefix, addr).to_string(), count)
+        }))
+        .collect();
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    entries
+}
+
+/// Выводит, сколько пакетов отбросила каждая запись `blocked-ips`; записи без единого
+/// срабатывания — кандидаты на удаление из конфигурации.
+fn print_hits(hits: &policy::Hits) {
+    let entries = blocklist_hits(hits);
+    if entries.is_empty() {
+        return;
+    }
+    let (hit, idle): (Vec<_>, Vec<_>) = entries.iter().partition(|(_, count)| *count > 0);
+    let width = hit.iter().map(|(entry, _)| entry.len()).max().unwrap_or(0);
+    println!();
+    println!("Срабатывания blocked-ips с запуска файрволла или добавления записи:");
+    for (entry, count) in &hit {
+        println!("  {entry:<width$}  {count}");
+    }
+    if !idle.is_empty() {
+        let idle: Vec<&str> = idle.iter().map(|(entry, _)| entry.as_str()).collect();
+        println!("  Без срабатываний ({}), кандидаты на удаление: {}", idle.len(), idle.join(", "));
+    }
+}
+
+/// Значения ключа, которые есть в `from`, но не в `other`.
+pub fn missing<'a>(from: &'a [String], other: &[String]) -> Vec<&'a str> {
+    from.iter().filter(|item| !other.contains(item)).map(String::as_str).collect()
+}
+
+/// Выполняет `firewall-cli dump-rules`: 0 — карты совпадают с конфигурацией, 1 — нет или
+/// файрволл не запущен.
+pub fn run(config: &Config) -> i32 {
+    let live = match policy::live().and_then(|policy| Ok((policy, Limits::live()?))) {
+        Ok(live) => live,
+        Err(e) => {
+            println!("Не удалось прочитать карты: {e:#}");
+            return 1;
+        }
+    };
+    let expected = match Policy::from_config(config) {
+        Ok(policy) => (policy, Limits::from_config(config)),
+        Err(e) => {
+            println!("Ошибка: {e:#}");
+            return 1;
+        }
+    };
+    let live = entries(&live.0, &live.1);
+    let expected = entries(&expected.0, &expected.1);
+
+    println!("Правила в картах запущенного файрволла:");
+    for (key, items) in &live {
+        if !items.is_empty() {
+            println!("  {key}: {}", items.join(", "));
+        }
+    }
+    match policy::hits() {
+        Ok(hits) => print_hits(&hits),
+        Err(e) => println!("Не удалось прочитать счётчики blocked-ips: {e:#}"),
+    }
+
+    let mut drift = Vec::new();
+    for ((key, live), (_, expected)) in live.iter().zip(&expected) {
+        let extra = missing(live, expected);
+        let absent = missing(expected, live);
+        if !extra.is_empty() {
+            drift.push(format!("{key}: только в картах {}", extra.join(", ")));
+        }
+        if !absent.is_empty() {
+            drift.push(format!("{key}: только в конфигурации {}", absent.join(", ")));
+        }
+    }
+    println!();
+    if drift.is_empty() {
+        println!("Карты совпадают с конфигурацией.");
+        return 0;
+    }
+    println!("Карты расходятся с конфигурацией ({}):", drift.len());
+    for line in &drift {
+        println!("  {line}");
+    }
+    println!(
+        "Правила из файла применятся по SIGHUP процессу firewall-cli, через compile-policy и \
+         apply-policy или после перезапуска; пределы и таймауты — только после перезапуска."
+    );
+    1
+}
@@ -0,0 +1,139 @@
+//! Журнал отброшенных пакетов в файле `log-file`.
+//!
+//! Пока работает загрузчик, один поток читает события из его сокета и кладёт их в
+//! ограниченную очередь, другой дописывает их в файл по строке JSON с временем получения.
+//! Медленный диск не задерживает чтение сокета: если очередь полна, событие не пишется, а
+//! следующей строкой в журнал попадает число пропущенных. Когда файл дорастает до
+//! `log-max-size`, он переименовывается в `.1` (прежний `.1` теряется) и начинается заново.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    thread,
+};
+
+use firewall_common::DropEvent;
+
+use crate::events;
+
+/// Размер файла по умолчанию, после которого он ротируется.
+pub const DEFAULT_MAX_SIZE: u64 = 10 << 20;
+
+/// Сколько событий может ждать записи.
+const QUEUE_LEN: usize = 4096;
+
+/// Запись журнала; останавливается, когда значение удаляется.
+pub struct EventLog {
+    stop: Arc<AtomicBool>,
+}
+
+impl EventLog {
+    /// Начинает дописывать события в `path`.
+    pub fn start(path: PathBuf, max_size: u64) -> EventLog {
+        let stop = Arc::new(AtomicBool::new(false));
+        let lost = Arc::new(AtomicU64::new(0));
+        let (queue, events) = mpsc::sync_channel(QUEUE_LEN);
+        {
+            let (stop, lost) = (Arc::clone(&stop), Arc::clone(&lost));
+            thread::spawn(move || read(&stop, &queue, &lost));
+        }
+        thread::spawn(move || {
+            if let Err(e) = write(&path, max_size, &events, &lost) {
+                println!("Журнал событий {} отключён: {e}", path.display());
+            }
+        });
+        EventLog { stop }
+    }
+}
+
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Читает события из сокета загрузчика, пока журнал не остановлен.
+///
+/// Недавние события, которые загрузчик присылает при подключении, пишутся только при
+/// первом подключении: после переподключения они повторили бы уже записанное.
+fn read(stop: &AtomicBool, queue: &SyncSender<DropEvent>, lost: &AtomicU64) {
+    let mut first = true;
+    while !stop.load(Ordering::SeqCst) {
+        let Ok((mut stream, backlog)) = events::connect() else {
+            thread::sleep(events::RECONNECT_DELAY);
+            continue;
+        };
+        let backlog = if first { backlog } else { Vec::new() };
+        first = false;
+        for event in backlog {
+            send(queue, event, lost);
+        }
+        while let Ok(event) = events::read_event(&mut stream) {
+            if !send(queue, event, lost) {
+                return;
+            }
+        }
+    }
+}
+
+/// Ставит событие в очередь, не дожидаясь записи; `false` — записывающий поток завершился.
+fn send(queue: &SyncSender<DropEvent>, event: DropEvent, lost: &AtomicU64) -> bool {
+    match queue.try_send(event) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            lost.fetch_add(1, Ordering::Relaxed);
+            true
+        }
+        Err(TrySendError::Disconnected(_)) => false,
+    }
+}
+
+fn open(path: &Path) -> io::Result<(File, u64)> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+fn rotated(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".1");
+    PathBuf::from(name)
+}
+
+/// Дописывает события из очереди, пока читающий поток не закроет её.
+fn write(
+    path: &Path,
+    max_size: u64,
+    events: &Receiver<DropEvent>,
+    lost: &AtomicU64,
+) -> io::Result<()> {
+    let (mut file, mut size) = open(path)?;
+    for event in events {
+        let time = events::now();
+        let mut lines = String::new();
+        let skipped = lost.swap(0, Ordering::Relaxed);
+        if skipped > 0 {
+            lines.push_str(&format!("{{\"time\":\"{time}\",\"lost\":{skipped}}}\n"));
+        }
+        lines.push_str(&events::format_json_at(&event, &time));
+        lines.push('\n');
+        file.write_all(lines.as_bytes())?;
+        size += lines.len() as u64;
+
+        if size >= max_size {
+            drop(file);
+            fs::rename(path, rotated(path))?;
+            (file, size) = open(path)?;
+        }
+    }
+    Ok(())
+}
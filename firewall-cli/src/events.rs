@@ -0,0 +1,254 @@
+use std::{
+    io::{self, Read as _},
+    net::Ipv4Addr,
+    os::unix::net::UnixStream,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use firewall_common::{
+    event_fields, event_flags, pack_country, unpack_country, DropEvent, DropReason, EVENTS_SOCKET,
+};
+use ipnetwork::Ipv4Network;
+
+/// Пауза перед повторным подключением к загрузчику.
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Условия отбора событий; пустое поле пропускает всё.
+#[derive(Debug, Default, Clone)]
+pub struct Filter {
+    pub src: Option<Ipv4Network>,
+    pub reason: Option<DropReason>,
+    /// Код страны в виде [`pack_country`].
+    pub country: Option<u16>,
+}
+
+impl Filter {
+    pub fn matches(&self, event: &DropEvent) -> bool {
+        self.src.is_none_or(|net| net.contains(Ipv4Addr::from(event.src_addr)))
+            && self.reason.is_none_or(|r| r as u8 == event.reason)
+            && self.country.is_none_or(|c| c == event.country)
+    }
+}
+
+/// Разбирает имя причины, как его печатает загрузчик (`blocked-ip`).
+pub fn parse_reason(name: &str) -> Result<DropReason, String> {
+    DropReason::ALL.into_iter().find(|r| r.as_str() == name).ok_or_else(|| {
+        let names: Vec<_> = DropReason::ALL.iter().map(|r| r.as_str()).collect();
+        format!("неизвестная причина '{name}', допустимы: {}", names.join(", "))
+    })
+}
+
+/// Разбирает код или название страны в ключ для [`Filter::country`].
+pub fn parse_country(token: &str) -> Result<u16, String> {
+    crate::config::parse_country(token).map(|code| pack_country(code.as_bytes()))
+}
+
+fn reason_name(event: &DropEvent) -> &'static str {
+    DropReason::from_u8(event.reason).map_or("unknown", DropReason::as_str)
+}
+
+fn country_code(event: &DropEvent) -> Option<String> {
+    (event.country != 0)
+        .then(|| String::from_utf8_lossy(&unpack_country(event.country)).into_owned())
+}
+
+/// Строка для чтения человеком.
+pub fn format_plain(event: &DropEvent) -> String {
+    let mut out = format!(
+        "{}:{} -> {}:{} proto {} страна {} ({})",
+        Ipv4Addr::from(event.src_addr),
+        event.src_port,
+        Ipv4Addr::from(event.dst_addr),
+        event.dst_port,
+        event.proto,
+        country_code(event).as_deref().unwrap_or("??"),
+        reason_name(event),
+    );
+    for (name, value) in extra_fields(event) {
+        out.push_str(&format!(" {name}={value}"));
+    }
+    if is_dry_run(event) {
+        out.insert_str(0, "[dry-run] ");
+    }
+    out
+}
+
+/// Одна строка JSON на событие, для передачи другим программам.
+pub fn format_json(event: &DropEvent) -> String {
+    let country = country_code(event).map_or("null".to_string(), |c| format!("\"{c}\""));
+    let extra: String = extra_fields(event)
+        .into_iter()
+        .map(|(name, value)| format!(",\"{}\":{value}", name.replace('-', "_")))
+        .collect();
+    let dry_run = if is_dry_run(event) { ",\"dry_run\":true" } else { "" };
+    format!(
+        "{{\"src\":\"{}\",\"src_port\":{},\"dst\":\"{}\",\"dst_port\":{},\"proto\":{},\
+         \"country\":{},\"reason\":\"{}\"{extra}{dry_run}}}",
+        Ipv4Addr::from(event.src_addr),
+        event.src_port,
+        Ipv4Addr::from(event.dst_addr),
+        event.dst_port,
+        event.proto,
+        country,
+        reason_name(event),
+    )
+}
+
+/// [`format_json`] с временем получения `time` первым полем, как строки `log-file`.
+pub fn format_json_at(event: &DropEvent, time: &str) -> String {
+    format!("{{\"time\":\"{time}\",{}", &format_json(event)[1..])
+}
+
+/// Текущее время UTC в виде поля `time`.
+pub fn now() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    crate::audit::utc_timestamp(secs)
+}
+
+pub fn read_event(stream: &mut UnixStream) -> io::Result<DropEvent> {
+    let mut buf = [0u8; size_of::<DropEvent>()];
+    stream.read_exact(&mut buf)?;
+    DropEvent::from_bytes(&buf).ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))
+}
+
+/// Пакет прошёл только потому, что межсетевой экран работает в пробном режиме.
+fn is_dry_run(event: &DropEvent) -> bool {
+    event.flags & event_flags::DRY_RUN != 0
+}
+
+/// Необязательные поля, которые загрузчик заполнил в событии, в виде пар «имя — значение».
+fn extra_fields(event: &DropEvent) -> Vec<(&'static str, u16)> {
+    let values = [
+        (event_fields::TTL, u16::from(event.ttl)),
+        (event_fields::TCP_FLAGS, u16::from(event.tcp_flags)),
+        (event_fields::LENGTH, event.len),
+        (event_fields::VLAN, event.vlan),
+    ];
+    event_fields::NAMES
+        .iter()
+        .zip(values)
+        .filter(|(_, (bit, _))| event.fields & bit != 0)
+        .map(|(&(name, _), (_, value))| (name, value))
+        .collect()
+}
+
+/// Подключается к загрузчику и возвращает поток вместе с недавними событиями.
+pub fn connect() -> io::Result<(UnixStream, Vec<DropEvent>)> {
+    let mut stream = UnixStream::connect(EVENTS_SOCKET)?;
+    let mut count = [0u8; 4];
+    stream.read_exact(&mut count)?;
+    let backlog = (0..u32::from_le_bytes(count))
+        .map(|_| read_event(&mut stream))
+        .collect::<io::Result<_>>()?;
+    Ok((stream, backlog))
+}
+
+/// Выполняет `firewall-cli events` и возвращает код выхода.
+///
+/// С `json` события печатаются строками JSON с полем `time` — временем получения.
+/// Строка для события, которое проходит `filter`; `None` — событие не показывается.
+fn render(filter: &Filter, event: &DropEvent, json: bool) -> Option<String> {
+    if !filter.matches(event) {
+        return None;
+    }
+    Some(if json { format_json_at(event, &now()) } else { format_plain(event) })
+}
+
+/// Без `follow` печатает недавние события и завершается. С `follow` продолжает выводить
+/// новые до Ctrl+C, а при обрыве соединения переподключается; недавние события после
+/// переподключения не повторяются.
+pub fn run(filter: &Filter, follow: bool, json: bool) -> i32 {
+    let print = |event: &DropEvent| {
+        if let Some(line) = render(filter, event, json) {
+            println!("{line}");
+        }
+    };
+
+    let (mut stream, backlog) = match connect() {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Не удалось подключиться к {EVENTS_SOCKET}: {e}. Файрволл запущен?");
+            return 1;
+        }
+    };
+    backlog.iter().for_each(print);
+    if !follow {
+        return 0;
+    }
+
+    loop {
+        match read_event(&mut stream) {
+            Ok(event) => print(&event),
+            Err(e) => {
+                eprintln!("Соединение с загрузчиком потеряно ({e}), переподключение...");
+                stream = loop {
+                    thread::sleep(RECONNECT_DELAY);
+                    if let Ok((stream, _)) = connect() {
+                        break stream;
+                    }
+                };
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(src: [u8; 4], reason: DropReason, country: &[u8]) -> DropEvent {
+        DropEvent {
+            src_addr: u32::from(Ipv4Addr::from(src)),
+            dst_addr: u32::from(Ipv4Addr::new(192, 0, 2, 1)),
+            src_port: 40000,
+            dst_port: 22,
+            proto: 6,
+            reason: reason as u8,
+            country: pack_country(country),
+            ..Default::default()
+        }
+    }
+
+    fn shown(filter: &Filter, events: &[DropEvent]) -> Vec<String> {
+        events.iter().filter_map(|event| render(filter, event, false)).collect()
+    }
+
+    #[test]
+    fn only_matching_events_are_shown() {
+        let events = [
+            event([198, 51, 100, 7], DropReason::BlockedIp, b"US"),
+            event([198, 51, 100, 8], DropReason::BlockedCountry, b"RU"),
+            event([203, 0, 113, 7], DropReason::BlockedCountry, b"RU"),
+        ];
+        assert_eq!(shown(&Filter::default(), &events).len(), 3);
+
+        let filter = Filter {
+            src: Some("198.51.100.0/24".parse().unwrap()),
+            reason: Some(parse_reason("blocked-country").unwrap()),
+            country: None,
+        };
+        let lines = shown(&filter, &events);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].starts_with("198.51.100.8:40000 -> 192.0.2.1:22"), "{}", lines[0]);
+
+        let filter = Filter {
+            country: Some(parse_country("Russia").unwrap()),
+            ..Filter::default()
+        };
+        let lines = shown(&filter, &events);
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| line.contains("страна RU")));
+    }
+
+    #[test]
+    fn json_line_carries_time_and_reason() {
+        let event = event([198, 51, 100, 7], DropReason::BlockedIp, b"US");
+        let line = render(&Filter::default(), &event, true).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert!(value["time"].is_string());
+        assert_eq!(value["src"], "198.51.100.7");
+        assert_eq!(value["reason"], "blocked-ip");
+        assert_eq!(value["country"], "US");
+    }
+}
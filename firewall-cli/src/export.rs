@@ -0,0 +1,289 @@
+//! Перевод конфигурации в правила других файрволлов для документации и сверки.
+//!
+//! Правила применяет по-прежнему программа XDP; экспорт лишь повторяет её порядок проверок.
+
+use clap::ValueEnum;
+use firewall_common::RateState;
+
+use crate::config::{BlockAction, Config, DefaultPolicy, EndpointMatch, PortMatch, PortProto};
+
+/// Формат `firewall-cli export`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Набор правил для `nft -f`.
+    Nftables,
+}
+
+fn set(items: impl IntoIterator<Item = String>) -> String {
+    items.into_iter().collect::<Vec<_>>().join(", ")
+}
+
+/// Переводит конфигурацию в таблицу nftables на хуке ingress интерфейса.
+///
+/// Хук ingress семейства netdev ближе всего к XDP: он видит пакеты до маршрутизации, и
+/// кадры не IPv4 так же проходят без проверки. Правила идут в порядке программы XDP.
+/// Блокировка стран не переносится: в nftables нет GeoIP, это отмечено комментарием.
+pub fn nftables(config: &Config) -> String {
+    // Один хук на несколько устройств (`devices`) понимают ядро 5.5 и nft 0.9.7.
+    let device = match config.ifaces.as_slice() {
+        [] => "device \"eth0\"".to_string(),
+        [iface] => format!("device \"{iface}\""),
+        ifaces => {
+            let quoted: Vec<String> = ifaces.iter().map(|iface| format!("\"{iface}\"")).collect();
+            format!("devices = {{ {} }}", quoted.join(", "))
+        }
+    };
+    let mut sets = String::new();
+    let mut rules = Vec::new();
+
+    // MAC-адрес программа проверяет первым, до разбора IP, и у кадров любого протокола.
+    if !config.blocked_macs.is_empty() {
+        rules.push(format!(
+            "ether saddr {{ {} }} drop",
+            set(config.blocked_macs.iter().map(|mac| mac.to_string()))
+        ));
+    }
+    // ARP программа решает до пропуска широковещательных кадров, а правила IP к нему не
+    // применяются.
+    if let Some(subnet) = config.arp_subnet {
+        rules.push(format!("arp saddr ip != {}/{} drop", subnet.network(), subnet.prefix()));
+    }
+    rules.push("ether type arp accept".to_string());
+    // Широковещательные и групповые кадры программа пропускает сразу за проверкой MAC.
+    if !config.filter_multicast {
+        rules.push("meta pkttype { broadcast, multicast } accept".to_string());
+    }
+    // Пакеты не к защищаемым адресам программа пропускает сразу за доверенными префиксами.
+    for (name, networks, rule) in [
+        ("fast_accept", &config.fast_accept_prefixes, "ip saddr @fast_accept accept"),
+        ("protected_ips", &config.protected_ips, "ip daddr != @protected_ips accept"),
+        ("blocked_ips", &config.blocked_ips, "ip saddr @blocked_ips drop"),
+    ] {
+        if networks.is_empty() {
+            continue;
+        }
+        sets.push_str(&format!(
+            "    set {name} {{\n        type ipv4_addr\n        flags interval\n        \
+             elements = {{ {} }}\n    }}\n\n",
+            set(networks.iter().map(|net| net.to_string()))
+        ));
+        rules.push(rule.to_string());
+    }
+    if !config.blocked_ips6.is_empty() {
+        sets.push_str(&format!(
+            "    set blocked_ips6 {{\n        type ipv6_addr\n        flags interval\n        \
+             elements = {{ {} }}\n    }}\n\n",
+            set(config.blocked_ips6.iter().map(|net| net.to_string()))
+        ));
+        rules.push("ip6 saddr @blocked_ips6 drop".to_string());
+    }
+    for rule in &config.blocked_masks {
+        rules.push(format!("ip saddr & {} == {} drop", rule.mask, rule.addr));
+    }
+    if !config.blocked_countries.is_empty() {
+        rules.push(format!(
+            "# не переносится: blocked-countries {} (в nftables нет GeoIP)",
+            config.blocked_countries.join(" ")
+        ));
+        if !config.allowed_ips.is_empty() {
+            let ips: Vec<_> = config.allowed_ips.iter().map(|ip| ip.to_string()).collect();
+            rules.push(format!(
+                "# не переносится: allowed-ips {} (исключения из блокировки стран)",
+                ips.join(" ")
+            ));
+        }
+    }
+    if !config.blocked_regions.is_empty() {
+        let regions: Vec<_> = config.blocked_regions.iter().map(u32::to_string).collect();
+        rules.push(format!(
+            "# не переносится: blocked-regions {} (в nftables нет GeoIP)",
+            regions.join(" ")
+        ));
+    }
+    if config.block_action != BlockAction::default() {
+        rules.push(format!(
+            "# не переносится: block-action {} (здесь пакеты просто отбрасываются)",
+            config.block_action.as_str()
+        ));
+    }
+    if config.unwrap_ipip {
+        rules.push("# не переносится: unwrap-ipip (здесь проверяется внешний заголовок)".into());
+    }
+    if let Some(rate) = config.syn_rate_limit {
+        let burst = config.syn_rate_burst.unwrap_or(rate.min(RateState::MAX_BURST));
+        rules.push(format!(
+            "tcp flags & (syn | ack) == syn meter syn_rate_limit {{ ip saddr limit rate over \
+             {rate}/second burst {burst} packets }} drop"
+        ));
+    }
+    if let Some(rate) = config.rate_limit {
+        let burst = config.rate_burst.unwrap_or(rate.min(RateState::MAX_BURST));
+        rules.push(
+            "# rate-limit: в XDP токены тратят только пропущенные пакеты, здесь — все".into(),
+        );
+        rules.push(format!(
+            "meter rate_limit {{ ip saddr limit rate over {rate}/second burst {burst} packets }} \
+             drop"
+        ));
+    }
+    // Следующие фрагменты программа решает только по адресу, а с drop-fragments отбрасывает
+    // все фрагменты.
+    if config.drop_fragments {
+        rules.push("ip frag-off & 0x3fff != 0 drop".to_string());
+    } else {
+        rules.push("ip frag-off & 0x1fff != 0 accept".to_string());
+    }
+    // Протоколы из `protocols` задаются номерами: имена в nftables берутся из
+    // /etc/protocols и не везде совпадают с именами конфигурации.
+    let protocols = |allow: bool| -> Vec<String> {
+        config
+            .protocols
+            .iter()
+            .filter(|rule| rule.allow == allow)
+            .filter(|rule| !allow || ![1, 6, 17, 58].contains(&rule.proto))
+            .map(|rule| rule.proto.to_string())
+            .collect()
+    };
+    let (denied, allowed) = (protocols(false), protocols(true));
+    if !denied.is_empty() {
+        rules.push(format!("meta l4proto {{ {} }} drop", denied.join(", ")));
+    }
+    if !allowed.is_empty() {
+        rules.push(format!("meta l4proto {{ {} }} accept", allowed.join(", ")));
+    }
+    let mut icmp_types = vec!["echo-reply", "destination-unreachable", "time-exceeded"];
+    if config.allow_icmp_echo {
+        icmp_types.push("echo-request");
+    }
+    let deny = config.policy == DefaultPolicy::Deny;
+    rules.push(format!("meta protocol ip icmp type {{ {} }} accept", icmp_types.join(", ")));
+    if !config.strict_protocols_in_effect() {
+        rules.push("meta protocol ip meta l4proto != { tcp, udp, icmp } accept".to_string());
+    }
+    if deny {
+        rules.push("meta protocol ip meta l4proto != { tcp, udp } drop".to_string());
+    }
+    let addr = match config.endpoint_match {
+        EndpointMatch::Src => "saddr",
+        EndpointMatch::Dst => "daddr",
+    };
+    for endpoint in &config.blocked_endpoints {
+        rules.push(format!("ip {addr} {} th dport {} drop", endpoint.network, endpoint.port));
+    }
+    if let Some(port) = config.knock_port {
+        let sequence: Vec<String> = config.knock_sequence.iter().map(u16::to_string).collect();
+        rules.push(format!(
+            "# не переносится: knock-sequence {} (порт {port} закрыт)",
+            sequence.join(" ")
+        ));
+        rules.push(format!("th dport {port} drop"));
+    }
+    let matchers = [(None, "th"), (Some(PortProto::Tcp), "tcp"), (Some(PortProto::Udp), "udp")];
+    for (proto, matcher) in matchers {
+        let ports: Vec<String> = config
+            .blocked_src_ports
+            .iter()
+            .filter(|p| p.proto == proto)
+            .map(|p| p.range())
+            .collect();
+        if !ports.is_empty() {
+            rules.push(format!("meta protocol ip {matcher} sport {{ {} }} drop", set(ports)));
+        }
+    }
+    if !config.allow_invalid_tcp_flags {
+        rules.push("tcp flags == 0x0 drop".to_string());
+        rules.push("tcp flags & (fin | psh | urg) == fin | psh | urg drop".to_string());
+        rules.push("tcp flags & (fin | syn | rst | ack) == fin drop".to_string());
+    }
+    if !config.blocked_tcp_windows.is_empty() {
+        rules.push(format!(
+            "meta protocol ip tcp window {{ {} }} drop",
+            set(config.blocked_tcp_windows.iter().map(u16::to_string))
+        ));
+    }
+    if config.conntrack_timeout.is_some() {
+        rules.push(
+            "# не переносится: conntrack-timeout (в хуке ingress нет conntrack)".to_string(),
+        );
+    }
+    if config.allows_dns() {
+        rules.push("meta protocol ip th sport 53 accept".to_string());
+        rules.push("meta protocol ip th dport 53 accept".to_string());
+    }
+    let field = match config.port_match {
+        PortMatch::Dst => "dport",
+        PortMatch::Src => "sport",
+    };
+    for (proto, matcher) in matchers {
+        let ports: Vec<String> = config
+            .allowed_ports
+            .iter()
+            .filter(|p| p.proto == proto)
+            .map(|p| p.range())
+            .collect();
+        if !ports.is_empty() {
+            rules.push(format!("meta protocol ip {matcher} {field} {{ {} }} accept", set(ports)));
+        }
+    }
+    if deny {
+        rules.push("meta protocol ip drop".to_string());
+    }
+
+    let mut out = format!(
+        "# Экспорт конфигурации firewall; правила применяет программа XDP.\n\
+         table netdev firewall {{\n{sets}    chain ingress {{\n        \
+         type filter hook ingress {device} priority -500; policy accept;\n"
+    );
+    for rule in &rules {
+        out.push_str(&format!("        {rule}\n"));
+    }
+    out.push_str("    }\n}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn export(pairs: &[(&str, &str)]) -> String {
+        let pairs: Vec<_> = pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect();
+        nftables(&Config::parse_values(&pairs).unwrap())
+    }
+
+    #[test]
+    fn allow_and_block_config_becomes_nft_rules() {
+        let out = export(&[
+            ("iface", "eth1"),
+            ("allowed-ports", "22, 443/tcp, 53/udp"),
+            ("blocked-ips", "198.51.100.0/24, 203.0.113.7"),
+            ("blocked-countries", "CN"),
+        ]);
+        assert!(out.contains("type filter hook ingress device \"eth1\" priority -500;"));
+        assert!(out.contains(
+            "    set blocked_ips {\n        type ipv4_addr\n        flags interval\n        \
+             elements = { 198.51.100.0/24, 203.0.113.7/32 }\n    }\n"
+        ));
+
+        let rules: Vec<&str> = out.lines().map(str::trim).collect();
+        let position = |rule: &str| {
+            rules.iter().position(|line| *line == rule).unwrap_or_else(|| panic!("{rule}\n{out}"))
+        };
+        let blocked = position("ip saddr @blocked_ips drop");
+        let countries = position("# не переносится: blocked-countries CN (в nftables нет GeoIP)");
+        let any = position("meta protocol ip th dport { 22 } accept");
+        let tcp = position("meta protocol ip tcp dport { 443 } accept");
+        let udp = position("meta protocol ip udp dport { 53 } accept");
+        let policy = position("meta protocol ip drop");
+        assert!(blocked < countries && countries < any);
+        assert!(any < tcp && tcp < udp && udp < policy);
+        assert_eq!(policy, rules.len() - 3);
+    }
+
+    #[test]
+    fn allow_policy_has_no_final_drop() {
+        let out = export(&[("allowed-ports", "80"), ("policy", "allow")]);
+        assert!(out.contains("meta protocol ip th dport { 80 } accept"));
+        assert!(!out.contains("meta protocol ip drop"));
+        assert!(out.contains("device \"eth0\""));
+    }
+}
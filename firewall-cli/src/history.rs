@@ -0,0 +1,88 @@
+//! Прежние версии основного файла конфигурации для пункта меню «Отменить последнее изменение».
+//!
+//! Когда меню записывает файл, его прежний текст кладётся на вершину стека: `config.cfg.1` —
+//! самая свежая версия, `config.cfg.3` — самая старая, версии глубже [`DEPTH`] вытесняются.
+//! Отмена возвращает вершину в основной файл и снимает её со стека.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Сколько прежних версий хранится.
+pub const DEPTH: usize = 3;
+
+/// Файл `n`-й версии: `config.cfg.1` и так далее.
+fn version_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{n}"));
+    PathBuf::from(name)
+}
+
+/// Кладёт `previous` — текст файла до записи — на вершину стека.
+pub fn push(path: &Path, previous: &str) -> io::Result<()> {
+    for n in (1..DEPTH).rev() {
+        let from = version_path(path, n);
+        if from.exists() {
+            fs::rename(from, version_path(path, n + 1))?;
+        }
+    }
+    fs::write(version_path(path, 1), previous)
+}
+
+/// Версия на вершине стека; `None`, если отменять нечего.
+pub fn peek(path: &Path) -> io::Result<Option<String>> {
+    match fs::read_to_string(version_path(path, 1)) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Снимает вершину стека, остальные версии сдвигаются на одну позицию вверх.
+pub fn pop(path: &Path) -> io::Result<()> {
+    fs::remove_file(version_path(path, 1))?;
+    for n in 2..=DEPTH {
+        let from = version_path(path, n);
+        if from.exists() {
+            fs::rename(from, version_path(path, n - 1))?;
+        }
+    }
+    Ok(())
+}
+
+/// Построчная разница между `old` и `new`: удаляемые строки с `-`, добавляемые с `+`,
+/// совпадающие строки не показываются.
+///
+/// Файлы конфигурации небольшие, поэтому общая подпоследовательность ищется полной таблицей.
+pub fn diff(old: &str, new: &str) -> Vec<String> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    // common[i][j] — длина общей подпоследовательности old[i..] и new[j..].
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(format!("- {}", old[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+ {}", new[j]));
+            j += 1;
+        }
+    }
+    lines
+}
@@ -0,0 +1,144 @@
+use std::process::Command;
+
+use anyhow::Context as _;
+
+use crate::stats::Stats;
+
+/// Счётчики действий XDP, которые ведёт драйвер сетевой карты.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct XdpCounters {
+    pub pass: u64,
+    pub drop: u64,
+    pub aborted: u64,
+}
+
+/// Выбирает XDP-счётчики из вывода `ethtool -S`.
+///
+/// Драйверы называют их по-разному (`rx_xdp_drop`, `rx_queue_0_xdp_drops`, `xdp_aborted`),
+/// поэтому учитываются все строки с `xdp`, а значения очередей суммируются. Возвращает
+/// `None`, если драйвер не публикует ни одного такого счётчика.
+pub fn parse_ethtool(output: &str) -> Option<XdpCounters> {
+    let mut counters = XdpCounters::default();
+    let mut found = false;
+    for line in output.lines() {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let name = name.trim().to_ascii_lowercase();
+        let Ok(value) = value.trim().parse::<u64>() else {
+            continue;
+        };
+        if !name.contains("xdp") {
+            continue;
+        }
+        let slot = if name.contains("drop") {
+            &mut counters.drop
+        } else if name.contains("pass") {
+            &mut counters.pass
+        } else if name.contains("abort") {
+            &mut counters.aborted
+        } else {
+            continue;
+        };
+        *slot += value;
+        found = true;
+    }
+    found.then_some(counters)
+}
+
+/// Читает XDP-счётчики драйвера интерфейса `iface` через `ethtool -S`.
+///
+/// Общего rtnetlink-атрибута со статистикой XDP в ядре нет, драйверы публикуют её только
+/// среди своих счётчиков ethtool.
+pub fn read(iface: &str) -> anyhow::Result<Option<XdpCounters>> {
+    let output = Command::new("ethtool")
+        .arg("-S")
+        .arg(iface)
+        .output()
+        .context("не удалось запустить ethtool")?;
+    if !output.status.success() {
+        // Виртуальные интерфейсы часто вовсе не поддерживают статистику ethtool.
+        return Ok(None);
+    }
+    Ok(parse_ethtool(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Сравнивает счётчики ядра со счётчиками программы.
+pub fn format_cross_check(iface: &str, kernel: Option<XdpCounters>, app: Option<&Stats>) -> String {
+    let Some(kernel) = kernel else {
+        return format!(
+            "Драйвер {iface} не публикует XDP-счётчики. Для режима SKB (generic XDP) это \
+             ожидаемо: пакеты видит только сама программа.\n"
+        );
+    };
+
+    let mut out = format!(
+        "Счётчики драйвера {iface}:\n  {:<12}{:>14}\n  {:<12}{:>14}\n  {:<12}{:>14}\n",
+        "Пропущено", kernel.pass, "Отброшено", kernel.drop, "Ошибки", kernel.aborted,
+    );
+    let Some(app) = app else {
+        out.push_str("Файрволл не запущен: сравнить со счётчиками программы нельзя.\n");
+        return out;
+    };
+
+    let rows = [
+        ("Пропущено", kernel.pass, app.pass),
+        ("Отброшено", kernel.drop, app.drop),
+        ("Ошибки", kernel.aborted, app.aborted),
+    ];
+    let mismatches: Vec<_> = rows.iter().filter(|(_, k, a)| k != a).collect();
+    if mismatches.is_empty() {
+        out.push_str("Счётчики драйвера совпадают со счётчиками программы.\n");
+        return out;
+    }
+    out.push_str(
+        "Расхождения (драйвер считает с момента своей инициализации, программа — с запуска):\n",
+    );
+    for (name, kernel, app) in mismatches {
+        let delta = *kernel as i128 - *app as i128;
+        out.push_str(&format!("  {name:<12} драйвер {kernel}, программа {app} ({delta:+})\n"));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Вывод `ethtool -S` драйвера с XDP-счётчиками по очередям.
+    const ETHTOOL: &str = "NIC statistics:
+     rx_packets: 1500
+     rx_queue_0_xdp_pass: 100
+     rx_queue_1_xdp_pass: 20
+     rx_queue_0_xdp_drops: 7
+     rx_queue_1_xdp_drops: 3
+     rx_xdp_aborted: 1
+     rx_xdp_tx: 5
+     rx_xdp_redirect: not-a-number
+";
+
+    #[test]
+    fn queue_counters_are_summed_by_action() {
+        let counters = parse_ethtool(ETHTOOL).unwrap();
+        assert_eq!(counters, XdpCounters { pass: 120, drop: 10, aborted: 1 });
+    }
+
+    #[test]
+    fn driver_without_xdp_counters_reports_none() {
+        assert_eq!(parse_ethtool("NIC statistics:\n     rx_packets: 10\n     tx_drop: 2\n"), None);
+        assert_eq!(parse_ethtool(""), None);
+    }
+
+    #[test]
+    fn mismatch_with_app_counters_is_shown_as_delta() {
+        let kernel = parse_ethtool(ETHTOOL);
+        let app = Stats { pass: 120, drop: 4, aborted: 1, ..Default::default() };
+        let out = format_cross_check("eth0", kernel, Some(&app));
+        assert!(out.contains("драйвер 10, программа 4 (+6)"), "{out}");
+        assert!(!out.contains("драйвер 120"), "{out}");
+
+        let app = Stats { drop: 10, ..app };
+        let out = format_cross_check("eth0", kernel, Some(&app));
+        assert!(out.contains("совпадают"), "{out}");
+    }
+}
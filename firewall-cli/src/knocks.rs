@@ -0,0 +1,80 @@
+//! `firewall-cli knocks`: источники, открывшие `knock-port` стуком, и сколько им осталось.
+
+use std::{net::Ipv4Addr, path::Path};
+
+use anyhow::Context as _;
+use aya::maps::{HashMap as BpfHashMap, Map, MapData};
+use firewall_common::{settings, KNOCK_UNLOCKED_MAP, PIN_PATH};
+
+use crate::control;
+
+/// Время `bpf_ktime_get_ns`, с которым сравниваются отметки таблицы.
+pub fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// `knock-port` запущенного файрволла (0 — стук выключен) и открытые им источники.
+struct Unlocked {
+    port: u32,
+    /// Адрес и секунды до закрытия, сначала те, что закроются позже.
+    sources: Vec<(Ipv4Addr, u64)>,
+}
+
+fn unlocked() -> anyhow::Result<Option<Unlocked>> {
+    let Some(settings_map) = control::open_settings()? else {
+        return Ok(None);
+    };
+    let port = settings_map.get(&settings::KNOCK_PORT, 0)?;
+    let timeout_ns = u64::from(settings_map.get(&settings::KNOCK_TIMEOUT, 0)?) * 1_000_000_000;
+    let path = Path::new(PIN_PATH).join(KNOCK_UNLOCKED_MAP);
+    if port == 0 || !path.exists() {
+        return Ok(Some(Unlocked { port, sources: Vec::new() }));
+    }
+    let data = MapData::from_pin(&path).with_context(|| format!("не удалось открыть {path:?}"))?;
+    let map: BpfHashMap<_, u32, u64> = BpfHashMap::try_from(Map::LruHashMap(data))?;
+    let now = monotonic_ns();
+    let mut sources = Vec::new();
+    for entry in map.iter() {
+        let (addr, seen) = entry?;
+        let left = (seen + timeout_ns).saturating_sub(now);
+        // Истёкшие записи программа удаляет только при следующем пакете источника к порту.
+        if left > 0 {
+            sources.push((Ipv4Addr::from(addr), left.div_ceil(1_000_000_000)));
+        }
+    }
+    sources.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    Ok(Some(Unlocked { port, sources }))
+}
+
+pub fn run() -> i32 {
+    match unlocked() {
+        Ok(None) => {
+            println!("Файрволл не запущен.");
+            1
+        }
+        Ok(Some(Unlocked { port: 0, .. })) => {
+            println!("Стук выключен: knock-port не задан.");
+            0
+        }
+        Ok(Some(Unlocked { port, sources })) if sources.is_empty() => {
+            println!("Порт {port} не открыт ни одному источнику.");
+            0
+        }
+        Ok(Some(Unlocked { port, sources })) => {
+            println!("Порт {port} открыт для:");
+            for (addr, secs) in sources {
+                println!("  {addr:<15}  ещё {secs} с");
+            }
+            0
+        }
+        Err(e) => {
+            println!("Не удалось прочитать {KNOCK_UNLOCKED_MAP}: {e:#}");
+            1
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::net::Ipv4Addr;
+
+use ipnetwork::Ipv4Network;
+
+use crate::config::{AllowedPort, Config};
+
+/// Категория совета линтера.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SuggestionKind {
+    /// Два соседних префикса одной длины складываются в один.
+    MergeableCidrs,
+    /// Отдельный адрес уже покрыт префиксом из того же списка.
+    CollapsibleIp,
+    /// Префикс целиком лежит внутри более широкого.
+    ShadowedRule,
+    /// Отдельный порт уже входит в диапазон из того же списка.
+    PortInRange,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Suggestion {
+    pub kind: SuggestionKind,
+    pub message: String,
+}
+
+/// Возвращает объединение `a` и `b`, если это две половины одного префикса.
+fn merged(a: Ipv4Network, b: Ipv4Network) -> Option<Ipv4Network> {
+    if a.prefix() != b.prefix() || a.prefix() == 0 || a.network() == b.network() {
+        return None;
+    }
+    let sibling = u32::from(a.network()) ^ (1u32 << (32 - a.prefix()));
+    if Ipv4Addr::from(sibling) != b.network() {
+        return None;
+    }
+    let wide = Ipv4Network::new(a.network(), a.prefix() - 1).ok()?;
+    Ipv4Network::new(wide.network(), wide.prefix()).ok()
+}
+
+/// Проверяет список сетей и предлагает, как его упростить.
+fn lint_networks(key: &str, networks: &[Ipv4Network], out: &mut Vec<Suggestion>) {
+    for (i, &inner) in networks.iter().enumerate() {
+        let outer = networks
+            .iter()
+            .enumerate()
+            .find(|&(j, &outer)| {
+                j != i && outer.prefix() < inner.prefix() && inner.is_subnet_of(outer)
+            })
+            .map(|(_, &outer)| outer);
+        if let Some(outer) = outer {
+            let (kind, what) = if inner.prefix() == 32 {
+                (SuggestionKind::CollapsibleIp, format!("адрес {}", inner.ip()))
+            } else {
+                (SuggestionKind::ShadowedRule, format!("префикс {inner}"))
+            };
+            out.push(Suggestion {
+                kind,
+                message: format!("{key}: {what} уже покрыт {outer}, его можно удалить"),
+            });
+        }
+    }
+
+    for (i, &a) in networks.iter().enumerate() {
+        for &b in &networks[i + 1..] {
+            if let Some(parent) = merged(a, b) {
+                out.push(Suggestion {
+                    kind: SuggestionKind::MergeableCidrs,
+                    message: format!("{key}: {a} и {b} можно объединить в {parent}"),
+                });
+            }
+        }
+    }
+}
+
+/// Ищет порты, которые перечислены и отдельно, и в диапазоне для тех же протоколов.
+fn lint_ports(key: &str, ports: &[AllowedPort], out: &mut Vec<Suggestion>) {
+    for single in ports.iter().filter(|p| p.port == p.last) {
+        let range = ports.iter().find(|range| {
+            range.port < range.last
+                && range.ports().contains(&single.port)
+                && (range.proto.is_none() || range.proto == single.proto)
+        });
+        if let Some(range) = range {
+            out.push(Suggestion {
+                kind: SuggestionKind::PortInRange,
+                message: format!("{key}: {single} уже входит в {range}, его можно удалить"),
+            });
+        }
+    }
+}
+
+/// Ищет в корректной конфигурации то, что можно упростить. Файл не изменяется.
+pub fn lint(config: &Config) -> Vec<Suggestion> {
+    let mut suggestions = Vec::new();
+    lint_networks("blocked-ips", &config.blocked_ips, &mut suggestions);
+    lint_networks("fast-accept-prefixes", &config.fast_accept_prefixes, &mut suggestions);
+    lint_networks("protected-ips", &config.protected_ips, &mut suggestions);
+    lint_ports("allowed-ports", &config.allowed_ports, &mut suggestions);
+    lint_ports("blocked-src-ports", &config.blocked_src_ports, &mut suggestions);
+    suggestions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suggestions(pairs: &[(&str, &str)]) -> Vec<Suggestion> {
+        let pairs: Vec<_> = pairs.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect();
+        lint(&Config::parse_values(&pairs).unwrap())
+    }
+
+    fn kinds(pairs: &[(&str, &str)]) -> Vec<SuggestionKind> {
+        suggestions(pairs).iter().map(|s| s.kind).collect()
+    }
+
+    #[test]
+    fn sibling_prefixes_are_mergeable() {
+        let found = suggestions(&[("blocked-ips", "10.0.0.0/25, 10.0.0.128/25")]);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].kind, SuggestionKind::MergeableCidrs);
+        assert!(found[0].message.contains("10.0.0.0/24"));
+        assert!(kinds(&[("blocked-ips", "10.0.0.128/25, 10.0.1.0/25")]).is_empty());
+    }
+
+    #[test]
+    fn single_ip_inside_prefix_collapses() {
+        assert_eq!(
+            kinds(&[("fast-accept-prefixes", "192.0.2.0/24, 192.0.2.7")]),
+            [SuggestionKind::CollapsibleIp]
+        );
+    }
+
+    #[test]
+    fn narrower_prefix_is_shadowed() {
+        assert_eq!(
+            kinds(&[("protected-ips", "10.0.0.0/8, 10.1.0.0/16")]),
+            [SuggestionKind::ShadowedRule]
+        );
+    }
+
+    #[test]
+    fn port_inside_range_is_reported_for_matching_protocols() {
+        assert_eq!(
+            kinds(&[("allowed-ports", "8000-8100/tcp, 8080/tcp, 8080/udp, 9000")]),
+            [SuggestionKind::PortInRange]
+        );
+        assert_eq!(
+            kinds(&[("blocked-src-ports", "1-1024, 123/udp")]),
+            [SuggestionKind::PortInRange]
+        );
+    }
+
+    #[test]
+    fn tidy_config_has_no_suggestions() {
+        assert!(kinds(&[("blocked-ips", "10.0.0.0/8, 192.0.2.7"), ("allowed-ports", "22, 80")])
+            .is_empty());
+    }
+}
@@ -0,0 +1,138 @@
+//! Защита от второго интерактивного экземпляра в том же каталоге конфигурации и от двух
+//! файрволлов на одном интерфейсе.
+//!
+//! Блокировка — `flock` на файле в каталоге основного файла конфигурации, а не в текущем
+//! (`--config /etc/fw.toml` занимает `/etc`, откуда бы ни запустили меню), или на файле
+//! интерфейса в [`IFACE_LOCK_DIR`], общем для всех каталогов конфигурации. Ядро снимает её
+//! при завершении процесса, в том числе аварийном, поэтому оставшийся файл не мешает
+//! следующему запуску: в нём лишь PID прежнего владельца, который перезаписывается.
+
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, Read as _, Seek as _, SeekFrom, Write as _},
+    os::unix::fs::OpenOptionsExt as _,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Имя файла блокировки в каталоге конфигурации.
+pub const LOCK_FILE: &str = ".firewall-cli.lock";
+
+/// Каталог блокировок интерфейсов. Он принадлежит root, как и сам файрволл: во временный
+/// каталог, открытый на запись всем, любой пользователь мог бы заранее положить файл с
+/// нужным именем и занять интерфейс.
+pub const IFACE_LOCK_DIR: &str = "/run/firewall";
+
+/// Файл блокировки интерфейса `iface`.
+pub fn iface_lock_path(iface: &str) -> PathBuf {
+    Path::new(IFACE_LOCK_DIR).join(format!("firewall-cli-{}.lock", iface.replace('/', "_")))
+}
+
+/// Удерживаемая блокировка; снимается при освобождении вместе с файлом.
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+}
+
+/// Результат попытки занять каталог.
+#[derive(Debug)]
+pub enum Acquire {
+    Locked(InstanceLock),
+    /// Каталог занят другим экземпляром; PID из файла, если его удалось прочитать.
+    Busy(Option<u32>),
+}
+
+/// Каталог файла конфигурации `config`, который занимает экземпляр; у пути без каталога —
+/// текущий.
+pub fn config_dir(config: &Path) -> &Path {
+    config.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."))
+}
+
+/// Пытается занять каталог `dir`, не дожидаясь освобождения.
+pub fn acquire(dir: &Path) -> io::Result<Acquire> {
+    lock(&dir.join(LOCK_FILE))
+}
+
+/// Пытается занять интерфейс `iface`, не дожидаясь освобождения.
+pub fn acquire_iface(iface: &str) -> io::Result<Acquire> {
+    fs::create_dir_all(IFACE_LOCK_DIR)?;
+    lock(&iface_lock_path(iface))
+}
+
+fn lock(path: &Path) -> io::Result<Acquire> {
+    // По этому имени может оказаться чужая символическая ссылка. Писать в файл может только
+    // владелец: PID в нём не должен подменить никто другой.
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .mode(0o644)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let mut content = String::new();
+            file.read_to_string(&mut content)?;
+            return Ok(Acquire::Busy(content.trim().parse().ok()));
+        }
+        Err(TryLockError::Error(e)) => return Err(e),
+    }
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    writeln!(file, "{}", process::id())?;
+    Ok(Acquire::Locked(InstanceLock { _file: file }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_instance_detects_the_lock() {
+        let dir = std::env::temp_dir().join(format!("firewall-lock-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // Файл от завершившегося экземпляра не мешает: ядро уже сняло его блокировку.
+        fs::write(dir.join(LOCK_FILE), "999999\n").unwrap();
+
+        let Acquire::Locked(first) = acquire(&dir).unwrap() else {
+            panic!("каталог занят оставшимся файлом");
+        };
+        // `flock` принадлежит открытому файлу, так что второе открытие в том же процессе
+        // ведёт себя как второй экземпляр.
+        match acquire(&dir).unwrap() {
+            Acquire::Busy(pid) => assert_eq!(pid, Some(process::id())),
+            Acquire::Locked(_) => panic!("второй экземпляр занял каталог"),
+        }
+        drop(first);
+        assert!(matches!(acquire(&dir).unwrap(), Acquire::Locked(_)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn one_config_is_locked_from_any_directory() {
+        let root = std::env::temp_dir().join(format!("firewall-lock-cwd-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let config = root.join("etc").join("fw.toml");
+        let (first_cwd, second_cwd) = (root.join("a"), root.join("b"));
+        for dir in [config.parent().unwrap(), &first_cwd, &second_cwd] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        let cwd = std::env::current_dir().unwrap();
+
+        std::env::set_current_dir(&first_cwd).unwrap();
+        let first = acquire(config_dir(&config)).unwrap();
+        std::env::set_current_dir(&second_cwd).unwrap();
+        let second = acquire(config_dir(&config)).unwrap();
+        std::env::set_current_dir(cwd).unwrap();
+
+        assert!(matches!(first, Acquire::Locked(_)));
+        assert!(matches!(second, Acquire::Busy(_)), "второй экземпляр занял {config:?}");
+        assert!(root.join("etc").join(LOCK_FILE).exists());
+        assert!(!first_cwd.join(LOCK_FILE).exists() && !second_cwd.join(LOCK_FILE).exists());
+        drop(first);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
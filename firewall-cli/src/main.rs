@@ -1,21 +1,397 @@
-use dialoguer::{Input, Select};
+mod apply;
+mod audit;
+mod bench;
+mod block;
+mod bundle;
+mod check;
+mod config;
+mod conflicts;
+mod control;
+mod countries;
+mod detach;
+mod doctor;
+mod dump;
+mod event_log;
+mod events;
+mod export;
+mod history;
+mod knocks;
+mod kernel_stats;
+mod lint;
+mod lock;
+mod menu;
+mod metrics;
+mod migrate;
+mod policy;
+mod privileges;
+mod profile;
+mod rate;
+mod rdns;
+mod reload;
+mod replay;
+mod selftest;
+mod stats;
+mod syslog;
+mod totals;
+
+use anyhow::Context as _;
+use clap::{Parser, Subcommand};
+use dialoguer::{Confirm, Input, MultiSelect, Select};
 use std::{
-    collections::HashMap,
     fs,
-    io::Write,
-    path::Path,
-    process::Command,
+    io::{IsTerminal as _, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
+use ipnetwork::IpNetwork;
 use pnet::datalink;
 
+/// Как часто обновляется таблица статистики в меню.
+const STATS_REFRESH: Duration = Duration::from_secs(1);
+
+/// Сколько загрузчику дают на то, чтобы завершиться самому после Ctrl+C.
+const CHILD_GRACE: Duration = Duration::from_secs(5);
+
+/// Как часто `run_firewall` проверяет, что интерфейсы файрволла ещё есть в системе.
+const IFACE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Parser)]
+#[command(name = "firewall-cli", about = "Управление XDP-файрволлом")]
+struct Cli {
+    /// Каталог с дополнительными файлами правил, объединяемыми в лексическом порядке.
+    #[arg(long, global = true)]
+    rules_dir: Option<PathBuf>,
+
+    /// Основной файл конфигурации; по умолчанию config.toml, если он есть, иначе config.cfg.
+    #[arg(long, global = true, env = "FW_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Как записывать файл конфигурации, если это символическая ссылка.
+    #[arg(long, global = true, value_enum, default_value_t = config::SymlinkPolicy::Follow)]
+    config_symlink: config::SymlinkPolicy,
+
+    /// Показывать в статистике имена хостов источников (обратный DNS). Запросы идут в фоне,
+    /// пока имени нет, виден только адрес.
+    #[arg(long, global = true)]
+    resolve_names: bool,
+
+    /// Без команды открывается меню; для него stdin и stdout должны быть терминалом.
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+}
+
+#[derive(Debug, Subcommand)]
+enum CliCommand {
+    /// Запустить файрволл по конфигурации без меню и дождаться его завершения.
+    Run {
+        /// Интерфейсы вместо заданных в конфигурации, через запятую.
+        #[arg(long, value_delimiter = ',')]
+        iface: Vec<String>,
+        /// Разрешённые порты вместо заданных в конфигурации, через запятую.
+        #[arg(long, value_delimiter = ',', value_parser = config::parse_allowed_port)]
+        ports: Vec<config::AllowedPort>,
+        /// Отдавать счётчики в формате Prometheus по HTTP на этом адресе (127.0.0.1:9200).
+        #[arg(long)]
+        metrics_addr: Option<std::net::SocketAddr>,
+        /// Запустить, даже если allowed-ports пуст при policy deny и отброшен будет весь
+        /// трафик TCP и UDP.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Записать интерфейсы в конфигурацию.
+    SetIface {
+        /// Один или несколько интерфейсов, через пробел или запятую.
+        #[arg(required = true, value_delimiter = ',')]
+        names: Vec<String>,
+        /// Не проверять, что интерфейсы есть в системе.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Показать действующую конфигурацию вместе с каталогом правил.
+    ShowConfig,
+    /// Перенести config.cfg в config.toml; config.cfg не меняется.
+    Migrate,
+    /// Проверить, поддерживает ли ядро всё, что нужно файрволлу.
+    Doctor,
+    /// Проверить конфигурацию и каталог правил, не запуская файрволл и без прав root.
+    #[command(visible_alias = "validate")]
+    Check {
+        /// Вывести отчёт одним объектом JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Подсказать, как упростить правила, не изменяя конфигурацию.
+    Lint,
+    /// Показать недавние события об отброшенных пакетах.
+    Events {
+        /// Продолжать выводить новые события до Ctrl+C.
+        #[arg(long)]
+        follow: bool,
+        /// Только пакеты с этого адреса или из этой сети.
+        #[arg(long)]
+        src: Option<ipnetwork::Ipv4Network>,
+        /// Только события с этой причиной (например, blocked-ip).
+        #[arg(long, value_parser = events::parse_reason)]
+        reason: Option<firewall_common::DropReason>,
+        /// Только пакеты из этой страны (код или название).
+        #[arg(long, value_parser = events::parse_country)]
+        country: Option<u16>,
+        /// Выводить по одному JSON-объекту на строку, даже с log-format: text.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Вывести правила конфигурации в формате другого файрволла, а с файлом — записать
+    /// их пакетом правил для import на другой машине.
+    Export {
+        #[arg(long, value_enum, default_value_t = export::Format::Nftables)]
+        format: export::Format,
+        /// Файл пакета правил (JSON).
+        #[arg(conflicts_with = "format")]
+        file: Option<PathBuf>,
+        /// Включить в пакет интерфейсы; без этого import оставит локальные.
+        #[arg(long, requires = "file")]
+        with_iface: bool,
+    },
+    /// Заменить конфигурацию пакетом правил из export, проверив его и спросив подтверждение.
+    Import { file: PathBuf },
+    /// Непрерывно показывать скорость пропуска и отбрасывания пакетов.
+    Rate {
+        /// Интерфейс для заголовка строк; по умолчанию из конфигурации.
+        #[arg(long)]
+        iface: Option<String>,
+        /// Интервал между замерами в секундах.
+        #[arg(long, default_value_t = 1)]
+        interval: u64,
+    },
+    /// Замерить, сколько времени на пакет занимает каждый тип правил.
+    Profile {
+        /// Длительность замера в секундах.
+        #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+        duration: u64,
+    },
+    /// Собрать правила из конфигурации в файл политики для apply-policy.
+    CompilePolicy {
+        /// Куда записать политику.
+        #[arg(short, long, default_value = "policy.bin")]
+        output: PathBuf,
+    },
+    /// Заменить правила запущенного файрволла политикой из файла.
+    ApplyPolicy {
+        policy: PathBuf,
+        /// Сколько секунд доживают принятые соединения к портам, которые политика больше не
+        /// разрешает; 0 — обрываются сразу.
+        #[arg(long, default_value_t = 0)]
+        grace: u32,
+    },
+    /// Прогнать запись трафика через правила и сверить решения с эталоном.
+    Test {
+        /// Запись трафика в формате pcap.
+        #[arg(long)]
+        pcap: PathBuf,
+        /// Эталон: JSON с ожидаемым решением для каждого номера пакета.
+        #[arg(long)]
+        expect: PathBuf,
+    },
+    /// Замерить, сколько решений в секунду правила принимают на этой машине.
+    Bench {
+        /// Запись трафика в формате pcap; её пакеты проходят правила по кругу.
+        #[arg(long)]
+        pcap: PathBuf,
+        /// Сколько длится замер (5s, 1m).
+        #[arg(long, default_value = "5s", value_parser = block::parse_duration)]
+        duration: Duration,
+    },
+    /// Заблокировать адрес в запущенном файрволле, при желании после испытания.
+    Block {
+        ip: std::net::Ipv4Addr,
+        /// Сначала только считать пакеты с адреса в течение этого срока (30s, 10m, 1h).
+        #[arg(long, value_parser = block::parse_duration)]
+        stage: Option<Duration>,
+        /// Наибольшая частота совпадений за испытание, пакетов в секунду, при которой
+        /// правило применяется.
+        #[arg(long, default_value_t = 50.0, requires = "stage")]
+        max_rate: f64,
+    },
+    /// Временно пропускать весь трафик, не выгружая программу.
+    Pause,
+    /// Возобновить фильтрацию после pause.
+    Resume,
+    /// Аварийный режим: отбрасывать весь трафик, кроме fast-accept-prefixes; off выключает.
+    Panic {
+        #[arg(value_enum, default_value_t = control::Switch::On)]
+        state: control::Switch,
+        /// Включить, даже если fast-accept-prefixes пуст и отрезано будет всё.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Один раз вывести счётчики; без запущенного файрволла код выхода 1.
+    Stats {
+        /// Вывести счётчики и все адреса источника одним объектом JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Снять с интерфейса программу XDP, оставшуюся после аварийного выхода загрузчика.
+    Detach {
+        /// Интерфейс; по умолчанию все из конфигурации.
+        #[arg(long)]
+        iface: Option<String>,
+    },
+    /// Показать источники, открывшие knock-port стуком, и сколько им осталось.
+    Knocks,
+    /// Вывести правила из карт запущенного файрволла, срабатывания записей blocked-ips и
+    /// расхождения с конфигурацией; при расхождениях код выхода 1.
+    DumpRules,
+    /// Перевести правила запущенного файрволла на текущую конфигурацию без перезапуска и
+    /// вывести, что изменилось; если файрволл не запущен, код выхода 1.
+    Apply,
+    /// Прогнать через программу XDP запущенного файрволла пробные пакеты (на разрешённый
+    /// порт, из blocked-ips, из заблокированной страны) и сверить её действия с
+    /// конфигурацией; при расхождениях код выхода 1.
+    SelfTest,
+    /// Показать, запущен ли файрволл, и его счётчики.
+    Status {
+        /// Сверить счётчики программы с XDP-счётчиками драйвера интерфейса.
+        #[arg(long)]
+        kernel_stats: bool,
+    },
+}
+
 fn main() {
-    ensure_config_exists();
+    let cli = Cli::parse();
+    if let Some(path) = cli.config {
+        config::set_main_path(path);
+    }
+    let names = cli.resolve_names.then(rdns::Resolver::new);
+    if let Some(command) = cli.command {
+        let code = match command {
+            CliCommand::Run { iface, ports, metrics_addr, force } => {
+                run_direct(cli.rules_dir.as_deref(), iface, ports, metrics_addr, force)
+            }
+            CliCommand::SetIface { names, force } => set_iface(&names, force, cli.config_symlink),
+            CliCommand::ShowConfig => match load_config(cli.rules_dir.as_deref()) {
+                Some(config) => {
+                    print!("{}", config::render(&config));
+                    0
+                }
+                None => 1,
+            },
+            CliCommand::Migrate => migrate::run(),
+            CliCommand::Doctor => doctor::run(),
+            CliCommand::Check { json } => check::run(cli.rules_dir.as_deref(), json),
+            CliCommand::Lint => run_lint(cli.rules_dir.as_deref()),
+            CliCommand::Events {
+                follow,
+                src,
+                reason,
+                country,
+                json,
+            } => {
+                let format = event_format(cli.rules_dir.as_deref());
+                let json = json || format == config::LogFormat::Json;
+                events::run(&events::Filter { src, reason, country }, follow, json)
+            }
+            CliCommand::Export { format, file: None, .. } => {
+                run_export(cli.rules_dir.as_deref(), format)
+            }
+            CliCommand::Export { file: Some(file), with_iface, .. } => {
+                run_export_bundle(cli.rules_dir.as_deref(), &file, with_iface)
+            }
+            CliCommand::Import { file } => run_import(&file, cli.config_symlink),
+            CliCommand::Rate { iface, interval } => {
+                run_rate(cli.rules_dir.as_deref(), iface, interval)
+            }
+            CliCommand::Profile { duration } => profile::run(duration),
+            CliCommand::CompilePolicy { output } => match load_config(cli.rules_dir.as_deref()) {
+                Some(config) => policy::compile(&config, &output),
+                None => 1,
+            },
+            CliCommand::ApplyPolicy { policy, grace } => policy::apply(&policy, grace),
+            CliCommand::Test { pcap, expect } => match load_config(cli.rules_dir.as_deref()) {
+                Some(config) => replay::run(&config, &pcap, &expect),
+                None => 1,
+            },
+            CliCommand::Bench { pcap, duration } => match load_config(cli.rules_dir.as_deref()) {
+                Some(config) => bench::run(&config, &pcap, duration),
+                None => 1,
+            },
+            CliCommand::Block { ip, stage, max_rate } => {
+                block::run(ip, stage, max_rate, cli.config_symlink)
+            }
+            CliCommand::Pause => control::pause(),
+            CliCommand::Resume => control::resume(),
+            CliCommand::Panic { state, force } => control::panic(state, force),
+            CliCommand::Stats { json } => print_stats(json, names.as_ref()),
+            CliCommand::Detach { iface } => match iface {
+                Some(iface) => detach::run(&iface),
+                None => match load_config(cli.rules_dir.as_deref()).map(|config| config.ifaces) {
+                    Some(ifaces) if ifaces.is_empty() => {
+                        println!("Интерфейс не задан: укажите --iface.");
+                        1
+                    }
+                    Some(ifaces) => {
+                        ifaces.iter().map(|iface| detach::run(iface)).max().unwrap_or(0)
+                    }
+                    None => 1,
+                },
+            },
+            CliCommand::Knocks => knocks::run(),
+            CliCommand::Apply => match load_config(cli.rules_dir.as_deref()) {
+                Some(config) => apply::run(&config),
+                None => 1,
+            },
+            CliCommand::DumpRules => match load_config(cli.rules_dir.as_deref()) {
+                Some(config) => dump::run(&config),
+                None => 1,
+            },
+            CliCommand::SelfTest => match load_config(cli.rules_dir.as_deref()) {
+                Some(config) => selftest::run(&config),
+                None => 1,
+            },
+            CliCommand::Status { kernel_stats } => {
+                run_status(cli.rules_dir.as_deref(), kernel_stats, names.as_ref())
+            }
+        };
+        std::process::exit(code);
+    }
+
+    // Без терминала меню выводит управляющие последовательности в журнал службы, а выбор
+    // пункта ждёт ввода, которого не будет.
+    if !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+        println!(
+            "Меню работает только в терминале. Без него укажите команду, например \
+             firewall-cli run; список команд — firewall-cli --help."
+        );
+        std::process::exit(1);
+    }
+
+    // Каталог конфигурации должен существовать до того, как в нём появится блокировка.
+    ensure_config_exists(cli.config_symlink);
+
+    // Интерактивный режим меняет конфигурацию и запускает файрволл: второй экземпляр с той же
+    // конфигурацией не нужен.
+    let lock_dir = lock::config_dir(config::main_path());
+    let _lock = match lock::acquire(lock_dir) {
+        Ok(lock::Acquire::Locked(lock)) => lock,
+        Ok(lock::Acquire::Busy(pid)) => {
+            let owner = pid.map(|pid| format!(" (PID {pid})")).unwrap_or_default();
+            println!(
+                "В каталоге {} уже запущен другой экземпляр firewall-cli{owner}.",
+                lock_dir.display()
+            );
+            std::process::exit(1);
+        }
+        Err(e) => {
+            let path = lock_dir.join(lock::LOCK_FILE);
+            println!("Не удалось создать файл блокировки {}: {e}", path.display());
+            std::process::exit(1);
+        }
+    };
 
     let running = Arc::new(AtomicBool::new(true));
     {
@@ -30,7 +406,8 @@ fn main() {
         // Сброс флага перед каждым запуском
         running.store(true, Ordering::SeqCst);
 
-        if !show_main_menu(&running) {
+        if !show_main_menu(&running, cli.rules_dir.as_deref(), cli.config_symlink, names.as_ref())
+        {
             break;
         }
     }
@@ -38,25 +415,266 @@ fn main() {
     println!("Программа завершена.");
 }
 
-fn show_main_menu(running: &Arc<AtomicBool>) -> bool {
+/// Загружает конфигурацию вместе с каталогом правил; ошибки выводит пользователю.
+fn load_config(rules_dir: Option<&Path>) -> Option<config::Config> {
+    match config::load_effective(config::main_path(), rules_dir) {
+        Ok(config) => Some(config),
+        Err(errors) => {
+            println!("Конфигурация содержит ошибки:");
+            for error in &errors {
+                println!("  {error}");
+            }
+            None
+        }
+    }
+}
+
+/// `log-format` из конфигурации; испорченная конфигурация не мешает смотреть события, они
+/// тогда выводятся текстом.
+fn event_format(rules_dir: Option<&Path>) -> config::LogFormat {
+    config::load_effective(config::main_path(), rules_dir)
+        .map_or(config::LogFormat::default(), |config| config.log_format)
+}
+
+fn run_lint(rules_dir: Option<&Path>) -> i32 {
+    let Some(config) = load_config(rules_dir) else {
+        return 1;
+    };
+
+    let suggestions = lint::lint(&config);
+    if suggestions.is_empty() {
+        println!("Замечаний нет.");
+    }
+    for suggestion in &suggestions {
+        println!("- {}", suggestion.message);
+    }
+    0
+}
+
+fn run_export(rules_dir: Option<&Path>, format: export::Format) -> i32 {
+    let Some(config) = load_config(rules_dir) else {
+        return 1;
+    };
+    match format {
+        export::Format::Nftables => print!("{}", export::nftables(&config)),
+    }
+    0
+}
+
+fn run_export_bundle(rules_dir: Option<&Path>, file: &Path, with_iface: bool) -> i32 {
+    let Some(config) = load_config(rules_dir) else {
+        return 1;
+    };
+    if let Err(e) = fs::write(file, bundle::encode(&config, with_iface)) {
+        println!("Не удалось записать {}: {e}", file.display());
+        return 1;
+    }
+    println!("Пакет правил записан в {}.", file.display());
+    0
+}
+
+/// Выполняет `firewall-cli import`: проверяет пакет целиком и только потом, после
+/// подтверждения, переписывает основной файл конфигурации.
+fn run_import(file: &Path, symlinks: config::SymlinkPolicy) -> i32 {
+    let text = match fs::read_to_string(file) {
+        Ok(text) => text,
+        Err(e) => {
+            println!("Не удалось прочитать {}: {e}", file.display());
+            return 1;
+        }
+    };
+    let mut imported = match bundle::decode(&text) {
+        Ok(imported) => imported,
+        Err(errors) => {
+            println!("Пакет {} не принят:", file.display());
+            for error in &errors {
+                println!("  {error}");
+            }
+            return 1;
+        }
+    };
+    let path = config::main_path();
+    let content = match config::read(path) {
+        Ok(content) => content,
+        Err(e) => {
+            println!("Не удалось прочитать {}: {e}", path.display());
+            return 1;
+        }
+    };
+    if !imported.has_iface {
+        match config::Config::load(path) {
+            Ok(local) => imported.config.ifaces = local.ifaces,
+            Err(errors) => {
+                println!("В пакете нет iface, а взять его из текущей конфигурации нельзя:");
+                for error in &errors {
+                    println!("  {error}");
+                }
+                return 1;
+            }
+        }
+    }
+    let updated = if config::is_toml(path) {
+        config::render_toml(&imported.config)
+    } else {
+        config::render(&imported.config)
+    };
+    if config::changed_keys(path, &content, &updated).is_empty() {
+        println!("Конфигурация уже совпадает с пакетом.");
+        return 0;
+    }
+    if !confirm_write(path, &content, &updated) {
+        println!("Импорт отменён.");
+        return 1;
+    }
+    let result = config::backup(path)
+        .and_then(|()| config::write(path, &updated, symlinks))
+        .and_then(|()| history::push(path, &content));
+    if let Err(e) = result {
+        println!("Не удалось записать конфигурацию: {e}");
+        return 1;
+    }
+    println!(
+        "Правила из {} записаны в {}; запущенный файрволл применит их после перезапуска.",
+        file.display(),
+        path.display()
+    );
+    0
+}
+
+fn run_rate(rules_dir: Option<&Path>, iface: Option<String>, interval: u64) -> i32 {
+    let iface = match iface {
+        Some(iface) => iface,
+        None => match load_config(rules_dir) {
+            Some(config) => config.ifaces.first().cloned().unwrap_or_else(|| "eth0".to_string()),
+            None => return 1,
+        },
+    };
+    rate::run(&iface, Duration::from_secs(interval.max(1)))
+}
+
+fn print_stats(json: bool, names: Option<&rdns::Resolver>) -> i32 {
+    let snapshot = stats::fetch_stats().and_then(|snapshot| match snapshot {
+        Some(snapshot) if json => Ok(Some((snapshot, stats::fetch_sources()?))),
+        Some(snapshot) => Ok(Some((snapshot, Vec::new()))),
+        None => Ok(None),
+    });
+    match snapshot {
+        Ok(Some((snapshot, sources))) if json => {
+            println!("{}", stats::format_json(&snapshot, &sources));
+            0
+        }
+        Ok(Some((snapshot, _))) => {
+            if let Some(names) = names {
+                names.prefetch(snapshot.top_sources.iter().map(|&(addr, ..)| addr));
+            }
+            print!("{}", stats::format_stats(&snapshot, names));
+            0
+        }
+        Ok(None) => {
+            eprintln!("Файрволл не запущен: статистика недоступна.");
+            1
+        }
+        Err(e) => {
+            eprintln!("Не удалось прочитать статистику: {e:#}");
+            1
+        }
+    }
+}
+
+fn run_status(
+    rules_dir: Option<&Path>,
+    kernel_stats: bool,
+    names: Option<&rdns::Resolver>,
+) -> i32 {
+    let app = match stats::fetch_stats() {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            println!("Не удалось прочитать статистику: {e:#}");
+            return 1;
+        }
+    };
+    match &app {
+        Some(current) => {
+            if let Some(names) = names {
+                names.prefetch(current.top_sources.iter().map(|&(addr, ..)| addr));
+            }
+            println!("{}", stats::format_stats(current, names));
+        }
+        None => println!("Файрволл не запущен."),
+    }
+    match control::current_mode() {
+        Ok(Some(mode)) => println!("Режим: {}", control::mode_name(mode)),
+        Ok(None) => {}
+        Err(e) => println!("Не удалось прочитать режим: {e:#}"),
+    }
+    if let Ok(Some(true)) = control::panic_active() {
+        println!("{}", colored(control::PANIC_BANNER, RED));
+    }
+
+    if kernel_stats {
+        let Some(config) = load_config(rules_dir) else {
+            return 1;
+        };
+        let ifaces = firewall_ifaces(&config);
+        // Драйвер считает с подключения программы, поэтому итоги прошлых запусков не нужны.
+        let current = match stats::fetch_current_stats() {
+            Ok(current) => current,
+            Err(e) => {
+                println!("Не удалось прочитать статистику: {e:#}");
+                return 1;
+            }
+        };
+        for iface in &ifaces {
+            match kernel_stats::read(iface) {
+                Ok(kernel) => {
+                    print!("{}", kernel_stats::format_cross_check(iface, kernel, current.as_ref()));
+                }
+                Err(e) => {
+                    println!("Не удалось прочитать счётчики драйвера {iface}: {e:#}");
+                    return 1;
+                }
+            }
+        }
+    }
+    0
+}
+
+fn show_main_menu(
+    running: &Arc<AtomicBool>,
+    rules_dir: Option<&Path>,
+    symlinks: config::SymlinkPolicy,
+    names: Option<&rdns::Resolver>,
+) -> bool {
     clear_screen();
+    // Подробности ошибок конфигурации покажет запуск файрволла, меню тогда строится по
+    // умолчанию.
+    let loaded = config::load_effective(config::main_path(), rules_dir);
+    print_config_summary(loaded.as_ref().ok());
     println!("Выберите действие:");
-    let items = vec![
-        "1. Запустить файрволл",
-        "2. Настроить конфигурацию",
-        "3. Выбрать интерфейс",
-        "4. Выход",
-    ];
+    let actions = match loaded {
+        Ok(config) => menu::build(&config.menu_order, &config.menu_hidden),
+        Err(_) => menu::build(&[], &[]),
+    };
+    let items: Vec<String> = actions
+        .iter()
+        .enumerate()
+        .map(|(i, action)| format!("{}. {}", i + 1, action.label()))
+        .collect();
 
     let selection = Select::new().items(&items).default(0).interact();
 
     match selection {
-        Ok(choice) => match choice {
-            0 => run_firewall(running),
-            1 => configure_file(),
-            2 => choose_interface(),
-            3 => return false, // выход
-            _ => {}
+        Ok(choice) => match actions.get(choice) {
+            Some(menu::Action::Run) => run_from_menu(running, rules_dir),
+            Some(menu::Action::Configure) => configure_file(),
+            Some(menu::Action::ChooseInterface) => choose_interface(symlinks),
+            Some(menu::Action::BlockedIps) => manage_blocked_ips(symlinks),
+            Some(menu::Action::BlockedCountries) => manage_blocked_countries(symlinks),
+            Some(menu::Action::Undo) => undo_last_change(symlinks),
+            Some(menu::Action::Stats) => show_stats(names),
+            Some(menu::Action::Panic) => toggle_panic(),
+            Some(menu::Action::Exit) => return false, // выход
+            None => {}
         },
         Err(e) => {
             if e.to_string().contains("interrupted") {
@@ -71,195 +689,1138 @@ fn show_main_menu(running: &Arc<AtomicBool>) -> bool {
     true
 }
 
+/// Коды цветов ANSI для состояния в сводке меню.
+const GREEN: u8 = 32;
+const RED: u8 = 31;
+
+/// `text` в цвете ANSI `color`; с непустой переменной `NO_COLOR` — без цвета.
+fn colored(text: &str, color: u8) -> String {
+    if std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) {
+        text.to_string()
+    } else {
+        format!("{esc}[{color}m{text}{esc}[0m", esc = 27 as char)
+    }
+}
+
+/// Сводка над пунктами меню: запущен ли файрволл и действующая конфигурация; `None` —
+/// конфигурация с ошибками.
+///
+/// Пока открыто меню, файрволл из этого же меню не работает (запуск ждёт Ctrl+C), поэтому
+/// состояние берётся из закреплённых карт: оно видит и экземпляр, запущенный отдельно.
+fn print_config_summary(config: Option<&config::Config>) {
+    let firewall = match control::current_mode() {
+        Ok(Some(mode)) => colored(&format!("запущен, {}", control::mode_name(mode)), GREEN),
+        Ok(None) => colored("остановлен", RED),
+        Err(e) => colored(&format!("состояние неизвестно ({e:#})"), RED),
+    };
+    println!("Файрволл: {firewall}");
+    if let Ok(Some(true)) = control::panic_active() {
+        println!("{}", colored(control::PANIC_BANNER, RED));
+    }
+    let Some(config) = config else {
+        let warning = "с ошибками, подробности — firewall-cli check";
+        println!("Конфигурация: {}", colored(warning, RED));
+        println!();
+        return;
+    };
+    println!("Конфигурация: {}", colored("верна", GREEN));
+    let ifaces = if config.ifaces.is_empty() {
+        "не задан".to_string()
+    } else {
+        config.ifaces.join(", ")
+    };
+    let countries = if config.blocked_countries.is_empty() {
+        "нет".to_string()
+    } else {
+        config.blocked_countries.join(", ")
+    };
+    println!("Интерфейс: {ifaces}");
+    println!(
+        "Разрешённых портов: {}, заблокированных адресов: {}, заблокированные страны: {countries}",
+        config.allowed_ports.len(),
+        config.blocked_ips.len() + config.blocked_ips6.len()
+    );
+    println!();
+}
+
 fn clear_screen() {
     print!("{esc}c", esc = 27 as char);
     let _ = std::io::stdout().flush();
 }
 
-fn ensure_config_exists() {
-    let path = "config.cfg";
-    if !Path::new(path).exists() {
-        let default = "\"iface\"\neth0\n\"allowed-ports\"\n80, 443\n\"blocked-ips\"\n\n\"blocked-countries\"\n";
-        fs::write(path, default).expect("Не удалось создать config.cfg");
+fn ensure_config_exists(symlinks: config::SymlinkPolicy) {
+    let path = config::main_path();
+    // symlink_metadata, а не exists: висячая ссылка — тоже чужой файл, её не заменяем.
+    if fs::symlink_metadata(path).is_err() {
+        // Без --config новый каталог получает config.toml, а не config.cfg.
+        let path = if path == Path::new(config::LEGACY_PATH) {
+            Path::new(config::TOML_PATH)
+        } else {
+            path
+        };
+        let template = config::toml_template();
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            if let Err(e) = fs::create_dir_all(dir) {
+                println!("Не удалось создать каталог {}: {e}", dir.display());
+                std::process::exit(1);
+            }
+        }
+        let content = if config::is_toml(path) {
+            template
+        } else {
+            config::render(&config::Config::parse_toml(&template).unwrap_or_default())
+        };
+        match config::create(path, &content) {
+            Ok(()) => {}
+            // Файл создал одновременно запущенный firewall-cli.
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(e) => {
+                println!("Не удалось создать {}: {e}", path.display());
+                std::process::exit(1);
+            }
+        }
+    } else if !config::is_toml(path) {
+        match config::migrate(path, symlinks) {
+            Ok(notes) if notes.is_empty() => {}
+            Ok(notes) => {
+                println!("config.cfg обновлён до версии {}:", config::CONFIG_VERSION);
+                for note in notes {
+                    println!("  {note}.");
+                }
+            }
+            Err(e) => println!("Не удалось обновить config.cfg: {e}"),
+        }
+    }
+}
+
+/// Предупреждает, что с `dry-run` файрволл ничего не отбрасывает, с `fast-path` ничего
+/// не считает, и о противоречиях в правилах.
+fn print_dry_run_banner(config: &config::Config) {
+    if config.dry_run {
+        println!("{}", "=".repeat(72));
+        println!("ПРОБНЫЙ РЕЖИМ (dry-run): пакеты НЕ отбрасываются, весь трафик пропускается.");
+        println!("Пакеты, которые отбросили бы правила, видны в firewall-cli events и stats.");
+        println!("{}\n", "=".repeat(72));
+    }
+    if config.fast_path {
+        println!("РЕЖИМ ЗАМЕРА (fast-path): логи, события и счётчики отключены.\n");
+    }
+    for warning in conflicts::warnings(config) {
+        println!("Предупреждение: {warning}");
+    }
+}
+
+/// Пункт меню «Запустить»: ошибка запуска показывается, и меню возвращается, а не
+/// запускает файрволл без правил.
+fn run_from_menu(running: &Arc<AtomicBool>, rules_dir: Option<&Path>) {
+    if let Err(e) = run_firewall(running, rules_dir) {
+        println!("Файрволл не запущен: {e:#}.");
+        thread::sleep(Duration::from_secs(3));
     }
 }
 
-fn run_firewall(running: &Arc<AtomicBool>) {
+/// Занимает интерфейсы файрволла, чтобы второй экземпляр firewall-cli, запущенный из
+/// другого каталога, не привязал к ним свою программу. Блокировки держатся, пока живёт
+/// результат.
+fn lock_ifaces(ifaces: &[String]) -> Result<Vec<lock::InstanceLock>, String> {
+    let mut locks = Vec::new();
+    for iface in ifaces {
+        match lock::acquire_iface(iface) {
+            Ok(lock::Acquire::Locked(lock)) => locks.push(lock),
+            Ok(lock::Acquire::Busy(pid)) => {
+                let owner = pid.map(|pid| format!(" (PID {pid})")).unwrap_or_default();
+                return Err(format!("интерфейс {iface} уже защищает другой firewall-cli{owner}"));
+            }
+            Err(e) => {
+                let path = lock::iface_lock_path(iface);
+                return Err(format!("не удалось создать файл блокировки {}: {e}", path.display()));
+            }
+        }
+    }
+    Ok(locks)
+}
+
+/// Интерфейсы, которые получает загрузчик в `firewall_args`.
+fn firewall_ifaces(config: &config::Config) -> Vec<String> {
+    match &config.ifaces {
+        ifaces if ifaces.is_empty() => vec!["eth0".to_string()],
+        ifaces => ifaces.clone(),
+    }
+}
+
+fn run_firewall(running: &Arc<AtomicBool>, rules_dir: Option<&Path>) -> anyhow::Result<()> {
     println!("Запуск файрволла (нажмите Ctrl+C для возврата в меню)");
 
-    let config = parse_config("config.cfg");
-    let iface = config.get("iface").cloned().unwrap_or_else(|| "eth0".to_string());
+    let config = load_config(rules_dir).context("конфигурация не загружена")?;
+    if let Some(message) = conflicts::no_open_ports(&config) {
+        println!("Внимание: allowed-ports: {message}.");
+        let confirmed = Confirm::new()
+            .with_prompt("Всё равно запустить?")
+            .default(false)
+            .interact();
+        match confirmed {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(e) => {
+                input_interrupted(&e);
+                return Ok(());
+            }
+        }
+    }
+    let mut command = privileges::firewall_command(&firewall_args(&config), config.escalator)
+        .map_err(anyhow::Error::msg)?;
+    let ifaces = firewall_ifaces(&config);
+    let _locks = lock_ifaces(&ifaces).map_err(anyhow::Error::msg)?;
+
+    println!("Выполняется команда:\n");
+    println!("{}\n", privileges::display(&command));
+    print_dry_run_banner(&config);
+    if let Err(e) = reload::install() {
+        println!("Не удалось установить обработчик SIGHUP, перечитывание недоступно: {e}");
+    }
+    loop {
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                reload::restore();
+                return Err(anyhow::Error::from(e).context("не удалось запустить загрузчик"));
+            }
+        };
+        println!("Сервис запущен :)");
+        let _event_log = start_event_log(&config);
+        let _syslog = start_syslog(&config);
+        let mut recorder = totals::Recorder::new();
+        let mut checked_at = Instant::now();
+        let mut missing = None;
+
+        while running.load(Ordering::SeqCst) {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    reload::restore();
+                    recorder.finish();
+                    println!("\nФайрволл завершился сам ({status}). Возврат в главное меню...");
+                    thread::sleep(Duration::from_secs(3));
+                    return Ok(());
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    println!("\nНе удалось проверить процесс файрволла: {e}");
+                    break;
+                }
+            }
+            if reload::requested() {
+                reload::apply(load_config(rules_dir));
+            }
+            if checked_at.elapsed() >= IFACE_CHECK_INTERVAL {
+                checked_at = Instant::now();
+                missing = missing_iface(&ifaces);
+                if missing.is_some() {
+                    break;
+                }
+            }
+            recorder.poll();
+            thread::sleep(Duration::from_millis(200));
+        }
+
+        // Ctrl+C загрузчик получил сам, а об исчезнувшем интерфейсе его надо попросить.
+        if let Some(iface) = &missing {
+            println!("\nИнтерфейс {iface} пропал, программа XDP на нём больше не работает.");
+            unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+        }
+        match stop_child(&mut child) {
+            Ok(status) => println!("\nФайрволл остановлен ({status})."),
+            Err(e) => println!("\nНе удалось остановить файрволл: {e}"),
+        }
+        recorder.finish();
+        let Some(iface) = missing else {
+            break;
+        };
+        if !config.reattach_iface {
+            thread::sleep(Duration::from_secs(3));
+            break;
+        }
+        println!("Ожидание интерфейса {iface} (reattach-iface); Ctrl+C — возврат в меню...");
+        if !wait_for_ifaces(running, &ifaces) {
+            break;
+        }
+        println!("Интерфейс {iface} снова на месте, перезапуск файрволла.");
+    }
+
+    reload::restore();
+    println!("Возврат в главное меню...");
+    Ok(())
+}
+
+/// Первый из `ifaces`, которого больше нет в системе.
+fn missing_iface(ifaces: &[String]) -> Option<String> {
+    let present: Vec<String> = datalink::interfaces().into_iter().map(|i| i.name).collect();
+    ifaces.iter().find(|iface| !present.contains(iface)).cloned()
+}
+
+/// Ждёт, пока все `ifaces` снова появятся; `false` — ожидание прервано Ctrl+C.
+fn wait_for_ifaces(running: &AtomicBool, ifaces: &[String]) -> bool {
+    while running.load(Ordering::SeqCst) {
+        if missing_iface(ifaces).is_none() {
+            return true;
+        }
+        thread::sleep(IFACE_CHECK_INTERVAL);
+    }
+    false
+}
+
+/// Код выхода `firewall-cli run`, если с `config` запускать нельзя: при политике deny не
+/// открыт ни один порт, а `--force` не задан.
+fn refuse_without_ports(config: &config::Config, force: bool) -> Option<i32> {
+    let message = conflicts::no_open_ports(config).filter(|_| !force)?;
+    println!(
+        "Файрволл не запущен: allowed-ports: {message}. Задайте порты (--ports) или \
+         запустите с --force."
+    );
+    Some(1)
+}
+
+/// Выполняет `firewall-cli run`; код выхода — код выхода загрузчика.
+fn run_direct(
+    rules_dir: Option<&Path>,
+    ifaces: Vec<String>,
+    ports: Vec<config::AllowedPort>,
+    metrics_addr: Option<std::net::SocketAddr>,
+    force: bool,
+) -> i32 {
+    // Переопределения из командной строки действуют и после перечитывания по SIGHUP.
+    let load = || {
+        let mut config = load_config(rules_dir)?;
+        if !ifaces.is_empty() {
+            config.ifaces = ifaces.clone();
+        }
+        if !ports.is_empty() {
+            config.allowed_ports = ports.clone();
+        }
+        Some(config)
+    };
+    let Some(config) = load() else {
+        return 1;
+    };
+    if let Some(code) = refuse_without_ports(&config, force) {
+        return code;
+    }
+    let _locks = match lock_ifaces(&firewall_ifaces(&config)) {
+        Ok(locks) => locks,
+        Err(e) => {
+            println!("Не удалось запустить файрволл: {e}.");
+            return 1;
+        }
+    };
+    let command = privileges::firewall_command(&firewall_args(&config), config.escalator);
+    let mut command = match command {
+        Ok(command) => command,
+        Err(e) => {
+            println!("Не удалось запустить файрволл: {e}.");
+            return 1;
+        }
+    };
+    println!("{}", privileges::display(&command));
+    print_dry_run_banner(&config);
+    if let Some(addr) = metrics_addr {
+        if let Err(e) = metrics::serve(addr) {
+            println!("Не удалось открыть {addr} для метрик: {e}");
+            return 1;
+        }
+        println!("Метрики Prometheus: http://{addr}/metrics");
+    }
+
+    // Ctrl+C получает и загрузчик: он сам отключает программу XDP, а firewall-cli
+    // дожидается его, чтобы вернуть его код выхода.
+    if let Err(e) = ctrlc::set_handler(|| {}) {
+        println!("Не удалось установить обработчик Ctrl+C: {e}");
+        return 1;
+    }
+    if let Err(e) = reload::install() {
+        println!("Не удалось установить обработчик SIGHUP: {e}");
+        return 1;
+    }
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            println!("Не удалось запустить файрволл: {e}");
+            return 1;
+        }
+    };
+    let _event_log = start_event_log(&config);
+    let _syslog = start_syslog(&config);
+    let mut recorder = totals::Recorder::new();
+    let code = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status.code().unwrap_or(1),
+            Ok(None) => {}
+            Err(e) => {
+                println!("Не удалось проверить процесс файрволла: {e}");
+                break 1;
+            }
+        }
+        if reload::requested() {
+            reload::apply(load());
+        }
+        recorder.poll();
+        thread::sleep(Duration::from_millis(200));
+    };
+    recorder.finish();
+    code
+}
+
+/// Выполняет `firewall-cli set-iface`.
+fn set_iface(names: &[String], force: bool, symlinks: config::SymlinkPolicy) -> i32 {
+    let names = config::normalize_list("iface", names).items;
+    if !force {
+        let interfaces = datalink::interfaces();
+        for name in &names {
+            match interfaces.iter().find(|iface| iface.name == *name) {
+                None => {
+                    println!("Интерфейса '{name}' нет в системе (--force — записать всё равно).");
+                    return 1;
+                }
+                Some(iface) if !iface.is_up() => {
+                    println!("Предупреждение: интерфейс '{name}' выключен.");
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    match update_config_iface(&names, false, symlinks) {
+        Ok(_) => {
+            let names = names.join(", ");
+            println!("Интерфейсы '{names}' сохранены в {}", config::main_path().display());
+            0
+        }
+        Err(e) => {
+            println!("Не удалось записать конфигурацию: {e}");
+            1
+        }
+    }
+}
 
-    let ports = config
-        .get("allowed-ports")
-        .unwrap_or(&String::new())
-        .split(',')
-        .map(str::trim)
-        .filter(|s| !s.is_empty())
-        .collect::<Vec<_>>()
-        .join(" ");
+/// Журнал событий в `log-file`, если он задан; пишется, пока значение живо.
+fn start_event_log(config: &config::Config) -> Option<event_log::EventLog> {
+    let path = config.log_file.clone()?;
+    let max_size = config.log_max_size.unwrap_or(event_log::DEFAULT_MAX_SIZE);
+    Some(event_log::EventLog::start(path, max_size))
+}
 
-    let blocked_ips = config
-        .get("blocked-ips")
-        .unwrap_or(&String::new())
-        .split(',')
-        .map(str::trim)
-        .filter(|s| !s.is_empty())
-        .collect::<Vec<_>>()
-        .join(" ");
+/// Передача событий в системный журнал, если включён `syslog`; идёт, пока значение живо.
+fn start_syslog(config: &config::Config) -> Option<syslog::Syslog> {
+    config
+        .syslog
+        .then(|| syslog::Syslog::start(config.syslog_facility, config.log_format))
+}
 
-    let blocked_countries = config
-        .get("blocked-countries")
-        .unwrap_or(&String::new())
-        .split(',')
-        .map(str::trim)
-        .filter(|s| !s.is_empty())
-        .collect::<Vec<_>>()
-        .join(" ");
+/// Аргументы загрузчика для конфигурации.
+fn firewall_args(config: &config::Config) -> Vec<String> {
+    fn strings<T: ToString>(items: &[T]) -> Vec<String> {
+        items.iter().map(ToString::to_string).collect()
+    }
 
-    let mut parts = vec![format!("--iface {}", iface.trim())];
+    // Каждое значение — отдельный элемент argv: списки загрузчик принимает через
+    // `num_args`, а путь с пробелами не должен распасться на несколько аргументов.
+    let mut args = vec!["--iface".to_string()];
+    if config.ifaces.is_empty() {
+        args.push("eth0".to_string());
+    } else {
+        args.extend(config.ifaces.iter().cloned());
+    }
+    let push_list = |args: &mut Vec<String>, flag: &str, values: Vec<String>| {
+        if !values.is_empty() {
+            args.push(flag.to_string());
+            args.extend(values);
+        }
+    };
 
-    if !ports.is_empty() {
-        parts.push(format!("--ports {}", ports));
+    args.extend(["--attach-mode".to_string(), config.attach_mode.as_str().to_string()]);
+    if config.watchdog {
+        let secs = config.watchdog_interval.unwrap_or(config::DEFAULT_WATCHDOG_INTERVAL);
+        args.extend(["--watchdog".to_string(), secs.to_string()]);
+    }
+    args.extend(["--direction".to_string(), config.direction.as_str().to_string()]);
+    push_list(&mut args, "--ports", strings(&config.allowed_ports));
+    push_list(&mut args, "--blocked-src-ports", strings(&config.blocked_src_ports));
+    args.extend(["--port-match".to_string(), config.port_match.as_str().to_string()]);
+    args.extend(["--policy".to_string(), config.policy.as_str().to_string()]);
+    args.extend(["--log-level".to_string(), config.log_level.as_str().to_string()]);
+    args.extend(["--log-backend".to_string(), config.log_backend.as_str().to_string()]);
+    push_list(&mut args, "--blocked-ips", strings(&config.blocked_ips));
+    push_list(&mut args, "--blocked-ips6", strings(&config.blocked_ips6));
+    push_list(&mut args, "--blocked-masks", strings(&config.blocked_masks));
+    push_list(&mut args, "--blocked-macs", strings(&config.blocked_macs));
+    push_list(&mut args, "--blocked-countries", config.blocked_countries.clone());
+    if let Some(window) = config.blocked_countries_window {
+        args.extend(["--blocked-countries-window".to_string(), config::format_window(window)]);
     }
 
-    if !blocked_ips.is_empty() {
-        parts.push(format!("--blocked-ips {}", blocked_ips));
+    if let Some(db) = &config.country_db {
+        args.extend(["--country-db".to_string(), db.display().to_string()]);
     }
 
-    if !blocked_countries.is_empty() {
-        parts.push(format!("--blocked-countries {}", blocked_countries));
+    if let (Some(db), false) = (&config.region_db, config.blocked_regions.is_empty()) {
+        args.extend(["--region-db".to_string(), db.display().to_string()]);
+        push_list(&mut args, "--blocked-regions", strings(&config.blocked_regions));
     }
 
-    let final_command = format!("firewall");
+    if !config.blocked_endpoints.is_empty() {
+        push_list(&mut args, "--blocked-endpoints", strings(&config.blocked_endpoints));
+        args.extend(["--endpoint-match".to_string(), config.endpoint_match.as_str().to_string()]);
+    }
 
-    println!("Выполняется команда:\n");
-    println!("sudo {}\n", final_command);
-    println!("Сервис запущен :)");
+    push_list(&mut args, "--allowed-ips", strings(&config.allowed_ips));
+    push_list(&mut args, "--fast-accept-prefixes", strings(&config.fast_accept_prefixes));
+    push_list(&mut args, "--protected-ips", strings(&config.protected_ips));
 
-    let _ = Command::new("sudo").
-    arg(&final_command).
-    status();
+    if config.unwrap_ipip {
+        args.push("--unwrap-ipip".to_string());
+    }
 
+    if config.allow_icmp_echo {
+        args.push("--allow-icmp-echo".to_string());
+    }
 
-    while running.load(Ordering::SeqCst) {
-        thread::sleep(Duration::from_secs(1));
+    if config.allow_invalid_tcp_flags {
+        args.push("--allow-invalid-tcp-flags".to_string());
     }
 
-    println!("\nФайрволл остановлен. Возврат в главное меню...");
+    if config.strict_protocols {
+        args.push("--strict-protocols".to_string());
+    }
+    push_list(&mut args, "--protocols", strings(&config.protocols));
+    if let Some(port) = config.knock_port {
+        args.extend(["--knock-port".to_string(), port.to_string()]);
+        push_list(&mut args, "--knock-sequence", strings(&config.knock_sequence));
+        let timeout = config.knock_timeout.unwrap_or(config::DEFAULT_KNOCK_TIMEOUT);
+        args.extend(["--knock-timeout".to_string(), timeout.to_string()]);
+    }
+
+    if config.filter_multicast {
+        args.push("--filter-multicast".to_string());
+    }
+
+    if config.drop_fragments {
+        args.push("--drop-fragments".to_string());
+    }
+
+    if !config.allows_dns() {
+        args.push("--strict-dns".to_string());
+    }
+
+    if let Some(secs) = config.conntrack_timeout {
+        args.extend(["--conntrack-timeout".to_string(), secs.to_string()]);
+    }
+
+    if let Some(subnet) = config.arp_subnet {
+        args.extend(["--arp-subnet".to_string(), subnet.to_string()]);
+    }
+
+    if config.dry_run {
+        args.push("--dry-run".to_string());
+    }
+    if config.fast_path {
+        args.push("--fast-path".to_string());
+    }
+    if config.block_action != config::BlockAction::default() {
+        args.extend(["--block-action".to_string(), config.block_action.as_str().to_string()]);
+    }
+
+    // Всплеск без предела загрузчик не примет, а без предела он ничего и не значит.
+    if let Some(rate) = config.rate_limit {
+        args.extend(["--rate-limit".to_string(), rate.to_string()]);
+        if let Some(burst) = config.rate_burst {
+            args.extend(["--rate-burst".to_string(), burst.to_string()]);
+        }
+    }
+    if let Some(rate) = config.syn_rate_limit {
+        args.extend(["--syn-rate-limit".to_string(), rate.to_string()]);
+        if let Some(burst) = config.syn_rate_burst {
+            args.extend(["--syn-rate-burst".to_string(), burst.to_string()]);
+        }
+    }
+    if let Some(secs) = config.sweep_interval {
+        args.extend(["--sweep-interval".to_string(), secs.to_string()]);
+    }
+    if let Some(secs) = config.sweep_ttl {
+        args.extend(["--sweep-ttl".to_string(), secs.to_string()]);
+    }
+
+    push_list(&mut args, "--block-tcp-window", strings(&config.blocked_tcp_windows));
+    push_list(&mut args, "--event-fields", config.event_fields.clone());
+    args
+}
+
+/// Останавливает загрузчик, запущенный из меню, и дожидается его.
+///
+/// Ctrl+C в терминале получает вся группа процессов, так что загрузчик уже сам отключает
+/// программу XDP; ему даётся на это время, и только потом процесс убивается.
+fn stop_child(child: &mut Child) -> std::io::Result<ExitStatus> {
+    let started = Instant::now();
+    while started.elapsed() < CHILD_GRACE {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    child.kill()?;
+    child.wait()
+}
+
+/// Аргументы для показа в виде команды оболочки: аргумент с пробелами или кавычками
+/// берётся в одинарные кавычки.
+fn shell_words(args: &[String]) -> String {
+    let quote = |arg: &String| {
+        if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || "'\"\\$".contains(c)) {
+            arg.clone()
+        } else {
+            format!("'{}'", arg.replace('\'', "'\\''"))
+        }
+    };
+    args.iter().map(quote).collect::<Vec<_>>().join(" ")
+}
+
+/// Обновляемая таблица счётчиков до нажатия любой клавиши.
+///
+/// Клавишу ждёт отдельный поток, и таблица закрывается только после неё, даже если
+/// файрволл остановился: иначе поток остался бы читать ввод вместе со следующим меню.
+fn show_stats(names: Option<&rdns::Resolver>) {
+    let mut prev = match stats::fetch_stats() {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => {
+            println!("Файрволл не запущен: статистика недоступна.");
+            thread::sleep(Duration::from_secs(2));
+            return;
+        }
+        Err(e) => {
+            println!("Не удалось прочитать статистику: {e:#}");
+            thread::sleep(Duration::from_secs(2));
+            return;
+        }
+    };
+    let mut taken = Instant::now();
+
+    let pressed = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let pressed = Arc::clone(&pressed);
+        thread::spawn(move || {
+            // Ctrl+C в этом режиме терминала тоже приходит как клавиша.
+            let _ = dialoguer::console::Term::stdout().read_key();
+            pressed.store(true, Ordering::SeqCst);
+        })
+    };
+
+    let mut speed = None;
+    loop {
+        clear_screen();
+        println!("{}", stats::format_stats(&prev, names));
+        if let Some(speed) = &speed {
+            println!("{}\n", rate::format_rate("Сейчас", speed));
+        }
+        println!(
+            "Обновление каждые {} с, нажмите любую клавишу для возврата в меню",
+            STATS_REFRESH.as_secs()
+        );
+
+        let started = Instant::now();
+        while started.elapsed() < STATS_REFRESH && !pressed.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        if pressed.load(Ordering::SeqCst) {
+            break;
+        }
+        let now = Instant::now();
+        match stats::fetch_stats() {
+            Ok(Some(cur)) => {
+                speed = Some(rate::between(&prev, &cur, now - taken));
+                (prev, taken) = (cur, now);
+            }
+            Ok(None) => {
+                println!("\nФайрволл остановлен. Нажмите любую клавишу для возврата в меню.");
+                break;
+            }
+            Err(e) => {
+                println!("\nНе удалось прочитать статистику: {e:#}");
+                println!("Нажмите любую клавишу для возврата в меню.");
+                break;
+            }
+        }
+    }
+    let _ = reader.join();
 }
 
+/// Открывает основной файл конфигурации в `$EDITOR`. Перед этим файл копируется в
+/// [`config::backup_path`], и если после правки он не разбирается, предлагается вернуть копию.
+/// Изменённый файл можно вернуть и позже, через «Отменить последнее изменение».
 fn configure_file() {
     let editor = std::env::var("EDITOR").unwrap_or_else(|_| "nano".to_string());
+    let path = config::main_path();
+    let before = fs::read_to_string(path).ok();
+    let backed_up = match config::backup(path) {
+        Ok(()) => path.exists(),
+        Err(e) => {
+            println!("Не удалось сохранить резервную копию: {e}");
+            false
+        }
+    };
 
-    match Command::new(editor).arg("config.cfg").status() {
+    match Command::new(editor).arg(path).status() {
         Ok(status) if status.success() => {}
         Ok(_) => println!("Редактор завершился с ошибкой."),
-        Err(e) => println!("Ошибка запуска редактора: {e}"),
+        Err(e) => return println!("Ошибка запуска редактора: {e}"),
+    }
+    let after = fs::read_to_string(path).ok();
+    if let Some(before) = before.filter(|before| after.as_ref() != Some(before)) {
+        if let Err(e) = history::push(path, &before) {
+            println!("Не удалось сохранить версию для отмены: {e}");
+        }
     }
-}
 
-fn choose_interface() {
-    println!("Выберите интерфейс:");
+    let Err(errors) = config::Config::load(path) else {
+        return;
+    };
+    println!("В конфигурации после правки есть ошибки:");
+    for error in &errors {
+        println!("  {error}");
+    }
+    if !backed_up {
+        return;
+    }
+    let backup = config::backup_path(path);
+    let restore = Confirm::new()
+        .with_prompt(format!("Восстановить прежнюю версию из {}?", backup.display()))
+        .default(true)
+        .interact();
+    match restore {
+        Ok(true) => match fs::copy(&backup, path) {
+            Ok(_) => println!("Конфигурация восстановлена."),
+            Err(e) => println!("Не удалось восстановить конфигурацию: {e}"),
+        },
+        Ok(false) => {}
+        Err(e) => input_interrupted(&e),
+    }
+}
 
+fn choose_interface(symlinks: config::SymlinkPolicy) {
     let interfaces: Vec<_> = datalink::interfaces()
         .into_iter()
         .filter(|iface| iface.name != "lo")
         .collect();
-
-    for (i, iface) in interfaces.iter().enumerate() {
-        println!("{}. {}", i + 1, iface.name);
+    if interfaces.is_empty() {
+        println!("В системе нет сетевых интерфейсов, кроме lo.");
+        thread::sleep(Duration::from_secs(2));
+        return;
     }
+    let current = config::Config::load(config::main_path())
+        .map(|config| config.ifaces)
+        .unwrap_or_default();
+
+    let items: Vec<String> = interfaces
+        .iter()
+        .map(|iface| {
+            let state = if iface.is_up() { "" } else { " (выключен)" };
+            format!("{}{state}", iface.name)
+        })
+        .collect();
+    let checked: Vec<bool> = interfaces.iter().map(|iface| current.contains(&iface.name)).collect();
 
-    let iface_input: Result<String, _> = Input::new()
-        .with_prompt("Введите номер или имя интерфейса")
-        .interact_text();
+    loop {
+        println!("Выберите интерфейсы: пробел — отметить, Enter — сохранить.");
+        let chosen = match MultiSelect::new().items(&items).defaults(&checked).interact() {
+            Ok(chosen) if chosen.is_empty() => {
+                println!("Нужно отметить хотя бы один интерфейс.");
+                continue;
+            }
+            Ok(chosen) => chosen,
+            Err(e) => return input_interrupted(&e),
+        };
+        let chosen: Vec<_> = chosen.iter().map(|&i| &interfaces[i]).collect();
 
-    match iface_input {
-        Ok(input) => {
-            let selected_iface = if let Ok(index) = input.parse::<usize>() {
-                interfaces.get(index - 1).map(|iface| iface.name.clone())
-            } else {
-                Some(input.clone())
-            };
+        let down: Vec<&str> = chosen
+            .iter()
+            .filter(|iface| !iface.is_up())
+            .map(|iface| iface.name.as_str())
+            .collect();
+        if !down.is_empty() {
+            let save = Confirm::new()
+                .with_prompt(format!(
+                    "Выключены: {}; программа XDP на них не увидит трафика. Всё равно сохранить?",
+                    down.join(", ")
+                ))
+                .default(false)
+                .interact();
+            match save {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => return input_interrupted(&e),
+            }
+        }
 
-            match selected_iface {
-                Some(name) => {
-                    update_config_iface(&name);
-                    println!("Интерфейс '{}' сохранён в config.cfg", name);
+        let names: Vec<String> = chosen.iter().map(|iface| iface.name.clone()).collect();
+        match update_config_iface(&names, true, symlinks) {
+            Ok(true) => println!(
+                "Интерфейсы '{}' сохранены в {}",
+                names.join(", "),
+                config::main_path().display()
+            ),
+            Ok(false) => println!("Конфигурация не изменена."),
+            Err(e) => println!("Не удалось записать конфигурацию: {e}"),
+        }
+        return;
+    }
+}
+
+/// Просмотр, добавление и удаление `blocked-ips` основного файла конфигурации.
+///
+/// Сети из каталога правил здесь не показываются: они меняются в своих файлах.
+fn manage_blocked_ips(symlinks: config::SymlinkPolicy) {
+    let path = config::main_path();
+    loop {
+        clear_screen();
+        let blocked: Vec<IpNetwork> = match config::Config::load(path) {
+            Ok(config) => {
+                let v4 = config.blocked_ips.into_iter().map(IpNetwork::V4);
+                v4.chain(config.blocked_ips6.into_iter().map(IpNetwork::V6)).collect()
+            }
+            Err(errors) => {
+                println!("Конфигурация содержит ошибки:");
+                for error in &errors {
+                    println!("  {error}");
                 }
-                None => println!("Некорректный выбор интерфейса."),
+                thread::sleep(Duration::from_secs(3));
+                return;
+            }
+        };
+        if blocked.is_empty() {
+            println!("Заблокированных адресов нет.");
+        } else {
+            println!("Заблокированные адреса:");
+            for (i, network) in blocked.iter().enumerate() {
+                println!("{}. {network}", i + 1);
             }
         }
-        Err(e) => {
-            if e.to_string().contains("interrupted") {
-                println!("Ввод прерван пользователем (Ctrl+C). Возврат в меню...");
-            } else {
-                println!("Ошибка ввода: {e}. Возврат в меню...");
+        println!();
+
+        let choice = Select::new()
+            .items(&["Добавить адрес", "Удалить адрес", "Назад"])
+            .default(0)
+            .interact();
+        let result = match choice {
+            Ok(0) => Input::<String>::new()
+                .with_prompt("Адрес или сеть CIDR, IPv4 или IPv6")
+                .validate_with(|input: &String| config::parse_blocked_ip(input.trim()).map(|_| ()))
+                .interact_text()
+                .map(|input| {
+                    let network = config::parse_blocked_ip(input.trim()).expect("проверено выше");
+                    let mut updated = blocked.clone();
+                    if !updated.contains(&network) {
+                        updated.push(network);
+                    }
+                    updated
+                }),
+            Ok(1) if blocked.is_empty() => continue,
+            Ok(1) => Input::<usize>::new()
+                .with_prompt("Номер адреса для удаления")
+                .validate_with(|index: &usize| {
+                    (1..=blocked.len())
+                        .contains(index)
+                        .then_some(())
+                        .ok_or(format!("ожидается номер от 1 до {}", blocked.len()))
+                })
+                .interact_text()
+                .map(|index| {
+                    let mut updated = blocked.clone();
+                    updated.remove(index - 1);
+                    updated
+                }),
+            Ok(_) => return,
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(updated) if updated == blocked => {}
+            Ok(updated) => {
+                let items: Vec<String> = updated.iter().map(ToString::to_string).collect();
+                save_list(path, "blocked-ips", &items, symlinks);
             }
-            thread::sleep(Duration::from_secs(1));
+            Err(e) => return input_interrupted(&e),
         }
     }
 }
 
-fn update_config_iface(new_iface: &str) {
-    let path = "config.cfg";
+/// Выбор `blocked-countries` основного файла конфигурации из встроенной таблицы стран.
+fn manage_blocked_countries(symlinks: config::SymlinkPolicy) {
+    let path = config::main_path();
+    loop {
+        clear_screen();
+        let blocked = match config::Config::load(path) {
+            Ok(config) => config.blocked_countries,
+            Err(errors) => {
+                println!("Конфигурация содержит ошибки:");
+                for error in &errors {
+                    println!("  {error}");
+                }
+                thread::sleep(Duration::from_secs(3));
+                return;
+            }
+        };
+        if blocked.is_empty() {
+            println!("Заблокированных стран нет.");
+        } else {
+            println!("Заблокированные страны:");
+            for code in &blocked {
+                println!("  {code} — {}", countries::name(code).unwrap_or("?"));
+            }
+        }
+        println!();
 
-    let content = fs::read_to_string(path).unwrap_or_default();
-    let mut lines: Vec<String> = content.lines().map(|s| s.to_string()).collect();
+        let choice = Select::new()
+            .items(&["Выбрать из списка", "Ввести код или название", "Назад"])
+            .default(0)
+            .interact();
+        let result = match choice {
+            Ok(0) => {
+                let items: Vec<String> = countries::COUNTRIES
+                    .iter()
+                    .map(|(code, names)| format!("{code} — {}", names[0]))
+                    .collect();
+                let checked: Vec<bool> = countries::COUNTRIES
+                    .iter()
+                    .map(|(code, _)| blocked.iter().any(|c| c == code))
+                    .collect();
+                println!("Пробел — отметить страну, Enter — сохранить.");
+                MultiSelect::new().items(&items).defaults(&checked).interact().map(|chosen| {
+                    chosen.iter().map(|&i| countries::COUNTRIES[i].0.to_string()).collect()
+                })
+            }
+            // Введённая страна добавляется, а уже заблокированная — снимается.
+            Ok(1) => Input::<String>::new()
+                .with_prompt("Код страны (GB) или название (United Kingdom)")
+                .validate_with(|input: &String| config::parse_country(input.trim()).map(|_| ()))
+                .interact_text()
+                .map(|input| {
+                    let code = config::parse_country(input.trim()).expect("проверено выше");
+                    let mut updated = blocked.clone();
+                    match updated.iter().position(|c| *c == code) {
+                        Some(index) => {
+                            updated.remove(index);
+                        }
+                        None => updated.push(code),
+                    }
+                    updated
+                }),
+            Ok(_) => return,
+            Err(e) => Err(e),
+        };
 
-    let mut found_iface = false;
+        match result {
+            Ok(updated) if updated == blocked => {}
+            Ok(updated) => save_list(path, "blocked-countries", &updated, symlinks),
+            Err(e) => return input_interrupted(&e),
+        }
+    }
+}
 
-    for i in 0..lines.len() {
-        if lines[i].trim() == "\"iface\"" {
-            if i + 1 < lines.len() {
-                lines[i + 1] = new_iface.to_string();
-                found_iface = true;
-                break;
-            }
+/// Записывает список `key` в основной файл конфигурации; ошибку показывает пользователю.
+fn save_list(path: &Path, key: &str, items: &[String], symlinks: config::SymlinkPolicy) {
+    let content = match config::read(path) {
+        Ok(content) => content,
+        Err(e) => {
+            println!("Не удалось прочитать {}: {e}", path.display());
+            thread::sleep(Duration::from_secs(2));
+            return;
         }
+    };
+    let normalized = config::normalize_list(key, items);
+    for warning in &normalized.warnings {
+        println!("Предупреждение: {warning}");
     }
+    let updated = config::set_list(path, &content, key, &normalized.items);
+    if !confirm_write(path, &content, &updated) {
+        return;
+    }
+    let result = config::backup(path)
+        .and_then(|()| config::write(path, &updated, symlinks))
+        .and_then(|()| history::push(path, &content));
+    if let Err(e) = result {
+        println!("Не удалось записать конфигурацию: {e}");
+        thread::sleep(Duration::from_secs(2));
+    }
+}
 
-    if !found_iface {
-        lines.push("\"iface\"".to_string());
-        lines.push(new_iface.to_string());
+/// Сообщение о прерванном или неудавшемся вводе перед возвратом в меню.
+fn input_interrupted(e: &dialoguer::Error) {
+    if e.to_string().contains("interrupted") {
+        println!("Ввод прерван пользователем (Ctrl+C). Возврат в меню...");
+    } else {
+        println!("Ошибка ввода: {e}. Возврат в меню...");
     }
+    thread::sleep(Duration::from_secs(1));
+}
 
-    if !lines.iter().any(|l| l.trim() == "\"allowed-ports\"") {
-        lines.push("\"allowed-ports\"".to_string());
-        lines.push("80, 443".to_string());
+/// Показывает, как запись основного файла конфигурации изменит значения ключей, и
+/// спрашивает подтверждение. `false` — не записывать: пользователь отказался или прервал
+/// ввод.
+fn confirm_write(path: &Path, content: &str, updated: &str) -> bool {
+    let changes = config::changed_keys(path, content, updated);
+    if changes.is_empty() {
+        return true;
+    }
+    let shown = |value: &Option<String>| match value.as_deref() {
+        None => "(нет ключа)".to_string(),
+        Some("") => "(пусто)".to_string(),
+        Some(value) => value.to_string(),
+    };
+    println!("Изменения в {}:", path.display());
+    for change in &changes {
+        println!("  {}", change.key);
+        println!("    было:   {}", shown(&change.old));
+        println!("    станет: {}", shown(&change.new));
     }
+    let confirmed = Confirm::new().with_prompt("Записать изменения?").default(true).interact();
+    match confirmed {
+        Ok(confirmed) => confirmed,
+        Err(e) => {
+            input_interrupted(&e);
+            false
+        }
+    }
+}
 
-    if !lines.iter().any(|l| l.trim() == "\"blocked-ips\"") {
-        lines.push("\"blocked-ips\"".to_string());
-        lines.push("".to_string());
+/// Записывает `iface`: один интерфейс — строкой, несколько — списком. С `confirm` сначала
+/// показывает изменения и спрашивает подтверждение; `false` — запись отменена.
+fn update_config_iface(
+    ifaces: &[String],
+    confirm: bool,
+    symlinks: config::SymlinkPolicy,
+) -> std::io::Result<bool> {
+    let path = config::main_path();
+
+    let content = config::read(path)?;
+    for warning in config::unknown_keys(path, &content) {
+        println!("Предупреждение: {}", warning.in_file(path));
+    }
+    let ifaces = config::normalize_list("iface", ifaces).items;
+    let mut updated = match ifaces.as_slice() {
+        [iface] => config::set_scalar(path, &content, "iface", iface),
+        ifaces => config::set_list(path, &content, "iface", ifaces),
+    };
+    if !config::is_toml(path) {
+        for (key, default) in [
+            ("allowed-ports", "80, 443/tcp, 443/udp, 53"),
+            ("blocked-ips", ""),
+            ("blocked-countries", ""),
+        ] {
+            if !config::has_key(&updated, key) {
+                updated = config::set_value(&updated, key, default);
+            }
+        }
     }
 
-    if !lines.iter().any(|l| l.trim() == "\"blocked-countries\"") {
-        lines.push("\"blocked-countries\"".to_string());
-        lines.push("".to_string());
+    if confirm && !confirm_write(path, &content, &updated) {
+        return Ok(false);
+    }
+    config::backup(path)?;
+    config::write(path, &updated, symlinks)?;
+    if updated != content {
+        history::push(path, &content)?;
     }
+    Ok(true)
+}
 
-    let updated = lines.join("\n");
-    fs::write(path, updated).expect("Не удалось записать конфигурацию");
+/// Возвращает основной файл конфигурации к версии до последней записи из меню, показав
+/// перед подтверждением, какие строки изменятся.
+/// Пункт меню «Аварийный режим»: включает его или, если включён, выключает.
+fn toggle_panic() {
+    let active = match control::panic_active() {
+        Ok(Some(active)) => active,
+        Ok(None) => {
+            println!("Файрволл не запущен.");
+            thread::sleep(Duration::from_secs(2));
+            return;
+        }
+        Err(e) => {
+            println!("Не удалось прочитать настройки: {e:#}");
+            thread::sleep(Duration::from_secs(2));
+            return;
+        }
+    };
+    let (prompt, state) = if active {
+        ("Выключить аварийный режим?", control::Switch::Off)
+    } else {
+        ("Отбрасывать весь трафик, кроме fast-accept-prefixes?", control::Switch::On)
+    };
+    match Confirm::new().with_prompt(prompt).default(active).interact() {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => return input_interrupted(&e),
+    }
+    control::panic(state, false);
+    thread::sleep(Duration::from_secs(2));
 }
 
-fn parse_config(path: &str) -> HashMap<String, String> {
-    let mut map = HashMap::new();
-    let content = fs::read_to_string(path).unwrap_or_default();
-    let lines: Vec<&str> = content.lines().collect();
+fn undo_last_change(symlinks: config::SymlinkPolicy) {
+    let path = config::main_path();
+    let previous = match history::peek(path) {
+        Ok(Some(previous)) => previous,
+        Ok(None) => {
+            println!("Нет изменений, которые можно отменить.");
+            thread::sleep(Duration::from_secs(2));
+            return;
+        }
+        Err(e) => {
+            println!("Не удалось прочитать прежнюю версию конфигурации: {e}");
+            thread::sleep(Duration::from_secs(2));
+            return;
+        }
+    };
+    let current = match config::read(path) {
+        Ok(current) => current,
+        Err(e) => {
+            println!("Не удалось прочитать {}: {e}", path.display());
+            thread::sleep(Duration::from_secs(2));
+            return;
+        }
+    };
 
-    let mut i = 0;
-    while i < lines.len() {
-        let key = lines[i].trim().trim_matches('"');
-        if i + 1 < lines.len() {
-            let value = lines[i + 1].trim();
-            map.insert(key.to_string(), value.to_string());
-            i += 2;
-        } else {
-            i += 1;
+    let changes = history::diff(&current, &previous);
+    if changes.is_empty() {
+        println!("Прежняя версия совпадает с текущей.");
+    } else {
+        println!("Изменения в {}:", path.display());
+        for line in &changes {
+            println!("  {line}");
         }
     }
+    let confirmed = Confirm::new()
+        .with_prompt("Вернуть прежнюю версию?")
+        .default(false)
+        .interact();
+    match confirmed {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => return input_interrupted(&e),
+    }
+
+    let result = config::write(path, &previous, symlinks).and_then(|()| history::pop(path));
+    match result {
+        Ok(()) => println!("Последнее изменение отменено."),
+        Err(e) => println!("Не удалось отменить изменение: {e}"),
+    }
+    thread::sleep(Duration::from_secs(2));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    map
+    #[test]
+    fn run_refuses_without_open_ports_unless_forced() {
+        let config = config::Config::parse("\"allowed-ports\"\n\n\"policy\"\ndeny\n").unwrap();
+        assert_eq!(refuse_without_ports(&config, false), Some(1));
+        assert_eq!(refuse_without_ports(&config, true), None);
+
+        let config = config::Config::parse("\"allowed-ports\"\n22\n\"policy\"\ndeny\n").unwrap();
+        assert_eq!(refuse_without_ports(&config, false), None);
+    }
 }
@@ -0,0 +1,101 @@
+//! Пункты главного меню и их настройка через `menu-order` и `menu-hidden`.
+
+/// Действие главного меню.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Run,
+    Configure,
+    ChooseInterface,
+    BlockedIps,
+    BlockedCountries,
+    Undo,
+    Stats,
+    Panic,
+    Exit,
+}
+
+impl Action {
+    /// Порядок пунктов по умолчанию.
+    pub const DEFAULT: [Action; 9] = [
+        Self::Run,
+        Self::Configure,
+        Self::ChooseInterface,
+        Self::BlockedIps,
+        Self::BlockedCountries,
+        Self::Undo,
+        Self::Stats,
+        Self::Panic,
+        Self::Exit,
+    ];
+
+    /// Имя действия в конфигурации.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Run => "run",
+            Self::Configure => "configure",
+            Self::ChooseInterface => "interface",
+            Self::BlockedIps => "blocked-ips",
+            Self::BlockedCountries => "blocked-countries",
+            Self::Undo => "undo",
+            Self::Stats => "stats",
+            Self::Panic => "panic",
+            Self::Exit => "exit",
+        }
+    }
+
+    /// Текст пункта меню без номера.
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Run => "Запустить файрволл",
+            Self::Configure => "Настроить конфигурацию",
+            Self::ChooseInterface => "Выбрать интерфейс",
+            Self::BlockedIps => "Заблокированные адреса",
+            Self::BlockedCountries => "Заблокированные страны",
+            Self::Undo => "Отменить последнее изменение",
+            Self::Stats => "Статистика",
+            Self::Panic => "Аварийный режим",
+            Self::Exit => "Выход",
+        }
+    }
+}
+
+/// Разбирает имя действия из `menu-order` или `menu-hidden`.
+pub fn parse_action(token: &str) -> Result<Action, String> {
+    Action::DEFAULT.into_iter().find(|a| a.name() == token).ok_or_else(|| {
+        let names: Vec<_> = Action::DEFAULT.iter().map(|a| a.name()).collect();
+        format!("неизвестное действие меню '{token}', допустимы: {}", names.join(", "))
+    })
+}
+
+/// Собирает пункты меню: сначала действия из `order` в указанном порядке, затем остальные
+/// в порядке по умолчанию, без скрытых в `hidden`.
+///
+/// «Выход» не скрывается: без него из меню не выйти иначе как прерыванием процесса.
+pub fn build(order: &[Action], hidden: &[Action]) -> Vec<Action> {
+    let mut items: Vec<Action> = Vec::new();
+    for &action in order.iter().chain(Action::DEFAULT.iter()) {
+        if !items.contains(&action) && (action == Action::Exit || !hidden.contains(&action)) {
+            items.push(action);
+        }
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hidden_action_is_absent_from_menu() {
+        assert_eq!(build(&[], &[]), Action::DEFAULT);
+
+        let menu = build(&[], &[Action::Configure]);
+        assert!(!menu.contains(&Action::Configure));
+        assert_eq!(menu.len(), Action::DEFAULT.len() - 1);
+
+        let menu = build(&[Action::Stats, Action::Run], &[Action::Configure, Action::Exit]);
+        assert_eq!(&menu[..3], [Action::Stats, Action::Run, Action::ChooseInterface]);
+        assert!(!menu.contains(&Action::Configure));
+        assert_eq!(menu.last(), Some(&Action::Exit));
+    }
+}
@@ -0,0 +1,118 @@
+//! Счётчики в текстовом формате Prometheus для `firewall-cli run --metrics-addr`.
+//!
+//! Сервер HTTP здесь минимальный, на `std::net`: он отвечает только на `GET /metrics`, по
+//! одному запросу за раз. Карты программы читаются заново при каждом опросе, так что
+//! значения те же, что показывает `firewall-cli stats`. Пока загрузчик не запущен, отдаётся
+//! только `firewall_up 0`.
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead as _, BufReader, Write as _},
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use crate::stats::{self, Stats};
+
+/// Сколько ждать запроса от клиента, прежде чем закрыть соединение.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Начинает отдавать счётчики на `addr` в фоновом потоке. Ошибка — только если адрес
+/// не удалось занять; сбои отдельных запросов сервер не останавливают.
+pub fn serve(addr: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = respond(stream);
+        }
+    });
+    Ok(())
+}
+
+fn respond(stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Заголовки не нужны, но их надо дочитать: иначе клиент может получить сброс соединения.
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => match stats::fetch_stats() {
+            Ok(current) => ("200 OK", render(current.as_ref())),
+            Err(e) => ("500 Internal Server Error", format!("{e:#}\n")),
+        },
+        _ => ("404 Not Found", "только GET /metrics\n".to_string()),
+    };
+    let content_type = if status.starts_with("200") {
+        "text/plain; version=0.0.4; charset=utf-8"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+/// Текст ответа; `None` — файрволл не запущен.
+fn render(stats: Option<&Stats>) -> String {
+    let mut out = String::new();
+    metric(&mut out, "firewall_up", "gauge", "1 if the loader is running.");
+    let Some(stats) = stats else {
+        out.push_str("firewall_up 0\n");
+        return out;
+    };
+    out.push_str("firewall_up 1\n");
+
+    metric(&mut out, "firewall_packets_total", "counter", "Packets by final XDP action.");
+    for (action, value) in [
+        ("pass", stats.pass),
+        ("drop", stats.drop),
+        ("aborted", stats.aborted),
+        ("redirect", stats.redirect),
+    ] {
+        let _ = writeln!(out, "firewall_packets_total{{action=\"{action}\"}} {value}");
+    }
+    for (name, help, value) in [
+        ("firewall_syn_flood_drops_total", "Drops by syn-rate-limit.", stats.syn_flood),
+        ("firewall_dry_run_total", "Packets passed only because of dry-run.", stats.dry_run),
+        ("firewall_map_full_total", "Entries lost to full counter maps.", stats.map_full),
+        ("firewall_quic_packets_total", "QUIC (UDP 443) packets, any verdict.", stats.quic_packets),
+        ("firewall_quic_bytes_total", "QUIC (UDP 443) bytes, any verdict.", stats.quic_bytes),
+    ] {
+        metric(&mut out, name, "counter", help);
+        let _ = writeln!(out, "{name} {value}");
+    }
+
+    metric(&mut out, "firewall_drops_total", "counter", "Dropped packets by reason.");
+    for &(reason, count) in &stats.drop_reasons {
+        let _ = writeln!(out, "firewall_drops_total{{reason=\"{}\"}} {count}", reason.as_str());
+    }
+
+    // Только самые нагруженные источники, как в `stats`: метка на каждый адрес сделала бы
+    // число рядов неограниченным.
+    metric(&mut out, "firewall_source_packets_total", "counter", "Packets from top sources.");
+    for &(addr, packets, _) in &stats.top_sources {
+        let _ = writeln!(out, "firewall_source_packets_total{{src=\"{addr}\"}} {packets}");
+    }
+    metric(&mut out, "firewall_source_bytes_total", "counter", "Bytes from top sources.");
+    for &(addr, _, bytes) in &stats.top_sources {
+        let _ = writeln!(out, "firewall_source_bytes_total{{src=\"{addr}\"}} {bytes}");
+    }
+    out
+}
+
+/// Строки `# HELP` и `# TYPE` перед значениями метрики.
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
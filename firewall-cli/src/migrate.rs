@@ -0,0 +1,66 @@
+//! `firewall-cli migrate`: перенос `config.cfg` в `config.toml`.
+//!
+//! Старый файл разбирается тем же кодом, что при запуске, и остаётся как был. В новый файл
+//! попадает действующая конфигурация с раскрытыми переменными; неверные значения и
+//! неизвестные ключи в него не переносятся, и о каждом из них сообщается.
+
+use std::{fs, io};
+
+use crate::config::{self, Config};
+
+/// Выполняет `firewall-cli migrate` и возвращает код выхода.
+pub fn run() -> i32 {
+    let source = config::main_path();
+    if config::is_toml(source) {
+        println!("{} уже в формате TOML, переносить нечего.", source.display());
+        return 1;
+    }
+    let target = source.with_extension("toml");
+    if target.exists() {
+        println!("{} уже существует; удалите или переименуйте его.", target.display());
+        return 1;
+    }
+    let content = match fs::read_to_string(source) {
+        Ok(content) => content,
+        Err(e) => {
+            println!("Не удалось прочитать {}: {e}", source.display());
+            return 1;
+        }
+    };
+
+    let unknown = config::unknown_keys(source, &content);
+    let (parsed, errors) = Config::parse_lenient(&content);
+    if !unknown.is_empty() || !errors.is_empty() {
+        println!("Не перенесено:");
+        for key in &unknown {
+            println!("  {}:{}: неизвестный ключ '{}'", source.display(), key.line, key.key);
+        }
+        for error in errors {
+            println!("  {}", error.in_file(source));
+        }
+    }
+
+    let rendered = format!(
+        "# Перенесено из {} командой `firewall-cli migrate`.\n{}",
+        source.display(),
+        config::render_toml(&parsed)
+    );
+    if let Err(errors) = Config::parse_toml(&rendered) {
+        // Перенесённый файл обязан разбираться: иначе это ошибка в render_toml.
+        println!("Не удалось перенести конфигурацию:");
+        for error in errors {
+            println!("  {error}");
+        }
+        return 1;
+    }
+    if let Err(e) = config::create(&target, &rendered) {
+        if e.kind() == io::ErrorKind::AlreadyExists {
+            println!("{} уже существует; удалите или переименуйте его.", target.display());
+        } else {
+            println!("Не удалось записать {}: {e}", target.display());
+        }
+        return 1;
+    }
+    println!("Конфигурация перенесена в {}; {} не изменён.", target.display(), source.display());
+    0
+}
@@ -0,0 +1,1158 @@
+//! Скомпилированная политика: содержимое всех карт правил одним файлом.
+//!
+//! `firewall-cli compile-policy` собирает файл из конфигурации, `apply-policy` заменяет им
+//! правила запущенного файрволла. Перед заменой файл проверяется целиком, а текущие правила
+//! сохраняются в [`ROLLBACK_FILE`]: если ядро отклонит запись посреди замены, прежние
+//! правила возвращаются сами, а вручную — тем же `apply-policy`.
+//!
+//! С окном (`grace-period`, `apply-policy --grace`) соединения TCP, принятые портом, который
+//! новая политика больше не разрешает, доживают столько секунд: программа пропускает их по
+//! записям `ACCEPTED_CONNS` до `settings::GRACE_UNTIL`, новые соединения к порту
+//! отбрасываются сразу. Записи ведутся, только если загрузчик запущен с `conntrack-timeout`.
+//!
+//! Формат, все числа little-endian:
+//!
+//! ```text
+//! "FWPOLICY"  u32 версия  u32 endpoint-match (0 — нет правил, 1 — src, 2 — dst)
+//!             u32 port-match (0 — dst, 1 — src)  u32 allow-icmp-echo (0 или 1)
+//!             u32 фильтр флагов TCP (0 — allow-invalid-tcp-flags, 1 — включён)
+//!             u32 policy (0 — deny, 1 — allow)  u32 strict-protocols (0 или 1)
+//!             u32 blocked-countries-window (time_window, 0 — всегда)
+//!             u32 filter-multicast (0 или 1)  u32 drop-fragments (0 или 1)
+//!             u32 arp-subnet: адрес, u32 длина префикса (0 — ARP не ограничен)
+//!             u32 allow-dns (0 или 1)
+//! разделы по порядку, каждый: u32 число записей, затем записи
+//!   allowed-ports      u16 порт, u8 маска port_protos
+//!   blocked-ips        u32 адрес
+//!   blocked-nets       u8 длина префикса, u32 адрес в сетевом порядке
+//!   allowed-ips        u32 адрес
+//!   blocked-countries  u16 pack_country
+//!   block-tcp-window   u16 окно
+//!   blocked-masks      u32 адрес, u32 маска
+//!   blocked-endpoints  u8 длина префикса ключа, [u8; 6] endpoint_key
+//!   fast-accept        u8 длина префикса, u32 адрес в сетевом порядке
+//!   blocked-macs       [u8; 6] MAC-адрес
+//!   blocked-src-ports  u16 порт, u8 маска port_protos
+//!   protected-ips      u8 длина префикса, u32 адрес в сетевом порядке
+//!   protocols          u8 номер протокола, u8 значение protocol_rule
+//!   blocked-ips6       u8 длина префикса, [u8; 16] адрес в сетевом порядке
+//! u64 FNV-1a всего предшествующего
+//! ```
+//!
+//! Регионов в файле нет: их сети берутся из базы `region-db`, которую читает загрузчик.
+//! Нет и `conntrack-timeout`: программу на отправке загрузчик привязывает только при запуске.
+//! Стук (`knock-port`, `knock-sequence`) загрузчик тоже задаёт только при запуске.
+
+use std::{collections::BTreeMap, fs, hash::Hash, path::Path};
+
+use anyhow::Context as _;
+use aya::{
+    maps::{
+        lpm_trie::{Key, LpmTrie},
+        Array, HashMap, Map, MapData,
+    },
+    Pod,
+};
+use firewall_common::{
+    endpoint_key, pack_country, port_protos, protocol_rule, settings, time_window, ConnKey,
+    MaskedAddr, ACCEPTED_CONNS_MAP, ALLOWED_IPS_MAP, ALLOWED_PORTS_MAP, BLOCKED_COUNTRIES_MAP,
+    BLOCKED_ENDPOINTS_MAP, BLOCKED_IPS6_MAP, BLOCKED_IPS_MAP, BLOCKED_MACS_MAP, BLOCKED_MASKS_MAP,
+    BLOCKED_NETS_MAP, BLOCKED_SRC_PORTS_MAP, FAST_ACCEPT_MAP, MAX_BLOCKED_MASKS, PIN_PATH,
+    PROTECTED_IPS_MAP, PROTOCOLS_MAP, TCP_WINDOWS_MAP,
+};
+use ipnetwork::Ipv4Network;
+
+use crate::{
+    audit,
+    config::{AllowedPort, Config, DefaultPolicy, EndpointMatch, PortMatch, PortProto},
+    control, knocks,
+};
+
+const MAGIC: &[u8; 8] = b"FWPOLICY";
+
+/// Текущая версия формата.
+pub const VERSION: u32 = 17;
+
+/// Куда `apply-policy` сохраняет заменённые правила: в каталог состояния, а не в текущий,
+/// чтобы откат находился независимо от того, откуда запущена замена.
+pub const ROLLBACK_FILE: &str = "/var/lib/firewall/policy-rollback.bin";
+
+/// Содержимое карт правил. Списки отсортированы и без повторов, поэтому одна и та же
+/// политика всегда даёт один и тот же файл.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Policy {
+    /// Значение `settings::ENDPOINT_MATCH`.
+    pub endpoint_match: u32,
+    /// Значение `settings::PORT_MATCH`.
+    pub port_match: u32,
+    /// Значение `settings::ICMP_ECHO`.
+    pub icmp_echo: u32,
+    /// Значение `settings::TCP_FLAG_FILTER`.
+    pub tcp_flag_filter: u32,
+    /// Значение `settings::DEFAULT_POLICY`.
+    pub default_policy: u32,
+    /// Значение `settings::STRICT_PROTOCOLS`.
+    pub strict_protocols: u32,
+    /// Значение `settings::COUNTRY_WINDOW`.
+    pub country_window: u32,
+    /// Значение `settings::FILTER_MULTICAST`.
+    pub filter_multicast: u32,
+    /// Значение `settings::DROP_FRAGMENTS`.
+    pub drop_fragments: u32,
+    /// Значения `settings::ARP_SUBNET` и `settings::ARP_PREFIX`.
+    pub arp_subnet: u32,
+    pub arp_prefix: u32,
+    /// Значение `settings::ALLOW_DNS`.
+    pub allow_dns: u32,
+    pub allowed_ports: Vec<(u16, u8)>,
+    pub blocked_ips: Vec<u32>,
+    pub blocked_nets: Vec<(u8, u32)>,
+    pub allowed_ips: Vec<u32>,
+    pub blocked_countries: Vec<u16>,
+    pub tcp_windows: Vec<u16>,
+    pub blocked_masks: Vec<MaskedAddr>,
+    pub blocked_endpoints: Vec<(u8, [u8; 6])>,
+    pub fast_accept: Vec<(u8, u32)>,
+    pub blocked_macs: Vec<[u8; 6]>,
+    pub blocked_src_ports: Vec<(u16, u8)>,
+    pub protected_ips: Vec<(u8, u32)>,
+    /// Протоколы с правилом, кроме [`protocol_rule::UNLISTED`].
+    pub protocols: Vec<(u8, u8)>,
+    pub blocked_ips6: Vec<(u8, [u8; 16])>,
+}
+
+fn sorted<T: Ord>(mut list: Vec<T>) -> Vec<T> {
+    list.sort();
+    list.dedup();
+    list
+}
+
+fn protos(port: &AllowedPort) -> u8 {
+    match port.proto {
+        Some(PortProto::Tcp) => port_protos::TCP,
+        Some(PortProto::Udp) => port_protos::UDP,
+        None => port_protos::ANY,
+    }
+}
+
+/// Раскрывает диапазоны в порты с масками протоколов, складывая маски повторов.
+fn port_masks(list: &[AllowedPort]) -> Vec<(u16, u8)> {
+    let mut ports = BTreeMap::new();
+    for entry in list {
+        for port in entry.ports() {
+            *ports.entry(port).or_insert(0) |= protos(entry);
+        }
+    }
+    ports.into_iter().collect()
+}
+
+impl Policy {
+    /// Собирает политику из конфигурации так же, как загрузчик заполняет карты при старте.
+    pub fn from_config(config: &Config) -> anyhow::Result<Policy> {
+        let (hosts, nets): (Vec<&Ipv4Network>, Vec<&Ipv4Network>) =
+            config.blocked_ips.iter().partition(|network| network.prefix() == 32);
+        let blocked_masks: Vec<MaskedAddr> = sorted(
+            config
+                .blocked_masks
+                .iter()
+                .map(|rule| MaskedAddr::new(u32::from(rule.addr), u32::from(rule.mask)))
+                .collect(),
+        );
+        let endpoint_match = match (config.blocked_endpoints.is_empty(), config.endpoint_match) {
+            (true, _) => 0,
+            (false, EndpointMatch::Src) => 1,
+            (false, EndpointMatch::Dst) => 2,
+        };
+        Ok(Policy {
+            endpoint_match,
+            port_match: u32::from(config.port_match == PortMatch::Src),
+            icmp_echo: u32::from(config.allow_icmp_echo),
+            tcp_flag_filter: u32::from(!config.allow_invalid_tcp_flags),
+            default_policy: u32::from(config.policy == DefaultPolicy::Allow),
+            strict_protocols: u32::from(config.strict_protocols_in_effect()),
+            country_window: config.blocked_countries_window.unwrap_or(0),
+            filter_multicast: u32::from(config.filter_multicast),
+            drop_fragments: u32::from(config.drop_fragments),
+            arp_subnet: config.arp_subnet.map_or(0, |net| u32::from(net.network())),
+            arp_prefix: config.arp_subnet.map_or(0, |net| u32::from(net.prefix())),
+            allow_dns: u32::from(config.allows_dns()),
+            allowed_ports: port_masks(&config.allowed_ports),
+            blocked_ips: sorted(hosts.iter().map(|network| u32::from(network.ip())).collect()),
+            blocked_nets: sorted(
+                nets.iter()
+                    .map(|net| (net.prefix(), u32::from(net.network()).to_be()))
+                    .collect(),
+            ),
+            allowed_ips: sorted(config.allowed_ips.iter().map(|&ip| u32::from(ip)).collect()),
+            blocked_countries: sorted(
+                config
+                    .blocked_countries
+                    .iter()
+                    .map(|code| pack_country(code.as_bytes()))
+                    .collect(),
+            ),
+            tcp_windows: sorted(config.blocked_tcp_windows.clone()),
+            blocked_masks,
+            blocked_endpoints: sorted(
+                config
+                    .blocked_endpoints
+                    .iter()
+                    .map(|endpoint| {
+                        let addr = u32::from(endpoint.network.network());
+                        (16 + endpoint.network.prefix(), endpoint_key(addr, endpoint.port))
+                    })
+                    .collect(),
+            ),
+            fast_accept: sorted(
+                config
+                    .fast_accept_prefixes
+                    .iter()
+                    .map(|net| (net.prefix(), u32::from(net.network()).to_be()))
+                    .collect(),
+            ),
+            blocked_macs: sorted(config.blocked_macs.iter().map(|mac| mac.0).collect()),
+            blocked_src_ports: port_masks(&config.blocked_src_ports),
+            protected_ips: sorted(
+                config
+                    .protected_ips
+                    .iter()
+                    .map(|net| (net.prefix(), u32::from(net.network()).to_be()))
+                    .collect(),
+            ),
+            protocols: sorted(
+                config
+                    .protocols
+                    .iter()
+                    .map(|rule| {
+                        let value =
+                            if rule.allow { protocol_rule::ALLOW } else { protocol_rule::DENY };
+                        (rule.proto, value)
+                    })
+                    .collect(),
+            ),
+            blocked_ips6: sorted(
+                config
+                    .blocked_ips6
+                    .iter()
+                    .map(|net| (net.prefix(), net.network().octets()))
+                    .collect(),
+            ),
+        })
+    }
+
+    /// Файл политики в формате из описания модуля.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend(VERSION.to_le_bytes());
+        out.extend(self.endpoint_match.to_le_bytes());
+        out.extend(self.port_match.to_le_bytes());
+        out.extend(self.icmp_echo.to_le_bytes());
+        out.extend(self.tcp_flag_filter.to_le_bytes());
+        out.extend(self.default_policy.to_le_bytes());
+        out.extend(self.strict_protocols.to_le_bytes());
+        out.extend(self.country_window.to_le_bytes());
+        out.extend(self.filter_multicast.to_le_bytes());
+        out.extend(self.drop_fragments.to_le_bytes());
+        out.extend(self.arp_subnet.to_le_bytes());
+        out.extend(self.arp_prefix.to_le_bytes());
+        out.extend(self.allow_dns.to_le_bytes());
+        section(&mut out, &self.allowed_ports, |out, &(port, protos)| {
+            out.extend(port.to_le_bytes());
+            out.push(protos);
+        });
+        section(&mut out, &self.blocked_ips, |out, addr| out.extend(addr.to_le_bytes()));
+        section(&mut out, &self.blocked_nets, |out, &(prefix, addr)| {
+            out.push(prefix);
+            out.extend(addr.to_le_bytes());
+        });
+        section(&mut out, &self.allowed_ips, |out, addr| out.extend(addr.to_le_bytes()));
+        section(&mut out, &self.blocked_countries, |out, c| out.extend(c.to_le_bytes()));
+        section(&mut out, &self.tcp_windows, |out, w| out.extend(w.to_le_bytes()));
+        section(&mut out, &self.blocked_masks, |out, rule| {
+            out.extend(rule.addr.to_le_bytes());
+            out.extend(rule.mask.to_le_bytes());
+        });
+        section(&mut out, &self.blocked_endpoints, |out, (prefix, key)| {
+            out.push(*prefix);
+            out.extend(key);
+        });
+        section(&mut out, &self.fast_accept, |out, &(prefix, addr)| {
+            out.push(prefix);
+            out.extend(addr.to_le_bytes());
+        });
+        section(&mut out, &self.blocked_macs, |out, mac| out.extend(mac));
+        section(&mut out, &self.blocked_src_ports, |out, &(port, protos)| {
+            out.extend(port.to_le_bytes());
+            out.push(protos);
+        });
+        section(&mut out, &self.protected_ips, |out, &(prefix, addr)| {
+            out.push(prefix);
+            out.extend(addr.to_le_bytes());
+        });
+        section(&mut out, &self.protocols, |out, &(proto, rule)| {
+            out.push(proto);
+            out.push(rule);
+        });
+        section(&mut out, &self.blocked_ips6, |out, (prefix, addr)| {
+            out.push(*prefix);
+            out.extend(addr);
+        });
+        let checksum = fnv1a(&out);
+        out.extend(checksum.to_le_bytes());
+        out
+    }
+
+    /// Разбирает и проверяет файл политики; ни одна карта не меняется, пока он не прошёл
+    /// проверку целиком.
+    pub fn decode(data: &[u8]) -> Result<Policy, String> {
+        let body_len = data.len().checked_sub(8).ok_or("файл короче заголовка")?;
+        let (body, checksum) = data.split_at(body_len);
+        let mut reader = Reader { data: body };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err("это не файл политики".to_string());
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(format!("версия формата {version} не поддерживается (ожидается {VERSION})"));
+        }
+        if u64::from_le_bytes(checksum.try_into().unwrap_or_default()) != fnv1a(body) {
+            return Err("контрольная сумма не совпадает: файл повреждён".to_string());
+        }
+
+        let endpoint_match = reader.u32()?;
+        if endpoint_match > 2 {
+            return Err(format!("endpoint-match {endpoint_match}: ожидается 0, 1 или 2"));
+        }
+        let port_match = reader.u32()?;
+        if port_match > 1 {
+            return Err(format!("port-match {port_match}: ожидается 0 или 1"));
+        }
+        let icmp_echo = reader.u32()?;
+        if icmp_echo > 1 {
+            return Err(format!("allow-icmp-echo {icmp_echo}: ожидается 0 или 1"));
+        }
+        let tcp_flag_filter = reader.u32()?;
+        if tcp_flag_filter > 1 {
+            return Err(format!("фильтр флагов TCP {tcp_flag_filter}: ожидается 0 или 1"));
+        }
+        let default_policy = reader.u32()?;
+        if default_policy > 1 {
+            return Err(format!("policy {default_policy}: ожидается 0 (deny) или 1 (allow)"));
+        }
+        let strict_protocols = reader.u32()?;
+        if strict_protocols > 1 {
+            return Err(format!("strict-protocols {strict_protocols}: ожидается 0 или 1"));
+        }
+        let country_window = reader.u32()?;
+        let valid_window = |(start, end): (u16, u16)| {
+            start != end && u32::from(start.max(end)) < time_window::MINUTES_PER_DAY
+        };
+        if country_window != 0 && !time_window::bounds(country_window).is_some_and(valid_window) {
+            return Err(format!("blocked-countries-window {country_window:#x}: неверное окно"));
+        }
+        let filter_multicast = reader.u32()?;
+        if filter_multicast > 1 {
+            return Err(format!("filter-multicast {filter_multicast}: ожидается 0 или 1"));
+        }
+        let drop_fragments = reader.u32()?;
+        if drop_fragments > 1 {
+            return Err(format!("drop-fragments {drop_fragments}: ожидается 0 или 1"));
+        }
+        let arp_subnet = reader.u32()?;
+        let arp_prefix = reader.u32()?;
+        if arp_prefix > 32 {
+            return Err(format!("arp-subnet: длина префикса {arp_prefix} больше 32"));
+        }
+        let allow_dns = reader.u32()?;
+        if allow_dns > 1 {
+            return Err(format!("allow-dns {allow_dns}: ожидается 0 или 1"));
+        }
+        let policy = Policy {
+            endpoint_match,
+            port_match,
+            icmp_echo,
+            tcp_flag_filter,
+            default_policy,
+            strict_protocols,
+            country_window,
+            filter_multicast,
+            drop_fragments,
+            arp_subnet,
+            arp_prefix,
+            allow_dns,
+            allowed_ports: reader.section(|r| Ok((r.u16()?, r.u8()?)))?,
+            blocked_ips: reader.section(Reader::u32)?,
+            blocked_nets: reader.section(|r| Ok((r.u8()?, r.u32()?)))?,
+            allowed_ips: reader.section(Reader::u32)?,
+            blocked_countries: reader.section(Reader::u16)?,
+            tcp_windows: reader.section(Reader::u16)?,
+            blocked_masks: reader.section(|r| Ok(MaskedAddr::new(r.u32()?, r.u32()?)))?,
+            blocked_endpoints: reader
+                .section(|r| Ok((r.u8()?, r.take(6)?.try_into().unwrap_or_default())))?,
+            fast_accept: reader.section(|r| Ok((r.u8()?, r.u32()?)))?,
+            blocked_macs: reader.section(|r| Ok(r.take(6)?.try_into().unwrap_or_default()))?,
+            blocked_src_ports: reader.section(|r| Ok((r.u16()?, r.u8()?)))?,
+            protected_ips: reader.section(|r| Ok((r.u8()?, r.u32()?)))?,
+            protocols: reader.section(|r| Ok((r.u8()?, r.u8()?)))?,
+            blocked_ips6: reader
+                .section(|r| Ok((r.u8()?, r.take(16)?.try_into().unwrap_or_default())))?,
+        };
+        if !reader.data.is_empty() {
+            return Err(format!("{} лишних байт после разделов", reader.data.len()));
+        }
+
+        for (name, ports) in [
+            ("allowed-ports", &policy.allowed_ports),
+            ("blocked-src-ports", &policy.blocked_src_ports),
+        ] {
+            if let Some((port, _)) =
+                ports.iter().find(|&&(_, protos)| protos == 0 || protos & !port_protos::ANY != 0)
+            {
+                return Err(format!("{name}: у порта {port} неверная маска протоколов"));
+            }
+        }
+        if policy.blocked_masks.len() > MAX_BLOCKED_MASKS as usize {
+            return Err(format!("blocked-masks: больше {MAX_BLOCKED_MASKS} правил"));
+        }
+        if policy.blocked_endpoints.iter().any(|&(prefix, _)| !(16..=48).contains(&prefix)) {
+            return Err("blocked-endpoints: длина префикса вне 16..=48".to_string());
+        }
+        if policy.blocked_nets.iter().any(|&(prefix, _)| prefix > 32) {
+            return Err("blocked-nets: длина префикса больше 32".to_string());
+        }
+        if policy.fast_accept.iter().any(|&(prefix, _)| prefix > 32) {
+            return Err("fast-accept: длина префикса больше 32".to_string());
+        }
+        if policy.protected_ips.iter().any(|&(prefix, _)| prefix > 32) {
+            return Err("protected-ips: длина префикса больше 32".to_string());
+        }
+        if policy.blocked_ips6.iter().any(|&(prefix, _)| prefix > 128) {
+            return Err("blocked-ips6: длина префикса больше 128".to_string());
+        }
+        let known = [protocol_rule::ALLOW, protocol_rule::DENY];
+        if let Some((proto, rule)) = policy.protocols.iter().find(|(_, rule)| !known.contains(rule))
+        {
+            return Err(format!("protocols: у протокола {proto} неверное правило {rule}"));
+        }
+        if policy.protocols.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            return Err("protocols: протокол указан дважды".to_string());
+        }
+        if (policy.endpoint_match == 0) != policy.blocked_endpoints.is_empty() {
+            return Err("endpoint-match не согласован с blocked-endpoints".to_string());
+        }
+        Ok(policy)
+    }
+}
+
+fn section<T>(out: &mut Vec<u8>, items: &[T], mut write: impl FnMut(&mut Vec<u8>, &T)) {
+    out.extend((items.len() as u32).to_le_bytes());
+    for item in items {
+        write(out, item);
+    }
+}
+
+/// 64-битный FNV-1a: не криптография, а защита от обрезанных и испорченных файлов.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < len {
+            return Err("файл обрезан".to_string());
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap_or_default()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap_or_default()))
+    }
+
+    fn section<T>(
+        &mut self,
+        mut read: impl FnMut(&mut Self) -> Result<T, String>,
+    ) -> Result<Vec<T>, String> {
+        let count = self.u32()? as usize;
+        // Каждая запись занимает хотя бы байт: счётчик больше остатка файла — порча.
+        if count > self.data.len() {
+            return Err("файл обрезан".to_string());
+        }
+        let items = (0..count).map(|_| read(self)).collect::<Result<Vec<T>, _>>()?;
+        Ok(items)
+    }
+}
+
+fn open(name: &str) -> anyhow::Result<MapData> {
+    let path = Path::new(PIN_PATH).join(name);
+    if !path.exists() {
+        anyhow::bail!("файрволл не запущен: нет {}", path.display());
+    }
+    MapData::from_pin(&path).with_context(|| format!("не удалось открыть {path:?}"))
+}
+
+/// Закреплённые карты правил запущенного файрволла.
+struct Maps {
+    settings: Array<MapData, u32>,
+    allowed_ports: HashMap<MapData, u16, u8>,
+    blocked_ips: HashMap<MapData, u32, u64>,
+    blocked_nets: LpmTrie<MapData, u32, u64>,
+    allowed_ips: HashMap<MapData, u32, u8>,
+    blocked_countries: HashMap<MapData, u16, u8>,
+    tcp_windows: HashMap<MapData, u16, u8>,
+    blocked_masks: Array<MapData, MaskedAddr>,
+    blocked_endpoints: LpmTrie<MapData, [u8; 6], u8>,
+    fast_accept: LpmTrie<MapData, u32, u8>,
+    blocked_macs: HashMap<MapData, [u8; 6], u8>,
+    accepted_conns: HashMap<MapData, ConnKey, u64>,
+    blocked_src_ports: HashMap<MapData, u16, u8>,
+    protected_ips: LpmTrie<MapData, u32, u8>,
+    protocols: Array<MapData, u8>,
+    blocked_ips6: LpmTrie<MapData, [u8; 16], u64>,
+}
+
+fn hash_map<K: Pod, V: Pod>(name: &str) -> anyhow::Result<HashMap<MapData, K, V>> {
+    Ok(HashMap::try_from(Map::HashMap(open(name)?))?)
+}
+
+fn trie<K: Pod, V: Pod>(name: &str) -> anyhow::Result<LpmTrie<MapData, K, V>> {
+    Ok(LpmTrie::try_from(Map::LpmTrie(open(name)?))?)
+}
+
+impl Maps {
+    fn open() -> anyhow::Result<Maps> {
+        Ok(Maps {
+            settings: control::open_settings()?.context("файрволл не запущен")?,
+            allowed_ports: hash_map(ALLOWED_PORTS_MAP)?,
+            blocked_ips: hash_map(BLOCKED_IPS_MAP)?,
+            blocked_nets: trie(BLOCKED_NETS_MAP)?,
+            allowed_ips: hash_map(ALLOWED_IPS_MAP)?,
+            blocked_countries: hash_map(BLOCKED_COUNTRIES_MAP)?,
+            tcp_windows: hash_map(TCP_WINDOWS_MAP)?,
+            blocked_masks: Array::try_from(Map::Array(open(BLOCKED_MASKS_MAP)?))?,
+            blocked_endpoints: trie(BLOCKED_ENDPOINTS_MAP)?,
+            fast_accept: trie(FAST_ACCEPT_MAP)?,
+            blocked_macs: hash_map(BLOCKED_MACS_MAP)?,
+            accepted_conns: HashMap::try_from(Map::LruHashMap(open(ACCEPTED_CONNS_MAP)?))?,
+            blocked_src_ports: hash_map(BLOCKED_SRC_PORTS_MAP)?,
+            protected_ips: trie(PROTECTED_IPS_MAP)?,
+            protocols: Array::try_from(Map::Array(open(PROTOCOLS_MAP)?))?,
+            blocked_ips6: trie(BLOCKED_IPS6_MAP)?,
+        })
+    }
+
+    /// Политика, которую сейчас применяет программа.
+    fn read(&self) -> anyhow::Result<Policy> {
+        let masks = self.settings.get(&settings::BLOCKED_MASKS, 0)?;
+        Ok(Policy {
+            endpoint_match: self.settings.get(&settings::ENDPOINT_MATCH, 0)?,
+            port_match: self.settings.get(&settings::PORT_MATCH, 0)?,
+            icmp_echo: self.settings.get(&settings::ICMP_ECHO, 0)?,
+            tcp_flag_filter: self.settings.get(&settings::TCP_FLAG_FILTER, 0)?,
+            default_policy: self.settings.get(&settings::DEFAULT_POLICY, 0)?,
+            strict_protocols: self.settings.get(&settings::STRICT_PROTOCOLS, 0)?,
+            country_window: self.settings.get(&settings::COUNTRY_WINDOW, 0)?,
+            filter_multicast: self.settings.get(&settings::FILTER_MULTICAST, 0)?,
+            drop_fragments: self.settings.get(&settings::DROP_FRAGMENTS, 0)?,
+            arp_subnet: self.settings.get(&settings::ARP_SUBNET, 0)?,
+            arp_prefix: self.settings.get(&settings::ARP_PREFIX, 0)?,
+            allow_dns: self.settings.get(&settings::ALLOW_DNS, 0)?,
+            allowed_ports: sorted(self.allowed_ports.iter().collect::<Result<_, _>>()?),
+            blocked_ips: sorted(self.blocked_ips.keys().collect::<Result<_, _>>()?),
+            blocked_nets: sorted(
+                self.blocked_nets
+                    .keys()
+                    .map(|key| key.map(|key| (key.prefix_len() as u8, key.data())))
+                    .collect::<Result<_, _>>()?,
+            ),
+            allowed_ips: sorted(self.allowed_ips.keys().collect::<Result<_, _>>()?),
+            blocked_countries: sorted(self.blocked_countries.keys().collect::<Result<_, _>>()?),
+            tcp_windows: sorted(self.tcp_windows.keys().collect::<Result<_, _>>()?),
+            // Порядок ячеек зависит от прошлых замен (см. `mask_steps`), а в политике список
+            // отсортирован.
+            blocked_masks: sorted(
+                (0..masks.min(MAX_BLOCKED_MASKS))
+                    .map(|index| self.blocked_masks.get(&index, 0))
+                    .collect::<Result<_, _>>()?,
+            ),
+            blocked_endpoints: sorted(
+                self.blocked_endpoints
+                    .keys()
+                    .map(|key| key.map(|key| (key.prefix_len() as u8, key.data())))
+                    .collect::<Result<_, _>>()?,
+            ),
+            fast_accept: sorted(
+                self.fast_accept
+                    .keys()
+                    .map(|key| key.map(|key| (key.prefix_len() as u8, key.data())))
+                    .collect::<Result<_, _>>()?,
+            ),
+            blocked_macs: sorted(self.blocked_macs.keys().collect::<Result<_, _>>()?),
+            blocked_src_ports: sorted(self.blocked_src_ports.iter().collect::<Result<_, _>>()?),
+            protected_ips: sorted(
+                self.protected_ips
+                    .keys()
+                    .map(|key| key.map(|key| (key.prefix_len() as u8, key.data())))
+                    .collect::<Result<_, _>>()?,
+            ),
+            protocols: (0..=u8::MAX)
+                .map(|proto| Ok((proto, self.protocols.get(&u32::from(proto), 0)?)))
+                .filter(|entry| !matches!(entry, Ok((_, protocol_rule::UNLISTED))))
+                .collect::<Result<_, aya::maps::MapError>>()?,
+            blocked_ips6: sorted(
+                self.blocked_ips6
+                    .keys()
+                    .map(|key| key.map(|key| (key.prefix_len() as u8, key.data())))
+                    .collect::<Result<_, _>>()?,
+            ),
+        })
+    }
+
+    /// Переводит карты с политики `old` на `new`.
+    ///
+    /// Одной транзакции над несколькими картами ядро не даёт, поэтому порядок выбран так,
+    /// чтобы в промежуточных состояниях правил было строже, а не мягче, чем в обеих
+    /// политиках: сначала добавляются блокировки и убираются разрешения, и только потом
+    /// добавляются новые разрешения и снимаются старые блокировки.
+    ///
+    /// С `grace` секундами соединения к портам, разрешение которых снимается, доживают окно;
+    /// без него их записи удаляются раньше, чем сами порты.
+    fn switch(&mut self, old: &Policy, new: &Policy, grace: u32) -> anyhow::Result<()> {
+        // Блокировки: добавить.
+        add(&mut self.blocked_macs, &old.blocked_macs, &new.blocked_macs, 1)?;
+        if !new.blocked_macs.is_empty() {
+            self.settings.set(settings::MAC_FILTER, 1, 0)?;
+        }
+        add(&mut self.blocked_ips, &old.blocked_ips, &new.blocked_ips, 0)?;
+        // Порт, заблокированный в обеих политиках, пока остаётся и со старыми протоколами.
+        for &(port, protos) in &new.blocked_src_ports {
+            let kept = old.blocked_src_ports.iter().find(|(p, _)| *p == port).map_or(0, |e| e.1);
+            self.blocked_src_ports.insert(port, protos | kept, 0)?;
+        }
+        add_prefixes(&mut self.blocked_nets, &old.blocked_nets, &new.blocked_nets, 0)?;
+        add_prefixes(&mut self.blocked_ips6, &old.blocked_ips6, &new.blocked_ips6, 0)?;
+        // Запреты протоколов встают раньше, чем правила начнут решать протоколы не из списка.
+        for &(proto, rule) in &new.protocols {
+            if rule == protocol_rule::DENY {
+                self.protocols.set(u32::from(proto), rule, 0)?;
+            }
+        }
+        // Защищаемых адресов становится больше, а без списка правила действуют для всех.
+        add_prefixes(&mut self.protected_ips, &old.protected_ips, &new.protected_ips, 1)?;
+        if new.protected_ips.is_empty() {
+            self.settings.set(settings::PROTECTED_IPS, 0, 0)?;
+        }
+        // Пока правила меняются, блокировки стран действуют круглые сутки.
+        if old.country_window != new.country_window {
+            self.settings.set(settings::COUNTRY_WINDOW, 0, 0)?;
+        }
+        add(&mut self.blocked_countries, &old.blocked_countries, &new.blocked_countries, 1)?;
+        add(&mut self.tcp_windows, &old.tcp_windows, &new.tcp_windows, 1)?;
+        if !new.tcp_windows.is_empty() {
+            self.settings.set(settings::TCP_WINDOW_FILTER, 1, 0)?;
+        }
+        if new.tcp_flag_filter != 0 {
+            self.settings.set(settings::TCP_FLAG_FILTER, 1, 0)?;
+        }
+        if new.strict_protocols != 0 {
+            self.settings.set(settings::STRICT_PROTOCOLS, 1, 0)?;
+        }
+        if new.filter_multicast != 0 {
+            self.settings.set(settings::FILTER_MULTICAST, 1, 0)?;
+        }
+        if new.drop_fragments != 0 {
+            self.settings.set(settings::DROP_FRAGMENTS, 1, 0)?;
+        }
+        // Адрес пишется раньше длины: при смене одной сети на другую ARP ненадолго
+        // проверяется по новому адресу со старой длиной.
+        if new.arp_prefix != 0 {
+            self.settings.set(settings::ARP_SUBNET, new.arp_subnet, 0)?;
+            self.settings.set(settings::ARP_PREFIX, new.arp_prefix, 0)?;
+        }
+        add_prefixes(
+            &mut self.blocked_endpoints,
+            &old.blocked_endpoints,
+            &new.blocked_endpoints,
+            1,
+        )?;
+        if new.endpoint_match != 0 {
+            self.settings.set(settings::ENDPOINT_MATCH, new.endpoint_match, 0)?;
+        }
+        let (append_masks, compact_masks) = mask_steps(&old.blocked_masks, &new.blocked_masks);
+        self.apply_masks(&append_masks)?;
+
+        // Разрешения: убрать лишние.
+        if new.default_policy == 0 {
+            self.settings.set(settings::DEFAULT_POLICY, 0, 0)?;
+        }
+        if new.icmp_echo == 0 {
+            self.settings.set(settings::ICMP_ECHO, 0, 0)?;
+        }
+        if new.allow_dns == 0 {
+            self.settings.set(settings::ALLOW_DNS, 0, 0)?;
+        }
+        if new.fast_accept.is_empty() {
+            self.settings.set(settings::FAST_ACCEPT, 0, 0)?;
+        }
+        remove_prefixes(&mut self.fast_accept, &old.fast_accept, &new.fast_accept)?;
+        for &(proto, rule) in &old.protocols {
+            if rule == protocol_rule::ALLOW && !new.protocols.contains(&(proto, rule)) {
+                let deny = new.protocols.contains(&(proto, protocol_rule::DENY));
+                if !deny {
+                    self.protocols.set(u32::from(proto), protocol_rule::UNLISTED, 0)?;
+                }
+            }
+        }
+        remove(&mut self.allowed_ips, &old.allowed_ips, &new.allowed_ips)?;
+        self.drain_accepted(old, new, grace)?;
+        for (port, _) in &old.allowed_ports {
+            if !new.allowed_ports.iter().any(|(p, _)| p == port) {
+                self.allowed_ports.remove(port)?;
+            }
+        }
+
+        // Разрешения: добавить новые. Смена port-match меняет смысл всех разрешённых портов,
+        // поэтому она идёт вместе с ними, когда лишние уже убраны.
+        self.settings.set(settings::PORT_MATCH, new.port_match, 0)?;
+        for (port, protos) in &new.allowed_ports {
+            self.allowed_ports.insert(port, protos, 0)?;
+        }
+        add(&mut self.allowed_ips, &old.allowed_ips, &new.allowed_ips, 1)?;
+        for &(proto, rule) in &new.protocols {
+            if rule == protocol_rule::ALLOW {
+                self.protocols.set(u32::from(proto), rule, 0)?;
+            }
+        }
+        if new.icmp_echo != 0 {
+            self.settings.set(settings::ICMP_ECHO, 1, 0)?;
+        }
+        if new.allow_dns != 0 {
+            self.settings.set(settings::ALLOW_DNS, 1, 0)?;
+        }
+        if new.default_policy != 0 {
+            self.settings.set(settings::DEFAULT_POLICY, 1, 0)?;
+        }
+        add_prefixes(&mut self.fast_accept, &old.fast_accept, &new.fast_accept, 1)?;
+        if !new.fast_accept.is_empty() {
+            self.settings.set(settings::FAST_ACCEPT, 1, 0)?;
+        }
+
+        // Блокировки: снять старые.
+        if new.endpoint_match == 0 {
+            self.settings.set(settings::ENDPOINT_MATCH, 0, 0)?;
+        }
+        remove_prefixes(
+            &mut self.blocked_endpoints,
+            &old.blocked_endpoints,
+            &new.blocked_endpoints,
+        )?;
+        if new.tcp_windows.is_empty() {
+            self.settings.set(settings::TCP_WINDOW_FILTER, 0, 0)?;
+        }
+        if new.tcp_flag_filter == 0 {
+            self.settings.set(settings::TCP_FLAG_FILTER, 0, 0)?;
+        }
+        if new.strict_protocols == 0 {
+            self.settings.set(settings::STRICT_PROTOCOLS, 0, 0)?;
+        }
+        for &(proto, rule) in &old.protocols {
+            if rule == protocol_rule::DENY && !new.protocols.iter().any(|&(p, _)| p == proto) {
+                self.protocols.set(u32::from(proto), protocol_rule::UNLISTED, 0)?;
+            }
+        }
+        if new.filter_multicast == 0 {
+            self.settings.set(settings::FILTER_MULTICAST, 0, 0)?;
+        }
+        if new.drop_fragments == 0 {
+            self.settings.set(settings::DROP_FRAGMENTS, 0, 0)?;
+        }
+        if new.arp_prefix == 0 {
+            self.settings.set(settings::ARP_PREFIX, 0, 0)?;
+            self.settings.set(settings::ARP_SUBNET, 0, 0)?;
+        }
+        for (port, _) in &old.blocked_src_ports {
+            if !new.blocked_src_ports.iter().any(|(p, _)| p == port) {
+                self.blocked_src_ports.remove(port)?;
+            }
+        }
+        for (port, protos) in &new.blocked_src_ports {
+            self.blocked_src_ports.insert(port, protos, 0)?;
+        }
+        remove(&mut self.tcp_windows, &old.tcp_windows, &new.tcp_windows)?;
+        remove(&mut self.blocked_countries, &old.blocked_countries, &new.blocked_countries)?;
+        self.settings.set(settings::COUNTRY_WINDOW, new.country_window, 0)?;
+        remove(&mut self.blocked_ips, &old.blocked_ips, &new.blocked_ips)?;
+        remove_prefixes(&mut self.blocked_nets, &old.blocked_nets, &new.blocked_nets)?;
+        remove_prefixes(&mut self.blocked_ips6, &old.blocked_ips6, &new.blocked_ips6)?;
+        if !new.protected_ips.is_empty() {
+            self.settings.set(settings::PROTECTED_IPS, 1, 0)?;
+        }
+        remove_prefixes(&mut self.protected_ips, &old.protected_ips, &new.protected_ips)?;
+        if new.blocked_macs.is_empty() {
+            self.settings.set(settings::MAC_FILTER, 0, 0)?;
+        }
+        remove(&mut self.blocked_macs, &old.blocked_macs, &new.blocked_macs)?;
+        self.apply_masks(&compact_masks)?;
+        Ok(())
+    }
+
+    fn apply_masks(&mut self, steps: &[MaskStep]) -> anyhow::Result<()> {
+        for step in steps {
+            match *step {
+                MaskStep::Set(index, rule) => self.blocked_masks.set(index, rule, 0)?,
+                MaskStep::Count(count) => self.settings.set(settings::BLOCKED_MASKS, count, 0)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Оставляет в `ACCEPTED_CONNS` соединения к портам, которые `new` разрешает, а с
+    /// `grace` — и к тем, что разрешала `old`, и открывает для них окно. Записи прошлых
+    /// окон к портам, которых нет ни в одной из политик, удаляются.
+    fn drain_accepted(&mut self, old: &Policy, new: &Policy, grace: u32) -> anyhow::Result<()> {
+        let kept = |policy: &Policy, key: &ConnKey| {
+            let port = if policy.port_match == 1 { key.src_port } else { key.dst_port };
+            is_tcp_port(&policy.allowed_ports, port)
+        };
+        let stale: Vec<ConnKey> = self
+            .accepted_conns
+            .keys()
+            .filter(|key| {
+                key.as_ref().map_or(true, |key| !kept(new, key) && (grace == 0 || !kept(old, key)))
+            })
+            .collect::<Result<_, _>>()?;
+        for key in &stale {
+            // Запись могла уйти из LRU-таблицы сама.
+            let _ = self.accepted_conns.remove(key);
+        }
+        let removed = old.allowed_ports.iter().any(|&(port, _)| {
+            is_tcp_port(&old.allowed_ports, port) && !is_tcp_port(&new.allowed_ports, port)
+        });
+        if grace != 0 && removed {
+            let until = knocks::monotonic_ns() / 1_000_000_000 + u64::from(grace);
+            self.settings.set(settings::GRACE_UNTIL, until as u32, 0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Запись в `BLOCKED_MASKS` или в счётчик `settings::BLOCKED_MASKS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MaskStep {
+    Set(u32, MaskedAddr),
+    Count(u32),
+}
+
+/// Записи перевода правил с маской с `old` (первые ячейки карты по порядку) на `new`: для
+/// добавления блокировок и для снятия старых.
+///
+/// Программа проверяет ячейки до счётчика, поэтому новые правила сперва дописываются за
+/// старыми, а счётчик растёт после них. Снятие заполняет ячейки лишних правил последними
+/// нужными и только затем уменьшает счётчик, так что ни в одном промежуточном состоянии не
+/// пропадает правило, которое есть в обеих политиках или уже добавлено. Правила, которым не
+/// хватило места за старыми, дописываются в конце.
+fn mask_steps(old: &[MaskedAddr], new: &[MaskedAddr]) -> (Vec<MaskStep>, Vec<MaskStep>) {
+    let mut live = old.to_vec();
+    let mut append = Vec::new();
+    for rule in new.iter().filter(|rule| !old.contains(rule)) {
+        if live.len() < MAX_BLOCKED_MASKS as usize {
+            append.push(MaskStep::Set(live.len() as u32, *rule));
+            live.push(*rule);
+        }
+    }
+    if live.len() != old.len() {
+        append.push(MaskStep::Count(live.len() as u32));
+    }
+
+    let mut compact = Vec::new();
+    // Ячейка нужна, если её правило есть в `new` и не повторяет одну из предыдущих.
+    let needed = |live: &[MaskedAddr], index: usize| {
+        new.contains(&live[index]) && !live[..index].contains(&live[index])
+    };
+    while let Some(hole) = (0..live.len()).find(|&index| !needed(&live, index)) {
+        let last = live.len() - 1;
+        if hole != last && needed(&live, last) {
+            compact.push(MaskStep::Set(hole as u32, live[last]));
+            live[hole] = live[last];
+        }
+        live.pop();
+        compact.push(MaskStep::Count(live.len() as u32));
+    }
+    for rule in new {
+        if !live.contains(rule) {
+            compact.push(MaskStep::Set(live.len() as u32, *rule));
+            live.push(*rule);
+            compact.push(MaskStep::Count(live.len() as u32));
+        }
+    }
+    (append, compact)
+}
+
+/// Разрешён ли порт `port` для TCP в списке `allowed-ports`.
+fn is_tcp_port(allowed: &[(u16, u8)], port: u16) -> bool {
+    allowed.iter().any(|&(p, protos)| p == port && protos & port_protos::TCP != 0)
+}
+
+/// Добавляет в `map` ключи `new`, которых нет в `old`, со значением `value`; ключи обеих
+/// политик не переписываются, и счётчики срабатываний блокировок сохраняются.
+fn add<K: Pod + Eq + Hash, V: Pod>(
+    map: &mut HashMap<MapData, K, V>,
+    old: &[K],
+    new: &[K],
+    value: V,
+) -> anyhow::Result<()> {
+    for key in new.iter().filter(|key| !old.contains(key)) {
+        map.insert(key, value, 0)?;
+    }
+    Ok(())
+}
+
+fn remove<K: Pod + Eq + Hash, V: Pod>(
+    map: &mut HashMap<MapData, K, V>,
+    old: &[K],
+    new: &[K],
+) -> anyhow::Result<()> {
+    for key in old.iter().filter(|key| !new.contains(key)) {
+        map.remove(key)?;
+    }
+    Ok(())
+}
+
+/// То же, что [`add`], для LPM-карты.
+fn add_prefixes<K: Pod + PartialEq, V: Pod>(
+    map: &mut LpmTrie<MapData, K, V>,
+    old: &[(u8, K)],
+    new: &[(u8, K)],
+    value: V,
+) -> anyhow::Result<()> {
+    for &(prefix, data) in new.iter().filter(|entry| !old.contains(entry)) {
+        map.insert(&Key::new(u32::from(prefix), data), value, 0)?;
+    }
+    Ok(())
+}
+
+fn remove_prefixes<K: Pod + PartialEq, V: Pod>(
+    map: &mut LpmTrie<MapData, K, V>,
+    old: &[(u8, K)],
+    new: &[(u8, K)],
+) -> anyhow::Result<()> {
+    for &(prefix, data) in old.iter().filter(|entry| !new.contains(entry)) {
+        map.remove(&Key::new(u32::from(prefix), data))?;
+    }
+    Ok(())
+}
+
+/// Выполняет `firewall-cli compile-policy`.
+pub fn compile(config: &Config, output: &Path) -> i32 {
+    let blob = match Policy::from_config(config) {
+        Ok(policy) => policy.encode(),
+        Err(e) => {
+            println!("Ошибка: {e:#}");
+            return 1;
+        }
+    };
+    if let Err(e) = fs::write(output, &blob) {
+        println!("Не удалось записать {}: {e}", output.display());
+        return 1;
+    }
+    println!("Политика записана в {} ({} байт).", output.display(), blob.len());
+    0
+}
+
+fn apply_file(path: &Path, grace: u32) -> anyhow::Result<()> {
+    let data = fs::read(path).with_context(|| format!("не удалось прочитать {}", path.display()))?;
+    let new = Policy::decode(&data)
+        .map_err(anyhow::Error::msg)
+        .with_context(|| format!("{}", path.display()))?;
+    switch_to(&new, grace)
+}
+
+/// Политика, которую сейчас применяет запущенный файрволл.
+pub fn live() -> anyhow::Result<Policy> {
+    Maps::open()?.read()
+}
+
+/// Сколько пакетов отброшено по каждой записи `blocked-ips` с запуска файрволла или с
+/// добавления записи; ключи — как в [`Policy`].
+#[derive(Debug, Default)]
+pub struct Hits {
+    pub blocked_ips: Vec<(u32, u64)>,
+    pub blocked_nets: Vec<((u8, u32), u64)>,
+    pub blocked_ips6: Vec<((u8, [u8; 16]), u64)>,
+}
+
+/// Счётчики срабатываний блокировок запущенного файрволла.
+pub fn hits() -> anyhow::Result<Hits> {
+    let maps = Maps::open()?;
+    Ok(Hits {
+        blocked_ips: maps.blocked_ips.iter().collect::<Result<_, _>>()?,
+        blocked_nets: maps
+            .blocked_nets
+            .iter()
+            .map(|entry| entry.map(|(key, hits)| ((key.prefix_len() as u8, key.data()), hits)))
+            .collect::<Result<_, _>>()?,
+        blocked_ips6: maps
+            .blocked_ips6
+            .iter()
+            .map(|entry| entry.map(|(key, hits)| ((key.prefix_len() as u8, key.data()), hits)))
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+/// Переводит запущенный файрволл на политику конфигурации, как `apply-policy`, но без
+/// файла политики (SIGHUP).
+pub fn reload(config: &Config) -> anyhow::Result<()> {
+    switch_to(&Policy::from_config(config)?, config.grace_period.unwrap_or(0))
+}
+
+/// Заменяет правила запущенного файрволла на `new`, сохранив прежние в [`ROLLBACK_FILE`];
+/// соединения к снятым портам доживают `grace` секунд.
+pub fn switch_to(new: &Policy, grace: u32) -> anyhow::Result<()> {
+    let mut maps = Maps::open()?;
+    let old = maps.read().context("не удалось прочитать текущие правила")?;
+    let rollback = Path::new(ROLLBACK_FILE);
+    if let Some(dir) = rollback.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("не удалось создать {}", dir.display()))?;
+    }
+    fs::write(rollback, old.encode())
+        .with_context(|| format!("не удалось сохранить текущие правила в {ROLLBACK_FILE}"))?;
+    if grace != 0 && maps.settings.get(&settings::CONNTRACK_TIMEOUT, 0)? == 0 {
+        println!("Окно {grace} с не действует: без conntrack-timeout соединения не записываются.");
+    }
+
+    if let Err(e) = maps.switch(&old, new, grace) {
+        // Карты могли остановиться посередине: возвращаются к прежней политике от того
+        // состояния, в котором оказались.
+        let current = maps.read()?;
+        maps.switch(&current, &old, 0).with_context(|| {
+            format!("{e:#}; откат тоже не удался, прежние правила в {ROLLBACK_FILE}")
+        })?;
+        return Err(e.context("правила не заменены, прежняя политика восстановлена"));
+    }
+    Ok(())
+}
+
+/// Выполняет `firewall-cli apply-policy`; `grace` — окно для соединений к снятым портам.
+pub fn apply(path: &Path, grace: u32) -> i32 {
+    if let Err(e) = apply_file(path, grace) {
+        println!("Ошибка: {e:#}");
+        return 1;
+    }
+    if let Err(e) = audit::record(&format!("apply-policy {}", path.display())) {
+        println!("Не удалось записать apply-policy в {}: {e}", audit::AUDIT_LOG);
+    }
+    println!(
+        "Политика из {} применена; прежняя сохранена в {ROLLBACK_FILE}.",
+        path.display()
+    );
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(last: u32) -> MaskedAddr {
+        MaskedAddr::new(last, 0xff)
+    }
+
+    /// Применяет `steps` к ячейкам `cells` со счётчиком `count`, проверяя после каждой
+    /// записи, что программа видит все правила `kept`.
+    fn run(cells: &mut Vec<MaskedAddr>, count: &mut u32, steps: &[MaskStep], kept: &[MaskedAddr]) {
+        for step in steps {
+            match *step {
+                MaskStep::Set(index, rule) => {
+                    let index = index as usize;
+                    assert!(index < MAX_BLOCKED_MASKS as usize);
+                    if index >= cells.len() {
+                        cells.resize(index + 1, MaskedAddr::default());
+                    }
+                    cells[index] = rule;
+                }
+                MaskStep::Count(n) => *count = n,
+            }
+            let live = &cells[..*count as usize];
+            for rule in kept {
+                assert!(live.contains(rule), "{rule:?} пропало после {step:?}: {live:?}");
+            }
+        }
+    }
+
+    fn switch_masks(old: &[MaskedAddr], new: &[MaskedAddr]) {
+        let (append, compact) = mask_steps(old, new);
+        let mut cells = old.to_vec();
+        let mut count = old.len() as u32;
+        let both: Vec<MaskedAddr> = old.iter().filter(|rule| new.contains(rule)).copied().collect();
+        run(&mut cells, &mut count, &append, old);
+        let added: Vec<MaskedAddr> = cells[..count as usize]
+            .iter()
+            .filter(|rule| new.contains(rule))
+            .copied()
+            .collect();
+        run(&mut cells, &mut count, &compact, &added);
+        assert!(both.iter().all(|rule| added.contains(rule)));
+        let live = sorted(cells[..count as usize].to_vec());
+        assert_eq!(live.len(), count as usize, "повторы в ячейках {cells:?}");
+        assert_eq!(live, sorted(new.to_vec()));
+    }
+
+    #[test]
+    fn masks_are_appended_before_old_ones_are_removed() {
+        switch_masks(&[rule(1), rule(2), rule(3)], &[rule(2), rule(4)]);
+        switch_masks(&[], &[rule(1)]);
+        switch_masks(&[rule(1), rule(2)], &[]);
+        switch_masks(&[rule(1), rule(1), rule(2)], &[rule(2)]);
+        switch_masks(&[rule(5), rule(1)], &[rule(1), rule(5)]);
+        let (append, compact) = mask_steps(&[rule(1)], &[rule(1)]);
+        assert!(append.is_empty() && compact.is_empty());
+    }
+
+    #[test]
+    fn masks_that_do_not_fit_are_added_after_old_ones_are_removed() {
+        let old: Vec<MaskedAddr> = (0..MAX_BLOCKED_MASKS).map(rule).collect();
+        let new: Vec<MaskedAddr> = (1..=MAX_BLOCKED_MASKS).map(rule).collect();
+        switch_masks(&old, &new);
+    }
+
+    #[test]
+    fn compiled_policy_reflects_every_rule_and_survives_the_blob() {
+        let pairs = [
+            ("allowed-ports", "22/tcp, 53/udp"),
+            ("blocked-ips", "198.51.100.7, 203.0.113.0/24"),
+            ("blocked-masks", "0.0.0.9/0.0.0.255, 0.0.0.7/0.0.0.255"),
+            ("blocked-countries", "RU"),
+            ("policy", "allow"),
+        ];
+        let pairs: Vec<(String, String)> =
+            pairs.iter().map(|&(key, value)| (key.to_string(), value.to_string())).collect();
+        let config = Config::parse_values(&pairs).unwrap();
+        let policy = Policy::from_config(&config).unwrap();
+        assert_eq!(policy.allowed_ports, [(22, port_protos::TCP), (53, port_protos::UDP)]);
+        assert_eq!(policy.blocked_ips, [0xc633_6407]);
+        assert_eq!(policy.blocked_nets, [(24, u32::from_be(0xcb00_7100))]);
+        assert_eq!(policy.blocked_masks, [rule(7), rule(9)]);
+        assert_eq!(policy.blocked_countries, [pack_country(b"RU")]);
+        assert_eq!(policy.default_policy, 1);
+
+        let blob = policy.encode();
+        assert_eq!(Policy::decode(&blob), Ok(policy));
+        let mut corrupt = blob.clone();
+        corrupt[MAGIC.len() + 4] ^= 1;
+        assert!(Policy::decode(&corrupt).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+//! Права, нужные загрузчику, и запуск через `sudo`, `doas` или `pkexec`, если их нет.
+//!
+//! Загрузка программы XDP требует `CAP_NET_ADMIN` и `CAP_BPF` (на старых ядрах вместо него
+//! `CAP_SYS_ADMIN`). Если у firewall-cli эти права уже есть (root или выданные
+//! возможности), загрузчик запускается напрямую, иначе через программу из `escalator`.
+//! Оболочка здесь не участвует: каждый аргумент из конфигурации передаётся отдельным
+//! элементом argv, так что имена интерфейсов и адреса не могут стать командой.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::config::Escalator;
+
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_BPF: u32 = 39;
+
+/// Действующие возможности процесса (`CapEff` из `/proc/self/status`).
+fn effective_caps() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find_map(|line| line.strip_prefix("CapEff:"))?;
+    u64::from_str_radix(line.trim(), 16).ok()
+}
+
+/// Хватает ли процессу прав, чтобы загрузить программу XDP.
+pub fn can_load_xdp() -> bool {
+    // SAFETY: geteuid не имеет предусловий и не может завершиться ошибкой.
+    if unsafe { libc::geteuid() } == 0 {
+        return true;
+    }
+    let Some(caps) = effective_caps() else {
+        return false;
+    };
+    let has = |cap: u32| caps & (1 << cap) != 0;
+    has(CAP_NET_ADMIN) && (has(CAP_BPF) || has(CAP_SYS_ADMIN))
+}
+
+/// Где `program` лежит в `PATH`.
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path).map(|dir| dir.join(program)).find(|path| path.is_file())
+}
+
+/// Команда запуска загрузчика с аргументами `args`: напрямую, если прав хватает, иначе
+/// через `escalator`. Ошибка объясняет, что делать, если прав нет и `escalator` тоже нет.
+pub fn firewall_command(args: &[String], escalator: Escalator) -> Result<Command, String> {
+    let mut command = if can_load_xdp() {
+        Command::new("firewall")
+    } else if find_in_path(escalator.as_str()).is_some() {
+        let name = escalator.as_str();
+        println!("Для загрузки программы XDP нужны права root, запуск через {name}.");
+        let mut command = Command::new(name);
+        match escalator {
+            // pkexec запускает программу с чистым окружением, без PATH пользователя.
+            Escalator::Pkexec => {
+                let firewall = find_in_path("firewall")
+                    .ok_or("загрузчик firewall не найден в PATH, pkexec нужен полный путь")?;
+                command.arg(firewall);
+            }
+            Escalator::Sudo | Escalator::Doas => {
+                command.arg("firewall");
+            }
+        }
+        command
+    } else {
+        return Err(format!(
+            "для загрузки программы XDP нужны права root (CAP_NET_ADMIN и CAP_BPF), а {} не \
+             найден: запустите firewall-cli от root или выберите другой escalator",
+            escalator.as_str()
+        ));
+    };
+    command.args(args);
+    Ok(command)
+}
+
+/// Команда в виде строки для показа пользователю.
+pub fn display(command: &Command) -> String {
+    let program = Path::new(command.get_program()).display().to_string();
+    let args: Vec<String> =
+        command.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect();
+    format!("{program} {}", crate::shell_words(&args))
+}
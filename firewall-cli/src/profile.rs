@@ -0,0 +1,203 @@
+//! `firewall-cli profile`: какие правила сколько стоят на пакет.
+//!
+//! На время замера включается настройка `PROFILE`, и программа XDP добавляет время каждой
+//! проверки правил к ячейке её типа в `RULE_COSTS`. Результат — разница снимков карты до и
+//! после замера, поэтому другие запуски `profile` в это же время искажают его.
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use aya::maps::{Map, MapData, PerCpuArray};
+use firewall_common::{rule_costs, settings, RuleCost, PIN_PATH, RULE_COSTS_MAP};
+
+use crate::control;
+
+/// Затраты одного типа правил за время замера.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleShare {
+    pub rule: &'static str,
+    pub calls: u64,
+    pub ns: u64,
+    /// Доля от времени всей проверки правил, от 0 до 1.
+    pub share: f64,
+}
+
+/// Итог замера: проверка правил целиком и разбивка по типам правил.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub elapsed: Duration,
+    /// Сколько пакетов прошло проверку правил и сколько она заняла в сумме.
+    pub total: RuleCost,
+    /// Типы правил, которые проверялись хотя бы раз, от самых дорогих.
+    pub rules: Vec<RuleShare>,
+}
+
+/// Распределяет время между типами правил по снимкам `RULE_COSTS` до и после замера.
+///
+/// Снимки — просуммированные по CPU ячейки, по одной на слот [`rule_costs`]. Если счётчик
+/// уменьшился, карта создана заново перезапуском загрузчика, и в замер идёт текущее значение.
+pub fn attribute(before: &[RuleCost], after: &[RuleCost], elapsed: Duration) -> Profile {
+    let delta = |slot: u32| {
+        let slot = slot as usize;
+        let prev = before.get(slot).copied().unwrap_or_default();
+        let cur = after.get(slot).copied().unwrap_or_default();
+        RuleCost {
+            calls: cur.calls.checked_sub(prev.calls).unwrap_or(cur.calls),
+            ns: cur.ns.checked_sub(prev.ns).unwrap_or(cur.ns),
+        }
+    };
+    let total = delta(rule_costs::DECIDE);
+    let mut rules: Vec<RuleShare> = (0..rule_costs::DECIDE)
+        .map(|slot| (slot, delta(slot)))
+        .filter(|(_, cost)| cost.calls > 0)
+        .map(|(slot, cost)| RuleShare {
+            rule: rule_costs::name(slot),
+            calls: cost.calls,
+            ns: cost.ns,
+            share: if total.ns == 0 { 0.0 } else { cost.ns as f64 / total.ns as f64 },
+        })
+        .collect();
+    rules.sort_by_key(|rule| std::cmp::Reverse(rule.ns));
+    Profile { elapsed, total, rules }
+}
+
+/// Таблица для терминала.
+pub fn format(profile: &Profile) -> String {
+    let total = profile.total;
+    if total.calls == 0 {
+        return format!(
+            "За {} с ни один пакет не дошёл до проверки правил.",
+            profile.elapsed.as_secs()
+        );
+    }
+    let mut out = format!(
+        "За {} с проверено пакетов: {}, в среднем {} нс на пакет.\n\n",
+        profile.elapsed.as_secs(),
+        total.calls,
+        total.ns / total.calls
+    );
+    out.push_str(&format!(
+        "{:<20} {:>12} {:>12} {:>10} {:>7}\n",
+        "Правила", "Проверок", "Время, мс", "нс/пров.", "Доля"
+    ));
+    for rule in &profile.rules {
+        out.push_str(&format!(
+            "{:<20} {:>12} {:>12.1} {:>10} {:>6.1}%\n",
+            rule.rule,
+            rule.calls,
+            rule.ns as f64 / 1e6,
+            rule.ns / rule.calls,
+            rule.share * 100.0
+        ));
+    }
+    let rules_ns: u64 = profile.rules.iter().map(|rule| rule.ns).sum();
+    out.push_str(&format!(
+        "\nОстальное ({:.1}%) — разбор и накладные расходы самого замера.",
+        total.ns.saturating_sub(rules_ns) as f64 / total.ns as f64 * 100.0
+    ));
+    out
+}
+
+/// Снимок `RULE_COSTS`, просуммированный по CPU.
+fn snapshot(map: &PerCpuArray<MapData, RuleCost>) -> anyhow::Result<Vec<RuleCost>> {
+    (0..rule_costs::LEN)
+        .map(|slot| {
+            let values = map.get(&slot, 0)?;
+            Ok(values.iter().fold(RuleCost::default(), |sum, cost| RuleCost {
+                calls: sum.calls.wrapping_add(cost.calls),
+                ns: sum.ns.wrapping_add(cost.ns),
+            }))
+        })
+        .collect()
+}
+
+fn measure(duration: Duration) -> anyhow::Result<Profile> {
+    let mut settings_map = control::open_settings()?.context("файрволл не запущен")?;
+    let path = Path::new(PIN_PATH).join(RULE_COSTS_MAP);
+    let data = MapData::from_pin(&path).with_context(|| format!("не удалось открыть {path:?}"))?;
+    let costs = PerCpuArray::try_from(Map::PerCpuArray(data))?;
+
+    // Ctrl+C завершает замер досрочно, но замер всё равно выключается.
+    let running = Arc::new(AtomicBool::new(true));
+    {
+        let r = Arc::clone(&running);
+        ctrlc::set_handler(move || r.store(false, Ordering::SeqCst))
+            .context("не удалось установить обработчик Ctrl+C")?;
+    }
+
+    let before = snapshot(&costs)?;
+    settings_map.set(settings::PROFILE, 1, 0)?;
+    let started = Instant::now();
+    while running.load(Ordering::SeqCst) && started.elapsed() < duration {
+        thread::sleep(Duration::from_millis(100));
+    }
+    settings_map.set(settings::PROFILE, 0, 0)?;
+    let elapsed = started.elapsed();
+    Ok(attribute(&before, &snapshot(&costs)?, elapsed))
+}
+
+/// Выполняет `firewall-cli profile`.
+pub fn run(duration: u64) -> i32 {
+    println!("Замер затрат на правила: {duration} с (Ctrl+C — закончить раньше)...");
+    match measure(Duration::from_secs(duration)) {
+        Ok(profile) => {
+            println!("{}", format(&profile));
+            0
+        }
+        Err(e) => {
+            println!("Ошибка: {e:#}");
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cost(calls: u64, ns: u64) -> RuleCost {
+        RuleCost { calls, ns }
+    }
+
+    #[test]
+    fn cost_is_attributed_to_rule_types_from_samples() {
+        let mut before = vec![RuleCost::default(); rule_costs::LEN as usize];
+        before[rule_costs::BLOCKED_IP as usize] = cost(100, 5_000);
+        before[rule_costs::DECIDE as usize] = cost(100, 40_000);
+        let mut after = before.clone();
+        after[rule_costs::BLOCKED_IP as usize] = cost(1_100, 55_000);
+        after[rule_costs::BLOCKED_MASK as usize] = cost(1_000, 300_000);
+        after[rule_costs::ALLOWED_PORT as usize] = cost(800, 40_000);
+        after[rule_costs::DECIDE as usize] = cost(1_100, 540_000);
+
+        let profile = attribute(&before, &after, Duration::from_secs(60));
+        assert_eq!(profile.total, cost(1_000, 500_000));
+        let rules: Vec<_> = profile.rules.iter().map(|r| (r.rule, r.calls, r.ns)).collect();
+        assert_eq!(
+            rules,
+            [
+                ("blocked-masks", 1_000, 300_000),
+                ("blocked-ips", 1_000, 50_000),
+                ("allowed-ports", 800, 40_000),
+            ]
+        );
+        let shares: Vec<_> = profile.rules.iter().map(|r| r.share).collect();
+        assert_eq!(shares, [0.6, 0.1, 0.08]);
+
+        let text = format(&profile);
+        assert!(text.contains("проверено пакетов: 1000, в среднем 500 нс на пакет"));
+        assert!(text.contains("Остальное (22.0%)"));
+
+        // Загрузчик перезапущен: счётчики начались заново, в замер идут текущие значения.
+        let restarted = attribute(&after, &before, Duration::from_secs(60));
+        assert_eq!(restarted.total, cost(100, 40_000));
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::stats::{self, Stats};
+
+/// Скорость по счётчикам действий между двумя снимками, пакетов в секунду.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub pass: f64,
+    pub drop: f64,
+    pub aborted: f64,
+}
+
+impl Rate {
+    pub fn total(&self) -> f64 {
+        self.pass + self.drop + self.aborted
+    }
+}
+
+/// Прирост счётчика между снимками.
+///
+/// Если счётчик уменьшился, он начался заново: загрузчик перезапущен и карты созданы снова
+/// (или, теоретически, переполнился u64). Тогда за интервал накоплено текущее значение.
+fn delta(prev: u64, cur: u64) -> u64 {
+    cur.checked_sub(prev).unwrap_or(cur)
+}
+
+/// Скорость между снимками `prev` и `cur`, снятыми с промежутком `elapsed`.
+pub fn between(prev: &Stats, cur: &Stats, elapsed: Duration) -> Rate {
+    let secs = elapsed.as_secs_f64();
+    if secs == 0.0 {
+        return Rate::default();
+    }
+    let per_sec = |prev, cur| delta(prev, cur) as f64 / secs;
+    Rate {
+        pass: per_sec(prev.pass, cur.pass),
+        drop: per_sec(prev.drop, cur.drop),
+        aborted: per_sec(prev.aborted, cur.aborted),
+    }
+}
+
+/// Одна строка вывода `firewall-cli rate`.
+pub fn format_rate(iface: &str, rate: &Rate) -> String {
+    let total = rate.total();
+    let share = if total == 0.0 { 0.0 } else { rate.drop * 100.0 / total };
+    format!(
+        "{iface}: {total:>10.0} пак/с, пропущено {:>10.0}, отброшено {:>10.0} ({share:.1}%), \
+         ошибки {:.0}",
+        rate.pass, rate.drop, rate.aborted
+    )
+}
+
+fn fetch() -> Result<Stats, String> {
+    match stats::fetch_stats() {
+        Ok(Some(current)) => Ok(current),
+        Ok(None) => Err("Файрволл не запущен: статистика недоступна.".to_string()),
+        Err(e) => Err(format!("Не удалось прочитать статистику: {e:#}")),
+    }
+}
+
+/// Выполняет `firewall-cli rate`: печатает скорость каждые `interval` до Ctrl+C.
+pub fn run(iface: &str, interval: Duration) -> i32 {
+    let mut prev = match fetch() {
+        Ok(snapshot) => snapshot,
+        Err(message) => {
+            println!("{message}");
+            return 1;
+        }
+    };
+    let mut taken = Instant::now();
+    loop {
+        thread::sleep(interval);
+        let cur = match fetch() {
+            Ok(snapshot) => snapshot,
+            Err(message) => {
+                println!("{message}");
+                return 1;
+            }
+        };
+        let now = Instant::now();
+        println!("{}", format_rate(iface, &between(&prev, &cur, now - taken)));
+        (prev, taken) = (cur, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(pass: u64, drop: u64, aborted: u64) -> Stats {
+        Stats { pass, drop, aborted, ..Default::default() }
+    }
+
+    #[test]
+    fn rate_is_delta_over_interval() {
+        let prev = snapshot(1_000, 200, 0);
+        let cur = snapshot(4_000, 1_200, 5);
+        let rate = between(&prev, &cur, Duration::from_millis(500));
+        assert_eq!(rate, Rate { pass: 6_000.0, drop: 2_000.0, aborted: 10.0 });
+        assert_eq!(rate.total(), 8_010.0);
+        assert_eq!(
+            format_rate("eth0", &rate),
+            "eth0:       8010 пак/с, пропущено       6000, отброшено       2000 (25.0%), ошибки 10"
+        );
+    }
+
+    #[test]
+    fn reset_counter_counts_from_zero() {
+        // Загрузчик перезапущен: счётчики начались заново.
+        let prev = snapshot(1_000_000, 50, 3);
+        let cur = snapshot(300, 20, 3);
+        let rate = between(&prev, &cur, Duration::from_secs(2));
+        assert_eq!(rate, Rate { pass: 150.0, drop: 10.0, aborted: 0.0 });
+        assert_eq!(delta(u64::MAX - 1, 4), 4);
+        assert_eq!(between(&prev, &cur, Duration::ZERO), Rate::default());
+    }
+}
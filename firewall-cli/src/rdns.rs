@@ -0,0 +1,118 @@
+//! Обратный DNS для адресов источника в таблицах статистики (`--resolve-names`).
+//!
+//! Запросы выполняют фоновые потоки, а таблица берёт имена только из кэша и никогда не ждёт
+//! DNS: пока имени нет или оно не нашлось, показывается сам адрес. Ответы, и удачные, и
+//! неудачные, хранятся [`CACHE_TTL`], поэтому обновляемая раз в секунду таблица не
+//! повторяет запросы.
+
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    mem,
+    net::Ipv4Addr,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Сколько хранится ответ, в том числе отрицательный.
+const CACHE_TTL: Duration = Duration::from_secs(600);
+
+/// Сколько ждать ответа: дольше запрос считается неудачным, и адрес показывается без имени.
+pub const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Сколько запросов выполняется одновременно: зависший запрос не задерживает остальные.
+const WORKERS: usize = 4;
+
+enum Entry {
+    /// Запрос отправлен в момент `since`.
+    Pending { since: Instant },
+    /// Ответ получен в момент `at`; `None` — у адреса нет имени.
+    Done { name: Option<String>, at: Instant },
+}
+
+type Cache = Arc<Mutex<HashMap<Ipv4Addr, Entry>>>;
+
+pub struct Resolver {
+    cache: Cache,
+    queue: mpsc::Sender<Ipv4Addr>,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        let cache = Cache::default();
+        let (queue, requests) = mpsc::channel();
+        let requests = Arc::new(Mutex::new(requests));
+        for _ in 0..WORKERS {
+            let cache = Arc::clone(&cache);
+            let requests = Arc::clone(&requests);
+            thread::spawn(move || loop {
+                let Ok(addr) = requests.lock().unwrap().recv() else {
+                    return;
+                };
+                let name = lookup(addr);
+                let entry = Entry::Done { name, at: Instant::now() };
+                cache.lock().unwrap().insert(addr, entry);
+            });
+        }
+        Self { cache, queue }
+    }
+
+    /// Имя адреса из кэша, не дожидаясь ответа; отсутствующий или устаревший адрес
+    /// отправляется на разрешение в фоне.
+    pub fn name(&self, addr: Ipv4Addr) -> Option<String> {
+        let mut cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+        let (cached, stale) = match cache.get(&addr) {
+            Some(Entry::Done { name, at }) => (name.clone(), now - *at > CACHE_TTL),
+            Some(Entry::Pending { since }) => (None, now - *since > CACHE_TTL),
+            None => (None, true),
+        };
+        if stale && self.queue.send(addr).is_ok() {
+            cache.insert(addr, Entry::Pending { since: now });
+        }
+        cached
+    }
+
+    /// Запрашивает имена `addrs` и ждёт их не дольше [`LOOKUP_TIMEOUT`], для разового
+    /// вывода статистики.
+    pub fn prefetch(&self, addrs: impl IntoIterator<Item = Ipv4Addr>) {
+        let addrs: Vec<_> = addrs.into_iter().collect();
+        for &addr in &addrs {
+            self.name(addr);
+        }
+        let started = Instant::now();
+        while started.elapsed() < LOOKUP_TIMEOUT {
+            let cache = self.cache.lock().unwrap();
+            if addrs.iter().all(|addr| matches!(cache.get(addr), Some(Entry::Done { .. }))) {
+                return;
+            }
+            drop(cache);
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+/// Запрос PTR через `getnameinfo`; `None`, если имени нет или запрос не удался.
+fn lookup(addr: Ipv4Addr) -> Option<String> {
+    let mut sockaddr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+    sockaddr.sin_addr.s_addr = u32::from(addr).to_be();
+    let mut host = [0 as libc::c_char; libc::NI_MAXHOST as usize];
+    let result = unsafe {
+        libc::getnameinfo(
+            (&sockaddr as *const libc::sockaddr_in).cast(),
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(host.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
@@ -0,0 +1,66 @@
+//! Перечитывание конфигурации по SIGHUP без перезапуска файрволла.
+//!
+//! Обработчик сигнала только поднимает флаг. Цикл, который ждёт загрузчик, проверяет его и
+//! переводит карты правил на новую конфигурацию так же, как `apply-policy`, поэтому
+//! программа XDP остаётся подключённой. Конфигурация с ошибками отклоняется, и правила
+//! остаются прежними. Соединения к портам, снятым с `allowed-ports`, доживают `grace-period`
+//! секунд. Интерфейс, пределы `rate-limit` и регионы задаются загрузчику при запуске и без
+//! перезапуска не меняются.
+
+use std::{
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{audit, config::Config, policy};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_hangup(_: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+fn set_handler(handler: libc::sighandler_t) -> io::Result<()> {
+    // SAFETY: обработчик только записывает атомарный флаг, это безопасно в обработчике
+    // сигнала.
+    if unsafe { libc::signal(libc::SIGHUP, handler) } == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Устанавливает обработчик SIGHUP на время работы загрузчика; флаг от прежних сигналов
+/// сбрасывается.
+pub fn install() -> io::Result<()> {
+    REQUESTED.store(false, Ordering::SeqCst);
+    set_handler(on_hangup as extern "C" fn(libc::c_int) as libc::sighandler_t)
+}
+
+/// Возвращает SIGHUP обычное действие: без запущенного файрволла обрыв терминала
+/// завершает меню, как раньше.
+pub fn restore() {
+    let _ = set_handler(libc::SIG_DFL);
+}
+
+/// Пришёл ли SIGHUP с прошлой проверки; флаг сбрасывается.
+pub fn requested() -> bool {
+    REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+/// Применяет перечитанную конфигурацию; `None` — конфигурация с ошибками, о которых уже
+/// сообщено.
+pub fn apply(config: Option<Config>) {
+    let Some(config) = config else {
+        println!("Конфигурация не применена, файрволл работает с прежними правилами.");
+        return;
+    };
+    match policy::reload(&config) {
+        Ok(()) => {
+            println!("Конфигурация перечитана, правила обновлены.");
+            if let Err(e) = audit::record("reload") {
+                println!("Не удалось записать reload в {}: {e}", audit::AUDIT_LOG);
+            }
+        }
+        Err(e) => println!("Не удалось обновить правила: {e:#}"),
+    }
+}
@@ -0,0 +1,422 @@
+//! `firewall-cli test`: прогон записи трафика через правила и сверка с эталоном.
+//!
+//! Каждый кадр из pcap разбирается [`classify::parse_frame`] и решается
+//! [`classify::decide`], как в программе XDP, по правилам из конфигурации; страна источника
+//! берётся из базы `country-db`, если она задана. Эталон — объект
+//! JSON, где ключ — номер пакета с нуля, а значение — `{"decision": "pass"}` или
+//! `{"decision": "drop", "reason": "blocked-ip"}`.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+use anyhow::Context as _;
+use firewall_common::{
+    classify::{self, Direction, Frame, Packet, Rules, Verdict},
+    direction,
+    geoip::{self, CountryTable},
+    pack_country, port_protos, protocol_rule, DropReason,
+};
+
+use crate::config::{AllowedPort, Config, DefaultPolicy, EndpointMatch, PortMatch, PortProto};
+
+/// Заголовок Ethernet в pcap (`LINKTYPE_ETHERNET`).
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Правила из конфигурации, как их загрузил бы загрузчик.
+///
+/// Регионы (`blocked-regions`) не проверяются: для них нужна база `region-db`, которую
+/// загружает только загрузчик. Пределы `rate-limit` и `syn-rate-limit` тоже не
+/// проверяются: они зависят от темпа трафика, а не от самих пакетов. Окно
+/// `blocked-countries-window` считается открытым, чтобы результат не зависел от времени
+/// прогона. Стук не воспроизводится, так что `knock-port` всегда закрыт.
+struct ConfigRules<'a>(&'a Config);
+
+fn protos(port: &AllowedPort) -> u8 {
+    match port.proto {
+        Some(PortProto::Tcp) => port_protos::TCP,
+        Some(PortProto::Udp) => port_protos::UDP,
+        None => port_protos::ANY,
+    }
+}
+
+impl Rules for ConfigRules<'_> {
+    fn is_blocked_ip(&self, addr: u32) -> bool {
+        self.0.blocked_ips.iter().any(|net| net.contains(Ipv4Addr::from(addr)))
+    }
+
+    fn is_blocked_ip6(&self, addr: &[u8; 16]) -> bool {
+        self.0.blocked_ips6.iter().any(|net| net.contains(Ipv6Addr::from(*addr)))
+    }
+
+    fn is_blocked_mask(&self, addr: u32) -> bool {
+        self.0
+            .blocked_masks
+            .iter()
+            .any(|rule| addr & u32::from(rule.mask) == u32::from(rule.addr))
+    }
+
+    fn is_blocked_country(&self, country: u16) -> bool {
+        self.0
+            .blocked_countries
+            .iter()
+            .any(|code| pack_country(code.as_bytes()) == country)
+    }
+
+    fn country_window_open(&self) -> bool {
+        true
+    }
+
+    fn is_blocked_region(&self, _addr: u32) -> bool {
+        false
+    }
+
+    fn is_allowed_ip(&self, addr: u32) -> bool {
+        self.0.allowed_ips.contains(&Ipv4Addr::from(addr))
+    }
+
+    fn is_blocked_tcp_window(&self, window: u16) -> bool {
+        self.0.blocked_tcp_windows.contains(&window)
+    }
+
+    fn filters_tcp_flags(&self) -> bool {
+        !self.0.allow_invalid_tcp_flags
+    }
+
+    fn is_allowed_port(&self, port: u16, proto: u8) -> bool {
+        let bit = port_protos::bit(proto);
+        self.0
+            .allowed_ports
+            .iter()
+            .any(|allowed| allowed.ports().contains(&port) && protos(allowed) & bit != 0)
+    }
+
+    fn is_blocked_src_port(&self, port: u16, proto: u8) -> bool {
+        let bit = port_protos::bit(proto);
+        self.0
+            .blocked_src_ports
+            .iter()
+            .any(|blocked| blocked.ports().contains(&port) && protos(blocked) & bit != 0)
+    }
+
+    fn port_match_src(&self) -> bool {
+        self.0.port_match == PortMatch::Src
+    }
+
+    fn is_icmp_echo_allowed(&self) -> bool {
+        self.0.allow_icmp_echo
+    }
+
+    fn default_allow(&self) -> bool {
+        self.0.policy == DefaultPolicy::Allow
+    }
+
+    fn strict_protocols(&self) -> bool {
+        self.0.strict_protocols_in_effect()
+    }
+
+    fn protocol_rule(&self, proto: u8) -> u8 {
+        match self.0.protocols.iter().find(|rule| rule.proto == proto) {
+            Some(rule) if rule.allow => protocol_rule::ALLOW,
+            Some(_) => protocol_rule::DENY,
+            None => protocol_rule::UNLISTED,
+        }
+    }
+
+    fn drops_fragments(&self) -> bool {
+        self.0.drop_fragments
+    }
+
+    // В записи только входящие кадры, исходящих SYN для таблицы соединений нет.
+    fn is_established(&self, _packet: &Packet) -> bool {
+        false
+    }
+
+    fn allows_dns(&self) -> bool {
+        self.0.allows_dns()
+    }
+
+    fn is_blocked_endpoint(&self, packet: &Packet) -> bool {
+        let addr = match self.0.endpoint_match {
+            EndpointMatch::Src => packet.src_addr,
+            EndpointMatch::Dst => packet.dst_addr,
+        };
+        self.0.blocked_endpoints.iter().any(|endpoint| {
+            endpoint.port == packet.dst_port && endpoint.network.contains(Ipv4Addr::from(addr))
+        })
+    }
+
+    fn knock_port(&self) -> u16 {
+        self.0.knock_port.unwrap_or(0)
+    }
+
+    // Стук зависит от порядка и темпа пакетов, а не от одного пакета: knock-port закрыт.
+    fn knock(&self, _packet: &Packet) -> bool {
+        false
+    }
+
+    fn filters_direction(&self, direction: Direction) -> bool {
+        direction::filters(self.0.direction.setting(), direction)
+    }
+
+    // Соединений нет, как и для is_established.
+    fn in_grace(&self, _packet: &Packet, _allowed: bool) -> bool {
+        false
+    }
+}
+
+/// Решение для одного пакета в виде, который сравнивается с эталоном.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub decision: String,
+    pub reason: Option<String>,
+}
+
+impl Decision {
+    fn pass() -> Self {
+        Self {
+            decision: "pass".to_string(),
+            reason: None,
+        }
+    }
+}
+
+impl std::fmt::Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.reason {
+            Some(reason) => write!(f, "{} ({reason})", self.decision),
+            None => write!(f, "{}", self.decision),
+        }
+    }
+}
+
+/// Решает судьбу кадра так же, как программа XDP в режиме применения правил.
+pub fn decide(config: &Config, countries: &CountryTable, frame: &[u8]) -> Decision {
+    match frame_verdict(config, countries, frame) {
+        Some(Verdict::Pass) => Decision::pass(),
+        Some(Verdict::Drop(reason)) => Decision {
+            decision: "drop".to_string(),
+            reason: Some(reason.as_str().to_string()),
+        },
+        None => Decision {
+            decision: "aborted".to_string(),
+            reason: None,
+        },
+    }
+}
+
+/// Решение для кадра без перевода в [`Decision`]; `None` — кадр не разобрался, программа
+/// вернула бы XDP_ABORTED.
+pub fn frame_verdict(config: &Config, countries: &CountryTable, frame: &[u8]) -> Option<Verdict> {
+    // MAC-адрес программа проверяет до разбора IP.
+    if classify::src_mac(frame).is_some_and(|mac| config.blocked_macs.iter().any(|m| m.0 == mac)) {
+        return Some(Verdict::Drop(DropReason::BlockedMac));
+    }
+    let parsed = classify::parse_frame(frame, config.unwrap_ipip);
+    // ARP решается до пропуска широковещательных кадров: запросы ARP широковещательные.
+    if let Some(Frame::Arp(arp)) = parsed {
+        let sender = Ipv4Addr::from(arp.sender_addr);
+        let allowed = config.arp_subnet.is_none_or(|net| net.contains(sender));
+        return Some(if allowed { Verdict::Pass } else { Verdict::Drop(DropReason::Arp) });
+    }
+    let multicast = classify::dst_mac(frame).is_some_and(|mac| classify::is_multicast_mac(&mac));
+    if multicast && !config.filter_multicast {
+        return Some(Verdict::Pass);
+    }
+    let mut packet = match parsed? {
+        Frame::Ipv4(packet) => packet,
+        // Защищаемые адреса — IPv4, так что с ними IPv6 не проверяется.
+        Frame::Ipv6(_) if !config.protected_ips.is_empty() => return Some(Verdict::Pass),
+        Frame::Ipv6(packet) => return Some(classify::decide_ipv6(&packet, &ConfigRules(config))),
+        Frame::NotIp | Frame::Arp(_) => return Some(Verdict::Pass),
+    };
+    packet.country = countries.country(packet.src_addr);
+    let src = Ipv4Addr::from(packet.src_addr);
+    if config.fast_accept_prefixes.iter().any(|net| net.contains(src)) {
+        return Some(Verdict::Pass);
+    }
+    let dst = Ipv4Addr::from(packet.dst_addr);
+    if !config.protected_ips.is_empty() && !config.protected_ips.iter().any(|n| n.contains(dst)) {
+        return Some(Verdict::Pass);
+    }
+    Some(classify::decide(&packet, &ConfigRules(config)))
+}
+
+/// Таблица стран из `country-db`; без базы — только запасное правило по первому октету.
+pub fn country_table(config: &Config) -> anyhow::Result<CountryTable> {
+    Ok(match &config.country_db {
+        Some(path) => CountryTable::new(
+            &geoip::load(path)
+                .with_context(|| format!("не удалось прочитать базу стран {}", path.display()))?,
+        ),
+        None => CountryTable::default(),
+    })
+}
+
+/// Кадры из файла pcap. Поддерживается классический формат с заголовками Ethernet в любом
+/// порядке байт, с микро- или наносекундными метками; pcapng нужно сначала перевести
+/// (`editcap -F pcap`).
+pub fn read_pcap(data: &[u8]) -> anyhow::Result<Vec<&[u8]>> {
+    let header = data.get(..24).context("файл короче заголовка pcap")?;
+    let magic = u32::from_le_bytes(header[..4].try_into()?);
+    let read_u32: fn([u8; 4]) -> u32 = match magic {
+        0xa1b2_c3d4 | 0xa1b2_3c4d => u32::from_le_bytes,
+        0xd4c3_b2a1 | 0x4d3c_b2a1 => u32::from_be_bytes,
+        0x0a0d_0d0a => {
+            anyhow::bail!("формат pcapng не поддерживается, переведите запись: editcap -F pcap")
+        }
+        _ => anyhow::bail!("это не файл pcap (сигнатура {magic:#010x})"),
+    };
+    let field = |bytes: &[u8], offset: usize| -> anyhow::Result<u32> {
+        let raw = bytes.get(offset..offset + 4).context("заголовок записи pcap обрезан")?;
+        Ok(read_u32(raw.try_into()?))
+    };
+    let linktype = field(header, 20)?;
+    if linktype != LINKTYPE_ETHERNET {
+        anyhow::bail!("тип канала {linktype} не Ethernet");
+    }
+
+    let mut frames = Vec::new();
+    let mut offset = 24;
+    while offset < data.len() {
+        let record = &data[offset..];
+        let len = field(record, 8)? as usize;
+        let frame = record
+            .get(16..16 + len)
+            .with_context(|| format!("пакет {} обрезан", frames.len()))?;
+        frames.push(frame);
+        offset += 16 + len;
+    }
+    Ok(frames)
+}
+
+/// Разбирает эталон: номер пакета и ожидаемое решение.
+pub fn parse_golden(text: &str) -> anyhow::Result<BTreeMap<usize, Decision>> {
+    let value: serde_json::Value = serde_json::from_str(text).context("некорректный JSON")?;
+    let object = value.as_object().context("ожидается объект с номерами пакетов в ключах")?;
+    let mut golden = BTreeMap::new();
+    for (key, entry) in object {
+        let index = key
+            .parse()
+            .with_context(|| format!("'{key}' не является номером пакета"))?;
+        let decision = entry
+            .get("decision")
+            .and_then(|d| d.as_str())
+            .with_context(|| format!("пакет {key}: нет поля \"decision\""))?;
+        let reason = entry.get("reason").and_then(|r| r.as_str());
+        golden.insert(
+            index,
+            Decision {
+                decision: decision.to_string(),
+                reason: reason.map(str::to_string),
+            },
+        );
+    }
+    Ok(golden)
+}
+
+/// Расхождения решений с эталоном, по одной строке на пакет.
+pub fn compare(actual: &[Decision], golden: &BTreeMap<usize, Decision>) -> Vec<String> {
+    let mut diff = Vec::new();
+    for (index, decision) in actual.iter().enumerate() {
+        match golden.get(&index) {
+            Some(expected) if expected == decision => {}
+            Some(expected) => {
+                diff.push(format!("#{index}: ожидалось {expected}, получено {decision}"))
+            }
+            None => diff.push(format!("#{index}: нет в эталоне, получено {decision}")),
+        }
+    }
+    for (index, expected) in golden.range(actual.len()..) {
+        diff.push(format!("#{index}: ожидалось {expected}, но в записи нет такого пакета"));
+    }
+    diff
+}
+
+fn replay(config: &Config, pcap: &Path, expect: &Path) -> anyhow::Result<(usize, Vec<String>)> {
+    let data = fs::read(pcap).with_context(|| format!("не удалось прочитать {}", pcap.display()))?;
+    let frames = read_pcap(&data).with_context(|| format!("{}", pcap.display()))?;
+    let golden = fs::read_to_string(expect)
+        .with_context(|| format!("не удалось прочитать {}", expect.display()))?;
+    let golden = parse_golden(&golden).with_context(|| format!("{}", expect.display()))?;
+    let countries = country_table(config)?;
+    let actual: Vec<Decision> =
+        frames.iter().map(|frame| decide(config, &countries, frame)).collect();
+    Ok((actual.len(), compare(&actual, &golden)))
+}
+
+/// Выполняет `firewall-cli test`; код выхода 1, если хоть одно решение не совпало.
+pub fn run(config: &Config, pcap: &Path, expect: &Path) -> i32 {
+    match replay(config, pcap, expect) {
+        Ok((count, diff)) if diff.is_empty() => {
+            println!("Все {count} пакетов совпали с эталоном.");
+            0
+        }
+        Ok((count, diff)) => {
+            println!("Решения изменились для {} из {count} пакетов:", diff.len());
+            for line in &diff {
+                println!("  {line}");
+            }
+            1
+        }
+        Err(e) => {
+            println!("Ошибка: {e:#}");
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selftest;
+
+    /// Запись pcap (little-endian, микросекунды) с кадрами `frames`.
+    fn pcap(frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for word in [0xa1b2_c3d4u32, 0x0004_0002, 0, 0, 65535, LINKTYPE_ETHERNET] {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        for frame in frames {
+            let len = frame.len() as u32;
+            for word in [0, 0, len, len] {
+                data.extend_from_slice(&word.to_le_bytes());
+            }
+            data.extend_from_slice(frame);
+        }
+        data
+    }
+
+    #[test]
+    fn golden_file_matches_and_mismatch_is_reported() {
+        let pairs = [("allowed-ports".to_string(), "22".to_string())];
+        let config = Config::parse_values(&pairs).unwrap();
+        let (src, dst) = (Ipv4Addr::new(198, 51, 100, 7), Ipv4Addr::new(192, 0, 2, 1));
+        let frames = [selftest::frame(src, dst, 22, false), selftest::frame(src, dst, 443, false)];
+
+        let dir = std::env::temp_dir().join(format!("firewall-replay-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let capture = dir.join("traffic.pcap");
+        fs::write(&capture, pcap(&frames)).unwrap();
+        let golden = |name: &str, text: &str| {
+            let path = dir.join(name);
+            fs::write(&path, text).unwrap();
+            path
+        };
+
+        let pass = r#"{"decision": "pass"}"#;
+        let drop = r#"{"decision": "drop", "reason": "port-not-allowed"}"#;
+        let matching = golden("matching.json", &format!(r#"{{"0": {pass}, "1": {drop}}}"#));
+        assert_eq!(replay(&config, &capture, &matching).unwrap(), (2, Vec::new()));
+
+        let changed = golden("changed.json", &format!(r#"{{"0": {pass}, "1": {pass}}}"#));
+        let (count, diff) = replay(&config, &capture, &changed).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(diff, ["#1: ожидалось pass, получено drop (port-not-allowed)"]);
+        assert_eq!(run(&config, &capture, &changed), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
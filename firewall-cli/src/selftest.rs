@@ -0,0 +1,336 @@
+//! `firewall-cli self-test`: пробные пакеты через программу XDP запущенного файрволла.
+//!
+//! Кадры собираются здесь же и отдаются программе через `BPF_PROG_TEST_RUN`
+//! ([`probe::test_run`]): ядро выполняет её над кадром в памяти, так что настоящий трафик
+//! не нужен, а кадр с XDP_TX никуда не уходит. Ожидаемое действие — решение
+//! [`replay::frame_verdict`] для того же кадра с `block-action` из карты `SETTINGS`;
+//! совпадение значит, что в картах программы те же правила, что в конфигурации.
+//!
+//! Для программы пробные пакеты ничем не отличаются от настоящих: они попадают в
+//! счётчики, события, срабатывания blocked-ips и вёдра `rate-limit`. Источники, где можно,
+//! берутся из документационных сетей RFC 5737.
+
+use std::{net::Ipv4Addr, os::fd::AsFd as _, path::Path};
+
+use anyhow::Context as _;
+use aya::{
+    maps::{Array, Map, MapData},
+    programs::{loaded_programs, ProgramFd},
+};
+use firewall_common::{
+    block_action,
+    classify::Verdict,
+    geoip::{self, CountryNetwork, CountryTable},
+    mode, pack_country, probe, settings, PIN_PATH, SETTINGS_MAP,
+};
+
+use crate::{
+    config::{Config, PortProto},
+    replay,
+};
+
+const XDP_ABORTED: u32 = 0;
+const XDP_DROP: u32 = 1;
+const XDP_PASS: u32 = 2;
+const XDP_TX: u32 = 3;
+
+/// Имя функции программы в `firewall-ebpf`.
+const PROGRAM_NAME: &str = "xdp_firewall";
+
+/// Источники для пакета на разрешённый порт: первый, который правила пропускают.
+const PASS_SOURCES: [Ipv4Addr; 2] = [Ipv4Addr::new(198, 51, 100, 7), Ipv4Addr::new(203, 0, 113, 7)];
+
+/// Назначение, если `protected-ips` не задан.
+const DEFAULT_DST: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+/// Порт назначения для пакетов, которые должны отбрасываться по источнику.
+const DEFAULT_PORT: u16 = 80;
+
+const SRC_PORT: u16 = 40000;
+
+/// Пробный пакет: что проверяется и кадр для программы.
+struct Case {
+    name: String,
+    frame: Vec<u8>,
+}
+
+/// Программа XDP запущенного файрволла — та, что пользуется закреплённой картой
+/// `SETTINGS`, — и действие для отбрасываемых пакетов из этой карты. В пробном и
+/// аварийном режимах, в паузе и при только подсчёте сверять не с чем: это ошибка.
+fn live_program() -> anyhow::Result<Option<(ProgramFd, u32)>> {
+    let path = Path::new(PIN_PATH).join(SETTINGS_MAP);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = MapData::from_pin(&path).with_context(|| format!("не удалось открыть {path:?}"))?;
+    let settings_id = data.info()?.id();
+    let settings: Array<_, u32> = Array::try_from(Map::Array(data))?;
+    if settings.get(&settings::MODE, 0)? != mode::ENFORCE || settings.get(&settings::PANIC, 0)? != 0
+    {
+        anyhow::bail!("программа не в режиме фильтрации: проверка имеет смысл только в нём");
+    }
+    let action = match settings.get(&settings::BLOCK_ACTION, 0)? {
+        block_action::ABORT => XDP_ABORTED,
+        block_action::TX => XDP_TX,
+        _ => XDP_DROP,
+    };
+    for info in loaded_programs() {
+        let info = info?;
+        if info.name_as_str() != Some(PROGRAM_NAME) {
+            continue;
+        }
+        if info.map_ids()?.is_some_and(|ids| ids.contains(&settings_id)) {
+            return Ok(Some((info.fd()?, action)));
+        }
+    }
+    anyhow::bail!("карты на месте, но программа {PROGRAM_NAME} не загружена (режим userspace?)")
+}
+
+fn action_name(action: u32) -> String {
+    match action {
+        XDP_ABORTED => "XDP_ABORTED".to_string(),
+        XDP_DROP => "XDP_DROP".to_string(),
+        XDP_PASS => "XDP_PASS".to_string(),
+        XDP_TX => "XDP_TX".to_string(),
+        _ => format!("код {action}"),
+    }
+}
+
+fn verdict_name(verdict: Option<Verdict>) -> String {
+    match verdict {
+        Some(Verdict::Pass) => "pass".to_string(),
+        Some(Verdict::Drop(reason)) => format!("drop ({})", reason.as_str()),
+        None => "aborted".to_string(),
+    }
+}
+
+/// Контрольная сумма заголовка IPv4.
+fn checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Кадр Ethernet с IPv4 и SYN TCP (или пустой датаграммой UDP) на порт `port`.
+pub fn frame(src: Ipv4Addr, dst: Ipv4Addr, port: u16, udp: bool) -> Vec<u8> {
+    let l4_len: u16 = if udp { 8 } else { 20 };
+    let mut frame = Vec::with_capacity(34 + usize::from(l4_len));
+    // Локально администрируемые MAC-адреса: кадр не широковещательный.
+    frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 0x02, 0x02, 0, 0, 0, 0, 0x01, 0x08, 0x00]);
+    let mut ip = [0u8; 20];
+    ip[0] = 0x45;
+    ip[2..4].copy_from_slice(&(20 + l4_len).to_be_bytes());
+    ip[8] = 64;
+    ip[9] = if udp { 17 } else { 6 };
+    ip[12..16].copy_from_slice(&src.octets());
+    ip[16..20].copy_from_slice(&dst.octets());
+    let sum = checksum(&ip);
+    ip[10..12].copy_from_slice(&sum.to_be_bytes());
+    frame.extend_from_slice(&ip);
+    frame.extend_from_slice(&SRC_PORT.to_be_bytes());
+    frame.extend_from_slice(&port.to_be_bytes());
+    if udp {
+        frame.extend_from_slice(&l4_len.to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+    } else {
+        // Номер 1, без подтверждения, длина заголовка 5 слов, SYN, окно 64240.
+        frame.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0x50, 0x02, 0xfa, 0xf0, 0, 0, 0, 0]);
+    }
+    frame
+}
+
+/// Адрес из страны `country`: из базы `country-db`, иначе по первому октету.
+fn country_addr(
+    networks: &[CountryNetwork],
+    countries: &CountryTable,
+    country: u16,
+) -> Option<u32> {
+    let from_db = networks.iter().map(|network| network.addr);
+    let by_octet = (1..=223u32).map(|octet| (octet << 24) | 7);
+    from_db
+        .chain(by_octet)
+        .find(|&addr| countries.country(addr) == country)
+}
+
+/// Пробные пакеты для правил конфигурации; правило, которого нет, — строка с причиной.
+fn cases(
+    config: &Config,
+    networks: &[CountryNetwork],
+    countries: &CountryTable,
+) -> (Vec<Case>, Vec<String>) {
+    let mut cases = Vec::new();
+    let mut skipped = Vec::new();
+    let dst = config.protected_ips.first().map_or(DEFAULT_DST, |net| net.ip());
+    let allowed = config.allowed_ports.first();
+    let (port, udp) = allowed.map_or((DEFAULT_PORT, false), |p| {
+        (p.port, p.proto == Some(PortProto::Udp))
+    });
+
+    if allowed.is_none() {
+        skipped.push("разрешённый порт: allowed-ports пуст".to_string());
+    } else {
+        let pass = PASS_SOURCES.iter().find(|&&src| {
+            replay::frame_verdict(config, countries, &frame(src, dst, port, udp))
+                == Some(Verdict::Pass)
+        });
+        match pass {
+            Some(&src) => cases.push(Case {
+                name: format!("разрешённый порт {port} от {src}"),
+                frame: frame(src, dst, port, udp),
+            }),
+            None => skipped.push(format!(
+                "разрешённый порт: правила отбрасывают пакеты на {port} от {}",
+                PASS_SOURCES.map(|src| src.to_string()).join(", ")
+            )),
+        }
+    }
+
+    match config.blocked_ips.first() {
+        Some(net) => cases.push(Case {
+            name: format!("blocked-ips {net} (источник {})", net.ip()),
+            frame: frame(net.ip(), dst, port, udp),
+        }),
+        None => skipped.push("blocked-ips: список пуст".to_string()),
+    }
+
+    match config.blocked_countries.first() {
+        Some(code) => match country_addr(networks, countries, pack_country(code.as_bytes())) {
+            Some(addr) => {
+                let src = Ipv4Addr::from(addr);
+                cases.push(Case {
+                    name: format!("blocked-countries {code} (источник {src})"),
+                    frame: frame(src, dst, port, udp),
+                });
+            }
+            None => skipped.push(format!(
+                "blocked-countries {code}: ни в базе стран, ни по первому октету адресов нет"
+            )),
+        },
+        None => skipped.push("blocked-countries: список пуст".to_string()),
+    }
+    (cases, skipped)
+}
+
+/// Выполняет `firewall-cli self-test`: 0 — программа вернула ожидаемое действие для
+/// каждого пакета, 1 — нет, файрволл не запущен или проверять нечего.
+pub fn run(config: &Config) -> i32 {
+    let (program, block) = match live_program() {
+        Ok(Some(live)) => live,
+        Ok(None) => {
+            println!("Файрволл не запущен.");
+            return 1;
+        }
+        Err(e) => {
+            println!("Ошибка: {e:#}");
+            return 1;
+        }
+    };
+    let networks = match &config.country_db {
+        Some(path) => match geoip::load(path) {
+            Ok(networks) => networks,
+            Err(e) => {
+                println!("Не удалось прочитать базу стран {}: {e}", path.display());
+                return 1;
+            }
+        },
+        None => Vec::new(),
+    };
+    let countries = CountryTable::new(&networks);
+    let (cases, skipped) = self::cases(config, &networks, &countries);
+
+    let mut failed = 0;
+    for case in &cases {
+        let verdict = replay::frame_verdict(config, &countries, &case.frame);
+        let expected = match verdict {
+            Some(Verdict::Pass) => XDP_PASS,
+            Some(Verdict::Drop(_)) => block,
+            None => XDP_ABORTED,
+        };
+        match probe::test_run(program.as_fd(), &case.frame) {
+            Ok(action) if action == expected => {
+                println!("  ok      {}: {}", case.name, verdict_name(verdict));
+            }
+            Ok(action) => {
+                failed += 1;
+                println!(
+                    "  ОШИБКА  {}: ожидалось {} ({}), программа вернула {}",
+                    case.name,
+                    verdict_name(verdict),
+                    action_name(expected),
+                    action_name(action)
+                );
+            }
+            Err(e) => {
+                failed += 1;
+                println!("  ОШИБКА  {}: не удалось прогнать пакет: {e}", case.name);
+            }
+        }
+    }
+    for reason in &skipped {
+        println!("  пропуск {reason}");
+    }
+
+    if cases.is_empty() {
+        println!("Проверять нечего: задайте allowed-ports, blocked-ips или blocked-countries.");
+        1
+    } else if failed > 0 {
+        println!("Не прошли {failed} из {} проверок.", cases.len());
+        1
+    } else {
+        println!("Все {} проверок прошли.", cases.len());
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use firewall_common::DropReason;
+
+    use super::*;
+
+    #[test]
+    fn cases_cover_each_rule_with_its_expected_verdict() {
+        let content = "\"allowed-ports\"\n443\n\"blocked-ips\"\n198.51.100.0/24\n\
+                       \"blocked-countries\"\nCN\n";
+        let config = Config::parse(content).unwrap();
+        let networks = [CountryNetwork {
+            addr: u32::from(Ipv4Addr::new(1, 2, 3, 0)),
+            prefix: 24,
+            country: pack_country(b"CN"),
+        }];
+        let countries = CountryTable::new(&networks);
+
+        let (cases, skipped) = self::cases(&config, &networks, &countries);
+        assert!(skipped.is_empty(), "{skipped:?}");
+        let verdicts: Vec<_> = cases
+            .iter()
+            .map(|case| replay::frame_verdict(&config, &countries, &case.frame))
+            .collect();
+        assert_eq!(
+            verdicts,
+            [
+                Some(Verdict::Pass),
+                Some(Verdict::Drop(DropReason::BlockedIp)),
+                Some(Verdict::Drop(DropReason::BlockedCountry)),
+            ]
+        );
+        // Первый источник из PASS_SOURCES заблокирован, поэтому берётся второй.
+        assert_eq!(cases[0].name, "разрешённый порт 443 от 203.0.113.7");
+        assert_eq!(cases[2].name, "blocked-countries CN (источник 1.2.3.0)");
+    }
+
+    #[test]
+    fn missing_rules_are_skipped_with_reason() {
+        let config = Config::parse("\"allowed-ports\"\n\n").unwrap();
+        let countries = CountryTable::new(&[]);
+        let (cases, skipped) = self::cases(&config, &[], &countries);
+        assert!(cases.is_empty());
+        assert_eq!(skipped.len(), 3, "{skipped:?}");
+        assert!(skipped[0].contains("allowed-ports пуст"));
+    }
+}
@@ -0,0 +1,563 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+use aya::{
+    maps::{HashMap as BpfHashMap, Map, MapData, PerCpuArray, PerCpuHashMap},
+    util::{nr_cpus, online_cpus},
+    Pod,
+};
+use firewall_common::{
+    stats, unpack_country, ConnKey, DropReason, PacketStats, CONNTRACK_MAP, COUNTRY_DROPS_MAP,
+    COUNTRY_STATS_MAP, DROP_REASONS_MAP, PIN_PATH, PORT_STATS_MAP, SOURCE_STATS_MAP, STATS_MAP,
+};
+
+use crate::{
+    rdns::Resolver,
+    totals::{self, Totals},
+};
+
+/// Сколько строк показывать в таблицах портов, стран и источников.
+const TOP_N: usize = 5;
+
+/// Снимок счётчиков файрволла, просуммированный по всем CPU.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub pass: u64,
+    pub drop: u64,
+    pub aborted: u64,
+    /// Перенаправленные в сокеты AF_XDP.
+    pub redirect: u64,
+    /// Отброшенные пределом `syn-rate-limit`, часть `drop`.
+    pub syn_flood: u64,
+    /// Пропущенные пробным режимом (`dry-run`) пакеты, которые иначе были бы отброшены;
+    /// часть `pass`.
+    pub dry_run: u64,
+    /// Пакеты и байты QUIC (UDP 443), с любым решением.
+    pub quic_packets: u64,
+    pub quic_bytes: u64,
+    /// Отброшенные пакеты по причинам, по убыванию; причины без пакетов не входят.
+    pub drop_reasons: Vec<(DropReason, u64)>,
+    /// Самые нагруженные порты назначения по числу пакетов.
+    pub top_ports: Vec<(u16, u64)>,
+    /// Страны источника с наибольшим числом пакетов.
+    pub top_countries: Vec<(String, u64)>,
+    /// Страны источника с наибольшим числом отброшенных пакетов и сколько всего пакетов
+    /// учтено по странам.
+    pub top_country_drops: Vec<(String, u64)>,
+    pub country_drops: u64,
+    /// Адреса источника с наибольшим числом пакетов: адрес, пакеты, байты.
+    pub top_sources: Vec<(Ipv4Addr, u64, u64)>,
+    /// По каким CPU сведены счётчики: все они хранятся в per-CPU картах.
+    pub cpus: Cpus,
+    /// Записи, не попавшие в переполненные карты счётчиков.
+    pub map_full: u64,
+    /// Заполненность карт счётчиков.
+    pub fill: Vec<MapFill>,
+}
+
+/// Сколько записей занято в карте из допустимого максимума.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapFill {
+    pub name: &'static str,
+    pub used: usize,
+    pub capacity: u32,
+}
+
+/// CPU, по которым просуммированы per-CPU счётчики.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Cpus {
+    /// Возможные CPU: ядро держит ячейку per-CPU карты для каждого, включая отключённые.
+    pub possible: usize,
+    /// Включённые сейчас; меньше `possible`, если часть CPU отключена.
+    pub online: usize,
+}
+
+impl Cpus {
+    fn detect() -> anyhow::Result<Self> {
+        let possible = nr_cpus().map_err(|(path, e)| anyhow::anyhow!("{path}: {e}"))?;
+        let online = online_cpus().map_or(possible, |cpus| cpus.len());
+        Ok(Self { possible, online })
+    }
+}
+
+/// Суммирует значения per-CPU карты по всем возможным CPU.
+///
+/// Ячейка отключённого CPU сохраняет накопленное и тоже входит в сумму: сумма только по
+/// включённым CPU занижала бы счётчики после горячего отключения.
+fn sum_cpus(values: impl IntoIterator<Item = u64>) -> u64 {
+    values.into_iter().fold(0, u64::wrapping_add)
+}
+
+/// [`sum_cpus`] для пакетов и байт одной записи.
+fn sum_stats(per_cpu: &[PacketStats]) -> PacketStats {
+    PacketStats {
+        packets: sum_cpus(per_cpu.iter().map(|v| v.packets)),
+        bytes: sum_cpus(per_cpu.iter().map(|v| v.bytes)),
+    }
+}
+
+impl Stats {
+    pub fn total(&self) -> u64 {
+        self.pass + self.drop + self.aborted + self.redirect
+    }
+}
+
+fn pin(name: &str) -> PathBuf {
+    Path::new(PIN_PATH).join(name)
+}
+
+/// Читает закреплённые карты статистики и прибавляет к ним итоги прошлых запусков из
+/// [`totals`].
+///
+/// Возвращает `Ok(None)`, если карта `STATS` не закреплена, то есть файрволл не запущен.
+pub fn fetch_stats() -> anyhow::Result<Option<Stats>> {
+    let Some((current, fill)) = read_maps()? else {
+        return Ok(None);
+    };
+    let mut all = totals::load();
+    all.add(&current);
+    Ok(Some(summarize(&all, fill)?))
+}
+
+/// Как [`fetch_stats`], но только счётчики текущего запуска загрузчика: их можно сравнивать
+/// со счётчиками драйвера.
+pub fn fetch_current_stats() -> anyhow::Result<Option<Stats>> {
+    match read_maps()? {
+        Some((current, fill)) => Ok(Some(summarize(&current, fill)?)),
+        None => Ok(None),
+    }
+}
+
+/// Счётчики за всё время целиком, для сохранения в [`totals`]; `Ok(None)`, если файрволл
+/// не запущен.
+pub fn fetch_totals() -> anyhow::Result<Option<Totals>> {
+    let Some((current, _)) = read_maps()? else {
+        return Ok(None);
+    };
+    let mut all = totals::load();
+    all.add(&current);
+    Ok(Some(all))
+}
+
+/// Все адреса источника за всё время, по убыванию числа пакетов.
+pub fn fetch_sources() -> anyhow::Result<Vec<(Ipv4Addr, PacketStats)>> {
+    let sources = fetch_totals()?.map(|all| all.sources).unwrap_or_default();
+    Ok(sorted(sources).into_iter().map(|(addr, totals)| (Ipv4Addr::from(addr), totals)).collect())
+}
+
+/// Счётчики текущего запуска из карт и заполненность карт.
+fn read_maps() -> anyhow::Result<Option<(Totals, Vec<MapFill>)>> {
+    let path = pin(STATS_MAP);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let data = MapData::from_pin(&path).with_context(|| format!("не удалось открыть {path:?}"))?;
+    let map: PerCpuArray<_, u64> = PerCpuArray::try_from(Map::PerCpuArray(data))?;
+    let mut current = Totals::default();
+    for slot in 0..stats::LEN {
+        current.counters[slot as usize] = match map.get(&slot, 0) {
+            Ok(values) => sum_cpus(values.iter().copied()),
+            // У карты загрузчика прежней версии этой ячейки ещё нет.
+            Err(_) if slot > stats::REDIRECT => 0,
+            Err(e) => return Err(e.into()),
+        };
+    }
+    read_drop_reasons(&mut current.drop_reasons)?;
+    current.country_drops = read_country_drops()?;
+
+    let mut fill = Vec::new();
+    current.ports = read_all(PORT_STATS_MAP, Map::PerCpuHashMap, &mut fill)?;
+    current.countries = read_all(COUNTRY_STATS_MAP, Map::PerCpuHashMap, &mut fill)?;
+    current.sources = read_all(SOURCE_STATS_MAP, Map::PerCpuLruHashMap, &mut fill)?;
+    fill.extend(conntrack_fill()?);
+    Ok(Some((current, fill)))
+}
+
+/// Читает счётчики по причинам; у загрузчика прежней версии их карты нет, и они остаются
+/// нулевыми.
+fn read_drop_reasons(reasons: &mut [u64]) -> anyhow::Result<()> {
+    let path = pin(DROP_REASONS_MAP);
+    if !path.exists() {
+        return Ok(());
+    }
+    let data = MapData::from_pin(&path).with_context(|| format!("не удалось открыть {path:?}"))?;
+    let map: PerCpuArray<_, u64> = PerCpuArray::try_from(Map::PerCpuArray(data))?;
+    for (slot, value) in reasons.iter_mut().enumerate() {
+        *value = sum_cpus(map.get(&(slot as u32), 0)?.iter().copied());
+    }
+    Ok(())
+}
+
+/// Отброшенные пакеты по странам; у загрузчика прежней версии их карты нет.
+fn read_country_drops() -> anyhow::Result<HashMap<u16, u64>> {
+    let path = pin(COUNTRY_DROPS_MAP);
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let data = MapData::from_pin(&path).with_context(|| format!("не удалось открыть {path:?}"))?;
+    let map: PerCpuHashMap<_, u16, u64> = PerCpuHashMap::try_from(Map::PerCpuHashMap(data))?;
+    let mut drops = HashMap::new();
+    for entry in map.iter() {
+        let (country, values) = entry?;
+        drops.insert(country, sum_cpus(values.iter().copied()));
+    }
+    Ok(drops)
+}
+
+/// Код страны для таблиц; `??` — страна неизвестна.
+fn country_code(key: u16) -> String {
+    match key {
+        0 => "??".to_string(),
+        key => String::from_utf8_lossy(&unpack_country(key)).into_owned(),
+    }
+}
+
+/// Размер таблицы соединений (`conntrack-timeout`), вместе с устаревшими записями, которые
+/// ещё не удалены; `None`, если её карта не закреплена.
+fn conntrack_fill() -> anyhow::Result<Option<MapFill>> {
+    let path = pin(CONNTRACK_MAP);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let data = MapData::from_pin(&path).with_context(|| format!("не удалось открыть {path:?}"))?;
+    let capacity = data.info()?.max_entries();
+    let map: BpfHashMap<_, ConnKey, u64> = BpfHashMap::try_from(Map::LruHashMap(data))?;
+    Ok(Some(MapFill {
+        name: CONNTRACK_MAP,
+        used: map.keys().count(),
+        capacity,
+    }))
+}
+
+/// Снимок для вывода: значения ячеек и `TOP_N` самых нагруженных ключей каждой таблицы.
+fn summarize(totals: &Totals, fill: Vec<MapFill>) -> anyhow::Result<Stats> {
+    let top_ports = top(&totals.ports).map(|(port, totals)| (port, totals.packets)).collect();
+    let top_countries = top(&totals.countries)
+        .map(|(key, PacketStats { packets, .. })| (country_code(key), packets))
+        .collect();
+    let mut country_drops: Vec<_> = totals.country_drops.iter().map(|(&k, &v)| (k, v)).collect();
+    country_drops.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let top_country_drops = country_drops
+        .iter()
+        .take(TOP_N)
+        .map(|&(key, count)| (country_code(key), count))
+        .collect();
+    let top_sources = top(&totals.sources)
+        .map(|(addr, totals)| (Ipv4Addr::from(addr), totals.packets, totals.bytes))
+        .collect();
+    let mut drop_reasons: Vec<_> = DropReason::ALL
+        .into_iter()
+        .map(|reason| (reason, totals.drop_reasons[reason as usize]))
+        .filter(|&(_, count)| count > 0)
+        .collect();
+    drop_reasons.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+
+    Ok(Stats {
+        pass: totals.counter(stats::PASS),
+        drop: totals.counter(stats::DROP),
+        aborted: totals.counter(stats::ABORTED),
+        redirect: totals.counter(stats::REDIRECT),
+        syn_flood: totals.counter(stats::SYN_FLOOD),
+        dry_run: totals.counter(stats::DRY_RUN),
+        quic_packets: totals.counter(stats::QUIC_PACKETS),
+        quic_bytes: totals.counter(stats::QUIC_BYTES),
+        drop_reasons,
+        top_ports,
+        top_countries,
+        top_country_drops,
+        country_drops: country_drops.iter().map(|&(_, count)| count).fold(0, u64::wrapping_add),
+        top_sources,
+        cpus: Cpus::detect()?,
+        map_full: totals.counter(stats::MAP_FULL),
+        fill,
+    })
+}
+
+/// `TOP_N` ключей с наибольшим числом пакетов.
+fn top<K: Copy + Ord>(
+    table: &HashMap<K, PacketStats>,
+) -> impl Iterator<Item = (K, PacketStats)> {
+    sorted(table.clone()).into_iter().take(TOP_N)
+}
+
+/// Записи по убыванию числа пакетов, при равенстве — по ключу.
+fn sorted<K: Ord>(table: HashMap<K, PacketStats>) -> Vec<(K, PacketStats)> {
+    let mut entries: Vec<_> = table.into_iter().collect();
+    entries.sort_by(|a, b| b.1.packets.cmp(&a.1.packets).then(a.0.cmp(&b.0)));
+    entries
+}
+
+/// Читает per-CPU карту счётчиков целиком, суммируя значения по CPU. Заполненность карты
+/// добавляется в `fill`.
+///
+/// `kind` — вариант [`Map`] для типа карты (обычная или LRU).
+fn read_all<K: Pod + Eq + Hash>(
+    name: &'static str,
+    kind: fn(MapData) -> Map,
+    fill: &mut Vec<MapFill>,
+) -> anyhow::Result<HashMap<K, PacketStats>> {
+    let path = pin(name);
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    let data = MapData::from_pin(&path).with_context(|| format!("не удалось открыть {path:?}"))?;
+    let capacity = data.info()?.max_entries();
+    let map: PerCpuHashMap<_, K, PacketStats> = PerCpuHashMap::try_from(kind(data))?;
+    let mut totals = HashMap::new();
+    for entry in map.iter() {
+        let (key, values) = entry?;
+        totals.insert(key, sum_stats(&values));
+    }
+    fill.push(MapFill {
+        name,
+        used: totals.len(),
+        capacity,
+    });
+    Ok(totals)
+}
+
+/// Счётчики одним документом JSON для `firewall-cli stats --json`.
+///
+/// Схема постоянна, новые поля только добавляются:
+///
+/// ```json
+/// {
+///   "pass": 10, "drop": 2, "aborted": 0, "redirect": 0, "total": 12,
+///   "syn_flood": 0, "dry_run": 0, "map_full": 0, "quic_packets": 0, "quic_bytes": 0,
+///   "drop_reasons": {"port-not-allowed": 2},
+///   "sources": [{"ip": "192.0.2.1", "packets": 7, "bytes": 420}]
+/// }
+/// ```
+///
+/// `sources` — все адреса из карты источников по убыванию числа пакетов, а не только
+/// первые, как в таблице. В `drop_reasons` только причины, по которым что-то отброшено.
+pub fn format_json(stats: &Stats, sources: &[(Ipv4Addr, PacketStats)]) -> String {
+    let sources: Vec<_> = sources
+        .iter()
+        .map(|(addr, totals)| {
+            serde_json::json!({
+                "ip": addr.to_string(),
+                "packets": totals.packets,
+                "bytes": totals.bytes,
+            })
+        })
+        .collect();
+    let drop_reasons: serde_json::Map<_, _> = stats
+        .drop_reasons
+        .iter()
+        .map(|&(reason, count)| (reason.as_str().to_string(), count.into()))
+        .collect();
+    serde_json::json!({
+        "pass": stats.pass,
+        "drop": stats.drop,
+        "aborted": stats.aborted,
+        "redirect": stats.redirect,
+        "total": stats.total(),
+        "syn_flood": stats.syn_flood,
+        "dry_run": stats.dry_run,
+        "map_full": stats.map_full,
+        "quic_packets": stats.quic_packets,
+        "quic_bytes": stats.quic_bytes,
+        "drop_reasons": drop_reasons,
+        "sources": sources,
+    })
+    .to_string()
+}
+
+/// Форматирует счётчики в таблицу для вывода в терминал.
+///
+/// С `names` у источников печатаются имена хостов, которые уже есть в его кэше.
+pub fn format_stats(stats: &Stats, names: Option<&Resolver>) -> String {
+    let total = stats.total();
+    let percent = |value: u64| {
+        if total == 0 {
+            0.0
+        } else {
+            value as f64 * 100.0 / total as f64
+        }
+    };
+
+    let mut out = format!(
+        "Статистика файрволла\n\n\
+         {:<12}{:>14}{:>9.1}%\n\
+         {:<12}{:>14}{:>9.1}%\n\
+         {:<12}{:>14}{:>9.1}%\n",
+        "Пропущено",
+        stats.pass,
+        percent(stats.pass),
+        "Отброшено",
+        stats.drop,
+        percent(stats.drop),
+        "Ошибки",
+        stats.aborted,
+        percent(stats.aborted),
+    );
+    if stats.redirect > 0 {
+        out.push_str(&format!(
+            "{:<12}{:>14}{:>9.1}%\n",
+            "В AF_XDP",
+            stats.redirect,
+            percent(stats.redirect)
+        ));
+    }
+    out.push_str(&format!("{:<12}{:>14}\n", "Всего", total));
+    if stats.syn_flood > 0 {
+        out.push_str(&format!(
+            "\nИз отброшенных — флуд SYN (syn-rate-limit): {}\n",
+            stats.syn_flood
+        ));
+    }
+    if stats.dry_run > 0 {
+        out.push_str(&format!(
+            "\nИз пропущенных отбросили бы без пробного режима (dry-run): {}\n",
+            stats.dry_run
+        ));
+    }
+    if stats.quic_packets > 0 {
+        out.push_str(&format!(
+            "\nQUIC (UDP 443): пакетов {}, байт {}\n",
+            stats.quic_packets, stats.quic_bytes
+        ));
+    }
+
+    if !stats.drop_reasons.is_empty() {
+        out.push_str("\nОтброшено по причинам:\n");
+        for (reason, count) in &stats.drop_reasons {
+            let share = percent(*count);
+            out.push_str(&format!("  {:<22}{:>14}{:>9.1}%\n", reason.as_str(), count, share));
+        }
+    }
+
+    let Cpus { possible, online } = stats.cpus;
+    out.push_str(&format!("\nСчётчики per-CPU, сумма по {possible} CPU"));
+    if online < possible {
+        out.push_str(&format!(" (включено {online})"));
+    }
+    out.push('\n');
+
+    if !stats.fill.is_empty() {
+        out.push_str("\nЗаполненность карт:\n");
+        for MapFill { name, used, capacity } in &stats.fill {
+            out.push_str(&format!("  {name:<14}{used:>10} из {capacity}\n"));
+        }
+    }
+    if stats.map_full > 0 {
+        out.push_str(&format!(
+            "Не записано в переполненные карты: {} (счётчики неполны)\n",
+            stats.map_full
+        ));
+    }
+
+    if !stats.top_ports.is_empty() {
+        out.push_str("\nПорты назначения:\n");
+        for (port, packets) in &stats.top_ports {
+            out.push_str(&format!("  {:<10}{:>14}\n", port, packets));
+        }
+    }
+    if !stats.top_countries.is_empty() {
+        out.push_str("\nСтраны источника:\n");
+        for (country, packets) in &stats.top_countries {
+            out.push_str(&format!("  {:<10}{:>14}\n", country, packets));
+        }
+    }
+    if !stats.top_country_drops.is_empty() {
+        out.push_str("\nОтброшено по странам источника:\n");
+        for (country, count) in &stats.top_country_drops {
+            let share = *count as f64 * 100.0 / stats.country_drops.max(1) as f64;
+            out.push_str(&format!("  {:<10}{:>14}{:>9.1}%\n", country, count, share));
+        }
+    }
+    if !stats.top_sources.is_empty() {
+        out.push_str(&format!("\n{:<18}{:>14}{:>16}\n", "Источники:", "пакетов", "байт"));
+        for (addr, packets, bytes) in &stats.top_sources {
+            out.push_str(&format!("  {:<16}{:>14}{:>16}", addr.to_string(), packets, bytes));
+            if let Some(name) = names.and_then(|names| names.name(*addr)) {
+                out.push_str(&format!("  {name}"));
+            }
+            out.push('\n');
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use firewall_common::pack_country;
+
+    use super::*;
+
+    fn packets(packets: u64) -> PacketStats {
+        PacketStats {
+            packets,
+            bytes: packets * 60,
+        }
+    }
+
+    #[test]
+    fn snapshot_is_summarized_and_formatted_for_the_view() {
+        let mut totals = Totals::default();
+        totals.counters[stats::PASS as usize] = 75;
+        totals.counters[stats::DROP as usize] = 25;
+        totals.drop_reasons[DropReason::PortNotAllowed as usize] = 20;
+        totals.drop_reasons[DropReason::BlockedIp as usize] = 5;
+        totals.ports = (1..=7).map(|port| (port, packets(u64::from(port)))).collect();
+        totals.countries = HashMap::from([(pack_country(b"RU"), packets(9))]);
+        totals.sources = HashMap::from([(0xc633_6407, packets(3))]);
+        let fill = vec![MapFill {
+            name: PORT_STATS_MAP,
+            used: 7,
+            capacity: 1024,
+        }];
+        let stats = summarize(&totals, fill).unwrap();
+
+        assert_eq!(stats.total(), 100);
+        assert_eq!(
+            stats.drop_reasons,
+            [(DropReason::PortNotAllowed, 20), (DropReason::BlockedIp, 5)]
+        );
+        assert_eq!(stats.top_ports, [(7, 7), (6, 6), (5, 5), (4, 4), (3, 3)]);
+        assert_eq!(stats.top_countries, [("RU".to_string(), 9)]);
+        assert_eq!(stats.top_sources, [(Ipv4Addr::new(198, 51, 100, 7), 3, 180)]);
+
+        let text = format_stats(&stats, None);
+        assert!(text.contains(&format!("{:<12}{:>14}{:>9.1}%\n", "Пропущено", 75, 75.0)));
+        assert!(text.contains(&format!("{:<12}{:>14}\n", "Всего", 100)));
+        assert!(text.contains("port-not-allowed"));
+        assert!(text.contains(&format!("  {:<14}{:>10} из 1024\n", PORT_STATS_MAP, 7)));
+        assert!(text.contains("198.51.100.7"));
+        assert!(!text.contains("В AF_XDP"));
+    }
+
+    #[test]
+    fn per_cpu_values_sum_over_every_possible_cpu() {
+        // Карта на четыре возможных CPU; третий отключён, но хранит накопленное до этого.
+        let map: [(u16, [PacketStats; 4]); 2] = [
+            (22, [packets(5), packets(0), packets(7), packets(1)]),
+            (443, [packets(0), packets(2), packets(0), packets(0)]),
+        ];
+        let totals: HashMap<u16, PacketStats> =
+            map.iter().map(|(port, per_cpu)| (*port, sum_stats(per_cpu))).collect();
+        assert_eq!(totals[&22].packets, 13);
+        assert_eq!(totals[&22].bytes, 13 * 60);
+        assert_eq!(totals[&443].packets, 2);
+        assert_eq!(sum_cpus([u64::MAX, 2]), 1);
+
+        let stats = Stats {
+            pass: 15,
+            cpus: Cpus { possible: 4, online: 3 },
+            ..Default::default()
+        };
+        let text = format_stats(&stats, None);
+        assert!(text.contains("сумма по 4 CPU (включено 3)"), "{text}");
+        let stats = Stats { cpus: Cpus { possible: 4, online: 4 }, ..stats };
+        assert!(!format_stats(&stats, None).contains("включено"));
+    }
+}
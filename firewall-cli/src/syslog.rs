@@ -0,0 +1,152 @@
+//! Передача событий об отброшенных пакетах в системный журнал (`syslog`).
+//!
+//! Пока работает загрузчик, отдельный поток читает события из его сокета и отправляет
+//! каждое датаграммой в [`SOCKET`] в формате RFC 3164 без времени: его ставит сама служба
+//! журнала (journald, rsyslog). Текст события — по `log-format`: строка, как в
+//! `firewall-cli events`, или JSON, как в `log-file`. Отброшенные пакеты идут с важностью
+//! warning, пакеты пробного режима — notice, события `allowed` — info. Отправка не
+//! блокируется: если служба не успевает или недоступна, событие теряется, чтение сокета
+//! загрузчика продолжается, а о сбое сообщается один раз до следующей удачной отправки.
+
+use std::{
+    io,
+    os::unix::net::UnixDatagram,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use firewall_common::{event_flags, DropEvent, DropReason};
+
+use crate::{
+    config::{LogFormat, SyslogFacility},
+    events,
+};
+
+/// Сокет локальной службы журнала.
+pub const SOCKET: &str = "/dev/log";
+
+/// Имя программы в записях журнала.
+const TAG: &str = "firewall";
+
+const WARNING: u8 = 4;
+const NOTICE: u8 = 5;
+const INFO: u8 = 6;
+
+/// Передача в журнал; останавливается, когда значение удаляется.
+pub struct Syslog {
+    stop: Arc<AtomicBool>,
+}
+
+impl Syslog {
+    /// Начинает отправлять события в журнал с источником `facility`.
+    pub fn start(facility: SyslogFacility, format: LogFormat) -> Syslog {
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || forward(&stop, Sender::new(facility, format)));
+        }
+        Syslog { stop }
+    }
+}
+
+impl Drop for Syslog {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Читает события из сокета загрузчика и отправляет их, пока передача не остановлена.
+/// Недавние события, как и в `log-file`, отправляются только при первом подключении.
+fn forward(stop: &AtomicBool, mut sender: Sender) {
+    let mut first = true;
+    while !stop.load(Ordering::SeqCst) {
+        let Ok((mut stream, backlog)) = events::connect() else {
+            thread::sleep(events::RECONNECT_DELAY);
+            continue;
+        };
+        let backlog = if first { backlog } else { Vec::new() };
+        first = false;
+        for event in &backlog {
+            sender.send(event);
+        }
+        while let Ok(event) = events::read_event(&mut stream) {
+            if stop.load(Ordering::SeqCst) {
+                return;
+            }
+            sender.send(&event);
+        }
+    }
+}
+
+/// Сокет службы журнала; после ошибки открывается заново при следующем событии, так что
+/// перезапуск службы не прекращает передачу.
+struct Sender {
+    facility: SyslogFacility,
+    format: LogFormat,
+    socket: Option<UnixDatagram>,
+    failing: bool,
+}
+
+impl Sender {
+    fn new(facility: SyslogFacility, format: LogFormat) -> Self {
+        Self {
+            facility,
+            format,
+            socket: None,
+            failing: false,
+        }
+    }
+
+    fn send(&mut self, event: &DropEvent) {
+        match self.try_send(event) {
+            Ok(()) => self.failing = false,
+            Err(e) => {
+                self.socket = None;
+                if !self.failing {
+                    println!("Не удалось записать событие в {SOCKET}: {e}");
+                    self.failing = true;
+                }
+            }
+        }
+    }
+
+    fn try_send(&mut self, event: &DropEvent) -> io::Result<()> {
+        let socket = match &self.socket {
+            Some(socket) => socket,
+            None => self.socket.insert(open()?),
+        };
+        socket.send(record(self.facility, self.format, event).as_bytes())?;
+        Ok(())
+    }
+}
+
+fn open() -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(SOCKET)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+fn severity(event: &DropEvent) -> u8 {
+    if event.reason == DropReason::Allowed as u8 {
+        INFO
+    } else if event.flags & event_flags::DRY_RUN != 0 {
+        NOTICE
+    } else {
+        WARNING
+    }
+}
+
+/// Запись журнала: `<PRI>firewall[pid]: ` и текст события.
+fn record(facility: SyslogFacility, format: LogFormat, event: &DropEvent) -> String {
+    let priority = facility.code() * 8 + severity(event);
+    let text = match format {
+        LogFormat::Text => events::format_plain(event),
+        LogFormat::Json => events::format_json_at(event, &events::now()),
+    };
+    format!("<{priority}>{TAG}[{}]: {text}", process::id())
+}
@@ -0,0 +1,225 @@
+//! Итоги прошлых запусков файрволла: с ними `stats` показывает счётчики за всё время.
+//!
+//! Карты программы начинаются с нуля при каждом запуске загрузчика. Когда firewall-cli
+//! останавливает файрволл (`run` или пункт меню, в том числе по Ctrl+C), он записывает
+//! накопленные итоги в [`FILE_NAME`] рядом с основным файлом конфигурации, а
+//! [`crate::stats::fetch_stats`] прибавляет их к текущим значениям карт. Нет файла или он
+//! испорчен — счёт начинается с нуля. Файрволл, запущенный без firewall-cli, итогов не
+//! сохраняет.
+//!
+//! Файл — JSON: счётчики по именам, `drop_reasons` по именам причин и таблицы `ports`,
+//! `countries` (ключ — `pack_country`) и `sources`, в которых у каждого ключа пара
+//! `[пакеты, байты]`, и `country_drops`: число отброшенных пакетов по `pack_country`.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::Ipv4Addr,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use firewall_common::{stats, DropReason, PacketStats};
+use serde_json::{json, Map, Value};
+
+use crate::{config, stats as fw_stats};
+
+/// Имя файла итогов в каталоге основного файла конфигурации.
+pub const FILE_NAME: &str = "stats-totals.json";
+
+/// Сколько источников сохраняется, самые нагруженные: таблица в `stats` показывает только
+/// первые из них, а файл должен оставаться небольшим.
+const SAVED_SOURCES: usize = 1024;
+
+/// Как часто перечитываются карты запущенного файрволла. Ctrl+C получает и загрузчик, и к
+/// остановке карт может уже не быть: тогда сохраняется последнее прочитанное.
+const READ_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Ячейки карты `STATS` и их имена в файле.
+const COUNTERS: [(&str, u32); stats::LEN as usize] = [
+    ("pass", stats::PASS),
+    ("drop", stats::DROP),
+    ("aborted", stats::ABORTED),
+    ("map_full", stats::MAP_FULL),
+    ("redirect", stats::REDIRECT),
+    ("syn_flood", stats::SYN_FLOOD),
+    ("dry_run", stats::DRY_RUN),
+    ("quic_packets", stats::QUIC_PACKETS),
+    ("quic_bytes", stats::QUIC_BYTES),
+];
+
+/// Счётчики целиком: ячейки `STATS` и все записи карт портов, стран и источников.
+#[derive(Debug, Default, Clone)]
+pub struct Totals {
+    /// Значения по номерам ячеек `STATS`.
+    pub counters: [u64; stats::LEN as usize],
+    /// Отброшенные пакеты по кодам [`DropReason`].
+    pub drop_reasons: [u64; DropReason::SLOTS as usize],
+    pub ports: HashMap<u16, PacketStats>,
+    pub countries: HashMap<u16, PacketStats>,
+    pub sources: HashMap<u32, PacketStats>,
+    /// Отброшенные пакеты по `pack_country`, 0 — страна неизвестна.
+    pub country_drops: HashMap<u16, u64>,
+}
+
+impl Totals {
+    pub fn counter(&self, slot: u32) -> u64 {
+        self.counters[slot as usize]
+    }
+
+    /// Прибавляет к счётчикам `other`.
+    pub fn add(&mut self, other: &Totals) {
+        for (value, more) in self.counters.iter_mut().zip(other.counters) {
+            *value = value.wrapping_add(more);
+        }
+        for (value, more) in self.drop_reasons.iter_mut().zip(other.drop_reasons) {
+            *value = value.wrapping_add(more);
+        }
+        merge(&mut self.ports, &other.ports);
+        merge(&mut self.countries, &other.countries);
+        merge(&mut self.sources, &other.sources);
+        for (&country, &count) in &other.country_drops {
+            let entry = self.country_drops.entry(country).or_default();
+            *entry = entry.wrapping_add(count);
+        }
+    }
+}
+
+fn merge<K: Copy + Eq + std::hash::Hash>(
+    into: &mut HashMap<K, PacketStats>,
+    from: &HashMap<K, PacketStats>,
+) {
+    for (key, totals) in from {
+        let entry = into.entry(*key).or_default();
+        entry.packets = entry.packets.wrapping_add(totals.packets);
+        entry.bytes = entry.bytes.wrapping_add(totals.bytes);
+    }
+}
+
+pub fn path() -> PathBuf {
+    config::main_path().with_file_name(FILE_NAME)
+}
+
+/// Итоги прошлых запусков; пустые, если файла нет или его не удалось разобрать.
+pub fn load() -> Totals {
+    fs::read_to_string(path()).ok().and_then(|text| parse(&text)).unwrap_or_default()
+}
+
+pub fn save(totals: &Totals) -> io::Result<()> {
+    fs::write(path(), render(totals))
+}
+
+fn render(totals: &Totals) -> String {
+    fn table<K: ToString>(entries: impl IntoIterator<Item = (K, PacketStats)>) -> Value {
+        let entries = entries
+            .into_iter()
+            .map(|(key, totals)| (key.to_string(), json!([totals.packets, totals.bytes])));
+        Value::Object(entries.collect())
+    }
+    let mut out = Map::new();
+    for (name, slot) in COUNTERS {
+        out.insert(name.to_string(), json!(totals.counter(slot)));
+    }
+    let reasons = DropReason::ALL
+        .into_iter()
+        .map(|reason| (reason.as_str().to_string(), json!(totals.drop_reasons[reason as usize])));
+    out.insert("drop_reasons".to_string(), Value::Object(reasons.collect()));
+    out.insert("ports".to_string(), table(totals.ports.iter().map(|(&k, &v)| (k, v))));
+    out.insert("countries".to_string(), table(totals.countries.iter().map(|(&k, &v)| (k, v))));
+    let drops = totals.country_drops.iter().map(|(k, &v)| (k.to_string(), json!(v)));
+    out.insert("country_drops".to_string(), Value::Object(drops.collect()));
+    let mut sources: Vec<_> = totals.sources.iter().map(|(&k, &v)| (k, v)).collect();
+    sources.sort_by(|a, b| b.1.packets.cmp(&a.1.packets).then(a.0.cmp(&b.0)));
+    sources.truncate(SAVED_SOURCES);
+    let sources = sources.into_iter().map(|(addr, totals)| (Ipv4Addr::from(addr), totals));
+    out.insert("sources".to_string(), table(sources));
+    Value::Object(out).to_string()
+}
+
+fn parse(text: &str) -> Option<Totals> {
+    fn table<K: FromStr + Eq + std::hash::Hash>(value: &Value) -> Option<HashMap<K, PacketStats>> {
+        let mut entries = HashMap::new();
+        for (key, pair) in value.as_object()? {
+            let [packets, bytes] = pair.as_array()?.as_slice() else {
+                return None;
+            };
+            let totals = PacketStats {
+                packets: packets.as_u64()?,
+                bytes: bytes.as_u64()?,
+            };
+            entries.insert(key.parse().ok()?, totals);
+        }
+        Some(entries)
+    }
+    let value: Value = serde_json::from_str(text).ok()?;
+    let mut totals = Totals::default();
+    for (name, slot) in COUNTERS {
+        // Счётчики, которых ещё не было в файле прежней версии, считаются нулём.
+        totals.counters[slot as usize] = match value.get(name) {
+            Some(counter) => counter.as_u64()?,
+            None => 0,
+        };
+    }
+    if let Some(reasons) = value.get("drop_reasons") {
+        for (name, count) in reasons.as_object()? {
+            let reason = DropReason::ALL.into_iter().find(|r| r.as_str() == name)?;
+            totals.drop_reasons[reason as usize] = count.as_u64()?;
+        }
+    }
+    totals.ports = table(value.get("ports")?)?;
+    totals.countries = table(value.get("countries")?)?;
+    // В файле прежней версии отброшенных по странам ещё нет.
+    if let Some(drops) = value.get("country_drops") {
+        for (key, count) in drops.as_object()? {
+            totals.country_drops.insert(key.parse().ok()?, count.as_u64()?);
+        }
+    }
+    totals.sources = table::<Ipv4Addr>(value.get("sources")?)?
+        .into_iter()
+        .map(|(addr, totals)| (u32::from(addr), totals))
+        .collect();
+    Some(totals)
+}
+
+/// Следит за счётчиками файрволла, запущенного из firewall-cli, чтобы сохранить их при
+/// остановке.
+pub struct Recorder {
+    last: Option<Totals>,
+    read_at: Option<Instant>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self {
+            last: None,
+            read_at: None,
+        }
+    }
+
+    /// Перечитывает карты, если с прошлого чтения прошло [`READ_INTERVAL`].
+    pub fn poll(&mut self) {
+        if self.read_at.is_some_and(|at| at.elapsed() < READ_INTERVAL) {
+            return;
+        }
+        self.read_at = Some(Instant::now());
+        self.read();
+    }
+
+    fn read(&mut self) {
+        if let Ok(Some(totals)) = fw_stats::fetch_totals() {
+            self.last = Some(totals);
+        }
+    }
+
+    /// Сохраняет итоги после остановки файрволла: свежие, если карты ещё читаются, иначе
+    /// прочитанные последними.
+    pub fn finish(mut self) {
+        self.read();
+        if let Some(totals) = &self.last {
+            if let Err(e) = save(totals) {
+                println!("Не удалось сохранить статистику в {}: {e}", path().display());
+            }
+        }
+    }
+}
@@ -0,0 +1,1341 @@
+//! Решение о судьбе пакета, общее для программы XDP и режима без XDP.
+//!
+//! Программа eBPF разбирает заголовки через указатели (так требует верификатор), а
+//! пользовательский режим — через [`parse_frame`]; оба собирают [`Packet`] и передают его
+//! в [`decide`] вместе со своей реализацией [`Rules`].
+
+use crate::{
+    event_fields, protocol_rule, verdict_override, ConnKey, DropEvent, DropReason, FlowKey,
+};
+
+/// Длина заголовка Ethernet без тегов VLAN.
+pub const ETH_HDR_LEN: usize = 14;
+/// Смещение MAC-адреса источника в заголовке Ethernet.
+pub const ETH_SRC_OFFSET: usize = 6;
+/// Длина тега VLAN: TCI и EtherType вложенного кадра.
+pub const VLAN_HDR_LEN: usize = 4;
+/// Сколько тегов VLAN снимается перед заголовком IP: один тег 802.1Q или два (QinQ).
+pub const MAX_VLAN_TAGS: usize = 2;
+/// Длина заголовка IPv4 без опций, она же наименьшая допустимая.
+pub const IPV4_HDR_LEN: usize = 20;
+/// Флаг «есть ещё фрагменты» (MF) в поле флагов и смещения фрагмента IPv4.
+pub const IPV4_MORE_FRAGMENTS: u16 = 0x2000;
+/// Смещение фрагмента в восьмибайтовых блоках: младшие 13 бит того же поля.
+pub const IPV4_FRAG_OFFSET_MASK: u16 = 0x1fff;
+/// Длина основного заголовка IPv6; в отличие от IPv4 она постоянна.
+pub const IPV6_HDR_LEN: usize = 40;
+/// Порт DNS, см. [`Rules::allows_dns`].
+pub const DNS_PORT: u16 = 53;
+/// Порт HTTPS; по UDP на нём работает QUIC (HTTP/3).
+pub const QUIC_PORT: u16 = 443;
+/// Смещение байта флагов внутри заголовка TCP.
+pub const TCP_FLAGS_OFFSET: usize = 13;
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+pub const TCP_URG: u8 = 0x20;
+
+pub const ETH_P_IPV4: u16 = 0x0800;
+pub const ETH_P_IPV6: u16 = 0x86dd;
+pub const ETH_P_ARP: u16 = 0x0806;
+pub const ETH_P_8021Q: u16 = 0x8100;
+/// Внешний тег QinQ (802.1ad).
+pub const ETH_P_8021AD: u16 = 0x88a8;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+/// IPv4 внутри IPv4 (IP-in-IP).
+pub const IPPROTO_IPIP: u8 = 4;
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_ICMPV6: u8 = 58;
+
+/// Заголовки расширения IPv6 (RFC 8200): номера в поле Next Header, за которыми идёт не
+/// транспортный заголовок, а ещё один заголовок IPv6.
+pub mod ipv6_ext {
+    pub const HOP_BY_HOP: u8 = 0;
+    pub const ROUTING: u8 = 43;
+    pub const FRAGMENT: u8 = 44;
+    pub const AUTH: u8 = 51;
+    pub const DEST_OPTS: u8 = 60;
+    pub const MOBILITY: u8 = 135;
+    pub const HIP: u8 = 139;
+    pub const SHIM6: u8 = 140;
+    /// Номера для экспериментов (RFC 3692).
+    pub const EXPERIMENTAL_1: u8 = 253;
+    pub const EXPERIMENTAL_2: u8 = 254;
+}
+
+/// Сколько заголовков расширения IPv6 проходится в поисках транспортного: ограничение для
+/// верификатора. Рекомендованный RFC 8200 порядок — не больше шести заголовков.
+pub const MAX_IPV6_EXT_HDRS: usize = 6;
+
+/// Проходится ли заголовок расширения `next_hdr` до транспортного: параметры для узлов
+/// (hop-by-hop), маршрутизация, фрагмент, аутентификация (AH) и параметры для получателя.
+/// За остальными (ESP, мобильность, HIP...) транспортный заголовок не найти.
+#[inline(always)]
+pub const fn walks_ipv6_ext(next_hdr: u8) -> bool {
+    matches!(
+        next_hdr,
+        ipv6_ext::HOP_BY_HOP
+            | ipv6_ext::ROUTING
+            | ipv6_ext::FRAGMENT
+            | ipv6_ext::AUTH
+            | ipv6_ext::DEST_OPTS
+    )
+}
+
+/// Длина заголовка расширения `next_hdr`, который проходит [`walks_ipv6_ext`], по его
+/// второму байту: обычно 8-байтовые блоки без первого, у AH — 4-байтовые без двух первых,
+/// заголовок фрагмента всегда 8 байт.
+#[inline(always)]
+pub const fn ipv6_ext_len(next_hdr: u8, hdr_ext_len: u8) -> usize {
+    match next_hdr {
+        ipv6_ext::FRAGMENT => 8,
+        ipv6_ext::AUTH => (hdr_ext_len as usize + 2) * 4,
+        _ => (hdr_ext_len as usize + 1) * 8,
+    }
+}
+
+/// Заголовок расширения ли `next_hdr`, см. [`ipv6_ext`].
+#[inline(always)]
+pub const fn is_ipv6_ext(next_hdr: u8) -> bool {
+    matches!(
+        next_hdr,
+        ipv6_ext::HOP_BY_HOP
+            | ipv6_ext::ROUTING
+            | ipv6_ext::FRAGMENT
+            | ipv6_ext::AUTH
+            | ipv6_ext::DEST_OPTS
+            | ipv6_ext::MOBILITY
+            | ipv6_ext::HIP
+            | ipv6_ext::SHIM6
+            | ipv6_ext::EXPERIMENTAL_1
+            | ipv6_ext::EXPERIMENTAL_2
+    )
+}
+
+/// Длина пакета ARP для Ethernet и IPv4.
+pub const ARP_LEN: usize = 28;
+
+/// Операции ARP.
+pub mod arp {
+    pub const REQUEST: u16 = 1;
+    pub const REPLY: u16 = 2;
+}
+
+/// Типы сообщений ICMP и ICMPv6, которые различают правила.
+pub mod icmp {
+    pub const ECHO_REPLY: u8 = 0;
+    pub const DEST_UNREACHABLE: u8 = 3;
+    pub const ECHO_REQUEST: u8 = 8;
+    pub const TIME_EXCEEDED: u8 = 11;
+
+    pub const V6_DEST_UNREACHABLE: u8 = 1;
+    pub const V6_PACKET_TOO_BIG: u8 = 2;
+    pub const V6_TIME_EXCEEDED: u8 = 3;
+    pub const V6_ECHO_REQUEST: u8 = 128;
+    pub const V6_ECHO_REPLY: u8 = 129;
+    /// Обнаружение соседей (NDP): запрос и объявление маршрутизатора, запрос и объявление
+    /// соседа. Без них IPv6 не работает вовсе.
+    pub const V6_NDP: core::ops::RangeInclusive<u8> = 133..=136;
+}
+
+/// Поля пакета, нужные для решения. Адреса и порты в порядке байт хоста.
+///
+/// Адреса — IPv4; у пакета IPv6 они нулевые, его источник — в `src_addr6`, и он решается
+/// [`decide_ipv6`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Packet {
+    pub src_addr: u32,
+    /// Адрес источника IPv6 в сетевом порядке байт; у пакета IPv4 нулевой.
+    pub src_addr6: [u8; 16],
+    pub dst_addr: u32,
+    pub proto: u8,
+    /// Порты; 0 для протоколов без портов.
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// Окно TCP, только для TCP.
+    pub tcp_window: Option<u16>,
+    /// Тип и код сообщения, только для ICMP и ICMPv6.
+    pub icmp: Option<(u8, u8)>,
+    pub tcp_flags: u8,
+    pub ttl: u8,
+    /// Длина кадра целиком.
+    pub len: u16,
+    /// Идентификатор VLAN внешнего тега; 0 — кадр без тега.
+    pub vlan: u16,
+    /// Страна источника, [`crate::pack_country`].
+    pub country: u16,
+    /// Место пакета среди фрагментов, по заголовку IPv4 или заголовку фрагмента IPv6; у
+    /// следующих фрагментов портов нет, и они нулевые.
+    pub fragment: Fragment,
+    /// Направление пакета; правила применяются только к [`Rules::filters_direction`].
+    pub direction: Direction,
+}
+
+/// Фрагментация пакета IPv4 или IPv6.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fragment {
+    /// Пакет не фрагментирован.
+    #[default]
+    None,
+    /// Первый фрагмент: транспортный заголовок в нём.
+    First,
+    /// Следующий фрагмент: за заголовком IP сразу данные.
+    Later,
+}
+
+impl Fragment {
+    /// Фрагментация по полю флагов и смещения фрагмента (`frag_off`) в порядке байт хоста.
+    #[inline(always)]
+    pub const fn from_frag_off(frag_off: u16) -> Self {
+        if frag_off & IPV4_FRAG_OFFSET_MASK != 0 {
+            Self::Later
+        } else if frag_off & IPV4_MORE_FRAGMENTS != 0 {
+            Self::First
+        } else {
+            Self::None
+        }
+    }
+
+    /// Фрагментация по полю смещения и флагов заголовка фрагмента IPv6 в порядке байт
+    /// хоста: смещение в старших 13 битах, флаг M — младший бит. Смещение 0 без M — пакет
+    /// целиком в одном «атомарном» фрагменте (RFC 6946), он не фрагментирован.
+    #[inline(always)]
+    pub const fn from_ipv6_frag(frag: u16) -> Self {
+        if frag & 0xfff8 != 0 {
+            Self::Later
+        } else if frag & 1 != 0 {
+            Self::First
+        } else {
+            Self::None
+        }
+    }
+}
+
+/// Направление пакета относительно интерфейса.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Direction {
+    /// Входящий: такие пакеты видит программа XDP.
+    #[default]
+    Ingress,
+    /// Исходящий.
+    Egress,
+}
+
+impl Packet {
+    /// Первый пакет рукопожатия TCP: SYN без ACK.
+    pub fn is_bare_syn(&self) -> bool {
+        self.proto == IPPROTO_TCP && self.tcp_flags & (TCP_SYN | TCP_ACK) == TCP_SYN
+    }
+
+    /// Пакет QUIC: UDP с портом [`QUIC_PORT`] в источнике (ответ сервера) или назначении.
+    pub fn is_quic(&self) -> bool {
+        self.proto == IPPROTO_UDP && (self.src_port == QUIC_PORT || self.dst_port == QUIC_PORT)
+    }
+
+    /// 4-кортеж пакета: ключ `CONNTRACK`.
+    pub fn conn_key(&self) -> ConnKey {
+        ConnKey {
+            src_addr: self.src_addr,
+            dst_addr: self.dst_addr,
+            src_port: self.src_port,
+            dst_port: self.dst_port,
+        }
+    }
+
+    /// 5-кортеж пакета: ключ `FLOWS` и `VERDICT_OVERRIDES`.
+    pub fn flow_key(&self) -> FlowKey {
+        FlowKey {
+            src_addr: self.src_addr,
+            dst_addr: self.dst_addr,
+            src_port: self.src_port,
+            dst_port: self.dst_port,
+            proto: self.proto,
+            _pad: [0; 3],
+        }
+    }
+
+    /// Событие об отброшенном пакете с необязательными полями из маски `fields`.
+    pub fn drop_event(&self, reason: DropReason, fields: u8) -> DropEvent {
+        let mut event = DropEvent {
+            src_addr: self.src_addr,
+            dst_addr: self.dst_addr,
+            src_port: self.src_port,
+            dst_port: self.dst_port,
+            proto: self.proto,
+            reason: reason as u8,
+            country: self.country,
+            fields,
+            ..Default::default()
+        };
+        if fields & event_fields::TTL != 0 {
+            event.ttl = self.ttl;
+        }
+        if fields & event_fields::TCP_FLAGS != 0 {
+            event.tcp_flags = self.tcp_flags;
+        }
+        if fields & event_fields::LENGTH != 0 {
+            event.len = self.len;
+        }
+        if fields & event_fields::VLAN != 0 {
+            event.vlan = self.vlan;
+        }
+        event
+    }
+}
+
+/// Источник правил: карты eBPF в ядре или обычные коллекции в пользовательском режиме.
+pub trait Rules {
+    fn is_blocked_ip(&self, addr: u32) -> bool;
+    /// Входит ли адрес IPv6 (в сетевом порядке байт) в `--blocked-ips6`.
+    fn is_blocked_ip6(&self, addr: &[u8; 16]) -> bool;
+    /// Совпадает ли адрес с правилом «адрес/маска» из `BLOCKED_MASKS`.
+    fn is_blocked_mask(&self, addr: u32) -> bool;
+    fn is_blocked_country(&self, country: u16) -> bool;
+    /// Действуют ли сейчас блокировки стран: истина, если окно времени не задано
+    /// (`--blocked-countries-window`).
+    fn country_window_open(&self) -> bool;
+    /// Входит ли адрес в сеть заблокированного региона; ложь, если регионы не загружены.
+    fn is_blocked_region(&self, addr: u32) -> bool;
+    /// Исключение из блокировки по стране.
+    fn is_allowed_ip(&self, addr: u32) -> bool;
+    /// Совпадает ли окно TCP с сигнатурой; ложь, если фильтр по окну выключен.
+    fn is_blocked_tcp_window(&self, window: u16) -> bool;
+    /// Отбрасывать ли пакеты TCP с недопустимыми флагами, см. [`tcp_scan`].
+    fn filters_tcp_flags(&self) -> bool;
+    /// Пропускать ли эхо-запросы ICMP и ICMPv6 (`--allow-icmp-echo`).
+    fn is_icmp_echo_allowed(&self) -> bool;
+    /// Политика по умолчанию `allow` (`--policy`): см. [`fall_through`].
+    fn default_allow(&self) -> bool;
+    /// Решаются ли пакеты неизвестных протоколов (ESP, GRE, SCTP...) политикой по умолчанию.
+    /// Если нет, они пропускаются: файрволл не должен молча ломать VPN и туннели.
+    fn strict_protocols(&self) -> bool;
+    /// Правило для протокола IP `proto` (`--protocols`), одно из значений [`protocol_rule`].
+    fn protocol_rule(&self, proto: u8) -> u8;
+    /// Отбрасывать ли все фрагменты IPv4 (`--drop-fragments`).
+    fn drops_fragments(&self) -> bool;
+    /// Принадлежит ли пакет TCP соединению, которое открыл сам хост (`--conntrack-timeout`);
+    /// ложь, если таблица соединений не ведётся.
+    fn is_established(&self, packet: &Packet) -> bool;
+    /// Пропускать ли DNS, запросы и ответы по TCP и UDP, без разрешённых портов: порт
+    /// [`DNS_PORT`] в источнике или назначении. Ложь — `--strict-dns`.
+    fn allows_dns(&self) -> bool;
+    /// Заблокирован ли порт источника для протокола `proto` (`--blocked-src-ports`).
+    fn is_blocked_src_port(&self, port: u16, proto: u8) -> bool;
+    /// Разрешён ли порт для протокола `proto` (`--ports`).
+    fn is_allowed_port(&self, port: u16, proto: u8) -> bool;
+    /// Сравнивать разрешённые порты с портом источника, а не назначения (`--port-match`).
+    fn port_match_src(&self) -> bool;
+    /// Совпадает ли пакет с составным правилом «адрес:порт назначения»; какой адрес
+    /// сравнивается, источника или назначения, решает реализация по настройке.
+    fn is_blocked_endpoint(&self, packet: &Packet) -> bool;
+    /// Порт назначения за стуком (`--knock-port`); 0 — стук выключен.
+    fn knock_port(&self) -> u16;
+    /// Учитывает пакет IPv4 в стуке его источника, см. [`crate::knock_next`]. Для пакета к
+    /// порту за стуком отвечает, открыт ли порт источнику: тот простучал последовательность
+    /// и с последнего пакета к порту прошло не больше `--knock-timeout` секунд. Для
+    /// остальных пакетов — ложь.
+    fn knock(&self, packet: &Packet) -> bool;
+    /// Применяются ли правила к пакетам направления `direction` (`--direction`); пакеты
+    /// другого направления пропускаются без проверки.
+    fn filters_direction(&self, direction: Direction) -> bool;
+    /// Для пакета TCP к разрешённому порту (`allowed`) записывает его соединение в
+    /// `ACCEPTED_CONNS` и отвечает ложью. Для пакета к порту без разрешения отвечает, доживает
+    /// ли его соединение окно `grace-period` после перезагрузки, снявшей разрешение: запись
+    /// есть, жива и окно не закрылось. Ложь, если таблица соединений не ведётся.
+    fn in_grace(&self, packet: &Packet, allowed: bool) -> bool;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Pass,
+    Drop(DropReason),
+}
+
+/// Вердикт по значению из `VERDICT_OVERRIDES` для [`Packet::flow_key`]; он сильнее
+/// [`decide`]. Неизвестное значение — как отсутствие записи.
+#[inline(always)]
+pub fn override_verdict(value: Option<u8>) -> Option<Verdict> {
+    match value {
+        Some(verdict_override::PASS) => Some(Verdict::Pass),
+        Some(verdict_override::DROP) => Some(Verdict::Drop(DropReason::Override)),
+        _ => None,
+    }
+}
+
+/// Применяет правила по порядку: чёрный список адресов и масок, страна (в своё окно времени)
+/// и регион (если адрес не в исключениях), протокол ([`Rules::protocol_rule`]), составные
+/// правила «адрес:порт», стук ([`Rules::knock`]), заблокированные порты источника, флаги и
+/// окно TCP, ответы на соединения хоста ([`Rules::is_established`]), DNS
+/// ([`Rules::allows_dns`]) и, наконец, разрешённые порты назначения (или источника, см.
+/// [`Rules::port_match_src`]). ICMP после адресных правил решается по типу сообщения, см.
+/// [`decide_icmp`].
+///
+/// К следующим фрагментам применяются только адресные правила: портов и флагов в них
+/// нет, а первый фрагмент, без которого пакет не собрать, проверен полностью. С
+/// [`Rules::drops_fragments`] после адресных правил отбрасываются все фрагменты.
+///
+/// Блокировки действуют всегда, а пакет, который не разрешило ни одно правило, решается
+/// политикой по умолчанию, см. [`fall_through`]. Исключение — пакет направления, к которому
+/// правила не относятся ([`Rules::filters_direction`]): он пропускается сразу.
+#[inline(always)]
+pub fn decide<R: Rules>(packet: &Packet, rules: &R) -> Verdict {
+    if !rules.filters_direction(packet.direction) {
+        return Verdict::Pass;
+    }
+    if rules.is_blocked_ip(packet.src_addr) {
+        return Verdict::Drop(DropReason::BlockedIp);
+    }
+    if rules.is_blocked_mask(packet.src_addr) {
+        return Verdict::Drop(DropReason::BlockedMask);
+    }
+    // Явное исключение из ALLOWED_IPS сильнее блокировки страны, но не чёрного списка выше.
+    if rules.is_blocked_country(packet.country)
+        && rules.country_window_open()
+        && !rules.is_allowed_ip(packet.src_addr)
+    {
+        return Verdict::Drop(DropReason::BlockedCountry);
+    }
+    if rules.is_blocked_region(packet.src_addr) && !rules.is_allowed_ip(packet.src_addr) {
+        return Verdict::Drop(DropReason::BlockedRegion);
+    }
+    if let Some(verdict) = decide_fragment(packet, rules) {
+        return verdict;
+    }
+    let rule = rules.protocol_rule(packet.proto);
+    if rule == protocol_rule::DENY {
+        return Verdict::Drop(DropReason::DeniedProtocol);
+    }
+    if packet.proto == IPPROTO_ICMP {
+        return decide_icmp(packet, rules);
+    }
+    if packet.proto != IPPROTO_TCP && packet.proto != IPPROTO_UDP {
+        return unknown_protocol(rules, rule);
+    }
+    if rules.is_blocked_endpoint(packet) {
+        return Verdict::Drop(DropReason::BlockedEndpoint);
+    }
+    // Порт за стуком закрыт для всех, кто не простучал последовательность, что бы ни
+    // разрешали `--ports`, а тем, кто простучал, открыт и без них.
+    let knock_port = rules.knock_port();
+    let knocked = knock_port != 0 && rules.knock(packet);
+    if knock_port != 0 && packet.dst_port == knock_port && !knocked {
+        return Verdict::Drop(DropReason::KnockLocked);
+    }
+    decide_transport(packet, rules, knocked)
+}
+
+/// Применяет к пакету IPv6 чёрный список адресов IPv6 ([`Rules::is_blocked_ip6`]) и правила,
+/// которые от адреса не зависят: протокол, ICMPv6, порты источника, окно TCP и разрешённые
+/// порты. Остальные списки адресов, страны, регионы и правила «адрес:порт» заданы для IPv4 и
+/// к IPv6 не относятся. Стук ведётся по адресу IPv4, так что порт за стуком для IPv6 закрыт.
+/// Фрагменты и направление решаются, как у IPv4.
+///
+/// Если в `proto` заголовок расширения, транспортный заголовок за ним не найден, и портов
+/// у пакета нет. Такой пакет не считается неизвестным протоколом, иначе один заголовок
+/// расширения перед TCP обходил бы все правила портов: он решается политикой по умолчанию.
+#[inline(always)]
+pub fn decide_ipv6<R: Rules>(packet: &Packet, rules: &R) -> Verdict {
+    if !rules.filters_direction(packet.direction) {
+        return Verdict::Pass;
+    }
+    if rules.is_blocked_ip6(&packet.src_addr6) {
+        return Verdict::Drop(DropReason::BlockedIp);
+    }
+    if let Some(verdict) = decide_fragment(packet, rules) {
+        return verdict;
+    }
+    let rule = rules.protocol_rule(packet.proto);
+    if rule == protocol_rule::DENY {
+        return Verdict::Drop(DropReason::DeniedProtocol);
+    }
+    if packet.proto == IPPROTO_ICMPV6 {
+        return decide_icmp(packet, rules);
+    }
+    if is_ipv6_ext(packet.proto) {
+        return fall_through(rules, DropReason::UnsupportedProtocol);
+    }
+    if packet.proto != IPPROTO_TCP && packet.proto != IPPROTO_UDP {
+        return unknown_protocol(rules, rule);
+    }
+    let knock_port = rules.knock_port();
+    if knock_port != 0 && packet.dst_port == knock_port {
+        return Verdict::Drop(DropReason::KnockLocked);
+    }
+    decide_transport(packet, rules, false)
+}
+
+/// Решение для фрагмента после адресных правил: с [`Rules::drops_fragments`] отбрасываются
+/// все фрагменты, иначе следующие пропускаются. `None` — пакет проверяется дальше.
+#[inline(always)]
+fn decide_fragment<R: Rules>(packet: &Packet, rules: &R) -> Option<Verdict> {
+    match packet.fragment {
+        Fragment::None => None,
+        _ if rules.drops_fragments() => Some(Verdict::Drop(DropReason::Fragment)),
+        Fragment::First => None,
+        Fragment::Later => Some(Verdict::Pass),
+    }
+}
+
+/// Протокол, который правила не разбирают: пропускается, если он разрешён в `--protocols`
+/// или строгий режим ([`Rules::strict_protocols`]) выключен, иначе решается политикой по
+/// умолчанию.
+#[inline(always)]
+fn unknown_protocol<R: Rules>(rules: &R, rule: u8) -> Verdict {
+    if rule == protocol_rule::ALLOW {
+        Verdict::Pass
+    } else if rules.strict_protocols() {
+        fall_through(rules, DropReason::UnsupportedProtocol)
+    } else {
+        Verdict::Pass
+    }
+}
+
+/// Сообщения ICMP и ICMPv6, без которых ломается связность, проходят всегда: эхо-ответы,
+/// «адресат недоступен» (в том числе «нужна фрагментация» для поиска MTU), «превышено время
+/// жизни», для IPv6 ещё «пакет слишком велик» и обнаружение соседей. Эхо-запросы —
+/// по [`Rules::is_icmp_echo_allowed`], остальные типы отбрасываются.
+#[inline(always)]
+pub fn decide_icmp<R: Rules>(packet: &Packet, rules: &R) -> Verdict {
+    let Some((kind, _code)) = packet.icmp else {
+        return fall_through(rules, DropReason::Icmp);
+    };
+    let allowed = if packet.proto == IPPROTO_ICMPV6 {
+        match kind {
+            icmp::V6_ECHO_REQUEST => rules.is_icmp_echo_allowed(),
+            icmp::V6_ECHO_REPLY
+            | icmp::V6_DEST_UNREACHABLE
+            | icmp::V6_PACKET_TOO_BIG
+            | icmp::V6_TIME_EXCEEDED => true,
+            kind => icmp::V6_NDP.contains(&kind),
+        }
+    } else {
+        match kind {
+            icmp::ECHO_REQUEST => rules.is_icmp_echo_allowed(),
+            icmp::ECHO_REPLY | icmp::DEST_UNREACHABLE | icmp::TIME_EXCEEDED => true,
+            _ => false,
+        }
+    };
+    if allowed {
+        Verdict::Pass
+    } else {
+        fall_through(rules, DropReason::Icmp)
+    }
+}
+
+/// Общий для IPv4 и IPv6 конец проверки: порты источника, флаги и окно TCP, разрешённые
+/// порты. С `knocked` пакет к порту за стуком от источника, который его открыл, проходит как
+/// к разрешённому порту.
+#[inline(always)]
+fn decide_transport<R: Rules>(packet: &Packet, rules: &R, knocked: bool) -> Verdict {
+    // Блокировка, а не разрешение: действует до таблицы соединений и DNS, так что ответы
+    // отражающих серверов (NTP, SSDP, memcached) не проходят и по этим путям.
+    if rules.is_blocked_src_port(packet.src_port, packet.proto) {
+        return Verdict::Drop(DropReason::BlockedSrcPort);
+    }
+    if packet.proto == IPPROTO_TCP && rules.filters_tcp_flags() {
+        if let Some(reason) = tcp_scan(packet.tcp_flags) {
+            return Verdict::Drop(reason);
+        }
+    }
+    if let Some(window) = packet.tcp_window {
+        if rules.is_blocked_tcp_window(window) {
+            return Verdict::Drop(DropReason::TcpWindow);
+        }
+    }
+    // Ответы серверов, к которым подключился сам хост, приходят на случайный локальный порт.
+    // Соединения записываются только для IPv4, у пакетов IPv6 адреса нулевые и записи нет.
+    if packet.proto == IPPROTO_TCP && rules.is_established(packet) {
+        return Verdict::Pass;
+    }
+    // DNS нужен в обе стороны и по обоим протоколам: по TCP приходят большие ответы и
+    // передачи зон.
+    if rules.allows_dns() && (packet.src_port == DNS_PORT || packet.dst_port == DNS_PORT) {
+        return Verdict::Pass;
+    }
+    // Входящее соединение к нашей службе несёт её порт в поле назначения; порт источника
+    // имеет смысл только для ответов серверов, к которым подключается сам хост.
+    let port = if rules.port_match_src() { packet.src_port } else { packet.dst_port };
+    let allowed = knocked || rules.is_allowed_port(port, packet.proto);
+    // Соединение, принятое до снятия разрешения, доживает окно; новые к порту не проходят.
+    let draining = packet.proto == IPPROTO_TCP && rules.in_grace(packet, allowed);
+    if allowed || draining {
+        Verdict::Pass
+    } else {
+        fall_through(rules, DropReason::PortNotAllowed)
+    }
+}
+
+/// Решение для пакета, который не разрешило ни одно правило: с политикой `deny` он
+/// отбрасывается по `reason`, с `allow` пропускается.
+#[inline(always)]
+fn fall_through<R: Rules>(rules: &R, reason: DropReason) -> Verdict {
+    if rules.default_allow() {
+        Verdict::Pass
+    } else {
+        Verdict::Drop(reason)
+    }
+}
+
+/// Сканирование по флагам TCP, которых не бывает в настоящем соединении.
+///
+/// FIN без ACK тоже считается сканированием. После рукопожатия ACK стоит в каждом сегменте,
+/// так что такой пакет не может принадлежать установленному соединению, и таблица
+/// соединений для этой проверки не нужна.
+#[inline(always)]
+pub const fn tcp_scan(flags: u8) -> Option<DropReason> {
+    if flags == 0 {
+        Some(DropReason::NullScan)
+    } else if flags & (TCP_FIN | TCP_PSH | TCP_URG) == TCP_FIN | TCP_PSH | TCP_URG {
+        Some(DropReason::XmasScan)
+    } else if flags & (TCP_FIN | TCP_SYN | TCP_RST | TCP_ACK) == TCP_FIN {
+        Some(DropReason::FinScan)
+    } else {
+        None
+    }
+}
+
+/// Тег VLAN ли это: 802.1Q или внешний тег QinQ.
+#[inline(always)]
+pub const fn is_vlan(ether_type: u16) -> bool {
+    ether_type == ETH_P_8021Q || ether_type == ETH_P_8021AD
+}
+
+/// Идентификатор VLAN — младшие 12 бит TCI тега.
+#[inline(always)]
+pub const fn vlan_id(tci: u16) -> u16 {
+    tci & 0x0fff
+}
+
+/// Длина заголовка IPv4 с опциями по первому его байту (версия и IHL): IHL в младших
+/// четырёх битах, в 32-битных словах. `None`, если заголовок короче [`IPV4_HDR_LEN`].
+#[inline(always)]
+pub const fn ipv4_hdr_len(version_ihl: u8) -> Option<usize> {
+    let len = (version_ihl & 0x0f) as usize * 4;
+    if len < IPV4_HDR_LEN {
+        None
+    } else {
+        Some(len)
+    }
+}
+
+/// Результат разбора кадра из пользовательского режима.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Frame {
+    /// Ни IPv4, ни IPv6, ни ARP для IPv4: программа XDP такие кадры пропускает без проверки.
+    NotIp,
+    Arp(Arp),
+    Ipv4(Packet),
+    /// Пакет IPv6, его адреса в [`Packet`] нулевые.
+    Ipv6(Packet),
+}
+
+/// Поля пакета ARP, нужные для решения.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Arp {
+    /// Операция, [`arp::REQUEST`] или [`arp::REPLY`].
+    pub op: u16,
+    /// Адрес IPv4 отправителя в порядке байт хоста.
+    pub sender_addr: u32,
+}
+
+/// ARP для Ethernet и IPv4: тип оборудования 1, протокол IPv4, адреса по 6 и 4 байта. Значения
+/// в порядке байт хоста; прочие варианты ARP правила не разбирают.
+#[inline(always)]
+pub const fn is_ethernet_ipv4_arp(htype: u16, ptype: u16, hlen: u8, plen: u8) -> bool {
+    htype == 1 && ptype == ETH_P_IPV4 && hlen == 6 && plen == 4
+}
+
+/// Пропускается ли ARP от `sender_addr` при ограничении `--arp-subnet net/prefix`;
+/// `prefix` 0 — ограничения нет.
+#[inline(always)]
+pub fn arp_allowed(sender_addr: u32, net: u32, prefix: u32) -> bool {
+    prefix == 0 || (sender_addr ^ net) >> (32 - prefix.min(32)) == 0
+}
+
+/// MAC-адрес назначения кадра; `None`, если кадр короче заголовка Ethernet.
+pub fn dst_mac(frame: &[u8]) -> Option<[u8; 6]> {
+    frame.get(..6)?.try_into().ok()
+}
+
+/// Групповой MAC-адрес: установлен младший бит первого байта (I/G). Широковещательный
+/// `ff:ff:ff:ff:ff:ff` — частный случай группового.
+pub fn is_multicast_mac(mac: &[u8; 6]) -> bool {
+    mac[0] & 1 != 0
+}
+
+/// MAC-адрес источника кадра; `None`, если кадр короче заголовка Ethernet.
+pub fn src_mac(frame: &[u8]) -> Option<[u8; 6]> {
+    frame.get(ETH_SRC_OFFSET..ETH_SRC_OFFSET + 6)?.try_into().ok()
+}
+
+fn be16(frame: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*frame.get(offset)?, *frame.get(offset + 1)?]))
+}
+
+fn be32(frame: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(frame.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+/// Разбирает кадр Ethernet так же, как программа XDP; `None` — кадр обрезан или IHL
+/// меньше пяти слов.
+///
+/// До [`MAX_VLAN_TAGS`] тегов VLAN снимаются; кадр с большим числом тегов не считается IP.
+/// С `unwrap_ipip` пакет IP-in-IP разбирается по внутреннему заголовку.
+pub fn parse_frame(frame: &[u8], unwrap_ipip: bool) -> Option<Frame> {
+    let mut ether_type = be16(frame, 12)?;
+    let mut ip = ETH_HDR_LEN;
+    let mut vlan = 0;
+    for tag in 0..MAX_VLAN_TAGS {
+        if !is_vlan(ether_type) {
+            break;
+        }
+        if tag == 0 {
+            vlan = vlan_id(be16(frame, ip)?);
+        }
+        ether_type = be16(frame, ip + 2)?;
+        ip += VLAN_HDR_LEN;
+    }
+    match ether_type {
+        ETH_P_IPV4 => {}
+        ETH_P_IPV6 => return parse_ipv6(frame, ip, vlan).map(Frame::Ipv6),
+        ETH_P_ARP => return parse_arp(frame, ip),
+        _ => return Some(Frame::NotIp),
+    }
+    // Последний байт заголовка IPv4 без опций должен быть в кадре, как и в ptr_at.
+    frame.get(ip + IPV4_HDR_LEN - 1)?;
+    let mut hdr_len = ipv4_hdr_len(frame[ip])?;
+    // В следующем фрагменте внешнего пакета внутреннего заголовка нет, как и в программе.
+    let outer_frag = Fragment::from_frag_off(be16(frame, ip + 6)?);
+    if unwrap_ipip && frame[ip + 9] == IPPROTO_IPIP && outer_frag != Fragment::Later {
+        ip += hdr_len;
+        frame.get(ip + IPV4_HDR_LEN - 1)?;
+        hdr_len = ipv4_hdr_len(frame[ip])?;
+    }
+    let l4 = ip + hdr_len;
+
+    let src_addr = be32(frame, ip + 12)?;
+    let fragment = Fragment::from_frag_off(be16(frame, ip + 6)?);
+    let mut packet = Packet {
+        src_addr,
+        dst_addr: be32(frame, ip + 16)?,
+        proto: frame[ip + 9],
+        ttl: frame[ip + 8],
+        len: frame.len().min(u16::MAX as usize) as u16,
+        vlan,
+        country: crate::pack_country(crate::lookup_country(src_addr).as_bytes()),
+        fragment,
+        ..Default::default()
+    };
+    // В следующих фрагментах за заголовком IP идут данные, а не транспортный заголовок.
+    if fragment != Fragment::Later {
+        parse_transport(frame, l4, &mut packet)?;
+    }
+    Some(Frame::Ipv4(packet))
+}
+
+/// Разбирает пакет ARP по смещению `arp`; `None` — пакет обрезан.
+fn parse_arp(frame: &[u8], arp: usize) -> Option<Frame> {
+    frame.get(arp + ARP_LEN - 1)?;
+    let htype = be16(frame, arp)?;
+    if !is_ethernet_ipv4_arp(htype, be16(frame, arp + 2)?, frame[arp + 4], frame[arp + 5]) {
+        return Some(Frame::NotIp);
+    }
+    Some(Frame::Arp(Arp {
+        op: be16(frame, arp + 6)?,
+        sender_addr: be32(frame, arp + 14)?,
+    }))
+}
+
+/// Разбирает пакет IPv6 с заголовком по смещению `ip`. До [`MAX_IPV6_EXT_HDRS`] заголовков
+/// расширения ([`walks_ipv6_ext`]) проходятся по цепочке, как в программе XDP; в `proto`
+/// остаётся протокол за последним из них.
+fn parse_ipv6(frame: &[u8], ip: usize, vlan: u16) -> Option<Packet> {
+    frame.get(ip + IPV6_HDR_LEN - 1)?;
+    let mut packet = Packet {
+        proto: frame[ip + 6],
+        ttl: frame[ip + 7],
+        len: frame.len().min(u16::MAX as usize) as u16,
+        vlan,
+        ..Default::default()
+    };
+    packet.src_addr6.copy_from_slice(&frame[ip + 8..ip + 24]);
+    let mut l4 = ip + IPV6_HDR_LEN;
+    for _ in 0..MAX_IPV6_EXT_HDRS {
+        // За заголовком следующего фрагмента уже данные, а не следующий заголовок.
+        if !walks_ipv6_ext(packet.proto) || packet.fragment == Fragment::Later {
+            break;
+        }
+        // Заголовок расширения не короче 8 байт, они и проверяются, как `Ipv6ExtHdr`.
+        frame.get(l4 + 7)?;
+        if packet.proto == ipv6_ext::FRAGMENT {
+            packet.fragment = Fragment::from_ipv6_frag(be16(frame, l4 + 2)?);
+        }
+        let len = ipv6_ext_len(packet.proto, frame[l4 + 1]);
+        packet.proto = frame[l4];
+        l4 += len;
+    }
+    if packet.fragment != Fragment::Later {
+        parse_transport(frame, l4, &mut packet)?;
+    }
+    Some(packet)
+}
+
+/// Заполняет порты, окно и флаги TCP по заголовку, который начинается с `l4`.
+fn parse_transport(frame: &[u8], l4: usize, packet: &mut Packet) -> Option<()> {
+    match packet.proto {
+        IPPROTO_TCP => {
+            frame.get(l4 + 19)?;
+            packet.src_port = be16(frame, l4)?;
+            packet.dst_port = be16(frame, l4 + 2)?;
+            packet.tcp_window = Some(be16(frame, l4 + 14)?);
+            packet.tcp_flags = frame[l4 + TCP_FLAGS_OFFSET];
+        }
+        IPPROTO_UDP => {
+            frame.get(l4 + 7)?;
+            packet.src_port = be16(frame, l4)?;
+            packet.dst_port = be16(frame, l4 + 2)?;
+        }
+        IPPROTO_ICMP | IPPROTO_ICMPV6 => {
+            // Заголовок ICMP — 8 байт, как `IcmpHdr` в программе XDP.
+            frame.get(l4 + 7)?;
+            packet.icmp = Some((frame[l4], frame[l4 + 1]));
+        }
+        _ => {}
+    }
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{grace_alive, lookup_country, port_protos};
+
+    /// Правила из полей теста: по умолчанию ничего не заблокировано и не разрешено,
+    /// политика `deny`, DNS пропускается, флаги TCP проверяются, правила — для входящих.
+    struct TestRules {
+        blocked_ips: &'static [u32],
+        blocked_masks: &'static [(u32, u32)],
+        blocked_countries: &'static [u16],
+        allowed_ips: &'static [u32],
+        allowed_ports: &'static [(u16, u8)],
+        default_allow: bool,
+        strict_protocols: bool,
+        drops_fragments: bool,
+        directions: &'static [Direction],
+        tcp_windows: &'static [u16],
+        /// Соединения `ACCEPTED_CONNS` со временем последнего пакета.
+        accepted: &'static [(ConnKey, u64)],
+        /// Окно `settings::GRACE_UNTIL` и текущее время `bpf_ktime_get_ns`.
+        grace_until: u32,
+        now_ns: u64,
+    }
+
+    impl TestRules {
+        const DEFAULT: Self = Self {
+            blocked_ips: &[],
+            blocked_masks: &[],
+            blocked_countries: &[],
+            allowed_ips: &[],
+            allowed_ports: &[],
+            default_allow: false,
+            strict_protocols: false,
+            drops_fragments: false,
+            directions: &[Direction::Ingress],
+            tcp_windows: &[],
+            accepted: &[],
+            grace_until: 0,
+            now_ns: 0,
+        };
+    }
+
+    impl Rules for TestRules {
+        fn is_blocked_ip(&self, addr: u32) -> bool {
+            self.blocked_ips.contains(&addr)
+        }
+
+        fn is_blocked_ip6(&self, _addr: &[u8; 16]) -> bool {
+            false
+        }
+
+        fn is_blocked_mask(&self, addr: u32) -> bool {
+            self.blocked_masks.iter().any(|&(rule, mask)| addr & mask == rule)
+        }
+
+        fn is_blocked_country(&self, country: u16) -> bool {
+            self.blocked_countries.contains(&country)
+        }
+
+        fn country_window_open(&self) -> bool {
+            true
+        }
+
+        fn is_blocked_region(&self, _addr: u32) -> bool {
+            false
+        }
+
+        fn is_allowed_ip(&self, addr: u32) -> bool {
+            self.allowed_ips.contains(&addr)
+        }
+
+        fn is_blocked_tcp_window(&self, window: u16) -> bool {
+            self.tcp_windows.contains(&window)
+        }
+
+        fn filters_tcp_flags(&self) -> bool {
+            true
+        }
+
+        fn is_icmp_echo_allowed(&self) -> bool {
+            false
+        }
+
+        fn default_allow(&self) -> bool {
+            self.default_allow
+        }
+
+        fn strict_protocols(&self) -> bool {
+            self.strict_protocols
+        }
+
+        fn protocol_rule(&self, _proto: u8) -> u8 {
+            protocol_rule::UNLISTED
+        }
+
+        fn drops_fragments(&self) -> bool {
+            self.drops_fragments
+        }
+
+        fn is_established(&self, _packet: &Packet) -> bool {
+            false
+        }
+
+        fn allows_dns(&self) -> bool {
+            true
+        }
+
+        fn is_blocked_src_port(&self, _port: u16, _proto: u8) -> bool {
+            false
+        }
+
+        fn is_allowed_port(&self, port: u16, proto: u8) -> bool {
+            let bit = port_protos::bit(proto);
+            self.allowed_ports.iter().any(|&(allowed, protos)| allowed == port && protos & bit != 0)
+        }
+
+        fn port_match_src(&self) -> bool {
+            false
+        }
+
+        fn is_blocked_endpoint(&self, _packet: &Packet) -> bool {
+            false
+        }
+
+        fn knock_port(&self) -> u16 {
+            0
+        }
+
+        fn knock(&self, _packet: &Packet) -> bool {
+            false
+        }
+
+        fn filters_direction(&self, direction: Direction) -> bool {
+            self.directions.contains(&direction)
+        }
+
+        fn in_grace(&self, packet: &Packet, allowed: bool) -> bool {
+            !allowed
+                && !packet.is_bare_syn()
+                && self.accepted.iter().any(|&(key, last_seen)| {
+                    key == packet.conn_key()
+                        && grace_alive(last_seen, self.now_ns, CONNTRACK_SECS, self.grace_until)
+                })
+        }
+    }
+
+    const SRC: u32 = 0xc633_6407; // 198.51.100.7
+    const DST: u32 = 0xc000_0201; // 192.0.2.1
+    const RU: u16 = crate::pack_country(b"RU");
+
+    fn packet(proto: u8, dst_port: u16) -> Packet {
+        Packet {
+            src_addr: SRC,
+            dst_addr: DST,
+            proto,
+            src_port: 40000,
+            dst_port,
+            tcp_flags: if proto == IPPROTO_TCP { TCP_SYN } else { 0 },
+            ..Default::default()
+        }
+    }
+
+    const WEB: TestRules = TestRules {
+        allowed_ports: &[(22, port_protos::TCP), (53, port_protos::UDP), (80, port_protos::ANY)],
+        ..TestRules::DEFAULT
+    };
+
+    #[test]
+    fn allowed_ports_pass_only_for_their_protocol() {
+        assert_eq!(decide(&packet(IPPROTO_TCP, 22), &WEB), Verdict::Pass);
+        assert_eq!(decide(&packet(IPPROTO_TCP, 80), &WEB), Verdict::Pass);
+        assert_eq!(decide(&packet(IPPROTO_UDP, 80), &WEB), Verdict::Pass);
+        assert_eq!(
+            decide(&packet(IPPROTO_UDP, 22), &WEB),
+            Verdict::Drop(DropReason::PortNotAllowed)
+        );
+        assert_eq!(
+            decide(&packet(IPPROTO_TCP, 443), &WEB),
+            Verdict::Drop(DropReason::PortNotAllowed)
+        );
+    }
+
+    #[test]
+    fn dns_passes_without_an_allowed_port() {
+        let rules = TestRules::DEFAULT;
+        assert_eq!(decide(&packet(IPPROTO_UDP, DNS_PORT), &rules), Verdict::Pass);
+        assert_eq!(decide(&packet(IPPROTO_TCP, DNS_PORT), &rules), Verdict::Pass);
+    }
+
+    #[test]
+    fn blocklist_beats_allowed_port() {
+        let rules = TestRules { blocked_ips: &[SRC], ..WEB };
+        assert_eq!(decide(&packet(IPPROTO_TCP, 22), &rules), Verdict::Drop(DropReason::BlockedIp));
+        let rules = TestRules { blocked_masks: &[(0x07, 0xff)], ..WEB };
+        assert_eq!(
+            decide(&packet(IPPROTO_TCP, 22), &rules),
+            Verdict::Drop(DropReason::BlockedMask)
+        );
+    }
+
+    #[test]
+    fn allowed_ip_is_exempt_from_country_block_only() {
+        let mut ru = packet(IPPROTO_TCP, 22);
+        ru.country = RU;
+        let rules = TestRules { blocked_countries: &[RU], ..WEB };
+        assert_eq!(decide(&ru, &rules), Verdict::Drop(DropReason::BlockedCountry));
+        let rules = TestRules { blocked_countries: &[RU], allowed_ips: &[SRC], ..WEB };
+        assert_eq!(decide(&ru, &rules), Verdict::Pass);
+        let rules = TestRules { blocked_ips: &[SRC], allowed_ips: &[SRC], ..WEB };
+        assert_eq!(decide(&ru, &rules), Verdict::Drop(DropReason::BlockedIp));
+    }
+
+    #[test]
+    fn default_policy_decides_unmatched_packets_only() {
+        let allow = TestRules { default_allow: true, ..WEB };
+        assert_eq!(decide(&packet(IPPROTO_TCP, 443), &allow), Verdict::Pass);
+        let blocked = TestRules { blocked_ips: &[SRC], ..allow };
+        assert_eq!(
+            decide(&packet(IPPROTO_TCP, 443), &blocked),
+            Verdict::Drop(DropReason::BlockedIp)
+        );
+        let mut scan = packet(IPPROTO_TCP, 22);
+        scan.tcp_flags = 0;
+        assert_eq!(decide(&scan, &allow), Verdict::Drop(DropReason::NullScan));
+    }
+
+    #[test]
+    fn unknown_protocols_follow_policy_only_when_strict() {
+        const GRE: u8 = 47;
+        assert_eq!(decide(&packet(GRE, 0), &WEB), Verdict::Pass);
+        let strict = TestRules { strict_protocols: true, ..WEB };
+        assert_eq!(
+            decide(&packet(GRE, 0), &strict),
+            Verdict::Drop(DropReason::UnsupportedProtocol)
+        );
+        let strict = TestRules { default_allow: true, ..strict };
+        assert_eq!(decide(&packet(GRE, 0), &strict), Verdict::Pass);
+    }
+
+    #[test]
+    fn later_fragments_get_address_rules_only() {
+        let mut later = packet(IPPROTO_TCP, 0);
+        later.fragment = Fragment::Later;
+        assert_eq!(decide(&later, &WEB), Verdict::Pass);
+        let rules = TestRules { blocked_ips: &[SRC], ..WEB };
+        assert_eq!(decide(&later, &rules), Verdict::Drop(DropReason::BlockedIp));
+        let rules = TestRules { drops_fragments: true, ..WEB };
+        assert_eq!(decide(&later, &rules), Verdict::Drop(DropReason::Fragment));
+    }
+
+    #[test]
+    fn rules_apply_to_their_direction_only() {
+        let mut egress = packet(IPPROTO_TCP, 443);
+        egress.direction = Direction::Egress;
+        let rules = TestRules { blocked_ips: &[SRC], ..WEB };
+        assert_eq!(decide(&egress, &rules), Verdict::Pass);
+        assert_eq!(decide_ipv6(&egress, &rules), Verdict::Pass);
+        let both = TestRules { directions: &[Direction::Ingress, Direction::Egress], ..rules };
+        assert_eq!(decide(&egress, &both), Verdict::Drop(DropReason::BlockedIp));
+        let egress_only = TestRules { directions: &[Direction::Egress], ..WEB };
+        assert_eq!(
+            decide(&egress, &egress_only),
+            Verdict::Drop(DropReason::PortNotAllowed)
+        );
+        assert_eq!(decide(&packet(IPPROTO_TCP, 443), &egress_only), Verdict::Pass);
+    }
+
+    const CONNTRACK_SECS: u32 = 300;
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn accepted_connection_outlives_removed_port_until_grace_ends() {
+        const SSH: ConnKey = ConnKey {
+            src_addr: SRC,
+            dst_addr: DST,
+            src_port: 40000,
+            dst_port: 22,
+        };
+        let mut ack = packet(IPPROTO_TCP, 22);
+        ack.tcp_flags = TCP_ACK;
+        // Разрешение на 22 снято в 100 с, окно — 30 с, последний пакет соединения — в 95 с.
+        let reloaded = TestRules {
+            allowed_ports: &[(80, port_protos::ANY)],
+            accepted: &[(SSH, 95 * SECOND)],
+            grace_until: 130,
+            now_ns: 110 * SECOND,
+            ..TestRules::DEFAULT
+        };
+        assert_eq!(decide(&ack, &reloaded), Verdict::Pass);
+        assert_eq!(
+            decide(&packet(IPPROTO_TCP, 22), &reloaded),
+            Verdict::Drop(DropReason::PortNotAllowed)
+        );
+        let expired = TestRules { now_ns: 130 * SECOND, ..reloaded };
+        assert_eq!(decide(&ack, &expired), Verdict::Drop(DropReason::PortNotAllowed));
+        let no_grace = TestRules { grace_until: 0, now_ns: 110 * SECOND, ..expired };
+        assert_eq!(decide(&ack, &no_grace), Verdict::Drop(DropReason::PortNotAllowed));
+    }
+
+    /// Кадр Ethernet с пакетом IPv6 от 2001:db8::7: первый следующий заголовок `next_hdr`,
+    /// за основным заголовком `payload`.
+    fn ipv6(next_hdr: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::from([0x02, 0, 0, 0, 0, 0x02, 0x02, 0, 0, 0, 0, 0x01, 0x86, 0xdd]);
+        frame.extend_from_slice(&[0x60, 0, 0, 0]);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&[next_hdr, 64]);
+        frame.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7]);
+        frame.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Заголовок расширения из `blocks` восьмибайтовых блоков со следующим `next_hdr`.
+    fn ext(next_hdr: u8, blocks: u8) -> Vec<u8> {
+        let mut header = Vec::from([next_hdr, blocks - 1]);
+        header.resize(usize::from(blocks) * 8, 0);
+        header
+    }
+
+    fn fragment_hdr(next_hdr: u8, frag: u16) -> Vec<u8> {
+        let mut header = Vec::from([next_hdr, 0]);
+        header.extend_from_slice(&frag.to_be_bytes());
+        header.extend_from_slice(&[0, 0, 0, 1]);
+        header
+    }
+
+    /// Заголовок SYN TCP с порта 40000 на `port`.
+    fn tcp_syn(port: u16) -> Vec<u8> {
+        let mut header = Vec::from(40000u16.to_be_bytes());
+        header.extend_from_slice(&port.to_be_bytes());
+        header.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0x50, TCP_SYN, 0xfa, 0xf0, 0, 0, 0, 0]);
+        header
+    }
+
+    /// Кадр Ethernet с пакетом IPv4 протокола `proto` от `src` к [`DST`].
+    fn ipv4(src: u32, proto: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::from([0x02, 0, 0, 0, 0, 0x02, 0x02, 0, 0, 0, 0, 0x01, 0x08, 0x00]);
+        frame.extend_from_slice(&[0x45, 0]);
+        frame.extend_from_slice(&(20 + payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0, 0, 64, proto, 0, 0]);
+        frame.extend_from_slice(&src.to_be_bytes());
+        frame.extend_from_slice(&DST.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn parse_ipv4_frame(frame: &[u8]) -> Packet {
+        match parse_frame(frame, false) {
+            Some(Frame::Ipv4(packet)) => packet,
+            other => panic!("ожидался пакет IPv4, разобрано {other:?}"),
+        }
+    }
+
+    #[test]
+    fn syn_with_blocked_tcp_window_is_dropped() {
+        let mut syn = tcp_syn(22);
+        let packet = parse_ipv4_frame(&ipv4(SRC, IPPROTO_TCP, &syn));
+        assert_eq!(packet.tcp_window, Some(64240));
+        let rules = TestRules { tcp_windows: &[64240], ..WEB };
+        assert_eq!(decide(&packet, &rules), Verdict::Drop(DropReason::TcpWindow));
+
+        syn[14..16].copy_from_slice(&1024u16.to_be_bytes());
+        let packet = parse_ipv4_frame(&ipv4(SRC, IPPROTO_TCP, &syn));
+        assert_eq!(packet.tcp_window, Some(1024));
+        assert_eq!(decide(&packet, &rules), Verdict::Pass);
+    }
+
+    #[test]
+    fn ipip_inner_port_decides_when_unwrapped() {
+        const INNER: u32 = 0x0a00_0007; // 10.0.0.7
+        let mut inner = ipv4(INNER, IPPROTO_TCP, &tcp_syn(443));
+        inner.drain(..14);
+        let frame = ipv4(SRC, IPPROTO_IPIP, &inner);
+
+        let packet = match parse_frame(&frame, true) {
+            Some(Frame::Ipv4(packet)) => packet,
+            other => panic!("ожидался пакет IPv4, разобрано {other:?}"),
+        };
+        assert_eq!((packet.src_addr, packet.proto, packet.dst_port), (INNER, IPPROTO_TCP, 443));
+        assert_eq!(decide(&packet, &WEB), Verdict::Drop(DropReason::PortNotAllowed));
+
+        // Без разворачивания туннель — неизвестный протокол, и он проходит.
+        let outer = parse_ipv4_frame(&frame);
+        assert_eq!((outer.src_addr, outer.proto), (SRC, IPPROTO_IPIP));
+        assert_eq!(decide(&outer, &WEB), Verdict::Pass);
+
+        // Следующий фрагмент туннеля не разворачивается: за внешним заголовком данные.
+        let mut later = frame.clone();
+        later[20..22].copy_from_slice(&185u16.to_be_bytes());
+        let packet = match parse_frame(&later, true) {
+            Some(Frame::Ipv4(packet)) => packet,
+            other => panic!("ожидался пакет IPv4, разобрано {other:?}"),
+        };
+        assert_eq!((packet.src_addr, packet.proto), (SRC, IPPROTO_IPIP));
+        assert_eq!((packet.fragment, packet.dst_port), (Fragment::Later, 0));
+    }
+
+    #[test]
+    fn override_drops_otherwise_allowed_flow() {
+        let packet = parse_ipv4_frame(&ipv4(SRC, IPPROTO_TCP, &tcp_syn(22)));
+        assert_eq!(decide(&packet, &WEB), Verdict::Pass);
+
+        // Внешний классификатор пишет вердикт по 5-кортежу пакета.
+        let overrides = [(packet.flow_key(), verdict_override::DROP)];
+        let verdict = |packet: &Packet| {
+            let value = overrides.iter().find(|(key, _)| *key == packet.flow_key());
+            override_verdict(value.map(|&(_, v)| v)).unwrap_or_else(|| decide(packet, &WEB))
+        };
+        assert_eq!(verdict(&packet), Verdict::Drop(DropReason::Override));
+
+        let other = Packet { src_port: 40001, ..packet };
+        assert_eq!(verdict(&other), Verdict::Pass);
+        assert_eq!(override_verdict(Some(0)), None);
+        assert_eq!(override_verdict(Some(verdict_override::PASS)), Some(Verdict::Pass));
+    }
+
+    #[test]
+    fn ttl_is_in_event_only_when_enabled() {
+        let packet = parse_ipv4_frame(&ipv4(SRC, IPPROTO_TCP, &tcp_syn(443)));
+        assert_eq!(packet.ttl, 64);
+        let core = packet.drop_event(DropReason::PortNotAllowed, 0);
+        assert_eq!((core.fields, core.ttl), (0, 0));
+        let rich = packet.drop_event(DropReason::PortNotAllowed, event_fields::TTL);
+        assert_eq!((rich.fields, rich.ttl), (event_fields::TTL, 64));
+        assert_eq!((rich.tcp_flags, rich.len), (0, 0));
+        assert_eq!((rich.src_addr, rich.dst_port, rich.reason), (SRC, 443, core.reason));
+    }
+
+    #[test]
+    fn allowed_ip_from_blocked_country_passes() {
+        const CN: u16 = crate::pack_country(b"CN");
+        const TRUSTED: u32 = 0x0600_0007; // 6.0.0.7, по первому октету — Китай
+        const OTHER: u32 = 0x0600_0008;
+        let rules = TestRules { blocked_countries: &[CN], allowed_ips: &[TRUSTED], ..WEB };
+        let decide_from = |src| {
+            let packet = parse_ipv4_frame(&ipv4(src, IPPROTO_TCP, &tcp_syn(22)));
+            assert_eq!(packet.country, crate::pack_country(lookup_country(src).as_bytes()));
+            decide(&packet, &rules)
+        };
+        assert_eq!(decide_from(TRUSTED), Verdict::Pass);
+        assert_eq!(decide_from(OTHER), Verdict::Drop(DropReason::BlockedCountry));
+        assert_eq!(decide_from(SRC), Verdict::Pass);
+    }
+
+    fn parse_ipv6_frame(frame: &[u8]) -> Packet {
+        match parse_frame(frame, false) {
+            Some(Frame::Ipv6(packet)) => packet,
+            other => panic!("ожидался пакет IPv6, разобрано {other:?}"),
+        }
+    }
+
+    #[test]
+    fn hop_by_hop_header_keeps_tcp_port() {
+        let frame = ipv6(ipv6_ext::HOP_BY_HOP, &[ext(IPPROTO_TCP, 1), tcp_syn(22)].concat());
+        let packet = parse_ipv6_frame(&frame);
+        assert_eq!((packet.proto, packet.dst_port), (IPPROTO_TCP, 22));
+        assert_eq!(decide_ipv6(&packet, &WEB), Verdict::Pass);
+
+        let frame = ipv6(ipv6_ext::HOP_BY_HOP, &[ext(IPPROTO_TCP, 1), tcp_syn(443)].concat());
+        assert_eq!(
+            decide_ipv6(&parse_ipv6_frame(&frame), &WEB),
+            Verdict::Drop(DropReason::PortNotAllowed)
+        );
+    }
+
+    #[test]
+    fn extension_chain_is_walked_to_transport() {
+        let chain = [
+            ext(ipv6_ext::DEST_OPTS, 1),
+            ext(ipv6_ext::ROUTING, 2),
+            ext(IPPROTO_TCP, 3),
+            tcp_syn(80),
+        ];
+        let packet = parse_ipv6_frame(&ipv6(ipv6_ext::HOP_BY_HOP, &chain.concat()));
+        assert_eq!((packet.proto, packet.dst_port), (IPPROTO_TCP, 80));
+        assert_eq!(packet.fragment, Fragment::None);
+    }
+
+    #[test]
+    fn ipv6_fragment_header_maps_to_fragments() {
+        let first = [fragment_hdr(IPPROTO_TCP, 1), tcp_syn(443)].concat();
+        let packet = parse_ipv6_frame(&ipv6(ipv6_ext::FRAGMENT, &first));
+        assert_eq!((packet.fragment, packet.dst_port), (Fragment::First, 443));
+        assert_eq!(decide_ipv6(&packet, &WEB), Verdict::Drop(DropReason::PortNotAllowed));
+
+        let later = [fragment_hdr(IPPROTO_TCP, 8 << 3), tcp_syn(443)].concat();
+        let packet = parse_ipv6_frame(&ipv6(ipv6_ext::FRAGMENT, &later));
+        assert_eq!((packet.fragment, packet.dst_port), (Fragment::Later, 0));
+        assert_eq!(decide_ipv6(&packet, &WEB), Verdict::Pass);
+        let rules = TestRules { drops_fragments: true, ..WEB };
+        assert_eq!(decide_ipv6(&packet, &rules), Verdict::Drop(DropReason::Fragment));
+
+        let atomic = [fragment_hdr(IPPROTO_TCP, 0), tcp_syn(22)].concat();
+        let packet = parse_ipv6_frame(&ipv6(ipv6_ext::FRAGMENT, &atomic));
+        assert_eq!((packet.fragment, packet.dst_port), (Fragment::None, 22));
+    }
+
+    #[test]
+    fn unwalked_extension_follows_policy() {
+        let mut chain: Vec<u8> = (0..MAX_IPV6_EXT_HDRS).flat_map(|_| ext(0, 1)).collect();
+        chain.extend(ext(IPPROTO_TCP, 1));
+        chain.extend(tcp_syn(22));
+        let packet = parse_ipv6_frame(&ipv6(ipv6_ext::HOP_BY_HOP, &chain));
+        assert_eq!(packet.proto, ipv6_ext::HOP_BY_HOP);
+        assert_eq!(
+            decide_ipv6(&packet, &WEB),
+            Verdict::Drop(DropReason::UnsupportedProtocol)
+        );
+
+        let packet = parse_ipv6_frame(&ipv6(ipv6_ext::MOBILITY, &ext(IPPROTO_TCP, 1)));
+        assert_eq!(
+            decide_ipv6(&packet, &WEB),
+            Verdict::Drop(DropReason::UnsupportedProtocol)
+        );
+        let allow = TestRules { default_allow: true, ..WEB };
+        assert_eq!(decide_ipv6(&packet, &allow), Verdict::Pass);
+    }
+
+    #[test]
+    fn truncated_extension_header_is_a_parse_error() {
+        let frame = ipv6(ipv6_ext::HOP_BY_HOP, &[IPPROTO_TCP, 0, 0, 0]);
+        assert_eq!(parse_frame(&frame, false), None);
+        let frame = ipv6(ipv6_ext::HOP_BY_HOP, &ext(IPPROTO_TCP, 1));
+        assert_eq!(parse_frame(&frame, false), None);
+    }
+}
@@ -0,0 +1,167 @@
+//! База «сеть → страна» для `--country-db`.
+//!
+//! Понимаются два вида строк CSV, в файле их можно смешивать:
+//!
+//! ```text
+//! 1.0.0.0/24,AU                 сеть и код страны
+//! 1.0.1.0,1.0.3.255,CN          первый и последний адрес диапазона (DB-IP, IP2Location)
+//! 16777472,16778239,CN          то же, адреса числами
+//! ```
+//!
+//! Кавычки вокруг полей и поля после кода страны пропускаются, как и пустые строки,
+//! комментарии `#`, строка заголовка в начале файла и строки с кодом `-` или `ZZ` (так
+//! базы отмечают адреса без страны). Диапазоны раскладываются на сети, поэтому одна и та
+//! же структура наполняет LPM-карту `COUNTRIES` и ищет страну в пользовательском режиме.
+
+use std::{collections::HashMap, fs, io, net::Ipv4Addr, path::Path};
+
+use crate::{country_key, lookup_country, pack_country};
+
+/// Сеть из базы и её страна ([`pack_country`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CountryNetwork {
+    pub addr: u32,
+    pub prefix: u8,
+    pub country: u16,
+}
+
+/// Наименьший набор сетей, которые в точности покрывают диапазон `first..=last`.
+pub fn range_prefixes(first: u32, last: u32) -> Vec<(u32, u8)> {
+    let mut prefixes = Vec::new();
+    let (mut start, end) = (u64::from(first), u64::from(last));
+    while start <= end {
+        // Самый крупный блок, выровненный по `start` и не выходящий за `end`.
+        let mut size = if start == 0 { 1u64 << 32 } else { 1u64 << start.trailing_zeros() };
+        while size > end - start + 1 {
+            size >>= 1;
+        }
+        prefixes.push((start as u32, 32 - size.trailing_zeros() as u8));
+        start += size;
+    }
+    prefixes
+}
+
+fn parse_addr(field: &str) -> Option<u32> {
+    field
+        .parse::<Ipv4Addr>()
+        .map(u32::from)
+        .ok()
+        .or_else(|| field.parse::<u32>().ok())
+}
+
+fn parse_network(field: &str) -> Option<(u32, u8)> {
+    let (addr, len) = field.split_once('/')?;
+    let addr = u32::from(addr.parse::<Ipv4Addr>().ok()?);
+    let len: u8 = len.parse().ok().filter(|len| *len <= 32)?;
+    let mask = u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0);
+    Some((addr & mask, len))
+}
+
+/// Код страны из поля: две латинские буквы; `None` — адрес без страны.
+fn parse_country(field: &str) -> Result<Option<u16>, String> {
+    match field.to_ascii_uppercase().as_str() {
+        "-" | "ZZ" => Ok(None),
+        code => match country_key(code.as_bytes()) {
+            Some(key) => Ok(Some(key)),
+            None => Err(format!("'{field}' is not a two-letter country code")),
+        },
+    }
+}
+
+/// Сети из одной строки; пустой список — строка без данных или адреса без страны.
+fn parse_line(line: &str) -> Result<Vec<CountryNetwork>, String> {
+    let fields: Vec<&str> = line.split(',').map(|f| f.trim().trim_matches('"')).collect();
+    let (prefixes, country) = match fields.as_slice() {
+        [network, country, ..] if network.contains('/') => {
+            let network = parse_network(network)
+                .ok_or_else(|| format!("'{network}' is not an IPv4 network"))?;
+            (vec![network], country)
+        }
+        [first, last, country, ..] => {
+            let bad = |field: &str| format!("'{field}' is not an IPv4 address");
+            let start = parse_addr(first).ok_or_else(|| bad(first))?;
+            let end = parse_addr(last).ok_or_else(|| bad(last))?;
+            if start > end {
+                return Err(format!("range {first}-{last} ends before it starts"));
+            }
+            (range_prefixes(start, end), country)
+        }
+        _ => return Err("expected NETWORK,CC or FIRST,LAST,CC".to_string()),
+    };
+    let Some(country) = parse_country(country)? else {
+        return Ok(Vec::new());
+    };
+    Ok(prefixes
+        .into_iter()
+        .map(|(addr, prefix)| CountryNetwork { addr, prefix, country })
+        .collect())
+}
+
+/// Разбирает текст базы; ошибка называет номер строки.
+pub fn parse(text: &str) -> Result<Vec<CountryNetwork>, String> {
+    let mut networks = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_line(line) {
+            Ok(parsed) => networks.extend(parsed),
+            // Первая строка файла, которая не разбирается, считается заголовком.
+            Err(_) if index == 0 => {}
+            Err(e) => return Err(format!("line {}: {e}", index + 1)),
+        }
+    }
+    Ok(networks)
+}
+
+/// Читает и разбирает базу из файла.
+pub fn load(path: &Path) -> io::Result<Vec<CountryNetwork>> {
+    let text = fs::read_to_string(path)?;
+    parse(&text).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{}: {e}", path.display()))
+    })
+}
+
+/// Поиск страны по самому длинному совпавшему префиксу, как в LPM-карте.
+#[derive(Debug, Default)]
+pub struct CountryTable {
+    networks: HashMap<(u8, u32), u16>,
+    /// Длины префиксов, которые есть в базе, от самой длинной.
+    prefixes: Vec<u8>,
+}
+
+impl CountryTable {
+    pub fn new(networks: &[CountryNetwork]) -> Self {
+        let networks: HashMap<(u8, u32), u16> = networks
+            .iter()
+            .map(|network| ((network.prefix, network.addr), network.country))
+            .collect();
+        let mut prefixes: Vec<u8> = networks.keys().map(|&(prefix, _)| prefix).collect();
+        prefixes.sort_unstable_by(|a, b| b.cmp(a));
+        prefixes.dedup();
+        Self { networks, prefixes }
+    }
+
+    pub fn len(&self) -> usize {
+        self.networks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.networks.is_empty()
+    }
+
+    /// Страна из базы, если адрес входит в одну из её сетей.
+    pub fn lookup(&self, addr: u32) -> Option<u16> {
+        self.prefixes.iter().find_map(|&prefix| {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            self.networks.get(&(prefix, addr & mask)).copied()
+        })
+    }
+
+    /// Страна адреса так же, как её определяет программа XDP: по базе, а для адресов
+    /// вне базы — по [`lookup_country`].
+    pub fn country(&self, addr: u32) -> u16 {
+        self.lookup(addr).unwrap_or_else(|| pack_country(lookup_country(addr).as_bytes()))
+    }
+}
@@ -1 +1,1141 @@
-#![no_std]
+#![cfg_attr(not(feature = "user"), no_std)]
+
+pub mod classify;
+#[cfg(feature = "user")]
+pub mod geoip;
+#[cfg(feature = "user")]
+pub mod probe;
+
+/// Каталог в bpffs, куда загрузчик закрепляет карты, чтобы CLI мог их открыть.
+pub const PIN_PATH: &str = "/sys/fs/bpf/firewall";
+
+/// Имя карты глобальных счётчиков действий XDP.
+pub const STATS_MAP: &str = "STATS";
+
+/// Индексы счётчиков в per-CPU массиве `STATS`.
+pub mod stats {
+    pub const PASS: u32 = 0;
+    pub const DROP: u32 = 1;
+    pub const ABORTED: u32 = 2;
+    /// Не действие, а число записей, не попавших в переполненную карту счётчиков.
+    pub const MAP_FULL: u32 = 3;
+    /// Пакеты, перенаправленные в сокет AF_XDP (`XDP_REDIRECT`).
+    pub const REDIRECT: u32 = 4;
+    /// Пакеты SYN, отброшенные пределом `--syn-rate-limit`; они учтены и в `DROP`.
+    pub const SYN_FLOOD: u32 = 5;
+    /// Пакеты, которые правила отбросили бы в режиме `--dry-run`; они учтены в `PASS`.
+    pub const DRY_RUN: u32 = 6;
+    /// Пакеты и байты QUIC (HTTP/3): UDP с портом 443 в источнике или назначении, с любым
+    /// решением; см. `classify::Packet::is_quic`.
+    pub const QUIC_PACKETS: u32 = 7;
+    pub const QUIC_BYTES: u32 = 8;
+
+    /// Количество слотов в карте.
+    pub const LEN: u32 = 9;
+}
+
+/// Имя per-CPU массива затрат на проверку правил (`firewall-cli profile`), ячейка на тип
+/// правила из [`rule_costs`].
+pub const RULE_COSTS_MAP: &str = "RULE_COSTS";
+
+/// Типы правил, затраты на которые учитываются в `RULE_COSTS`.
+pub mod rule_costs {
+    pub const BLOCKED_IP: u32 = 0;
+    pub const BLOCKED_MASK: u32 = 1;
+    pub const BLOCKED_COUNTRY: u32 = 2;
+    pub const BLOCKED_REGION: u32 = 3;
+    pub const ALLOWED_IP: u32 = 4;
+    pub const BLOCKED_ENDPOINT: u32 = 5;
+    pub const TCP_WINDOW: u32 = 6;
+    pub const ALLOWED_PORT: u32 = 7;
+    pub const BLOCKED_SRC_PORT: u32 = 8;
+    /// Не правило, а вся проверка пакета по правилам целиком, вместе с накладными расходами.
+    pub const DECIDE: u32 = 9;
+
+    /// Количество слотов в карте.
+    pub const LEN: u32 = 10;
+
+    /// Название слота, как его показывает `firewall-cli profile`.
+    pub const fn name(slot: u32) -> &'static str {
+        match slot {
+            BLOCKED_IP => "blocked-ips",
+            BLOCKED_MASK => "blocked-masks",
+            BLOCKED_COUNTRY => "blocked-countries",
+            BLOCKED_REGION => "blocked-regions",
+            ALLOWED_IP => "allowed-ips",
+            BLOCKED_ENDPOINT => "blocked-endpoints",
+            TCP_WINDOW => "block-tcp-window",
+            ALLOWED_PORT => "allowed-ports",
+            BLOCKED_SRC_PORT => "blocked-src-ports",
+            DECIDE => "всего",
+            _ => "?",
+        }
+    }
+}
+
+/// Затраты на один тип правил: сколько раз он проверялся и сколько это заняло.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RuleCost {
+    pub calls: u64,
+    pub ns: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for RuleCost {}
+
+/// Имя карты счётчиков трафика по порту назначения.
+pub const PORT_STATS_MAP: &str = "PORT_STATS";
+
+/// Имя карты счётчиков трафика по стране источника.
+pub const COUNTRY_STATS_MAP: &str = "COUNTRY_STATS";
+
+/// Имя карты отброшенных пакетов по стране источника (ключ — `pack_country`, 0 — страна
+/// неизвестна, значение — число пакетов).
+pub const COUNTRY_DROPS_MAP: &str = "COUNTRY_DROPS";
+
+/// Имя LRU-карты счётчиков трафика по адресу источника IPv4 (байты — по полю total length
+/// заголовка); при заполнении вытесняются давно не слышанные источники.
+pub const SOURCE_STATS_MAP: &str = "SOURCE_STATS";
+
+/// Счётчики пакетов и байт для одного ключа.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PacketStats {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for PacketStats {}
+
+/// Упаковывает двухбуквенный код страны в ключ карты: первая буква в старшем байте.
+///
+/// Код не проверяется, для кодов другой длины возвращается 0 («неизвестная страна»).
+/// Коды из ввода пользователя и из файлов переводятся через [`country_key`].
+pub const fn pack_country(code: &[u8]) -> u16 {
+    if code.len() != 2 {
+        return 0;
+    }
+    ((code[0] as u16) << 8) | code[1] as u16
+}
+
+/// Ключ карты для кода страны из ввода: ровно две латинские буквы в любом регистре,
+/// приводятся к заглавным, как их записывает программа. Для остального `None`: такой
+/// ключ молча не совпал бы ни с одной страной.
+pub const fn country_key(code: &[u8]) -> Option<u16> {
+    if code.len() != 2 || !code[0].is_ascii_alphabetic() || !code[1].is_ascii_alphabetic() {
+        return None;
+    }
+    Some(pack_country(&[code[0].to_ascii_uppercase(), code[1].to_ascii_uppercase()]))
+}
+
+/// Обратное преобразование ключа из [`pack_country`].
+pub const fn unpack_country(key: u16) -> [u8; 2] {
+    [(key >> 8) as u8, key as u8]
+}
+
+/// Имя карты значений окна TCP, пакеты с которыми отбрасываются.
+pub const TCP_WINDOWS_MAP: &str = "TCP_WINDOWS";
+
+/// Имя карты заблокированных адресов источника (ключ — IPv4 в порядке байт хоста). Значение
+/// — счётчик `u64` пакетов, отброшенных по записи; загрузчик и `firewall-cli` добавляют
+/// записи с нулём. Так же устроены `BLOCKED_NETS` и `BLOCKED_IPS6`, где пакет засчитывается
+/// самой длинной совпавшей сети.
+///
+/// Карта закреплена: `firewall-cli block` добавляет адреса в запущенный файрволл.
+pub const BLOCKED_IPS_MAP: &str = "BLOCKED_IPS";
+
+/// Имя LPM-карты заблокированных сетей источника (`--blocked-ips` с длиной префикса меньше
+/// 32). Ключ — длина префикса и адрес в сетевом порядке байт; отдельные адреса остаются в
+/// `BLOCKED_IPS`.
+pub const BLOCKED_NETS_MAP: &str = "BLOCKED_NETS";
+
+/// Имя LPM-карты заблокированных адресов и сетей источника IPv6 (`--blocked-ips6`). Ключ —
+/// длина префикса и адрес в сетевом порядке байт, отдельный адрес — префикс длины 128.
+pub const BLOCKED_IPS6_MAP: &str = "BLOCKED_IPS6";
+
+/// Имя закреплённой per-CPU карты адресов источника на испытании (`firewall-cli block
+/// --stage`): пакеты с них только считаются, значение — число пакетов, дошедших до правил.
+pub const STAGED_IPS_MAP: &str = "STAGED_IPS";
+
+/// Имя LPM-карты «сеть → страна» из `--country-db`: ключ — длина префикса и адрес в
+/// сетевом порядке байт, значение — [`pack_country`]. Адреса вне карты относятся к стране
+/// по [`lookup_country`].
+pub const COUNTRIES_MAP: &str = "COUNTRIES";
+
+/// Имя карты заблокированных стран (ключ — [`pack_country`]).
+pub const BLOCKED_COUNTRIES_MAP: &str = "BLOCKED_COUNTRIES";
+
+/// Имя массива правил «адрес/маска» с произвольной, не обязательно непрерывной маской
+/// (`--blocked-masks`); занято первых `settings::BLOCKED_MASKS` ячеек.
+pub const BLOCKED_MASKS_MAP: &str = "BLOCKED_MASKS";
+
+/// Размер `BLOCKED_MASKS`. LPM такие маски не выражает, поэтому программа просматривает
+/// правила по очереди для каждого пакета, и их число ограничено.
+pub const MAX_BLOCKED_MASKS: u32 = 32;
+
+/// Правило из `BLOCKED_MASKS`: адрес совпадает, если `addr & mask == self.addr`.
+/// Адрес хранится уже с наложенной маской, в порядке байт хоста.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MaskedAddr {
+    pub addr: u32,
+    pub mask: u32,
+}
+
+impl MaskedAddr {
+    pub const fn new(addr: u32, mask: u32) -> Self {
+        Self { addr: addr & mask, mask }
+    }
+
+    pub const fn matches(&self, addr: u32) -> bool {
+        addr & self.mask == self.addr
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for MaskedAddr {}
+
+/// Имя LPM-карты сетей источника с идентификатором их региона (`--region-db`).
+///
+/// Ключ — длина префикса и адрес в сетевом порядке байт, значение — `geoname_id` региона
+/// из базы MaxMind City. Загрузчик кладёт сюда только сети заблокированных регионов.
+pub const REGIONS_MAP: &str = "REGIONS";
+
+/// Имя карты адресов-исключений из блокировки по стране (ключ — IPv4 в порядке байт хоста).
+pub const ALLOWED_IPS_MAP: &str = "ALLOWED_IPS";
+
+/// Имя карты разрешённых портов (`--ports`, сравниваются по `settings::PORT_MATCH`);
+/// значение — маска [`port_protos`].
+pub const ALLOWED_PORTS_MAP: &str = "ALLOWED_PORTS";
+
+/// Имя карты заблокированных портов источника (`--blocked-src-ports`); значение — маска
+/// [`port_protos`], как в `ALLOWED_PORTS`.
+pub const BLOCKED_SRC_PORTS_MAP: &str = "BLOCKED_SRC_PORTS";
+
+/// Биты протоколов, для которых действует порт из `ALLOWED_PORTS` или `BLOCKED_SRC_PORTS`.
+pub mod port_protos {
+    use crate::classify::{IPPROTO_TCP, IPPROTO_UDP};
+
+    pub const TCP: u8 = 1 << 0;
+    pub const UDP: u8 = 1 << 1;
+    /// Порт без уточнения протокола (`80`): разрешён и для TCP, и для UDP.
+    pub const ANY: u8 = TCP | UDP;
+
+    /// Бит протокола транспортного уровня; 0 для протоколов без портов.
+    pub const fn bit(proto: u8) -> u8 {
+        match proto {
+            IPPROTO_TCP => TCP,
+            IPPROTO_UDP => UDP,
+            _ => 0,
+        }
+    }
+}
+
+/// Биты направлений в `settings::DIRECTIONS`.
+pub mod direction {
+    use crate::classify::Direction;
+
+    pub const INGRESS: u32 = 1 << 0;
+    pub const EGRESS: u32 = 1 << 1;
+    pub const BOTH: u32 = INGRESS | EGRESS;
+
+    /// Имена значений `--direction`.
+    pub const NAMES: [(&str, u32); 3] = [("ingress", INGRESS), ("egress", EGRESS), ("both", BOTH)];
+
+    /// Применяются ли правила с направлениями `directions` к пакетам направления
+    /// `direction`; 0 — только к входящим, как до появления настройки.
+    #[inline(always)]
+    pub const fn filters(directions: u32, direction: Direction) -> bool {
+        let directions = if directions == 0 { INGRESS } else { directions };
+        let bit = match direction {
+            Direction::Ingress => INGRESS,
+            Direction::Egress => EGRESS,
+        };
+        directions & bit != 0
+    }
+}
+
+/// Имя карты правил для протоколов IP (`--protocols`): ячейка — номер протокола, значение —
+/// одно из [`protocol_rule`].
+pub const PROTOCOLS_MAP: &str = "PROTOCOLS";
+
+/// Значения карты `PROTOCOLS` и имена протоколов IP в конфигурации.
+pub mod protocol_rule {
+    /// Протокол не упомянут: TCP, UDP и ICMP проверяются своими правилами, остальные
+    /// решаются по `settings::STRICT_PROTOCOLS`.
+    pub const UNLISTED: u8 = 0;
+    /// Разрешён: TCP, UDP и ICMP проверяются своими правилами, остальные пропускаются.
+    pub const ALLOW: u8 = 1;
+    /// Все пакеты протокола отбрасываются.
+    pub const DENY: u8 = 2;
+
+    /// Имена протоколов и их номера IANA; другие протоколы задаются номером.
+    pub const NAMES: [(&str, u8); 15] = [
+        ("icmp", 1),
+        ("igmp", 2),
+        ("ipip", 4),
+        ("tcp", 6),
+        ("udp", 17),
+        ("gre", 47),
+        ("esp", 50),
+        ("ah", 51),
+        ("icmpv6", 58),
+        ("ospf", 89),
+        ("pim", 103),
+        ("vrrp", 112),
+        ("l2tp", 115),
+        ("sctp", 132),
+        ("udplite", 136),
+    ];
+
+    /// Номер протокола по имени из [`NAMES`] или по номеру (`47`).
+    pub fn from_name(name: &str) -> Option<u8> {
+        let named = NAMES.iter().find(|(n, _)| *n == name).map(|&(_, proto)| proto);
+        named.or_else(|| name.parse().ok())
+    }
+
+    /// Имя протокола для вывода; `None`, если его нет в [`NAMES`].
+    pub fn name(proto: u8) -> Option<&'static str> {
+        NAMES.iter().find(|&&(_, p)| p == proto).map(|&(name, _)| name)
+    }
+}
+
+/// Имя LPM-карты доверенных префиксов, трафик из которых пропускается без проверки правил.
+///
+/// Ключ — длина префикса и адрес в сетевом порядке байт, как требует `BPF_MAP_TYPE_LPM_TRIE`.
+pub const FAST_ACCEPT_MAP: &str = "FAST_ACCEPT";
+
+/// Имя LPM-карты защищаемых адресов назначения (`--protected-ips`): пока
+/// `settings::PROTECTED_IPS` не ноль, правила применяются только к пакетам для них. Ключ —
+/// как в [`FAST_ACCEPT_MAP`].
+pub const PROTECTED_IPS_MAP: &str = "PROTECTED_IPS";
+
+/// Имя карты заблокированных MAC-адресов источника (`--blocked-macs`), ключ — 6 байт адреса
+/// в порядке кадра. Проверяется до разбора IP, пока `settings::MAC_FILTER` не ноль.
+pub const BLOCKED_MACS_MAP: &str = "BLOCKED_MACS";
+
+/// Разбирает MAC-адрес вида `xx:xx:xx:xx:xx:xx` (шестнадцатеричные цифры в любом регистре).
+pub fn parse_mac(text: &str) -> Option<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    let mut groups = text.split(':');
+    for byte in &mut mac {
+        let group = groups.next()?;
+        if group.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(group, 16).ok()?;
+    }
+    groups.next().is_none().then_some(mac)
+}
+
+/// Имя LPM-карты составных правил «адрес:порт» (`--blocked-endpoints`).
+///
+/// Данные ключа — [`endpoint_key`]: сначала порт, потом адрес, поэтому длина префикса
+/// равна 16 плюс длина префикса сети.
+pub const BLOCKED_ENDPOINTS_MAP: &str = "BLOCKED_ENDPOINTS";
+
+/// Данные ключа `BLOCKED_ENDPOINTS`: порт назначения и адрес в сетевом порядке байт.
+pub const fn endpoint_key(addr: u32, port: u16) -> [u8; 6] {
+    let port = port.to_be_bytes();
+    let addr = addr.to_be_bytes();
+    [port[0], port[1], addr[0], addr[1], addr[2], addr[3]]
+}
+
+/// Имя карты портов назначения, пропущенный трафик на которые уходит в сокеты AF_XDP
+/// (`--xsk-redirect-ports`).
+pub const XSK_PORTS_MAP: &str = "XSK_PORTS";
+
+/// Имя закреплённой карты сокетов AF_XDP (`BPF_MAP_TYPE_XSKMAP`), ключ — номер очереди RX.
+///
+/// Карту заполняет приложение, которому перенаправляется трафик: оно открывает её в
+/// [`PIN_PATH`] и записывает дескриптор своего сокета для каждой очереди. Пока сокета для
+/// очереди нет, пакеты с неё проходят в стек как обычно.
+pub const XSKS_MAP: &str = "XSKS";
+
+/// Наибольшее число очередей RX, для которых можно зарегистрировать сокет в `XSKS`.
+pub const MAX_XSK_QUEUES: u32 = 64;
+
+/// Имя кольцевого буфера событий об отброшенных пакетах.
+pub const EVENTS_MAP: &str = "EVENTS";
+
+/// Unix-сокет, через который загрузчик раздаёт события об отброшенных пакетах.
+///
+/// После подключения клиент получает `u32` (little-endian) — число недавних событий, затем
+/// сами эти события и дальше новые по мере появления, каждое как полные байты [`DropEvent`]
+/// (незапрошенные необязательные поля равны нулю).
+pub const EVENTS_SOCKET: &str = "/run/firewall/events.sock";
+
+/// Имя массива настроек, которые загрузчик передаёт программе XDP.
+pub const SETTINGS_MAP: &str = "SETTINGS";
+
+/// Индексы значений в массиве `SETTINGS`.
+pub mod settings {
+    /// Каждое N-е событие об отброшенном пакете попадает в кольцевой буфер (0 — выключено).
+    pub const EVENT_SAMPLE_RATE: u32 = 0;
+    /// Каждый N-й пакет логируется через `info!` (0 — выключено), с подробностью
+    /// [`LOG_LEVEL`].
+    pub const LOG_SAMPLE_RATE: u32 = 1;
+    /// Режим работы программы, одно из значений [`super::mode`].
+    pub const MODE: u32 = 2;
+    /// 1 — проверять TCP-пакеты по карте `TCP_WINDOWS`.
+    pub const TCP_WINDOW_FILTER: u32 = 3;
+    /// 1 — вести таблицу потоков `FLOWS` для экспорта NetFlow.
+    pub const FLOW_TRACKING: u32 = 4;
+    /// Какие необязательные поля заполнять в событиях, маска из [`super::event_fields`].
+    pub const EVENT_FIELDS: u32 = 5;
+    /// 1 — сверять источник с картой `FAST_ACCEPT` до всех остальных проверок.
+    pub const FAST_ACCEPT: u32 = 6;
+    /// 1 — снимать внешний заголовок IP-in-IP (протокол 4) и проверять внутренний пакет.
+    pub const UNWRAP_IPIP: u32 = 7;
+    /// С каким адресом сравниваются правила `BLOCKED_ENDPOINTS`: 0 — правил нет,
+    /// 1 — с адресом источника, 2 — с адресом назначения (порт всегда назначения).
+    pub const ENDPOINT_MATCH: u32 = 8;
+    /// 1 — искать адрес источника в карте `REGIONS`.
+    pub const REGION_MATCH: u32 = 9;
+    /// Каждый N-й пропущенный пакет попадает в кольцевой буфер как событие
+    /// [`super::DropReason::Allowed`] (0 — выключено, `--log-allows`).
+    pub const ALLOW_SAMPLE_RATE: u32 = 10;
+    /// Сколько правил занято в `BLOCKED_MASKS` (0 — проверка выключена).
+    pub const BLOCKED_MASKS: u32 = 11;
+    /// 1 — перенаправлять пропущенный трафик на порты из `XSK_PORTS` в `XSKS`.
+    pub const XSK_REDIRECT: u32 = 12;
+    /// 1 — замерять время проверки правил в `RULE_COSTS` (`firewall-cli profile`).
+    pub const PROFILE: u32 = 13;
+    /// 1 — сравнивать `ALLOWED_PORTS` с портом источника, 0 — с портом назначения.
+    pub const PORT_MATCH: u32 = 14;
+    /// 1 — пропускать эхо-запросы ICMP и ICMPv6 (`--allow-icmp-echo`).
+    pub const ICMP_ECHO: u32 = 15;
+    /// Пакетов в секунду с одного источника, пополнение `RATE_BUCKETS` (0 — без ограничения).
+    pub const RATE_LIMIT: u32 = 16;
+    /// Ёмкость ведра `RATE_BUCKETS` в пакетах: сколько можно отправить подряд.
+    pub const RATE_BURST: u32 = 17;
+    /// Пакетов SYN без ACK в секунду с одного источника, `SYN_BUCKETS` (0 — без ограничения).
+    pub const SYN_RATE_LIMIT: u32 = 18;
+    /// Ёмкость ведра `SYN_BUCKETS` в пакетах.
+    pub const SYN_RATE_BURST: u32 = 19;
+    /// 1 — отбрасывать пакеты TCP с недопустимым набором флагов (NULL, FIN, XMAS); загрузчик
+    /// включает проверку, если не задан `--allow-invalid-tcp-flags`.
+    pub const TCP_FLAG_FILTER: u32 = 20;
+    /// Политика по умолчанию: 0 — `deny`, 1 — `allow` (см. `classify::decide`).
+    pub const DEFAULT_POLICY: u32 = 21;
+    /// 1 — протоколы, которые правила не разбирают (не TCP, UDP и ICMP), решаются политикой
+    /// по умолчанию; 0 — такие пакеты пропускаются (`--strict-protocols`).
+    pub const STRICT_PROTOCOLS: u32 = 22;
+    /// Когда действуют блокировки стран: окно [`super::time_window`] (0 — всегда).
+    pub const COUNTRY_WINDOW: u32 = 23;
+    /// Минута местного времени от полуночи. Своих часов у программы нет
+    /// (`bpf_ktime_get_ns` монотонен), значение раз в несколько секунд пишет загрузчик.
+    pub const CLOCK_MINUTE: u32 = 24;
+    /// 1 — искать MAC-адрес источника в карте `BLOCKED_MACS` (`--blocked-macs`).
+    pub const MAC_FILTER: u32 = 25;
+    /// Что логируется через `info!`, одно из значений [`super::log_level`].
+    pub const LOG_LEVEL: u32 = 26;
+    /// 1 — широковещательные и групповые кадры проверяются правилами, как остальные;
+    /// 0 — пропускаются сразу, чтобы не ломать DHCP, mDNS и другие службы L2
+    /// (`--filter-multicast`).
+    pub const FILTER_MULTICAST: u32 = 27;
+    /// 1 — отбрасывать все фрагменты IPv4, и первые, и следующие (`--drop-fragments`).
+    pub const DROP_FRAGMENTS: u32 = 28;
+    /// Сеть `--arp-subnet`: адрес в порядке байт хоста и длина префикса. Длина 0 — ARP
+    /// пропускается от любого отправителя.
+    pub const ARP_SUBNET: u32 = 29;
+    pub const ARP_PREFIX: u32 = 30;
+    /// 1 — пакеты TCP и UDP с портом 53 в источнике или назначении пропускаются после
+    /// блокировок, не доходя до разрешённых портов; 0 — `--strict-dns`.
+    pub const ALLOW_DNS: u32 = 31;
+    /// Сколько секунд без пакетов живёт запись `CONNTRACK` (`--conntrack-timeout`);
+    /// 0 — таблица соединений не ведётся.
+    pub const CONNTRACK_TIMEOUT: u32 = 32;
+    /// До какой секунды CLOCK_MONOTONIC (шкала `bpf_ktime_get_ns`) соединения из
+    /// `ACCEPTED_CONNS` проходят к портам, разрешение которых снято перезагрузкой с
+    /// `grace-period`; 0 — окна нет.
+    pub const GRACE_UNTIL: u32 = 33;
+    /// 1 — режим замера (`--fast-path`): программа только решает судьбу пакета, без
+    /// логирования, событий и счётчиков, включая `STATS`.
+    pub const FAST_PATH: u32 = 34;
+    /// 1 — правила применяются только к пакетам, адрес назначения которых есть в карте
+    /// `PROTECTED_IPS`, остальные пропускаются; 0 — ко всем пакетам.
+    pub const PROTECTED_IPS: u32 = 35;
+    /// Что программа делает с пакетом, который решено отбросить, одно из значений
+    /// [`super::block_action`] (`--block-action`).
+    pub const BLOCK_ACTION: u32 = 36;
+    /// 1 — аварийный режим (`firewall-cli panic`): отбрасывается всё, кроме ARP и пакетов
+    /// IPv4 из префиксов `FAST_ACCEPT`, даже на паузе и в пробном режиме; остальные правила
+    /// не проверяются. Загрузчик не включает его никогда.
+    pub const PANIC: u32 = 37;
+    /// Порт назначения, закрытый, пока источник не простучит последовательность
+    /// `KNOCK_SEQUENCE` (`--knock-port`); 0 — стук выключен.
+    pub const KNOCK_PORT: u32 = 38;
+    /// Сколько портов `KNOCK_SEQUENCE` занято.
+    pub const KNOCK_LEN: u32 = 39;
+    /// Сколько секунд без пакетов к порту за стуком он остаётся открытым для источника
+    /// (`--knock-timeout`).
+    pub const KNOCK_TIMEOUT: u32 = 40;
+    /// Направления, к которым применяются правила, биты [`super::direction`]
+    /// (`--direction`); 0 — только входящие.
+    pub const DIRECTIONS: u32 = 41;
+
+    /// Количество слотов в карте.
+    pub const LEN: u32 = 42;
+}
+
+/// Шаг счётчика выборки с частотой `rate` (`settings::EVENT_SAMPLE_RATE` и соседи):
+/// выбран ли пакет. Частота N — каждый N-й пакет, 0 — путь выключен и счётчик не растёт.
+#[inline(always)]
+pub fn sample_step(counter: &mut u32, rate: u32) -> bool {
+    if rate == 0 {
+        return false;
+    }
+    *counter += 1;
+    if *counter >= rate {
+        *counter = 0;
+        true
+    } else {
+        false
+    }
+}
+
+/// Значения настройки `settings::LOG_LEVEL`.
+pub mod log_level {
+    /// Программа не вызывает `info!` вовсе.
+    pub const NONE: u32 = 0;
+    /// Только сообщения об отброшенных пакетах.
+    pub const DROPS: u32 = 1;
+    /// Ещё разбор заголовков, страна, порт и пропущенные пакеты: на полной скорости линии
+    /// такой поток сообщений сам становится узким местом.
+    pub const VERBOSE: u32 = 2;
+}
+
+/// Биты настройки `settings::EVENT_FIELDS`: необязательные поля [`DropEvent`].
+pub mod event_fields {
+    pub const TTL: u8 = 1 << 0;
+    pub const TCP_FLAGS: u8 = 1 << 1;
+    pub const LENGTH: u8 = 1 << 2;
+    pub const VLAN: u8 = 1 << 3;
+
+    /// Имена полей в конфигурации и в командной строке.
+    pub const NAMES: [(&str, u8); 4] = [
+        ("ttl", TTL),
+        ("tcp-flags", TCP_FLAGS),
+        ("length", LENGTH),
+        ("vlan", VLAN),
+    ];
+
+    pub fn from_name(name: &str) -> Option<u8> {
+        NAMES.iter().find(|(n, _)| *n == name).map(|&(_, bit)| bit)
+    }
+}
+
+/// Окна времени суток вида `22:00-06:00`, в которые действует правило.
+///
+/// Окно хранится одним числом: старший бит — признак окна, дальше минута начала и минута
+/// конца от полуночи. Ноль означает «окна нет, правило действует всегда». Начало входит в
+/// окно, конец — нет; окно, у которого конец раньше начала, переходит через полночь.
+pub mod time_window {
+    pub const MINUTES_PER_DAY: u32 = 24 * 60;
+
+    const SET: u32 = 1 << 31;
+
+    /// Окно с `start` до `end`, минуты от полуночи.
+    pub const fn pack(start: u16, end: u16) -> u32 {
+        SET | ((start as u32) << 16) | end as u32
+    }
+
+    /// Начало и конец окна; `None`, если окна нет.
+    pub const fn bounds(window: u32) -> Option<(u16, u16)> {
+        if window & SET == 0 {
+            return None;
+        }
+        Some((((window >> 16) & 0x7fff) as u16, window as u16))
+    }
+
+    /// Попадает ли минута `minute` в окно `window`.
+    #[inline(always)]
+    pub fn contains(window: u32, minute: u32) -> bool {
+        let Some((start, end)) = bounds(window) else {
+            return true;
+        };
+        let (start, end) = (u32::from(start), u32::from(end));
+        if start <= end {
+            start <= minute && minute < end
+        } else {
+            minute >= start || minute < end
+        }
+    }
+
+    /// Разбирает `HH:MM` в минуты от полуночи.
+    pub fn parse_time(text: &str) -> Option<u16> {
+        let (hours, minutes) = text.split_once(':')?;
+        if hours.is_empty() || hours.len() > 2 || minutes.len() != 2 {
+            return None;
+        }
+        let hours: u16 = hours.parse().ok()?;
+        let minutes: u16 = minutes.parse().ok()?;
+        (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+    }
+
+    /// Разбирает окно `HH:MM-HH:MM`; пустое окно (начало равно концу) не принимается.
+    pub fn parse(text: &str) -> Option<u32> {
+        let (start, end) = text.split_once('-')?;
+        let (start, end) = (parse_time(start.trim())?, parse_time(end.trim())?);
+        (start != end).then_some(pack(start, end))
+    }
+
+    /// Текущая минута местного времени от полуночи.
+    #[cfg(feature = "user")]
+    pub fn local_minute() -> u32 {
+        let now = unsafe { libc::time(core::ptr::null_mut()) };
+        let mut tm = unsafe { core::mem::zeroed::<libc::tm>() };
+        if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+            return 0;
+        }
+        (tm.tm_hour * 60 + tm.tm_min) as u32
+    }
+}
+
+/// Значения настройки `settings::MODE`.
+pub mod mode {
+    /// Обычная работа: правила применяются.
+    pub const ENFORCE: u32 = 0;
+    /// Пассивный мониторинг: правила не проверяются, пакеты только считаются.
+    pub const COUNT_ONLY: u32 = 1;
+    /// Фильтрация приостановлена `firewall-cli pause`: как `COUNT_ONLY`, но до `resume`.
+    pub const PAUSED: u32 = 2;
+    /// Пробный режим (`--dry-run`): правила проверяются, о пакетах, которые были бы
+    /// отброшены, приходят события с [`super::event_flags::DRY_RUN`], но проходят все пакеты.
+    pub const DRY_RUN: u32 = 3;
+
+    /// Пропускается ли в режиме `mode` весь трафик без проверки правил.
+    pub const fn passes_all(mode: u32) -> bool {
+        mode == COUNT_ONLY || mode == PAUSED
+    }
+}
+
+/// Значения настройки `settings::BLOCK_ACTION`. Отброшенным пакет считается при любом
+/// значении: в `STATS` он попадает в `DROP`, в `DROP_REASONS` — под своей причиной.
+pub mod block_action {
+    /// XDP_DROP: пакет молча отбрасывается.
+    pub const DROP: u32 = 0;
+    /// XDP_ABORTED: пакет отбрасывается с событием трассировки `xdp:xdp_exception`.
+    pub const ABORT: u32 = 1;
+    /// XDP_TX: MAC-адреса кадра меняются местами, и он уходит обратно в тот же интерфейс.
+    pub const TX: u32 = 2;
+}
+
+/// Биты [`DropEvent::flags`].
+pub mod event_flags {
+    /// Пакет не отброшен, а только был бы отброшен: программа в режиме `--dry-run`.
+    pub const DRY_RUN: u8 = 1 << 0;
+}
+
+/// Причина, по которой программа отбросила пакет.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    /// Порт не входит в список разрешённых.
+    PortNotAllowed = 1,
+    /// Протокол транспортного уровня не поддерживается.
+    UnsupportedProtocol = 2,
+    /// Размер окна TCP совпал с сигнатурой из `block-tcp-window`.
+    TcpWindow = 3,
+    /// Адрес источника есть в `BLOCKED_IPS` или входит в сеть из `BLOCKED_NETS`.
+    BlockedIp = 4,
+    /// Страна источника есть в `BLOCKED_COUNTRIES`, а адрес не входит в `ALLOWED_IPS`.
+    BlockedCountry = 5,
+    /// Адрес и порт назначения совпали с правилом из `BLOCKED_ENDPOINTS`.
+    BlockedEndpoint = 6,
+    /// Внешний классификатор записал в `VERDICT_OVERRIDES` вердикт «отбросить».
+    Override = 7,
+    /// Сеть источника есть в `REGIONS`, а адрес не входит в `ALLOWED_IPS`.
+    BlockedRegion = 8,
+    /// Не отбрасывание: пакет пропущен, событие отправлено по `--log-allows`.
+    Allowed = 9,
+    /// Адрес источника совпал с правилом из `BLOCKED_MASKS`.
+    BlockedMask = 10,
+    /// Сообщение ICMP или ICMPv6 типа, который не пропускается (см. `classify::decide_icmp`).
+    Icmp = 11,
+    /// Источник превысил `--rate-limit`: его ведро в `RATE_BUCKETS` пусто.
+    RateLimited = 12,
+    /// Источник превысил `--syn-rate-limit`: слишком много новых соединений TCP.
+    SynFlood = 13,
+    /// Пакет TCP без единого флага: сканирование NULL.
+    NullScan = 14,
+    /// Пакет TCP с одним FIN, без ACK: сканирование FIN.
+    FinScan = 15,
+    /// Пакет TCP с FIN, PSH и URG: сканирование XMAS.
+    XmasScan = 16,
+    /// MAC-адрес источника есть в `BLOCKED_MACS`.
+    BlockedMac = 17,
+    /// Фрагмент IPv4 при `--drop-fragments`.
+    Fragment = 18,
+    /// Пакет ARP с адресом отправителя вне сети `--arp-subnet`.
+    Arp = 19,
+    /// Порт источника есть в `BLOCKED_SRC_PORTS`.
+    BlockedSrcPort = 20,
+    /// Аварийный режим `settings::PANIC`, источник не входит в `FAST_ACCEPT`.
+    Panic = 21,
+    /// Протокол запрещён в `PROTOCOLS` (`--protocols`).
+    DeniedProtocol = 22,
+    /// Пакет к порту за стуком (`--knock-port`) от источника, который не простучал
+    /// последовательность `KNOCK_SEQUENCE`.
+    KnockLocked = 23,
+}
+
+/// Имя per-CPU массива отброшенных пакетов по причинам: ячейка — код [`DropReason`], в
+/// пробном режиме (`--dry-run`) считаются пакеты, которые были бы отброшены.
+pub const DROP_REASONS_MAP: &str = "DROP_REASONS";
+
+impl DropReason {
+    /// Ячеек в `DROP_REASONS`: коды идут подряд с единицы, ячейка 0 не используется.
+    pub const SLOTS: u32 = Self::ALL.len() as u32 + 1;
+
+    /// Все причины в порядке кодов.
+    pub const ALL: [DropReason; 23] = [
+        Self::PortNotAllowed,
+        Self::UnsupportedProtocol,
+        Self::TcpWindow,
+        Self::BlockedIp,
+        Self::BlockedCountry,
+        Self::BlockedEndpoint,
+        Self::Override,
+        Self::BlockedRegion,
+        Self::Allowed,
+        Self::BlockedMask,
+        Self::Icmp,
+        Self::RateLimited,
+        Self::SynFlood,
+        Self::NullScan,
+        Self::FinScan,
+        Self::XmasScan,
+        Self::BlockedMac,
+        Self::Fragment,
+        Self::Arp,
+        Self::BlockedSrcPort,
+        Self::Panic,
+        Self::DeniedProtocol,
+        Self::KnockLocked,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::PortNotAllowed),
+            2 => Some(Self::UnsupportedProtocol),
+            3 => Some(Self::TcpWindow),
+            4 => Some(Self::BlockedIp),
+            5 => Some(Self::BlockedCountry),
+            6 => Some(Self::BlockedEndpoint),
+            7 => Some(Self::Override),
+            8 => Some(Self::BlockedRegion),
+            9 => Some(Self::Allowed),
+            10 => Some(Self::BlockedMask),
+            11 => Some(Self::Icmp),
+            12 => Some(Self::RateLimited),
+            13 => Some(Self::SynFlood),
+            14 => Some(Self::NullScan),
+            15 => Some(Self::FinScan),
+            16 => Some(Self::XmasScan),
+            17 => Some(Self::BlockedMac),
+            18 => Some(Self::Fragment),
+            19 => Some(Self::Arp),
+            20 => Some(Self::BlockedSrcPort),
+            21 => Some(Self::Panic),
+            22 => Some(Self::DeniedProtocol),
+            23 => Some(Self::KnockLocked),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::PortNotAllowed => "port-not-allowed",
+            Self::UnsupportedProtocol => "unsupported-protocol",
+            Self::TcpWindow => "tcp-window",
+            Self::BlockedIp => "blocked-ip",
+            Self::BlockedCountry => "blocked-country",
+            Self::BlockedEndpoint => "blocked-endpoint",
+            Self::Override => "override",
+            Self::BlockedRegion => "blocked-region",
+            Self::Allowed => "allowed",
+            Self::BlockedMask => "blocked-mask",
+            Self::Icmp => "icmp",
+            Self::RateLimited => "rate-limited",
+            Self::SynFlood => "syn-flood",
+            Self::NullScan => "null-scan",
+            Self::FinScan => "fin-scan",
+            Self::XmasScan => "xmas-scan",
+            Self::BlockedMac => "blocked-mac",
+            Self::Fragment => "fragment",
+            Self::Arp => "arp",
+            Self::BlockedSrcPort => "blocked-src-port",
+            Self::Panic => "panic",
+            Self::DeniedProtocol => "denied-protocol",
+            Self::KnockLocked => "knock-locked",
+        }
+    }
+}
+
+/// Событие об отброшенном пакете, которое программа кладёт в `EVENTS`; с `--log-allows`
+/// так же описываются и пропущенные пакеты (причина [`DropReason::Allowed`]).
+///
+/// Адреса и порты хранятся в порядке байт хоста. Если необязательные поля не запрошены и
+/// флагов нет, программа отправляет только первые [`DropEvent::CORE_LEN`] байт: 5-кортеж и
+/// причину.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DropEvent {
+    pub src_addr: u32,
+    pub dst_addr: u32,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub proto: u8,
+    /// Код [`DropReason`].
+    pub reason: u8,
+    /// Страна источника, [`pack_country`]; 0 — неизвестна.
+    pub country: u16,
+    /// Какие из полей ниже заполнены, биты [`event_fields`].
+    pub fields: u8,
+    pub ttl: u8,
+    /// Флаги TCP; 0, если пакет отброшен раньше разбора заголовка TCP.
+    pub tcp_flags: u8,
+    /// Признаки события, биты [`event_flags`].
+    pub flags: u8,
+    /// Длина кадра в байтах.
+    pub len: u16,
+    /// Идентификатор VLAN; 0 — кадр без тега.
+    pub vlan: u16,
+}
+
+impl DropEvent {
+    /// Размер обязательной части события.
+    pub const CORE_LEN: usize = 16;
+
+    /// Восстанавливает событие из записи кольцевого буфера, возможно укороченной.
+    #[cfg(feature = "user")]
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::CORE_LEN {
+            return None;
+        }
+        let mut event = Self::default();
+        let len = bytes.len().min(core::mem::size_of::<Self>());
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                (&mut event as *mut Self).cast::<u8>(),
+                len,
+            );
+        }
+        Some(event)
+    }
+
+    /// Байты события целиком, как они передаются через `EVENTS_SOCKET`.
+    #[cfg(feature = "user")]
+    pub fn as_bytes(&self) -> &[u8] {
+        let len = core::mem::size_of::<Self>();
+        unsafe { core::slice::from_raw_parts((self as *const Self).cast::<u8>(), len) }
+    }
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for DropEvent {}
+
+/// Имя таблицы потоков, которую загрузчик выгружает в NetFlow.
+pub const FLOWS_MAP: &str = "FLOWS";
+
+/// Имя карты вердиктов внешнего классификатора, закрепляется в `PIN_PATH`.
+///
+/// Через неё сторонняя программа eBPF или агент в пространстве пользователя (например, IDS)
+/// навязывает решение, не меняя файрволл. Ключ — [`FlowKey`] пакета (после снятия IP-in-IP),
+/// значение — один байт из [`verdict_override`]. Вердикт проверяется первым и сильнее всех
+/// правил конфигурации, включая доверенные префиксы; в режимах только подсчёта и паузы
+/// не применяется. Записи не устаревают: их удаляет тот, кто добавил.
+pub const VERDICT_OVERRIDES_MAP: &str = "VERDICT_OVERRIDES";
+
+/// Значения карты `VERDICT_OVERRIDES`; прочие значения игнорируются.
+pub mod verdict_override {
+    pub const PASS: u8 = 1;
+    pub const DROP: u8 = 2;
+}
+
+/// Ключ потока: 5-кортеж в порядке байт хоста (порты 0 для протоколов без портов).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    pub src_addr: u32,
+    pub dst_addr: u32,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub proto: u8,
+    pub _pad: [u8; 3],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for FlowKey {}
+
+/// Накопленные счётчики потока. Время — `bpf_ktime_get_ns`, то есть CLOCK_MONOTONIC.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FlowStats {
+    pub packets: u64,
+    pub bytes: u64,
+    pub first_seen_ns: u64,
+    pub last_seen_ns: u64,
+    /// Объединение (OR) флагов TCP всех пакетов потока.
+    pub tcp_flags: u8,
+    pub _pad: [u8; 7],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for FlowStats {}
+
+/// Имя LRU-таблицы соединений TCP, которые открыл сам хост. Значение — время последнего
+/// пакета соединения, `bpf_ktime_get_ns`.
+///
+/// Запись добавляет программа на отправке (TC egress), увидев исходящий SYN; входящие пакеты
+/// с тем же 4-кортежем проходят без разрешённого порта, см. `classify::Rules::is_established`.
+pub const CONNTRACK_MAP: &str = "CONNTRACK";
+
+/// Ключ `CONNTRACK`: 4-кортеж входящего пакета соединения в порядке байт хоста, то есть
+/// удалённый адрес и порт в полях источника.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ConnKey {
+    pub src_addr: u32,
+    pub dst_addr: u32,
+    pub src_port: u16,
+    pub dst_port: u16,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ConnKey {}
+
+impl ConnKey {
+    /// Ключ ответов на исходящий пакет с такими адресами и портами.
+    pub const fn reply(src_addr: u32, dst_addr: u32, src_port: u16, dst_port: u16) -> Self {
+        Self {
+            src_addr: dst_addr,
+            dst_addr: src_addr,
+            src_port: dst_port,
+            dst_port: src_port,
+        }
+    }
+}
+
+/// Жива ли запись `CONNTRACK`, последний пакет которой был в `last_seen_ns`, при сроке
+/// `timeout_secs` секунд.
+#[inline(always)]
+pub const fn conntrack_alive(last_seen_ns: u64, now_ns: u64, timeout_secs: u32) -> bool {
+    now_ns.wrapping_sub(last_seen_ns) <= timeout_secs as u64 * 1_000_000_000
+}
+
+/// Имя LRU-таблицы входящих соединений TCP, принятых разрешённым портом, пока ведётся
+/// `CONNTRACK`: ключ — [`ConnKey`] входящего пакета, значение — время последнего пакета,
+/// `bpf_ktime_get_ns`.
+///
+/// По ней соединения к порту, разрешение которого снято, доживают окно
+/// `settings::GRACE_UNTIL`, см. [`grace_alive`].
+pub const ACCEPTED_CONNS_MAP: &str = "ACCEPTED_CONNS";
+
+/// Пропускается ли пакет соединения из `ACCEPTED_CONNS`, последний пакет которого был в
+/// `last_seen_ns`: окно `grace_until_secs` (секунды CLOCK_MONOTONIC, 0 — окна нет) ещё не
+/// закрылось, а сама запись жива при сроке `timeout_secs`.
+#[inline(always)]
+pub const fn grace_alive(
+    last_seen_ns: u64,
+    now_ns: u64,
+    timeout_secs: u32,
+    grace_until_secs: u32,
+) -> bool {
+    grace_until_secs != 0
+        && now_ns < grace_until_secs as u64 * 1_000_000_000
+        && conntrack_alive(last_seen_ns, now_ns, timeout_secs)
+}
+
+/// Имя карты последовательности стука (`--knock-sequence`): ячейка — номер шага, значение —
+/// порт назначения. Занято `settings::KNOCK_LEN` первых ячеек.
+pub const KNOCK_SEQUENCE_MAP: &str = "KNOCK_SEQUENCE";
+
+/// Наибольшая длина последовательности стука.
+pub const MAX_KNOCK_PORTS: u32 = 8;
+
+/// За сколько секунд источник должен простучать следующий порт последовательности, иначе
+/// стук начинается заново.
+pub const KNOCK_STEP_SECS: u32 = 10;
+
+/// Имя закреплённой LRU-таблицы источников, которые простучали последовательность: ключ —
+/// адрес в порядке байт хоста, значение — время последнего пакета к порту за стуком,
+/// `bpf_ktime_get_ns`. Запись, как и `CONNTRACK`, живёт `settings::KNOCK_TIMEOUT` секунд
+/// после этого пакета, см. [`conntrack_alive`].
+pub const KNOCK_UNLOCKED_MAP: &str = "KNOCK_UNLOCKED";
+
+/// Имя LRU-таблицы продвижения источников по последовательности стука, [`KnockProgress`]
+/// по адресу в порядке байт хоста.
+pub const KNOCK_PROGRESS_MAP: &str = "KNOCK_PROGRESS";
+
+/// Продвижение источника по последовательности стука в `KNOCK_PROGRESS`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KnockProgress {
+    /// Сколько портов последовательности уже простучано.
+    pub step: u32,
+    pub _pad: u32,
+    /// Время последнего засчитанного порта, `bpf_ktime_get_ns`.
+    pub at_ns: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for KnockProgress {}
+
+/// Сколько портов последовательности простучано после пакета к порту `port`, если до него
+/// было простучано `step`; `at` — порт последовательности по номеру шага.
+///
+/// Повтор последнего засчитанного порта (повторная отправка SYN) шага не меняет. Неверный
+/// порт начинает стук заново, но если это первый порт последовательности, он засчитывается.
+#[inline(always)]
+pub fn knock_next(step: u32, port: u16, at: impl Fn(u32) -> Option<u16>) -> u32 {
+    if at(step) == Some(port) {
+        step + 1
+    } else if step > 0 && at(step - 1) == Some(port) {
+        step
+    } else if at(0) == Some(port) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Имена LRU-таблиц вёдер токенов `--rate-limit` и `--syn-rate-limit`: [`RateState`] по
+/// адресу источника IPv4 в порядке байт хоста.
+pub const RATE_BUCKETS_MAP: &str = "RATE_BUCKETS";
+pub const SYN_BUCKETS_MAP: &str = "SYN_BUCKETS";
+
+/// Ведро токенов одного источника в `RATE_BUCKETS`.
+///
+/// Токены хранятся в миллиардных долях пакета, чтобы пополнять ведро за каждую наносекунду
+/// без дробей: один пакет стоит [`RateState::PACKET`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RateState {
+    pub tokens: u64,
+    /// Время последнего пополнения, `bpf_ktime_get_ns`.
+    pub last_ns: u64,
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for RateState {}
+
+impl RateState {
+    /// Цена одного пакета в токенах.
+    pub const PACKET: u64 = 1_000_000_000;
+    /// Наибольшая ёмкость ведра (`--rate-burst`): при ней токены не переполняют `u64`.
+    pub const MAX_BURST: u32 = 1_000_000;
+
+    /// Полное ведро нового источника.
+    pub const fn full(now_ns: u64, burst: u32) -> Self {
+        Self {
+            tokens: burst as u64 * Self::PACKET,
+            last_ns: now_ns,
+        }
+    }
+
+    /// Пополняет ведро за время с прошлого пакета со скоростью `rate` пакетов в секунду и
+    /// забирает токен на текущий пакет; `false` — токенов не хватило.
+    #[inline(always)]
+    pub fn take(&mut self, now_ns: u64, rate: u32, burst: u32) -> bool {
+        let capacity = u64::from(burst) * Self::PACKET;
+        let rate = u64::from(rate).max(1);
+        // Дольше, чем нужно на пополнение пустого ведра, ждать незачем; заодно произведение
+        // ниже не переполняется.
+        let elapsed = now_ns.saturating_sub(self.last_ns).min(capacity / rate);
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.last_ns = now_ns;
+        if self.tokens < Self::PACKET {
+            return false;
+        }
+        self.tokens -= Self::PACKET;
+        true
+    }
+}
+
+/// Функция определения "страны" по первому октету IP-адреса.
+///
+/// Это упрощённая демонстрационная логика, где для разных значений
+/// первого октета возвращаются различные коды стран. Настоящая страна берётся из базы
+/// `--country-db` (карта `COUNTRIES`), а эта функция остаётся запасной для адресов вне базы.
+pub fn lookup_country(src_ip: u32) -> &'static str {
+    let first_octet = (src_ip >> 24) as u8;
+    match first_octet {
+        1  => "US", // Соединённые Штаты
+        2  => "CA", // Канада
+        3  => "MX", // Мексика
+        4  => "BR", // Бразилия
+        5  => "RU", // Россия
+        6  => "CN", // Китай
+        7  => "IN", // Индия
+        8  => "GB", // Великобритания
+        9  => "DE", // Германия
+        10 => "FR", // Франция
+        11 => "ES", // Испания
+        12 => "IT", // Италия
+        13 => "AU", // Австралия
+        14 => "JP", // Япония
+        15 => "KR", // Южная Корея
+        16 => "SE", // Швеция
+        17 => "NO", // Норвегия
+        18 => "FI", // Финляндия
+        19 => "DK", // Дания
+        20 => "NL", // Нидерланды
+        21 => "BE", // Бельгия
+        22 => "CH", // Швейцария
+        23 => "AT", // Австрия
+        24 => "PL", // Польша
+        25 => "CZ", // Чехия
+        26 => "SK", // Словакия
+        27 => "HU", // Венгрия
+        28 => "RO", // Румыния
+        29 => "BG", // Болгария
+        30 => "TR", // Турция
+        // Можно добавить остальные необходимые страны.
+        _  => "OTHER",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    #[test]
+    fn each_path_samples_at_its_own_rate() {
+        let (mut events, mut logs, mut allows) = (0, 0, 0);
+        let (mut event_hits, mut log_hits, mut allow_hits) = (0, 0, 0);
+        for _ in 0..12 {
+            event_hits += usize::from(sample_step(&mut events, 3));
+            log_hits += usize::from(sample_step(&mut logs, 4));
+            allow_hits += usize::from(sample_step(&mut allows, 0));
+        }
+        assert_eq!((event_hits, log_hits, allow_hits), (4, 3, 0));
+        assert_eq!((events, logs, allows), (0, 0, 0));
+        assert!(sample_step(&mut 0, 1));
+        // Смена частоты одного пути не сдвигает другой.
+        let mut logs = 3;
+        assert!(!sample_step(&mut events, 2));
+        assert!(sample_step(&mut logs, 4));
+    }
+
+    #[test]
+    fn mask_matches_last_octet_across_subnets() {
+        let addr = |a: u8, b: u8, c: u8, d: u8| u32::from_be_bytes([a, b, c, d]);
+        let rule = MaskedAddr::new(addr(0, 0, 0, 100), addr(0, 0, 0, 255));
+        for hit in [addr(10, 0, 0, 100), addr(192, 168, 7, 100), addr(203, 0, 113, 100)] {
+            assert!(rule.matches(hit));
+        }
+        for miss in [addr(10, 0, 0, 101), addr(10, 0, 100, 1), addr(100, 0, 0, 0)] {
+            assert!(!rule.matches(miss));
+        }
+        // Биты адреса вне маски отбрасываются при разборе правила.
+        assert_eq!(MaskedAddr::new(addr(10, 20, 30, 100), addr(0, 0, 0, 255)), rule);
+    }
+}
@@ -0,0 +1,281 @@
+//! Проверка возможностей ядра через прямые вызовы `bpf(2)`.
+//!
+//! aya не умеет спрашивать ядро о поддержке отдельных типов программ и карт, поэтому
+//! пробы создают минимальный объект нужного типа и сразу его закрывают. Здесь же живут
+//! пакетное обновление карт и прогон кадра через загруженную программу, которых в aya тоже
+//! нет.
+
+use std::{
+    io, mem,
+    os::fd::{AsRawFd as _, BorrowedFd},
+    path::Path,
+};
+
+use aya::{maps::MapType, programs::ProgramType, Pod};
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_PROG_TEST_RUN: libc::c_long = 10;
+const BPF_MAP_UPDATE_BATCH: libc::c_long = 26;
+const BPF_F_NO_PREALLOC: u32 = 1;
+
+/// Путь, по которому ядро публикует собственный BTF.
+pub const VMLINUX_BTF: &str = "/sys/kernel/btf/vmlinux";
+
+/// Результат одной пробы.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Support {
+    Supported,
+    /// Ядро отказало, `errno` указывает причину.
+    Unsupported(i32),
+    /// Недостаточно прав, чтобы проверить (нужен root или CAP_BPF).
+    PermissionDenied,
+}
+
+impl Support {
+    pub fn is_supported(self) -> bool {
+        self == Support::Supported
+    }
+
+    fn from_result(result: io::Result<libc::c_long>) -> Self {
+        match result {
+            Ok(fd) => {
+                unsafe { libc::close(fd as libc::c_int) };
+                Support::Supported
+            }
+            Err(e) => Support::from_error(&e),
+        }
+    }
+
+    fn from_error(e: &io::Error) -> Self {
+        match e.raw_os_error() {
+            Some(libc::EPERM) | Some(libc::EACCES) => Support::PermissionDenied,
+            Some(errno) => Support::Unsupported(errno),
+            None => Support::Unsupported(0),
+        }
+    }
+}
+
+/// Минимальное описание карты, достаточное для `BPF_MAP_CREATE`.
+#[repr(C)]
+#[derive(Default)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+/// Минимальное описание программы для `BPF_PROG_LOAD`.
+#[repr(C)]
+#[derive(Default)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+}
+
+/// Описание пакетной операции над картой (`BPF_MAP_*_BATCH`).
+#[repr(C)]
+#[derive(Default)]
+struct BatchAttr {
+    in_batch: u64,
+    out_batch: u64,
+    keys: u64,
+    values: u64,
+    count: u32,
+    map_fd: u32,
+    elem_flags: u64,
+    flags: u64,
+}
+
+/// Прогон программы над данными (`BPF_PROG_TEST_RUN`) без контекста и без копии
+/// результата.
+#[repr(C)]
+#[derive(Default)]
+struct TestRunAttr {
+    prog_fd: u32,
+    retval: u32,
+    data_size_in: u32,
+    data_size_out: u32,
+    data_in: u64,
+    data_out: u64,
+    repeat: u32,
+    duration: u32,
+}
+
+/// Размер `union bpf_attr`, который передаётся ядру целиком, с нулями в хвосте.
+const BPF_ATTR_SIZE: usize = 128;
+
+fn sys_bpf<T>(cmd: libc::c_long, attr: &T) -> io::Result<libc::c_long> {
+    let mut buf = [0u8; BPF_ATTR_SIZE];
+    let len = mem::size_of::<T>().min(BPF_ATTR_SIZE);
+    unsafe { std::ptr::copy_nonoverlapping(attr as *const T as *const u8, buf.as_mut_ptr(), len) };
+    bpf(cmd, &mut buf)
+}
+
+/// Как [`sys_bpf`], но то, что ядро записало в `union bpf_attr`, возвращается в `attr`.
+fn sys_bpf_out<T>(cmd: libc::c_long, attr: &mut T) -> io::Result<libc::c_long> {
+    let mut buf = [0u8; BPF_ATTR_SIZE];
+    let len = mem::size_of::<T>().min(BPF_ATTR_SIZE);
+    unsafe { std::ptr::copy_nonoverlapping(attr as *const T as *const u8, buf.as_mut_ptr(), len) };
+    let ret = bpf(cmd, &mut buf);
+    unsafe { std::ptr::copy_nonoverlapping(buf.as_ptr(), attr as *mut T as *mut u8, len) };
+    ret
+}
+
+fn bpf(cmd: libc::c_long, buf: &mut [u8; BPF_ATTR_SIZE]) -> io::Result<libc::c_long> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            buf.as_mut_ptr(),
+            BPF_ATTR_SIZE as libc::c_uint,
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Проверяет, что ядро умеет загружать программы XDP.
+pub fn probe_xdp() -> Support {
+    // r0 = XDP_PASS; exit
+    let insns: [u64; 2] = [
+        u64::from_le_bytes([0xb7, 0, 0, 0, 2, 0, 0, 0]),
+        u64::from_le_bytes([0x95, 0, 0, 0, 0, 0, 0, 0]),
+    ];
+    let license = c"GPL";
+    let attr = ProgLoadAttr {
+        prog_type: ProgramType::Xdp as u32,
+        insn_cnt: insns.len() as u32,
+        insns: insns.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        ..Default::default()
+    };
+    Support::from_result(sys_bpf(BPF_PROG_LOAD, &attr))
+}
+
+/// Проверяет, что ядро умеет создавать карты типа `map_type`.
+pub fn probe_map(map_type: MapType) -> Support {
+    let mut attr = MapCreateAttr {
+        map_type: map_type as u32,
+        key_size: 4,
+        value_size: 4,
+        max_entries: 1,
+        map_flags: 0,
+    };
+    match map_type {
+        MapType::LpmTrie => {
+            // Длина префикса (u32) плюс данные; LPM trie создаётся только с BPF_F_NO_PREALLOC.
+            attr.key_size = 8;
+            attr.map_flags = BPF_F_NO_PREALLOC;
+        }
+        MapType::RingBuf => {
+            attr.key_size = 0;
+            attr.value_size = 0;
+            attr.max_entries = page_size();
+        }
+        _ => {}
+    }
+    Support::from_result(sys_bpf(BPF_MAP_CREATE, &attr))
+}
+
+/// Записывает пары `keys[i]`/`values[i]` в карту одним вызовом `BPF_MAP_UPDATE_BATCH`.
+///
+/// Ядра до 5.6 возвращают `EINVAL`; проверить заранее можно через [`probe_map_batch`].
+pub fn update_batch<K: Pod, V: Pod>(
+    fd: BorrowedFd<'_>,
+    keys: &[K],
+    values: &[V],
+) -> io::Result<()> {
+    let count = keys.len().min(values.len());
+    let attr = BatchAttr {
+        keys: keys.as_ptr() as u64,
+        values: values.as_ptr() as u64,
+        count: count as u32,
+        map_fd: fd.as_raw_fd() as u32,
+        ..Default::default()
+    };
+    sys_bpf(BPF_MAP_UPDATE_BATCH, &attr).map(drop)
+}
+
+/// Один раз выполняет программу `fd` над кадром `frame` и возвращает её код (для XDP —
+/// действие). Кадр обрабатывается в памяти ядра: XDP_TX и XDP_REDIRECT никуда его не
+/// отправляют. Нужен root или CAP_BPF вместе с CAP_NET_ADMIN.
+pub fn test_run(fd: BorrowedFd<'_>, frame: &[u8]) -> io::Result<u32> {
+    let mut attr = TestRunAttr {
+        prog_fd: fd.as_raw_fd() as u32,
+        data_size_in: frame.len() as u32,
+        data_in: frame.as_ptr() as u64,
+        repeat: 1,
+        ..Default::default()
+    };
+    sys_bpf_out(BPF_PROG_TEST_RUN, &mut attr)?;
+    Ok(attr.retval)
+}
+
+/// Проверяет, что ядро поддерживает пакетное обновление хеш-карт.
+pub fn probe_map_batch() -> Support {
+    let attr = MapCreateAttr {
+        map_type: MapType::Hash as u32,
+        key_size: 4,
+        value_size: 4,
+        max_entries: 1,
+        map_flags: 0,
+    };
+    let fd = match sys_bpf(BPF_MAP_CREATE, &attr) {
+        Ok(fd) => fd as libc::c_int,
+        Err(e) => return Support::from_error(&e),
+    };
+    let result = update_batch(unsafe { BorrowedFd::borrow_raw(fd) }, &[0u32], &[0u32]);
+    unsafe { libc::close(fd) };
+    match result {
+        Ok(()) => Support::Supported,
+        Err(e) => Support::from_error(&e),
+    }
+}
+
+/// Есть ли у ядра BTF, нужный для CO-RE релокаций и aya-log.
+pub fn probe_btf() -> bool {
+    Path::new(VMLINUX_BTF).exists()
+}
+
+fn page_size() -> u32 {
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size > 0 {
+        size as u32
+    } else {
+        4096
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_attr_matches_kernel_layout() {
+        // Поля `bpf_attr.test` из linux/bpf.h до `duration` включительно: ядро читает их по
+        // смещениям, а не по именам.
+        assert_eq!(mem::offset_of!(TestRunAttr, prog_fd), 0);
+        assert_eq!(mem::offset_of!(TestRunAttr, retval), 4);
+        assert_eq!(mem::offset_of!(TestRunAttr, data_size_in), 8);
+        assert_eq!(mem::offset_of!(TestRunAttr, data_size_out), 12);
+        assert_eq!(mem::offset_of!(TestRunAttr, data_in), 16);
+        assert_eq!(mem::offset_of!(TestRunAttr, data_out), 24);
+        assert_eq!(mem::offset_of!(TestRunAttr, repeat), 32);
+        assert_eq!(mem::offset_of!(TestRunAttr, duration), 36);
+        assert_eq!(mem::size_of::<TestRunAttr>(), 40);
+        assert!(mem::size_of::<TestRunAttr>() <= BPF_ATTR_SIZE);
+    }
+}
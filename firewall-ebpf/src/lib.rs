@@ -1,3 +1,749 @@
 #![no_std]
+// Ошибка разбора у программы одна — кадр не тот, что заявляют его заголовки; `()` в
+// `Result` здесь, как и в `main.rs`, значит ровно это.
+#![allow(clippy::result_unit_err)]
 
-// This file exists to enable the library target.
+//! Разбор заголовков кадра для программы XDP: проверки границ и переходы от заголовка к
+//! заголовку.
+//!
+//! Здесь нет ни карт, ни логов, а кадр доступен только через [`PacketData`], поэтому тот же
+//! код, что проверяет верификатор, собирается и для хоста. `cargo test -p firewall-ebpf`
+//! прогоняет его над кадрами в обычных буферах и сверяет с [`classify::parse_frame`]; сама
+//! программа (`src/main.rs`) собирается только для bpf и в тестах не участвует.
+
+use core::mem;
+
+use aya_ebpf::{bindings::xdp_action, programs::XdpContext};
+use firewall_common::{
+    classify::{
+        self, ipv4_hdr_len, ipv6_ext, ipv6_ext_len, is_vlan, vlan_id, walks_ipv6_ext, Fragment,
+        Packet, ETH_HDR_LEN, IPPROTO_ICMP, IPPROTO_ICMPV6, IPPROTO_IPIP, IPPROTO_TCP,
+        IPPROTO_UDP, MAX_IPV6_EXT_HDRS, TCP_FLAGS_OFFSET,
+    },
+    lookup_country, pack_country,
+};
+use network_types::{
+    icmp::IcmpHdr,
+    ip::{Ipv4Hdr, Ipv6Hdr},
+    tcp::TcpHdr,
+    udp::UdpHdr,
+};
+
+/// Линейная часть кадра: адреса её начала и конца, как у `XdpContext`.
+pub trait PacketData {
+    fn data(&self) -> usize;
+    fn data_end(&self) -> usize;
+}
+
+impl PacketData for XdpContext {
+    #[inline(always)]
+    fn data(&self) -> usize {
+        XdpContext::data(self)
+    }
+
+    #[inline(always)]
+    fn data_end(&self) -> usize {
+        XdpContext::data_end(self)
+    }
+}
+
+/// Наибольшее смещение от начала пакета, которое принимает верификатор (`MAX_PACKET_OFF`).
+pub const MAX_PACKET_OFF: usize = 0xffff;
+
+/// Безопасное получение указателя с проверкой границ: offset + размер T должен быть внутри пакета.
+#[inline(always)]
+pub fn ptr_at<T>(ctx: &impl PacketData, offset: usize) -> Result<*const T, ()> {
+    let start = ctx.data();
+    let end = ctx.data_end();
+    let len = mem::size_of::<T>();
+
+    // Смещение вычисляется из полей заголовков (IHL, теги VLAN), то есть из данных пакета.
+    // Линейная часть кадра XDP не длиннее 64 КиБ, поэтому большее смещение заведомо за её
+    // концом. После этой проверки `offset + len` не больше 0xffff плюс размер заголовка и
+    // переполниться не может, а сложение с адресом данных проверяется `checked_add`: без
+    // переноса сумма не меньше `start`, и сравнение с `end` ниже честное.
+    if offset > MAX_PACKET_OFF {
+        return Err(());
+    }
+    let item_end = start.checked_add(offset + len).ok_or(())?;
+    if item_end > end {
+        return Err(());
+    }
+
+    Ok((start + offset) as *const T)
+}
+
+/// Заголовок `T` по смещению `offset`; смещение сдвигается за заголовок.
+///
+/// Все заголовки кадра разбираются через этот помощник, поэтому проверка границ и переход к
+/// следующему уровню нигде не повторяются вручную.
+#[inline(always)]
+pub fn header_at<T>(ctx: &impl PacketData, offset: &mut usize) -> Result<*const T, ()> {
+    header_with_len(ctx, offset, |_| Ok(mem::size_of::<T>()))
+}
+
+/// Как [`header_at`], но длина заголовка берётся из него самого (IHL у IPv4). Длина короче
+/// `T` — ошибка разбора: иначе следующий заголовок наложился бы на уже прочитанный.
+#[inline(always)]
+pub fn header_with_len<T>(
+    ctx: &impl PacketData,
+    offset: &mut usize,
+    len: impl FnOnce(*const T) -> Result<usize, ()>,
+) -> Result<*const T, ()> {
+    let header: *const T = ptr_at(ctx, *offset)?;
+    let len = len(header)?;
+    if len < mem::size_of::<T>() {
+        return Err(());
+    }
+    *offset += len;
+    Ok(header)
+}
+
+/// Заголовок Ethernet. EtherType хранится числом: в перечислении `EtherType` нет тегов
+/// VLAN и многих других значений, которые встречаются в кадрах.
+#[repr(C)]
+pub struct EthFrameHdr {
+    pub dst_addr: [u8; 6],
+    pub src_addr: [u8; 6],
+    pub ether_type: u16,
+}
+
+const _: () = assert!(mem::size_of::<EthFrameHdr>() == ETH_HDR_LEN);
+const _: () = assert!(TCP_FLAGS_OFFSET < mem::size_of::<TcpHdr>());
+
+/// Тег VLAN: TCI и EtherType вложенного кадра.
+#[repr(C)]
+pub struct VlanHdr {
+    pub tci: u16,
+    pub ether_type: u16,
+}
+
+/// Начало заголовка расширения IPv6: следующий заголовок, длина и, у заголовка фрагмента,
+/// смещение с флагами. Любой заголовок расширения не короче этих 8 байт.
+#[repr(C)]
+pub struct Ipv6ExtHdr {
+    pub next_hdr: u8,
+    pub hdr_ext_len: u8,
+    pub frag_off: u16,
+    _rest: u32,
+}
+
+/// Снимает до двух тегов VLAN (QinQ, `MAX_VLAN_TAGS`) за заголовком Ethernet с EtherType
+/// `ether_type`; проверки развёрнуты вручную. Возвращает EtherType вложенного кадра и
+/// идентификатор VLAN внешнего тега (0 — кадр без тега).
+#[inline(always)]
+pub fn vlan_tags(
+    ctx: &impl PacketData,
+    offset: &mut usize,
+    mut ether_type: u16,
+) -> Result<(u16, u16), ()> {
+    let mut vlan = 0;
+    if is_vlan(ether_type) {
+        let tag: *const VlanHdr = header_at(ctx, offset)?;
+        vlan = vlan_id(u16::from_be(unsafe { (*tag).tci }));
+        ether_type = u16::from_be(unsafe { (*tag).ether_type });
+    }
+    if is_vlan(ether_type) {
+        let tag: *const VlanHdr = header_at(ctx, offset)?;
+        ether_type = u16::from_be(unsafe { (*tag).ether_type });
+    }
+    Ok((ether_type, vlan))
+}
+
+/// Длина заголовка IPv4 по полю IHL; заголовок короче 20 байт — ошибка разбора.
+#[inline(always)]
+pub fn header_len(ipv4hdr: *const Ipv4Hdr) -> Result<usize, ()> {
+    // Первый байт заголовка — версия в старших битах и IHL в младших.
+    ipv4_hdr_len(unsafe { *ipv4hdr.cast::<u8>() }).ok_or(())
+}
+
+/// Разбирает заголовок IPv4 по смещению `offset` (с `unwrap_ipip` — внутренний заголовок
+/// IP-in-IP) и транспортный заголовок за ним в `packet`: адреса, протокол, TTL,
+/// фрагментацию и порты. Возвращает заголовок, по которому решают правила.
+#[inline(always)]
+pub fn parse_ipv4(
+    ctx: &impl PacketData,
+    offset: &mut usize,
+    unwrap_ipip: bool,
+    packet: &mut Packet,
+) -> Result<*const Ipv4Hdr, ()> {
+    // С опциями заголовок длиннее 20 байт, поэтому смещение сдвигается по IHL.
+    let mut ipv4hdr: *const Ipv4Hdr = header_with_len(ctx, offset, header_len)?;
+    // IP-in-IP: правила применяются к внутреннему заголовку, внешний только снимается. В
+    // следующем фрагменте внешнего пакета за заголовком продолжение данных, а не внутренний
+    // заголовок, и такой фрагмент решается по внешнему.
+    let outer_frag = Fragment::from_frag_off(u16::from_be(unsafe { (*ipv4hdr).frag_off }));
+    if unwrap_ipip
+        && unsafe { *core::ptr::addr_of!((*ipv4hdr).proto).cast::<u8>() } == IPPROTO_IPIP
+        && outer_frag != Fragment::Later
+    {
+        ipv4hdr = header_with_len(ctx, offset, header_len)?;
+    }
+    unsafe {
+        packet.src_addr = u32::from_be((*ipv4hdr).src_addr);
+        packet.dst_addr = u32::from_be((*ipv4hdr).dst_addr);
+        // Протокол читается числом: в `IpProto` есть не все значения.
+        packet.proto = *core::ptr::addr_of!((*ipv4hdr).proto).cast::<u8>();
+        packet.ttl = (*ipv4hdr).ttl;
+        packet.fragment = Fragment::from_frag_off(u16::from_be((*ipv4hdr).frag_off));
+    }
+    // Извлекаем порты из транспортного заголовка сразу за IP. В следующих фрагментах его
+    // нет, там уже данные: порты остаются нулевыми, и правила решают только по адресу.
+    if packet.fragment != Fragment::Later {
+        parse_ports(ctx, *offset, packet.proto, packet)?;
+    }
+    Ok(ipv4hdr)
+}
+
+/// Разбирает пакет IPv6 по смещению `offset` в `packet`: источник, hop limit, протокол,
+/// фрагментацию и порты.
+///
+/// Основной заголовок IPv6 всегда 40 байт. За ним до [`MAX_IPV6_EXT_HDRS`] заголовков
+/// расширения ([`walks_ipv6_ext`]) проходятся по цепочке, каждый с проверкой границ;
+/// транспортный заголовок ищется за последним. Заголовок фрагмента решает, как у IPv4, есть
+/// ли в пакете порты.
+#[inline(always)]
+pub fn parse_ipv6(
+    ctx: &impl PacketData,
+    offset: &mut usize,
+    packet: &mut Packet,
+) -> Result<*const Ipv6Hdr, ()> {
+    let ipv6hdr: *const Ipv6Hdr = header_at(ctx, offset)?;
+    // Следующий заголовок читается числом: в `IpProto` есть не все значения.
+    let mut proto = unsafe { *core::ptr::addr_of!((*ipv6hdr).next_hdr).cast::<u8>() };
+    let mut fragment = Fragment::None;
+    for _ in 0..MAX_IPV6_EXT_HDRS {
+        // За заголовком следующего фрагмента уже данные, а не следующий заголовок.
+        if !walks_ipv6_ext(proto) || fragment == Fragment::Later {
+            break;
+        }
+        let ext = header_with_len(ctx, offset, |ext: *const Ipv6ExtHdr| {
+            Ok(ipv6_ext_len(proto, unsafe { (*ext).hdr_ext_len }))
+        })?;
+        if proto == ipv6_ext::FRAGMENT {
+            fragment = Fragment::from_ipv6_frag(u16::from_be(unsafe { (*ext).frag_off }));
+        }
+        proto = unsafe { (*ext).next_hdr };
+    }
+    packet.proto = proto;
+    packet.src_addr6 = unsafe { (*ipv6hdr).src_addr.in6_u.u6_addr8 };
+    packet.ttl = unsafe { (*ipv6hdr).hop_limit };
+    packet.fragment = fragment;
+    if fragment != Fragment::Later {
+        parse_ports(ctx, *offset, proto, packet)?;
+    }
+    Ok(ipv6hdr)
+}
+
+/// Заполняет порты, окно и флаги TCP или тип ICMP по транспортному заголовку по смещению
+/// `offset`.
+#[inline(always)]
+pub fn parse_ports(
+    ctx: &impl PacketData,
+    mut offset: usize,
+    proto: u8,
+    packet: &mut Packet,
+) -> Result<(), ()> {
+    match proto {
+        IPPROTO_TCP => {
+            let tcphdr: *const TcpHdr = header_at(ctx, &mut offset)?;
+            // Байт флагов лежит внутри уже проверенных 20 байт заголовка.
+            let flags = unsafe { *tcphdr.cast::<u8>().add(TCP_FLAGS_OFFSET) };
+            unsafe {
+                packet.src_port = u16::from_be((*tcphdr).source);
+                packet.dst_port = u16::from_be((*tcphdr).dest);
+                packet.tcp_window = Some(u16::from_be((*tcphdr).window));
+            }
+            packet.tcp_flags = flags;
+        }
+        IPPROTO_UDP => {
+            let udphdr: *const UdpHdr = header_at(ctx, &mut offset)?;
+            unsafe {
+                packet.src_port = u16::from_be((*udphdr).source);
+                packet.dst_port = u16::from_be((*udphdr).dest);
+            }
+        }
+        IPPROTO_ICMP | IPPROTO_ICMPV6 => {
+            let icmphdr: *const IcmpHdr = header_at(ctx, &mut offset)?;
+            packet.icmp = Some(unsafe { ((*icmphdr).type_, (*icmphdr).code) });
+        }
+        // Остальные протоколы учитываются под портом 0 и отбрасываются правилами.
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Протокол с портами; для остальных в журнал вместо порта пишется номер протокола.
+#[inline(always)]
+pub fn is_transport(proto: u8) -> bool {
+    proto == classify::IPPROTO_TCP || proto == classify::IPPROTO_UDP
+}
+
+/// Страна источника, ключ `BLOCKED_COUNTRIES`: значение `COUNTRIES` для адреса (`db`), а
+/// для адресов вне базы — по первому октету.
+#[inline(always)]
+pub fn source_country(db: Option<u16>, src_addr: u32) -> u16 {
+    match db {
+        Some(country) => country,
+        None => pack_country(lookup_country(src_addr).as_bytes()),
+    }
+}
+
+/// Действие для разрешённого пакета на порт из `XSK_PORTS` (`xsk_port`); `None` — обычный
+/// пропуск. `redirect` — вызов `XSKS.redirect` для очереди пакета с переданными флагами. Во
+/// флагах XDP_PASS: если для очереди нет сокета, пакет пропускается, а не теряется.
+#[inline(always)]
+pub fn xsk_action(xsk_port: bool, redirect: impl FnOnce(u64) -> Result<u32, u32>) -> Option<u32> {
+    if !xsk_port {
+        return None;
+    }
+    Some(redirect(u64::from(xdp_action::XDP_PASS)).unwrap_or(xdp_action::XDP_PASS))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use std::{vec, vec::Vec};
+
+    use firewall_common::{
+        classify::{Direction, Frame, Rules, Verdict, ETH_P_IPV4, ETH_P_IPV6},
+        country_key, protocol_rule, unpack_country, DropReason,
+    };
+
+    use super::*;
+
+    /// Сдвиг начала кадра в буфере, как `NET_IP_ALIGN` в ядре: за 14 байтами Ethernet
+    /// заголовок IP выровнен на 4 байта, и поля `Ipv4Hdr` читаются по выровненным адресам.
+    const NET_IP_ALIGN: usize = 2;
+
+    const ETH_P_8021Q: u16 = 0x8100;
+    const SRC: u32 = 0xcb00_7107; // 203.0.113.7
+    const DST: u32 = 0xc000_0201; // 192.0.2.1
+
+    /// Кадр в обычной памяти вместо `XdpContext`.
+    struct FakeCtx {
+        buf: Vec<u64>,
+        len: usize,
+    }
+
+    impl FakeCtx {
+        fn new(frame: &[u8]) -> Self {
+            let mut buf = vec![0u64; (NET_IP_ALIGN + frame.len()).div_ceil(8)];
+            let bytes = unsafe {
+                core::slice::from_raw_parts_mut(buf.as_mut_ptr().cast::<u8>(), buf.len() * 8)
+            };
+            bytes[NET_IP_ALIGN..NET_IP_ALIGN + frame.len()].copy_from_slice(frame);
+            Self {
+                buf,
+                len: frame.len(),
+            }
+        }
+    }
+
+    impl PacketData for FakeCtx {
+        fn data(&self) -> usize {
+            self.buf.as_ptr() as usize + NET_IP_ALIGN
+        }
+
+        fn data_end(&self) -> usize {
+            self.data() + self.len
+        }
+    }
+
+    /// Разбор кадра в том же порядке, что и в `try_xdp_firewall`: `Err` — программа вернула
+    /// бы XDP_ABORTED, `Ok(None)` — кадр не IP, и его программа пропускает без проверки.
+    fn parse(frame: &[u8], unwrap_ipip: bool) -> Result<Option<Packet>, ()> {
+        let ctx = FakeCtx::new(frame);
+        let mut offset = 0;
+        let ethhdr: *const EthFrameHdr = header_at(&ctx, &mut offset)?;
+        let ether_type = u16::from_be(unsafe { (*ethhdr).ether_type });
+        let (ether_type, vlan) = vlan_tags(&ctx, &mut offset, ether_type)?;
+        let mut packet = Packet {
+            len: frame.len() as u16,
+            vlan,
+            ..Default::default()
+        };
+        match ether_type {
+            ETH_P_IPV4 => {
+                parse_ipv4(&ctx, &mut offset, unwrap_ipip, &mut packet)?;
+                // Без базы стран программа берёт страну из `lookup_country`, как и `parse_frame`.
+                packet.country = source_country(None, packet.src_addr);
+            }
+            ETH_P_IPV6 => {
+                parse_ipv6(&ctx, &mut offset, &mut packet)?;
+            }
+            _ => return Ok(None),
+        }
+        Ok(Some(packet))
+    }
+
+    /// Разбирает кадр и сверяет результат с [`classify::parse_frame`].
+    fn parse_checked(frame: &[u8], unwrap_ipip: bool) -> Result<Option<Packet>, ()> {
+        let expected = match classify::parse_frame(frame, unwrap_ipip) {
+            None => Err(()),
+            Some(Frame::Ipv4(packet) | Frame::Ipv6(packet)) => Ok(Some(packet)),
+            Some(Frame::NotIp | Frame::Arp(_)) => Ok(None),
+        };
+        let parsed = parse(frame, unwrap_ipip);
+        assert_eq!(parsed, expected, "кадр {frame:02x?}");
+        parsed
+    }
+
+    fn eth(ether_type: u16, vlans: &[u16], payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0x02, 0, 0, 0, 0, 0x02, 0x02, 0, 0, 0, 0, 0x01];
+        for &vlan in vlans {
+            frame.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+            frame.extend_from_slice(&vlan.to_be_bytes());
+        }
+        frame.extend_from_slice(&ether_type.to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Заголовок IPv4 с `options` словами опций, полем `frag_off` и данными за ним.
+    fn ipv4(proto: u8, options: usize, frag_off: u16, src: u32, payload: &[u8]) -> Vec<u8> {
+        let mut ip = vec![0u8; 20 + options * 4];
+        ip[0] = 0x40 | (5 + options as u8);
+        ip[6..8].copy_from_slice(&frag_off.to_be_bytes());
+        ip[8] = 64;
+        ip[9] = proto;
+        ip[12..16].copy_from_slice(&src.to_be_bytes());
+        ip[16..20].copy_from_slice(&DST.to_be_bytes());
+        ip.extend_from_slice(payload);
+        ip
+    }
+
+    fn ipv6(next_hdr: u8, payload: &[u8]) -> Vec<u8> {
+        let mut ip = vec![0u8; 40];
+        ip[0] = 0x60;
+        ip[6] = next_hdr;
+        ip[7] = 64;
+        ip[8] = 0x20;
+        ip[9] = 0x01;
+        ip[23] = 7;
+        ip.extend_from_slice(payload);
+        ip
+    }
+
+    /// Заголовок расширения IPv6 из `blocks` восьмибайтных блоков.
+    fn ext(next_hdr: u8, blocks: usize, payload: &[u8]) -> Vec<u8> {
+        let mut hdr = vec![0u8; blocks * 8];
+        hdr[0] = next_hdr;
+        hdr[1] = blocks as u8 - 1;
+        hdr.extend_from_slice(payload);
+        hdr
+    }
+
+    fn fragment_hdr(next_hdr: u8, frag: u16, payload: &[u8]) -> Vec<u8> {
+        let mut hdr = vec![next_hdr, 0, 0, 0, 0, 0, 0, 1];
+        hdr[2..4].copy_from_slice(&frag.to_be_bytes());
+        hdr.extend_from_slice(payload);
+        hdr
+    }
+
+    fn tcp_syn(port: u16) -> Vec<u8> {
+        let mut tcp = vec![0u8; 20];
+        tcp[0..2].copy_from_slice(&40000u16.to_be_bytes());
+        tcp[2..4].copy_from_slice(&port.to_be_bytes());
+        tcp[12] = 0x50;
+        tcp[13] = 0x02;
+        tcp[14..16].copy_from_slice(&64240u16.to_be_bytes());
+        tcp
+    }
+
+    fn tcp_frame(src: u32, port: u16) -> Vec<u8> {
+        eth(ETH_P_IPV4, &[], &ipv4(IPPROTO_TCP, 0, 0, src, &tcp_syn(port)))
+    }
+
+    /// Чёрный список из одного адреса и один разрешённый порт TCP; остальные правила
+    /// выключены.
+    struct Blocklist {
+        blocked_ip: u32,
+        allowed_port: u16,
+    }
+
+    impl Rules for Blocklist {
+        fn is_blocked_ip(&self, addr: u32) -> bool {
+            addr == self.blocked_ip
+        }
+
+        fn is_blocked_ip6(&self, _addr: &[u8; 16]) -> bool {
+            false
+        }
+
+        fn is_blocked_mask(&self, _addr: u32) -> bool {
+            false
+        }
+
+        fn is_blocked_country(&self, _country: u16) -> bool {
+            false
+        }
+
+        fn country_window_open(&self) -> bool {
+            true
+        }
+
+        fn is_blocked_region(&self, _addr: u32) -> bool {
+            false
+        }
+
+        fn is_allowed_ip(&self, _addr: u32) -> bool {
+            false
+        }
+
+        fn is_blocked_tcp_window(&self, _window: u16) -> bool {
+            false
+        }
+
+        fn filters_tcp_flags(&self) -> bool {
+            true
+        }
+
+        fn is_icmp_echo_allowed(&self) -> bool {
+            false
+        }
+
+        fn default_allow(&self) -> bool {
+            false
+        }
+
+        fn strict_protocols(&self) -> bool {
+            false
+        }
+
+        fn protocol_rule(&self, _proto: u8) -> u8 {
+            protocol_rule::UNLISTED
+        }
+
+        fn drops_fragments(&self) -> bool {
+            false
+        }
+
+        fn is_established(&self, _packet: &Packet) -> bool {
+            false
+        }
+
+        fn allows_dns(&self) -> bool {
+            false
+        }
+
+        fn is_blocked_src_port(&self, _port: u16, _proto: u8) -> bool {
+            false
+        }
+
+        fn is_allowed_port(&self, port: u16, proto: u8) -> bool {
+            port == self.allowed_port && proto == IPPROTO_TCP
+        }
+
+        fn port_match_src(&self) -> bool {
+            false
+        }
+
+        fn is_blocked_endpoint(&self, _packet: &Packet) -> bool {
+            false
+        }
+
+        fn knock_port(&self) -> u16 {
+            0
+        }
+
+        fn knock(&self, _packet: &Packet) -> bool {
+            false
+        }
+
+        fn filters_direction(&self, direction: Direction) -> bool {
+            direction == Direction::Ingress
+        }
+
+        fn in_grace(&self, _packet: &Packet, _allowed: bool) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn redirect_port_goes_to_xsk_map() {
+        // Как `bpf_redirect_map` над `XSKS`, где сокет зарегистрирован только для очереди 0.
+        let xsks = |queue: u32| {
+            move |flags: u64| {
+                if queue == 0 {
+                    Ok(xdp_action::XDP_REDIRECT)
+                } else {
+                    Err(flags as u32)
+                }
+            }
+        };
+        assert_eq!(xsk_action(true, xsks(0)), Some(xdp_action::XDP_REDIRECT));
+        assert_eq!(xsk_action(true, xsks(3)), Some(xdp_action::XDP_PASS));
+        assert_eq!(xsk_action(false, |_| unreachable!("порт не из XSK_PORTS")), None);
+    }
+
+    #[test]
+    fn ptr_at_checks_bounds() {
+        let ctx = FakeCtx::new(&[0; 20]);
+        assert!(ptr_at::<[u8; 20]>(&ctx, 0).is_ok());
+        assert!(ptr_at::<[u8; 20]>(&ctx, 1).is_err());
+        assert!(ptr_at::<u8>(&ctx, 19).is_ok());
+        assert!(ptr_at::<u8>(&ctx, 20).is_err());
+        assert!(ptr_at::<u8>(&ctx, MAX_PACKET_OFF + 1).is_err());
+        assert!(ptr_at::<u8>(&ctx, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn tcp_over_ipv4_is_parsed_like_parse_frame() {
+        let packet = parse_checked(&tcp_frame(SRC, 22), false).unwrap().unwrap();
+        assert_eq!(packet.src_addr, SRC);
+        assert_eq!(packet.dst_addr, DST);
+        assert_eq!((packet.proto, packet.src_port, packet.dst_port), (IPPROTO_TCP, 40000, 22));
+        assert_eq!(packet.tcp_window, Some(64240));
+        assert_eq!(packet.fragment, Fragment::None);
+
+        let udp = [0x9c, 0x40, 0, 53, 0, 8, 0, 0];
+        let frame = eth(ETH_P_IPV4, &[], &ipv4(IPPROTO_UDP, 0, 0, SRC, &udp));
+        let packet = parse_checked(&frame, false).unwrap().unwrap();
+        assert_eq!((packet.proto, packet.dst_port), (IPPROTO_UDP, 53));
+
+        let echo = [8, 0, 0, 0, 0, 1, 0, 1];
+        let frame = eth(ETH_P_IPV4, &[], &ipv4(IPPROTO_ICMP, 0, 0, SRC, &echo));
+        assert_eq!(parse_checked(&frame, false).unwrap().unwrap().icmp, Some((8, 0)));
+    }
+
+    #[test]
+    fn truncated_frames_abort() {
+        let frames = [
+            tcp_frame(SRC, 22),
+            eth(ETH_P_IPV4, &[100], &ipv4(IPPROTO_TCP, 2, 0, SRC, &tcp_syn(22))),
+            eth(ETH_P_IPV6, &[], &ipv6(ipv6_ext::HOP_BY_HOP, &ext(IPPROTO_TCP, 1, &tcp_syn(22)))),
+        ];
+        for frame in frames {
+            for len in 0..frame.len() {
+                assert_eq!(parse_checked(&frame[..len], false), Err(()), "длина {len}");
+            }
+            assert!(parse_checked(&frame, false).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn vlan_tags_are_stripped() {
+        let ip = ipv4(IPPROTO_TCP, 0, 0, SRC, &tcp_syn(22));
+        let packet = parse_checked(&eth(ETH_P_IPV4, &[100], &ip), false).unwrap().unwrap();
+        assert_eq!((packet.vlan, packet.dst_port), (100, 22));
+        // QinQ: идентификатор берётся из внешнего тега.
+        let packet = parse_checked(&eth(ETH_P_IPV4, &[100, 200], &ip), false).unwrap().unwrap();
+        assert_eq!((packet.vlan, packet.dst_port), (100, 22));
+        // Третий тег программа не снимает, и кадр для неё не IP.
+        assert_eq!(parse_checked(&eth(ETH_P_IPV4, &[1, 2, 3], &ip), false), Ok(None));
+        // PCP и DEI в TCI не входят в идентификатор.
+        let packet = parse_checked(&eth(ETH_P_IPV4, &[0xe00a], &ip), false).unwrap().unwrap();
+        assert_eq!(packet.vlan, 10);
+    }
+
+    #[test]
+    fn ipv4_options_move_the_transport_header() {
+        for options in [1, 2, 10] {
+            let frame = eth(ETH_P_IPV4, &[], &ipv4(IPPROTO_TCP, options, 0, SRC, &tcp_syn(443)));
+            let packet = parse_checked(&frame, false).unwrap().unwrap();
+            assert_eq!((packet.src_port, packet.dst_port), (40000, 443));
+        }
+        // IHL меньше пяти слов — заголовок испорчен.
+        let mut frame = tcp_frame(SRC, 22);
+        frame[ETH_HDR_LEN] = 0x44;
+        assert_eq!(parse_checked(&frame, false), Err(()));
+    }
+
+    #[test]
+    fn fragments_have_ports_only_in_the_first() {
+        const MF: u16 = 0x2000;
+        let frame = eth(ETH_P_IPV4, &[], &ipv4(IPPROTO_TCP, 0, MF, SRC, &tcp_syn(22)));
+        let packet = parse_checked(&frame, false).unwrap().unwrap();
+        assert_eq!((packet.fragment, packet.dst_port), (Fragment::First, 22));
+
+        // В следующем фрагменте за заголовком данные, даже короче заголовка TCP.
+        let frame = eth(ETH_P_IPV4, &[], &ipv4(IPPROTO_TCP, 0, 185, SRC, &[0xff; 4]));
+        let packet = parse_checked(&frame, false).unwrap().unwrap();
+        assert_eq!((packet.fragment, packet.src_port, packet.dst_port), (Fragment::Later, 0, 0));
+        assert_eq!(packet.tcp_window, None);
+
+        let later = fragment_hdr(IPPROTO_TCP, 185 << 3, &[0xff; 4]);
+        let frame = eth(ETH_P_IPV6, &[], &ipv6(ipv6_ext::FRAGMENT, &later));
+        let packet = parse_checked(&frame, false).unwrap().unwrap();
+        assert_eq!((packet.fragment, packet.dst_port), (Fragment::Later, 0));
+
+        let first = fragment_hdr(IPPROTO_TCP, 1, &tcp_syn(22));
+        let frame = eth(ETH_P_IPV6, &[], &ipv6(ipv6_ext::FRAGMENT, &first));
+        let packet = parse_checked(&frame, false).unwrap().unwrap();
+        assert_eq!((packet.fragment, packet.dst_port), (Fragment::First, 22));
+    }
+
+    #[test]
+    fn ipv6_extension_headers_are_walked() {
+        let chain = ext(ipv6_ext::DEST_OPTS, 1, &ext(IPPROTO_TCP, 3, &tcp_syn(22)));
+        let frame = eth(ETH_P_IPV6, &[], &ipv6(ipv6_ext::HOP_BY_HOP, &chain));
+        let packet = parse_checked(&frame, false).unwrap().unwrap();
+        assert_eq!((packet.proto, packet.dst_port), (IPPROTO_TCP, 22));
+        assert_eq!(packet.ttl, 64);
+        assert_eq!(packet.src_addr6[..2], [0x20, 0x01]);
+
+        // Цепочка длиннее `MAX_IPV6_EXT_HDRS`: протоколом остаётся заголовок расширения.
+        let mut payload = tcp_syn(22);
+        for _ in 0..=MAX_IPV6_EXT_HDRS {
+            let next = if payload.len() == 20 { IPPROTO_TCP } else { ipv6_ext::DEST_OPTS };
+            payload = ext(next, 1, &payload);
+        }
+        let frame = eth(ETH_P_IPV6, &[], &ipv6(ipv6_ext::DEST_OPTS, &payload));
+        let packet = parse_checked(&frame, false).unwrap().unwrap();
+        assert_eq!((packet.proto, packet.dst_port), (ipv6_ext::DEST_OPTS, 0));
+    }
+
+    #[test]
+    fn ipip_is_unwrapped_on_request() {
+        const INNER: u32 = 0xc633_6407; // 198.51.100.7
+        let inner = ipv4(IPPROTO_TCP, 0, 0, INNER, &tcp_syn(22));
+        let frame = eth(ETH_P_IPV4, &[], &ipv4(IPPROTO_IPIP, 0, 0, SRC, &inner));
+        let outer = parse_checked(&frame, false).unwrap().unwrap();
+        assert_eq!((outer.src_addr, outer.proto, outer.dst_port), (SRC, IPPROTO_IPIP, 0));
+        let packet = parse_checked(&frame, true).unwrap().unwrap();
+        assert_eq!((packet.src_addr, packet.proto, packet.dst_port), (INNER, IPPROTO_TCP, 22));
+
+        // Следующий фрагмент туннеля начинается с данных, а не с внутреннего заголовка.
+        let frame = eth(ETH_P_IPV4, &[], &ipv4(IPPROTO_IPIP, 0, 185, SRC, &inner));
+        let packet = parse_checked(&frame, true).unwrap().unwrap();
+        assert_eq!((packet.src_addr, packet.proto), (SRC, IPPROTO_IPIP));
+        assert_eq!((packet.fragment, packet.dst_port), (Fragment::Later, 0));
+    }
+
+    #[test]
+    fn blocklisted_source_is_dropped() {
+        const OTHER: u32 = 0xc633_6407; // 198.51.100.7
+        let rules = Blocklist {
+            blocked_ip: SRC,
+            allowed_port: 22,
+        };
+        let decide = |frame: &[u8]| {
+            classify::decide(&parse_checked(frame, false).unwrap().unwrap(), &rules)
+        };
+        assert_eq!(decide(&tcp_frame(SRC, 22)), Verdict::Drop(DropReason::BlockedIp));
+        assert_eq!(decide(&tcp_frame(OTHER, 22)), Verdict::Pass);
+        assert_eq!(decide(&tcp_frame(OTHER, 23)), Verdict::Drop(DropReason::PortNotAllowed));
+        // Фрагмент от источника из чёрного списка отбрасывается по адресу, без портов.
+        let frame = eth(ETH_P_IPV4, &[7], &ipv4(IPPROTO_TCP, 0, 185, SRC, &[0; 8]));
+        assert_eq!(decide(&frame), Verdict::Drop(DropReason::BlockedIp));
+    }
+
+    #[test]
+    fn cli_country_key_matches_program_decode() {
+        const RU_SRC: u32 = 0x0500_0007; // 5.0.0.7, по первому октету — Россия
+        // Так firewall-cli и загрузчик записывают «RU» в `BLOCKED_COUNTRIES`.
+        let written = country_key(b"ru").unwrap();
+        assert_eq!(written, 0x5255);
+        assert_eq!(written, pack_country(b"RU"));
+
+        let packet = parse_checked(&tcp_frame(RU_SRC, 22), false).unwrap().unwrap();
+        assert_eq!(packet.country, written);
+        assert_eq!(source_country(Some(written), SRC), written);
+        assert_eq!(&unpack_country(packet.country), b"RU");
+
+        for code in [&b"R"[..], b"RUS", b"R1", "ЯЯ".as_bytes()] {
+            assert_eq!(country_key(code), None, "{code:?}");
+        }
+    }
+}
@@ -1,14 +1,38 @@
 #![no_std]
 #![no_main]
 
-use aya_ebpf::{bindings::xdp_action, macros::xdp, programs::XdpContext};
+use aya_ebpf::{
+    bindings::{xdp_action, TC_ACT_OK},
+    cty::c_void,
+    helpers::{bpf_ktime_get_ns, bpf_map_lookup_elem},
+    macros::{classifier, map, xdp},
+    maps::{
+        lpm_trie::Key, Array, HashMap, LpmTrie, LruHashMap, LruPerCpuHashMap, PerCpuArray,
+        PerCpuHashMap, RingBuf, XskMap,
+    },
+    programs::{TcContext, XdpContext},
+};
 use aya_log_ebpf::info;
 use core::mem;
+use firewall_common::{
+    classify::{
+        self, arp, ipv4_hdr_len, Direction, Fragment, Packet, Rules, Verdict, ETH_HDR_LEN,
+        ETH_P_ARP, ETH_P_IPV4, ETH_P_IPV6, TCP_ACK, TCP_FLAGS_OFFSET, TCP_SYN,
+    },
+    block_action, conntrack_alive, direction, endpoint_key, event_flags, grace_alive, knock_next,
+    log_level, mode, port_protos, protocol_rule, rule_costs, sample_step, settings, stats,
+    time_window, unpack_country, ConnKey, DropEvent, DropReason, FlowKey, FlowStats, KnockProgress,
+    MaskedAddr, PacketStats, RateState, RuleCost, KNOCK_STEP_SECS, MAX_BLOCKED_MASKS,
+    MAX_KNOCK_PORTS, MAX_XSK_QUEUES,
+};
+use firewall_ebpf::{
+    header_at, header_len, header_with_len, is_transport, parse_ipv4, parse_ipv6, ptr_at,
+    source_country, vlan_tags, xsk_action, EthFrameHdr,
+};
 use network_types::{
-    eth::{EthHdr, EtherType},
+    arp::ArpHdr,
     ip::{IpProto, Ipv4Hdr},
     tcp::TcpHdr,
-    udp::UdpHdr,
 };
 
 #[cfg(not(test))]
@@ -17,136 +41,1282 @@ fn panic(_info: &core::panic::PanicInfo) -> ! {
     loop {}
 }
 
-const ETH_HDR_LEN: usize = 14;
-const IPV4_HDR_LEN: usize = 20;
+/// Глобальные счётчики итоговых действий (PASS/DROP/ABORTED), по одному на CPU.
+#[map]
+static STATS: PerCpuArray<u64> = PerCpuArray::with_max_entries(stats::LEN, 0);
+
+/// Отброшенные пакеты по причинам, ячейка — код `DropReason`.
+#[map]
+static DROP_REASONS: PerCpuArray<u64> = PerCpuArray::with_max_entries(DropReason::SLOTS, 0);
+
+/// Затраты на проверку правил по типам, пока включена настройка `PROFILE`.
+#[map]
+static RULE_COSTS: PerCpuArray<RuleCost> = PerCpuArray::with_max_entries(rule_costs::LEN, 0);
+
+/// Трафик по порту назначения (0 — пакеты без транспортного порта).
+#[map]
+static PORT_STATS: PerCpuHashMap<u16, PacketStats> = PerCpuHashMap::with_max_entries(65536, 0);
+
+/// Трафик по стране источника, ключ — `pack_country`.
+#[map]
+static COUNTRY_STATS: PerCpuHashMap<u16, PacketStats> = PerCpuHashMap::with_max_entries(512, 0);
+
+/// Отброшенные пакеты по стране источника, с любой причиной. У кадров, отброшенных до
+/// определения страны (по MAC, ARP, IPv6), ключ 0.
+#[map]
+static COUNTRY_DROPS: PerCpuHashMap<u16, u64> = PerCpuHashMap::with_max_entries(512, 0);
+
+/// Трафик по адресу источника IPv4. Источников при флуде с подменой адресов бесконечно
+/// много, поэтому карта LRU: новые адреса вытесняют самые давние.
+#[map]
+static SOURCE_STATS: LruPerCpuHashMap<u32, PacketStats> =
+    LruPerCpuHashMap::with_max_entries(65536, 0);
+
+/// Значения окна TCP, характерные для сканеров; проверяются при `TCP_WINDOW_FILTER`.
+#[map]
+static TCP_WINDOWS: HashMap<u16, u8> = HashMap::with_max_entries(64, 0);
+
+/// Заблокированные адреса источника, заполняются загрузчиком (`--blocked-ips`); значение —
+/// сколько пакетов отброшено по записи.
+#[map]
+static BLOCKED_IPS: HashMap<u32, u64> = HashMap::with_max_entries(131072, 0);
+
+/// Заблокированные сети источника (`--blocked-ips` с префиксом), ключ — адрес в сетевом
+/// порядке; значение, как в `BLOCKED_IPS`, — отброшенные пакеты самой длинной совпавшей сети.
+#[map]
+static BLOCKED_NETS: LpmTrie<u32, u64> = LpmTrie::with_max_entries(65536, 0);
+
+/// Заблокированные адреса и сети источника IPv6 (`--blocked-ips6`), ключ — адрес в сетевом
+/// порядке; значение — как в `BLOCKED_NETS`.
+#[map]
+static BLOCKED_IPS6: LpmTrie<[u8; 16], u64> = LpmTrie::with_max_entries(65536, 0);
+
+/// Адреса на испытании перед блокировкой: сколько пакетов с них дошло до правил.
+#[map]
+static STAGED_IPS: PerCpuHashMap<u32, u64> = PerCpuHashMap::with_max_entries(256, 0);
+
+/// Правила «адрес/маска» с произвольной маской; занято `settings::BLOCKED_MASKS` ячеек.
+#[map]
+static BLOCKED_MASKS: Array<MaskedAddr> = Array::with_max_entries(MAX_BLOCKED_MASKS, 0);
+
+/// Страна сети из `--country-db`, ключ — адрес в сетевом порядке, значение — `pack_country`.
+#[map]
+static COUNTRIES: LpmTrie<u32, u16> = LpmTrie::with_max_entries(1048576, 0);
+
+/// Заблокированные MAC-адреса источника (`--blocked-macs`).
+#[map]
+static BLOCKED_MACS: HashMap<[u8; 6], u8> = HashMap::with_max_entries(4096, 0);
+
+/// Заблокированные страны источника, ключ — `pack_country`.
+#[map]
+static BLOCKED_COUNTRIES: HashMap<u16, u8> = HashMap::with_max_entries(256, 0);
+
+/// Сети заблокированных регионов из базы MaxMind City (`--region-db`), ключ — адрес в
+/// сетевом порядке, значение — идентификатор региона.
+#[map]
+static REGIONS: LpmTrie<u32, u32> = LpmTrie::with_max_entries(262144, 0);
+
+/// Адреса, которые пропускаются несмотря на блокировку их страны или региона.
+#[map]
+static ALLOWED_IPS: HashMap<u32, u8> = HashMap::with_max_entries(65536, 0);
+
+/// Разрешённые порты назначения или источника (`PORT_MATCH`), значение — маска
+/// `port_protos` (`--ports`). Места хватает на все порты, так что любые диапазоны помещаются.
+#[map]
+static ALLOWED_PORTS: HashMap<u16, u8> = HashMap::with_max_entries(65536, 0);
+
+/// Заблокированные порты источника (`--blocked-src-ports`), значение — маска `port_protos`.
+#[map]
+static BLOCKED_SRC_PORTS: HashMap<u16, u8> = HashMap::with_max_entries(65536, 0);
+
+/// Правила для протоколов IP (`--protocols`): ячейка — номер протокола, значение —
+/// `protocol_rule`.
+#[map]
+static PROTOCOLS: Array<u8> = Array::with_max_entries(256, 0);
+
+/// Составные правила «адрес:порт назначения», ключ — `endpoint_key`.
+#[map]
+static BLOCKED_ENDPOINTS: LpmTrie<[u8; 6], u8> = LpmTrie::with_max_entries(4096, 0);
+
+/// Доверенные префиксы источника (`--fast-accept-prefixes`), ключ — адрес в сетевом порядке.
+#[map]
+static FAST_ACCEPT: LpmTrie<u32, u8> = LpmTrie::with_max_entries(1024, 0);
+
+/// Защищаемые адреса назначения (`--protected-ips`), ключ — адрес в сетевом порядке.
+#[map]
+static PROTECTED_IPS: LpmTrie<u32, u8> = LpmTrie::with_max_entries(1024, 0);
+
+/// Вердикты внешнего классификатора по 5-кортежу (`firewall_common::verdict_override`).
+#[map]
+static VERDICT_OVERRIDES: HashMap<FlowKey, u8> = HashMap::with_max_entries(65536, 0);
+
+/// Порты назначения для перенаправления в AF_XDP (`--xsk-redirect-ports`).
+#[map]
+static XSK_PORTS: HashMap<u16, u8> = HashMap::with_max_entries(1024, 0);
+
+/// Сокеты AF_XDP по номеру очереди RX; заполняет приложение, получающее трафик.
+#[map]
+static XSKS: XskMap = XskMap::with_max_entries(MAX_XSK_QUEUES, 0);
+
+/// Таблица потоков для экспорта NetFlow; при переполнении вытесняются самые старые записи.
+#[map]
+static FLOWS: LruHashMap<FlowKey, FlowStats> = LruHashMap::with_max_entries(65536, 0);
+
+/// Соединения TCP, открытые самим хостом (`--conntrack-timeout`): время последнего пакета
+/// по 4-кортежу ответа. Давно закрытые соединения вытесняются при заполнении.
+#[map]
+static CONNTRACK: LruHashMap<ConnKey, u64> = LruHashMap::with_max_entries(65536, 0);
+
+/// Входящие соединения TCP, принятые разрешённым портом, пока ведётся `CONNTRACK`: время
+/// последнего пакета по 4-кортежу. По ним соединение доживает окно `grace-period`.
+#[map]
+static ACCEPTED_CONNS: LruHashMap<ConnKey, u64> = LruHashMap::with_max_entries(65536, 0);
+
+/// Последовательность стука (`--knock-sequence`): порт назначения по номеру шага.
+#[map]
+static KNOCK_SEQUENCE: Array<u16> = Array::with_max_entries(MAX_KNOCK_PORTS, 0);
+
+/// Сколько портов последовательности простучал источник IPv4.
+#[map]
+static KNOCK_PROGRESS: LruHashMap<u32, KnockProgress> = LruHashMap::with_max_entries(4096, 0);
+
+/// Источники IPv4, которые простучали последовательность: время последнего пакета к порту
+/// за стуком.
+#[map]
+static KNOCK_UNLOCKED: LruHashMap<u32, u64> = LruHashMap::with_max_entries(4096, 0);
+
+/// Вёдра токенов `--rate-limit` по адресу источника IPv4; при заполнении вытесняются
+/// давно не слышанные источники, и их ведро при следующем пакете снова полное.
+#[map]
+static RATE_BUCKETS: LruHashMap<u32, RateState> = LruHashMap::with_max_entries(65536, 0);
+
+/// Вёдра токенов `--syn-rate-limit` для пакетов SYN без ACK, отдельные от `RATE_BUCKETS`.
+#[map]
+static SYN_BUCKETS: LruHashMap<u32, RateState> = LruHashMap::with_max_entries(65536, 0);
+
+/// События об отброшенных пакетах для загрузчика.
+#[map]
+static EVENTS: RingBuf = RingBuf::with_byte_size(256 * 1024, 0);
+
+/// Настройки, которые загрузчик записывает при старте (см. `firewall_common::settings`).
+#[map]
+static SETTINGS: Array<u32> = Array::with_max_entries(settings::LEN, 0);
+
+/// Счётчики пакетов для сэмплирования, отдельные для событий и для логов.
+#[map]
+static SAMPLERS: PerCpuArray<u32> = PerCpuArray::with_max_entries(SAMPLER_COUNT, 0);
+
+const SAMPLER_EVENTS: u32 = 0;
+const SAMPLER_LOGS: u32 = 1;
+const SAMPLER_ALLOWS: u32 = 2;
+const SAMPLER_COUNT: u32 = 3;
 
 #[xdp]
 pub fn xdp_firewall(ctx: XdpContext) -> u32 {
-    match try_xdp_firewall(ctx) {
+    let raw = ctx.ctx;
+    let action = match try_xdp_firewall(ctx) {
         Ok(ret) => ret,
         Err(_) => xdp_action::XDP_ABORTED,
+    };
+    if !fast_path() {
+        count_action(action);
     }
+    if action == xdp_action::XDP_DROP {
+        return block(&XdpContext::new(raw));
+    }
+    action
 }
 
-/// Безопасное получение указателя с проверкой границ: offset + размер T должен быть внутри пакета.
+/// Действие для отбрасываемого пакета по `settings::BLOCK_ACTION`.
 #[inline(always)]
-fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<*const T, ()> {
-    let start = ctx.data();
-    let end = ctx.data_end();
-    let len = mem::size_of::<T>();
+fn block(ctx: &XdpContext) -> u32 {
+    match setting(settings::BLOCK_ACTION) {
+        block_action::ABORT => xdp_action::XDP_ABORTED,
+        block_action::TX => {
+            // Кадр короче заголовка Ethernet до правил не дошёл бы, но верификатору нужна
+            // своя проверка перед записью.
+            let Ok(ethhdr) = ptr_at::<EthFrameHdr>(ctx, 0) else {
+                return xdp_action::XDP_DROP;
+            };
+            let ethhdr = ethhdr.cast_mut();
+            unsafe { mem::swap(&mut (*ethhdr).dst_addr, &mut (*ethhdr).src_addr) };
+            xdp_action::XDP_TX
+        }
+        _ => xdp_action::XDP_DROP,
+    }
+}
+
+/// Программа на отправке (TC egress): записывает в `CONNTRACK` соединения TCP, которые
+/// открывает сам хост. Пакеты не меняются и не задерживаются.
+#[classifier]
+pub fn tc_conntrack(ctx: TcContext) -> i32 {
+    let _ = try_tc_conntrack(&ctx);
+    TC_ACT_OK
+}
 
-    if start + offset + len > end {
-        return Err(());
+/// Исходящий SYN без ACK добавляет запись, остальные исходящие пакеты соединения продлевают
+/// её, чтобы долгое соединение с молчащим сервером не устарело. Кадры с тегами VLAN уходят
+/// через отдельный интерфейс VLAN и здесь не разбираются.
+#[inline(always)]
+fn try_tc_conntrack(ctx: &TcContext) -> Result<(), ()> {
+    if setting(settings::CONNTRACK_TIMEOUT) == 0 {
+        return Ok(());
+    }
+    let ethhdr: EthFrameHdr = ctx.load(0).map_err(|_| ())?;
+    if u16::from_be(ethhdr.ether_type) != ETH_P_IPV4 {
+        return Ok(());
+    }
+    let ipv4hdr: Ipv4Hdr = ctx.load(ETH_HDR_LEN).map_err(|_| ())?;
+    if ipv4hdr.proto != IpProto::Tcp
+        || Fragment::from_frag_off(u16::from_be(ipv4hdr.frag_off)) == Fragment::Later
+    {
+        return Ok(());
     }
+    let version_ihl: u8 = ctx.load(ETH_HDR_LEN).map_err(|_| ())?;
+    let offset = ETH_HDR_LEN + ipv4_hdr_len(version_ihl).ok_or(())?;
+    let tcphdr: TcpHdr = ctx.load(offset).map_err(|_| ())?;
+    let flags: u8 = ctx.load(offset + TCP_FLAGS_OFFSET).map_err(|_| ())?;
 
-    Ok((start + offset) as *const T)
+    let key = ConnKey::reply(
+        u32::from_be(ipv4hdr.src_addr),
+        u32::from_be(ipv4hdr.dst_addr),
+        u16::from_be(tcphdr.source),
+        u16::from_be(tcphdr.dest),
+    );
+    let now = unsafe { bpf_ktime_get_ns() };
+    if flags & (TCP_SYN | TCP_ACK) == TCP_SYN {
+        // LRU-карта при заполнении вытесняет самую давнюю запись, ошибка здесь редкость.
+        if CONNTRACK.insert(&key, &now, 0).is_err() {
+            count_map_full();
+        }
+    } else if let Some(last_seen) = CONNTRACK.get_ptr_mut(&key) {
+        unsafe { *last_seen = now };
+    }
+    Ok(())
 }
 
-fn try_xdp_firewall(ctx: XdpContext) -> Result<u32, ()> {
-    // Парсим заголовок Ethernet.
-    let ethhdr: *const EthHdr = ptr_at(&ctx, 0)?;
-    info!(&ctx, "Ethernet header parsed");
+/// Увеличивает счётчик итогового действия, включая путь XDP_ABORTED.
+#[inline(always)]
+fn count_action(action: u32) {
+    let slot = match action {
+        xdp_action::XDP_PASS => stats::PASS,
+        xdp_action::XDP_DROP => stats::DROP,
+        xdp_action::XDP_REDIRECT => stats::REDIRECT,
+        _ => stats::ABORTED,
+    };
+    if let Some(counter) = STATS.get_ptr_mut(slot) {
+        unsafe { *counter += 1 };
+    }
+}
 
-    match unsafe { (*ethhdr).ether_type } {
-        EtherType::Ipv4 => {}
-        _ => return Ok(xdp_action::XDP_PASS),
+#[inline(always)]
+fn setting(index: u32) -> u32 {
+    SETTINGS.get(index).copied().unwrap_or(0)
+}
+
+/// Режим замера `FAST_PATH`: пакет только проходит правила, всё учётное пропускается.
+#[inline(always)]
+fn fast_path() -> bool {
+    setting(settings::FAST_PATH) != 0
+}
+
+/// Решает, попадает ли текущий пакет в выборку с частотой, заданной настройкой `rate_setting`.
+///
+/// Частота N означает каждый N-й пакет, 0 выключает путь целиком. Счётчик свой у каждого
+/// CPU и у каждого пути, поэтому события и логи сэмплируются независимо.
+#[inline(always)]
+fn sampled(sampler: u32, rate_setting: u32) -> bool {
+    let rate = setting(rate_setting);
+    if rate == 0 {
+        return false;
     }
+    let Some(counter) = SAMPLERS.get_ptr_mut(sampler) else {
+        return false;
+    };
+    sample_step(unsafe { &mut *counter }, rate)
+}
 
-    // Парсим IPv4-заголовок.
-    let ipv4hdr: *const Ipv4Hdr = ptr_at(&ctx, ETH_HDR_LEN)?;
-    let src_ip = u32::from_be(unsafe { (*ipv4hdr).src_addr });
-    let dst_ip = u32::from_be(unsafe { (*ipv4hdr).dst_addr });
-    info!(
-        &ctx,
-        "IPv4 header parsed: SRC IP: {:i}, DST IP: {:i}",
-        src_ip,
-        dst_ip
-    );
+/// Уровень логирования пакета, одно из значений [`log_level`]: заданный загрузчиком, если
+/// пакет выбран частотой `LOG_SAMPLE_RATE`, иначе `NONE`. На уровне `NONE` не трогается и
+/// счётчик частоты.
+#[inline(always)]
+fn packet_log_level() -> u32 {
+    let level = setting(settings::LOG_LEVEL);
+    if level != log_level::NONE && sampled(SAMPLER_LOGS, settings::LOG_SAMPLE_RATE) {
+        level
+    } else {
+        log_level::NONE
+    }
+}
 
-    // Определяем страну по упрощённой логике (на основе первого октета)
-    let country = lookup_country(src_ip);
-    info!(&ctx, "Traffic originates from country: {}", country);
+/// Добавляет пакет размером `bytes` к счётчику `key`.
+#[inline(always)]
+fn account<K>(map: &PerCpuHashMap<K, PacketStats>, key: &K, bytes: u64) {
+    match map.get_ptr_mut(key) {
+        Some(entry) => unsafe {
+            (*entry).packets += 1;
+            (*entry).bytes += bytes;
+        },
+        None => {
+            if map.insert(key, &PacketStats { packets: 1, bytes }, 0).is_err() {
+                count_map_full();
+            }
+        }
+    }
+}
 
-    // Извлекаем только исходный порт, используя фиксированное смещение.
-    let source_port = match unsafe { (*ipv4hdr).proto } {
-        IpProto::Tcp => {
-            let tcphdr: *const TcpHdr = ptr_at(&ctx, ETH_HDR_LEN + IPV4_HDR_LEN)?;
-            u16::from_be(unsafe { (*tcphdr).source })
+/// Учитывает пакет QUIC в `stats::QUIC_PACKETS` и `stats::QUIC_BYTES`; `bytes` — длина
+/// кадра.
+#[inline(always)]
+fn account_quic(packet: &Packet, bytes: u64) {
+    if !packet.is_quic() {
+        return;
+    }
+    if let Some(counter) = STATS.get_ptr_mut(stats::QUIC_PACKETS) {
+        unsafe { *counter += 1 };
+    }
+    if let Some(counter) = STATS.get_ptr_mut(stats::QUIC_BYTES) {
+        unsafe { *counter += bytes };
+    }
+}
+
+/// Добавляет пакет к счётчику источника `src`; `bytes` — длина пакета IP.
+#[inline(always)]
+fn account_source(src: u32, bytes: u64) {
+    match SOURCE_STATS.get_ptr_mut(&src) {
+        Some(entry) => unsafe {
+            (*entry).packets += 1;
+            (*entry).bytes += bytes;
+        },
+        // LRU-карта освобождает место сама, ошибка тут — не переполнение.
+        None => {
+            let _ = SOURCE_STATS.insert(&src, &PacketStats { packets: 1, bytes }, 0);
         }
-        IpProto::Udp => {
-            let udphdr: *const UdpHdr = ptr_at(&ctx, ETH_HDR_LEN + IPV4_HDR_LEN)?;
-            u16::from_be(unsafe { (*udphdr).source })
+    }
+}
+
+/// Забирает токен из ведра источника в `buckets`; `true` — источник превысил предел из
+/// настройки `rate_setting` (ёмкость ведра — в `burst_setting`).
+///
+/// Как и `FLOWS`, карта общая для всех CPU: одновременные пакеты одного источника изредка
+/// забирают один и тот же токен, так что предел соблюдается приблизительно.
+#[inline(always)]
+fn rate_limited(
+    buckets: &LruHashMap<u32, RateState>,
+    src: u32,
+    rate_setting: u32,
+    burst_setting: u32,
+) -> bool {
+    let rate = setting(rate_setting);
+    if rate == 0 {
+        return false;
+    }
+    let burst = setting(burst_setting);
+    let now = unsafe { bpf_ktime_get_ns() };
+    match buckets.get_ptr_mut(&src) {
+        Some(state) => !unsafe { (*state).take(now, rate, burst) },
+        None => {
+            let mut state = RateState::full(now, burst);
+            let allowed = state.take(now, rate, burst);
+            let _ = buckets.insert(&src, &state, 0);
+            !allowed
         }
-        _ => {
-            info!(&ctx, "Unsupported protocol, dropping");
-            return Ok(xdp_action::XDP_DROP);
+    }
+}
+
+/// Проверяет первый пакет рукопожатия TCP по пределу `SYN_RATE_LIMIT` и учитывает
+/// отброшенные в `stats::SYN_FLOOD`.
+#[inline(always)]
+fn syn_flood(packet: &Packet) -> bool {
+    if !packet.is_bare_syn()
+        || !rate_limited(
+            &SYN_BUCKETS,
+            packet.src_addr,
+            settings::SYN_RATE_LIMIT,
+            settings::SYN_RATE_BURST,
+        )
+    {
+        return false;
+    }
+    if let Some(counter) = STATS.get_ptr_mut(stats::SYN_FLOOD) {
+        unsafe { *counter += 1 };
+    }
+    true
+}
+
+/// Учитывает запись, которую не удалось добавить в карту (обычно `E2BIG`: карта заполнена).
+#[inline(always)]
+fn count_map_full() {
+    if let Some(counter) = STATS.get_ptr_mut(stats::MAP_FULL) {
+        unsafe { *counter += 1 };
+    }
+}
+
+/// Учитывает пакет в таблице потоков, если загрузчик включил `FLOW_TRACKING`.
+///
+/// Карта общая для всех CPU, поэтому счётчики одного потока на разных CPU могут изредка
+/// терять обновления; для экспорта статистики это допустимо.
+#[inline(always)]
+fn track_flow(packet: &Packet, bytes: u64) {
+    if setting(settings::FLOW_TRACKING) == 0 {
+        return;
+    }
+    let key = packet.flow_key();
+    let tcp_flags = packet.tcp_flags;
+    let now = unsafe { bpf_ktime_get_ns() };
+    match FLOWS.get_ptr_mut(&key) {
+        Some(flow) => unsafe {
+            (*flow).packets += 1;
+            (*flow).bytes += bytes;
+            (*flow).last_seen_ns = now;
+            (*flow).tcp_flags |= tcp_flags;
+        },
+        None => {
+            let flow = FlowStats {
+                packets: 1,
+                bytes,
+                first_seen_ns: now,
+                last_seen_ns: now,
+                tcp_flags,
+                _pad: [0; 7],
+            };
+            // LRU-карта при заполнении вытесняет самый старый поток, ошибка здесь редкость.
+            if FLOWS.insert(&key, &flow, 0).is_err() {
+                count_map_full();
+            }
+        }
+    }
+}
+
+/// Отправляет событие в `EVENTS`; переполненный буфер не должен влиять на решение.
+#[inline(always)]
+fn send_event(event: &DropEvent) {
+    if event.fields == 0 && event.flags == 0 {
+        // Без необязательных полей отправляется только обязательная часть события.
+        let start = (event as *const DropEvent).cast::<u8>();
+        let core = unsafe { core::slice::from_raw_parts(start, DropEvent::CORE_LEN) };
+        let _ = EVENTS.output(core, 0);
+    } else {
+        let _ = EVENTS.output(event, 0);
+    }
+}
+
+/// Отбрасывает пакет, отправляя событие в `EVENTS`, если оно попало в выборку, и
+/// учитывает его причину в `DROP_REASONS`.
+///
+/// В режиме `--dry-run` пакет пропускается: событие уходит с флагом
+/// [`event_flags::DRY_RUN`], а пакет учитывается в `stats::DRY_RUN`.
+#[inline(always)]
+fn drop_packet(event: &DropEvent) -> u32 {
+    // Аварийный режим отбрасывает и в пробном.
+    let dry_run = setting(settings::MODE) == mode::DRY_RUN && setting(settings::PANIC) == 0;
+    if fast_path() {
+        return if dry_run { xdp_action::XDP_PASS } else { xdp_action::XDP_DROP };
+    }
+    if let Some(counter) = DROP_REASONS.get_ptr_mut(u32::from(event.reason)) {
+        unsafe { *counter += 1 };
+    }
+    match COUNTRY_DROPS.get_ptr_mut(&event.country) {
+        Some(counter) => unsafe { *counter += 1 },
+        None => {
+            if COUNTRY_DROPS.insert(&event.country, &1, 0).is_err() {
+                count_map_full();
+            }
+        }
+    }
+    if sampled(SAMPLER_EVENTS, settings::EVENT_SAMPLE_RATE) {
+        if dry_run {
+            let mut event = *event;
+            event.flags |= event_flags::DRY_RUN;
+            send_event(&event);
+        } else {
+            send_event(event);
         }
+    }
+    if !dry_run {
+        return xdp_action::XDP_DROP;
+    }
+    if let Some(counter) = STATS.get_ptr_mut(stats::DRY_RUN) {
+        unsafe { *counter += 1 };
+    }
+    xdp_action::XDP_PASS
+}
+
+/// Пропускает пакет; с `--log-allows` каждый N-й пропущенный пакет тоже даёт событие.
+#[inline(always)]
+fn pass_packet(packet: &Packet) -> u32 {
+    if !fast_path() && sampled(SAMPLER_ALLOWS, settings::ALLOW_SAMPLE_RATE) {
+        let fields = setting(settings::EVENT_FIELDS) as u8;
+        send_event(&packet.drop_event(DropReason::Allowed, fields));
+    }
+    xdp_action::XDP_PASS
+}
+
+/// Аварийный режим `settings::PANIC`: проходят пакеты IPv4 из префиксов `FAST_ACCEPT` и
+/// ARP — без него до хоста не добраться и с доверенных адресов. Остальное отбрасывается с
+/// причиной [`DropReason::Panic`].
+#[inline(always)]
+fn try_panic(
+    ctx: &XdpContext,
+    mut offset: usize,
+    ether_type: u16,
+    vlan: u16,
+    packet_len: u64,
+) -> Result<u32, ()> {
+    let mut packet = Packet {
+        len: packet_len as u16,
+        vlan,
+        ..Default::default()
     };
+    match ether_type {
+        ETH_P_ARP => return Ok(xdp_action::XDP_PASS),
+        ETH_P_IPV4 => {
+            let ipv4hdr: *const Ipv4Hdr = header_with_len(ctx, &mut offset, header_len)?;
+            if FAST_ACCEPT.get(&Key::new(32, unsafe { (*ipv4hdr).src_addr })).is_some() {
+                return Ok(xdp_action::XDP_PASS);
+            }
+            packet.src_addr = u32::from_be(unsafe { (*ipv4hdr).src_addr });
+            packet.dst_addr = u32::from_be(unsafe { (*ipv4hdr).dst_addr });
+            packet.proto = unsafe { (*ipv4hdr).proto } as u8;
+        }
+        _ => {}
+    }
+    Ok(drop_packet(&packet.drop_event(DropReason::Panic, 0)))
+}
 
-    info!(&ctx, "Parsed source port: {}", source_port);
+fn try_xdp_firewall(ctx: XdpContext) -> Result<u32, ()> {
+    // В режиме замера нет ни логов, ни учёта: остаются разбор и правила.
+    let fast = fast_path();
+    // Решение о логировании принимается один раз на пакет: подробные сообщения о разборе и
+    // пропуске — только на уровне `VERBOSE`, об отбрасывании — начиная с `DROPS`.
+    let level = if fast { log_level::NONE } else { packet_log_level() };
+    let log = level >= log_level::VERBOSE;
+    let log_drops = level >= log_level::DROPS;
+    // И пассивный мониторинг, и пауза пропускают весь трафик, продолжая его считать. Пробный
+    // режим проверяет правила как обычно и пропускает пакеты только в `drop_packet`.
+    let count_only = mode::passes_all(setting(settings::MODE));
+    let packet_len = (ctx.data_end() - ctx.data()) as u64;
+
+    // Парсим заголовок Ethernet; `offset` дальше указывает на начало следующего заголовка.
+    let mut offset = 0;
+    let ethhdr: *const EthFrameHdr = header_at(&ctx, &mut offset)?;
+    let ether_type = u16::from_be(unsafe { (*ethhdr).ether_type });
+    if log {
+        info!(&ctx, "Ethernet header parsed");
+    }
+    // MAC-адрес проверяется до разбора IP: так отсекаются и кадры с подделанным адресом IP.
+    if !count_only
+        && setting(settings::MAC_FILTER) != 0
+        && unsafe { BLOCKED_MACS.get(&(*ethhdr).src_addr) }.is_some()
+    {
+        // Адресов и портов кадр ещё не дал, у события есть только причина.
+        let event = Packet::default().drop_event(DropReason::BlockedMac, 0);
+        return Ok(drop_packet(&event));
+    }
+
+    let (ether_type, vlan) = vlan_tags(&ctx, &mut offset, ether_type)?;
 
-    // Разрешённые входящие порты HTTP/HTTPS.
-    const ALLOWED_HTTP: u16 = 80;
-    const ALLOWED_HTTPS: u16 = 443;
+    // Аварийный режим решает раньше паузы и всех правил.
+    if setting(settings::PANIC) != 0 {
+        return try_panic(&ctx, offset, ether_type, vlan, packet_len);
+    }
+
+    // ARP разбирается до пропуска широковещательных кадров: запросы ARP широковещательные, и
+    // иначе `--arp-subnet` их бы не касался.
+    if ether_type == ETH_P_ARP {
+        return try_arp(&ctx, offset, level, count_only);
+    }
+    // Широковещательные и групповые кадры (DHCP, mDNS) без `--filter-multicast` пропускаются,
+    // не доходя до правил IP.
+    if classify::is_multicast_mac(unsafe { &(*ethhdr).dst_addr })
+        && setting(settings::FILTER_MULTICAST) == 0
+    {
+        if log {
+            info!(&ctx, "Broadcast/multicast frame passed");
+        }
+        return Ok(xdp_action::XDP_PASS);
+    }
 
-    if source_port == ALLOWED_HTTP || source_port == ALLOWED_HTTPS || source_port == 53 {
+    match ether_type {
+        ETH_P_IPV4 => {}
+        ETH_P_IPV6 => return try_ipv6(&ctx, offset, vlan, level, count_only, fast, packet_len),
+        _ => return Ok(xdp_action::XDP_PASS),
+    }
+
+    let mut packet = Packet {
+        len: packet_len as u16,
+        vlan,
+        ..Default::default()
+    };
+    let unwrap_ipip = setting(settings::UNWRAP_IPIP) != 0;
+    let ipv4hdr = parse_ipv4(&ctx, &mut offset, unwrap_ipip, &mut packet)?;
+    let src_ip = packet.src_addr;
+    let dst_ip = packet.dst_addr;
+    if log {
         info!(
             &ctx,
-            "Allowed traffic: packet from {:i}:{}",
+            "IPv4 header parsed: SRC IP: {:i}, DST IP: {:i}",
             src_ip,
-            source_port
+            dst_ip
         );
-        Ok(xdp_action::XDP_PASS)
+    }
+
+    // Источник учитывается до любых решений, так что в счётчик попадает весь его трафик.
+    if !fast {
+        account_source(src_ip, u64::from(u16::from_be(unsafe { (*ipv4hdr).tot_len })));
+        account_quic(&packet, packet_len);
+    }
+
+    // Вердикт внешнего классификатора и доверенный источник решают до учёта по странам и
+    // портам и до любых правил; в режиме только подсчёта учитывается весь трафик.
+    if !count_only {
+        let value = unsafe { VERDICT_OVERRIDES.get(&packet.flow_key()) }.copied();
+        match classify::override_verdict(value) {
+            Some(Verdict::Pass) => return Ok(pass_packet(&packet)),
+            Some(Verdict::Drop(reason)) => {
+                let fields = setting(settings::EVENT_FIELDS) as u8;
+                return Ok(drop_packet(&packet.drop_event(reason, fields)));
+            }
+            None => {}
+        }
+        if setting(settings::FAST_ACCEPT) != 0
+            && FAST_ACCEPT.get(&Key::new(32, unsafe { (*ipv4hdr).src_addr })).is_some()
+        {
+            return Ok(pass_packet(&packet));
+        }
+    }
+
+    // Страна — из базы `--country-db`, а для адресов вне её — по первому октету.
+    let db = COUNTRIES.get(&Key::new(32, unsafe { (*ipv4hdr).src_addr })).copied();
+    packet.country = source_country(db, src_ip);
+    if log {
+        let code = unpack_country(packet.country);
+        let country = core::str::from_utf8(&code).unwrap_or("??");
+        info!(&ctx, "Traffic originates from country: {}", country);
+    }
+    if !fast {
+        account(&COUNTRY_STATS, &packet.country, packet_len);
+        account(&PORT_STATS, &packet.dst_port, packet_len);
+        track_flow(&packet, packet_len);
+    }
+    // В режиме только подсчёта правила не проверяются вовсе, а с `--protected-ips` — для
+    // пакетов к чужим адресам.
+    if count_only
+        || (setting(settings::PROTECTED_IPS) != 0
+            && PROTECTED_IPS.get(&Key::new(32, unsafe { (*ipv4hdr).dst_addr })).is_none())
+    {
+        return Ok(pass_packet(&packet));
+    }
+
+    if log {
+        info!(&ctx, "Parsed source port: {}", packet.src_port);
+    }
+
+    // Испытываемый адрес только считается, решение о нём примет firewall-cli block.
+    if !fast {
+        if let Some(matches) = STAGED_IPS.get_ptr_mut(&packet.src_addr) {
+            unsafe { *matches += 1 };
+        }
+    }
+
+    // Флуд SYN отсекается до правил: каждый такой пакет иначе обошёлся бы в полную проверку.
+    let verdict = if syn_flood(&packet) {
+        Verdict::Drop(DropReason::SynFlood)
+    } else if setting(settings::PROFILE) != 0 {
+        timed(rule_costs::DECIDE, || classify::decide(&packet, &ProfiledRules))
     } else {
-        info!(
-            &ctx,
-            "Blocked traffic: packet from {:i}:{}",
-            src_ip,
-            source_port
-        );
-        Ok(xdp_action::XDP_DROP)
+        classify::decide(&packet, &MapRules)
+    };
+    // Предел частоты касается только пакетов, которые правила пропустили бы.
+    let verdict = match verdict {
+        Verdict::Pass
+            if rate_limited(
+                &RATE_BUCKETS,
+                packet.src_addr,
+                settings::RATE_LIMIT,
+                settings::RATE_BURST,
+            ) =>
+        {
+            Verdict::Drop(DropReason::RateLimited)
+        }
+        verdict => verdict,
+    };
+    match verdict {
+        Verdict::Pass => {
+            if log && !is_transport(packet.proto) {
+                info!(
+                    &ctx,
+                    "Allowed traffic: packet from {:i} with protocol {}",
+                    src_ip,
+                    packet.proto
+                );
+            } else if log {
+                info!(
+                    &ctx,
+                    "Allowed traffic: packet from {:i}:{}",
+                    src_ip,
+                    packet.src_port
+                );
+            }
+            Ok(pass_or_redirect(&ctx, &packet))
+        }
+        Verdict::Drop(reason) => {
+            if log_drops && reason == DropReason::UnsupportedProtocol {
+                info!(
+                    &ctx,
+                    "Blocked traffic: packet from {:i} with unknown protocol {}",
+                    src_ip,
+                    packet.proto
+                );
+            } else if log_drops {
+                info!(
+                    &ctx,
+                    "Blocked traffic: packet from {:i}:{} ({})",
+                    src_ip,
+                    packet.src_port,
+                    reason.as_str()
+                );
+            }
+            // Необязательные поля заполняются только по запросу (`--event-fields`).
+            let fields = setting(settings::EVENT_FIELDS) as u8;
+            Ok(drop_packet(&packet.drop_event(reason, fields)))
+        }
+    }
+}
+
+/// Пропускает разрешённый пакет или, для портов из `XSK_PORTS`, перенаправляет его в AF_XDP.
+#[inline(always)]
+fn pass_or_redirect(ctx: &XdpContext, packet: &Packet) -> u32 {
+    let xsk_port = setting(settings::XSK_REDIRECT) != 0
+        && unsafe { XSK_PORTS.get(&packet.dst_port) }.is_some();
+    let queue = unsafe { (*ctx.ctx).rx_queue_index };
+    match xsk_action(xsk_port, |flags| XSKS.redirect(queue, flags)) {
+        Some(action) => action,
+        None => pass_packet(packet),
+    }
+}
+
+/// Пропускает ARP, а с `--arp-subnet` — только от отправителей из этой сети. Без ARP
+/// теряется связь со всем сегментом, поэтому политика по умолчанию и правила IP к нему не
+/// применяются; ARP не для Ethernet и IPv4 пропускается без проверки.
+#[inline(always)]
+fn try_arp(ctx: &XdpContext, mut offset: usize, level: u32, count_only: bool) -> Result<u32, ()> {
+    let arphdr: *const ArpHdr = header_at(ctx, &mut offset)?;
+    let ethernet_ipv4 = unsafe {
+        classify::is_ethernet_ipv4_arp(
+            u16::from_be((*arphdr).htype),
+            u16::from_be((*arphdr).ptype),
+            (*arphdr).hlen,
+            (*arphdr).plen,
+        )
+    };
+    if !ethernet_ipv4 {
+        return Ok(xdp_action::XDP_PASS);
+    }
+    let op = u16::from_be(unsafe { (*arphdr).oper });
+    let sender = u32::from_be_bytes(unsafe { (*arphdr).spa });
+    if level >= log_level::VERBOSE {
+        match op {
+            arp::REQUEST => info!(ctx, "ARP request from {:i}", sender),
+            arp::REPLY => info!(ctx, "ARP reply from {:i}", sender),
+            _ => {}
+        }
     }
+    let allowed = classify::arp_allowed(
+        sender,
+        setting(settings::ARP_SUBNET),
+        setting(settings::ARP_PREFIX),
+    );
+    if count_only || allowed {
+        return Ok(xdp_action::XDP_PASS);
+    }
+    if level >= log_level::DROPS {
+        info!(ctx, "Blocked traffic: ARP from {:i} outside --arp-subnet", sender);
+    }
+    let packet = Packet {
+        src_addr: sender,
+        ..Default::default()
+    };
+    Ok(drop_packet(&packet.drop_event(DropReason::Arp, 0)))
 }
 
-/// Функция определения "страны" по первому октету IP-адреса.
+/// Разбирает пакет IPv6 и применяет к нему правила, не зависящие от адреса.
 ///
-/// Это упрощённая демонстрационная логика, где для разных значений
-/// первого октета возвращаются различные коды стран. В реальном приложении
-/// необходимо использовать корректную базу данных IP-диапазонов.
-fn lookup_country(src_ip: u32) -> &'static str {
-    let first_octet = (src_ip >> 24) as u8;
-    match first_octet {
-        1  => "US", // Соединённые Штаты
-        2  => "CA", // Канада
-        3  => "MX", // Мексика
-        4  => "BR", // Бразилия
-        5  => "RU", // Россия
-        6  => "CN", // Китай
-        7  => "IN", // Индия
-        8  => "GB", // Великобритания
-        9  => "DE", // Германия
-        10 => "FR", // Франция
-        11 => "ES", // Испания
-        12 => "IT", // Италия
-        13 => "AU", // Австралия
-        14 => "JP", // Япония
-        15 => "KR", // Южная Корея
-        16 => "SE", // Швеция
-        17 => "NO", // Норвегия
-        18 => "FI", // Финляндия
-        19 => "DK", // Дания
-        20 => "NL", // Нидерланды
-        21 => "BE", // Бельгия
-        22 => "CH", // Швейцария
-        23 => "AT", // Австрия
-        24 => "PL", // Польша
-        25 => "CZ", // Чехия
-        26 => "SK", // Словакия
-        27 => "HU", // Венгрия
-        28 => "RO", // Румыния
-        29 => "BG", // Болгария
-        30 => "TR", // Турция
-        // Можно добавить остальные необходимые страны.
-        _  => "OTHER",
+/// Заголовки, от основного (по смещению `offset`, после тегов VLAN) до транспортного,
+/// разбирает [`parse_ipv6`].
+/// Из адресных правил для IPv6 есть только `BLOCKED_IPS6`. Остальные, учёт потоков, вердикты
+/// по 5-кортежу и доверенные префиксы заданы для IPv4, поэтому адреса IPv4 в [`Packet`]
+/// остаются нулевыми, а страна — неизвестной.
+#[inline(always)]
+fn try_ipv6(
+    ctx: &XdpContext,
+    mut offset: usize,
+    vlan: u16,
+    level: u32,
+    count_only: bool,
+    fast: bool,
+    packet_len: u64,
+) -> Result<u32, ()> {
+    let log = level >= log_level::VERBOSE;
+    let log_drops = level >= log_level::DROPS;
+    let mut packet = Packet {
+        len: packet_len as u16,
+        vlan,
+        ..Default::default()
+    };
+    let ipv6hdr = parse_ipv6(ctx, &mut offset, &mut packet)?;
+    if log {
+        let src = unsafe { (*ipv6hdr).src_addr.in6_u.u6_addr8 };
+        let dst = unsafe { (*ipv6hdr).dst_addr.in6_u.u6_addr8 };
+        info!(ctx, "IPv6 header parsed: SRC IP: {:i}, DST IP: {:i}", src, dst);
+    }
+
+    if !fast {
+        account_quic(&packet, packet_len);
+        account(&COUNTRY_STATS, &packet.country, packet_len);
+        account(&PORT_STATS, &packet.dst_port, packet_len);
+    }
+    // Защищаемые адреса `--protected-ips` — IPv4, так что с ними IPv6 не проверяется.
+    if count_only || setting(settings::PROTECTED_IPS) != 0 {
+        return Ok(pass_packet(&packet));
+    }
+
+    let verdict = if setting(settings::PROFILE) != 0 {
+        timed(rule_costs::DECIDE, || classify::decide_ipv6(&packet, &ProfiledRules))
+    } else {
+        classify::decide_ipv6(&packet, &MapRules)
+    };
+    match verdict {
+        Verdict::Pass => {
+            if log && !is_transport(packet.proto) {
+                info!(ctx, "Allowed IPv6 traffic with protocol {}", packet.proto);
+            } else if log {
+                info!(ctx, "Allowed IPv6 traffic: source port {}", packet.src_port);
+            }
+            Ok(pass_or_redirect(ctx, &packet))
+        }
+        Verdict::Drop(reason) => {
+            if log_drops && reason == DropReason::UnsupportedProtocol {
+                info!(ctx, "Blocked IPv6 traffic with unknown protocol {}", packet.proto);
+            } else if log_drops {
+                info!(
+                    ctx,
+                    "Blocked IPv6 traffic: source port {} ({})",
+                    packet.src_port,
+                    reason.as_str()
+                );
+            }
+            let fields = setting(settings::EVENT_FIELDS) as u8;
+            Ok(drop_packet(&packet.drop_event(reason, fields)))
+        }
+    }
+}
+
+/// Учитывает пакет, отброшенный по записи блокировки со счётчиком `hits`, и сообщает, что
+/// запись нашлась.
+///
+/// Карты блокировки общие для всех CPU, так что, как и в `FLOWS`, одновременные пакеты
+/// изредка теряют обновление: для того, чтобы отличить работающую запись от мёртвой, это
+/// неважно.
+#[inline(always)]
+fn count_hit(hits: *mut u64) -> bool {
+    if !fast_path() {
+        unsafe { *hits += 1 };
+    }
+    true
+}
+
+/// Значение самой длинной сети из `trie`, в которую входит `key`, для записи.
+///
+/// У `LpmTrie` из aya-ebpf нет `get_ptr_mut`, а `get` отдаёт общую ссылку, писать через
+/// которую нельзя. Поэтому поиск идёт прямо через `bpf_map_lookup_elem`: он возвращает
+/// указатель на изменяемое значение карты, как и у хеш-карты. `LpmTrie` —
+/// `repr(transparent)` над описанием карты, так что её адрес и есть адрес описания.
+#[inline(always)]
+fn trie_value_mut<K, V>(trie: &LpmTrie<K, V>, key: &Key<K>) -> Option<*mut V> {
+    let value = unsafe {
+        bpf_map_lookup_elem(
+            trie as *const LpmTrie<K, V> as *mut c_void,
+            key as *const Key<K> as *const c_void,
+        )
+    };
+    (!value.is_null()).then_some(value.cast())
+}
+
+/// Правила из карт, которые заполняет загрузчик.
+struct MapRules;
+
+impl Rules for MapRules {
+    #[inline(always)]
+    fn is_blocked_ip(&self, addr: u32) -> bool {
+        if let Some(hits) = BLOCKED_IPS.get_ptr_mut(&addr) {
+            return count_hit(hits);
+        }
+        match trie_value_mut(&BLOCKED_NETS, &Key::new(32, addr.to_be())) {
+            Some(hits) => count_hit(hits),
+            None => false,
+        }
+    }
+
+    #[inline(always)]
+    fn is_blocked_ip6(&self, addr: &[u8; 16]) -> bool {
+        match trie_value_mut(&BLOCKED_IPS6, &Key::new(128, *addr)) {
+            Some(hits) => count_hit(hits),
+            None => false,
+        }
+    }
+
+    #[inline(always)]
+    fn is_blocked_mask(&self, addr: u32) -> bool {
+        let count = setting(settings::BLOCKED_MASKS);
+        for index in 0..MAX_BLOCKED_MASKS {
+            if index >= count {
+                break;
+            }
+            if BLOCKED_MASKS.get(index).is_some_and(|rule| rule.matches(addr)) {
+                return true;
+            }
+        }
+        false
+    }
+
+    #[inline(always)]
+    fn is_blocked_country(&self, country: u16) -> bool {
+        unsafe { BLOCKED_COUNTRIES.get(&country) }.is_some()
+    }
+
+    #[inline(always)]
+    fn country_window_open(&self) -> bool {
+        time_window::contains(setting(settings::COUNTRY_WINDOW), setting(settings::CLOCK_MINUTE))
+    }
+
+    #[inline(always)]
+    fn is_blocked_region(&self, addr: u32) -> bool {
+        setting(settings::REGION_MATCH) != 0 && REGIONS.get(&Key::new(32, addr.to_be())).is_some()
+    }
+
+    #[inline(always)]
+    fn is_allowed_ip(&self, addr: u32) -> bool {
+        unsafe { ALLOWED_IPS.get(&addr) }.is_some()
+    }
+
+    #[inline(always)]
+    fn is_blocked_tcp_window(&self, window: u16) -> bool {
+        setting(settings::TCP_WINDOW_FILTER) != 0 && unsafe { TCP_WINDOWS.get(&window) }.is_some()
+    }
+
+    #[inline(always)]
+    fn filters_tcp_flags(&self) -> bool {
+        setting(settings::TCP_FLAG_FILTER) != 0
+    }
+
+    #[inline(always)]
+    fn is_allowed_port(&self, port: u16, proto: u8) -> bool {
+        let bit = port_protos::bit(proto);
+        unsafe { ALLOWED_PORTS.get(&port) }.is_some_and(|protos| protos & bit != 0)
+    }
+
+    #[inline(always)]
+    fn is_blocked_src_port(&self, port: u16, proto: u8) -> bool {
+        let bit = port_protos::bit(proto);
+        unsafe { BLOCKED_SRC_PORTS.get(&port) }.is_some_and(|protos| protos & bit != 0)
+    }
+
+    #[inline(always)]
+    fn port_match_src(&self) -> bool {
+        setting(settings::PORT_MATCH) != 0
+    }
+
+    #[inline(always)]
+    fn is_icmp_echo_allowed(&self) -> bool {
+        setting(settings::ICMP_ECHO) != 0
+    }
+
+    #[inline(always)]
+    fn default_allow(&self) -> bool {
+        setting(settings::DEFAULT_POLICY) != 0
+    }
+
+    #[inline(always)]
+    fn strict_protocols(&self) -> bool {
+        setting(settings::STRICT_PROTOCOLS) != 0
+    }
+
+    #[inline(always)]
+    fn protocol_rule(&self, proto: u8) -> u8 {
+        PROTOCOLS.get(u32::from(proto)).copied().unwrap_or(protocol_rule::UNLISTED)
+    }
+
+    #[inline(always)]
+    fn drops_fragments(&self) -> bool {
+        setting(settings::DROP_FRAGMENTS) != 0
+    }
+
+    #[inline(always)]
+    fn allows_dns(&self) -> bool {
+        setting(settings::ALLOW_DNS) != 0
+    }
+
+    /// Устаревшая запись удаляется, живая продлевается входящим пакетом.
+    #[inline(always)]
+    fn is_established(&self, packet: &Packet) -> bool {
+        let timeout = setting(settings::CONNTRACK_TIMEOUT);
+        if timeout == 0 {
+            return false;
+        }
+        let key = packet.conn_key();
+        let Some(last_seen) = CONNTRACK.get_ptr_mut(&key) else {
+            return false;
+        };
+        let now = unsafe { bpf_ktime_get_ns() };
+        if !conntrack_alive(unsafe { *last_seen }, now, timeout) {
+            let _ = CONNTRACK.remove(&key);
+            return false;
+        }
+        unsafe { *last_seen = now };
+        true
+    }
+
+    #[inline(always)]
+    fn is_blocked_endpoint(&self, packet: &Packet) -> bool {
+        let addr = match setting(settings::ENDPOINT_MATCH) {
+            1 => packet.src_addr,
+            2 => packet.dst_addr,
+            _ => return false,
+        };
+        let key = Key::new(48, endpoint_key(addr, packet.dst_port));
+        BLOCKED_ENDPOINTS.get(&key).is_some()
+    }
+
+    #[inline(always)]
+    fn knock_port(&self) -> u16 {
+        setting(settings::KNOCK_PORT) as u16
+    }
+
+    /// Открытый порт продлевается пакетом к нему, как запись `CONNTRACK`, поэтому сессия
+    /// не обрывается, пока по ней идёт трафик.
+    #[inline(always)]
+    fn knock(&self, packet: &Packet) -> bool {
+        let now = unsafe { bpf_ktime_get_ns() };
+        let src = packet.src_addr;
+        if packet.dst_port == self.knock_port() {
+            let Some(last_seen) = KNOCK_UNLOCKED.get_ptr_mut(&src) else {
+                return false;
+            };
+            if !conntrack_alive(unsafe { *last_seen }, now, setting(settings::KNOCK_TIMEOUT)) {
+                let _ = KNOCK_UNLOCKED.remove(&src);
+                return false;
+            }
+            unsafe { *last_seen = now };
+            return true;
+        }
+        let len = setting(settings::KNOCK_LEN);
+        let step = match unsafe { KNOCK_PROGRESS.get(&src) } {
+            Some(progress) if conntrack_alive(progress.at_ns, now, KNOCK_STEP_SECS) => {
+                progress.step
+            }
+            _ => 0,
+        };
+        let at = |step: u32| if step < len { KNOCK_SEQUENCE.get(step).copied() } else { None };
+        let next = knock_next(step, packet.dst_port, at);
+        if next == step {
+            return false;
+        }
+        if next >= len {
+            let _ = KNOCK_PROGRESS.remove(&src);
+            let _ = KNOCK_UNLOCKED.insert(&src, &now, 0);
+        } else if next == 0 {
+            let _ = KNOCK_PROGRESS.remove(&src);
+        } else {
+            let progress = KnockProgress {
+                step: next,
+                _pad: 0,
+                at_ns: now,
+            };
+            let _ = KNOCK_PROGRESS.insert(&src, &progress, 0);
+        }
+        false
+    }
+
+    #[inline(always)]
+    fn filters_direction(&self, direction: Direction) -> bool {
+        direction::filters(setting(settings::DIRECTIONS), direction)
+    }
+
+    /// Запись заводит SYN к разрешённому порту, а продлевает любой пакет соединения, так
+    /// что в окно попадают только соединения, по которым шёл трафик.
+    #[inline(always)]
+    fn in_grace(&self, packet: &Packet, allowed: bool) -> bool {
+        let timeout = setting(settings::CONNTRACK_TIMEOUT);
+        // У пакетов IPv6 адреса IPv4 нулевые: их соединения не различить.
+        if timeout == 0 || packet.src_addr == 0 {
+            return false;
+        }
+        let key = packet.conn_key();
+        let now = unsafe { bpf_ktime_get_ns() };
+        if allowed {
+            if packet.is_bare_syn() {
+                let _ = ACCEPTED_CONNS.insert(&key, &now, 0);
+            } else if let Some(last_seen) = ACCEPTED_CONNS.get_ptr_mut(&key) {
+                unsafe { *last_seen = now };
+            }
+            return false;
+        }
+        if packet.is_bare_syn() {
+            return false;
+        }
+        let Some(last_seen) = ACCEPTED_CONNS.get_ptr_mut(&key) else {
+            return false;
+        };
+        let grace_until = setting(settings::GRACE_UNTIL);
+        if !grace_alive(unsafe { *last_seen }, now, timeout, grace_until) {
+            let _ = ACCEPTED_CONNS.remove(&key);
+            return false;
+        }
+        unsafe { *last_seen = now };
+        true
+    }
+}
+
+/// Выполняет `check` и добавляет затраченное время к ячейке `slot` в `RULE_COSTS`.
+#[inline(always)]
+fn timed<T>(slot: u32, check: impl FnOnce() -> T) -> T {
+    let start = unsafe { bpf_ktime_get_ns() };
+    let result = check();
+    let elapsed = unsafe { bpf_ktime_get_ns() }.wrapping_sub(start);
+    if let Some(cost) = RULE_COSTS.get_ptr_mut(slot) {
+        unsafe {
+            (*cost).calls += 1;
+            (*cost).ns += elapsed;
+        }
+    }
+    result
+}
+
+/// Те же правила, что [`MapRules`], с замером времени каждой проверки.
+///
+/// В замер входит и вызов `bpf_ktime_get_ns`, поэтому дешёвые проверки выглядят дороже, чем
+/// есть; для сравнения типов правил между собой это неважно.
+struct ProfiledRules;
+
+impl Rules for ProfiledRules {
+    #[inline(always)]
+    fn is_blocked_ip(&self, addr: u32) -> bool {
+        timed(rule_costs::BLOCKED_IP, || MapRules.is_blocked_ip(addr))
+    }
+
+    #[inline(always)]
+    fn is_blocked_ip6(&self, addr: &[u8; 16]) -> bool {
+        timed(rule_costs::BLOCKED_IP, || MapRules.is_blocked_ip6(addr))
+    }
+
+    #[inline(always)]
+    fn is_blocked_mask(&self, addr: u32) -> bool {
+        timed(rule_costs::BLOCKED_MASK, || MapRules.is_blocked_mask(addr))
+    }
+
+    #[inline(always)]
+    fn is_blocked_country(&self, country: u16) -> bool {
+        timed(rule_costs::BLOCKED_COUNTRY, || MapRules.is_blocked_country(country))
+    }
+
+    #[inline(always)]
+    fn country_window_open(&self) -> bool {
+        MapRules.country_window_open()
+    }
+
+    #[inline(always)]
+    fn is_blocked_region(&self, addr: u32) -> bool {
+        timed(rule_costs::BLOCKED_REGION, || MapRules.is_blocked_region(addr))
+    }
+
+    #[inline(always)]
+    fn is_allowed_ip(&self, addr: u32) -> bool {
+        timed(rule_costs::ALLOWED_IP, || MapRules.is_allowed_ip(addr))
+    }
+
+    #[inline(always)]
+    fn is_blocked_tcp_window(&self, window: u16) -> bool {
+        timed(rule_costs::TCP_WINDOW, || MapRules.is_blocked_tcp_window(window))
+    }
+
+    #[inline(always)]
+    fn filters_tcp_flags(&self) -> bool {
+        MapRules.filters_tcp_flags()
+    }
+
+    #[inline(always)]
+    fn is_allowed_port(&self, port: u16, proto: u8) -> bool {
+        timed(rule_costs::ALLOWED_PORT, || MapRules.is_allowed_port(port, proto))
+    }
+
+    #[inline(always)]
+    fn is_blocked_src_port(&self, port: u16, proto: u8) -> bool {
+        timed(rule_costs::BLOCKED_SRC_PORT, || MapRules.is_blocked_src_port(port, proto))
+    }
+
+    #[inline(always)]
+    fn port_match_src(&self) -> bool {
+        MapRules.port_match_src()
+    }
+
+    #[inline(always)]
+    fn is_icmp_echo_allowed(&self) -> bool {
+        MapRules.is_icmp_echo_allowed()
+    }
+
+    #[inline(always)]
+    fn default_allow(&self) -> bool {
+        MapRules.default_allow()
+    }
+
+    #[inline(always)]
+    fn strict_protocols(&self) -> bool {
+        MapRules.strict_protocols()
+    }
+
+    #[inline(always)]
+    fn protocol_rule(&self, proto: u8) -> u8 {
+        MapRules.protocol_rule(proto)
+    }
+
+    #[inline(always)]
+    fn drops_fragments(&self) -> bool {
+        MapRules.drops_fragments()
+    }
+
+    #[inline(always)]
+    fn allows_dns(&self) -> bool {
+        MapRules.allows_dns()
+    }
+
+    #[inline(always)]
+    fn is_established(&self, packet: &Packet) -> bool {
+        MapRules.is_established(packet)
+    }
+
+    #[inline(always)]
+    fn is_blocked_endpoint(&self, packet: &Packet) -> bool {
+        timed(rule_costs::BLOCKED_ENDPOINT, || MapRules.is_blocked_endpoint(packet))
+    }
+
+    #[inline(always)]
+    fn knock_port(&self) -> u16 {
+        MapRules.knock_port()
+    }
+
+    #[inline(always)]
+    fn knock(&self, packet: &Packet) -> bool {
+        MapRules.knock(packet)
+    }
+
+    #[inline(always)]
+    fn filters_direction(&self, direction: Direction) -> bool {
+        MapRules.filters_direction(direction)
+    }
+
+    #[inline(always)]
+    fn in_grace(&self, packet: &Packet, allowed: bool) -> bool {
+        MapRules.in_grace(packet, allowed)
     }
 }
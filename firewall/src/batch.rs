@@ -0,0 +1,193 @@
+use std::{io, os::fd::AsFd as _};
+
+use aya::{
+    maps::{HashMap, Map},
+    Pod,
+};
+use firewall_common::probe;
+use log::warn;
+
+/// Каким способом записи попали в карту.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadMethod {
+    Batched,
+    PerEntry,
+}
+
+impl LoadMethod {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Batched => "batched",
+            Self::PerEntry => "per-entry",
+        }
+    }
+}
+
+/// Проверяет, что `needed` записей поместятся в карту, и иначе отказывает с понятной ошибкой.
+///
+/// Без проверки ядро отказало бы посреди загрузки (`E2BIG`), и часть списка молча не
+/// применилась бы. LRU-карты не проверяются: они вытесняют старые записи сами.
+pub fn ensure_fits(map: &Map, needed: usize, what: &str) -> anyhow::Result<()> {
+    let capacity = match map {
+        Map::HashMap(data) | Map::LpmTrie(data) => Some(data.info()?.max_entries() as usize),
+        _ => None,
+    };
+    check_fits(capacity, needed, what)
+}
+
+/// Проверка из [`ensure_fits`]; `capacity` — `None` для карт, которые вытесняют записи.
+fn check_fits(capacity: Option<usize>, needed: usize, what: &str) -> anyhow::Result<()> {
+    match capacity {
+        Some(capacity) if needed > capacity => {
+            anyhow::bail!("{needed} {what} do not fit: the map holds at most {capacity} entries")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Куда [`load`] записывает пары: карта ядра или, в тестах, её подмена.
+trait Sink<K, V> {
+    /// Записывает пары одним вызовом `BPF_MAP_UPDATE_BATCH`.
+    fn update_batch(&mut self, keys: &[K], values: &[V]) -> io::Result<()>;
+    /// Записывает пары по одной.
+    fn insert_each(&mut self, entries: &[(K, V)]) -> anyhow::Result<()>;
+}
+
+impl<K: Pod, V: Pod> Sink<K, V> for &mut Map {
+    fn update_batch(&mut self, keys: &[K], values: &[V]) -> io::Result<()> {
+        match &**self {
+            Map::HashMap(data) | Map::LruHashMap(data) => {
+                probe::update_batch(data.fd().as_fd(), keys, values)
+            }
+            _ => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    fn insert_each(&mut self, entries: &[(K, V)]) -> anyhow::Result<()> {
+        let mut hash: HashMap<_, K, V> = HashMap::try_from(&mut **self)?;
+        for (key, value) in entries {
+            hash.insert(key, value, 0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Записывает `entries` в хеш-карту пакетами по `batch_size` записей.
+///
+/// Если ядро не поддерживает `BPF_MAP_UPDATE_BATCH` или `batch_size` меньше 2, записи
+/// вставляются по одной. Отказ пакетного вызова посреди загрузки тоже не фатален: вставка
+/// в хеш-карту идемпотентна, поэтому оставшиеся записи дописываются по одной.
+pub fn insert_all<K: Pod, V: Pod>(
+    mut map: &mut Map,
+    entries: &[(K, V)],
+    batch_size: usize,
+    what: &str,
+) -> anyhow::Result<LoadMethod> {
+    ensure_fits(map, entries.len(), what)?;
+    let batched = matches!(map, Map::HashMap(_) | Map::LruHashMap(_))
+        && batch_size > 1
+        && probe::probe_map_batch().is_supported();
+    load(&mut map, entries, if batched { batch_size } else { 0 })
+}
+
+/// Записывает `entries` пакетами по `batch_size`, а то, что не удалось, — по одной;
+/// `batch_size` меньше 2 — только по одной.
+fn load<K: Copy, V: Copy>(
+    sink: &mut impl Sink<K, V>,
+    entries: &[(K, V)],
+    batch_size: usize,
+) -> anyhow::Result<LoadMethod> {
+    let mut start = 0;
+    if batch_size > 1 {
+        for chunk in entries.chunks(batch_size) {
+            let keys: Vec<K> = chunk.iter().map(|(k, _)| *k).collect();
+            let values: Vec<V> = chunk.iter().map(|(_, v)| *v).collect();
+            if let Err(e) = sink.update_batch(&keys, &values) {
+                warn!("batched map update failed, falling back to per-entry: {e}");
+                break;
+            }
+            start += chunk.len();
+        }
+        if start == entries.len() {
+            return Ok(LoadMethod::Batched);
+        }
+    }
+    sink.insert_each(&entries[start..])?;
+    Ok(LoadMethod::PerEntry)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    /// Карта в памяти; пакетные вызовы после `batches_left` отказывают, как на старом ядре.
+    #[derive(Default)]
+    struct Memory {
+        entries: BTreeMap<u32, u8>,
+        batches_left: usize,
+        batch_calls: usize,
+    }
+
+    impl Sink<u32, u8> for Memory {
+        fn update_batch(&mut self, keys: &[u32], values: &[u8]) -> io::Result<()> {
+            if self.batches_left == 0 {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            self.batches_left -= 1;
+            self.batch_calls += 1;
+            self.entries.extend(keys.iter().copied().zip(values.iter().copied()));
+            Ok(())
+        }
+
+        fn insert_each(&mut self, entries: &[(u32, u8)]) -> anyhow::Result<()> {
+            self.entries.extend(entries.iter().copied());
+            Ok(())
+        }
+    }
+
+    fn blocklist() -> Vec<(u32, u8)> {
+        (0..2500u32).map(|i| (0x0a00_0000 + i, 1)).collect()
+    }
+
+    #[test]
+    fn batched_and_per_entry_load_the_same_entries() {
+        let entries = blocklist();
+        let mut batched = Memory { batches_left: usize::MAX, ..Default::default() };
+        assert_eq!(load(&mut batched, &entries, 1024).unwrap(), LoadMethod::Batched);
+        assert_eq!(batched.batch_calls, 3);
+
+        let mut fallback = Memory::default();
+        assert_eq!(load(&mut fallback, &entries, 0).unwrap(), LoadMethod::PerEntry);
+        assert_eq!(fallback.batch_calls, 0);
+
+        assert_eq!(batched.entries.len(), entries.len());
+        assert_eq!(batched.entries, fallback.entries);
+    }
+
+    #[test]
+    fn full_blocklist_is_refused_and_lru_map_is_not() {
+        check_fits(Some(2500), blocklist().len(), "blocked IPs").unwrap();
+        let error = check_fits(Some(2499), blocklist().len(), "blocked IPs").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "2500 blocked IPs do not fit: the map holds at most 2499 entries"
+        );
+        // Таблица соединений — LRU-карта: при заполнении ядро вытесняет самую старую запись.
+        check_fits(None, 1 << 20, "connections").unwrap();
+    }
+
+    #[test]
+    fn failed_batch_is_finished_per_entry() {
+        let entries = blocklist();
+        let mut partial = Memory { batches_left: 1, ..Default::default() };
+        assert_eq!(load(&mut partial, &entries, 1024).unwrap(), LoadMethod::PerEntry);
+        assert_eq!(partial.batch_calls, 1);
+        assert_eq!(partial.entries.len(), entries.len());
+
+        let mut unsupported = Memory::default();
+        assert_eq!(load(&mut unsupported, &entries, 1024).unwrap(), LoadMethod::PerEntry);
+        assert_eq!(unsupported.entries.len(), entries.len());
+    }
+}
@@ -0,0 +1,77 @@
+//! Загрузка на ядрах без собственного BTF.
+
+use std::fmt::Display;
+
+use aya::Btf;
+use firewall_common::probe::VMLINUX_BTF;
+use log::warn;
+
+/// Как загружать программу в зависимости от наличия BTF ядра.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadPlan {
+    /// Передавать BTF ядра загрузчику aya для релокаций.
+    pub use_btf: bool,
+    /// Поднимать aya-log; без BTF логи программы отключаются, остаются события и счётчики.
+    pub enable_logger: bool,
+}
+
+impl LoadPlan {
+    pub fn for_kernel(btf_available: bool) -> Self {
+        Self {
+            use_btf: btf_available,
+            enable_logger: btf_available,
+        }
+    }
+}
+
+/// Читает BTF ядра и выбирает план загрузки; при отсутствии BTF предупреждает, что отключено.
+pub fn detect() -> (LoadPlan, Option<Btf>) {
+    choose(Btf::from_sys_fs())
+}
+
+/// План загрузки по результату чтения BTF ядра.
+fn choose<B, E: Display>(read: Result<B, E>) -> (LoadPlan, Option<B>) {
+    let btf = match read {
+        Ok(btf) => Some(btf),
+        Err(e) => {
+            warn!(
+                "kernel BTF is unavailable ({VMLINUX_BTF}: {e}); loading without BTF, eBPF logs \
+                 are disabled. Install the kernel BTF package or rebuild the kernel with \
+                 CONFIG_DEBUG_INFO_BTF=y to restore them"
+            );
+            None
+        }
+    };
+    (LoadPlan::for_kernel(btf.is_some()), btf)
+}
+
+/// Совет к ошибке загрузки: без BTF причина чаще всего именно в нём.
+pub fn load_error_hint(plan: LoadPlan) -> &'static str {
+    if plan.use_btf {
+        "failed to load the eBPF object"
+    } else {
+        "failed to load the eBPF object without kernel BTF; install kernel BTF \
+         (/sys/kernel/btf/vmlinux, CONFIG_DEBUG_INFO_BTF=y) and retry"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_btf_degrades_to_load_without_logs() {
+        let (plan, btf) = choose::<(), _>(Err("No such file or directory"));
+        assert_eq!(plan, LoadPlan { use_btf: false, enable_logger: false });
+        assert!(btf.is_none());
+        assert!(load_error_hint(plan).contains(VMLINUX_BTF));
+    }
+
+    #[test]
+    fn available_btf_is_used_with_logs() {
+        let (plan, btf) = choose::<_, &str>(Ok("vmlinux"));
+        assert_eq!(plan, LoadPlan { use_btf: true, enable_logger: true });
+        assert_eq!(btf, Some("vmlinux"));
+        assert_eq!(load_error_hint(plan), "failed to load the eBPF object");
+    }
+}
@@ -0,0 +1,71 @@
+//! Совместная работа с другими программами XDP на том же интерфейсе.
+//!
+//! Цепочки в экосистеме XDP строит диспетчер libxdp: он занимает интерфейс сам и вызывает
+//! программы-участники через `freplace` в порядке приоритета. Загрузчик пока не умеет
+//! встраиваться в такой диспетчер (программа собирается без BTF глобальных функций,
+//! который нужен для `freplace`), поэтому `--chain-priority` лишь находит диспетчер и
+//! объясняет, почему подключение будет монопольным.
+
+use std::{ffi::CString, fs, path::Path};
+
+use log::warn;
+
+/// Каталог bpffs, где libxdp закрепляет свои диспетчеры.
+const LIBXDP_PIN_DIR: &str = "/sys/fs/bpf/xdp";
+
+/// Ищет закреплённый диспетчер libxdp для интерфейса (`dispatch-<ifindex>-<prog_id>`).
+pub fn find_dispatcher(iface: &str) -> Option<String> {
+    let name = CString::new(iface).ok()?;
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        return None;
+    }
+    find_in(Path::new(LIBXDP_PIN_DIR), ifindex)
+}
+
+/// Ищет в каталоге `dir` диспетчер интерфейса с индексом `ifindex`.
+fn find_in(dir: &Path, ifindex: u32) -> Option<String> {
+    let prefix = format!("dispatch-{ifindex}-");
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .find(|name| name.starts_with(&prefix))
+}
+
+/// Предупреждает, что запрошенная цепочка с приоритетом `priority` недоступна.
+pub fn warn_exclusive(iface: &str, priority: u32) {
+    match find_dispatcher(iface) {
+        Some(dispatcher) => warn!(
+            "libxdp dispatcher {LIBXDP_PIN_DIR}/{dispatcher} owns {iface}; chaining at priority \
+             {priority} is not supported yet, exclusive attach will fail until it is unloaded \
+             (xdp-loader unload {iface} --all)"
+        ),
+        None => warn!(
+            "no XDP dispatcher on {iface}; chaining at priority {priority} is not supported yet, \
+             attaching exclusively"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dispatcher_is_found_by_ifindex() {
+        let dir = std::env::temp_dir().join(format!("firewall-xdp-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // Закреплённые libxdp диспетчеры с программами-заглушками на интерфейсах 3 и 31.
+        fs::write(dir.join("dispatch-31-70"), b"").unwrap();
+        fs::write(dir.join("dispatch-3-42"), b"").unwrap();
+        fs::write(dir.join("prog-3-42"), b"").unwrap();
+
+        assert_eq!(find_in(&dir, 3).as_deref(), Some("dispatch-3-42"));
+        assert_eq!(find_in(&dir, 31).as_deref(), Some("dispatch-31-70"));
+        assert_eq!(find_in(&dir, 4), None);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(find_in(&dir, 3), None);
+    }
+}
@@ -0,0 +1,59 @@
+//! Таблица соединений `--conntrack-timeout`: программа на отправке (TC egress).
+//!
+//! XDP видит только входящие пакеты, поэтому исходящие SYN записывает в `CONNTRACK`
+//! отдельная программа `tc_conntrack` на хуке egress тех же интерфейсов. Интерфейс, к
+//! которому её привязать не удалось, фильтруется как без таблицы соединений.
+
+use std::io;
+
+use aya::programs::{tc, tc::SchedClassifierLinkId, SchedClassifier, TcAttachType};
+use log::{info, warn};
+
+const PROGRAM: &str = "tc_conntrack";
+
+/// Загружает программу на отправке и привязывает её к `ifaces`; возвращает привязки,
+/// которые удалось создать.
+pub fn attach(
+    ebpf: &mut aya::Ebpf,
+    ifaces: &[&str],
+) -> anyhow::Result<Vec<(String, SchedClassifierLinkId)>> {
+    let program: &mut SchedClassifier = ebpf.program_mut(PROGRAM).unwrap().try_into()?;
+    program.load()?;
+    let mut links = Vec::new();
+    for &iface in ifaces {
+        // Очередь clsact может уже быть от другой программы TC, это не ошибка.
+        if let Err(e) = tc::qdisc_add_clsact(iface) {
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                warn!("no connection tracking on {iface}: failed to add the clsact qdisc: {e}");
+                continue;
+            }
+        }
+        match program.attach(iface, TcAttachType::Egress) {
+            Ok(link) => {
+                println!("Connection tracking attached to {iface} egress");
+                links.push((iface.to_string(), link));
+            }
+            Err(e) => warn!(
+                "no connection tracking on {iface}: {:#}",
+                anyhow::Error::from(e).context("failed to attach the TC egress program")
+            ),
+        }
+    }
+    Ok(links)
+}
+
+/// Отвязывает программу на отправке; исчезнувший интерфейс забрал привязку с собой.
+pub fn detach(ebpf: &mut aya::Ebpf, links: Vec<(String, SchedClassifierLinkId)>) {
+    let Some(program) = ebpf.program_mut(PROGRAM) else {
+        return;
+    };
+    let Ok(program): Result<&mut SchedClassifier, _> = program.try_into() else {
+        return;
+    };
+    for (iface, link) in links {
+        match program.detach(link) {
+            Ok(()) => info!("detached connection tracking from {iface}"),
+            Err(e) => warn!("failed to detach connection tracking from {iface}: {e}"),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    net::Ipv4Addr,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context as _;
+use aya::maps::{MapData, RingBuf};
+use firewall_common::{event_flags, DropEvent, DropReason, EVENTS_SOCKET};
+use log::{debug, info, warn};
+use tokio::{
+    io::{unix::AsyncFd, AsyncWriteExt as _},
+    net::{UnixListener, UnixStream},
+    sync::broadcast,
+};
+
+/// Сколько последних событий отдаётся клиенту сразу после подключения.
+const BACKLOG_LEN: usize = 100;
+
+/// Очередь событий для подписчиков; медленный клиент теряет старые, а не тормозит чтение.
+const CHANNEL_LEN: usize = 1024;
+
+/// Раздаёт события из кольцевого буфера клиентам `EVENTS_SOCKET`.
+#[derive(Clone)]
+pub struct Hub {
+    sender: broadcast::Sender<DropEvent>,
+    backlog: Arc<Mutex<VecDeque<DropEvent>>>,
+}
+
+impl Hub {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_LEN);
+        Self {
+            sender,
+            backlog: Arc::new(Mutex::new(VecDeque::with_capacity(BACKLOG_LEN))),
+        }
+    }
+
+    pub fn publish(&self, event: DropEvent) {
+        let mut backlog = self.backlog.lock().unwrap_or_else(|e| e.into_inner());
+        if backlog.len() == BACKLOG_LEN {
+            backlog.pop_front();
+        }
+        backlog.push_back(event);
+        // Ошибка означает лишь, что сейчас никто не подписан.
+        let _ = self.sender.send(event);
+    }
+
+    /// Подписка и снимок недавних событий, взятые атомарно, чтобы не потерять и не задвоить.
+    fn subscribe(&self) -> (Vec<DropEvent>, broadcast::Receiver<DropEvent>) {
+        let backlog = self.backlog.lock().unwrap_or_else(|e| e.into_inner());
+        (backlog.iter().copied().collect(), self.sender.subscribe())
+    }
+}
+
+/// Читает события об отброшенных пакетах из кольцевого буфера, пока он доступен.
+pub async fn read_events(events: RingBuf<MapData>, hub: Hub) -> anyhow::Result<()> {
+    let mut events = AsyncFd::new(events)?;
+    loop {
+        let mut guard = events.readable_mut().await?;
+        let ring = guard.get_inner_mut();
+        while let Some(item) = ring.next() {
+            let Some(event) = DropEvent::from_bytes(&item) else {
+                continue;
+            };
+            let reason = DropReason::from_u8(event.reason);
+            let verb = if reason == Some(DropReason::Allowed) {
+                "passed"
+            } else if event.flags & event_flags::DRY_RUN != 0 {
+                "would drop"
+            } else {
+                "dropped"
+            };
+            info!(
+                "{verb} {}:{} -> {}:{} proto {} ({})",
+                Ipv4Addr::from(event.src_addr),
+                event.src_port,
+                Ipv4Addr::from(event.dst_addr),
+                event.dst_port,
+                event.proto,
+                reason.map_or("unknown", DropReason::as_str),
+            );
+            hub.publish(event);
+        }
+        guard.clear_ready();
+    }
+}
+
+/// Принимает клиентов на `EVENTS_SOCKET` и отправляет им события.
+pub async fn serve(hub: Hub) -> anyhow::Result<()> {
+    let path = Path::new(EVENTS_SOCKET);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    if path.exists() {
+        fs::remove_file(path).with_context(|| format!("failed to remove stale {EVENTS_SOCKET}"))?;
+    }
+    let listener =
+        UnixListener::bind(path).with_context(|| format!("failed to bind {EVENTS_SOCKET}"))?;
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (backlog, receiver) = hub.subscribe();
+        tokio::spawn(async move {
+            if let Err(e) = stream_to(stream, backlog, receiver).await {
+                debug!("event client disconnected: {e}");
+            }
+        });
+    }
+}
+
+async fn stream_to(
+    mut stream: UnixStream,
+    backlog: Vec<DropEvent>,
+    mut receiver: broadcast::Receiver<DropEvent>,
+) -> anyhow::Result<()> {
+    stream.write_all(&(backlog.len() as u32).to_le_bytes()).await?;
+    for event in &backlog {
+        stream.write_all(event.as_bytes()).await?;
+    }
+    loop {
+        match receiver.recv().await {
+            Ok(event) => stream.write_all(event.as_bytes()).await?,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("event client is too slow, skipped {skipped} events");
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+/// Удаляет сокет событий при выходе.
+pub fn remove_socket() {
+    if let Err(e) = fs::remove_file(EVENTS_SOCKET) {
+        warn!("failed to remove {EVENTS_SOCKET}: {e}");
+    }
+}
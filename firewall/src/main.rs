@@ -1,21 +1,555 @@
+mod batch;
+mod btf;
+mod chain;
+mod conntrack;
+mod events;
+mod netflow;
+mod ratelimit;
+mod regions;
+mod safeguard;
+mod sweep;
+mod userspace;
+mod xdp_attach;
+
+use std::{
+    collections::HashSet,
+    fs,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::RangeInclusive,
+    os::fd::AsFd as _,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
 use anyhow::Context as _;
-use aya::programs::{Xdp, XdpFlags};
-use clap::Parser;
+use aya::{
+    maps::{
+        lpm_trie::{Key, LpmTrie},
+        Array, HashMap, Map, MapData, RingBuf,
+    },
+    programs::{xdp::XdpLinkId, Xdp, XdpFlags},
+};
+use clap::{Parser, ValueEnum};
+use firewall_common::{
+    block_action, country_key, direction, endpoint_key, event_fields, geoip, log_level, mode,
+    port_protos, protocol_rule, settings, time_window, ConnKey, KnockProgress, MaskedAddr,
+    RateState, ACCEPTED_CONNS_MAP, ALLOWED_IPS_MAP, ALLOWED_PORTS_MAP, BLOCKED_COUNTRIES_MAP,
+    BLOCKED_ENDPOINTS_MAP, BLOCKED_IPS6_MAP, BLOCKED_IPS_MAP, BLOCKED_MACS_MAP, BLOCKED_MASKS_MAP,
+    BLOCKED_NETS_MAP, BLOCKED_SRC_PORTS_MAP, CONNTRACK_MAP, COUNTRIES_MAP, COUNTRY_DROPS_MAP,
+    COUNTRY_STATS_MAP, DROP_REASONS_MAP, EVENTS_MAP, FAST_ACCEPT_MAP, FLOWS_MAP, KNOCK_PROGRESS_MAP,
+    KNOCK_SEQUENCE_MAP, KNOCK_STEP_SECS, KNOCK_UNLOCKED_MAP, MAX_BLOCKED_MASKS, MAX_KNOCK_PORTS,
+    PIN_PATH, PORT_STATS_MAP, PROTECTED_IPS_MAP, PROTOCOLS_MAP, RATE_BUCKETS_MAP, REGIONS_MAP,
+    RULE_COSTS_MAP, SETTINGS_MAP, SOURCE_STATS_MAP, STAGED_IPS_MAP, STATS_MAP, SYN_BUCKETS_MAP,
+    TCP_WINDOWS_MAP, VERDICT_OVERRIDES_MAP, XSKS_MAP, XSK_PORTS_MAP,
+};
 #[rustfmt::skip]
-use log::{debug, warn};
+use log::{debug, info, warn};
 use tokio::signal;
 
+/// Где применяются правила.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Mode {
+    /// Программа XDP отбрасывает пакеты в драйвере.
+    Xdp,
+    /// Наблюдение через AF_PACKET без отбрасывания, если XDP недоступен.
+    Userspace,
+}
+
+/// Как привязывать программу XDP к интерфейсу.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum AttachMode {
+    /// Сначала в драйвере, при неудаче — generic.
+    Auto,
+    /// Generic (SKB): с любым интерфейсом, но медленнее.
+    #[value(alias = "generic")]
+    Skb,
+    /// В драйвере сетевой карты.
+    #[value(alias = "native")]
+    Driver,
+    /// Выгрузка в сетевую карту.
+    #[value(alias = "offload")]
+    Hw,
+}
+
+impl AttachMode {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Skb => "generic (SKB)",
+            Self::Driver => "driver",
+            Self::Hw => "hardware offload",
+        }
+    }
+}
+
+/// К каким пакетам применяются правила.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Direction {
+    /// Входящие: программа XDP.
+    Ingress,
+    /// Исходящие. Программа TC на egress (`tc_conntrack`) только записывает соединения для
+    /// `--conntrack-timeout` и правила к пакетам не применяет.
+    Egress,
+    /// Входящие и исходящие.
+    Both,
+}
+
+impl Direction {
+    /// Значение настройки `settings::DIRECTIONS`.
+    fn setting(self) -> u32 {
+        match self {
+            Self::Ingress => direction::INGRESS,
+            Self::Egress => direction::EGRESS,
+            Self::Both => direction::BOTH,
+        }
+    }
+}
+
+/// С каким портом пакета сравниваются `--ports`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum PortMatch {
+    /// Порт источника: ответы серверов, к которым подключается сам хост.
+    Src,
+    /// Порт назначения: входящие подключения к службам хоста.
+    Dst,
+}
+
+/// Что делать с пакетом, который не разрешило ни одно правило.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Policy {
+    /// Отбросить: проходит только разрешённое `--ports` и ICMP.
+    Deny,
+    /// Пропустить: отбрасываются только пакеты, попавшие под блокировки.
+    Allow,
+}
+
+/// С каким адресом сравниваются правила `--blocked-endpoints`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum EndpointMatch {
+    /// Адрес источника и порт назначения.
+    Src,
+    /// Адрес и порт назначения.
+    Dst,
+}
+
+impl EndpointMatch {
+    /// Значение настройки `settings::ENDPOINT_MATCH`.
+    fn setting(self) -> u32 {
+        match self {
+            Self::Src => 1,
+            Self::Dst => 2,
+        }
+    }
+}
+
+/// Что программа XDP пишет в журнал через `info!`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogLevel {
+    /// Ничего.
+    None,
+    /// Только отброшенные пакеты.
+    Drops,
+    /// Ещё разбор каждого пакета и пропущенные пакеты; на полной нагрузке замедляет фильтрацию.
+    Verbose,
+}
+
+impl LogLevel {
+    /// Значение настройки `settings::LOG_LEVEL`.
+    fn setting(self) -> u32 {
+        match self {
+            Self::None => log_level::NONE,
+            Self::Drops => log_level::DROPS,
+            Self::Verbose => log_level::VERBOSE,
+        }
+    }
+}
+
+/// Как программа XDP сообщает о пакетах.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogBackend {
+    /// Только события в кольцевом буфере `EVENTS`; `info!` не вызывается.
+    Events,
+    /// Ещё текстовые сообщения `info!` через aya-log по `--log-level`.
+    AyaLog,
+}
+
+/// Что программа XDP делает с пакетом, который решено отбросить.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BlockAction {
+    /// XDP_DROP: молча отбросить.
+    Drop,
+    /// XDP_ABORTED: отбросить с событием трассировки `xdp:xdp_exception`.
+    Abort,
+    /// XDP_TX: поменять местами MAC-адреса и вернуть кадр в тот же интерфейс.
+    Tx,
+}
+
+impl BlockAction {
+    /// Значение настройки `settings::BLOCK_ACTION`.
+    fn setting(self) -> u32 {
+        match self {
+            Self::Drop => block_action::DROP,
+            Self::Abort => block_action::ABORT,
+            Self::Tx => block_action::TX,
+        }
+    }
+}
+
+/// Составное правило `адрес[/длина]:порт`.
+#[derive(Debug, Clone, Copy)]
+struct Endpoint {
+    addr: Ipv4Addr,
+    prefix: u8,
+    port: u16,
+}
+
 #[derive(Debug, Parser)]
 struct Opt {
-    #[clap(short, long, default_value = "enp0s5")]
-    iface: String,
+    /// Interfaces to attach to, separated by spaces or commas; the same rules apply to all.
+    #[clap(short, long, num_args = 1.., value_delimiter = ',', default_value = "enp0s5")]
+    iface: Vec<String>,
+    /// Enforce rules with XDP, or only observe matches from userspace where XDP is unavailable.
+    #[clap(long, value_enum, default_value_t = Mode::Xdp)]
+    mode: Mode,
+    /// How to attach the XDP program: auto tries driver mode and falls back to generic (skb).
+    #[clap(long, value_enum, default_value_t = AttachMode::Auto)]
+    attach_mode: AttachMode,
+    /// Which traffic the rules apply to; the programs skip the rules for other directions.
+    /// Only ingress can be filtered: XDP sees incoming packets, and the TC egress program
+    /// only records connections for --conntrack-timeout.
+    #[clap(long, value_enum, default_value_t = Direction::Ingress)]
+    direction: Direction,
+    /// Send every Nth drop event to the ring buffer (0 disables events).
+    #[clap(long, default_value_t = 1)]
+    event_sample_rate: u32,
+    /// Emit eBPF `info!` logs for every Nth packet (0 disables logs).
+    #[clap(long, default_value_t = 1)]
+    log_sample_rate: u32,
+    /// Which eBPF `info!` logs to emit: none, drops only, or every parsed and passed packet.
+    #[clap(long, value_enum, default_value_t = LogLevel::Drops)]
+    log_level: LogLevel,
+    /// How the eBPF program reports packets. `events` sends only structured drop events
+    /// through the EVENTS ring buffer, the cheapest option with nothing else to read.
+    /// `aya-log` also formats the `info!` messages chosen by --log-level, which costs CPU per
+    /// packet and sets up the aya-log reader; use it to see per-packet parsing details.
+    #[clap(long, value_enum, default_value_t = LogBackend::Events)]
+    log_backend: LogBackend,
+    /// Print at most this many log lines per second, summarising the rest (0 disables the limit).
+    #[clap(long, default_value_t = 100)]
+    log_rate_limit: u32,
+    /// Also send every Nth passed packet to the ring buffer as an "allowed" event (N >= 1).
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    log_allows: Option<u32>,
+    /// Optional fields to fill in drop events: ttl, tcp-flags, length, vlan.
+    #[clap(long, num_args = 1.., value_parser = parse_event_field)]
+    event_fields: Vec<u8>,
+    /// Pass packets to these ports (or from them, see --port-match), as PORT or FIRST-LAST
+    /// (TCP and UDP), optionally with /tcp or /udp; all others are dropped. HTTPS and QUIC
+    /// (HTTP/3) are separate rules: 443/tcp and 443/udp.
+    #[clap(
+        long,
+        num_args = 1..,
+        value_parser = parse_port_spec,
+        default_values = ["80", "443/tcp", "443/udp", "53"]
+    )]
+    ports: Vec<(RangeInclusive<u16>, u8)>,
+    /// Drop TCP and UDP packets from these source ports, as PORT or FIRST-LAST optionally with
+    /// /tcp or /udp, whatever --ports allows: e.g. 123/udp, 1900/udp and 11211/udp against
+    /// NTP, SSDP and memcached reflection.
+    #[clap(long, num_args = 1.., value_parser = parse_port_spec)]
+    blocked_src_ports: Vec<(RangeInclusive<u16>, u8)>,
+    /// Compare --ports with the destination port of a packet or with its source port.
+    #[clap(long, value_enum, default_value_t = PortMatch::Dst)]
+    port_match: PortMatch,
+    /// What happens to a packet that no rule allowed: deny drops it (other ports, other ICMP
+    /// types, other protocols with --strict-protocols), allow passes it. Block rules apply
+    /// either way.
+    #[clap(long, value_enum, default_value_t = Policy::Deny)]
+    policy: Policy,
+    /// Apply --policy to protocols the rules do not parse (anything but TCP, UDP and ICMP:
+    /// ESP, GRE, SCTP, ...). Without it such packets pass, so VPNs and tunnels keep working.
+    #[clap(long)]
+    strict_protocols: bool,
+    /// Per-protocol rules as NAME=allow or NAME=deny, NAME being tcp, udp, icmp, gre, esp,
+    /// sctp, ... or an IP protocol number: deny drops every packet of the protocol, allow
+    /// passes protocols the rules do not parse (TCP, UDP and ICMP still go through their own
+    /// rules). Any entry implies --strict-protocols, so unlisted protocols follow --policy.
+    #[clap(long, num_args = 1.., value_parser = parse_protocol_rule)]
+    protocols: Vec<(u8, u8)>,
+    /// Keep this TCP/UDP destination port closed until an IPv4 source sends packets to the
+    /// --knock-sequence ports in order; the port then opens for that source, whatever --ports
+    /// says, and closes --knock-timeout seconds after its last packet to the port.
+    #[clap(long, value_name = "PORT", requires = "knock_sequence")]
+    knock_port: Option<u16>,
+    /// Destination ports to knock on, in order, each within 10 seconds of the previous one.
+    #[clap(long, value_name = "PORT", num_args = 1.., requires = "knock_port")]
+    knock_sequence: Vec<u16>,
+    /// Seconds the --knock-port stays open to a source after its last packet to the port.
+    #[clap(
+        long,
+        value_name = "SECS",
+        default_value_t = 30,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    knock_timeout: u32,
+    /// Check broadcast and multicast frames against the rules like any other. Without it they
+    /// pass before any IP rule, so DHCP, mDNS and other link-local services keep working.
+    #[clap(long)]
+    filter_multicast: bool,
+    /// Drop every IPv4 fragment, first or not. Without it later fragments, which carry no
+    /// ports, are checked against the address rules only.
+    #[clap(long)]
+    drop_fragments: bool,
+    /// Check DNS (TCP and UDP port 53, as source or destination) against --ports like any
+    /// other port. Without it DNS passes once the block rules are checked.
+    #[clap(long)]
+    strict_dns: bool,
+    /// Pass TCP packets of connections this host opened, whatever --ports says: a TC egress
+    /// program records outgoing SYNs, and an entry expires after SECS seconds without packets
+    /// (300 is a reasonable start). IPv4 only, not in --mode userspace.
+    #[clap(long, value_name = "SECS", value_parser = clap::value_parser!(u32).range(1..))]
+    conntrack_timeout: Option<u32>,
+    /// Pass ARP only from senders inside this network (ADDR/LEN), e.g. the local subnet.
+    /// Without it every ARP packet passes, whatever --policy says.
+    #[clap(long, value_name = "PREFIX", value_parser = parse_prefix)]
+    arp_subnet: Option<(Ipv4Addr, u8)>,
+    /// Pass ICMP and ICMPv6 echo requests (ping). Echo replies, destination-unreachable and
+    /// time-exceeded messages always pass, as does IPv6 neighbor discovery.
+    #[clap(long)]
+    allow_icmp_echo: bool,
+    /// Pass TCP packets with flag combinations used by stealth scans: no flags (NULL), FIN
+    /// without ACK, or FIN+PSH+URG (XMAS). They are dropped by default.
+    #[clap(long)]
+    allow_invalid_tcp_flags: bool,
+    /// Drop packets that the rules would pass once a source address sends more than PPS
+    /// packets per second (IPv4, token bucket per source).
+    #[clap(long, value_name = "PPS", value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit: Option<u32>,
+    /// Packets a source may send in a burst above --rate-limit (default: one second's worth).
+    #[clap(
+        long,
+        value_name = "N",
+        requires = "rate_limit",
+        value_parser = clap::value_parser!(u32).range(1..=i64::from(RateState::MAX_BURST))
+    )]
+    rate_burst: Option<u32>,
+    /// Drop TCP SYN packets without ACK once a source opens more than PPS connections per
+    /// second; checked before the other rules.
+    #[clap(long, value_name = "PPS", value_parser = clap::value_parser!(u32).range(1..))]
+    syn_rate_limit: Option<u32>,
+    /// Connection attempts a source may burst above --syn-rate-limit (default: one second's
+    /// worth).
+    #[clap(
+        long,
+        value_name = "N",
+        requires = "syn_rate_limit",
+        value_parser = clap::value_parser!(u32).range(1..=i64::from(RateState::MAX_BURST))
+    )]
+    syn_rate_burst: Option<u32>,
+    /// Every SECS seconds delete the entries of the connection, knock and rate-limit tables
+    /// whose source has sent nothing for --sweep-ttl seconds, so that idle sources do not
+    /// push live ones out of the full tables (0 disables). XDP mode only.
+    #[clap(long, value_name = "SECS", default_value_t = 60)]
+    sweep_interval: u32,
+    /// Seconds without packets after which --sweep-interval deletes an entry. A table's own
+    /// timeout (--conntrack-timeout, --knock-timeout, the time to refill a rate bucket) is
+    /// used instead when it is longer, so sweeping never changes a verdict.
+    #[clap(
+        long,
+        value_name = "SECS",
+        default_value_t = 300,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    sweep_ttl: u32,
+    /// Passive monitoring: pass every packet without evaluating rules, only update counters.
+    #[clap(long)]
+    count_only: bool,
+    /// Evaluate the rules and report every packet they would drop as an event tagged
+    /// dry_run, but pass all traffic.
+    #[clap(long, conflicts_with = "count_only")]
+    dry_run: bool,
+    /// Benchmark mode: the program only parses packets and decides pass or drop. Logging,
+    /// events and every counter are skipped, so stats, metrics and events stay empty. Use it
+    /// to measure the ceiling throughput, not in production.
+    #[clap(long, conflicts_with = "count_only")]
+    fast_path: bool,
+    /// What to do with a packet the rules drop: drop it, abort (drop and fire the
+    /// xdp:xdp_exception tracepoint), or tx (swap the MAC addresses and send the frame back
+    /// out of the same interface, to confuse scanners). The packet is counted as dropped
+    /// either way.
+    #[clap(long, value_enum, default_value_t = BlockAction::Drop)]
+    block_action: BlockAction,
+    /// Drop TCP packets whose window size equals one of these values (scanner fingerprints).
+    #[clap(long, num_args = 1..)]
+    block_tcp_window: Vec<u16>,
+    /// Drop all packets from these source addresses or networks (ADDR or ADDR/LEN).
+    #[clap(long, num_args = 1.., value_parser = parse_prefix)]
+    blocked_ips: Vec<(Ipv4Addr, u8)>,
+    /// Drop all IPv6 packets from these source addresses or networks (ADDR or ADDR/LEN).
+    #[clap(long = "blocked-ips6", num_args = 1.., value_parser = parse_prefix6)]
+    blocked_ips6: Vec<(Ipv6Addr, u8)>,
+    /// Drop sources matching ADDR/MASK with an arbitrary dotted-quad mask (e.g.
+    /// 0.0.0.100/0.0.0.255 for every address ending in .100); at most 32 rules.
+    #[clap(long, num_args = 1.., value_parser = parse_masked)]
+    blocked_masks: Vec<MaskedAddr>,
+    /// Drop frames from these source MAC addresses (xx:xx:xx:xx:xx:xx), checked before the
+    /// IP header; useful on LAN segments where source addresses are easy to spoof.
+    #[clap(long, num_args = 1.., value_parser = parse_mac)]
+    blocked_macs: Vec<[u8; 6]>,
+    /// Drop packets from these source countries (ISO 3166-1 alpha-2 codes).
+    #[clap(long, num_args = 1.., value_parser = parse_country_code)]
+    blocked_countries: Vec<u16>,
+    /// Block --blocked-countries only between these local times, as HH:MM-HH:MM; a window
+    /// whose end is earlier than its start wraps past midnight (22:00-06:00).
+    #[clap(long, value_name = "WINDOW", value_parser = parse_window)]
+    blocked_countries_window: Option<u32>,
+    /// IP-to-country database in CSV form, rows NETWORK,CC or FIRST,LAST,CC; addresses it
+    /// does not cover fall back to a placeholder guess from the first octet.
+    #[clap(long)]
+    country_db: Option<PathBuf>,
+    /// MaxMind City database in CSV form (GeoLite2-City-Blocks-IPv4.csv) for region rules.
+    #[clap(long, requires = "blocked_regions")]
+    region_db: Option<PathBuf>,
+    /// Drop packets from these regions, given as geoname_id values from --region-db.
+    #[clap(long, num_args = 1.., requires = "region_db")]
+    blocked_regions: Vec<u32>,
+    /// Drop traffic matching ADDR[/LEN]:PORT rules, where PORT is the destination port.
+    #[clap(long, num_args = 1.., value_parser = parse_endpoint)]
+    blocked_endpoints: Vec<Endpoint>,
+    /// Compare endpoint rules with the source or the destination address.
+    #[clap(long, value_enum, default_value_t = EndpointMatch::Src)]
+    endpoint_match: EndpointMatch,
+    /// Let these source addresses through even if their country is blocked.
+    #[clap(long, num_args = 1..)]
+    allowed_ips: Vec<Ipv4Addr>,
+    /// Pass traffic from these trusted prefixes (e.g. 10.0.0.0/8) before evaluating any rule.
+    #[clap(long, num_args = 1.., value_parser = parse_prefix)]
+    fast_accept_prefixes: Vec<(Ipv4Addr, u8)>,
+    /// Apply the rules only to packets sent to these addresses or prefixes (the services to
+    /// protect on a shared host); all other traffic passes unchecked, IPv6 included since the
+    /// list is IPv4. Without it the rules apply to every packet.
+    #[clap(long, num_args = 1.., value_parser = parse_prefix)]
+    protected_ips: Vec<(Ipv4Addr, u8)>,
+    /// Apply rules to the inner header of IP-in-IP (protocol 4) packets instead of dropping them.
+    #[clap(long)]
+    unwrap_ipip: bool,
+    /// Redirect passed traffic to these destination ports into AF_XDP sockets registered by
+    /// RX queue in the pinned XSKS map; queues without a socket pass to the stack as usual.
+    #[clap(long, num_args = 1..)]
+    xsk_redirect_ports: Vec<u16>,
+    /// Entries per BPF_MAP_UPDATE_BATCH call when loading lists (0 or 1 inserts one by one).
+    #[clap(long, default_value_t = 1024)]
+    map_batch_size: usize,
+    /// Export per-flow statistics as NetFlow v5 to this UDP collector, e.g. 10.0.0.5:2055.
+    #[clap(long)]
+    netflow_collector: Option<SocketAddr>,
+    /// Export a flow after this many seconds without packets.
+    #[clap(long, default_value_t = 15)]
+    flow_idle_timeout: u64,
+    /// Export a long-lived flow after this many seconds even if it is still active.
+    #[clap(long, default_value_t = 60)]
+    flow_active_timeout: u64,
+    /// Run in a chain with other XDP programs at this priority (lower runs first).
+    #[clap(long)]
+    chain_priority: Option<u32>,
+    /// Do not ask for confirmation when attaching to the interface of the current SSH session.
+    #[clap(long)]
+    yes: bool,
+    /// Replace an XDP program that is already attached to the interface instead of failing.
+    #[clap(long)]
+    force: bool,
+    /// Every SECS seconds check that the firewall is still attached to each interface and
+    /// attach it again if another program or `ip link set xdp off` removed it.
+    #[clap(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    watchdog: Option<u64>,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let opt = Opt::parse();
 
-    env_logger::init();
+    if opt.log_rate_limit == 0 {
+        env_logger::init();
+    } else {
+        let inner = env_logger::Builder::from_default_env().build();
+        let logger = ratelimit::RateLimitedLogger::new(inner, opt.log_rate_limit).install()?;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ratelimit::SUMMARY_INTERVAL);
+            loop {
+                interval.tick().await;
+                logger.flush_summary();
+            }
+        });
+    }
+
+    let region_networks = match &opt.region_db {
+        Some(path) => {
+            let blocked: HashSet<u32> = opt.blocked_regions.iter().copied().collect();
+            let networks = regions::load(path, &blocked)
+                .with_context(|| format!("failed to read region database {}", path.display()))?;
+            println!("Loaded {} networks of {} blocked regions", networks.len(), blocked.len());
+            networks
+        }
+        None => Vec::new(),
+    };
+
+    let country_networks = match &opt.country_db {
+        Some(path) => {
+            let networks = geoip::load(path)
+                .with_context(|| format!("failed to read country database {}", path.display()))?;
+            println!("Loaded {} country networks", networks.len());
+            networks
+        }
+        None => {
+            if !opt.blocked_countries.is_empty() {
+                warn!(
+                    "--blocked-countries without --country-db: countries are guessed from the \
+                     first octet of the address and are mostly wrong"
+                );
+            }
+            Vec::new()
+        }
+    };
+
+    if opt.blocked_masks.len() > MAX_BLOCKED_MASKS as usize {
+        anyhow::bail!(
+            "{} masked rules do not fit: at most {MAX_BLOCKED_MASKS} are checked per packet",
+            opt.blocked_masks.len()
+        );
+    }
+
+    if let Some(port) = opt.knock_port {
+        check_knock(port, &opt.knock_sequence)?;
+    }
+
+    if opt.direction != Direction::Ingress {
+        anyhow::bail!(
+            "--direction {}: outgoing packets are not filtered, the TC egress program only \
+             records connections for --conntrack-timeout; use --direction ingress",
+            opt.direction.to_possible_value().map_or_else(String::new, |v| v.get_name().into())
+        );
+    }
+
+    if opt.mode == Mode::Userspace {
+        if let Some(rate) = opt.log_allows {
+            warn_log_allows(rate);
+        }
+        if !opt.xsk_redirect_ports.is_empty() {
+            warn!("--xsk-redirect-ports needs XDP and is ignored in userspace mode");
+        }
+        if opt.fast_path {
+            warn!("--fast-path tunes the XDP program and is ignored in userspace mode");
+        }
+        if opt.block_action != BlockAction::Drop {
+            warn!("--block-action needs XDP and is ignored in userspace mode");
+        }
+        if let [first, rest @ ..] = opt.iface.as_slice() {
+            if !rest.is_empty() {
+                warn!("userspace mode watches one interface, only {first} is observed");
+            }
+        }
+        return userspace::run(userspace_options(opt, &region_networks, &country_networks)).await;
+    }
 
     // Bump the memlock rlimit. This is needed for older kernels that don't use the
     // new memcg based accounting, see https://lwn.net/Articles/837122/
@@ -32,24 +566,1018 @@ async fn main() -> anyhow::Result<()> {
     // runtime. This approach is recommended for most real-world use cases. If you would
     // like to specify the eBPF program at runtime rather than at compile-time, you can
     // reach for `Bpf::load_file` instead.
-    let mut ebpf = aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
-        env!("OUT_DIR"),
-        "/firewall"
-    )))?;
-    if let Err(e) = aya_log::EbpfLogger::init(&mut ebpf) {
-        // This can happen if you remove all log statements from your eBPF program.
-        warn!("failed to initialize eBPF logger: {}", e);
-    }
-    let Opt { iface } = opt;
+    let (plan, kernel_btf) = btf::detect();
+    let mut ebpf = aya::EbpfLoader::new()
+        .btf(kernel_btf.as_ref())
+        .load(aya::include_bytes_aligned!(concat!(
+            env!("OUT_DIR"),
+            "/firewall"
+        )))
+        .context(btf::load_error_hint(plan))
+        .map_err(xdp_attach::explain)?;
+    // С `events` сообщения `info!` выключены, и читать их некому.
+    if plan.enable_logger && opt.log_backend == LogBackend::AyaLog {
+        if let Err(e) = aya_log::EbpfLogger::init(&mut ebpf) {
+            // This can happen if you remove all log statements from your eBPF program.
+            warn!("failed to initialize eBPF logger: {}", e);
+        }
+    }
+    let Opt {
+        iface,
+        mode: _,
+        attach_mode,
+        direction,
+        event_sample_rate,
+        log_sample_rate,
+        log_level,
+        log_backend,
+        log_rate_limit: _,
+        log_allows,
+        event_fields: requested_fields,
+        ports,
+        blocked_src_ports,
+        port_match,
+        policy,
+        strict_protocols,
+        protocols,
+        knock_port,
+        knock_sequence,
+        knock_timeout,
+        filter_multicast,
+        drop_fragments,
+        strict_dns,
+        conntrack_timeout,
+        arp_subnet,
+        allow_icmp_echo,
+        allow_invalid_tcp_flags,
+        rate_limit,
+        rate_burst,
+        syn_rate_limit,
+        syn_rate_burst,
+        sweep_interval,
+        sweep_ttl,
+        count_only,
+        dry_run,
+        fast_path,
+        block_action,
+        block_tcp_window,
+        blocked_ips,
+        blocked_ips6,
+        blocked_masks,
+        blocked_macs,
+        blocked_countries,
+        blocked_countries_window,
+        country_db: _,
+        region_db: _,
+        blocked_regions: _,
+        blocked_endpoints,
+        endpoint_match,
+        allowed_ips,
+        fast_accept_prefixes,
+        protected_ips,
+        unwrap_ipip,
+        xsk_redirect_ports,
+        map_batch_size,
+        netflow_collector,
+        flow_idle_timeout,
+        flow_active_timeout,
+        chain_priority,
+        yes,
+        force,
+        watchdog,
+    } = opt;
+
+    // В режимах только подсчёта и пробном пакеты не отбрасываются, заблокировать себя нельзя.
+    let mut ifaces = Vec::new();
+    for name in iface {
+        if !yes && !count_only && !dry_run && !safeguard::confirm_attach(&name)? {
+            warn!("not attaching to {name}: confirmation declined");
+            continue;
+        }
+        ifaces.push(name);
+    }
+    if ifaces.is_empty() {
+        anyhow::bail!("no interface to attach to: confirmation declined for all of them");
+    }
+
+    if log_backend == LogBackend::Events && log_level == LogLevel::Verbose {
+        warn!("--log-level verbose has no effect with --log-backend events, use aya-log");
+    }
+    let logger = plan.enable_logger && log_backend == LogBackend::AyaLog;
+    let mut values = vec![
+        (settings::EVENT_SAMPLE_RATE, event_sample_rate),
+        // Без aya-log логи некому читать, программа не тратит на них время.
+        (settings::LOG_SAMPLE_RATE, if logger { log_sample_rate } else { 0 }),
+        (settings::LOG_LEVEL, log_level.setting()),
+        (settings::DIRECTIONS, direction.setting()),
+    ];
+    if let Some(rate) = log_allows {
+        warn_log_allows(rate);
+        values.push((settings::ALLOW_SAMPLE_RATE, rate));
+    }
+    let fields = requested_fields.iter().fold(0, |mask, bit| mask | bit);
+    if fields != 0 {
+        values.push((settings::EVENT_FIELDS, u32::from(fields)));
+    }
+    if count_only {
+        values.push((settings::MODE, mode::COUNT_ONLY));
+        println!("Count-only mode: rules are not evaluated, all traffic is passed and counted");
+    }
+    if dry_run {
+        values.push((settings::MODE, mode::DRY_RUN));
+        println!(
+            "DRY RUN: rules are evaluated but nothing is dropped; packets they would drop are \
+             reported as events tagged dry_run"
+        );
+    }
+
+    if fast_path {
+        values.push((settings::FAST_PATH, 1));
+        println!(
+            "FAST PATH: logging, events and statistics are off, only pass/drop decisions are \
+             made"
+        );
+    }
+
+    if block_action != BlockAction::Drop {
+        values.push((settings::BLOCK_ACTION, block_action.setting()));
+    }
+
+    if port_match == PortMatch::Src {
+        values.push((settings::PORT_MATCH, 1));
+    }
+    if policy == Policy::Allow {
+        values.push((settings::DEFAULT_POLICY, 1));
+    }
+    if strict_protocols || !protocols.is_empty() {
+        values.push((settings::STRICT_PROTOCOLS, 1));
+    }
+    if !protocols.is_empty() {
+        let map = ebpf.map_mut(PROTOCOLS_MAP).context("map PROTOCOLS not found")?;
+        let mut rules: Array<_, u8> = Array::try_from(map)?;
+        for &(proto, rule) in &protocols {
+            rules.set(u32::from(proto), rule, 0)?;
+        }
+        println!("Protocol rules: {} entries, other protocols follow --policy", protocols.len());
+    }
+    if let Some(port) = knock_port {
+        let map = ebpf.map_mut(KNOCK_SEQUENCE_MAP).context("map KNOCK_SEQUENCE not found")?;
+        let mut sequence: Array<_, u16> = Array::try_from(map)?;
+        for (step, &knock) in knock_sequence.iter().enumerate() {
+            sequence.set(step as u32, knock, 0)?;
+        }
+        values.extend([
+            (settings::KNOCK_PORT, u32::from(port)),
+            (settings::KNOCK_LEN, knock_sequence.len() as u32),
+            (settings::KNOCK_TIMEOUT, knock_timeout),
+        ]);
+        println!("Port {port} opens after knocking on {knock_sequence:?}");
+    }
+    if filter_multicast {
+        values.push((settings::FILTER_MULTICAST, 1));
+    }
+    if drop_fragments {
+        values.push((settings::DROP_FRAGMENTS, 1));
+    }
+    if !strict_dns {
+        values.push((settings::ALLOW_DNS, 1));
+    }
+    if let Some(timeout) = conntrack_timeout {
+        values.push((settings::CONNTRACK_TIMEOUT, timeout));
+    }
+    if let Some((addr, len)) = arp_subnet {
+        values.push((settings::ARP_SUBNET, u32::from(addr)));
+        values.push((settings::ARP_PREFIX, u32::from(len)));
+    }
+    if allow_icmp_echo {
+        values.push((settings::ICMP_ECHO, 1));
+    }
+    if !allow_invalid_tcp_flags {
+        values.push((settings::TCP_FLAG_FILTER, 1));
+    }
+    if let Some(rate) = rate_limit {
+        values.push((settings::RATE_LIMIT, rate));
+        values.push((settings::RATE_BURST, burst_size(rate, rate_burst)));
+    }
+    if let Some(rate) = syn_rate_limit {
+        values.push((settings::SYN_RATE_LIMIT, rate));
+        values.push((settings::SYN_RATE_BURST, burst_size(rate, syn_rate_burst)));
+    }
+    let ports = merge_ports(&ports);
+    let mut allowed_ports: HashMap<_, u16, u8> =
+        HashMap::try_from(list_map(&mut ebpf, ALLOWED_PORTS_MAP, ports.len(), "ports")?)?;
+    for (port, protos) in &ports {
+        allowed_ports.insert(port, protos, 0)?;
+    }
+    if !blocked_src_ports.is_empty() {
+        let ports = merge_ports(&blocked_src_ports);
+        let map = list_map(&mut ebpf, BLOCKED_SRC_PORTS_MAP, ports.len(), "source ports")?;
+        let mut blocked: HashMap<_, u16, u8> = HashMap::try_from(map)?;
+        for (port, protos) in &ports {
+            blocked.insert(port, protos, 0)?;
+        }
+        println!("Blocking {} source ports", ports.len());
+    }
+
+    if !block_tcp_window.is_empty() {
+        let map = list_map(&mut ebpf, TCP_WINDOWS_MAP, block_tcp_window.len(), "TCP windows")?;
+        let mut windows: HashMap<_, u16, u8> = HashMap::try_from(map)?;
+        for window in &block_tcp_window {
+            windows.insert(window, 1, 0)?;
+        }
+        values.push((settings::TCP_WINDOW_FILTER, 1));
+    }
+
+    // Отдельные адреса — в хеш-карту, сети — в LPM-карту: в хеше поиск дешевле, а адресов
+    // в списках блокировки обычно на порядки больше, чем сетей.
+    let (blocked_hosts, blocked_nets): (Vec<_>, Vec<_>) =
+        blocked_ips.iter().partition(|(_, len)| *len == 32);
+    // Значение записи блокировки — счётчик отброшенных по ней пакетов, он начинается с нуля.
+    let blocked_hosts: Vec<(u32, u64)> =
+        blocked_hosts.iter().map(|(addr, _)| (u32::from(*addr), 0)).collect();
+    let allowed: Vec<(u32, u8)> = allowed_ips.iter().map(|ip| (u32::from(*ip), 1)).collect();
+    load_list(&mut ebpf, BLOCKED_IPS_MAP, &blocked_hosts, map_batch_size, "blocked IPs")?;
+    load_list(&mut ebpf, ALLOWED_IPS_MAP, &allowed, map_batch_size, "allowed IPs")?;
+
+    if !blocked_nets.is_empty() {
+        let map = list_map(&mut ebpf, BLOCKED_NETS_MAP, blocked_nets.len(), "blocked networks")?;
+        let mut trie: LpmTrie<_, u32, u64> = LpmTrie::try_from(map)?;
+        for (addr, len) in &blocked_nets {
+            trie.insert(&Key::new(u32::from(*len), u32::from(*addr).to_be()), 0, 0)?;
+        }
+    }
+
+    if !blocked_ips6.is_empty() {
+        let map = list_map(&mut ebpf, BLOCKED_IPS6_MAP, blocked_ips6.len(), "blocked IPv6")?;
+        let mut trie: LpmTrie<_, [u8; 16], u64> = LpmTrie::try_from(map)?;
+        for (addr, len) in &blocked_ips6 {
+            trie.insert(&Key::new(u32::from(*len), addr.octets()), 0, 0)?;
+        }
+        println!("Loaded {} blocked IPv6 addresses and networks", blocked_ips6.len());
+    }
+
+    if !blocked_masks.is_empty() {
+        let mut masks: Array<_, MaskedAddr> = Array::try_from(
+            ebpf.map_mut(BLOCKED_MASKS_MAP).context("map BLOCKED_MASKS not found")?,
+        )?;
+        for (index, rule) in (0..).zip(&blocked_masks) {
+            masks.set(index, rule, 0)?;
+        }
+        values.push((settings::BLOCKED_MASKS, blocked_masks.len() as u32));
+    }
+
+    if !blocked_macs.is_empty() {
+        let map = list_map(&mut ebpf, BLOCKED_MACS_MAP, blocked_macs.len(), "MAC addresses")?;
+        let mut macs: HashMap<_, [u8; 6], u8> = HashMap::try_from(map)?;
+        for mac in &blocked_macs {
+            macs.insert(mac, 1, 0)?;
+        }
+        values.push((settings::MAC_FILTER, 1));
+        println!("Blocking {} source MAC addresses", blocked_macs.len());
+    }
+
+    if !blocked_countries.is_empty() {
+        let map = list_map(&mut ebpf, BLOCKED_COUNTRIES_MAP, blocked_countries.len(), "countries")?;
+        let mut countries: HashMap<_, u16, u8> = HashMap::try_from(map)?;
+        for country in &blocked_countries {
+            countries.insert(country, 1, 0)?;
+        }
+    }
+    if let Some(window) = blocked_countries_window {
+        values.push((settings::COUNTRY_WINDOW, window));
+        println!("Blocking countries only between {} local time", format_window(window));
+    }
+    // Часы нужны и без окна: apply-policy может задать его на ходу.
+    values.push((settings::CLOCK_MINUTE, time_window::local_minute()));
+
+    if !country_networks.is_empty() {
+        let map = list_map(&mut ebpf, COUNTRIES_MAP, country_networks.len(), "country networks")?;
+        let mut trie: LpmTrie<_, u32, u16> = LpmTrie::try_from(map)?;
+        for network in &country_networks {
+            let key = Key::new(u32::from(network.prefix), network.addr.to_be());
+            trie.insert(&key, network.country, 0)?;
+        }
+    }
+
+    if !region_networks.is_empty() {
+        let map = list_map(&mut ebpf, REGIONS_MAP, region_networks.len(), "region networks")?;
+        let mut trie: LpmTrie<_, u32, u32> = LpmTrie::try_from(map)?;
+        for network in &region_networks {
+            let key = Key::new(u32::from(network.prefix), u32::from(network.addr).to_be());
+            trie.insert(&key, network.region, 0)?;
+        }
+        values.push((settings::REGION_MATCH, 1));
+    }
+
+    if !blocked_endpoints.is_empty() {
+        let map = list_map(&mut ebpf, BLOCKED_ENDPOINTS_MAP, blocked_endpoints.len(), "endpoints")?;
+        let mut trie: LpmTrie<_, [u8; 6], u8> = LpmTrie::try_from(map)?;
+        for endpoint in &blocked_endpoints {
+            let data = endpoint_key(u32::from(endpoint.addr), endpoint.port);
+            trie.insert(&Key::new(16 + u32::from(endpoint.prefix), data), 1, 0)?;
+        }
+        values.push((settings::ENDPOINT_MATCH, endpoint_match.setting()));
+    }
+
+    if unwrap_ipip {
+        values.push((settings::UNWRAP_IPIP, 1));
+    }
+
+    if !fast_accept_prefixes.is_empty() {
+        let map = list_map(&mut ebpf, FAST_ACCEPT_MAP, fast_accept_prefixes.len(), "prefixes")?;
+        let mut trie: LpmTrie<_, u32, u8> = LpmTrie::try_from(map)?;
+        for (addr, len) in &fast_accept_prefixes {
+            trie.insert(&Key::new(u32::from(*len), u32::from(*addr).to_be()), 1, 0)?;
+        }
+        values.push((settings::FAST_ACCEPT, 1));
+    }
+    if !protected_ips.is_empty() {
+        let map = list_map(&mut ebpf, PROTECTED_IPS_MAP, protected_ips.len(), "protected IPs")?;
+        let mut trie: LpmTrie<_, u32, u8> = LpmTrie::try_from(map)?;
+        for (addr, len) in &protected_ips {
+            trie.insert(&Key::new(u32::from(*len), u32::from(*addr).to_be()), 1, 0)?;
+        }
+        values.push((settings::PROTECTED_IPS, 1));
+        println!("Rules apply only to traffic for {} protected prefixes", protected_ips.len());
+    }
+
+    if !xsk_redirect_ports.is_empty() {
+        let map = list_map(&mut ebpf, XSK_PORTS_MAP, xsk_redirect_ports.len(), "XSK ports")?;
+        let mut xsk_ports: HashMap<_, u16, u8> = HashMap::try_from(map)?;
+        for port in &xsk_redirect_ports {
+            xsk_ports.insert(port, 1, 0)?;
+        }
+        values.push((settings::XSK_REDIRECT, 1));
+        println!(
+            "Redirecting passed traffic to ports {xsk_redirect_ports:?} into AF_XDP sockets \
+             registered in {PIN_PATH}/{XSKS_MAP}"
+        );
+    }
+
+    if let Some(collector) = netflow_collector {
+        let flows = HashMap::try_from(ebpf.take_map(FLOWS_MAP).context("map FLOWS not found")?)?;
+        let idle = Duration::from_secs(flow_idle_timeout);
+        let active = Duration::from_secs(flow_active_timeout);
+        tokio::spawn(async move {
+            if let Err(e) = netflow::export(flows, collector, idle, active).await {
+                warn!("NetFlow exporter stopped: {e:#}");
+            }
+        });
+        values.push((settings::FLOW_TRACKING, 1));
+        println!("Exporting NetFlow v5 to {collector}");
+    }
+
+    let mut settings_map: Array<_, u32> =
+        Array::try_from(ebpf.map_mut(SETTINGS_MAP).context("map SETTINGS not found")?)?;
+    for (index, value) in values {
+        settings_map.set(index, value, 0)?;
+    }
+
+    tokio::spawn(run_clock(settings_handle(&ebpf)?));
+
+    if sweep_interval != 0 {
+        // Срок таблицы в секундах, не короче её собственного `own`.
+        let ttl = |own: u64| Duration::from_secs(u64::from(sweep_ttl).max(own));
+        // Пустое ведро наполняется за `burst / rate` секунд, после этого запись не нужна.
+        let refill = |rate: u32, burst| u64::from(burst_size(rate, burst)).div_ceil(rate.into());
+        let mut tables = Vec::new();
+        if let Some(timeout) = conntrack_timeout {
+            let ttl = ttl(timeout.into());
+            tables.push(sweep::table::<ConnKey, u64>(&ebpf, CONNTRACK_MAP, ttl)?);
+            tables.push(sweep::table::<ConnKey, u64>(&ebpf, ACCEPTED_CONNS_MAP, ttl)?);
+        }
+        if knock_port.is_some() {
+            let unlocked = ttl(knock_timeout.into());
+            tables.push(sweep::table::<u32, u64>(&ebpf, KNOCK_UNLOCKED_MAP, unlocked)?);
+            let progress = ttl(KNOCK_STEP_SECS.into());
+            tables.push(sweep::table::<u32, KnockProgress>(&ebpf, KNOCK_PROGRESS_MAP, progress)?);
+        }
+        if let Some(rate) = rate_limit {
+            let ttl = ttl(refill(rate, rate_burst));
+            tables.push(sweep::table::<u32, RateState>(&ebpf, RATE_BUCKETS_MAP, ttl)?);
+        }
+        if let Some(rate) = syn_rate_limit {
+            let ttl = ttl(refill(rate, syn_rate_burst));
+            tables.push(sweep::table::<u32, RateState>(&ebpf, SYN_BUCKETS_MAP, ttl)?);
+        }
+        if !tables.is_empty() {
+            tokio::spawn(sweep::run(tables, Duration::from_secs(sweep_interval.into())));
+        }
+    }
+
+    let ring = RingBuf::try_from(ebpf.take_map(EVENTS_MAP).context("map EVENTS not found")?)?;
+    let hub = events::Hub::new();
+    let reader = hub.clone();
+    tokio::spawn(async move {
+        if let Err(e) = events::read_events(ring, reader).await {
+            warn!("drop event reader stopped: {e:#}");
+        }
+    });
+    tokio::spawn(async move {
+        if let Err(e) = events::serve(hub).await {
+            warn!("event socket stopped: {e:#}");
+        }
+    });
+
+    if let Some(priority) = chain_priority {
+        for iface in &ifaces {
+            chain::warn_exclusive(iface, priority);
+        }
+    }
+
     let program: &mut Xdp = ebpf.program_mut("xdp_firewall").unwrap().try_into()?;
-    program.load()?;
-    program.attach(&iface, XdpFlags::SKB_MODE)
-        .context("failed to attach the XDP program with default flags - try changing XdpFlags::default() to XdpFlags::SKB_MODE")?;
+    program.load().map_err(|e| xdp_attach::explain(e.into()))?;
+    // Интерфейс, к которому привязать не удалось, не мешает защищать остальные.
+    let mut links = Vec::new();
+    let mut failed = Vec::new();
+    let mut last_error = None;
+    for iface in ifaces {
+        match attach_replacing(program, &iface, attach_mode, force) {
+            Ok(link) => links.push((iface, link)),
+            Err(e) => {
+                warn!("{e:#}");
+                failed.push(iface);
+                last_error = Some(e);
+            }
+        }
+    }
+    if links.is_empty() {
+        // Предупреждения видны только с RUST_LOG, поэтому причину несёт сама ошибка.
+        let e = last_error.unwrap_or_else(|| anyhow::anyhow!("no interface to attach to"));
+        return Err(e.context(format!(
+            "failed to attach the XDP program to any of {}",
+            failed.join(", ")
+        )));
+    }
+    if !failed.is_empty() {
+        warn!("not filtering on {}: attaching failed", failed.join(", "));
+    }
+    let conntrack_links = if conntrack_timeout.is_some() {
+        let attached: Vec<&str> = links.iter().map(|(iface, _)| iface.as_str()).collect();
+        conntrack::attach(&mut ebpf, &attached)?
+    } else {
+        Vec::new()
+    };
+
+    // При ошибке дальше программа отвязывается, когда `ebpf` удаляется.
+    pin_maps(&ebpf).context("failed to pin maps")?;
 
-    let ctrl_c = signal::ctrl_c();
     println!("Waiting for Ctrl-C...");
-    ctrl_c.await?;
+    let stopped = match watchdog {
+        Some(secs) => {
+            let every = Duration::from_secs(secs);
+            watch(&mut ebpf, &mut links, attach_mode, force, every).await
+        }
+        None => shutdown_signal().await,
+    };
     println!("Exiting...");
 
+    let program: &mut Xdp = ebpf.program_mut("xdp_firewall").unwrap().try_into()?;
+    for (iface, link) in links {
+        detach(program, link, &iface);
+    }
+    conntrack::detach(&mut ebpf, conntrack_links);
+    unpin_maps();
+    events::remove_socket();
+
+    stopped
+}
+
+/// Привязывает программу к интерфейсу в режиме `mode` и сообщает, в каком режиме она
+/// работает.
+fn attach(program: &mut Xdp, iface: &str, mode: AttachMode) -> anyhow::Result<XdpLinkId> {
+    let flags = |mode| match mode {
+        AttachMode::Auto | AttachMode::Driver => XdpFlags::DRV_MODE,
+        AttachMode::Skb => XdpFlags::SKB_MODE,
+        AttachMode::Hw => XdpFlags::HW_MODE,
+    };
+    let (link, used) = match program.attach(iface, flags(mode)) {
+        Ok(link) if mode == AttachMode::Auto => (link, AttachMode::Driver),
+        Ok(link) => (link, mode),
+        Err(e) if mode == AttachMode::Auto => {
+            warn!(
+                "driver-mode attach to {iface} failed ({:#}), falling back to generic (skb) \
+                 mode, which filters later and is slower; set --attach-mode skb to skip the try",
+                anyhow::Error::from(e)
+            );
+            let link = program
+                .attach(iface, XdpFlags::SKB_MODE)
+                .with_context(|| {
+                    format!("failed to attach the XDP program to {iface} in generic mode")
+                })
+                .map_err(|e| xdp_attach::explain_attach(e, iface, AttachMode::Skb))?;
+            (link, AttachMode::Skb)
+        }
+        Err(e) => {
+            let e = anyhow::Error::from(e).context(format!(
+                "failed to attach the XDP program to {iface} in {} mode",
+                mode.name()
+            ));
+            return Err(xdp_attach::explain_attach(e, iface, mode));
+        }
+    };
+    println!("XDP program attached to {iface} in {} mode", used.name());
+    Ok(link)
+}
+
+/// Привязывает программу, как [`attach`], а с `force` сначала снимает с интерфейса чужую.
+fn attach_replacing(
+    program: &mut Xdp,
+    iface: &str,
+    mode: AttachMode,
+    force: bool,
+) -> anyhow::Result<XdpLinkId> {
+    match attach(program, iface, mode) {
+        Err(e) if force && xdp_attach::is_busy(&e) => {
+            xdp_attach::remove_existing(iface)?;
+            println!("Removed the XDP program that was attached to {iface}");
+            attach(program, iface, mode)
+        }
+        attached => attached,
+    }
+}
+
+/// Ждёт сигнала остановки, как [`shutdown_signal`], и раз в `every` проверяет, что на каждом
+/// интерфейсе из `links` стоит своя программа. Снятую привязывает снова; если это не
+/// удаётся, пробует на следующей проверке.
+///
+/// Удалённые интерфейсы пропускаются: к пересозданному программу привязывает
+/// `firewall-cli reattach-iface`.
+async fn watch(
+    ebpf: &mut aya::Ebpf,
+    links: &mut Vec<(String, XdpLinkId)>,
+    mode: AttachMode,
+    force: bool,
+    every: Duration,
+) -> anyhow::Result<()> {
+    let stopped = shutdown_signal();
+    tokio::pin!(stopped);
+    let mut ticks = tokio::time::interval(every);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    ticks.tick().await;
+    let mut lost: Vec<String> = Vec::new();
+    loop {
+        tokio::select! {
+            result = &mut stopped => return result,
+            _ = ticks.tick() => {}
+        }
+        let program: &mut Xdp = ebpf.program_mut("xdp_firewall").unwrap().try_into()?;
+        let id = program.info()?.id();
+        let mut i = 0;
+        while i < links.len() {
+            let iface = &links[i].0;
+            let attached = match xdp_attach::attached_programs(iface) {
+                Ok(ids) => ids.contains(&id),
+                Err(_) if !iface_exists(iface) => true,
+                Err(e) => {
+                    warn!("watchdog: failed to check {iface}: {e:#}");
+                    true
+                }
+            };
+            if attached {
+                i += 1;
+                continue;
+            }
+            let (iface, link) = links.swap_remove(i);
+            println!("watchdog: the XDP program was removed from {iface}, attaching it again");
+            // Привязки уже нет, освобождаем только её описание.
+            drop(program.take_link(link));
+            lost.push(iface);
+        }
+        let mut still_lost = Vec::new();
+        for iface in lost.drain(..) {
+            if !iface_exists(&iface) {
+                continue;
+            }
+            match attach_replacing(program, &iface, mode, force) {
+                Ok(link) => {
+                    println!("watchdog: attached the XDP program to {iface} again");
+                    links.push((iface, link));
+                }
+                Err(e) => {
+                    warn!("watchdog: failed to attach the XDP program to {iface} again: {e:#}");
+                    still_lost.push(iface);
+                }
+            }
+        }
+        lost = still_lost;
+    }
+}
+
+/// Ждёт Ctrl+C или `SIGTERM`, которым загрузчик останавливают systemd и `kill`.
+async fn shutdown_signal() -> anyhow::Result<()> {
+    let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+    tokio::select! {
+        result = signal::ctrl_c() => Ok(result?),
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+/// Отвязывает программу от интерфейса, чтобы она не фильтровала трафик после выхода.
+///
+/// Если интерфейс успели удалить, вместе с ним исчезла и привязка: это не ошибка.
+fn detach(program: &mut Xdp, link: XdpLinkId, iface: &str) {
+    match program.detach(link) {
+        Ok(()) => info!("detached the XDP program from {iface}"),
+        Err(_) if !iface_exists(iface) => {
+            info!("{iface} no longer exists, the XDP program went away with it")
+        }
+        Err(e) => warn!(
+            "failed to detach the XDP program from {iface}: {e}; remove it with \
+             `firewall-cli detach --iface {iface}`"
+        ),
+    }
+}
+
+fn iface_exists(iface: &str) -> bool {
+    Path::new("/sys/class/net").join(iface).exists()
+}
+
+/// Предупреждает о цене `--log-allows`: пропущенного трафика обычно намного больше, чем
+/// отброшенного, и каждое событие проходит через кольцевой буфер и журнал.
+fn warn_log_allows(rate: u32) {
+    warn!(
+        "--log-allows {rate}: every {rate}th passed packet becomes an event; on busy links this \
+         costs CPU in the XDP program and can flood the ring buffer and the log, raise N if \
+         drop events go missing"
+    );
+}
+
+/// Ёмкость ведра `--rate-limit` и `--syn-rate-limit`: явная или на одну секунду трафика.
+fn burst_size(rate: u32, burst: Option<u32>) -> u32 {
+    burst.unwrap_or(rate.min(RateState::MAX_BURST))
+}
+
+/// Правила и настройки из аргументов для режима без XDP.
+fn userspace_options(
+    opt: Opt,
+    regions: &[regions::RegionNetwork],
+    countries: &[geoip::CountryNetwork],
+) -> userspace::Options {
+    let addrs = |list: &[Ipv4Addr]| list.iter().map(|ip| u32::from(*ip)).collect();
+    userspace::Options {
+        rules: userspace::UserRules {
+            blocked_ips: opt
+                .blocked_ips
+                .iter()
+                .filter(|(_, len)| *len == 32)
+                .map(|(addr, _)| u32::from(*addr))
+                .collect(),
+            blocked_nets: opt
+                .blocked_ips
+                .iter()
+                .filter(|(_, len)| *len < 32)
+                .map(|(addr, len)| (u32::from(*addr), prefix_mask(*len)))
+                .collect(),
+            blocked_ips6: opt
+                .blocked_ips6
+                .iter()
+                .map(|(addr, len)| (u128::from(*addr), prefix_mask6(*len)))
+                .collect(),
+            blocked_masks: opt.blocked_masks.clone(),
+            blocked_macs: opt.blocked_macs.iter().copied().collect(),
+            blocked_countries: opt.blocked_countries.iter().copied().collect(),
+            country_window: opt.blocked_countries_window.unwrap_or(0),
+            blocked_regions: regions
+                .iter()
+                .map(|n| (u32::from(n.addr), prefix_mask(n.prefix)))
+                .collect(),
+            allowed_ips: addrs(&opt.allowed_ips),
+            tcp_windows: opt.block_tcp_window.iter().copied().collect(),
+            tcp_flag_filter: !opt.allow_invalid_tcp_flags,
+            allowed_ports: merge_ports(&opt.ports),
+            blocked_src_ports: merge_ports(&opt.blocked_src_ports),
+            port_match_src: opt.port_match == PortMatch::Src,
+            default_allow: opt.policy == Policy::Allow,
+            strict_protocols: opt.strict_protocols || !opt.protocols.is_empty(),
+            protocols: opt.protocols.iter().copied().collect(),
+            knock_port: opt.knock_port.unwrap_or(0),
+            knock_sequence: opt.knock_sequence.clone(),
+            knock_timeout: Duration::from_secs(u64::from(opt.knock_timeout)),
+            knocks: Default::default(),
+            drop_fragments: opt.drop_fragments,
+            allow_dns: !opt.strict_dns,
+            icmp_echo: opt.allow_icmp_echo,
+            blocked_endpoints: opt
+                .blocked_endpoints
+                .iter()
+                .map(|e| (u32::from(e.addr), prefix_mask(e.prefix), e.port))
+                .collect(),
+            endpoint_match_dst: opt.endpoint_match == EndpointMatch::Dst,
+            fast_accept: opt
+                .fast_accept_prefixes
+                .iter()
+                .map(|(addr, len)| (u32::from(*addr), prefix_mask(*len)))
+                .collect(),
+            protected_ips: opt
+                .protected_ips
+                .iter()
+                .map(|(addr, len)| (u32::from(*addr), prefix_mask(*len)))
+                .collect(),
+            directions: opt.direction.setting(),
+        },
+        countries: geoip::CountryTable::new(countries),
+        event_sample_rate: opt.event_sample_rate,
+        allow_sample_rate: opt.log_allows.unwrap_or(0),
+        event_fields: opt.event_fields.iter().fold(0, |mask, bit| mask | bit),
+        rate_limit: opt.rate_limit.map(|rate| (rate, burst_size(rate, opt.rate_burst))),
+        syn_rate_limit: opt
+            .syn_rate_limit
+            .map(|rate| (rate, burst_size(rate, opt.syn_rate_burst))),
+        count_only: opt.count_only,
+        unwrap_ipip: opt.unwrap_ipip,
+        filter_multicast: opt.filter_multicast,
+        arp_subnet: opt.arp_subnet.map_or((0, 0), |(addr, len)| (u32::from(addr), u32::from(len))),
+        iface: opt.iface.into_iter().next().unwrap_or_default(),
+    }
+}
+
+/// Карта для списка из `needed` записей; список, который не поместится, отклоняется целиком.
+fn list_map<'a>(
+    ebpf: &'a mut aya::Ebpf,
+    name: &str,
+    needed: usize,
+    what: &str,
+) -> anyhow::Result<&'a mut aya::maps::Map> {
+    let map = ebpf.map_mut(name).with_context(|| format!("map {name} not found"))?;
+    batch::ensure_fits(map, needed, what).with_context(|| format!("cannot load {name}"))?;
+    Ok(map)
+}
+
+/// Загружает список адресов `entries` в хеш-карту `name`, если он не пуст, и сообщает, как.
+fn load_list<V: aya::Pod>(
+    ebpf: &mut aya::Ebpf,
+    name: &str,
+    entries: &[(u32, V)],
+    batch_size: usize,
+    what: &str,
+) -> anyhow::Result<()> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+    let map = ebpf.map_mut(name).with_context(|| format!("map {name} not found"))?;
+    let started = Instant::now();
+    let method = batch::insert_all(map, entries, batch_size, what)
+        .with_context(|| format!("failed to load {what}"))?;
+    println!("Loaded {} {what} in {:.1?} ({})", entries.len(), started.elapsed(), method.as_str());
+    Ok(())
+}
+
+/// Разбирает имя необязательного поля события в бит `event_fields`.
+fn parse_event_field(name: &str) -> Result<u8, String> {
+    event_fields::from_name(name).ok_or_else(|| {
+        let names: Vec<_> = event_fields::NAMES.iter().map(|(n, _)| *n).collect();
+        format!("unknown event field '{name}', expected one of: {}", names.join(", "))
+    })
+}
+
+/// Проверяет последовательность стука для порта `port`.
+fn check_knock(port: u16, sequence: &[u16]) -> anyhow::Result<()> {
+    if sequence.len() > MAX_KNOCK_PORTS as usize {
+        anyhow::bail!("--knock-sequence: at most {MAX_KNOCK_PORTS} ports");
+    }
+    if port == 0 || sequence.contains(&0) {
+        anyhow::bail!("knock ports must be 1-65535");
+    }
+    if sequence.contains(&port) {
+        anyhow::bail!("--knock-sequence must not contain --knock-port {port}");
+    }
+    // Повтор предыдущего порта засчитывается как повторная отправка, а не как новый шаг.
+    if let Some(pair) = sequence.windows(2).find(|pair| pair[0] == pair[1]) {
+        anyhow::bail!("--knock-sequence: port {} twice in a row never advances", pair[0]);
+    }
     Ok(())
 }
+
+/// Разбирает правило `NAME=allow|deny` в номер протокола и значение [`protocol_rule`].
+fn parse_protocol_rule(text: &str) -> Result<(u8, u8), String> {
+    let (name, action) = text
+        .split_once('=')
+        .ok_or_else(|| format!("'{text}': expected NAME=allow or NAME=deny"))?;
+    let proto = protocol_rule::from_name(&name.to_ascii_lowercase()).ok_or_else(|| {
+        let names: Vec<_> = protocol_rule::NAMES.iter().map(|(n, _)| *n).collect();
+        format!("unknown protocol '{name}', expected a number or one of: {}", names.join(", "))
+    })?;
+    let rule = match action.to_ascii_lowercase().as_str() {
+        "allow" => protocol_rule::ALLOW,
+        "deny" => protocol_rule::DENY,
+        _ => return Err(format!("'{text}': action must be allow or deny")),
+    };
+    Ok((proto, rule))
+}
+
+/// Разбирает порт `PORT` или диапазон `FIRST-LAST`, за которыми может идти `/tcp` или
+/// `/udp`, в диапазон портов и маску `port_protos`.
+fn parse_port_spec(text: &str) -> Result<(RangeInclusive<u16>, u8), String> {
+    let (port, protos) = match text.split_once('/') {
+        Some((port, proto)) => match proto.to_ascii_lowercase().as_str() {
+            "tcp" => (port, port_protos::TCP),
+            "udp" => (port, port_protos::UDP),
+            _ => return Err(format!("'{text}': protocol must be tcp or udp")),
+        },
+        None => (text, port_protos::ANY),
+    };
+    let number = |port: &str| -> Result<u16, String> {
+        port.parse().map_err(|_| format!("'{text}': '{port}' is not a port"))
+    };
+    let ports = match port.split_once('-') {
+        Some((first, last)) => number(first)?..=number(last)?,
+        None => number(port)?..=number(port)?,
+    };
+    if ports.is_empty() {
+        return Err(format!("'{text}': range ends before it starts"));
+    }
+    Ok((ports, protos))
+}
+
+/// Раскрывает диапазоны и складывает протоколы порта, заданного несколько раз
+/// (`53/tcp 53/udp`).
+fn merge_ports(ports: &[(RangeInclusive<u16>, u8)]) -> std::collections::HashMap<u16, u8> {
+    let mut merged = std::collections::HashMap::new();
+    for (range, protos) in ports {
+        for port in range.clone() {
+            *merged.entry(port).or_insert(0) |= protos;
+        }
+    }
+    merged
+}
+
+/// Разбирает окно времени `HH:MM-HH:MM` в значение `settings::COUNTRY_WINDOW`.
+fn parse_window(text: &str) -> Result<u32, String> {
+    time_window::parse(text).ok_or_else(|| {
+        format!("'{text}' is not a time window HH:MM-HH:MM with different start and end")
+    })
+}
+
+fn format_window(window: u32) -> String {
+    let (start, end) = time_window::bounds(window).unwrap_or_default();
+    let time = |minute: u16| format!("{:02}:{:02}", minute / 60, minute % 60);
+    format!("{} and {}", time(start), time(end))
+}
+
+/// Как часто обновлять минуту в `settings::CLOCK_MINUTE`: окно открывается не позже
+/// чем через столько после своего начала.
+const CLOCK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Отдельный дескриптор карты `SETTINGS` для задачи, которая живёт дольше заимствования `ebpf`.
+fn settings_handle(ebpf: &aya::Ebpf) -> anyhow::Result<Array<MapData, u32>> {
+    let Some(Map::Array(data)) = ebpf.map(SETTINGS_MAP) else {
+        anyhow::bail!("map SETTINGS not found");
+    };
+    let fd = data.fd().as_fd().try_clone_to_owned()?;
+    Ok(Array::try_from(Map::Array(MapData::from_fd(fd)?))?)
+}
+
+/// Раз в `CLOCK_INTERVAL` записывает в `settings::CLOCK_MINUTE` текущую минуту местного
+/// времени, по которой программа проверяет окна правил.
+async fn run_clock(mut settings_map: Array<MapData, u32>) {
+    let mut interval = tokio::time::interval(CLOCK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = settings_map.set(settings::CLOCK_MINUTE, time_window::local_minute(), 0) {
+            warn!("failed to update the clock for time windows: {e}");
+        }
+    }
+}
+
+/// Разбирает двухбуквенный код страны в ключ карты `BLOCKED_COUNTRIES`.
+fn parse_country_code(code: &str) -> Result<u16, String> {
+    country_key(code.as_bytes()).ok_or_else(|| format!("'{code}' is not a two-letter country code"))
+}
+
+/// Разбирает префикс `a.b.c.d/len` (или один адрес) и обнуляет биты хоста.
+fn parse_prefix(text: &str) -> Result<(Ipv4Addr, u8), String> {
+    let (addr, len) = text.split_once('/').unwrap_or((text, "32"));
+    let addr: Ipv4Addr = addr.parse().map_err(|_| format!("'{text}' is not an IPv4 prefix"))?;
+    let len: u8 = match len.parse() {
+        Ok(len) if len <= 32 => len,
+        _ => return Err(format!("'{text}': prefix length must be 0..=32")),
+    };
+    Ok((Ipv4Addr::from(u32::from(addr) & prefix_mask(len)), len))
+}
+
+/// Префикс IPv6 `адрес[/длина]`; биты узла обнуляются.
+fn parse_prefix6(text: &str) -> Result<(Ipv6Addr, u8), String> {
+    let (addr, len) = text.split_once('/').unwrap_or((text, "128"));
+    let addr: Ipv6Addr = addr.parse().map_err(|_| format!("'{text}' is not an IPv6 prefix"))?;
+    let len: u8 = match len.parse() {
+        Ok(len) if len <= 128 => len,
+        _ => return Err(format!("'{text}': prefix length must be 0..=128")),
+    };
+    Ok((Ipv6Addr::from(u128::from(addr) & prefix_mask6(len)), len))
+}
+
+/// Разбирает правило `адрес/маска`, где маска — произвольная битовая маска в виде адреса.
+fn parse_masked(text: &str) -> Result<MaskedAddr, String> {
+    let (addr, mask) = text
+        .split_once('/')
+        .ok_or_else(|| format!("'{text}' is not ADDR/MASK"))?;
+    let addr: Ipv4Addr = addr.parse().map_err(|_| format!("'{text}': '{addr}' is not an address"))?;
+    let mask: Ipv4Addr = mask.parse().map_err(|_| format!("'{text}': '{mask}' is not a mask"))?;
+    Ok(MaskedAddr::new(u32::from(addr), u32::from(mask)))
+}
+
+fn parse_mac(text: &str) -> Result<[u8; 6], String> {
+    firewall_common::parse_mac(text)
+        .ok_or_else(|| format!("'{text}' is not a MAC address xx:xx:xx:xx:xx:xx"))
+}
+
+/// Разбирает составное правило `адрес[/длина]:порт`.
+fn parse_endpoint(text: &str) -> Result<Endpoint, String> {
+    let (prefix, port) = text
+        .rsplit_once(':')
+        .ok_or_else(|| format!("'{text}' is not ADDR[/LEN]:PORT"))?;
+    let (addr, prefix) = parse_prefix(prefix)?;
+    let port = port.parse().map_err(|_| format!("'{text}': '{port}' is not a port"))?;
+    Ok(Endpoint { addr, prefix, port })
+}
+
+/// Маска сети для префикса длиной `len`.
+fn prefix_mask(len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0)
+}
+
+fn prefix_mask6(len: u8) -> u128 {
+    u128::MAX.checked_shl(128 - u32::from(len)).unwrap_or(0)
+}
+
+/// Карты, которые закрепляются в bpffs для firewall-cli; `SETTINGS` — для `pause`/`resume`,
+/// `VERDICT_OVERRIDES` — для внешних классификаторов, `XSKS` — для приложений AF_XDP,
+/// `BLOCKED_IPS` и `STAGED_IPS` — для `firewall-cli block`, `RULE_COSTS` — для
+/// `firewall-cli profile`, карты правил — для `firewall-cli apply-policy`, `CONNTRACK` — для
+/// размера таблицы соединений в `firewall-cli stats`, `DROP_REASONS` — для разбивки
+/// отброшенных по причинам там же, `ACCEPTED_CONNS` — для окна `grace-period` при
+/// перезагрузке политики.
+const PINNED_MAPS: &[&str] = &[
+    STATS_MAP,
+    DROP_REASONS_MAP,
+    PORT_STATS_MAP,
+    COUNTRY_STATS_MAP,
+    COUNTRY_DROPS_MAP,
+    SOURCE_STATS_MAP,
+    SETTINGS_MAP,
+    VERDICT_OVERRIDES_MAP,
+    XSKS_MAP,
+    BLOCKED_IPS_MAP,
+    STAGED_IPS_MAP,
+    RULE_COSTS_MAP,
+    ALLOWED_PORTS_MAP,
+    BLOCKED_SRC_PORTS_MAP,
+    ALLOWED_IPS_MAP,
+    BLOCKED_COUNTRIES_MAP,
+    TCP_WINDOWS_MAP,
+    BLOCKED_MASKS_MAP,
+    BLOCKED_ENDPOINTS_MAP,
+    FAST_ACCEPT_MAP,
+    PROTECTED_IPS_MAP,
+    PROTOCOLS_MAP,
+    KNOCK_UNLOCKED_MAP,
+    BLOCKED_NETS_MAP,
+    BLOCKED_IPS6_MAP,
+    BLOCKED_MACS_MAP,
+    CONNTRACK_MAP,
+    ACCEPTED_CONNS_MAP,
+];
+
+/// Закрепляет карты в `PIN_PATH`, заменяя оставшиеся от предыдущего запуска.
+fn pin_maps(ebpf: &aya::Ebpf) -> anyhow::Result<()> {
+    fs::create_dir_all(PIN_PATH).with_context(|| format!("failed to create {PIN_PATH}"))?;
+    for name in PINNED_MAPS {
+        let path = Path::new(PIN_PATH).join(name);
+        if path.exists() {
+            fs::remove_file(&path).with_context(|| format!("failed to remove stale pin {path:?}"))?;
+        }
+        let map = ebpf.map(name).with_context(|| format!("map {name} not found"))?;
+        map.pin(&path).with_context(|| format!("failed to pin {name}"))?;
+    }
+    Ok(())
+}
+
+/// Удаляет закреплённые карты: их отсутствие означает, что файрволл не запущен.
+fn unpin_maps() {
+    for name in PINNED_MAPS {
+        let path = Path::new(PIN_PATH).join(name);
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("failed to unpin {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoint_rules_build_composite_keys() {
+        let host = parse_endpoint("1.2.3.4:443").unwrap();
+        assert_eq!((host.addr, host.prefix, host.port), (Ipv4Addr::new(1, 2, 3, 4), 32, 443));
+        assert_eq!(endpoint_key(u32::from(host.addr), host.port), [0x01, 0xbb, 1, 2, 3, 4]);
+
+        let net = parse_endpoint("10.1.2.3/8:22").unwrap();
+        assert_eq!((net.addr, net.prefix, net.port), (Ipv4Addr::new(10, 0, 0, 0), 8, 22));
+        assert_eq!(endpoint_key(u32::from(net.addr), net.port), [0, 22, 10, 0, 0, 0]);
+
+        assert!(parse_endpoint("1.2.3.4").is_err());
+        assert!(parse_endpoint("1.2.3.4:https").is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context as _;
+use aya::maps::{HashMap, MapData};
+use firewall_common::{FlowKey, FlowStats};
+use log::{debug, warn};
+use tokio::{net::UdpSocket, time};
+
+/// Размер заголовка пакета NetFlow v5.
+pub const V5_HEADER_LEN: usize = 24;
+/// Размер одной записи о потоке NetFlow v5.
+pub const V5_RECORD_LEN: usize = 48;
+/// Больше записей в один пакет v5 не помещается по спецификации.
+pub const V5_MAX_RECORDS: usize = 30;
+
+/// Как часто просматривать таблицу потоков в поисках истёкших.
+const SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Поток, готовый к экспорту.
+#[derive(Clone, Copy, Debug)]
+pub struct FlowRecord {
+    pub key: FlowKey,
+    pub packets: u64,
+    pub bytes: u64,
+    /// Время первого и последнего пакета в миллисекундах от загрузки системы (SysUptime).
+    pub first_ms: u32,
+    pub last_ms: u32,
+    pub tcp_flags: u8,
+}
+
+impl FlowRecord {
+    pub fn new(key: FlowKey, stats: &FlowStats) -> Self {
+        Self {
+            key,
+            packets: stats.packets,
+            bytes: stats.bytes,
+            first_ms: (stats.first_seen_ns / 1_000_000) as u32,
+            last_ms: (stats.last_seen_ns / 1_000_000) as u32,
+            tcp_flags: stats.tcp_flags,
+        }
+    }
+}
+
+/// Кодирует до `V5_MAX_RECORDS` потоков в один пакет NetFlow v5.
+///
+/// `sequence` — число потоков, отправленных до этого пакета, как требует поле flow_sequence.
+/// Счётчики больше `u32::MAX` насыщаются.
+pub fn encode_v5(records: &[FlowRecord], uptime_ms: u32, now: Duration, sequence: u32) -> Vec<u8> {
+    let records = &records[..records.len().min(V5_MAX_RECORDS)];
+    let mut out = Vec::with_capacity(V5_HEADER_LEN + records.len() * V5_RECORD_LEN);
+
+    out.extend_from_slice(&5u16.to_be_bytes());
+    out.extend_from_slice(&(records.len() as u16).to_be_bytes());
+    out.extend_from_slice(&uptime_ms.to_be_bytes());
+    out.extend_from_slice(&(now.as_secs() as u32).to_be_bytes());
+    out.extend_from_slice(&now.subsec_nanos().to_be_bytes());
+    out.extend_from_slice(&sequence.to_be_bytes());
+    // engine_type, engine_id, sampling_interval: выборка не используется.
+    out.extend_from_slice(&[0, 0, 0, 0]);
+
+    let saturate = |value: u64| u32::try_from(value).unwrap_or(u32::MAX);
+    for record in records {
+        let key = &record.key;
+        out.extend_from_slice(&key.src_addr.to_be_bytes());
+        out.extend_from_slice(&key.dst_addr.to_be_bytes());
+        // nexthop, input, output: файрволл не маршрутизирует трафик.
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(&saturate(record.packets).to_be_bytes());
+        out.extend_from_slice(&saturate(record.bytes).to_be_bytes());
+        out.extend_from_slice(&record.first_ms.to_be_bytes());
+        out.extend_from_slice(&record.last_ms.to_be_bytes());
+        out.extend_from_slice(&key.src_port.to_be_bytes());
+        out.extend_from_slice(&key.dst_port.to_be_bytes());
+        out.extend_from_slice(&[0, record.tcp_flags, key.proto, 0]);
+        // src_as, dst_as, src_mask, dst_mask, pad2.
+        out.extend_from_slice(&[0; 8]);
+    }
+
+    out
+}
+
+/// Текущее время CLOCK_MONOTONIC в наносекундах — та же шкала, что у `bpf_ktime_get_ns`.
+pub fn monotonic_ns() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// Истекает ли поток: нет пакетов дольше `idle` или он длится дольше `active`.
+pub fn expired(stats: &FlowStats, now_ns: u64, idle: Duration, active: Duration) -> bool {
+    let idle_ns = now_ns.saturating_sub(stats.last_seen_ns);
+    let age_ns = now_ns.saturating_sub(stats.first_seen_ns);
+    idle_ns >= idle.as_nanos() as u64 || age_ns >= active.as_nanos() as u64
+}
+
+/// Периодически выгружает истёкшие потоки из `FLOWS` и отправляет их коллектору.
+///
+/// Выгруженный поток удаляется из карты, следующий пакет того же 5-кортежа начнёт новый.
+pub async fn export(
+    mut flows: HashMap<MapData, FlowKey, FlowStats>,
+    collector: SocketAddr,
+    idle: Duration,
+    active: Duration,
+) -> anyhow::Result<()> {
+    let bind: SocketAddr = if collector.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(bind).await.context("failed to bind NetFlow socket")?;
+    socket
+        .connect(collector)
+        .await
+        .with_context(|| format!("failed to connect to collector {collector}"))?;
+
+    let mut sequence: u32 = 0;
+    let mut interval = time::interval(SCAN_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let now_ns = monotonic_ns();
+        let mut records = Vec::new();
+        for entry in flows.iter() {
+            let (key, stats) = entry?;
+            if expired(&stats, now_ns, idle, active) {
+                records.push(FlowRecord::new(key, &stats));
+            }
+        }
+        for record in &records {
+            // Поток мог уже вытесниться из LRU-карты, это не ошибка.
+            let _ = flows.remove(&record.key);
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let uptime_ms = (now_ns / 1_000_000) as u32;
+        for chunk in records.chunks(V5_MAX_RECORDS) {
+            let packet = encode_v5(chunk, uptime_ms, now, sequence);
+            if let Err(e) = socket.send(&packet).await {
+                warn!("failed to send NetFlow export to {collector}: {e}");
+            }
+            sequence = sequence.wrapping_add(chunk.len() as u32);
+        }
+        if !records.is_empty() {
+            debug!("exported {} flows to {collector}", records.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    const HTTPS: FlowKey = FlowKey {
+        src_addr: 0xc633_6407, // 198.51.100.7
+        dst_addr: 0xc000_0201, // 192.0.2.1
+        src_port: 40000,
+        dst_port: 443,
+        proto: 6,
+        _pad: [0; 3],
+    };
+
+    #[test]
+    fn expired_flow_encodes_as_v5_record() {
+        let stats = FlowStats {
+            packets: 12,
+            bytes: 1 << 33,
+            first_seen_ns: 100 * SECOND,
+            last_seen_ns: 105 * SECOND + 250_000_000,
+            tcp_flags: 0x1b,
+            _pad: [0; 7],
+        };
+        let idle = Duration::from_secs(15);
+        let active = Duration::from_secs(1800);
+        assert!(!expired(&stats, 110 * SECOND, idle, active));
+        assert!(expired(&stats, 121 * SECOND, idle, active));
+
+        let now = Duration::new(1_700_000_000, 5);
+        let packet = encode_v5(&[FlowRecord::new(HTTPS, &stats)], 121_000, now, 7);
+        assert_eq!(packet.len(), V5_HEADER_LEN + V5_RECORD_LEN);
+
+        let header = &packet[..V5_HEADER_LEN];
+        assert_eq!(header[..4], [0, 5, 0, 1]);
+        assert_eq!(header[4..8], 121_000u32.to_be_bytes());
+        assert_eq!(header[8..12], 1_700_000_000u32.to_be_bytes());
+        assert_eq!(header[12..16], 5u32.to_be_bytes());
+        assert_eq!(header[16..20], 7u32.to_be_bytes());
+
+        let record = &packet[V5_HEADER_LEN..];
+        assert_eq!(record[0..4], [198, 51, 100, 7]);
+        assert_eq!(record[4..8], [192, 0, 2, 1]);
+        assert_eq!(record[8..16], [0; 8]);
+        assert_eq!(record[16..20], 12u32.to_be_bytes());
+        // Байт больше, чем помещается в поле dOctets: счётчик насыщается.
+        assert_eq!(record[20..24], u32::MAX.to_be_bytes());
+        assert_eq!(record[24..28], 100_000u32.to_be_bytes());
+        assert_eq!(record[28..32], 105_250u32.to_be_bytes());
+        assert_eq!(record[32..34], 40000u16.to_be_bytes());
+        assert_eq!(record[34..36], 443u16.to_be_bytes());
+        assert_eq!(record[36..40], [0, 0x1b, 6, 0]);
+        assert_eq!(record[40..48], [0; 8]);
+    }
+
+    #[test]
+    fn v5_packet_holds_at_most_thirty_records() {
+        let record = FlowRecord {
+            key: HTTPS,
+            packets: 1,
+            bytes: 60,
+            first_ms: 0,
+            last_ms: 0,
+            tcp_flags: 0,
+        };
+        let packet = encode_v5(&[record; 31], 0, Duration::ZERO, 0);
+        assert_eq!(packet[2..4], 30u16.to_be_bytes());
+        assert_eq!(packet.len(), V5_HEADER_LEN + 30 * V5_RECORD_LEN);
+    }
+}
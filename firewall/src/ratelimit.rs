@@ -0,0 +1,169 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::{Log, Metadata, Record};
+
+/// Как часто выводить сводку о подавленных строках, если поток логов прекратился.
+pub const SUMMARY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Ведро токенов: не больше `rate` событий в секунду с запасом на всплеск в одну секунду.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, now: Instant) -> Self {
+        Self {
+            rate: f64::from(rate),
+            tokens: f64::from(rate),
+            last: now,
+        }
+    }
+
+    /// Забирает токен, если он есть.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    bucket: TokenBucket,
+    suppressed: u64,
+}
+
+impl State {
+    /// Пропускает строку, если в ведре есть токен, и возвращает, сколько строк подавлено
+    /// до неё; `None` — строка подавлена.
+    fn admit(&mut self, now: Instant) -> Option<u64> {
+        if self.bucket.try_take(now) {
+            Some(std::mem::take(&mut self.suppressed))
+        } else {
+            self.suppressed += 1;
+            None
+        }
+    }
+}
+
+/// Логгер поверх env_logger, ограничивающий общее число строк в секунду.
+///
+/// Ограничение действует на все пути вывода сразу: события об отброшенных пакетах, логи
+/// программы eBPF и сообщения загрузчика. Лишние строки отбрасываются, вместо них
+/// периодически выводится сводка «suppressed N log lines».
+pub struct RateLimitedLogger {
+    inner: env_logger::Logger,
+    state: Mutex<State>,
+}
+
+impl RateLimitedLogger {
+    pub fn new(inner: env_logger::Logger, rate: u32) -> Self {
+        Self {
+            inner,
+            state: Mutex::new(State {
+                bucket: TokenBucket::new(rate, Instant::now()),
+                suppressed: 0,
+            }),
+        }
+    }
+
+    /// Устанавливает логгер глобально и возвращает ссылку для `flush_summary`.
+    pub fn install(self) -> Result<&'static Self, log::SetLoggerError> {
+        let logger: &'static Self = Box::leak(Box::new(self));
+        log::set_logger(logger)?;
+        log::set_max_level(logger.inner.filter());
+        Ok(logger)
+    }
+
+    /// Выводит сводку о подавленных строках, если они были.
+    pub fn flush_summary(&self) {
+        let suppressed = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            std::mem::take(&mut state.suppressed)
+        };
+        self.emit_summary(suppressed);
+    }
+
+    fn emit_summary(&self, suppressed: u64) {
+        if suppressed == 0 {
+            return;
+        }
+        self.inner.log(
+            &Record::builder()
+                .level(log::Level::Warn)
+                .target(module_path!())
+                .args(format_args!("suppressed {suppressed} log lines"))
+                .build(),
+        );
+    }
+}
+
+impl Log for RateLimitedLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        let admitted = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.admit(Instant::now())
+        };
+        let Some(suppressed) = admitted else {
+            return;
+        };
+        // Сводка идёт перед первой пропущенной строкой, чтобы порядок в логе был понятен.
+        self.emit_summary(suppressed);
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_is_capped_to_rate_with_summary() {
+        let start = Instant::now();
+        let mut state = State {
+            bucket: TokenBucket::new(100, start),
+            suppressed: 0,
+        };
+        let admitted = (0..1000).filter(|_| state.admit(start).is_some()).count();
+        assert_eq!(admitted, 100);
+        assert_eq!(state.suppressed, 900);
+
+        // За полсекунды ведро набирает 50 токенов; первая строка несёт сводку.
+        let later = start + Duration::from_millis(500);
+        assert_eq!(state.admit(later), Some(900));
+        let admitted = (0..1000).filter(|_| state.admit(later).is_some()).count();
+        assert_eq!(admitted, 49);
+        assert_eq!(state.suppressed, 951);
+    }
+
+    #[test]
+    fn idle_bucket_refills_to_one_second_of_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10, start);
+        let later = start + Duration::from_secs(60);
+        assert_eq!((0..100).filter(|_| bucket.try_take(later)).count(), 10);
+    }
+}
@@ -0,0 +1,122 @@
+//! Блокировка по региону (городу) из базы MaxMind GeoIP2/GeoLite2 City.
+//!
+//! Читается CSV-выпуск базы, файл `GeoLite2-City-Blocks-IPv4.csv`: столбец `network` с
+//! сетью и столбец `geoname_id` с идентификатором места, который и служит идентификатором
+//! региона в `--blocked-regions`. В полной базе миллионы сетей, поэтому в карту `REGIONS`
+//! попадают только сети заблокированных регионов.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufRead as _, BufReader},
+    net::Ipv4Addr,
+    path::Path,
+};
+
+use anyhow::Context as _;
+
+/// Сеть из базы вместе с её регионом.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionNetwork {
+    pub addr: Ipv4Addr,
+    pub prefix: u8,
+    pub region: u32,
+}
+
+/// Номер столбца `name` в строке заголовка.
+fn column(header: &str, name: &str) -> Option<usize> {
+    header.split(',').position(|field| field.trim() == name)
+}
+
+/// Читает из базы `path` сети, которые относятся к регионам из `blocked`.
+///
+/// Строки без `geoname_id` (в базе так отмечены сети, известные лишь до страны)
+/// пропускаются.
+pub fn load(path: &Path, blocked: &HashSet<u32>) -> anyhow::Result<Vec<RegionNetwork>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut lines = BufReader::new(file).lines();
+    let header = lines.next().context("region database is empty")??;
+    let (Some(network_col), Some(region_col)) =
+        (column(&header, "network"), column(&header, "geoname_id"))
+    else {
+        anyhow::bail!("{}: expected network and geoname_id columns", path.display());
+    };
+
+    let mut networks = Vec::new();
+    for (index, line) in lines.enumerate() {
+        let line = line?;
+        let fields: Vec<&str> = line.split(',').collect();
+        let Some(Ok(region)) = fields.get(region_col).map(|id| id.parse::<u32>()) else {
+            continue;
+        };
+        if !blocked.contains(&region) {
+            continue;
+        }
+        let network = fields.get(network_col).copied().unwrap_or_default();
+        let (addr, prefix) = crate::parse_prefix(network)
+            .map_err(anyhow::Error::msg)
+            .with_context(|| format!("{}:{}", path.display(), index + 2))?;
+        networks.push(RegionNetwork { addr, prefix, region });
+    }
+    Ok(networks)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs};
+
+    use firewall_common::{
+        classify::{self, Packet, Verdict, IPPROTO_TCP},
+        lookup_country, port_protos, DropReason,
+    };
+
+    use super::*;
+    use crate::userspace::UserRules;
+
+    const SAINT_PETERSBURG: u32 = 498817;
+    const MOSCOW: u32 = 524901;
+
+    #[test]
+    fn blocked_region_drops_and_other_region_of_same_country_passes() {
+        let name = format!("firewall-regions-{}.csv", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let csv = format!(
+            "network,geoname_id,registered_country_geoname_id\n\
+             5.44.0.0/16,{SAINT_PETERSBURG},2017370\n\
+             5.45.0.0/16,{MOSCOW},2017370\n\
+             5.46.0.0/16,,2017370\n"
+        );
+        fs::write(&path, csv).unwrap();
+        let networks = load(&path, &HashSet::from([SAINT_PETERSBURG])).unwrap();
+        fs::remove_file(&path).unwrap();
+        let spb = RegionNetwork {
+            addr: Ipv4Addr::new(5, 44, 0, 0),
+            prefix: 16,
+            region: SAINT_PETERSBURG,
+        };
+        assert_eq!(networks, [spb]);
+
+        let rules = UserRules {
+            blocked_regions: networks
+                .iter()
+                .map(|n| (u32::from(n.addr), crate::prefix_mask(n.prefix)))
+                .collect(),
+            allowed_ports: HashMap::from([(22, port_protos::TCP)]),
+            ..Default::default()
+        };
+        let decide_from = |src: Ipv4Addr| {
+            let packet = Packet {
+                src_addr: u32::from(src),
+                proto: IPPROTO_TCP,
+                dst_port: 22,
+                ..Default::default()
+            };
+            classify::decide(&packet, &rules)
+        };
+        let blocked = Ipv4Addr::new(5, 44, 1, 7);
+        let other = Ipv4Addr::new(5, 45, 1, 7);
+        assert_eq!(lookup_country(u32::from(blocked)), lookup_country(u32::from(other)));
+        assert_eq!(decide_from(blocked), Verdict::Drop(DropReason::BlockedRegion));
+        assert_eq!(decide_from(other), Verdict::Pass);
+    }
+}
@@ -0,0 +1,97 @@
+use std::{
+    ffi::CStr,
+    io::{self, BufRead as _, Write as _},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+/// Адрес сервера из `SSH_CONNECTION` (`client_ip client_port server_ip server_port`).
+pub fn ssh_server_addr(ssh_connection: &str) -> Option<IpAddr> {
+    ssh_connection.split_whitespace().nth(2)?.parse().ok()
+}
+
+/// Адреса, назначенные интерфейсу `iface`.
+pub fn iface_addrs(iface: &str) -> io::Result<Vec<IpAddr>> {
+    let mut addrs = Vec::new();
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut list) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut cursor = list;
+    while !cursor.is_null() {
+        let entry = unsafe { &*cursor };
+        cursor = entry.ifa_next;
+        if entry.ifa_addr.is_null() || entry.ifa_name.is_null() {
+            continue;
+        }
+        if unsafe { CStr::from_ptr(entry.ifa_name) }.to_bytes() != iface.as_bytes() {
+            continue;
+        }
+        match i32::from(unsafe { (*entry.ifa_addr).sa_family }) {
+            libc::AF_INET => {
+                let sin = unsafe { &*entry.ifa_addr.cast::<libc::sockaddr_in>() };
+                addrs.push(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)).into());
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*entry.ifa_addr.cast::<libc::sockaddr_in6>() };
+                addrs.push(Ipv6Addr::from(sin6.sin6_addr.s6_addr).into());
+            }
+            _ => {}
+        }
+    }
+
+    unsafe { libc::freeifaddrs(list) };
+    Ok(addrs)
+}
+
+/// Возвращает адрес SSH-сессии, если она идёт через интерфейс с адресами `addrs`.
+pub fn management_addr(ssh_connection: Option<&str>, addrs: &[IpAddr]) -> Option<IpAddr> {
+    let server = ssh_server_addr(ssh_connection?)?;
+    addrs.contains(&server).then_some(server)
+}
+
+/// Предупреждает, если файрволл собираются подключить к интерфейсу текущей SSH-сессии.
+///
+/// Возвращает `true`, если можно продолжать: сессия идёт через другой интерфейс или
+/// пользователь подтвердил подключение.
+pub fn confirm_attach(iface: &str) -> anyhow::Result<bool> {
+    let ssh_connection = std::env::var("SSH_CONNECTION").ok();
+    let addrs = iface_addrs(iface)?;
+    let Some(addr) = management_addr(ssh_connection.as_deref(), &addrs) else {
+        return Ok(true);
+    };
+
+    println!("WARNING: the current SSH session uses {addr}, which belongs to {iface}.");
+    println!("A restrictive policy on this interface may lock you out.");
+    println!("Consider trying the rules with --count-only first; pass --yes to skip this check.");
+    print!("Attach to {iface} anyway? [y/N] ");
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ssh_session_over_iface_is_detected() {
+        let addrs: [IpAddr; 2] =
+            [Ipv4Addr::new(192, 0, 2, 1).into(), "2001:db8::1".parse().unwrap()];
+        let v4 = "198.51.100.7 52144 192.0.2.1 22";
+        assert_eq!(management_addr(Some(v4), &addrs), Some(addrs[0]));
+        let v6 = "2001:db8::7 52144 2001:db8::1 22";
+        assert_eq!(management_addr(Some(v6), &addrs), Some(addrs[1]));
+    }
+
+    #[test]
+    fn other_iface_or_no_session_is_not_management() {
+        let addrs: [IpAddr; 1] = [Ipv4Addr::new(192, 0, 2, 1).into()];
+        assert_eq!(management_addr(Some("198.51.100.7 52144 10.0.0.1 22"), &addrs), None);
+        assert_eq!(management_addr(None, &addrs), None);
+        assert_eq!(management_addr(Some("198.51.100.7 52144"), &addrs), None);
+        assert_eq!(management_addr(Some(""), &addrs), None);
+    }
+}
@@ -0,0 +1,135 @@
+//! Уборка устаревших записей из LRU-таблиц источников (`--sweep-interval`).
+//!
+//! Программа удаляет запись, только когда к ней снова приходит пакет, так что источники,
+//! которые замолчали, остаются в таблице навсегда. Заполненная LRU-карта вытесняет записи
+//! приблизительно: вместе с давно молчащими уходят и живые — ответ на открытое соединение,
+//! почти простучанная последовательность, — а вставка на загруженном CPU может и вовсе не
+//! удаться. Задача раз в `--sweep-interval` секунд удаляет записи, последний пакет которых
+//! был раньше `--sweep-ttl` секунд назад.
+//!
+//! Срок каждой таблицы не короче её собственного (`--conntrack-timeout`, `--knock-timeout`,
+//! время пополнения ведра): удаляется только то, что программа и так сочла бы истёкшим,
+//! поэтому уборка не меняет решений.
+
+use std::{os::fd::AsFd as _, time::Duration};
+
+use aya::{
+    maps::{HashMap, Map, MapData},
+    Pod,
+};
+use firewall_common::{KnockProgress, RateState};
+use log::{info, warn};
+
+use crate::netflow::monotonic_ns;
+
+/// Значение таблицы, в котором программа отмечает время последнего пакета источника.
+pub trait Stamped: Pod {
+    /// Время последнего пакета, `bpf_ktime_get_ns`.
+    fn seen_ns(&self) -> u64;
+}
+
+/// `CONNTRACK` и `KNOCK_UNLOCKED` хранят само время.
+impl Stamped for u64 {
+    fn seen_ns(&self) -> u64 {
+        *self
+    }
+}
+
+impl Stamped for RateState {
+    fn seen_ns(&self) -> u64 {
+        self.last_ns
+    }
+}
+
+impl Stamped for KnockProgress {
+    fn seen_ns(&self) -> u64 {
+        self.at_ns
+    }
+}
+
+/// Таблица, которую убирает задача.
+pub trait Sweep: Send {
+    fn name(&self) -> &'static str;
+    /// Удаляет записи старше срока таблицы на момент `now_ns`; возвращает, сколько удалено.
+    fn sweep(&mut self, now_ns: u64) -> anyhow::Result<usize>;
+}
+
+struct Table<K, V> {
+    name: &'static str,
+    ttl_ns: u64,
+    map: HashMap<MapData, K, V>,
+}
+
+impl<K: Pod + Send, V: Stamped + Send> Sweep for Table<K, V> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn sweep(&mut self, now_ns: u64) -> anyhow::Result<usize> {
+        let ttl_ns = self.ttl_ns;
+        // Отметка может оказаться новее `now_ns`, если пакет пришёл уже после замера.
+        let stale = |value: &V| now_ns.saturating_sub(value.seen_ns()) > ttl_ns;
+        // Удалять во время обхода нельзя: если пропадёт ключ, от которого ядро ищет
+        // следующий, обход начнётся заново с первого ключа. Поэтому ключи сначала собираются.
+        let mut keys = Vec::new();
+        for entry in self.map.iter() {
+            let (key, value) = entry?;
+            if stale(&value) {
+                keys.push(key);
+            }
+        }
+        let mut reaped = 0;
+        for key in keys {
+            // Между обходом и удалением источник мог прислать пакет, а запись — вытесниться.
+            match self.map.get(&key, 0) {
+                Ok(value) if stale(&value) => {}
+                _ => continue,
+            }
+            if self.map.remove(&key).is_ok() {
+                reaped += 1;
+            }
+        }
+        Ok(reaped)
+    }
+}
+
+/// Таблица `name` со сроком `ttl` через отдельный дескриптор карты: задача живёт дольше
+/// заимствования `ebpf`, как и часы в `SETTINGS`.
+pub fn table<K, V>(
+    ebpf: &aya::Ebpf,
+    name: &'static str,
+    ttl: Duration,
+) -> anyhow::Result<Box<dyn Sweep>>
+where
+    K: Pod + Send + 'static,
+    V: Stamped + Send + 'static,
+{
+    let Some(Map::LruHashMap(data)) = ebpf.map(name) else {
+        anyhow::bail!("map {name} not found");
+    };
+    let fd = data.fd().as_fd().try_clone_to_owned()?;
+    let map: HashMap<_, K, V> = HashMap::try_from(Map::LruHashMap(MapData::from_fd(fd)?))?;
+    Ok(Box::new(Table {
+        name,
+        ttl_ns: ttl.as_nanos() as u64,
+        map,
+    }))
+}
+
+/// Раз в `interval` убирает таблицы `tables` и пишет в журнал, сколько записей удалено.
+pub async fn run(mut tables: Vec<Box<dyn Sweep>>, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    // Первый тик срабатывает сразу, а таблицы только что созданы.
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        let now_ns = monotonic_ns();
+        for table in &mut tables {
+            match table.sweep(now_ns) {
+                Ok(0) => {}
+                Ok(reaped) => info!("swept {reaped} stale entries from {}", table.name()),
+                Err(e) => warn!("failed to sweep {}: {e:#}", table.name()),
+            }
+        }
+    }
+}
@@ -0,0 +1,721 @@
+//! Режим без XDP (`--mode userspace`): наблюдение через сокет `AF_PACKET`.
+//!
+//! Сокет получает копии кадров, поэтому отбросить пакет отсюда нельзя. Решения принимает
+//! тот же [`classify::decide`], что и программа XDP; пакеты, которые она отбросила бы,
+//! только учитываются и публикуются как события в `EVENTS_SOCKET`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CString,
+    io, mem,
+    net::Ipv4Addr,
+    os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use firewall_common::{
+    classify::{self, Direction, Frame, Packet, Rules, Verdict},
+    direction, geoip, knock_next, port_protos, protocol_rule, time_window, DropEvent, DropReason,
+    MaskedAddr, RateState, KNOCK_STEP_SECS,
+};
+use log::{info, warn};
+use tokio::signal;
+
+use crate::events::{self, Hub};
+
+/// Как часто цикл чтения проверяет, не пора ли завершаться.
+const RECV_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Кадр Ethernet с запасом на теги VLAN.
+const FRAME_LEN: usize = 1536;
+
+/// Сколько источников помнит каждая таблица стука, как карты `KNOCK_PROGRESS` и
+/// `KNOCK_UNLOCKED`; при заполнении из неё удаляются устаревшие записи.
+const KNOCK_TABLE_LEN: usize = 4096;
+
+/// Стук источников IPv4: сколько портов простучано и когда, а для тех, кто простучал
+/// последовательность, — время последнего пакета к порту за стуком.
+#[derive(Debug, Default)]
+pub struct Knocks {
+    progress: HashMap<u32, (u32, Instant)>,
+    unlocked: HashMap<u32, Instant>,
+}
+
+/// Правила из аргументов командной строки.
+#[derive(Debug, Default)]
+pub struct UserRules {
+    pub blocked_ips: HashSet<u32>,
+    /// Заблокированные сети: адрес сети и маска.
+    pub blocked_nets: Vec<(u32, u32)>,
+    /// Заблокированные адреса и сети IPv6: адрес сети и маска.
+    pub blocked_ips6: Vec<(u128, u128)>,
+    pub blocked_masks: Vec<MaskedAddr>,
+    /// MAC-адреса источника; проверяются по кадру до [`classify::decide`].
+    pub blocked_macs: HashSet<[u8; 6]>,
+    pub blocked_countries: HashSet<u16>,
+    /// Окно времени блокировок стран, [`time_window`] (0 — всегда).
+    pub country_window: u32,
+    /// Сети заблокированных регионов: адрес сети и маска.
+    pub blocked_regions: Vec<(u32, u32)>,
+    pub allowed_ips: HashSet<u32>,
+    pub tcp_windows: HashSet<u16>,
+    /// Отбрасывать пакеты TCP с недопустимыми флагами.
+    pub tcp_flag_filter: bool,
+    /// Порт и маска `port_protos`.
+    pub allowed_ports: HashMap<u16, u8>,
+    /// Заблокированные порты источника и маски `port_protos`.
+    pub blocked_src_ports: HashMap<u16, u8>,
+    /// Сравнивать разрешённые порты с портом источника, а не назначения.
+    pub port_match_src: bool,
+    /// Пропускать эхо-запросы ICMP и ICMPv6.
+    pub icmp_echo: bool,
+    /// Пропускать пакеты, которые не разрешило ни одно правило (`--policy allow`).
+    pub default_allow: bool,
+    /// Решать неизвестные протоколы политикой, а не пропускать (`--strict-protocols`).
+    pub strict_protocols: bool,
+    /// Правила `--protocols`: номер протокола IP и значение [`protocol_rule`].
+    pub protocols: HashMap<u8, u8>,
+    /// Порт за стуком `--knock-port`; 0 — стук выключен.
+    pub knock_port: u16,
+    /// Порты `--knock-sequence` по порядку.
+    pub knock_sequence: Vec<u16>,
+    /// Сколько порт остаётся открытым после последнего пакета к нему (`--knock-timeout`).
+    pub knock_timeout: Duration,
+    pub knocks: Mutex<Knocks>,
+    /// Отбрасывать все фрагменты IPv4 (`--drop-fragments`).
+    pub drop_fragments: bool,
+    /// Пропускать DNS без разрешённых портов; ложь — `--strict-dns`.
+    pub allow_dns: bool,
+    /// Составные правила: адрес сети, маска и порт назначения.
+    pub blocked_endpoints: Vec<(u32, u32, u16)>,
+    /// Сравнивать составные правила с адресом назначения, а не источника.
+    pub endpoint_match_dst: bool,
+    /// Доверенные префиксы: адрес сети и маска.
+    pub fast_accept: Vec<(u32, u32)>,
+    /// Защищаемые адреса назначения `--protected-ips`: адрес сети и маска.
+    pub protected_ips: Vec<(u32, u32)>,
+    /// Направления `--direction`, биты [`direction`].
+    pub directions: u32,
+}
+
+impl UserRules {
+    fn is_fast_accepted(&self, addr: u32) -> bool {
+        self.fast_accept.iter().any(|&(net, mask)| addr & mask == net)
+    }
+
+    /// Применяются ли правила к пакету для `addr`: без `--protected-ips` — ко всем.
+    fn is_protected(&self, addr: u32) -> bool {
+        self.protected_ips.is_empty()
+            || self.protected_ips.iter().any(|&(net, mask)| addr & mask == net)
+    }
+}
+
+impl Rules for UserRules {
+    fn is_blocked_ip(&self, addr: u32) -> bool {
+        self.blocked_ips.contains(&addr)
+            || self.blocked_nets.iter().any(|&(net, mask)| addr & mask == net)
+    }
+
+    fn is_blocked_ip6(&self, addr: &[u8; 16]) -> bool {
+        let addr = u128::from_be_bytes(*addr);
+        self.blocked_ips6.iter().any(|&(net, mask)| addr & mask == net)
+    }
+
+    fn is_blocked_mask(&self, addr: u32) -> bool {
+        self.blocked_masks.iter().any(|rule| rule.matches(addr))
+    }
+
+    fn is_blocked_country(&self, country: u16) -> bool {
+        self.blocked_countries.contains(&country)
+    }
+
+    fn country_window_open(&self) -> bool {
+        time_window::contains(self.country_window, time_window::local_minute())
+    }
+
+    fn is_blocked_region(&self, addr: u32) -> bool {
+        self.blocked_regions.iter().any(|&(net, mask)| addr & mask == net)
+    }
+
+    fn is_allowed_ip(&self, addr: u32) -> bool {
+        self.allowed_ips.contains(&addr)
+    }
+
+    fn is_blocked_tcp_window(&self, window: u16) -> bool {
+        self.tcp_windows.contains(&window)
+    }
+
+    fn filters_tcp_flags(&self) -> bool {
+        self.tcp_flag_filter
+    }
+
+    fn is_allowed_port(&self, port: u16, proto: u8) -> bool {
+        self.allowed_ports
+            .get(&port)
+            .is_some_and(|protos| protos & port_protos::bit(proto) != 0)
+    }
+
+    fn is_blocked_src_port(&self, port: u16, proto: u8) -> bool {
+        self.blocked_src_ports
+            .get(&port)
+            .is_some_and(|protos| protos & port_protos::bit(proto) != 0)
+    }
+
+    fn port_match_src(&self) -> bool {
+        self.port_match_src
+    }
+
+    fn is_icmp_echo_allowed(&self) -> bool {
+        self.icmp_echo
+    }
+
+    fn default_allow(&self) -> bool {
+        self.default_allow
+    }
+
+    fn strict_protocols(&self) -> bool {
+        self.strict_protocols
+    }
+
+    fn protocol_rule(&self, proto: u8) -> u8 {
+        self.protocols.get(&proto).copied().unwrap_or(protocol_rule::UNLISTED)
+    }
+
+    fn drops_fragments(&self) -> bool {
+        self.drop_fragments
+    }
+
+    // Таблица соединений ведётся программой на отправке, которой в этом режиме нет.
+    fn is_established(&self, _packet: &Packet) -> bool {
+        false
+    }
+
+    fn allows_dns(&self) -> bool {
+        self.allow_dns
+    }
+
+    fn is_blocked_endpoint(&self, packet: &Packet) -> bool {
+        let addr = if self.endpoint_match_dst { packet.dst_addr } else { packet.src_addr };
+        self.blocked_endpoints
+            .iter()
+            .any(|&(net, mask, port)| port == packet.dst_port && addr & mask == net)
+    }
+
+    fn knock_port(&self) -> u16 {
+        self.knock_port
+    }
+
+    fn knock(&self, packet: &Packet) -> bool {
+        let mut knocks = self.knocks.lock().unwrap_or_else(|e| e.into_inner());
+        let src = packet.src_addr;
+        let now = Instant::now();
+        if packet.dst_port == self.knock_port {
+            return match knocks.unlocked.get_mut(&src) {
+                Some(last_seen) if now.duration_since(*last_seen) <= self.knock_timeout => {
+                    *last_seen = now;
+                    true
+                }
+                Some(_) => {
+                    knocks.unlocked.remove(&src);
+                    false
+                }
+                None => false,
+            };
+        }
+        let window = Duration::from_secs(u64::from(KNOCK_STEP_SECS));
+        let step = match knocks.progress.get(&src) {
+            Some(&(step, at)) if now.duration_since(at) <= window => step,
+            _ => 0,
+        };
+        let at = |i: u32| self.knock_sequence.get(i as usize).copied();
+        let next = knock_next(step, packet.dst_port, at);
+        if next == step {
+            return false;
+        }
+        if next as usize >= self.knock_sequence.len() {
+            knocks.progress.remove(&src);
+            if knocks.unlocked.len() >= KNOCK_TABLE_LEN {
+                let timeout = self.knock_timeout;
+                knocks.unlocked.retain(|_, last_seen| now.duration_since(*last_seen) <= timeout);
+            }
+            knocks.unlocked.insert(src, now);
+        } else if next == 0 {
+            knocks.progress.remove(&src);
+        } else {
+            if knocks.progress.len() >= KNOCK_TABLE_LEN {
+                knocks.progress.retain(|_, (_, at)| now.duration_since(*at) <= window);
+            }
+            knocks.progress.insert(src, (next, now));
+        }
+        false
+    }
+
+    fn filters_direction(&self, direction: Direction) -> bool {
+        direction::filters(self.directions, direction)
+    }
+
+    fn in_grace(&self, _packet: &Packet, _allowed: bool) -> bool {
+        false
+    }
+}
+
+/// Настройки наблюдения, аналог карты `SETTINGS` для программы XDP.
+#[derive(Debug)]
+pub struct Options {
+    pub iface: String,
+    pub rules: UserRules,
+    /// База `--country-db`; пустая, если базы нет.
+    pub countries: geoip::CountryTable,
+    pub event_sample_rate: u32,
+    /// Каждый N-й пропущенный пакет публикуется как событие `allowed` (0 — выключено).
+    pub allow_sample_rate: u32,
+    /// `--rate-limit` и ёмкость ведра, если предел задан.
+    pub rate_limit: Option<(u32, u32)>,
+    /// `--syn-rate-limit` и ёмкость ведра, если предел задан.
+    pub syn_rate_limit: Option<(u32, u32)>,
+    pub event_fields: u8,
+    pub count_only: bool,
+    pub unwrap_ipip: bool,
+    pub filter_multicast: bool,
+    /// Сеть `--arp-subnet`: адрес и длина префикса; длина 0 — ARP не ограничен.
+    pub arp_subnet: (u32, u32),
+}
+
+/// Счётчики и выборка событий, как у программы XDP.
+struct Monitor {
+    options: Options,
+    passed: u64,
+    dropped: u64,
+    sampler: u32,
+    allow_sampler: u32,
+    buckets: HashMap<u32, RateState>,
+    syn_buckets: HashMap<u32, RateState>,
+    started: Instant,
+}
+
+impl Monitor {
+    fn new(options: Options) -> Self {
+        Self {
+            options,
+            passed: 0,
+            dropped: 0,
+            sampler: 0,
+            allow_sampler: 0,
+            buckets: HashMap::new(),
+            syn_buckets: HashMap::new(),
+            started: Instant::now(),
+        }
+    }
+
+    fn now_ns(&self) -> u64 {
+        self.started.elapsed().as_nanos() as u64
+    }
+
+    /// Пакет SYN сверх `--syn-rate-limit`, как `syn_flood` в программе XDP.
+    fn syn_flood(&mut self, packet: &Packet) -> bool {
+        let now = self.now_ns();
+        match self.options.syn_rate_limit {
+            Some((rate, burst)) if packet.is_bare_syn() => {
+                !take(&mut self.syn_buckets, packet.src_addr, now, rate, burst)
+            }
+            _ => false,
+        }
+    }
+
+    /// Пропущенный пакет сверх `--rate-limit`, как `rate_limited` в программе XDP.
+    fn rate_limited(&mut self, src: u32) -> bool {
+        let now = self.now_ns();
+        match self.options.rate_limit {
+            Some((rate, burst)) => !take(&mut self.buckets, src, now, rate, burst),
+            None => false,
+        }
+    }
+
+    /// Учитывает кадр и возвращает событие, если его нужно опубликовать.
+    fn observe(&mut self, frame: &[u8]) -> Option<DropEvent> {
+        let rules = &self.options.rules;
+        let blocked_mac =
+            classify::src_mac(frame).is_some_and(|mac| rules.blocked_macs.contains(&mac));
+        if blocked_mac && !self.options.count_only {
+            self.dropped += 1;
+            return sampled(&mut self.sampler, self.options.event_sample_rate)
+                .then(|| Packet::default().drop_event(DropReason::BlockedMac, 0));
+        }
+        let parsed = classify::parse_frame(frame, self.options.unwrap_ipip);
+        // ARP, как и в программе XDP, решается до пропуска широковещательных кадров.
+        if let Some(Frame::Arp(arp)) = parsed {
+            let (net, prefix) = self.options.arp_subnet;
+            if self.options.count_only || classify::arp_allowed(arp.sender_addr, net, prefix) {
+                self.passed += 1;
+                return None;
+            }
+            self.dropped += 1;
+            let packet = Packet {
+                src_addr: arp.sender_addr,
+                ..Default::default()
+            };
+            return sampled(&mut self.sampler, self.options.event_sample_rate)
+                .then(|| packet.drop_event(DropReason::Arp, 0));
+        }
+        let multicast =
+            classify::dst_mac(frame).is_some_and(|mac| classify::is_multicast_mac(&mac));
+        if multicast && !self.options.filter_multicast {
+            self.passed += 1;
+            return None;
+        }
+        let (packet, verdict) = match parsed {
+            Some(Frame::Ipv4(mut packet)) => {
+                packet.country = self.options.countries.country(packet.src_addr);
+                let verdict = if self.options.count_only
+                    || rules.is_fast_accepted(packet.src_addr)
+                    || !rules.is_protected(packet.dst_addr)
+                {
+                    Verdict::Pass
+                } else if self.syn_flood(&packet) {
+                    Verdict::Drop(DropReason::SynFlood)
+                } else {
+                    match classify::decide(&packet, &self.options.rules) {
+                        Verdict::Pass if self.rate_limited(packet.src_addr) => {
+                            Verdict::Drop(DropReason::RateLimited)
+                        }
+                        verdict => verdict,
+                    }
+                };
+                (packet, verdict)
+            }
+            // Защищаемые адреса — IPv4, так что с ними IPv6 не проверяется.
+            Some(Frame::Ipv6(packet))
+                if self.options.count_only || !rules.protected_ips.is_empty() =>
+            {
+                (packet, Verdict::Pass)
+            }
+            Some(Frame::Ipv6(packet)) => (packet, classify::decide_ipv6(&packet, rules)),
+            // Обрезанные кадры программа XDP прерывает, здесь их просто не учитываем.
+            None => return None,
+            Some(Frame::NotIp | Frame::Arp(_)) => {
+                self.passed += 1;
+                return None;
+            }
+        };
+        let fields = self.options.event_fields;
+        let Verdict::Drop(reason) = verdict else {
+            self.passed += 1;
+            return sampled(&mut self.allow_sampler, self.options.allow_sample_rate)
+                .then(|| packet.drop_event(DropReason::Allowed, fields));
+        };
+        self.dropped += 1;
+        sampled(&mut self.sampler, self.options.event_sample_rate)
+            .then(|| packet.drop_event(reason, fields))
+    }
+}
+
+/// Забирает токен из ведра `src`; новый источник начинает с полным ведром.
+fn take(buckets: &mut HashMap<u32, RateState>, src: u32, now: u64, rate: u32, burst: u32) -> bool {
+    buckets
+        .entry(src)
+        .or_insert_with(|| RateState::full(now, burst))
+        .take(now, rate, burst)
+}
+
+/// Каждое N-е событие, как `sampled` в программе XDP.
+fn sampled(counter: &mut u32, rate: u32) -> bool {
+    if rate == 0 {
+        return false;
+    }
+    *counter += 1;
+    if *counter >= rate {
+        *counter = 0;
+        true
+    } else {
+        false
+    }
+}
+
+/// Сокет `AF_PACKET`, привязанный к интерфейсу и видящий весь трафик.
+fn open_socket(iface: &str) -> anyhow::Result<OwnedFd> {
+    let name = CString::new(iface).context("interface name contains a NUL byte")?;
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(io::Error::last_os_error()).with_context(|| format!("no interface {iface}"));
+    }
+
+    let protocol = (libc::ETH_P_ALL as u16).to_be();
+    let fd = unsafe {
+        libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_CLOEXEC, i32::from(protocol))
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("failed to open an AF_PACKET socket");
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = protocol;
+    addr.sll_ifindex = ifindex as i32;
+    let ret = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const _ as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("failed to bind to {iface}"));
+    }
+
+    let timeout = libc::timeval {
+        tv_sec: 0,
+        tv_usec: RECV_TIMEOUT.as_micros() as libc::suseconds_t,
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeout as *const _ as *const libc::c_void,
+            mem::size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error()).context("failed to set SO_RCVTIMEO");
+    }
+    Ok(socket)
+}
+
+/// Читает входящий кадр; `None` — истёк таймаут или кадр исходящий.
+fn recv_frame(socket: &OwnedFd, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+    let mut addr_len = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+    let len = unsafe {
+        libc::recvfrom(
+            socket.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            0,
+            &mut addr as *mut _ as *mut libc::sockaddr,
+            &mut addr_len,
+        )
+    };
+    if len < 0 {
+        let err = io::Error::last_os_error();
+        return match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(None),
+            _ => Err(err),
+        };
+    }
+    // Программа XDP видит только входящий трафик.
+    if addr.sll_pkttype == libc::PACKET_OUTGOING {
+        return Ok(None);
+    }
+    Ok(Some(len as usize))
+}
+
+fn capture(
+    socket: OwnedFd,
+    mut monitor: Monitor,
+    hub: Hub,
+    stop: &AtomicBool,
+) -> io::Result<Monitor> {
+    let mut buf = [0u8; FRAME_LEN];
+    while !stop.load(Ordering::Relaxed) {
+        let Some(len) = recv_frame(&socket, &mut buf)? else {
+            continue;
+        };
+        if let Some(event) = monitor.observe(&buf[..len]) {
+            let reason = DropReason::from_u8(event.reason);
+            let verb = if reason == Some(DropReason::Allowed) { "passed" } else { "would drop" };
+            info!(
+                "{verb} {}:{} -> {}:{} proto {} ({})",
+                Ipv4Addr::from(event.src_addr),
+                event.src_port,
+                Ipv4Addr::from(event.dst_addr),
+                event.dst_port,
+                event.proto,
+                reason.map_or("unknown", DropReason::as_str),
+            );
+            hub.publish(event);
+        }
+    }
+    Ok(monitor)
+}
+
+/// Наблюдает за интерфейсом до Ctrl-C и печатает итоговые счётчики.
+pub async fn run(options: Options) -> anyhow::Result<()> {
+    let socket = open_socket(&options.iface)?;
+    println!(
+        "Userspace mode on {}: packets are observed through AF_PACKET and cannot be dropped, \
+         rule matches are counted and reported as events",
+        options.iface
+    );
+
+    let hub = Hub::new();
+    let server = hub.clone();
+    tokio::spawn(async move {
+        if let Err(e) = events::serve(server).await {
+            warn!("event socket stopped: {e:#}");
+        }
+    });
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let flag = stop.clone();
+    let monitor = Monitor::new(options);
+    let mut worker = tokio::task::spawn_blocking(move || capture(socket, monitor, hub, &flag));
+
+    println!("Waiting for Ctrl-C...");
+    // Цикл чтения завершается сам только при ошибке сокета.
+    let finished = tokio::select! {
+        result = signal::ctrl_c() => {
+            result?;
+            None
+        }
+        joined = &mut worker => Some(joined),
+    };
+    println!("Exiting...");
+    stop.store(true, Ordering::Relaxed);
+    let joined = match finished {
+        Some(joined) => joined,
+        None => worker.await,
+    };
+    let monitor = joined?.context("AF_PACKET capture failed")?;
+    println!("Passed {} packets, would have dropped {}", monitor.passed, monitor.dropped);
+
+    events::remove_socket();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCKED: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+
+    /// Кадр Ethernet с IPv4 и SYN TCP от `src` на порт 22.
+    fn syn_frame(src: Ipv4Addr) -> Vec<u8> {
+        let mut frame = vec![0x02, 0, 0, 0, 0, 0x02, 0x02, 0, 0, 0, 0, 0x01, 0x08, 0x00];
+        let mut ip = [0u8; 20];
+        ip[0] = 0x45;
+        ip[2..4].copy_from_slice(&40u16.to_be_bytes());
+        ip[8] = 64;
+        ip[9] = 6;
+        ip[12..16].copy_from_slice(&src.octets());
+        ip[16..20].copy_from_slice(&[192, 0, 2, 1]);
+        frame.extend_from_slice(&ip);
+        frame.extend_from_slice(&40000u16.to_be_bytes());
+        frame.extend_from_slice(&22u16.to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0x50, 0x02, 0xfa, 0xf0, 0, 0, 0, 0]);
+        frame
+    }
+
+    fn monitor(count_only: bool) -> Monitor {
+        let rules = UserRules {
+            blocked_ips: HashSet::from([u32::from(BLOCKED)]),
+            allowed_ports: HashMap::from([(22, port_protos::TCP)]),
+            directions: direction::INGRESS,
+            ..Default::default()
+        };
+        Monitor::new(Options {
+            iface: "lo".to_string(),
+            rules,
+            countries: geoip::CountryTable::new(&[]),
+            event_sample_rate: 1,
+            allow_sample_rate: 0,
+            rate_limit: None,
+            syn_rate_limit: None,
+            event_fields: 0,
+            count_only,
+            unwrap_ipip: false,
+            filter_multicast: false,
+            arp_subnet: (0, 0),
+        })
+    }
+
+    #[test]
+    fn count_only_passes_blocked_packet_and_counts_it() {
+        let frame = syn_frame(BLOCKED);
+
+        let mut enforcing = monitor(false);
+        let event = enforcing.observe(&frame).expect("событие об отброшенном пакете");
+        assert_eq!(event.reason, DropReason::BlockedIp as u8);
+        assert_eq!((enforcing.passed, enforcing.dropped), (0, 1));
+
+        let mut counting = monitor(true);
+        assert!(counting.observe(&frame).is_none());
+        assert_eq!((counting.passed, counting.dropped), (1, 0));
+    }
+
+    #[test]
+    fn fast_accepted_source_skips_rule_evaluation() {
+        let mut monitor = monitor(false);
+        monitor.options.rules.fast_accept = vec![(0xc633_6400, 0xffff_ff00)]; // 198.51.100.0/24
+        monitor.options.rate_limit = Some((1, 1));
+        monitor.options.syn_rate_limit = Some((1, 1));
+        let frame = syn_frame(BLOCKED);
+        for _ in 0..3 {
+            assert!(monitor.observe(&frame).is_none());
+        }
+        assert_eq!((monitor.passed, monitor.dropped), (3, 0));
+        // Ни ведро SYN, ни ведро пропущенных пакетов не заводились: правила не проверялись.
+        assert!(monitor.syn_buckets.is_empty() && monitor.buckets.is_empty());
+
+        let other = syn_frame(Ipv4Addr::new(203, 0, 113, 7));
+        assert!(monitor.observe(&other).is_none());
+        assert_eq!(monitor.syn_buckets.len(), 1);
+    }
+
+    #[test]
+    fn log_allows_emits_allowed_event_for_passed_packet() {
+        let frame = syn_frame(Ipv4Addr::new(203, 0, 113, 7));
+        let mut silent = monitor(false);
+        assert!(silent.observe(&frame).is_none());
+
+        let mut logging = monitor(false);
+        logging.options.allow_sample_rate = 2;
+        let events: Vec<_> = (0..4).filter_map(|_| logging.observe(&frame)).collect();
+        assert_eq!((logging.passed, logging.dropped), (4, 0));
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| e.reason == DropReason::Allowed as u8));
+        assert_eq!((events[0].src_addr, events[0].dst_port), (0xcb00_7107, 22));
+    }
+
+    /// Пара сокетов вместо `AF_PACKET`: кадры, записанные в первый, читаются из второго.
+    fn mock_source(frames: &[&[u8]]) -> OwnedFd {
+        let mut fds = [0; 2];
+        let kind = libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+        assert_eq!(unsafe { libc::socketpair(libc::AF_UNIX, kind, 0, fds.as_mut_ptr()) }, 0);
+        let writer = unsafe { OwnedFd::from_raw_fd(fds[0]) };
+        let reader = unsafe { OwnedFd::from_raw_fd(fds[1]) };
+        for frame in frames {
+            let sent = unsafe {
+                libc::send(writer.as_raw_fd(), frame.as_ptr().cast(), frame.len(), 0)
+            };
+            assert_eq!(sent, frame.len() as isize);
+        }
+        reader
+    }
+
+    #[test]
+    fn frames_from_packet_source_are_classified() {
+        let blocked = syn_frame(BLOCKED);
+        let allowed = syn_frame(Ipv4Addr::new(203, 0, 113, 7));
+        let socket = mock_source(&[&allowed, &blocked, &blocked[..30], &allowed]);
+
+        let mut monitor = monitor(false);
+        let mut events = Vec::new();
+        let mut buf = [0u8; FRAME_LEN];
+        // Очередь пуста — неблокирующий сокет отвечает так же, как `AF_PACKET` по таймауту.
+        while let Some(len) = recv_frame(&socket, &mut buf).unwrap() {
+            events.extend(monitor.observe(&buf[..len]));
+        }
+        // Обрезанный кадр не учитывается, как его не учитывает и программа XDP.
+        assert_eq!((monitor.passed, monitor.dropped), (2, 1));
+        assert_eq!(events.len(), 1);
+        assert_eq!(Ipv4Addr::from(events[0].src_addr), BLOCKED);
+        assert_eq!(events[0].reason, DropReason::BlockedIp as u8);
+    }
+}
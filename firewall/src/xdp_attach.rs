@@ -0,0 +1,210 @@
+//! Объяснения частых ошибок загрузки и привязки программы XDP, замена чужой программы на
+//! интерфейсе (`--force`) и проверка, какая программа привязана сейчас (`--watchdog`).
+//!
+//! aya и ядро сообщают только код ошибки вызова (`bpf_link_create` failed: Device or
+//! resource busy), а что с ним делать, зависит от того, на каком шаге он получен. Подсказка
+//! добавляется к ошибке внешним контекстом, так что видна и в журнале, и в сообщении, с
+//! которым завершается загрузчик.
+
+use std::{
+    ffi::CString,
+    io,
+    os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd},
+};
+
+use crate::AttachMode;
+
+/// Код ошибки ОС из цепочки причин `e`.
+fn os_error(e: &anyhow::Error) -> Option<i32> {
+    e.chain().find_map(|cause| cause.downcast_ref::<io::Error>()?.raw_os_error())
+}
+
+fn privileges(code: i32) -> Option<&'static str> {
+    matches!(code, libc::EPERM | libc::EACCES).then_some(
+        "not enough privileges: run the loader as root (sudo firewall ...) or grant it \
+         CAP_BPF and CAP_NET_ADMIN (CAP_SYS_ADMIN on kernels older than 5.8)",
+    )
+}
+
+/// Есть ли по ошибке привязки `e` на интерфейсе другая программа XDP.
+pub fn is_busy(e: &anyhow::Error) -> bool {
+    matches!(os_error(e), Some(libc::EBUSY | libc::EEXIST))
+}
+
+/// Ошибка загрузки программы или карт с подсказкой, если её есть что подсказать.
+pub fn explain(e: anyhow::Error) -> anyhow::Error {
+    match os_error(&e).and_then(privileges) {
+        Some(hint) => e.context(hint),
+        None => e,
+    }
+}
+
+/// Ошибка привязки к `iface` в режиме `mode` с подсказкой.
+pub fn explain_attach(e: anyhow::Error, iface: &str, mode: AttachMode) -> anyhow::Error {
+    let Some(code) = os_error(&e) else {
+        return e;
+    };
+    let hint = match code {
+        libc::EBUSY | libc::EEXIST => format!(
+            "another XDP program is attached to {iface} (see `ip link show {iface}`); remove it \
+             with `firewall-cli detach --iface {iface}` or pass --force to replace it"
+        ),
+        libc::EOPNOTSUPP | libc::EINVAL if mode != AttachMode::Skb => format!(
+            "the driver of {iface} does not support XDP in {} mode; use --attach-mode skb, \
+             which works on any interface but filters later",
+            mode.name()
+        ),
+        code => match privileges(code) {
+            Some(hint) => hint.to_string(),
+            None => return e,
+        },
+    };
+    e.context(hint)
+}
+
+/// Флаги `XDP_FLAGS_*` режимов, в которых на интерфейсе может стоять программа.
+const MODE_FLAGS: [u32; 3] = [1 << 1, 1 << 2, 1 << 3];
+
+const IFLA_XDP_FD: u16 = 1;
+const IFLA_XDP_FLAGS: u16 = 3;
+const IFLA_XDP_PROG_ID: u16 = 4;
+const IFLA_XDP_HW_PROG_ID: u16 = 7;
+const NLA_F_NESTED: u16 = 1 << 15;
+const NLA_TYPE_MASK: u16 = (1 << 14) - 1;
+
+/// Атрибут netlink, дополненный до 4 байт.
+fn attr(kind: u16, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 8);
+    out.extend_from_slice(&(4 + payload.len() as u16).to_ne_bytes());
+    out.extend_from_slice(&kind.to_ne_bytes());
+    out.extend_from_slice(payload);
+    out.resize((out.len() + 3) & !3, 0);
+    out
+}
+
+/// Атрибуты netlink из `data`: тип без флагов и содержимое.
+fn attrs(mut data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let len = usize::from(u16::from_ne_bytes(data.get(..2)?.try_into().ok()?));
+        let kind = u16::from_ne_bytes(data.get(2..4)?.try_into().ok()?) & NLA_TYPE_MASK;
+        let payload = data.get(4..len)?;
+        data = data.get((len + 3) & !3..).unwrap_or_default();
+        Some((kind, payload))
+    })
+}
+
+/// Отправляет запрос rtnetlink `kind` об интерфейсе `ifindex` и возвращает первое сообщение
+/// ответа. Ошибку, которой ответило ядро, возвращает как ошибку ОС.
+fn request(
+    socket: &OwnedFd,
+    kind: u16,
+    flags: u16,
+    ifindex: u32,
+    attrs: &[u8],
+) -> io::Result<Vec<u8>> {
+    // nlmsghdr (16 байт), ifinfomsg (16 байт), атрибуты.
+    let len = 32 + attrs.len();
+    let mut msg = Vec::with_capacity(len);
+    msg.extend_from_slice(&(len as u32).to_ne_bytes());
+    msg.extend_from_slice(&kind.to_ne_bytes());
+    msg.extend_from_slice(&(libc::NLM_F_REQUEST as u16 | flags).to_ne_bytes());
+    msg.extend_from_slice(&1u32.to_ne_bytes());
+    msg.extend_from_slice(&0u32.to_ne_bytes());
+    msg.extend_from_slice(&[libc::AF_UNSPEC as u8, 0, 0, 0]);
+    msg.extend_from_slice(&(ifindex as i32).to_ne_bytes());
+    msg.extend_from_slice(&[0; 8]);
+    msg.extend_from_slice(attrs);
+    if unsafe { libc::send(socket.as_raw_fd(), msg.as_ptr().cast(), len, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Описание интерфейса со статистикой занимает несколько килобайт.
+    let mut reply = vec![0u8; 32 * 1024];
+    let received =
+        unsafe { libc::recv(socket.as_raw_fd(), reply.as_mut_ptr().cast(), reply.len(), 0) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    reply.truncate(received as usize);
+    if reply.len() >= 4 {
+        let len = u32::from_ne_bytes(reply[..4].try_into().unwrap()) as usize;
+        reply.truncate(len);
+    }
+    if reply.len() >= 20 && u16::from_ne_bytes([reply[4], reply[5]]) == libc::NLMSG_ERROR as u16 {
+        let errno = i32::from_ne_bytes(reply[16..20].try_into().unwrap());
+        if errno != 0 {
+            return Err(io::Error::from_raw_os_error(-errno));
+        }
+    }
+    Ok(reply)
+}
+
+/// Снимает программу XDP, привязанную к `ifindex` в режиме `flag`, через rtnetlink.
+/// Если программы в этом режиме нет, ядро отвечает успехом.
+fn unset(socket: &OwnedFd, ifindex: u32, flag: u32) -> io::Result<()> {
+    let mut xdp = attr(IFLA_XDP_FD, &(-1i32).to_ne_bytes());
+    xdp.extend(attr(IFLA_XDP_FLAGS, &flag.to_ne_bytes()));
+    let attrs = attr(libc::IFLA_XDP | NLA_F_NESTED, &xdp);
+    // Ответ на запрос с NLM_F_ACK — одно сообщение NLMSG_ERROR, код 0 означает успех.
+    request(socket, libc::RTM_SETLINK, libc::NLM_F_ACK as u16, ifindex, &attrs)?;
+    Ok(())
+}
+
+fn ifindex(iface: &str) -> anyhow::Result<u32> {
+    let name = CString::new(iface)?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => anyhow::bail!("unknown network interface {iface}"),
+        ifindex => Ok(ifindex),
+    }
+}
+
+fn route_socket() -> io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE)
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Номера программ XDP, привязанных сейчас к `iface`, во всех режимах.
+pub fn attached_programs(iface: &str) -> anyhow::Result<Vec<u32>> {
+    let ifindex = ifindex(iface)?;
+    let reply = request(&route_socket()?, libc::RTM_GETLINK, 0, ifindex, &[])?;
+    let Some((_, xdp)) = attrs(reply.get(32..).unwrap_or_default())
+        .find(|&(kind, _)| kind == libc::IFLA_XDP)
+    else {
+        return Ok(Vec::new());
+    };
+    let ids = attrs(xdp)
+        .filter(|(kind, _)| (IFLA_XDP_PROG_ID..=IFLA_XDP_HW_PROG_ID).contains(kind))
+        .filter_map(|(_, id)| Some(u32::from_ne_bytes(id.get(..4)?.try_into().ok()?)))
+        .filter(|&id| id != 0)
+        .collect();
+    Ok(ids)
+}
+
+/// Снимает с `iface` программы XDP во всех режимах, чтобы на их место встала своя.
+///
+/// Программу, привязанную другим процессом через bpf_link, снять нельзя: она держится,
+/// пока жив этот процесс.
+pub fn remove_existing(iface: &str) -> anyhow::Result<()> {
+    let ifindex = ifindex(iface)?;
+    let socket = route_socket()?;
+    for flag in MODE_FLAGS {
+        match unset(&socket, ifindex, flag) {
+            Ok(()) => {}
+            Err(e) if e.raw_os_error() == Some(libc::EBUSY) => anyhow::bail!(
+                "the XDP program on {iface} is held by a bpf_link of another process; stop that \
+                 process to free the interface"
+            ),
+            Err(e) => {
+                let e = anyhow::Error::from(e)
+                    .context(format!("failed to remove the XDP program from {iface}"));
+                return Err(explain(e));
+            }
+        }
+    }
+    Ok(())
+}